@@ -0,0 +1 @@
+ALTER TABLE rates ALTER COLUMN rate TYPE NUMERIC;
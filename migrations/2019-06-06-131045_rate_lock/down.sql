@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE transactions DROP COLUMN exchange_rate,
+  DROP COLUMN rate_locked_until,
+  DROP COLUMN requotes;
@@ -0,0 +1,3 @@
+ALTER TABLE transactions ADD COLUMN exchange_rate NUMERIC,
+  ADD COLUMN rate_locked_until TIMESTAMP,
+  ADD COLUMN requotes INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,12 @@
+CREATE TABLE api_requests (
+  id UUID PRIMARY KEY,
+  merchant_id TEXT,
+  method TEXT NOT NULL,
+  path TEXT NOT NULL,
+  status_code INTEGER NOT NULL,
+  latency_ms BIGINT NOT NULL,
+  error TEXT,
+  created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX api_requests_merchant_idx ON api_requests (merchant_id, created_at DESC);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE webauthn_credentials;
+ALTER TABLE merchants DROP COLUMN second_factor;
+DROP TYPE second_factor;
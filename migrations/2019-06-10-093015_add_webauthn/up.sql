@@ -0,0 +1,18 @@
+CREATE TYPE second_factor AS ENUM (
+    'totp',
+    'security_key',
+    'both'
+);
+
+ALTER TABLE merchants ADD COLUMN second_factor second_factor NOT NULL DEFAULT 'totp';
+
+CREATE TABLE webauthn_credentials (
+  id TEXT PRIMARY KEY,
+  merchant_id TEXT NOT NULL REFERENCES merchants(id),
+  name TEXT NOT NULL,
+  public_key BYTEA NOT NULL,
+  sign_count BIGINT NOT NULL DEFAULT 0,
+  created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX webauthn_credentials_merchant_idx ON webauthn_credentials (merchant_id);
@@ -0,0 +1 @@
+ALTER TABLE merchants ADD COLUMN oidc_subject TEXT UNIQUE;
@@ -0,0 +1,10 @@
+CREATE TABLE api_tokens (
+  id UUID PRIMARY KEY,
+  merchant_id TEXT NOT NULL REFERENCES merchants(id),
+  name TEXT NOT NULL,
+  token_hash TEXT NOT NULL UNIQUE,
+  scopes TEXT[] NOT NULL,
+  created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX api_tokens_merchant_idx ON api_tokens (merchant_id);
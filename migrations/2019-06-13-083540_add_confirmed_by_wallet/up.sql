@@ -0,0 +1 @@
+ALTER TABLE transactions ADD COLUMN confirmed_by_wallet BOOLEAN NOT NULL DEFAULT FALSE;
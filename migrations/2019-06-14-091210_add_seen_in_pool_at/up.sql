@@ -0,0 +1 @@
+ALTER TABLE transactions ADD COLUMN seen_in_pool_at TIMESTAMP;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE transactions DROP COLUMN payout_batch_id;
+DROP TABLE payout_batches;
@@ -0,0 +1,10 @@
+CREATE TABLE payout_batches (
+  id UUID PRIMARY KEY,
+  size INTEGER NOT NULL,
+  created_at TIMESTAMP NOT NULL,
+  processed_at TIMESTAMP
+);
+
+ALTER TABLE transactions ADD COLUMN payout_batch_id UUID REFERENCES payout_batches(id);
+
+CREATE INDEX transactions_payout_batch_idx ON transactions (payout_batch_id);
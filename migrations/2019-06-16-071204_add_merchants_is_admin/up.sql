@@ -0,0 +1 @@
+ALTER TABLE merchants ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,9 @@
+CREATE TABLE reconciliation_orphans (
+  id UUID PRIMARY KEY,
+  source TEXT NOT NULL,
+  slate_id TEXT NOT NULL,
+  wallet_tx_id BIGINT,
+  transaction_id UUID,
+  grin_amount BIGINT NOT NULL,
+  found_at TIMESTAMP NOT NULL
+);
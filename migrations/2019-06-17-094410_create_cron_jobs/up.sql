@@ -0,0 +1,7 @@
+CREATE TABLE cron_jobs (
+  name TEXT PRIMARY KEY,
+  locked_by TEXT,
+  locked_until TIMESTAMP,
+  last_started_at TIMESTAMP,
+  last_finished_at TIMESTAMP
+);
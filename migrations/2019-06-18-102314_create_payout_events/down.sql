@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE payout_events;
+
+ALTER TABLE merchants DROP COLUMN payout_callback_url;
@@ -0,0 +1,14 @@
+ALTER TABLE merchants ADD COLUMN payout_callback_url TEXT;
+
+CREATE TABLE payout_events (
+  id UUID PRIMARY KEY,
+  transaction_id UUID NOT NULL REFERENCES transactions(id),
+  merchant_id TEXT NOT NULL REFERENCES merchants(id),
+  event TEXT NOT NULL,
+  created_at TIMESTAMP NOT NULL,
+  delivered_at TIMESTAMP,
+  attempts INTEGER NOT NULL DEFAULT 0,
+  next_attempt TIMESTAMP
+);
+
+CREATE INDEX payout_events_undelivered_idx ON payout_events (created_at) WHERE delivered_at IS NULL;
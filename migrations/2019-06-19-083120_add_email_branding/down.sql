@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE transactions DROP COLUMN receipt_sent_at;
+
+ALTER TABLE merchants DROP COLUMN email_reply_to;
+ALTER TABLE merchants DROP COLUMN email_footer;
+ALTER TABLE merchants DROP COLUMN email_logo_url;
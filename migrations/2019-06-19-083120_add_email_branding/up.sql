@@ -0,0 +1,7 @@
+ALTER TABLE merchants ADD COLUMN email_logo_url TEXT;
+ALTER TABLE merchants ADD COLUMN email_footer TEXT;
+ALTER TABLE merchants ADD COLUMN email_reply_to TEXT;
+
+ALTER TABLE transactions ADD COLUMN receipt_sent_at TIMESTAMP;
+-- Buyers of payments confirmed before receipts existed don't get one now
+UPDATE transactions SET receipt_sent_at = updated_at WHERE status = 'confirmed' AND email IS NOT NULL;
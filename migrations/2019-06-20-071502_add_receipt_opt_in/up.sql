@@ -0,0 +1,3 @@
+-- Set when the buyer entered the email on the payment page and agreed to get a receipt,
+-- emails passed by the merchant on payment creation keep it false
+ALTER TABLE transactions ADD COLUMN receipt_opt_in BOOLEAN NOT NULL DEFAULT FALSE;
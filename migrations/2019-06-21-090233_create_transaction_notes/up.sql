@@ -0,0 +1,9 @@
+CREATE TABLE transaction_notes (
+  id UUID PRIMARY KEY,
+  transaction_id UUID NOT NULL REFERENCES transactions(id),
+  author TEXT NOT NULL REFERENCES merchants(id),
+  body TEXT NOT NULL,
+  created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX transaction_notes_transaction_id_idx ON transaction_notes (transaction_id, created_at);
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX transactions_merchant_external_id_idx;
+DROP INDEX transactions_merchant_status_idx;
+DROP INDEX transactions_merchant_idx;
+DROP INDEX transactions_unsent_receipts_idx;
+DROP INDEX transactions_type_status_idx;
+DROP INDEX transactions_unreported_idx;
@@ -0,0 +1,13 @@
+-- Unreported payments polled by the callback cron job. Queries filter on
+-- `NOT reported` literally, a bound parameter can't use a partial index
+CREATE INDEX transactions_unreported_idx ON transactions (status, next_report_attempt) WHERE NOT reported;
+-- Expiring new payments, payouts waiting for a batch or confirmations
+CREATE INDEX transactions_type_status_idx ON transactions (transaction_type, status, created_at);
+-- Confirmed payments waiting for a receipt
+CREATE INDEX transactions_unsent_receipts_idx ON transactions (updated_at) WHERE email IS NOT NULL AND receipt_sent_at IS NULL;
+-- Merchant's transaction listings, newest first
+CREATE INDEX transactions_merchant_idx ON transactions (merchant_id, created_at DESC);
+-- Settlements and dashboard totals
+CREATE INDEX transactions_merchant_status_idx ON transactions (merchant_id, status, updated_at);
+-- Status queries by order id
+CREATE INDEX transactions_merchant_external_id_idx ON transactions (merchant_id, external_id);
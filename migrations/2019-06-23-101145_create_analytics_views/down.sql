@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP MATERIALIZED VIEW analytics_merchant_daily;
+DROP MATERIALIZED VIEW analytics_hourly;
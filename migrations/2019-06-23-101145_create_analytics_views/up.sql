@@ -0,0 +1,26 @@
+-- Payments by the hour they were created in, sums rather than averages
+-- so buckets can be added up into days
+CREATE MATERIALIZED VIEW analytics_hourly AS
+SELECT date_trunc('hour', created_at) AS hour,
+  COUNT(*) AS created,
+  COUNT(*) FILTER (WHERE status = 'confirmed') AS confirmed,
+  COALESCE(SUM(grin_amount) FILTER (WHERE status = 'confirmed'), 0)::BIGINT AS volume,
+  COALESCE(SUM(EXTRACT(EPOCH FROM updated_at - created_at)) FILTER (WHERE status = 'confirmed'), 0)::BIGINT
+    AS confirmation_seconds,
+  COUNT(*) FILTER (WHERE reported) AS callbacks_succeeded,
+  COALESCE(SUM(report_attempts), 0)::BIGINT AS callbacks_failed
+FROM transactions
+WHERE transaction_type = 'payment'
+GROUP BY 1;
+CREATE UNIQUE INDEX analytics_hourly_hour_idx ON analytics_hourly (hour);
+
+-- Confirmed payments of every merchant by day
+CREATE MATERIALIZED VIEW analytics_merchant_daily AS
+SELECT updated_at::date AS day,
+  merchant_id,
+  COUNT(*) AS payments,
+  SUM(grin_amount)::BIGINT AS volume
+FROM transactions
+WHERE transaction_type = 'payment' AND status = 'confirmed'
+GROUP BY 1, 2;
+CREATE UNIQUE INDEX analytics_merchant_daily_idx ON analytics_merchant_daily (day, merchant_id);
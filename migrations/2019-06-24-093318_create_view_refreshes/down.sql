@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP MATERIALIZED VIEW unreported_summary;
+DROP TABLE view_refreshes;
@@ -0,0 +1,17 @@
+CREATE TABLE view_refreshes (
+  name TEXT PRIMARY KEY,
+  refreshed_at TIMESTAMP NOT NULL
+);
+
+-- Finished payments whose merchants haven't been notified yet
+CREATE MATERIALIZED VIEW unreported_summary AS
+SELECT merchant_id,
+  status,
+  COUNT(*) AS payments,
+  MIN(updated_at) AS oldest_updated_at,
+  MAX(report_attempts) AS max_report_attempts
+FROM transactions
+WHERE transaction_type = 'payment' AND NOT reported AND status IN ('confirmed', 'rejected')
+GROUP BY 1, 2;
+-- REFRESH CONCURRENTLY needs a unique index
+CREATE UNIQUE INDEX unreported_summary_idx ON unreported_summary (merchant_id, status);
@@ -0,0 +1,2 @@
+-- Overrides the wallet's output selection for a payout
+ALTER TABLE transactions ADD COLUMN output_selection JSONB;
@@ -0,0 +1,9 @@
+CREATE TABLE blocks (
+    height BIGINT PRIMARY KEY,
+    hash TEXT NOT NULL,
+    previous TEXT NOT NULL,
+    timestamp TIMESTAMP,
+    synced_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX blocks_hash_idx ON blocks (hash);
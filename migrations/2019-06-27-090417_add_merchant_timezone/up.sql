@@ -0,0 +1 @@
+ALTER TABLE merchants ADD COLUMN timezone TEXT NOT NULL DEFAULT 'UTC';
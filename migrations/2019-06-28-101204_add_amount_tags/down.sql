@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX transactions_amount_tag_idx;
+ALTER TABLE transactions DROP COLUMN amount_tag;
@@ -0,0 +1,5 @@
+-- Nanogrins added to the grin amount of a payment to make it unique
+ALTER TABLE transactions ADD COLUMN amount_tag BIGINT;
+-- Two new payments of a merchant never wait for the same tagged amount
+CREATE UNIQUE INDEX transactions_amount_tag_idx ON transactions (merchant_id, grin_amount)
+    WHERE amount_tag IS NOT NULL AND status = 'new';
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX transactions_invoice_number_idx;
+ALTER TABLE transactions DROP COLUMN invoice_number;
+ALTER TABLE merchants DROP COLUMN invoice_sequence;
+ALTER TABLE merchants DROP COLUMN invoice_prefix;
@@ -0,0 +1,5 @@
+ALTER TABLE merchants ADD COLUMN invoice_prefix TEXT NOT NULL DEFAULT 'KT';
+-- Last invoice number given to a payment of the merchant
+ALTER TABLE merchants ADD COLUMN invoice_sequence BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE transactions ADD COLUMN invoice_number TEXT;
+CREATE UNIQUE INDEX transactions_invoice_number_idx ON transactions (merchant_id, invoice_number);
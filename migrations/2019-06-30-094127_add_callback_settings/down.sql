@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE merchants DROP COLUMN callback_verify_tls;
+ALTER TABLE merchants DROP COLUMN callback_headers;
+ALTER TABLE merchants DROP COLUMN callback_timeout_seconds;
@@ -0,0 +1,4 @@
+-- How payment callbacks and payout events are sent to the merchant
+ALTER TABLE merchants ADD COLUMN callback_timeout_seconds INTEGER NOT NULL DEFAULT 5;
+ALTER TABLE merchants ADD COLUMN callback_headers JSONB;
+ALTER TABLE merchants ADD COLUMN callback_verify_tls BOOLEAN NOT NULL DEFAULT TRUE;
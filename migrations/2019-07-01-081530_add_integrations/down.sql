@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX payout_events_unnotified_idx;
+ALTER TABLE payout_events DROP COLUMN notified_at;
+ALTER TABLE merchants DROP COLUMN slack_webhook_url;
+ALTER TABLE merchants DROP COLUMN telegram_chat_id;
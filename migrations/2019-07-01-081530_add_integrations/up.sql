@@ -0,0 +1,7 @@
+-- Chats payment and payout events are pushed to
+ALTER TABLE merchants ADD COLUMN telegram_chat_id TEXT;
+ALTER TABLE merchants ADD COLUMN slack_webhook_url TEXT;
+ALTER TABLE payout_events ADD COLUMN notified_at TIMESTAMP;
+-- Earlier events are not pushed
+UPDATE payout_events SET notified_at = NOW();
+CREATE INDEX payout_events_unnotified_idx ON payout_events (created_at) WHERE notified_at IS NULL;
@@ -0,0 +1,2 @@
+-- Reshapes callback bodies, see callback_template.rs
+ALTER TABLE merchants ADD COLUMN callback_template JSONB;
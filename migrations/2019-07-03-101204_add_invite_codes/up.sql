@@ -0,0 +1,9 @@
+-- Codes merchants register with under REGISTRATION_POLICY=invite
+CREATE TABLE invite_codes (
+  code TEXT PRIMARY KEY,
+  max_uses INTEGER NOT NULL CHECK (max_uses > 0),
+  uses INTEGER NOT NULL DEFAULT 0,
+  expires_at TIMESTAMP,
+  created_by TEXT NOT NULL REFERENCES merchants(id),
+  created_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,2 @@
+-- How the buyer's slate message is checked, see SlateMessageCheck
+ALTER TABLE merchants ADD COLUMN slate_message_check TEXT NOT NULL DEFAULT 'off';
@@ -0,0 +1,2 @@
+-- Key which signed the buyer's slate message, hex
+ALTER TABLE transactions ADD COLUMN payer_public_key TEXT;
@@ -0,0 +1,17 @@
+CREATE TABLE jobs (
+  id UUID PRIMARY KEY,
+  job_type TEXT NOT NULL,
+  key TEXT,
+  payload JSONB NOT NULL,
+  run_at TIMESTAMP NOT NULL,
+  attempts INTEGER NOT NULL DEFAULT 0,
+  last_error TEXT,
+  locked_by TEXT,
+  locked_until TIMESTAMP,
+  created_at TIMESTAMP NOT NULL,
+  failed_at TIMESTAMP
+);
+
+-- Only one unfinished job per key
+CREATE UNIQUE INDEX jobs_key_idx ON jobs (key) WHERE failed_at IS NULL;
+CREATE INDEX jobs_due_idx ON jobs (run_at) WHERE failed_at IS NULL;
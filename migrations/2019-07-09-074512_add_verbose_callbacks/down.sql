@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE transactions DROP COLUMN reported_status;
+ALTER TABLE merchants DROP COLUMN verbose_callbacks;
@@ -0,0 +1,4 @@
+-- Merchant gets callbacks for pending and in chain payments too
+ALTER TABLE merchants ADD COLUMN verbose_callbacks BOOLEAN NOT NULL DEFAULT false;
+-- Last non final status the merchant was called back about
+ALTER TABLE transactions ADD COLUMN reported_status transaction_status;
@@ -0,0 +1,2 @@
+-- Why the payment is refunded, see RefundReason
+ALTER TABLE transactions ADD COLUMN refund_reason TEXT;
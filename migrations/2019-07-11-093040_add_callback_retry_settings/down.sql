@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE merchants DROP COLUMN callback_retry_window_seconds;
+ALTER TABLE merchants DROP COLUMN callback_backoff_seconds;
+ALTER TABLE merchants DROP COLUMN callback_max_attempts;
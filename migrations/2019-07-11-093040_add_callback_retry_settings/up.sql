@@ -0,0 +1,4 @@
+-- Merchant's overrides of the callback retry defaults, NULL keeps the default
+ALTER TABLE merchants ADD COLUMN callback_max_attempts INT;
+ALTER TABLE merchants ADD COLUMN callback_backoff_seconds INT;
+ALTER TABLE merchants ADD COLUMN callback_retry_window_seconds INT;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP MATERIALIZED VIEW analytics_payer_wallets_daily;
+ALTER TABLE transactions DROP COLUMN payer_user_agent;
+ALTER TABLE transactions DROP COLUMN slate_version;
@@ -0,0 +1,15 @@
+-- What the buyer paid with, set when the slate arrives
+ALTER TABLE transactions ADD COLUMN slate_version INT;
+ALTER TABLE transactions ADD COLUMN payer_user_agent TEXT;
+
+-- Paid payments by the slate version and user agent of the buyer's wallet
+CREATE MATERIALIZED VIEW analytics_payer_wallets_daily AS
+SELECT created_at::date AS day,
+  slate_version,
+  COALESCE(payer_user_agent, '') AS user_agent,
+  COUNT(*) AS payments
+FROM transactions
+WHERE transaction_type = 'payment' AND slate_version IS NOT NULL
+GROUP BY 1, 2, 3;
+CREATE UNIQUE INDEX analytics_payer_wallets_daily_idx
+  ON analytics_payer_wallets_daily (day, slate_version, user_agent);
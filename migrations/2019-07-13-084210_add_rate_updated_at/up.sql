@@ -0,0 +1,2 @@
+-- When the rate the payment was last quoted with was fetched
+ALTER TABLE transactions ADD COLUMN rate_updated_at TIMESTAMP;
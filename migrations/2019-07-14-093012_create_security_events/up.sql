@@ -0,0 +1,11 @@
+CREATE TABLE security_events (
+  id UUID PRIMARY KEY,
+  merchant_id TEXT NOT NULL REFERENCES merchants(id),
+  kind TEXT NOT NULL,
+  ip TEXT,
+  created_at TIMESTAMP NOT NULL,
+  emailed_at TIMESTAMP,
+  dismissed BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE INDEX security_events_merchant_idx ON security_events (merchant_id, kind, created_at DESC);
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+DROP MATERIALIZED VIEW analytics_payment_countries_daily;
+ALTER TABLE security_events DROP COLUMN country;
+ALTER TABLE api_requests DROP COLUMN asn;
+ALTER TABLE api_requests DROP COLUMN country;
+ALTER TABLE transactions DROP COLUMN payer_asn;
+ALTER TABLE transactions DROP COLUMN payer_country;
@@ -0,0 +1,20 @@
+-- Coarse location of clients, see `geoip`. Payments get the location of
+-- the buyer who first opened the checkout page
+ALTER TABLE transactions ADD COLUMN payer_country TEXT;
+ALTER TABLE transactions ADD COLUMN payer_asn BIGINT;
+ALTER TABLE api_requests ADD COLUMN country TEXT;
+ALTER TABLE api_requests ADD COLUMN asn BIGINT;
+ALTER TABLE security_events ADD COLUMN country TEXT;
+
+-- Payments by the buyer's country, '' for unknown
+CREATE MATERIALIZED VIEW analytics_payment_countries_daily AS
+SELECT created_at::date AS day,
+  COALESCE(payer_country, '') AS country,
+  COUNT(*) AS payments,
+  COUNT(*) FILTER (WHERE status = 'confirmed') AS confirmed,
+  COALESCE(SUM(grin_amount) FILTER (WHERE status = 'confirmed'), 0)::BIGINT AS volume
+FROM transactions
+WHERE transaction_type = 'payment'
+GROUP BY 1, 2;
+CREATE UNIQUE INDEX analytics_payment_countries_daily_idx
+  ON analytics_payment_countries_daily (day, country);
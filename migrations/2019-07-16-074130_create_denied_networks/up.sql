@@ -0,0 +1,7 @@
+-- Networks admins denied submitting payments, see `deny_list`
+CREATE TABLE denied_networks (
+  network TEXT PRIMARY KEY,
+  reason TEXT NOT NULL,
+  created_by TEXT NOT NULL REFERENCES merchants(id),
+  created_at TIMESTAMP NOT NULL
+);
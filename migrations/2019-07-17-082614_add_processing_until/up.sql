@@ -0,0 +1,2 @@
+-- A slate of the payment is being received until then, see `ClaimPayment`
+ALTER TABLE transactions ADD COLUMN processing_until TIMESTAMP;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE feature_flag_overrides;
+DROP TABLE feature_flags;
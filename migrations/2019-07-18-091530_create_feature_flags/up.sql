@@ -0,0 +1,19 @@
+-- Flags admins toggle without a redeploy, see `feature_flags`. A flag
+-- without a row is at its default.
+CREATE TABLE feature_flags (
+  name TEXT PRIMARY KEY,
+  enabled BOOLEAN NOT NULL,
+  rollout_percent INTEGER NOT NULL DEFAULT 100 CHECK (rollout_percent BETWEEN 0 AND 100),
+  updated_by TEXT NOT NULL REFERENCES merchants(id),
+  updated_at TIMESTAMP NOT NULL
+);
+
+-- A merchant's override wins over the flag and its rollout
+CREATE TABLE feature_flag_overrides (
+  name TEXT NOT NULL,
+  merchant_id TEXT NOT NULL REFERENCES merchants(id),
+  enabled BOOLEAN NOT NULL,
+  updated_by TEXT NOT NULL REFERENCES merchants(id),
+  updated_at TIMESTAMP NOT NULL,
+  PRIMARY KEY (name, merchant_id)
+);
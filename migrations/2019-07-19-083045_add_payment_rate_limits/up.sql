@@ -0,0 +1,10 @@
+-- Plan the merchant's payment rate limit is taken from, see `rate_limit`
+ALTER TABLE merchants ADD COLUMN plan TEXT NOT NULL DEFAULT 'standard';
+
+-- Token buckets of payment creation as an instance last saved them, so
+-- limits survive restarts approximately
+CREATE TABLE rate_limit_buckets (
+  merchant_id TEXT PRIMARY KEY REFERENCES merchants(id),
+  tokens DOUBLE PRECISION NOT NULL,
+  updated_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE merchants DROP CONSTRAINT merchants_plan_fkey;
+DROP TABLE plans;
@@ -0,0 +1,19 @@
+-- Limits of merchant plans, see `plans`. NULL limits are unlimited.
+CREATE TABLE plans (
+  name TEXT PRIMARY KEY,
+  -- Payments created per calendar month (UTC)
+  monthly_payments BIGINT,
+  -- In nanogrins
+  max_payment_amount BIGINT,
+  -- Callbacks of a payment are retried at most this often
+  min_callback_backoff_seconds INTEGER NOT NULL DEFAULT 0,
+  api_burst INTEGER NOT NULL,
+  api_per_minute INTEGER NOT NULL
+);
+
+INSERT INTO plans VALUES
+  ('free', 100, 100000000000, 60, 10, 10),
+  ('standard', 10000, 10000000000000, 10, 60, 60),
+  ('enterprise', NULL, NULL, 0, 600, 600);
+
+ALTER TABLE merchants ADD CONSTRAINT merchants_plan_fkey FOREIGN KEY (plan) REFERENCES plans(name);
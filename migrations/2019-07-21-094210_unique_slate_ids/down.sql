@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX transactions_wallet_tx_slate_id_key;
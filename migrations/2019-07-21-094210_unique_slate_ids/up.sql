@@ -0,0 +1,2 @@
+-- A slate is received for one payment only, see `ClaimPayment`
+CREATE UNIQUE INDEX transactions_wallet_tx_slate_id_key ON transactions (wallet_tx_slate_id);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX transactions_unsent_reminders_idx;
+ALTER TABLE transactions DROP COLUMN reminder_sent_at;
+ALTER TABLE merchants DROP COLUMN payment_reminder_minutes;
@@ -0,0 +1,7 @@
+-- Buyers who left an email are reminded of a new payment after that many
+-- minutes, NULL sends no reminders
+ALTER TABLE merchants ADD COLUMN payment_reminder_minutes INTEGER;
+ALTER TABLE transactions ADD COLUMN reminder_sent_at TIMESTAMP;
+
+CREATE INDEX transactions_unsent_reminders_idx ON transactions (created_at)
+  WHERE status = 'new' AND email IS NOT NULL AND reminder_sent_at IS NULL;
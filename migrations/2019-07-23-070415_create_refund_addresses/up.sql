@@ -0,0 +1,15 @@
+-- Refund destinations of a merchant, see `refund_addresses`
+CREATE TABLE refund_addresses (
+  id UUID PRIMARY KEY,
+  merchant_id TEXT NOT NULL REFERENCES merchants(id),
+  label TEXT NOT NULL,
+  address TEXT NOT NULL,
+  created_at TIMESTAMP NOT NULL,
+  -- The end of the hold period the merchant can delete the address within
+  verified_at TIMESTAMP NOT NULL
+);
+
+CREATE UNIQUE INDEX refund_addresses_merchant_address_idx ON refund_addresses (merchant_id, address);
+
+-- Where the grins of a refunded payment are returned to
+ALTER TABLE transactions ADD COLUMN refund_address TEXT;
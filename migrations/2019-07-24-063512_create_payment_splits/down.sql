@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE payment_splits;
+ALTER TABLE merchants DROP COLUMN platform_id;
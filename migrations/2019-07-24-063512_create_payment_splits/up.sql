@@ -0,0 +1,14 @@
+-- Sellers of a marketplace, see `splits`. Only the platform a merchant
+-- joined may give it a share of its payments.
+ALTER TABLE merchants ADD COLUMN platform_id TEXT REFERENCES merchants(id);
+
+-- Shares of a payment credited to other merchants than its own
+CREATE TABLE payment_splits (
+  transaction_id UUID NOT NULL REFERENCES transactions(id) ON DELETE CASCADE,
+  merchant_id TEXT NOT NULL REFERENCES merchants(id),
+  -- In hundredths of a percent
+  share_bps INTEGER NOT NULL CHECK (share_bps > 0 AND share_bps <= 10000),
+  PRIMARY KEY (transaction_id, merchant_id)
+);
+
+CREATE INDEX payment_splits_merchant_id_idx ON payment_splits (merchant_id);
@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX transactions_referrer_idx;
+ALTER TABLE transactions DROP COLUMN referral_fee;
+ALTER TABLE transactions DROP COLUMN referrer_id;
+ALTER TABLE merchants DROP CONSTRAINT merchants_referral_check;
+ALTER TABLE merchants DROP COLUMN referral_share_bps;
+ALTER TABLE merchants DROP COLUMN referrer_id;
+ALTER TABLE plans DROP COLUMN fee_bps;
@@ -0,0 +1,14 @@
+-- Knockturn fee of payments in hundredths of a percent, see `plans`
+ALTER TABLE plans ADD COLUMN fee_bps INTEGER NOT NULL DEFAULT 0 CHECK (fee_bps >= 0 AND fee_bps <= 10000);
+
+-- Who referred the merchant and their share of its knockturn fees, in
+-- hundredths of a percent, see `referrals`
+ALTER TABLE merchants ADD COLUMN referrer_id TEXT REFERENCES merchants(id);
+ALTER TABLE merchants ADD COLUMN referral_share_bps INTEGER CHECK (referral_share_bps > 0 AND referral_share_bps <= 10000);
+ALTER TABLE merchants ADD CONSTRAINT merchants_referral_check CHECK ((referrer_id IS NULL) = (referral_share_bps IS NULL));
+
+-- The referrer credited with `referral_fee` of the payment's knockturn fee
+ALTER TABLE transactions ADD COLUMN referrer_id TEXT REFERENCES merchants(id);
+ALTER TABLE transactions ADD COLUMN referral_fee BIGINT;
+
+CREATE INDEX transactions_referrer_idx ON transactions (referrer_id, updated_at) WHERE referrer_id IS NOT NULL;
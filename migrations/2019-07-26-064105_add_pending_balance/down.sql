@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE pending_credits;
+ALTER TABLE merchants DROP COLUMN pending_balance;
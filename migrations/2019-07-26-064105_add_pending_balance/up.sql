@@ -0,0 +1,15 @@
+-- Credited grins the merchant can't pay out yet, see `clearing`
+ALTER TABLE merchants ADD COLUMN pending_balance BIGINT NOT NULL DEFAULT 0;
+
+-- Credits of the pending balance moved to the available one at `available_at`
+CREATE TABLE pending_credits (
+  id UUID PRIMARY KEY,
+  transaction_id UUID NOT NULL REFERENCES transactions(id),
+  merchant_id TEXT NOT NULL REFERENCES merchants(id),
+  grin_amount BIGINT NOT NULL CHECK (grin_amount > 0),
+  created_at TIMESTAMP NOT NULL,
+  available_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX pending_credits_available_at_idx ON pending_credits (available_at);
+CREATE INDEX pending_credits_merchant_id_idx ON pending_credits (merchant_id);
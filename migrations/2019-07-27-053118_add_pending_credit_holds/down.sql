@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX pending_credits_transaction_id_idx;
+ALTER TABLE pending_credits DROP COLUMN held;
+ALTER TABLE pending_credits DROP COLUMN available_height;
@@ -0,0 +1,5 @@
+-- Credits also clear at a height, and an admin can hold them, see `clearing`
+ALTER TABLE pending_credits ADD COLUMN available_height BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE pending_credits ADD COLUMN held BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE INDEX pending_credits_transaction_id_idx ON pending_credits (transaction_id);
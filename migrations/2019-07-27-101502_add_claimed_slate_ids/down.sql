@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX transactions_claimed_slate_id_key;
+ALTER TABLE transactions DROP COLUMN claimed_slate_id;
@@ -0,0 +1,4 @@
+-- The slate a payment was claimed for, see `ClaimPayment`. A slate is
+-- claimed for one payment only, before the wallet receives it
+ALTER TABLE transactions ADD COLUMN claimed_slate_id TEXT;
+CREATE UNIQUE INDEX transactions_claimed_slate_id_key ON transactions (claimed_slate_id);
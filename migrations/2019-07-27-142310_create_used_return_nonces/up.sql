@@ -0,0 +1,10 @@
+-- Nonces of verified return payloads, see `return_url`. They're kept until
+-- their payload expired anyway
+CREATE TABLE used_return_nonces (
+  merchant_id TEXT NOT NULL REFERENCES merchants(id),
+  nonce TEXT NOT NULL,
+  used_at TIMESTAMP NOT NULL,
+  PRIMARY KEY (merchant_id, nonce)
+);
+
+CREATE INDEX used_return_nonces_used_at_idx ON used_return_nonces (used_at);
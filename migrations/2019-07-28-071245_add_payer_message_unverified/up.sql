@@ -0,0 +1,2 @@
+-- The buyer's slate message was signed, but the signature didn't verify
+ALTER TABLE transactions ADD COLUMN payer_message_unverified BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,13 @@
+-- Every change of a merchant's balance and pending balance by a payment,
+-- credits when it's reported and negative ones when an admin voids pending
+-- credits, for the dashboard's balance history. Payments credited before
+-- are not in the ledger.
+CREATE TABLE balance_credits (
+  id UUID PRIMARY KEY,
+  transaction_id UUID NOT NULL REFERENCES transactions(id),
+  merchant_id TEXT NOT NULL REFERENCES merchants(id),
+  grin_amount BIGINT NOT NULL,
+  created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX balance_credits_merchant_id_created_at_idx ON balance_credits (merchant_id, created_at);
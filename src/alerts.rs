@@ -0,0 +1,365 @@
+//! Alerts for admins who don't run Prometheus.
+//!
+//! Every instance checks its own metrics against `AlertConfig` every
+//! `ALERT_CHECK_SECONDS`. An alert which starts or stops firing on the
+//! leader is pushed to the chats of all admins, `alert_firing` shows what
+//! fires on each instance. `prometheus_rules` renders the same thresholds
+//! as Prometheus alerting rules for those who do run it.
+//!
+//! Callbacks are counted by the instance whose job worker ran them, the
+//! failure rate the leader sees only covers its own.
+
+use crate::db::{DbExecutor, GetAdmins};
+use crate::errors::Error;
+use crate::integrations::{Notifier, Notify};
+use crate::metrics;
+use crate::status::{self, GatewayHealth};
+use actix::Addr;
+use futures::future::{join_all, Future};
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::{HashSet, VecDeque};
+use std::env;
+use std::time::{Duration, Instant};
+use strum_macros::Display;
+
+pub const ALERT_CHECK_SECONDS: u64 = 60;
+/// Callbacks of the last 15 minutes make the failure rate
+const CALLBACK_WINDOW_SECONDS: u64 = 15 * 60;
+/// Fewer callbacks in the window say nothing about the rate
+const MIN_CALLBACKS: u64 = 10;
+
+lazy_static::lazy_static! {
+    pub static ref ALERT_CONFIG: AlertConfig = AlertConfig::from_env();
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AlertConfig {
+    /// Blocks the sync may be behind the node
+    pub node_lag_blocks: i64,
+    /// Share of failed callbacks, 0 to 100
+    pub callback_failure_percent: u64,
+    pub wallet_down_minutes: u64,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        AlertConfig {
+            node_lag_blocks: status::MAX_HEALTHY_HEIGHT_LAG,
+            callback_failure_percent: 20,
+            wallet_down_minutes: 5,
+        }
+    }
+}
+
+impl AlertConfig {
+    /// Reads ALERT_NODE_LAG_BLOCKS, ALERT_CALLBACK_FAILURE_PERCENT and
+    /// ALERT_WALLET_DOWN_MINUTES, defaults are used for unset ones
+    pub fn from_env() -> Self {
+        let default = AlertConfig::default();
+        let config = AlertConfig {
+            node_lag_blocks: env::var("ALERT_NODE_LAG_BLOCKS")
+                .map(|v| v.parse().expect("ALERT_NODE_LAG_BLOCKS must be a number"))
+                .unwrap_or(default.node_lag_blocks),
+            callback_failure_percent: env::var("ALERT_CALLBACK_FAILURE_PERCENT")
+                .map(|v| {
+                    v.parse()
+                        .expect("ALERT_CALLBACK_FAILURE_PERCENT must be a number")
+                })
+                .unwrap_or(default.callback_failure_percent),
+            wallet_down_minutes: env::var("ALERT_WALLET_DOWN_MINUTES")
+                .map(|v| {
+                    v.parse()
+                        .expect("ALERT_WALLET_DOWN_MINUTES must be a number")
+                })
+                .unwrap_or(default.wallet_down_minutes),
+        };
+        if config.callback_failure_percent > 100 {
+            panic!("ALERT_CALLBACK_FAILURE_PERCENT must be at most 100");
+        }
+        config
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Display)]
+pub enum Alert {
+    #[strum(serialize = "node_lag")]
+    NodeLag,
+    #[strum(serialize = "callback_failures")]
+    CallbackFailures,
+    #[strum(serialize = "wallet_down")]
+    WalletDown,
+}
+
+/// What the alerts are checked against
+#[derive(Debug, Clone)]
+pub struct Observation {
+    pub node_height_lag: Option<i64>,
+    pub wallet_up: Option<bool>,
+    /// Totals since the instance started
+    pub callbacks_delivered: u64,
+    pub callbacks_failed: u64,
+}
+
+impl Observation {
+    pub fn current() -> Self {
+        let health = GatewayHealth::current();
+        let (callbacks_delivered, callbacks_failed) = status::callback_totals();
+        Observation {
+            node_height_lag: health.node_height_lag,
+            wallet_up: health.wallet_up,
+            callbacks_delivered,
+            callbacks_failed,
+        }
+    }
+}
+
+/// An alert started (`firing`) or stopped firing
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertChange {
+    pub alert: Alert,
+    pub firing: bool,
+    pub text: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Alerts {
+    firing: HashSet<Alert>,
+    wallet_down_since: Option<Instant>,
+    /// Callback totals of the window, oldest first
+    callback_samples: VecDeque<(Instant, u64, u64)>,
+}
+
+impl Alerts {
+    pub fn evaluate(
+        &mut self,
+        config: &AlertConfig,
+        observation: &Observation,
+        now: Instant,
+    ) -> Vec<AlertChange> {
+        let node_lag = observation
+            .node_height_lag
+            .filter(|lag| *lag > config.node_lag_blocks)
+            .map(|lag| {
+                format!(
+                    "The sync is {} blocks behind the node, more than {}",
+                    lag, config.node_lag_blocks
+                )
+            });
+
+        match observation.wallet_up {
+            Some(false) => {
+                self.wallet_down_since.get_or_insert(now);
+            }
+            _ => self.wallet_down_since = None,
+        }
+        let wallet_down = self
+            .wallet_down_since
+            .map(|since| now.duration_since(since))
+            .filter(|down| *down >= Duration::from_secs(config.wallet_down_minutes * 60))
+            .map(|down| {
+                format!(
+                    "The wallet is unreachable for {} minutes",
+                    down.as_secs() / 60
+                )
+            });
+
+        self.callback_samples.push_back((
+            now,
+            observation.callbacks_delivered,
+            observation.callbacks_failed,
+        ));
+        while self.callback_samples.front().map_or(false, |(at, _, _)| {
+            now.duration_since(*at) > Duration::from_secs(CALLBACK_WINDOW_SECONDS)
+        }) {
+            self.callback_samples.pop_front();
+        }
+        let (_, delivered_before, failed_before) = self.callback_samples[0];
+        let failed = observation.callbacks_failed - failed_before;
+        let total = observation.callbacks_delivered - delivered_before + failed;
+        let callback_failures =
+            if total >= MIN_CALLBACKS && failed * 100 > config.callback_failure_percent * total {
+                Some(format!(
+                    "{} of {} callbacks in the last {} minutes failed, more than {}%",
+                    failed,
+                    total,
+                    CALLBACK_WINDOW_SECONDS / 60,
+                    config.callback_failure_percent
+                ))
+            } else {
+                None
+            };
+
+        let mut changes = Vec::new();
+        for (alert, problem) in vec![
+            (Alert::NodeLag, node_lag),
+            (Alert::CallbackFailures, callback_failures),
+            (Alert::WalletDown, wallet_down),
+        ] {
+            metrics::set(
+                "alert_firing",
+                &[("alert", &alert.to_string())],
+                problem.is_some() as i64,
+            );
+            match problem {
+                Some(text) => {
+                    if self.firing.insert(alert) {
+                        changes.push(AlertChange {
+                            alert,
+                            firing: true,
+                            text: format!("[alert] {}", text),
+                        });
+                    }
+                }
+                None => {
+                    if self.firing.remove(&alert) {
+                        changes.push(AlertChange {
+                            alert,
+                            firing: false,
+                            text: format!("[resolved] {}", alert),
+                        });
+                    }
+                }
+            }
+        }
+        changes
+    }
+}
+
+/// Pushes the changes to the chats of all admins, returns how many
+/// messages went through
+pub fn notify_admins(
+    db: Addr<DbExecutor>,
+    notifier: Addr<Notifier>,
+    changes: Vec<AlertChange>,
+) -> impl Future<Item = usize, Error = Error> {
+    db.send(GetAdmins)
+        .from_err()
+        .and_then(|db_response| {
+            let admins = db_response?;
+            Ok(admins)
+        })
+        .and_then(move |admins| {
+            let futures: Vec<_> = admins
+                .into_iter()
+                .flat_map(|admin| {
+                    changes
+                        .iter()
+                        .map(|change| Notify {
+                            merchant: admin.clone(),
+                            text: change.text.clone(),
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .map(|notify| {
+                    let admin_id = notify.merchant.id.clone();
+                    notifier
+                        .send(notify)
+                        .from_err()
+                        .and_then(|notifier_response| notifier_response)
+                        .then(move |res: Result<(), Error>| {
+                            if let Err(ref e) = res {
+                                warn!("Cannot push alert to admin {}: {}", admin_id, e);
+                            }
+                            Ok::<_, Error>(res.is_ok())
+                        })
+                })
+                .collect();
+            join_all(futures).map(|sent| {
+                let sent = sent.into_iter().filter(|sent| *sent).count();
+                info!("Pushed {} alert messages to admins", sent);
+                sent
+            })
+        })
+}
+
+/// Prometheus alerting rules with the thresholds of `config`
+pub fn prometheus_rules(config: &AlertConfig) -> String {
+    format!(
+        "groups:
+- name: knockturn
+  rules:
+  - alert: KnockturnNodeLag
+    expr: node_height_lag_blocks > {node_lag_blocks}
+    for: {check_seconds}s
+    annotations:
+      summary: The sync is more than {node_lag_blocks} blocks behind the node
+  - alert: KnockturnCallbackFailures
+    expr: >
+      100 * sum(increase(payment_callbacks_total{{result=\"failed\"}}[{window_minutes}m]))
+      / sum(increase(payment_callbacks_total[{window_minutes}m])) > {callback_failure_percent}
+      and sum(increase(payment_callbacks_total[{window_minutes}m])) >= {min_callbacks}
+    annotations:
+      summary: More than {callback_failure_percent}% of callbacks failed in {window_minutes} minutes
+  - alert: KnockturnWalletDown
+    expr: wallet_up == 0
+    for: {wallet_down_minutes}m
+    annotations:
+      summary: The wallet is unreachable for {wallet_down_minutes} minutes
+",
+        node_lag_blocks = config.node_lag_blocks,
+        check_seconds = ALERT_CHECK_SECONDS,
+        window_minutes = CALLBACK_WINDOW_SECONDS / 60,
+        callback_failure_percent = config.callback_failure_percent,
+        min_callbacks = MIN_CALLBACKS,
+        wallet_down_minutes = config.wallet_down_minutes,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observation(lag: i64, wallet_up: bool, delivered: u64, failed: u64) -> Observation {
+        Observation {
+            node_height_lag: Some(lag),
+            wallet_up: Some(wallet_up),
+            callbacks_delivered: delivered,
+            callbacks_failed: failed,
+        }
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let config = AlertConfig::default();
+        let mut alerts = Alerts::default();
+        let start = Instant::now();
+        let at = |minutes: u64| start + Duration::from_secs(minutes * 60);
+
+        assert!(alerts
+            .evaluate(&config, &observation(0, true, 100, 0), at(0))
+            .is_empty());
+
+        // The wallet has to be down for a while
+        assert!(alerts
+            .evaluate(&config, &observation(0, false, 100, 0), at(1))
+            .is_empty());
+        let changes = alerts.evaluate(&config, &observation(20, false, 100, 0), at(6));
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].alert, Alert::NodeLag);
+        assert_eq!(changes[1].alert, Alert::WalletDown);
+        assert!(changes.iter().all(|change| change.firing));
+        // Firing alerts aren't pushed again
+        assert!(alerts
+            .evaluate(&config, &observation(20, false, 100, 0), at(7))
+            .is_empty());
+
+        // 3 of 12 callbacks since the window started failed
+        let changes = alerts.evaluate(&config, &observation(0, true, 109, 3), at(8));
+        assert_eq!(changes.len(), 3);
+        assert_eq!(changes[0].alert, Alert::NodeLag);
+        assert!(!changes[0].firing);
+        assert_eq!(changes[1].alert, Alert::CallbackFailures);
+        assert!(changes[1].firing);
+        assert_eq!(changes[2].text, "[resolved] wallet_down");
+
+        // The failures left the window
+        let changes = alerts.evaluate(&config, &observation(0, true, 200, 3), at(30));
+        assert_eq!(changes.len(), 1);
+        assert!(!changes[0].firing);
+
+        let rules = prometheus_rules(&config);
+        assert!(rules.contains("expr: node_height_lag_blocks > 10"));
+        assert!(rules.contains("payment_callbacks_total{result=\"failed\"}[15m]"));
+    }
+}
@@ -0,0 +1,58 @@
+//! Unique amounts of new payments.
+//!
+//! With `PAYMENT_AMOUNT_TAGS=true` every new payment gets a tag, from 1 to
+//! `MAX_AMOUNT_TAG` nanogrins, added to its grin amount. The tag is the
+//! smallest one no other new payment of the merchant with the same amount
+//! has, so a merchant can tell which payment an amount which arrived in
+//! its wallet belongs to, even if the slate didn't go through the gateway.
+//! A unique index on new tagged payments catches concurrent creations.
+
+use std::env;
+
+/// Stays well within the 1_000_000 nanogrins a slate may exceed a payment by
+pub const MAX_AMOUNT_TAG: i64 = 999;
+
+lazy_static::lazy_static! {
+    pub static ref AMOUNT_TAGS: bool = from_env();
+}
+
+fn from_env() -> bool {
+    match env::var("PAYMENT_AMOUNT_TAGS") {
+        Ok(ref v) if v == "true" => true,
+        Ok(ref v) if v == "false" || v.is_empty() => false,
+        Ok(_) => panic!("PAYMENT_AMOUNT_TAGS must be true or false"),
+        Err(_) => false,
+    }
+}
+
+/// Smallest tag not in `taken`, `None` when all are taken
+pub fn free_tag(taken: &[i64]) -> Option<i64> {
+    (1..=MAX_AMOUNT_TAG).find(|tag| !taken.contains(tag))
+}
+
+/// Tags taken by `amounts` of new payments for the untagged `amount`
+pub fn taken_tags(amount: i64, amounts: &[i64]) -> Vec<i64> {
+    amounts
+        .iter()
+        .map(|tagged| tagged - amount)
+        .filter(|tag| *tag >= 1 && *tag <= MAX_AMOUNT_TAG)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_free_tag() {
+        assert_eq!(free_tag(&[]), Some(1));
+        let taken = taken_tags(
+            1_000_000_000,
+            &[1_000_000_001, 1_000_000_003, 1_000_001_000, 999_999_999],
+        );
+        assert_eq!(taken, vec![1, 3]);
+        assert_eq!(free_tag(&taken), Some(2));
+        let all: Vec<i64> = (1..=MAX_AMOUNT_TAG).collect();
+        assert_eq!(free_tag(&all), None);
+    }
+}
@@ -0,0 +1,328 @@
+//! Gateway-wide payment statistics for admins.
+//!
+//! Numbers come from materialized views over `transactions`, refreshed by
+//! the `refresh_views` cron job, so they lag behind by up to 10 minutes
+//! but never scan the transactions table on request. Confirmation
+//! latency is measured from creation to the last update of a confirmed
+//! payment, which is its confirmation.
+//!
+//! The fee report is the exception, it reads payouts from `transactions`
+//! as they are few. It compares the transfer fee charged to the merchant,
+//! `TRANSFER_FEE` unless the payout has its own, with the fee the wallet
+//! actually paid, the difference is the gateway's fee margin. Payouts
+//! whose actual fee was higher are underpriced.
+
+use crate::fsm::TRANSFER_FEE;
+use crate::models::Transaction;
+use chrono::{NaiveDate, NaiveDateTime};
+use diesel::sql_types::{BigInt, Date, Integer, Text, Timestamp};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    Hour,
+    Day,
+}
+
+impl Granularity {
+    /// Field name for `date_trunc`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Granularity::Hour => "hour",
+            Granularity::Day => "day",
+        }
+    }
+}
+
+/// Payments created in one hour or day, `volume` is in nanogrins
+#[derive(Debug, Serialize, QueryableByName)]
+pub struct VolumeBucket {
+    #[sql_type = "Timestamp"]
+    pub period: NaiveDateTime,
+    #[sql_type = "BigInt"]
+    pub created: i64,
+    #[sql_type = "BigInt"]
+    pub confirmed: i64,
+    #[sql_type = "BigInt"]
+    pub volume: i64,
+}
+
+/// Payments created at an hour (0 - 23, UTC) of a weekday (1 is Monday)
+#[derive(Debug, Serialize, QueryableByName)]
+pub struct HeatmapCell {
+    #[sql_type = "Integer"]
+    pub weekday: i32,
+    #[sql_type = "Integer"]
+    pub hour: i32,
+    #[sql_type = "BigInt"]
+    pub created: i64,
+}
+
+#[derive(Debug, Serialize, QueryableByName)]
+pub struct MerchantVolume {
+    #[sql_type = "Text"]
+    pub merchant_id: String,
+    #[sql_type = "BigInt"]
+    pub payments: i64,
+    #[sql_type = "BigInt"]
+    pub volume: i64,
+}
+
+/// Paid payments by what the buyer's wallet sent, `user_agent` is empty
+/// for wallets which didn't send one
+#[derive(Debug, Serialize, QueryableByName)]
+pub struct PayerWallets {
+    #[sql_type = "Integer"]
+    pub slate_version: i32,
+    #[sql_type = "Text"]
+    pub user_agent: String,
+    #[sql_type = "BigInt"]
+    pub payments: i64,
+    #[sql_type = "Date"]
+    pub last_seen: NaiveDate,
+}
+
+/// Payments by the country the buyer opened the checkout page from,
+/// `country` is empty when it's unknown
+#[derive(Debug, Serialize, QueryableByName)]
+pub struct PaymentCountries {
+    #[sql_type = "Text"]
+    pub country: String,
+    #[sql_type = "BigInt"]
+    pub payments: i64,
+    #[sql_type = "BigInt"]
+    pub confirmed: i64,
+    /// Nanogrins of the confirmed payments
+    #[sql_type = "BigInt"]
+    pub volume: i64,
+}
+
+/// Finished payments of a merchant waiting for a callback
+#[derive(Debug, Serialize, QueryableByName)]
+pub struct UnreportedPayments {
+    #[sql_type = "Text"]
+    pub merchant_id: String,
+    #[sql_type = "Text"]
+    pub status: String,
+    #[sql_type = "BigInt"]
+    pub payments: i64,
+    #[sql_type = "Timestamp"]
+    pub oldest_updated_at: NaiveDateTime,
+    #[sql_type = "Integer"]
+    pub max_report_attempts: i32,
+}
+
+/// Sums the summary is calculated from
+#[derive(Debug, QueryableByName)]
+pub struct AnalyticsTotals {
+    #[sql_type = "BigInt"]
+    pub created: i64,
+    #[sql_type = "BigInt"]
+    pub confirmed: i64,
+    #[sql_type = "BigInt"]
+    pub confirmation_seconds: i64,
+    #[sql_type = "BigInt"]
+    pub callbacks_succeeded: i64,
+    #[sql_type = "BigInt"]
+    pub callbacks_failed: i64,
+}
+
+/// Rates are `None` when there is nothing to divide by
+#[derive(Debug, Serialize)]
+pub struct AnalyticsSummary {
+    pub since: NaiveDate,
+    pub created: i64,
+    pub confirmed: i64,
+    pub conversion_rate: Option<f64>,
+    pub avg_confirmation_seconds: Option<i64>,
+    pub callbacks_succeeded: i64,
+    pub callbacks_failed: i64,
+    pub callback_success_rate: Option<f64>,
+}
+
+impl AnalyticsSummary {
+    pub fn new(since: NaiveDate, totals: AnalyticsTotals) -> Self {
+        let callbacks = totals.callbacks_succeeded + totals.callbacks_failed;
+        AnalyticsSummary {
+            since,
+            created: totals.created,
+            confirmed: totals.confirmed,
+            conversion_rate: ratio(totals.confirmed, totals.created),
+            avg_confirmation_seconds: if totals.confirmed > 0 {
+                Some(totals.confirmation_seconds / totals.confirmed)
+            } else {
+                None
+            },
+            callbacks_succeeded: totals.callbacks_succeeded,
+            callbacks_failed: totals.callbacks_failed,
+            callback_success_rate: ratio(totals.callbacks_succeeded, callbacks),
+        }
+    }
+}
+
+/// Transfer fees of the payouts started in one hour or day, in nanogrins
+#[derive(Debug, Serialize, QueryableByName)]
+pub struct FeeBucket {
+    #[sql_type = "Timestamp"]
+    pub period: NaiveDateTime,
+    #[sql_type = "BigInt"]
+    pub payouts: i64,
+    #[sql_type = "BigInt"]
+    pub charged: i64,
+    #[sql_type = "BigInt"]
+    pub actual: i64,
+    #[sql_type = "BigInt"]
+    pub underpriced: i64,
+}
+
+/// Fee buckets and their totals, `margin` is negative when the wallet
+/// paid more than merchants were charged
+#[derive(Debug, Serialize)]
+pub struct FeeReport {
+    pub since: NaiveDate,
+    pub payouts: i64,
+    pub charged: i64,
+    pub actual: i64,
+    pub margin: i64,
+    /// Margin per charged nanogrin
+    pub margin_rate: Option<f64>,
+    pub underpriced: i64,
+    pub buckets: Vec<FeeBucket>,
+}
+
+impl FeeReport {
+    pub fn new(since: NaiveDate, buckets: Vec<FeeBucket>) -> Self {
+        let sum = |f: fn(&FeeBucket) -> i64| buckets.iter().map(f).sum::<i64>();
+        let charged = sum(|b| b.charged);
+        let actual = sum(|b| b.actual);
+        FeeReport {
+            since,
+            payouts: sum(|b| b.payouts),
+            charged,
+            actual,
+            margin: charged - actual,
+            margin_rate: if charged > 0 {
+                Some((charged - actual) as f64 / charged as f64)
+            } else {
+                None
+            },
+            underpriced: sum(|b| b.underpriced),
+            buckets,
+        }
+    }
+}
+
+/// Payout whose wallet fee exceeded the charged fee
+#[derive(Debug, Serialize, QueryableByName)]
+pub struct UnderpricedPayout {
+    #[sql_type = "Text"]
+    pub id: String,
+    #[sql_type = "Text"]
+    pub merchant_id: String,
+    #[sql_type = "Text"]
+    pub status: String,
+    #[sql_type = "Timestamp"]
+    pub created_at: NaiveDateTime,
+    #[sql_type = "BigInt"]
+    pub charged: i64,
+    #[sql_type = "BigInt"]
+    pub actual: i64,
+}
+
+/// Transfer fee the merchant is charged for a payout
+pub fn charged_fee(payout: &Transaction) -> i64 {
+    payout.transfer_fee.unwrap_or(TRANSFER_FEE)
+}
+
+/// Whether the wallet paid more for the payout than it was charged
+pub fn is_underpriced(payout: &Transaction) -> bool {
+    payout
+        .real_transfer_fee
+        .map_or(false, |actual| actual > charged_fee(payout))
+}
+
+fn ratio(part: i64, total: i64) -> Option<f64> {
+    if total > 0 {
+        Some(part as f64 / total as f64)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::tests::create_tx;
+
+    #[test]
+    fn test_summary() {
+        let since = NaiveDate::from_ymd(2019, 6, 1);
+        let summary = AnalyticsSummary::new(
+            since,
+            AnalyticsTotals {
+                created: 8,
+                confirmed: 6,
+                confirmation_seconds: 1800,
+                callbacks_succeeded: 6,
+                callbacks_failed: 2,
+            },
+        );
+        assert_eq!(summary.conversion_rate, Some(0.75));
+        assert_eq!(summary.avg_confirmation_seconds, Some(300));
+        assert_eq!(summary.callback_success_rate, Some(0.75));
+
+        let empty = AnalyticsSummary::new(
+            since,
+            AnalyticsTotals {
+                created: 0,
+                confirmed: 0,
+                confirmation_seconds: 0,
+                callbacks_succeeded: 0,
+                callbacks_failed: 0,
+            },
+        );
+        assert_eq!(empty.conversion_rate, None);
+        assert_eq!(empty.avg_confirmation_seconds, None);
+        assert_eq!(empty.callback_success_rate, None);
+    }
+
+    #[test]
+    fn test_fee_report() {
+        let since = NaiveDate::from_ymd(2019, 7, 1);
+        let bucket = |day, payouts, charged, actual, underpriced| FeeBucket {
+            period: NaiveDate::from_ymd(2019, 7, day).and_hms(0, 0, 0),
+            payouts,
+            charged,
+            actual,
+            underpriced,
+        };
+        let report = FeeReport::new(
+            since,
+            vec![
+                bucket(1, 2, 16_000_000, 12_000_000, 0),
+                bucket(2, 1, 8_000_000, 10_000_000, 1),
+            ],
+        );
+        assert_eq!(report.payouts, 3);
+        assert_eq!(report.margin, 2_000_000);
+        assert_eq!(report.margin_rate, Some(2.0 / 24.0));
+        assert_eq!(report.underpriced, 1);
+        assert_eq!(FeeReport::new(since, vec![]).margin_rate, None);
+    }
+
+    #[test]
+    fn test_is_underpriced() {
+        let mut payout = create_tx();
+        payout.transfer_fee = None;
+        payout.real_transfer_fee = None;
+        assert!(!is_underpriced(&payout));
+        payout.real_transfer_fee = Some(TRANSFER_FEE);
+        assert!(!is_underpriced(&payout));
+        payout.real_transfer_fee = Some(TRANSFER_FEE + 1);
+        assert!(is_underpriced(&payout));
+        payout.transfer_fee = Some(TRANSFER_FEE * 2);
+        assert_eq!(charged_fee(&payout), TRANSFER_FEE * 2);
+        assert!(!is_underpriced(&payout));
+    }
+}
@@ -0,0 +1,161 @@
+//! Versions of the merchant API.
+//!
+//! The merchant API is served under `/api/{version}`, e.g.
+//! `/api/v1/merchants/{merchant_id}/payments`. The unversioned routes are
+//! aliases of `LEGACY` and stay so, integrations which never picked a
+//! version keep working. On them a client may ask for a version with the
+//! `Api-Version` header, the path wins on versioned routes. A breaking
+//! change (a new callback format, another error envelope) ships as the next
+//! version and its handlers branch on `ApiVersion::of`, additive changes
+//! land in every version. API responses carry the version they were
+//! answered in in `Api-Version`. API_CHANGELOG.md lists the changes.
+
+use crate::app::AppState;
+use crate::errors::Error;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::{Middleware, Response, Started};
+use actix_web::{HttpRequest, HttpResponse, Result};
+use serde::Serialize;
+use strum_macros::{Display, EnumString};
+
+pub const API_VERSION_HEADER: &str = "api-version";
+/// Versioned routes are below it
+pub const VERSIONED_PREFIX: &str = "/api/";
+/// Unversioned routes of the API
+const LEGACY_PREFIXES: &[&str] = &["/merchants", "/meta/"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Display, EnumString)]
+pub enum ApiVersion {
+    #[strum(serialize = "v1")]
+    #[serde(rename = "v1")]
+    V1,
+}
+
+impl ApiVersion {
+    /// Oldest first
+    pub const ALL: &'static [ApiVersion] = &[ApiVersion::V1];
+    pub const LATEST: ApiVersion = ApiVersion::V1;
+    /// What unversioned routes answer in, it never changes
+    pub const LEGACY: ApiVersion = ApiVersion::V1;
+
+    pub fn prefix(self) -> String {
+        format!("{}{}", VERSIONED_PREFIX, self)
+    }
+
+    /// Version a path is served in, `None` for paths outside the API. An
+    /// unversioned path is answered in `requested` or `LEGACY`.
+    pub fn of_path(path: &str, requested: Option<&str>) -> Result<Option<Self>, Error> {
+        if path.starts_with(VERSIONED_PREFIX) {
+            let version = path[VERSIONED_PREFIX.len()..]
+                .split('/')
+                .next()
+                .unwrap_or("");
+            return Ok(version.parse().ok());
+        }
+        if !LEGACY_PREFIXES
+            .iter()
+            .any(|prefix| path.starts_with(prefix))
+        {
+            return Ok(None);
+        }
+        match requested {
+            Some(version) => version
+                .trim()
+                .to_lowercase()
+                .parse()
+                .map(Some)
+                .map_err(|_| Error::UnsupportedApiVersion(version.to_owned())),
+            None => Ok(Some(ApiVersion::LEGACY)),
+        }
+    }
+
+    /// Version the request is answered in, `LEGACY` outside the API
+    pub fn of<S>(req: &HttpRequest<S>) -> Self {
+        ApiVersion::of_path(req.path(), requested(req))
+            .ok()
+            .and_then(|version| version)
+            .unwrap_or(ApiVersion::LEGACY)
+    }
+}
+
+fn requested<S>(req: &HttpRequest<S>) -> Option<&str> {
+    req.headers()
+        .get(API_VERSION_HEADER)
+        .and_then(|value| value.to_str().ok())
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    pub version: ApiVersion,
+    pub prefix: String,
+    pub latest: bool,
+    pub legacy: bool,
+}
+
+pub fn versions() -> Vec<VersionInfo> {
+    ApiVersion::ALL
+        .iter()
+        .map(|version| VersionInfo {
+            version: *version,
+            prefix: version.prefix(),
+            latest: *version == ApiVersion::LATEST,
+            legacy: *version == ApiVersion::LEGACY,
+        })
+        .collect()
+}
+
+/// Refuses versions we don't have and tells the client the version of
+/// the answer
+pub struct ApiVersioning;
+
+impl Middleware<AppState> for ApiVersioning {
+    fn start(&self, req: &HttpRequest<AppState>) -> Result<Started> {
+        ApiVersion::of_path(req.path(), requested(req))?;
+        Ok(Started::Done)
+    }
+
+    fn response(&self, req: &HttpRequest<AppState>, mut resp: HttpResponse) -> Result<Response> {
+        if let Ok(Some(version)) = ApiVersion::of_path(req.path(), requested(req)) {
+            if let Ok(value) = HeaderValue::from_str(&version.to_string()) {
+                resp.headers_mut()
+                    .insert(HeaderName::from_static(API_VERSION_HEADER), value);
+            }
+        }
+        Ok(Response::Done(resp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_of_path() {
+        let v1 = Some(ApiVersion::V1);
+        assert_eq!(ApiVersion::V1.prefix(), "/api/v1");
+        assert_eq!(
+            ApiVersion::of_path("/api/v1/merchants/shop/payments", None).unwrap(),
+            v1
+        );
+        // The path wins over the header
+        assert_eq!(
+            ApiVersion::of_path("/api/v1/merchants/shop", Some("v9")).unwrap(),
+            v1
+        );
+        assert_eq!(
+            ApiVersion::of_path("/api/v9/merchants", None).unwrap(),
+            None
+        );
+        assert_eq!(ApiVersion::of_path("/merchants/shop", None).unwrap(), v1);
+        assert_eq!(
+            ApiVersion::of_path("/merchants/shop", Some(" V1")).unwrap(),
+            v1
+        );
+        assert!(ApiVersion::of_path("/merchants/shop", Some("v9")).is_err());
+        assert_eq!(
+            ApiVersion::of_path("/meta/payment-states", None).unwrap(),
+            v1
+        );
+        assert_eq!(ApiVersion::of_path("/login", Some("v9")).unwrap(), None);
+    }
+}
@@ -1,41 +1,76 @@
+use crate::api_version::{ApiVersion, ApiVersioning};
+use crate::captcha::Captcha;
+use crate::compression::Compression;
+use crate::cron::Cron;
 use crate::db::DbExecutor;
+use crate::deny_list::DenyPayers;
 use crate::fsm::Fsm;
 use crate::handlers::*;
+use crate::integrations::Notifier;
+use crate::middleware::ApiRequestLogger;
+use crate::oidc::OidcClient;
+use crate::registration::Registration;
+use crate::trace::TraceRequests;
 use crate::wallet::Wallet;
 use actix::prelude::*;
 use actix_web::middleware::identity::{CookieIdentityPolicy, IdentityService};
 use actix_web::middleware::session::{CookieSessionBackend, SessionStorage};
 use actix_web::{http::Method, middleware, App};
-use diesel::pg::PgConnection;
-use diesel::r2d2::{ConnectionManager, Pool};
 use sentry_actix::SentryMiddleware;
 
 pub struct AppState {
     pub db: Addr<DbExecutor>,
     pub wallet: Wallet,
-    pub pool: Pool<ConnectionManager<PgConnection>>,
     pub fsm: Addr<Fsm>,
+    pub cron: Addr<Cron>,
+    pub notifier: Addr<Notifier>,
+    pub oidc: Option<OidcClient>,
+    pub registration: Registration,
+    pub captcha: Option<Captcha>,
+}
+
+/// Routes an app serves, with `INTERNAL_HOST` set the public listeners
+/// serve only the checkout
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Routes {
+    All,
+    Checkout,
+    Internal,
 }
 
 pub fn create_app(
     db: Addr<DbExecutor>,
     wallet: Wallet,
     fsm: Addr<Fsm>,
-    pool: Pool<ConnectionManager<PgConnection>>,
+    cron: Addr<Cron>,
+    notifier: Addr<Notifier>,
+    oidc: Option<OidcClient>,
+    registration: Registration,
+    captcha: Option<Captcha>,
     cookie_secret: &[u8],
     enable_sentry: bool,
+    routes: Routes,
 ) -> App<AppState> {
     let state = AppState {
         db,
         wallet,
         fsm,
-        pool,
+        cron,
+        notifier,
+        oidc,
+        registration,
+        captcha,
     };
     let mut app = App::with_state(state);
     if enable_sentry {
         app = app.middleware(SentryMiddleware::new());
     }
-    app.middleware(middleware::Logger::new("\"%r\" %s %b %Dms"))
+    let app = app
+        .middleware(TraceRequests)
+        .middleware(middleware::Logger::new("\"%r\" %s %b %Dms"))
+        .middleware(Compression)
+        .middleware(ApiRequestLogger)
+        .middleware(ApiVersioning)
         .middleware(IdentityService::new(
             CookieIdentityPolicy::new(cookie_secret)
                 .name("auth-example")
@@ -43,49 +78,363 @@ pub fn create_app(
         ))
         .middleware(SessionStorage::new(
             CookieSessionBackend::private(cookie_secret).secure(false),
-        ))
-        .resource("/merchants", |r| {
+        ));
+    match routes {
+        Routes::All => checkout_routes(internal_routes(app)),
+        Routes::Checkout => checkout_routes(app),
+        Routes::Internal => internal_routes(app),
+    }
+}
+
+/// Merchant API, dashboard, admin pages and metrics
+fn internal_routes(app: App<AppState>) -> App<AppState> {
+    let app = api_routes(app, &ApiVersion::V1.prefix());
+    api_routes(app, "")
+        .resource("/login", |r| {
+            r.method(Method::POST).with(webui::login);
+            r.method(Method::GET).with(webui::login_form);
+        })
+        .resource("/logout", |r| r.method(Method::POST).with(webui::logout))
+        .resource("/security_alerts/dismiss", |r| {
+            r.method(Method::POST).with(webui::dismiss_security_alerts);
+        })
+        .resource("/auth/oidc/login", |r| {
+            r.method(Method::GET).with(oidc::login);
+        })
+        .resource("/auth/oidc/callback", |r| {
+            r.method(Method::GET).with(oidc::callback);
+        })
+        .resource("/", |r| {
+            r.method(Method::GET).with(webui::index);
+        })
+        .resource("/set_2fa", |r| {
+            r.method(Method::GET).with(mfa::get_totp);
+            r.method(Method::POST).with(mfa::post_totp);
+        })
+        .resource("/2fa", |r| {
+            r.method(Method::GET).with(mfa::form_2fa);
+            r.method(Method::POST).with(mfa::post_2fa);
+        })
+        .resource("/2fa/security_key/challenge", |r| {
+            r.method(Method::POST).with(security_key::authentication_challenge);
+        })
+        .resource("/2fa/security_key", |r| {
+            r.method(Method::POST).with(security_key::authenticate);
+        })
+        .resource("/security_keys", |r| {
+            r.method(Method::GET).with(security_key::security_keys);
+            r.method(Method::POST).with(security_key::register);
+        })
+        .resource("/security_keys/challenge", |r| {
+            r.method(Method::POST).with(security_key::registration_challenge);
+        })
+        .resource("/security_keys/{credential_id}/delete", |r| {
+            r.method(Method::POST).with(security_key::delete);
+        })
+        .resource("/second_factor", |r| {
+            r.method(Method::POST).with(security_key::set_second_factor);
+        })
+            .resource("/transactions", |r| {
+            r.method(Method::GET).with(webui::get_transactions)
+        })
+        .resource("/transactions/{transaction_id}", |r| {
+            r.method(Method::GET).with(note::transaction);
+        })
+        .resource("/transactions/{transaction_id}/notes", |r| {
+            r.method(Method::POST).with(note::add_note);
+        })
+        .resource("/transactions/{transaction_id}/refund_address", |r| {
+            r.method(Method::POST).with(refund_address::set_refund_address);
+        })
+        .resource("/api_requests", |r| {
+            r.method(Method::GET).with(webui::get_api_requests)
+        })
+        .resource("/api_tokens", |r| {
+            r.method(Method::GET).with(api_token::api_tokens);
+            r.method(Method::POST).with(api_token::create);
+        })
+        .resource("/api_tokens/{token_id}/delete", |r| {
+            r.method(Method::POST).with(api_token::delete);
+        })
+        .resource("/refund_addresses", |r| {
+            r.method(Method::GET).with(refund_address::refund_addresses);
+            r.method(Method::POST).with(refund_address::create);
+        })
+        .resource("/refund_addresses/{address_id}/delete", |r| {
+            r.method(Method::POST).with(refund_address::delete);
+        })
+        .resource("/marketplace", |r| {
+            r.method(Method::GET).with(marketplace::marketplace);
+            r.method(Method::POST).with(marketplace::join_platform);
+        })
+        .resource("/referrals", |r| {
+            r.method(Method::GET).with(referral::referrals);
+        })
+        .resource("/email_branding", |r| {
+            r.method(Method::GET).with(email_branding::email_branding);
+            r.method(Method::POST).with(email_branding::update_email_branding);
+        })
+        .resource("/email_branding/preview", |r| {
+            r.method(Method::GET).with(email_branding::preview);
+        })
+        .resource("/slate_message", |r| {
+            r.method(Method::GET).with(slate_message::slate_message);
+            r.method(Method::POST).with(slate_message::update_slate_message);
+        })
+        .resource("/timezone", |r| {
+            r.method(Method::GET).with(timezone::timezone);
+            r.method(Method::POST).with(timezone::update_timezone);
+        })
+        .resource("/invoice_numbers", |r| {
+            r.method(Method::GET).with(invoice_numbers::invoice_numbers);
+            r.method(Method::POST).with(invoice_numbers::update_invoice_prefix);
+        })
+        .resource("/callback_settings", |r| {
+            r.method(Method::GET).with(callback_settings::callback_settings);
+            r.method(Method::POST).with(callback_settings::update_callback_settings);
+        })
+        .resource("/integrations", |r| {
+            r.method(Method::GET).with(integrations::integrations);
+            r.method(Method::POST).with(integrations::update_integrations);
+        })
+        .resource("/integrations/test", |r| {
+            r.method(Method::POST).with(integrations::test_integrations);
+        })
+        .resource("/admin/reconciliation", |r| {
+            r.method(Method::GET).with(admin::reconciliation);
+        })
+        .resource("/admin/alerts/rules", |r| {
+            r.method(Method::GET).with(admin::alert_rules);
+        })
+        .resource("/admin/reconciliation/cancel-stale", |r| {
+            r.method(Method::POST).with(admin::cancel_stale_transactions);
+        })
+        .resource("/admin/chain", |r| {
+            r.method(Method::GET).with(admin::chain_status);
+        })
+        .resource("/admin/transactions/{transaction_id}/transition", |r| {
+            r.method(Method::POST).with(admin::transition_transaction);
+        })
+        .resource("/admin/transactions/{transaction_id}/credits", |r| {
+            r.method(Method::POST).with(admin::change_pending_credits);
+        })
+        .resource("/admin/sync/replay", |r| {
+            r.method(Method::POST).with(admin::replay_blocks);
+        })
+        .resource("/admin/wallet", |r| {
+            r.method(Method::GET).with(admin::wallet_outputs);
+        })
+        .resource("/admin/wallet/consolidate", |r| {
+            r.method(Method::POST).with(admin::consolidate_outputs);
+        })
+        .resource("/admin/analytics/volume", |r| {
+            r.method(Method::GET).with(admin::analytics_volume);
+        })
+        .resource("/admin/analytics/heatmap", |r| {
+            r.method(Method::GET).with(admin::analytics_heatmap);
+        })
+        .resource("/admin/analytics/merchants", |r| {
+            r.method(Method::GET).with(admin::analytics_merchants);
+        })
+        .resource("/admin/analytics/summary", |r| {
+            r.method(Method::GET).with(admin::analytics_summary);
+        })
+        .resource("/admin/analytics/wallets", |r| {
+            r.method(Method::GET).with(admin::analytics_wallets);
+        })
+        .resource("/admin/analytics/countries", |r| {
+            r.method(Method::GET).with(admin::analytics_countries);
+        })
+        .resource("/admin/analytics/unreported", |r| {
+            r.method(Method::GET).with(admin::analytics_unreported);
+        })
+        .resource("/admin/analytics/fees", |r| {
+            r.method(Method::GET).with(admin::analytics_fees);
+        })
+        .resource("/admin/analytics/fees/underpriced", |r| {
+            r.method(Method::GET).with(admin::analytics_underpriced_payouts);
+        })
+        .resource("/admin/invite_codes", |r| {
+            r.method(Method::GET).with(admin::invite_codes);
+            r.method(Method::POST).with(admin::create_invite_code);
+        })
+        .resource("/admin/merchants/{merchant_id}/reset_2fa", |r| {
+            r.method(Method::POST).with(admin::reset_2fa);
+        })
+        .resource("/admin/merchants/{merchant_id}/referral", |r| {
+            r.method(Method::POST).with(admin::set_referral);
+        })
+        .resource("/admin/invite_codes/{code}/delete", |r| {
+            r.method(Method::POST).with(admin::delete_invite_code);
+        })
+        .resource("/admin/deny_list", |r| {
+            r.method(Method::GET).with(admin::deny_list);
+            r.method(Method::POST).with(admin::create_denied_network);
+        })
+        .resource("/admin/deny_list/delete", |r| {
+            r.method(Method::POST).with(admin::delete_denied_network);
+        })
+        .resource("/admin/feature_flags", |r| {
+            r.method(Method::GET).with(admin::feature_flags);
+            r.method(Method::POST).with(admin::set_feature_flag);
+        })
+        .resource("/admin/feature_flags/overrides", |r| {
+            r.method(Method::POST).with(admin::set_feature_flag_override);
+        })
+        .resource("/admin/feature_flags/overrides/delete", |r| {
+            r.method(Method::POST).with(admin::delete_feature_flag_override);
+        })
+        .resource("/metrics", |r| {
+            r.method(Method::GET).with(get_metrics);
+        })
+}
+
+/// Merchant API below `prefix`, unversioned routes are registered with
+/// an empty one, see `api_version`
+fn api_routes(app: App<AppState>, prefix: &str) -> App<AppState> {
+    let path = |path: &str| format!("{}{}", prefix, path);
+    app
+        .resource(&path("/merchants"), |r| {
             r.method(Method::POST).with(create_merchant)
         })
-        .resource("/merchants/{merchant_id}", |r| {
+        .resource(&path("/merchants/{merchant_id}"), |r| {
             r.method(Method::GET).with(get_merchant)
         })
-        .resource("/merchants/{merchant_id}/payments", |r| {
-            r.method(Method::POST).with(payment::create_payment)
+        .resource(&path("/merchants/{merchant_id}/callback/test"), |r| {
+            r.method(Method::POST).with(test_callback)
+        })
+        .resource(&path("/merchants/{merchant_id}/jwt"), |r| {
+            r.method(Method::POST).with(issue_jwt)
+        })
+        .resource(&path("/merchants/{merchant_id}/return_payload/verify"), |r| {
+            r.method(Method::POST).with(payment::verify_return_payload);
         })
-        .resource("/merchants/{merchant_id}/payments/{transaction_id}", |r| {
-            r.method(Method::GET).with(payment::get_payment);
-            r.method(Method::POST).with(payment::make_payment);
+        .resource(&path("/merchants/{merchant_id}/payments"), |r| {
+            r.method(Method::POST).with(payment::create_payment);
+            r.method(Method::GET).with(payment::get_payments);
+        })
+        .resource(&path("/merchants/{merchant_id}/payments/batch"), |r| {
+            r.method(Method::POST).with(payment::create_payments_batch);
+        })
+        .resource(&path("/merchants/{merchant_id}/payments/status"), |r| {
+            r.method(Method::POST).with(payment::get_payments_status);
         })
         .resource(
-            "/merchants/{merchant_id}/payments/{transaction_id}/status",
+            &path("/merchants/{merchant_id}/payments/{transaction_id}/status"),
             |r| {
                 r.method(Method::GET).with(payment::get_payment_status);
             },
         )
         .resource(
-            "/merchants/{merchant_id}/payments/{transaction_id}/{grin_path:.*}",
+            &path("/merchants/{merchant_id}/payments/{transaction_id}/requote"),
             |r| {
-                r.method(Method::POST).with(payment::make_payment);
+                r.method(Method::POST).with(payment::requote_payment);
             },
         )
-        .resource("/login", |r| {
-            r.method(Method::POST).with(webui::login);
-            r.method(Method::GET).with(webui::login_form);
+        .resource(
+            &path("/merchants/{merchant_id}/payments/{transaction_id}/conversion"),
+            |r| {
+                r.method(Method::GET).with(payment::get_payment_conversion);
+            },
+        )
+        .resource(
+            &path("/merchants/{merchant_id}/payments/{transaction_id}/checkout"),
+            |r| {
+                r.method(Method::POST).with(payment::create_checkout_link);
+            },
+        )
+        .resource(
+            &path("/merchants/{merchant_id}/payments/{transaction_id}/refund_address"),
+            |r| {
+                r.method(Method::POST)
+                    .with(refund_address::set_payment_refund_address);
+            },
+        )
+        .resource(
+            &path("/merchants/{merchant_id}/payments/{transaction_id}/splits"),
+            |r| {
+                r.method(Method::GET).with(marketplace::get_payment_splits);
+            },
+        )
+        .resource(&path("/merchants/{merchant_id}/refund_addresses"), |r| {
+            r.method(Method::GET).with(refund_address::get_refund_addresses);
+            r.method(Method::POST).with(refund_address::create_refund_address);
         })
-        .resource("/logout", |r| r.method(Method::POST).with(webui::logout))
-        .resource("/", |r| {
-            r.method(Method::GET).with(webui::index);
+        .resource(
+            &path("/merchants/{merchant_id}/refund_addresses/{address_id}"),
+            |r| {
+                r.method(Method::DELETE).with(refund_address::delete_refund_address);
+            },
+        )
+        .resource(
+            &path("/merchants/{merchant_id}/transactions/{transaction_id}/notes"),
+            |r| {
+                r.method(Method::GET).with(note::get_notes);
+                r.method(Method::POST).with(note::create_note);
+            },
+        )
+        .resource(&path("/merchants/{merchant_id}/referrals"), |r| {
+            r.method(Method::GET).with(referral::get_referrals);
         })
-        .resource("/set_2fa", |r| {
-            r.method(Method::GET).with(mfa::get_totp);
-            r.method(Method::POST).with(mfa::post_totp);
+        .resource(&path("/merchants/{merchant_id}/settlements"), |r| {
+            r.method(Method::GET).with(settlement::get_settlements);
         })
-        .resource("/2fa", |r| {
-            r.method(Method::GET).with(mfa::form_2fa);
-            r.method(Method::POST).with(mfa::post_2fa);
+        .resource(&path("/merchants/{merchant_id}/settlements/{date}.pdf"), |r| {
+            r.method(Method::GET).with(settlement::get_settlement_pdf);
         })
-            .resource("/transactions", |r| {
-            r.method(Method::GET).with(webui::get_transactions)
+        .resource(&path("/meta/payment-states"), |r| {
+            r.method(Method::GET).with(meta::get_payment_states);
+        })
+        .resource(&path("/meta/api-versions"), |r| {
+            r.method(Method::GET).with(meta::get_api_versions);
+        })
+}
+
+/// Whether `path` is one of `checkout_routes`, which buyers call rather than
+/// merchants
+pub fn is_checkout_path(path: &str) -> bool {
+    if path.starts_with("/checkout/") {
+        return true;
+    }
+    match path.split('/').collect::<Vec<_>>().as_slice() {
+        ["", "status"] | ["", "rates"] | ["", "merchants", _, "profile"] => true,
+        _ => false,
+    }
+}
+
+/// Payment pages, wallet requests of buyers, the status page and rates. Buyers
+/// only reach payments by checkout token, never by payment id.
+fn checkout_routes(app: App<AppState>) -> App<AppState> {
+    app
+        .resource("/merchants/{merchant_id}/profile", |r| {
+            r.method(Method::GET).with(get_merchant_profile);
+        })
+        .resource("/checkout/{token}", |r| {
+            r.middleware(DenyPayers);
+            r.method(Method::GET).with(checkout::get_checkout);
+            r.method(Method::POST).with(checkout::make_checkout_payment);
+        })
+        .resource("/checkout/{token}/status", |r| {
+            r.method(Method::GET).with(checkout::get_checkout_status);
+        })
+        .resource("/checkout/{token}/requote", |r| {
+            r.method(Method::POST).with(checkout::requote_checkout);
+        })
+        .resource("/checkout/{token}/receipt_email", |r| {
+            r.method(Method::POST).with(checkout::set_checkout_receipt_email);
+        })
+        .resource("/checkout/{token}/v1/wallet/foreign/receive_tx", |r| {
+            r.middleware(DenyPayers);
+            r.method(Method::POST).with(checkout::make_checkout_payment);
+        })
+        .resource("/checkout/{token}/v2/foreign", |r| {
+            r.middleware(DenyPayers);
+            r.method(Method::POST).with(checkout::checkout_foreign_rpc);
+        })
+        .resource("/status", |r| {
+            r.method(Method::GET).with(get_status);
+        })
+        .resource("/rates", |r| {
+            r.method(Method::GET).with(rates::get_rates);
         })
 }
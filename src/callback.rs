@@ -0,0 +1,412 @@
+//! Requests to merchants' callback urls.
+//!
+//! Payment callbacks and payout events are sent with the merchant's
+//! timeout and extra headers, e.g. their own bearer token. TLS
+//! verification can be turned off for self-signed staging endpoints. A
+//! merchant's template reshapes the body, see `callback_template`.
+//!
+//! Failed callbacks are retried by a `RetryPolicy`, the defaults come from
+//! the environment and merchants can override each of them. The backoff
+//! is never shorter than the merchant's plan allows.
+
+use crate::callback_template;
+use crate::errors::Error;
+use crate::models::Merchant;
+use crate::payout_webhook::SIGNATURE_HEADER;
+use crate::plans;
+use actix::{Actor, Addr};
+use actix_web::client::{self, ClientConnector, ClientRequestBuilder};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use chrono::{Duration as ChronoDuration, NaiveDateTime};
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::time::Duration;
+
+pub const DEFAULT_CALLBACK_TIMEOUT_SECONDS: i32 = 5;
+pub const MAX_CALLBACK_TIMEOUT_SECONDS: i32 = 60;
+pub const MAX_CALLBACK_HEADERS: usize = 10;
+pub const DEFAULT_CALLBACK_MAX_ATTEMPTS: i32 = 10;
+pub const DEFAULT_CALLBACK_BACKOFF_SECONDS: i32 = 10;
+pub const DEFAULT_CALLBACK_RETRY_WINDOW_SECONDS: i32 = 24 * 60 * 60;
+/// Upper bounds of the merchant's overrides
+pub const MAX_CALLBACK_ATTEMPTS: i32 = 50;
+pub const MAX_CALLBACK_BACKOFF_SECONDS: i32 = 60 * 60;
+pub const MAX_CALLBACK_RETRY_WINDOW_SECONDS: i32 = 7 * 24 * 60 * 60;
+
+lazy_static::lazy_static! {
+    /// Retries of merchants who didn't override them
+    pub static ref DEFAULT_RETRY_POLICY: RetryPolicy = RetryPolicy::from_env();
+}
+
+/// Set by the gateway, merchants can't override them
+const RESERVED_HEADERS: &[&str] = &["content-type", "content-length", "host", "connection"];
+
+#[derive(Clone, PartialEq)]
+pub struct CallbackSettings {
+    pub timeout_seconds: i32,
+    pub headers: BTreeMap<String, String>,
+    pub verify_tls: bool,
+    pub template: Option<Value>,
+    /// Pending and in chain payments are reported too
+    pub verbose: bool,
+    /// Overrides of `DEFAULT_RETRY_POLICY`, `None` keeps the default
+    pub max_attempts: Option<i32>,
+    pub backoff_seconds: Option<i32>,
+    pub retry_window_seconds: Option<i32>,
+    /// Of the merchant's plan, wins over a shorter backoff
+    pub min_backoff_seconds: i32,
+}
+
+impl CallbackSettings {
+    /// Headers which can't be read are left out, they were checked on save
+    pub fn of(merchant: &Merchant) -> Self {
+        CallbackSettings {
+            timeout_seconds: merchant.callback_timeout_seconds,
+            headers: merchant
+                .callback_headers
+                .clone()
+                .and_then(|headers| serde_json::from_value(headers).ok())
+                .unwrap_or_default(),
+            verify_tls: merchant.callback_verify_tls,
+            template: merchant.callback_template.clone(),
+            verbose: merchant.verbose_callbacks,
+            max_attempts: merchant.callback_max_attempts,
+            backoff_seconds: merchant.callback_backoff_seconds,
+            retry_window_seconds: merchant.callback_retry_window_seconds,
+            min_backoff_seconds: plans::get(&merchant.plan)
+                .map_or(0, |plan| plan.min_callback_backoff_seconds),
+        }
+    }
+
+    /// The merchant's overrides on top of the defaults
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self
+                .max_attempts
+                .unwrap_or(DEFAULT_RETRY_POLICY.max_attempts),
+            backoff_seconds: self
+                .backoff_seconds
+                .unwrap_or(DEFAULT_RETRY_POLICY.backoff_seconds)
+                .max(self.min_backoff_seconds),
+            window_seconds: self
+                .retry_window_seconds
+                .unwrap_or(DEFAULT_RETRY_POLICY.window_seconds),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.timeout_seconds < 1 || self.timeout_seconds > MAX_CALLBACK_TIMEOUT_SECONDS {
+            return Err(Error::InvalidEntity(format!(
+                "callback timeout should be from 1 to {} seconds",
+                MAX_CALLBACK_TIMEOUT_SECONDS
+            )));
+        }
+        if self.headers.len() > MAX_CALLBACK_HEADERS {
+            return Err(Error::InvalidEntity(format!(
+                "at most {} callback headers are allowed",
+                MAX_CALLBACK_HEADERS
+            )));
+        }
+        for (name, value) in &self.headers {
+            let header = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| Error::InvalidEntity(format!("invalid header name {}", name)))?;
+            if RESERVED_HEADERS.contains(&header.as_str())
+                || header.as_str() == SIGNATURE_HEADER.to_lowercase()
+            {
+                return Err(Error::InvalidEntity(format!(
+                    "header {} is set by the gateway",
+                    name
+                )));
+            }
+            HeaderValue::from_str(value)
+                .map_err(|_| Error::InvalidEntity(format!("invalid value of header {}", name)))?;
+        }
+        if let Some(ref template) = self.template {
+            callback_template::validate(template)?;
+        }
+        check_override("max attempts", self.max_attempts, 1, MAX_CALLBACK_ATTEMPTS)?;
+        check_override(
+            "retry backoff",
+            self.backoff_seconds,
+            1,
+            MAX_CALLBACK_BACKOFF_SECONDS,
+        )?;
+        check_override(
+            "retry window",
+            self.retry_window_seconds,
+            60,
+            MAX_CALLBACK_RETRY_WINDOW_SECONDS,
+        )?;
+        Ok(())
+    }
+
+    /// Empty form fields keep the default
+    pub fn parse_override(name: &str, text: &str) -> Result<Option<i32>, Error> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Ok(None);
+        }
+        text.parse()
+            .map(Some)
+            .map_err(|_| Error::InvalidEntity(format!("{} should be a number", name)))
+    }
+
+    /// JSON body of a callback, reshaped by the template if there is one
+    pub fn body<T: Serialize>(&self, payload: &T) -> Result<String, Error> {
+        let payload = serde_json::to_value(payload)?;
+        let body = match self.template {
+            Some(ref template) => callback_template::render(template, &payload),
+            None => payload,
+        };
+        Ok(serde_json::to_string(&body)?)
+    }
+
+    /// Parses `Name: value` lines, empty lines are skipped
+    pub fn parse_headers(text: &str) -> Result<BTreeMap<String, String>, Error> {
+        let mut headers = BTreeMap::new();
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if !name.trim().is_empty() => {
+                    headers.insert(name.trim().to_owned(), value.trim().to_owned());
+                }
+                _ => {
+                    return Err(Error::InvalidEntity(format!(
+                        "header should be Name: value, got {}",
+                        line
+                    )))
+                }
+            }
+        }
+        Ok(headers)
+    }
+
+    /// `Name: value` lines for the settings form
+    pub fn headers_text(&self) -> String {
+        self.headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}", name, value))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Pretty printed template for the settings form
+    pub fn template_text(&self) -> String {
+        self.template
+            .as_ref()
+            .and_then(|template| serde_json::to_string_pretty(template).ok())
+            .unwrap_or_default()
+    }
+}
+
+/// Header values may hold the merchant's credentials, only names are shown
+impl fmt::Debug for CallbackSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CallbackSettings")
+            .field("timeout_seconds", &self.timeout_seconds)
+            .field("headers", &self.headers.keys().collect::<Vec<_>>())
+            .field("verify_tls", &self.verify_tls)
+            .field("template", &self.template.is_some())
+            .field("verbose", &self.verbose)
+            .field("max_attempts", &self.max_attempts)
+            .field("backoff_seconds", &self.backoff_seconds)
+            .field("retry_window_seconds", &self.retry_window_seconds)
+            .field("min_backoff_seconds", &self.min_backoff_seconds)
+            .finish()
+    }
+}
+
+fn check_override(name: &str, value: Option<i32>, min: i32, max: i32) -> Result<(), Error> {
+    match value {
+        Some(value) if value < min || value > max => Err(Error::InvalidEntity(format!(
+            "callback {} should be from {} to {}",
+            name, min, max
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// How failed payment callbacks and payout events are retried
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct RetryPolicy {
+    pub max_attempts: i32,
+    /// The retry after the nth failed attempt waits `backoff_seconds` · n²
+    pub backoff_seconds: i32,
+    /// Nothing is retried this long after the first attempt
+    pub window_seconds: i32,
+}
+
+impl RetryPolicy {
+    /// Reads CALLBACK_MAX_ATTEMPTS, CALLBACK_BACKOFF_SECONDS and
+    /// CALLBACK_RETRY_WINDOW_SECONDS
+    pub fn from_env() -> Self {
+        RetryPolicy {
+            max_attempts: positive_from_env("CALLBACK_MAX_ATTEMPTS", DEFAULT_CALLBACK_MAX_ATTEMPTS),
+            backoff_seconds: positive_from_env(
+                "CALLBACK_BACKOFF_SECONDS",
+                DEFAULT_CALLBACK_BACKOFF_SECONDS,
+            ),
+            window_seconds: positive_from_env(
+                "CALLBACK_RETRY_WINDOW_SECONDS",
+                DEFAULT_CALLBACK_RETRY_WINDOW_SECONDS,
+            ),
+        }
+    }
+
+    /// When a callback which failed for the `attempts`th time is sent
+    /// again, `None` once it's out of attempts or the retry would fall out
+    /// of the window which started at `since`
+    pub fn next_attempt(
+        &self,
+        attempts: i32,
+        since: NaiveDateTime,
+        now: NaiveDateTime,
+    ) -> Option<NaiveDateTime> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+        let next =
+            now + ChronoDuration::seconds(self.backoff_seconds as i64 * (attempts as i64).pow(2));
+        if next > since + ChronoDuration::seconds(self.window_seconds as i64) {
+            return None;
+        }
+        Some(next)
+    }
+}
+
+fn positive_from_env(name: &str, default: i32) -> i32 {
+    env::var(name)
+        .ok()
+        .filter(|v| !v.is_empty())
+        .map(|v| match v.parse() {
+            Ok(value) if value > 0 => value,
+            _ => panic!("{} must be a positive number", name),
+        })
+        .unwrap_or(default)
+}
+
+/// POST request to a callback url with the merchant's settings applied
+pub fn post(url: &str, settings: &CallbackSettings) -> ClientRequestBuilder {
+    let mut request = client::post(url);
+    request.timeout(Duration::from_secs(settings.timeout_seconds as u64));
+    for (name, value) in &settings.headers {
+        request.header(name.as_str(), value.as_str());
+    }
+    if !settings.verify_tls {
+        request.with_connector(insecure_connector());
+    }
+    request
+}
+
+/// Connector which accepts any certificate, only for merchants who
+/// turned verification off
+fn insecure_connector() -> Addr<ClientConnector> {
+    let mut ssl = SslConnector::builder(SslMethod::tls()).expect("Cannot create SSL connector");
+    ssl.set_verify(SslVerifyMode::NONE);
+    ClientConnector::with_connector(ssl.build()).start()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_callback_headers() {
+        let headers =
+            CallbackSettings::parse_headers("Authorization: Bearer abc:def\n\n X-Shop : 1 \n")
+                .unwrap();
+        assert_eq!(headers.get("Authorization").unwrap(), "Bearer abc:def");
+        assert_eq!(headers.get("X-Shop").unwrap(), "1");
+        assert!(CallbackSettings::parse_headers("no colon").is_err());
+
+        let mut settings = CallbackSettings {
+            timeout_seconds: DEFAULT_CALLBACK_TIMEOUT_SECONDS,
+            headers,
+            verify_tls: true,
+            template: None,
+            verbose: false,
+            max_attempts: None,
+            backoff_seconds: None,
+            retry_window_seconds: None,
+            min_backoff_seconds: 0,
+        };
+        assert!(settings.validate().is_ok());
+        assert_eq!(
+            settings.headers_text(),
+            "Authorization: Bearer abc:def\nX-Shop: 1"
+        );
+        settings.timeout_seconds = MAX_CALLBACK_TIMEOUT_SECONDS + 1;
+        assert!(settings.validate().is_err());
+        settings.timeout_seconds = 10;
+        settings
+            .headers
+            .insert(s!("Content-Type"), s!("text/plain"));
+        assert!(settings.validate().is_err());
+        settings.headers.remove("Content-Type");
+        settings
+            .headers
+            .insert(s!("X-Knockturn-Signature"), s!("forged"));
+        assert!(settings.validate().is_err());
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let now = chrono::Utc::now().naive_utc();
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            backoff_seconds: 10,
+            window_seconds: 60,
+        };
+        assert_eq!(
+            policy.next_attempt(1, now, now),
+            Some(now + ChronoDuration::seconds(10))
+        );
+        assert_eq!(
+            policy.next_attempt(2, now, now),
+            Some(now + ChronoDuration::seconds(40))
+        );
+        assert_eq!(policy.next_attempt(3, now, now), None);
+        // 40 seconds from now is past the window
+        assert_eq!(
+            policy.next_attempt(2, now - ChronoDuration::seconds(30), now),
+            None
+        );
+
+        let settings = CallbackSettings {
+            timeout_seconds: DEFAULT_CALLBACK_TIMEOUT_SECONDS,
+            headers: BTreeMap::new(),
+            verify_tls: true,
+            template: None,
+            verbose: false,
+            max_attempts: Some(3),
+            backoff_seconds: None,
+            retry_window_seconds: Some(120),
+            min_backoff_seconds: 0,
+        };
+        assert!(settings.validate().is_ok());
+        let policy = settings.retry_policy();
+        assert_eq!(policy.max_attempts, 3);
+        assert_eq!(policy.backoff_seconds, DEFAULT_RETRY_POLICY.backoff_seconds);
+        assert_eq!(policy.window_seconds, 120);
+        let free = CallbackSettings {
+            backoff_seconds: Some(5),
+            min_backoff_seconds: 60,
+            ..settings
+        };
+        assert_eq!(free.retry_policy().backoff_seconds, 60);
+
+        assert_eq!(
+            CallbackSettings::parse_override("max attempts", " ").unwrap(),
+            None
+        );
+        assert_eq!(
+            CallbackSettings::parse_override("max attempts", "5").unwrap(),
+            Some(5)
+        );
+        assert!(CallbackSettings::parse_override("max attempts", "five").is_err());
+    }
+}
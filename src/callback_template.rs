@@ -0,0 +1,184 @@
+//! Merchant defined shape of callback bodies.
+//!
+//! A template is a JSON object whose strings may hold `{{path}}`
+//! placeholders, a path names a field of the payload, e.g. `external_id`
+//! or `amount.currency` for nested ones. A string which is a single
+//! placeholder is replaced by the field keeping its JSON type, placeholders
+//! inside longer strings are replaced by the field's text. Missing fields
+//! become `null` or an empty string. Templates only move data around, there
+//! is nothing to execute, and their size and nesting are limited.
+
+use crate::errors::Error;
+use serde_json::{Map, Value};
+
+pub const MAX_TEMPLATE_LENGTH: usize = 10_000;
+pub const MAX_TEMPLATE_DEPTH: usize = 10;
+
+const OPEN: &str = "{{";
+const CLOSE: &str = "}}";
+
+pub fn parse(text: &str) -> Result<Option<Value>, Error> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Ok(None);
+    }
+    if text.len() > MAX_TEMPLATE_LENGTH {
+        return Err(Error::InvalidEntity(format!(
+            "callback template should be at most {} bytes",
+            MAX_TEMPLATE_LENGTH
+        )));
+    }
+    let template = serde_json::from_str(text)
+        .map_err(|e| Error::InvalidEntity(format!("callback template is not JSON: {}", e)))?;
+    validate(&template)?;
+    Ok(Some(template))
+}
+
+pub fn validate(template: &Value) -> Result<(), Error> {
+    if !template.is_object() {
+        return Err(Error::InvalidEntity(s!(
+            "callback template should be a JSON object"
+        )));
+    }
+    validate_value(template, 1)
+}
+
+fn validate_value(value: &Value, depth: usize) -> Result<(), Error> {
+    if depth > MAX_TEMPLATE_DEPTH && (value.is_object() || value.is_array()) {
+        return Err(Error::InvalidEntity(format!(
+            "callback template should be nested at most {} levels",
+            MAX_TEMPLATE_DEPTH
+        )));
+    }
+    match value {
+        Value::String(s) => placeholders(s).map(|_| ()),
+        Value::Array(values) => values
+            .iter()
+            .try_for_each(|value| validate_value(value, depth + 1)),
+        Value::Object(fields) => fields
+            .values()
+            .try_for_each(|value| validate_value(value, depth + 1)),
+        _ => Ok(()),
+    }
+}
+
+/// Text between placeholders and the placeholder paths, in order
+enum Part<'a> {
+    Text(&'a str),
+    Field(&'a str),
+}
+
+fn placeholders(s: &str) -> Result<Vec<Part>, Error> {
+    let mut parts = Vec::new();
+    let mut rest = s;
+    while let Some(start) = rest.find(OPEN) {
+        let end = rest[start..]
+            .find(CLOSE)
+            .ok_or_else(|| Error::InvalidEntity(format!("unclosed placeholder in {:?}", s)))?
+            + start;
+        let path = rest[start + OPEN.len()..end].trim();
+        if path.is_empty() || path.split('.').any(str::is_empty) {
+            return Err(Error::InvalidEntity(format!(
+                "invalid placeholder in {:?}",
+                s
+            )));
+        }
+        if start > 0 {
+            parts.push(Part::Text(&rest[..start]));
+        }
+        parts.push(Part::Field(path));
+        rest = &rest[end + CLOSE.len()..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Text(rest));
+    }
+    Ok(parts)
+}
+
+fn lookup<'a>(payload: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(payload, |value, key| value.get(key))
+}
+
+/// Fills the template in, `template` must have been validated
+pub fn render(template: &Value, payload: &Value) -> Value {
+    match template {
+        Value::String(s) => render_string(s, payload),
+        Value::Array(values) => Value::Array(values.iter().map(|v| render(v, payload)).collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(name, value)| (name.clone(), render(value, payload)))
+                .collect::<Map<String, Value>>(),
+        ),
+        other => other.clone(),
+    }
+}
+
+fn render_string(s: &str, payload: &Value) -> Value {
+    let parts = match placeholders(s) {
+        Ok(parts) => parts,
+        Err(_) => return Value::String(s.to_owned()),
+    };
+    if let [Part::Field(path)] = parts.as_slice() {
+        return lookup(payload, path).cloned().unwrap_or(Value::Null);
+    }
+    let text = parts
+        .iter()
+        .map(|part| match part {
+            Part::Text(text) => text.to_string(),
+            Part::Field(path) => match lookup(payload, path) {
+                None | Some(Value::Null) => String::new(),
+                Some(Value::String(value)) => value.clone(),
+                Some(value) => value.to_string(),
+            },
+        })
+        .collect();
+    Value::String(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_render() {
+        let template = parse(
+            r#"{"order": "{{external_id}}", "paid": {"value": "{{grin_amount}}",
+                "unit": "{{amount.currency}}"}, "note": "order {{external_id}}: {{status}}",
+                "missing": "{{nope}}", "version": 2}"#,
+        )
+        .unwrap()
+        .unwrap();
+        let payload = json!({
+            "external_id": "42",
+            "grin_amount": 1000,
+            "amount": {"amount": 100, "currency": "USD"},
+            "status": "Confirmed",
+        });
+        assert_eq!(
+            render(&template, &payload),
+            json!({
+                "order": "42",
+                "paid": {"value": 1000, "unit": "USD"},
+                "note": "order 42: Confirmed",
+                "missing": null,
+                "version": 2,
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert!(parse("  ").unwrap().is_none());
+        assert!(parse("[1]").is_err());
+        assert!(parse(r#"{"a": "{{id"}"#).is_err());
+        assert!(parse(r#"{"a": "{{a..b}}"}"#).is_err());
+        let mut deep = s!("1");
+        for _ in 0..=MAX_TEMPLATE_DEPTH {
+            deep = format!(r#"{{"a": {}}}"#, deep);
+        }
+        assert!(parse(&deep).is_err());
+    }
+}
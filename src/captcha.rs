@@ -0,0 +1,176 @@
+//! CAPTCHA guarding merchant signup and the dashboard login.
+//!
+//! Enabled by `CAPTCHA_SECRET` and `CAPTCHA_SITE_KEY`, `CAPTCHA_PROVIDER`
+//! is `hcaptcha` (default) or `recaptcha`. The widget posts its token with
+//! the form, the token is verified with the provider before anything else
+//! is checked.
+
+use crate::errors::Error;
+use crate::metrics;
+use crate::redact::Secret;
+use actix_web::client;
+use actix_web::http::header;
+use actix_web::HttpMessage;
+use futures::future::{err, ok, result, Either, Future};
+use serde::{Deserialize, Serialize};
+use serde_json::from_slice;
+use std::env;
+use std::time::Duration;
+
+const CAPTCHA_TIMEOUT_SECONDS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaptchaProvider {
+    HCaptcha,
+    ReCaptcha,
+}
+
+impl CaptchaProvider {
+    pub fn verify_url(&self) -> &'static str {
+        match self {
+            CaptchaProvider::HCaptcha => "https://hcaptcha.com/siteverify",
+            CaptchaProvider::ReCaptcha => "https://www.google.com/recaptcha/api/siteverify",
+        }
+    }
+
+    /// Script rendering the widget on our pages
+    pub fn script_url(&self) -> &'static str {
+        match self {
+            CaptchaProvider::HCaptcha => "https://js.hcaptcha.com/1/api.js",
+            CaptchaProvider::ReCaptcha => "https://www.google.com/recaptcha/api.js",
+        }
+    }
+
+    /// Class of the element the widget is rendered in
+    pub fn widget_class(&self) -> &'static str {
+        match self {
+            CaptchaProvider::HCaptcha => "h-captcha",
+            CaptchaProvider::ReCaptcha => "g-recaptcha",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Captcha {
+    pub provider: CaptchaProvider,
+    pub site_key: String,
+    secret: Secret<String>,
+    verify_url: String,
+}
+
+#[derive(Debug, Serialize)]
+struct VerifyRequest<'a> {
+    secret: &'a str,
+    response: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct VerifyResponse {
+    success: bool,
+}
+
+impl Captcha {
+    pub fn new(provider: CaptchaProvider, site_key: &str, secret: &str) -> Self {
+        Captcha {
+            provider,
+            site_key: site_key.to_owned(),
+            secret: Secret::new(secret.to_owned()),
+            verify_url: provider.verify_url().to_owned(),
+        }
+    }
+
+    /// Disabled without CAPTCHA_SECRET, CAPTCHA_VERIFY_URL overrides
+    /// the provider's verification endpoint
+    pub fn from_env() -> Option<Self> {
+        let var = |name| env::var(name).ok().filter(|v: &String| !v.is_empty());
+        let secret = var("CAPTCHA_SECRET")?;
+        let site_key = var("CAPTCHA_SITE_KEY").expect("CAPTCHA_SITE_KEY must be set");
+        let provider = match var("CAPTCHA_PROVIDER").as_ref().map(String::as_str) {
+            None | Some("hcaptcha") => CaptchaProvider::HCaptcha,
+            Some("recaptcha") => CaptchaProvider::ReCaptcha,
+            Some(_) => panic!("CAPTCHA_PROVIDER must be hcaptcha or recaptcha"),
+        };
+        let mut captcha = Captcha::new(provider, &site_key, &secret);
+        if let Some(verify_url) = var("CAPTCHA_VERIFY_URL") {
+            captcha.verify_url = verify_url;
+        }
+        Some(captcha)
+    }
+
+    /// Asks the provider whether the response token is valid
+    pub fn verify(&self, response: &str) -> impl Future<Item = (), Error = Error> {
+        let body = serde_urlencoded::to_string(VerifyRequest {
+            secret: self.secret.expose(),
+            response,
+        })
+        .map_err(|e| Error::General(s!(e)));
+        let verify_url = self.verify_url.clone();
+        result(body)
+            .and_then(move |body| {
+                client::post(&verify_url)
+                    .timeout(Duration::from_secs(CAPTCHA_TIMEOUT_SECONDS))
+                    .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                    .body(body)
+                    .unwrap()
+                    .send()
+                    .map_err(|e| Error::General(format!("Cannot verify CAPTCHA: {}", e)))
+            })
+            .and_then(|resp| {
+                if !resp.status().is_success() {
+                    return Either::A(err(Error::General(format!(
+                        "CAPTCHA provider responded with {}",
+                        resp.status()
+                    ))));
+                }
+                Either::B(
+                    resp.body()
+                        .map_err(|e| Error::General(format!("Cannot verify CAPTCHA: {}", e))),
+                )
+            })
+            .and_then(|bytes| {
+                let verified: VerifyResponse = from_slice(&bytes)?;
+                if verified.success {
+                    Ok(())
+                } else {
+                    Err(Error::InvalidEntity(s!("CAPTCHA verification failed")))
+                }
+            })
+    }
+}
+
+/// Passes when CAPTCHA is disabled, `form` labels the failures metric
+pub fn check(
+    captcha: Option<&Captcha>,
+    response: Option<&str>,
+    form: &'static str,
+) -> impl Future<Item = (), Error = Error> {
+    let captcha = match captcha {
+        Some(captcha) => captcha,
+        None => return Either::A(ok(())),
+    };
+    let verified = match response {
+        Some(response) if !response.is_empty() => Either::A(captcha.verify(response)),
+        _ => Either::B(err(Error::InvalidEntity(s!("CAPTCHA is required")))),
+    };
+    Either::B(verified.map_err(move |e| {
+        metrics::inc("captcha_failures_total", &[("form", form)]);
+        e
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        assert!(check(None, None, "test").wait().is_ok());
+        let captcha = Captcha::new(CaptchaProvider::HCaptcha, "site", "secret");
+        assert!(check(Some(&captcha), None, "test").wait().is_err());
+        assert!(check(Some(&captcha), Some(""), "test").wait().is_err());
+        assert_eq!(
+            metrics::get("captcha_failures_total", &[("form", "test")]),
+            2
+        );
+    }
+}
@@ -0,0 +1,176 @@
+//! Checkout tokens, what buyers see instead of payment ids.
+//!
+//! A token is the payment id and an expiry encrypted with AES-256-GCM under
+//! a key derived from `COOKIE_SECRET`, so it can't be forged or read and
+//! needs no lookup. The payment page, the wallet URL and the Ironbelly QR
+//! code use `/checkout/{token}`. Merchants get a payment's status and
+//! requote it by id with their API credentials.
+//! Tokens live `CHECKOUT_TOKEN_TTL_SECONDS`, a buyer with an expired one is
+//! asked to get a fresh link from the merchant, who creates it with
+//! `POST /merchants/{merchant_id}/payments/{transaction_id}/checkout`.
+
+use crate::errors::Error;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use data_encoding::BASE64URL_NOPAD;
+use openssl::sha::sha256;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use rand::{thread_rng, Rng};
+use std::env;
+use uuid::Uuid;
+
+pub const DEFAULT_CHECKOUT_TOKEN_TTL_SECONDS: i64 = 24 * 3600;
+const NONCE_LENGTH: usize = 12;
+const TAG_LENGTH: usize = 16;
+/// Payment id and expiry
+const PLAINTEXT_LENGTH: usize = 16 + 8;
+
+lazy_static::lazy_static! {
+    static ref KEY: [u8; 32] = sha256(
+        format!(
+            "checkout|{}",
+            env::var("COOKIE_SECRET").expect("COOKIE_SECRET must be set")
+        )
+        .as_bytes()
+    );
+    pub static ref CHECKOUT_TOKEN_TTL_SECONDS: i64 = env::var("CHECKOUT_TOKEN_TTL_SECONDS")
+        .map(|v| {
+            v.parse()
+                .expect("CHECKOUT_TOKEN_TTL_SECONDS must be a number")
+        })
+        .unwrap_or(DEFAULT_CHECKOUT_TOKEN_TTL_SECONDS);
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckoutToken {
+    pub transaction_id: Uuid,
+    /// Unix time in seconds
+    pub expires_at: i64,
+}
+
+impl CheckoutToken {
+    pub fn new(transaction_id: Uuid, now: i64) -> Self {
+        CheckoutToken {
+            transaction_id,
+            expires_at: now + *CHECKOUT_TOKEN_TTL_SECONDS,
+        }
+    }
+
+    pub fn encode(&self) -> Result<String, Error> {
+        self.encode_with(&*KEY)
+    }
+
+    /// Fails with `EntityNotFound` for tokens we didn't make and with
+    /// `CheckoutExpired` for expired ones
+    pub fn decode(token: &str, now: i64) -> Result<Self, Error> {
+        CheckoutToken::decode_with(token, &*KEY, now)
+    }
+
+    fn encode_with(&self, key: &[u8]) -> Result<String, Error> {
+        let nonce = thread_rng().gen::<[u8; NONCE_LENGTH]>();
+        let mut plaintext = self.transaction_id.as_bytes().to_vec();
+        plaintext.extend_from_slice(&self.expires_at.to_be_bytes());
+        let mut tag = [0u8; TAG_LENGTH];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            key,
+            Some(&nonce),
+            &[],
+            &plaintext,
+            &mut tag,
+        )
+        .map_err(|e| Error::General(s!(e)))?;
+        let mut token = nonce.to_vec();
+        token.extend(ciphertext);
+        token.extend_from_slice(&tag);
+        Ok(BASE64URL_NOPAD.encode(&token))
+    }
+
+    fn decode_with(token: &str, key: &[u8], now: i64) -> Result<Self, Error> {
+        let not_found = || Error::EntityNotFound(s!("payment"));
+        let token = BASE64URL_NOPAD
+            .decode(token.as_bytes())
+            .map_err(|_| not_found())?;
+        if token.len() != NONCE_LENGTH + PLAINTEXT_LENGTH + TAG_LENGTH {
+            return Err(not_found());
+        }
+        let (nonce, rest) = token.split_at(NONCE_LENGTH);
+        let (ciphertext, tag) = rest.split_at(PLAINTEXT_LENGTH);
+        let plaintext = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            key,
+            Some(nonce),
+            &[],
+            ciphertext,
+            tag,
+        )
+        .map_err(|_| not_found())?;
+        let transaction_id = Uuid::from_slice(&plaintext[..16]).map_err(|_| not_found())?;
+        let mut expires_at = [0u8; 8];
+        expires_at.copy_from_slice(&plaintext[16..]);
+        let expires_at = i64::from_be_bytes(expires_at);
+        if expires_at < now {
+            return Err(Error::CheckoutExpired);
+        }
+        Ok(CheckoutToken {
+            transaction_id,
+            expires_at,
+        })
+    }
+
+    /// Path of the payment page, the routes of the page are below it
+    pub fn path(&self) -> Result<String, Error> {
+        Ok(format!("/checkout/{}", self.encode()?))
+    }
+
+    pub fn url(&self) -> Result<String, Error> {
+        Ok(format!(
+            "{}{}",
+            env::var("DOMAIN").unwrap().trim_end_matches('/'),
+            self.path()?
+        ))
+    }
+
+    pub fn expires_at_utc(&self) -> DateTime<Utc> {
+        DateTime::from_utc(NaiveDateTime::from_timestamp(self.expires_at, 0), Utc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = &[7u8; 32];
+
+    #[test]
+    fn test_checkout_token() {
+        let transaction_id = Uuid::new_v4();
+        let token = CheckoutToken {
+            transaction_id,
+            expires_at: 1_560_000_000,
+        };
+        let encoded = token.encode_with(KEY).unwrap();
+        assert!(!encoded.contains(&transaction_id.simple().to_string()));
+        // Every encoding has its own nonce
+        assert_ne!(encoded, token.encode_with(KEY).unwrap());
+        assert_eq!(
+            CheckoutToken::decode_with(&encoded, KEY, 1_559_999_000).unwrap(),
+            token
+        );
+
+        match CheckoutToken::decode_with(&encoded, KEY, 1_560_000_001) {
+            Err(Error::CheckoutExpired) => {}
+            res => panic!("expired token was accepted: {:?}", res),
+        }
+        match CheckoutToken::decode_with(&encoded, &[8u8; 32], 1_559_999_000) {
+            Err(Error::EntityNotFound(_)) => {}
+            res => panic!("token of another key was accepted: {:?}", res),
+        }
+        let mut tampered = BASE64URL_NOPAD.decode(encoded.as_bytes()).unwrap();
+        tampered[NONCE_LENGTH] ^= 1;
+        assert!(
+            CheckoutToken::decode_with(&BASE64URL_NOPAD.encode(&tampered), KEY, 1_559_999_000)
+                .is_err()
+        );
+        assert!(CheckoutToken::decode_with("not a token", KEY, 1_559_999_000).is_err());
+    }
+}
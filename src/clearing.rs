@@ -0,0 +1,129 @@
+//! Clearing period of credited payments.
+//!
+//! A confirmed payment is credited to the merchant's pending balance first,
+//! `merchants.pending_balance`, and each credit is kept in
+//! `pending_credits` until `BALANCE_CLEARING_HOURS` after it was credited
+//! and `BALANCE_CLEARING_BLOCKS` blocks above the height it was credited
+//! at. A cron job then moves due credits to the available balance,
+//! `merchants.balance`. Meanwhile an admin can hold the credits of a
+//! disputed payment, so they aren't released, and void them, which takes
+//! them off the pending balance for good. Without a clearing period
+//! credits are available right away.
+
+use chrono::{Duration, NaiveDateTime};
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+
+/// How often due credits are moved to the available balance
+pub const RELEASE_CREDITS_SECONDS: u64 = 60;
+
+lazy_static::lazy_static! {
+    pub static ref CLEARING_CONFIG: ClearingConfig = ClearingConfig::from_env();
+}
+
+#[derive(Debug, Clone)]
+pub struct ClearingConfig {
+    pub hours: i64,
+    pub blocks: i64,
+}
+
+impl Default for ClearingConfig {
+    fn default() -> Self {
+        ClearingConfig {
+            hours: 0,
+            blocks: 0,
+        }
+    }
+}
+
+impl ClearingConfig {
+    pub fn from_env() -> Self {
+        let default = ClearingConfig::default();
+        ClearingConfig {
+            hours: env::var("BALANCE_CLEARING_HOURS")
+                .map(|v| v.parse().expect("BALANCE_CLEARING_HOURS must be a number"))
+                .unwrap_or(default.hours),
+            blocks: env::var("BALANCE_CLEARING_BLOCKS")
+                .map(|v| v.parse().expect("BALANCE_CLEARING_BLOCKS must be a number"))
+                .unwrap_or(default.blocks),
+        }
+    }
+
+    /// Credits are available right away
+    pub fn is_immediate(&self) -> bool {
+        self.hours <= 0 && self.blocks <= 0
+    }
+
+    /// When a credit made at `now` becomes available
+    pub fn available_at(&self, now: NaiveDateTime) -> NaiveDateTime {
+        now + Duration::hours(self.hours.max(0))
+    }
+
+    /// Height from which a credit made at `height` is available, 0
+    /// without a clearing period in blocks
+    pub fn available_height(&self, height: i64) -> i64 {
+        if self.blocks > 0 {
+            height + self.blocks
+        } else {
+            0
+        }
+    }
+}
+
+/// What an admin does with the pending credits of a payment
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CreditAction {
+    /// Keeps them pending until they're released or voided
+    Hold,
+    /// Lets held credits clear as usual
+    Release,
+    /// Takes them off the pending balance, they're never available
+    Void,
+}
+
+impl fmt::Display for CreditAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let action = match self {
+            CreditAction::Hold => "held",
+            CreditAction::Release => "released",
+            CreditAction::Void => "voided",
+        };
+        write!(f, "{}", action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn test_clearing_period() {
+        let now = Utc::now().naive_utc();
+        let default = ClearingConfig::default();
+        assert!(default.is_immediate());
+        assert!(ClearingConfig {
+            hours: -1,
+            blocks: 0
+        }
+        .is_immediate());
+
+        let hours = ClearingConfig {
+            hours: 72,
+            blocks: 0,
+        };
+        assert!(!hours.is_immediate());
+        assert_eq!(hours.available_at(now), now + Duration::days(3));
+        assert_eq!(hours.available_height(1_000), 0);
+
+        let blocks = ClearingConfig {
+            hours: 0,
+            blocks: 1_440,
+        };
+        assert!(!blocks.is_immediate());
+        assert_eq!(blocks.available_at(now), now);
+        assert_eq!(blocks.available_height(1_000), 2_440);
+    }
+}
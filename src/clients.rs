@@ -1,14 +1,15 @@
+use crate::redact::Credentials;
 use actix_web::client::ClientRequestBuilder;
 use actix_web::http::header;
 use base64::encode;
 
 pub trait PlainHttpAuth {
-    fn auth(&mut self, username: &str, password: &str) -> &mut Self;
+    fn auth(&mut self, credentials: &Credentials) -> &mut Self;
 }
 
 impl PlainHttpAuth for ClientRequestBuilder {
-    fn auth(&mut self, username: &str, password: &str) -> &mut Self {
-        let auth = format!("{}:{}", username, password);
+    fn auth(&mut self, credentials: &Credentials) -> &mut Self {
+        let auth = format!("{}:{}", credentials.username, credentials.password.expose());
         let auth_header = format!("Basic {}", encode(&auth));
         self.header(header::AUTHORIZATION, auth_header)
     }
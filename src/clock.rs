@@ -0,0 +1,72 @@
+//! Source of the current time for the DB executor and the FSM.
+//!
+//! Timestamps are naive UTC everywhere. Tests use `ManualClock` to move
+//! time forward instead of waiting for payments to expire.
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use std::sync::{Arc, Mutex};
+
+pub trait Clock: Send + Sync {
+    /// Current time in UTC
+    fn now(&self) -> NaiveDateTime;
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> NaiveDateTime {
+        Utc::now().naive_utc()
+    }
+}
+
+pub fn system() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+/// Stands still until it's set or advanced
+#[derive(Debug)]
+pub struct ManualClock(Mutex<NaiveDateTime>);
+
+impl ManualClock {
+    pub fn new(now: NaiveDateTime) -> Self {
+        ManualClock(Mutex::new(now))
+    }
+
+    pub fn set(&self, now: NaiveDateTime) {
+        *self.0.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.0.lock().unwrap();
+        *now = *now + duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> NaiveDateTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn test_manual_clock() {
+        let start = NaiveDate::from_ymd(2019, 3, 31).and_hms(0, 30, 0);
+        let clock = ManualClock::new(start);
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::hours(1));
+        assert_eq!(
+            clock.now(),
+            NaiveDate::from_ymd(2019, 3, 31).and_hms(1, 30, 0)
+        );
+        clock.set(start);
+        assert_eq!(clock.now(), start);
+    }
+}
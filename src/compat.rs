@@ -0,0 +1,33 @@
+//! Compatibility layer between futures 0.1, which actix 0.7 is built on, and
+//! async/await. It lets handlers and actors be written as async blocks while
+//! the rest of the stack still speaks futures 0.1, so the crate can be moved
+//! to a newer actix-web one piece at a time.
+//!
+//! Awaiting a futures 0.1 value (e.g. an actor `send`):
+//!
+//! ```ignore
+//! let tx = db.send(GetTransaction { .. }).compat().await??;
+//! ```
+//!
+//! Returning an async block from an actix handler:
+//!
+//! ```ignore
+//! Box::new(compat::to_01(async move { Ok(HttpResponse::Ok().finish()) }))
+//! ```
+
+use futures03::compat::Compat;
+pub use futures03::compat::Future01CompatExt;
+use std::future::Future;
+use std::pin::Pin;
+
+/// Futures 0.1 future driven by an async block
+pub type Compat01<F> = Compat<Pin<Box<F>>>;
+
+/// Turns an async block into a futures 0.1 future which can be returned from
+/// actix handlers or chained with futures 0.1 combinators.
+pub fn to_01<F, T, E>(f: F) -> Compat01<F>
+where
+    F: Future<Output = Result<T, E>> + 'static,
+{
+    Compat::new(Box::pin(f))
+}
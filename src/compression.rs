@@ -0,0 +1,136 @@
+//! Middleware which picks the response compression.
+//!
+//! actix-web compresses every body longer than 96 bytes when the client
+//! accepts it. This middleware makes the choice explicit: small bodies and
+//! content which is compressed already (images, archives, fonts) are sent
+//! as is, the rest is compressed with brotli or gzip, whichever the client
+//! prefers, brotli on a tie.
+
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::ContentEncoding;
+use actix_web::middleware::{Middleware, Response};
+use actix_web::{Body, HttpRequest, HttpResponse, Result};
+
+/// Bodies shorter than this don't get smaller enough to pay off
+const MIN_SIZE: usize = 1024;
+
+/// Content types which are compressed already, `image/svg+xml` is text
+/// and is compressed
+const COMPRESSED_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "font/woff",
+    "font/woff2",
+    "application/zip",
+    "application/gzip",
+    "application/x-gzip",
+    "application/x-bzip2",
+    "application/x-7z-compressed",
+];
+
+pub struct Compression;
+
+impl<S> Middleware<S> for Compression {
+    fn response(&self, req: &HttpRequest<S>, mut resp: HttpResponse) -> Result<Response> {
+        let encoding = if should_compress(&resp) {
+            let accept_encoding = req
+                .headers()
+                .get(header::ACCEPT_ENCODING)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("");
+            negotiate(accept_encoding)
+        } else {
+            ContentEncoding::Identity
+        };
+        if resp.content_encoding().is_none() {
+            resp.set_content_encoding(encoding);
+        }
+        resp.headers_mut()
+            .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        Ok(Response::Done(resp))
+    }
+}
+
+fn should_compress(resp: &HttpResponse) -> bool {
+    if resp.headers().contains_key(header::CONTENT_ENCODING) {
+        return false;
+    }
+    let content_type = resp
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(';').next().unwrap_or("").trim().to_lowercase())
+        .unwrap_or_default();
+    if COMPRESSED_TYPES.contains(&content_type.as_str()) {
+        return false;
+    }
+    match resp.body() {
+        Body::Empty => false,
+        Body::Binary(ref binary) => binary.len() >= MIN_SIZE,
+        // Streams have unknown length, those are large exports
+        _ => true,
+    }
+}
+
+/// Encoding with the highest weight in `Accept-Encoding`, identity when
+/// neither brotli nor gzip is accepted
+fn negotiate(accept_encoding: &str) -> ContentEncoding {
+    let mut best = (ContentEncoding::Identity, 0.0);
+    for item in accept_encoding.split(',') {
+        let mut parts = item.split(';');
+        let coding = parts.next().unwrap_or("").trim().to_lowercase();
+        let quality = parts
+            .filter_map(|param| {
+                let param = param.trim();
+                if param.starts_with("q=") {
+                    param[2..].parse::<f32>().ok()
+                } else {
+                    None
+                }
+            })
+            .next()
+            .unwrap_or(1.0);
+        let encoding = match coding.as_str() {
+            "br" => ContentEncoding::Br,
+            "gzip" | "x-gzip" => ContentEncoding::Gzip,
+            _ => continue,
+        };
+        let better = quality > best.1
+            || (quality == best.1 && quality > 0.0 && encoding == ContentEncoding::Br);
+        if better {
+            best = (encoding, quality);
+        }
+    }
+    best.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(""), ContentEncoding::Identity);
+        assert_eq!(negotiate("gzip, deflate, br"), ContentEncoding::Br);
+        assert_eq!(negotiate("gzip, deflate"), ContentEncoding::Gzip);
+        assert_eq!(negotiate("br;q=0.5, gzip;q=0.8"), ContentEncoding::Gzip);
+        assert_eq!(negotiate("br;q=0, gzip;q=0"), ContentEncoding::Identity);
+        assert_eq!(negotiate("deflate"), ContentEncoding::Identity);
+    }
+
+    #[test]
+    fn test_should_compress() {
+        let page = HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body("x".repeat(MIN_SIZE));
+        assert!(should_compress(&page));
+        let small = HttpResponse::Ok().json("ok");
+        assert!(!should_compress(&small));
+        let png = HttpResponse::Ok()
+            .content_type("image/png")
+            .body(vec![0u8; MIN_SIZE * 2]);
+        assert!(!should_compress(&png));
+    }
+}
@@ -1,28 +1,120 @@
-use crate::blocking;
-use crate::db::{DbExecutor, RejectExpiredPayments};
+use crate::alerts::{self, Alerts, ALERT_CONFIG};
+use crate::clearing;
+use crate::db::{
+    AcquireJobLease, AutoConfirmTransactions, DbExecutor, DeleteApiRequests,
+    DeleteUsedReturnNonces, EnqueueJobs, GetCurrentHeight, GetRates, GetUnreportedStatusChanges,
+    MarkAsSeenInPool, RefreshDueViews, RejectExpiredPayments, ReleaseJobLease,
+    ReleasePendingCredits, ReplayCommits, SyncBlocks,
+};
+use crate::deny_list;
 use crate::errors::Error;
+use crate::feature_flags;
 use crate::fsm::{
-    Fsm, GetPendingPayments, GetUnreportedConfirmedPayments, GetUnreportedRejectedPayments,
-    RejectPayment, ReportPayment,
+    ConfirmByWallet, Fsm, GetInChainPayments, GetPendingPayments, GetUnreportedConfirmedPayments,
+    GetUnreportedRefundPayments, GetUnreportedRejectedPayments, Publish, RejectPayment,
 };
-use crate::models::{Transaction, TransactionStatus};
-use crate::node::Node;
-use crate::rates::RatesFetcher;
+use crate::integrations::{self, Notifier};
+use crate::jobs;
+use crate::leader::{LeaderElection, TryLead};
+use crate::mailer::{self, Mailer};
+use crate::metrics;
+use crate::models::{BlockHeader, Transaction};
+use crate::node::{Block, NodeClient};
+use crate::payout_webhook;
+use crate::plans;
+use crate::rate_limit;
+use crate::rates::{self, RatesFetcher};
+use crate::reconciliation;
+use crate::security_events;
+use crate::status;
+use crate::trace::{FutureTraceExt, Span, SpanKind};
+use crate::wallet::{OutputStatus, Wallet};
+use crate::wallet_version::{self, WalletCapabilities};
 use actix::prelude::*;
-use diesel::pg::PgConnection;
-use diesel::r2d2::{ConnectionManager, Pool};
-use diesel::{self, prelude::*};
-use futures::future::{join_all, Future};
+use chrono::{Duration, Utc};
+use futures::future::{err, join_all, ok, Either, Future};
 use log::*;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::rc::Rc;
+use std::time::Instant;
+use uuid::Uuid;
 
 const REQUST_BLOCKS_FROM_NODE: i64 = 10;
+/// Most blocks replayed at once
+pub const MAX_REPLAY_BLOCKS: i64 = 1000;
+const API_REQUESTS_RETENTION_DAYS: i64 = 7;
+/// How long the node should be failing before we trust the wallet
+/// to confirm payments
+const NODE_DOWN_FALLBACK_SECONDS: u64 = 60;
+const HEALTH_CHECK_SECONDS: u64 = 30;
+/// A job lease outlives a crashed instance by this long at most
+const JOB_LEASE_SECONDS: i64 = 10 * 60;
+
+/// Payouts due within `window_seconds` are sent to the wallet together,
+/// at most `size` of them in one batch
+#[derive(Debug, Clone)]
+pub struct PayoutBatchConfig {
+    pub window_seconds: u64,
+    pub size: i64,
+    /// How many wallet calls of a batch run at the same time
+    pub wallet_concurrency: usize,
+}
+
+impl Default for PayoutBatchConfig {
+    fn default() -> Self {
+        PayoutBatchConfig {
+            window_seconds: 60,
+            size: 20,
+            wallet_concurrency: 4,
+        }
+    }
+}
+
+impl PayoutBatchConfig {
+    /// Reads PAYOUT_BATCH_WINDOW_SECONDS, PAYOUT_BATCH_SIZE and
+    /// PAYOUT_WALLET_CONCURRENCY, defaults are used for unset ones
+    pub fn from_env() -> Self {
+        let default = PayoutBatchConfig::default();
+        PayoutBatchConfig {
+            window_seconds: env::var("PAYOUT_BATCH_WINDOW_SECONDS")
+                .map(|v| {
+                    v.parse()
+                        .expect("PAYOUT_BATCH_WINDOW_SECONDS must be a number")
+                })
+                .unwrap_or(default.window_seconds),
+            size: env::var("PAYOUT_BATCH_SIZE")
+                .map(|v| v.parse().expect("PAYOUT_BATCH_SIZE must be a number"))
+                .unwrap_or(default.size),
+            wallet_concurrency: env::var("PAYOUT_WALLET_CONCURRENCY")
+                .map(|v| {
+                    v.parse()
+                        .expect("PAYOUT_WALLET_CONCURRENCY must be a number")
+                })
+                .unwrap_or(default.wallet_concurrency),
+        }
+    }
+}
 
 pub struct Cron {
     db: Addr<DbExecutor>,
-    node: Node,
+    node: Box<dyn NodeClient>,
+    wallet: Wallet,
     fsm: Addr<Fsm>,
-    pool: Pool<ConnectionManager<PgConnection>>,
+    /// When syncing with the node started to fail
+    node_down_since: Option<Instant>,
+    payout_batches: PayoutBatchConfig,
+    leader: Addr<LeaderElection>,
+    mailer: Addr<Mailer>,
+    notifier: Addr<Notifier>,
+    /// Jobs run only on the instance holding the leader lock
+    is_leader: bool,
+    /// Identifies this instance in job leases
+    instance: String,
+    running_jobs: HashSet<&'static str>,
+    alerts: Alerts,
 }
 
 impl Actor for Cron {
@@ -30,25 +122,101 @@ impl Actor for Cron {
 
     fn started(&mut self, ctx: &mut Self::Context) {
         info!("Starting cron process");
-        let rates = RatesFetcher::new(self.db.clone());
+        elect_leader(self, ctx);
+        ctx.run_interval(std::time::Duration::new(5, 0), elect_leader);
+        // Every instance serves the status page, so every one checks
+        check_health(self, ctx);
         ctx.run_interval(
-            std::time::Duration::new(5, 0),
-            move |_instance: &mut Cron, _ctx: &mut Context<Self>| {
-                rates.fetch();
-            },
+            std::time::Duration::new(HEALTH_CHECK_SECONDS, 0),
+            check_health,
         );
-        ctx.run_interval(std::time::Duration::new(5, 0), reject_expired_payments);
-        ctx.run_interval(std::time::Duration::new(5, 0), process_pending_payments);
         ctx.run_interval(
-            std::time::Duration::new(5, 0),
-            process_unreported_confirmed_payments,
+            std::time::Duration::new(HEALTH_CHECK_SECONDS, 0),
+            check_rate_ages,
         );
         ctx.run_interval(
-            std::time::Duration::new(5, 0),
-            process_unreported_rejected_payments,
+            std::time::Duration::new(alerts::ALERT_CHECK_SECONDS, 0),
+            check_alerts,
+        );
+        reload_deny_list(self, ctx);
+        ctx.run_interval(
+            std::time::Duration::new(deny_list::DENY_LIST_RELOAD_SECONDS, 0),
+            reload_deny_list,
+        );
+        reload_feature_flags(self, ctx);
+        ctx.run_interval(
+            std::time::Duration::new(feature_flags::FEATURE_FLAG_RELOAD_SECONDS, 0),
+            reload_feature_flags,
+        );
+        reload_plans(self, ctx);
+        ctx.run_interval(
+            std::time::Duration::new(plans::PLAN_RELOAD_SECONDS, 0),
+            reload_plans,
+        );
+        // Every instance limits with its own buckets, so every one saves
+        // them
+        let res = rate_limit::load(self.db.clone())
+            .map_err(|e| error!("Cannot load the payment rate limits: {}", e));
+        ctx.spawn(res.into_actor(self));
+        ctx.run_interval(
+            std::time::Duration::new(rate_limit::RATE_LIMIT_SYNC_SECONDS, 0),
+            save_rate_limits,
+        );
+        check_wallet_version(self, ctx);
+        ctx.run_interval(
+            std::time::Duration::new(wallet_version::VERSION_CHECK_SECONDS, 0),
+            check_wallet_version,
+        );
+        let rates = RatesFetcher::new(self.db.clone());
+        schedule(
+            ctx,
+            "fetch_rates",
+            5,
+            move |_instance: &mut Cron, _ctx: &mut Context<Self>| -> Job {
+                rates.fetch();
+                Box::new(fut::ok(()))
+            },
+        );
+        schedule(ctx, "reject_expired_payments", 5, reject_expired_payments);
+        schedule(ctx, "process_pending_payments", 5, process_pending_payments);
+        schedule(ctx, "enqueue_reports", 5, enqueue_reports);
+        schedule(ctx, "sync_with_node", 5, sync_with_node);
+        schedule(ctx, "autoconfirmation", 5, autoconfirmation);
+        schedule(ctx, "check_pool", 5, check_pool);
+        schedule(ctx, "confirm_by_wallet", 30, confirm_by_wallet);
+        schedule(
+            ctx,
+            "enqueue_payout_batch",
+            self.payout_batches.window_seconds,
+            enqueue_payout_batch,
+        );
+        schedule(ctx, "deliver_payout_events", 5, deliver_payout_events);
+        schedule(ctx, "notify_payout_events", 5, notify_payout_events);
+        schedule(ctx, "send_receipts", 30, send_receipts);
+        schedule(ctx, "send_payment_reminders", 30, send_payment_reminders);
+        schedule(ctx, "send_security_alerts", 30, send_security_alerts);
+        schedule(
+            ctx,
+            "release_pending_credits",
+            clearing::RELEASE_CREDITS_SECONDS,
+            release_pending_credits,
+        );
+        schedule(ctx, "cleanup_api_requests", 3600, cleanup_api_requests);
+        schedule(ctx, "cleanup_return_nonces", 600, cleanup_return_nonces);
+        schedule(ctx, "refresh_views", 30, refresh_views);
+        schedule(ctx, "monitor_wallet_outputs", 600, monitor_wallet_outputs);
+        schedule(
+            ctx,
+            "reconcile_with_wallet",
+            24 * 3600,
+            reconcile_with_wallet,
+        );
+        schedule(
+            ctx,
+            "cancel_stale_wallet_txs",
+            24 * 3600,
+            cancel_stale_wallet_txs,
         );
-        ctx.run_interval(std::time::Duration::new(5, 0), sync_with_node);
-        ctx.run_interval(std::time::Duration::new(5, 0), autoconfirmation);
     }
 
     fn stopping(&mut self, _ctx: &mut Self::Context) -> Running {
@@ -60,31 +228,149 @@ impl Cron {
     pub fn new(
         db: Addr<DbExecutor>,
         fsm: Addr<Fsm>,
-        node: Node,
-        pool: Pool<ConnectionManager<PgConnection>>,
+        node: Box<dyn NodeClient>,
+        wallet: Wallet,
+        payout_batches: PayoutBatchConfig,
+        leader: Addr<LeaderElection>,
+        mailer: Addr<Mailer>,
+        notifier: Addr<Notifier>,
     ) -> Self {
         Cron {
             db,
             fsm,
             node,
-            pool,
+            wallet,
+            node_down_since: None,
+            payout_batches,
+            leader,
+            mailer,
+            notifier,
+            is_leader: false,
+            instance: Uuid::new_v4().to_string(),
+            running_jobs: HashSet::new(),
+            alerts: Alerts::default(),
         }
     }
 }
-fn reject_expired_payments(cron: &mut Cron, _: &mut Context<Cron>) {
+
+/// A run of a cron job, it's done when the future resolves
+type Job = Box<dyn ActorFuture<Item = (), Error = (), Actor = Cron>>;
+/// Whether the lease was acquired and the job was run
+type LeasedJob = Box<dyn ActorFuture<Item = bool, Error = (), Actor = Cron>>;
+
+/// Runs `job` every `seconds` on the leader. A tick is skipped while the
+/// previous run of the job is still going on here or on another instance.
+fn schedule(
+    ctx: &mut Context<Cron>,
+    name: &'static str,
+    seconds: u64,
+    job: impl FnMut(&mut Cron, &mut Context<Cron>) -> Job + 'static,
+) {
+    let job = Rc::new(RefCell::new(job));
+    ctx.run_interval(std::time::Duration::new(seconds, 0), move |cron, ctx| {
+        if !cron.is_leader {
+            return;
+        }
+        if !cron.running_jobs.insert(name) {
+            skip_tick(name, "running");
+            return;
+        }
+        let job = job.clone();
+        let lease = cron
+            .db
+            .send(AcquireJobLease {
+                name: s!(name),
+                instance: cron.instance.clone(),
+                lease_seconds: JOB_LEASE_SECONDS,
+            })
+            .from_err()
+            .and_then(|db_response| db_response)
+            .map_err(move |e: Error| error!("Cannot acquire lease for job {}: {}", name, e));
+        ctx.spawn(
+            lease
+                .into_actor(cron)
+                .and_then(move |acquired, cron, ctx| -> LeasedJob {
+                    if !acquired {
+                        skip_tick(name, "leased");
+                        return Box::new(fut::ok(false));
+                    }
+                    let mut span = Span::root(&format!("cron {}", name), SpanKind::Internal, None);
+                    span.set_attribute("cron.instance", &cron.instance);
+                    let run = (&mut *job.borrow_mut())(cron, ctx);
+                    Box::new(run.then(move |res, _, _| {
+                        if res.is_err() {
+                            span.set_error();
+                        }
+                        fut::ok(true)
+                    }))
+                })
+                .then(move |res, cron, _| {
+                    cron.running_jobs.remove(name);
+                    // Nothing to release when the lease wasn't acquired
+                    if let Ok(true) = res {
+                        let release = cron
+                            .db
+                            .send(ReleaseJobLease {
+                                name: s!(name),
+                                instance: cron.instance.clone(),
+                            })
+                            .from_err()
+                            .and_then(|db_response| db_response)
+                            .map_err(move |e: Error| {
+                                error!("Cannot release lease for job {}: {}", name, e)
+                            });
+                        actix::spawn(release);
+                    }
+                    fut::ok(())
+                }),
+        );
+    });
+}
+
+fn skip_tick(name: &'static str, reason: &'static str) {
+    metrics::inc(
+        "cron_skipped_ticks_total",
+        &[("job", name), ("reason", reason)],
+    );
+    debug!("Skip tick of {}: previous run is still {}", name, reason);
+}
+
+fn elect_leader(cron: &mut Cron, ctx: &mut Context<Cron>) {
+    let res = cron.leader.send(TryLead).from_err().and_then(|res| res);
+    ctx.spawn(res.into_actor(cron).then(|res, cron, _| {
+        let is_leader = match res {
+            Ok(is_leader) => is_leader,
+            Err(e) => {
+                error!("Got an error in leader election: {}", e);
+                false
+            }
+        };
+        if cron.is_leader && !is_leader {
+            warn!("This instance is not the leader anymore, cron jobs are paused");
+        }
+        cron.is_leader = is_leader;
+        fut::ok(())
+    }));
+}
+
+fn reject_expired_payments(cron: &mut Cron, _: &mut Context<Cron>) -> Job {
     debug!("run process_expired_payments");
+    let fsm = cron.fsm.clone();
     let res = cron
         .db
         .send(RejectExpiredPayments)
         .map_err(|e| Error::from(e))
-        .and_then(|db_response| {
-            db_response?;
+        .and_then(move |db_response| {
+            fsm.do_send(Publish(db_response?));
             Ok(())
         });
-    actix::spawn(res.map_err(|e| error!("Got an error in rejecting exprired payments {}", e)));
+    Box::new(
+        res.map_err(|e| error!("Got an error in rejecting exprired payments {}", e))
+            .into_actor(cron),
+    )
 }
 
-fn process_pending_payments(cron: &mut Cron, _: &mut Context<Cron>) {
+fn process_pending_payments(cron: &mut Cron, _: &mut Context<Cron>) -> Job {
     debug!("run process_pending_payments");
     let fsm = cron.fsm.clone();
     let res = cron
@@ -119,218 +405,706 @@ fn process_pending_payments(cron: &mut Cron, _: &mut Context<Cron>) {
             }
             join_all(futures).map(|_| ())
         });
-    actix::spawn(res.map_err(|e| error!("Got an error in processing penging payments {}", e)));
+    Box::new(
+        res.map_err(|e| error!("Got an error in processing penging payments {}", e))
+            .into_actor(cron),
+    )
 }
 
-fn process_unreported_confirmed_payments(cron: &mut Cron, _: &mut Context<Cron>) {
-    let res = cron
+/// Queues a report job for every unreported payment, the job runs the
+/// merchant's callback until it goes through. Merchants with verbose
+/// callbacks get one for pending and in chain payments too.
+fn enqueue_reports(cron: &mut Cron, _: &mut Context<Cron>) -> Job {
+    debug!("run enqueue_reports");
+    let db = cron.db.clone();
+    let confirmed = cron
         .fsm
         .send(GetUnreportedConfirmedPayments)
-        .map_err(|e| Error::General(s!(e)))
-        .and_then(move |db_response| {
-            let payments = db_response?;
-            Ok(payments)
+        .from_err()
+        .and_then(|fsm_response| fsm_response);
+    let rejected = cron
+        .fsm
+        .send(GetUnreportedRejectedPayments)
+        .from_err()
+        .and_then(|fsm_response| fsm_response);
+    let refunds = cron
+        .fsm
+        .send(GetUnreportedRefundPayments)
+        .from_err()
+        .and_then(|fsm_response| fsm_response);
+    let status_changes = cron
+        .db
+        .send(GetUnreportedStatusChanges)
+        .from_err()
+        .and_then(|db_response| db_response);
+    let res = confirmed.join4(rejected, refunds, status_changes).and_then(
+        move |(confirmed, rejected, refunds, status_changes)| {
+            let reports: Vec<_> = confirmed
+                .iter()
+                .map(|payment| payment.id)
+                .chain(rejected.iter().map(|payment| payment.id))
+                .chain(refunds.iter().map(|payment| payment.id))
+                .map(jobs::report_payment)
+                .chain(status_changes.iter().map(jobs::report_status))
+                .collect();
+            if reports.is_empty() {
+                return Either::A(ok(()));
+            }
+            Either::B(
+                db.send(EnqueueJobs(reports))
+                    .from_err()
+                    .and_then(|db_response| {
+                        let queued = db_response?;
+                        debug!("Queued {} payment reports", queued);
+                        Ok(())
+                    }),
+            )
+        },
+    );
+    Box::new(
+        res.map_err(|e: Error| error!("Got an error in queueing payment reports {}", e))
+            .into_actor(cron),
+    )
+}
+
+fn sync_with_node(cron: &mut Cron, _: &mut Context<Cron>) -> Job {
+    debug!("run sync_with_node");
+    let db = cron.db.clone();
+    let fsm = cron.fsm.clone();
+    let node = cron.node.clone();
+    let res = db
+        .send(GetCurrentHeight)
+        .from_err()
+        .and_then(|db_response| {
+            let last_height = db_response?;
+            Ok(last_height)
         })
-        .and_then({
-            let fsm = cron.fsm.clone();
-            move |payments| {
-                let mut futures = vec![];
-                debug!("Found {} unreported payments", payments.len());
-                for payment in payments {
-                    let payment_id = payment.id.clone();
-                    futures.push(
-                        fsm.send(ReportPayment { payment })
-                            .map_err(|e| Error::General(s!(e)))
-                            .and_then(|db_response| {
-                                db_response?;
-                                Ok(())
-                            })
-                            .or_else({
-                                move |e| {
-                                    warn!("Couldn't report payment {}: {}", payment_id, e);
-                                    Ok(())
+        .and_then(move |last_height| {
+            node.blocks(last_height + 1, last_height + 1 + REQUST_BLOCKS_FROM_NODE)
+                .and_then(move |results| {
+                    // Blocks after a malformed one wait, it's fetched again
+                    // on the next run
+                    let mut blocks = Vec::new();
+                    for result in results {
+                        match result {
+                            Ok(block) => blocks.push(block),
+                            Err(e) => {
+                                metrics::inc("node_malformed_blocks_total", &[]);
+                                error!("Sync with node stopped: {}", e);
+                                break;
+                            }
+                        }
+                    }
+                    let new_height =
+                        blocks
+                            .iter()
+                            .fold(last_height as u64, |current_height, block| {
+                                if block.header.height > current_height {
+                                    block.header.height
+                                } else {
+                                    current_height
                                 }
-                            }),
-                    );
+                            });
+                    let commits = output_commits(&blocks);
+                    debug!("Found {} non coinbase outputs", commits.len());
+                    let synced_at = Utc::now().naive_utc();
+                    let mut headers: Vec<BlockHeader> = blocks
+                        .iter()
+                        .map(|block| BlockHeader {
+                            height: block.header.height as i64,
+                            hash: block.header.hash.clone(),
+                            previous: block.header.previous.clone(),
+                            timestamp: block.header.timestamp.map(|ts| ts.naive_utc()),
+                            synced_at,
+                        })
+                        .collect();
+                    headers.sort_by_key(|header| header.height);
+                    let sync_blocks = SyncBlocks {
+                        commits,
+                        headers,
+                        new_height: new_height as i64,
+                    };
+                    let span = Span::child("db SyncBlocks", None).with_params(&sync_blocks);
+                    db.send(sync_blocks)
+                        .traced(span)
+                        .from_err()
+                        .and_then(move |db_response| {
+                            let synced = db_response?;
+                            fsm.do_send(Publish(synced.transactions));
+                            if let Some(fork_height) = synced.fork_height {
+                                metrics::inc("chain_reorgs_total", &[]);
+                                warn!(
+                                    "Block {} doesn't follow the synced chain, it was reorganized",
+                                    fork_height
+                                );
+                            }
+                            Ok(())
+                        })
+                })
+        });
+    Box::new(res.into_actor(cron).then(|res, cron, _| {
+        match res {
+            Ok(_) => cron.node_down_since = None,
+            Err(e) => {
+                error!("Got an error trying to sync with node: {}", e);
+                if cron.node_down_since.is_none() {
+                    cron.node_down_since = Some(Instant::now());
                 }
-                join_all(futures).map(|_| ()).map_err(|e| {
-                    error!("got an error {}", e);
-                    e
+            }
+        }
+        fut::ok(())
+    }))
+}
+
+/// Non coinbase outputs of the blocks mapped to their block height
+fn output_commits(blocks: &[Block]) -> HashMap<String, i64> {
+    blocks
+        .iter()
+        .flat_map(|block| block.outputs.iter())
+        .filter(|o| !o.is_coinbase())
+        .filter(|o| o.block_height.is_some())
+        .map(|o| (o.commit.clone(), o.block_height.unwrap() as i64))
+        .collect()
+}
+
+/// Matches outputs of blocks from `from` to `to` inclusive again, to pick
+/// up payments missed by the sync. Only synced heights can be replayed.
+#[derive(Debug, Deserialize)]
+pub struct ReplayBlocks {
+    pub from: i64,
+    pub to: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplayedBlocks {
+    pub from: i64,
+    pub to: i64,
+    pub blocks: usize,
+    /// Transactions which were found in chain
+    pub transactions: usize,
+}
+
+impl Message for ReplayBlocks {
+    type Result = Result<ReplayedBlocks, Error>;
+}
+
+impl Handler<ReplayBlocks> for Cron {
+    type Result = ResponseFuture<ReplayedBlocks, Error>;
+
+    fn handle(&mut self, msg: ReplayBlocks, _: &mut Self::Context) -> Self::Result {
+        let ReplayBlocks { from, to } = msg;
+        if from < 0 || from > to || to - from >= MAX_REPLAY_BLOCKS {
+            return Box::new(err(Error::InvalidEntity(format!(
+                "replay range should have from 1 to {} blocks",
+                MAX_REPLAY_BLOCKS
+            ))));
+        }
+        let db = self.db.clone();
+        let fsm = self.fsm.clone();
+        let node = self.node.clone();
+        Box::new(
+            db.send(GetCurrentHeight)
+                .from_err()
+                .and_then(move |db_response| {
+                    let current_height = db_response?;
+                    if to > current_height {
+                        return Err(Error::InvalidEntity(format!(
+                            "only blocks up to the synced height {} can be replayed",
+                            current_height
+                        )));
+                    }
+                    Ok(())
                 })
+                .and_then(move |_| node.blocks(from, to))
+                .and_then(|results| results.into_iter().collect::<Result<Vec<_>, _>>())
+                .and_then(move |blocks| {
+                    let commits = output_commits(&blocks);
+                    db.send(ReplayCommits { commits })
+                        .from_err()
+                        .and_then(move |db_response| {
+                            let found = db_response?;
+                            let transactions = found.len();
+                            fsm.do_send(Publish(found));
+                            info!(
+                                "Replayed blocks {} to {}, found {} transactions",
+                                from, to, transactions
+                            );
+                            Ok(ReplayedBlocks {
+                                from,
+                                to,
+                                blocks: blocks.len(),
+                                transactions,
+                            })
+                        })
+                }),
+        )
+    }
+}
+
+fn autoconfirmation(cron: &mut Cron, _: &mut Context<Cron>) -> Job {
+    debug!("run autoconfirmation");
+    let fsm = cron.fsm.clone();
+    let res = cron
+        .db
+        .send(AutoConfirmTransactions)
+        .traced(Span::child("db AutoConfirmTransactions", None))
+        .from_err()
+        .and_then(move |db_response| {
+            fsm.do_send(Publish(db_response?));
+            Ok(())
+        });
+    Box::new(
+        res.map_err(|e: Error| error!("Got an error trying to sync with node: {}", e))
+            .into_actor(cron),
+    )
+}
+
+/// Lets buyers know their transaction reached the network before
+/// it gets into a block
+fn check_pool(cron: &mut Cron, _: &mut Context<Cron>) -> Job {
+    debug!("run check_pool");
+    let db = cron.db.clone();
+    let res = cron.node.pool_transactions().and_then(move |txs| {
+        let commits: Vec<String> = txs
+            .iter()
+            .flat_map(|tx| tx.commits())
+            .map(|commit| commit.to_owned())
+            .collect();
+        if commits.is_empty() {
+            return Either::A(futures::future::ok(()));
+        }
+        let mark_as_seen = MarkAsSeenInPool { commits };
+        let span = Span::child("db MarkAsSeenInPool", None).with_params(&mark_as_seen);
+        Either::B(
+            db.send(mark_as_seen)
+                .traced(span)
+                .from_err()
+                .and_then(|db_response| {
+                    db_response?;
+                    Ok(())
+                }),
+        )
+    });
+    Box::new(
+        res.map_err(|e: Error| error!("Got an error trying to check the pool: {}", e))
+            .into_actor(cron),
+    )
+}
+
+/// Queues sending of due payouts, a batch which fails is retried by
+/// the job worker
+fn enqueue_payout_batch(cron: &mut Cron, _: &mut Context<Cron>) -> Job {
+    debug!("run enqueue_payout_batch");
+    let res = cron
+        .db
+        .send(EnqueueJobs(vec![jobs::process_payout_batch()]))
+        .from_err()
+        .and_then(|db_response| {
+            db_response?;
+            Ok(())
+        });
+    Box::new(
+        res.map_err(|e: Error| error!("Got an error in queueing payout batch {}", e))
+            .into_actor(cron),
+    )
+}
+
+fn deliver_payout_events(cron: &mut Cron, _: &mut Context<Cron>) -> Job {
+    debug!("run deliver_payout_events");
+    let res = payout_webhook::deliver(cron.db.clone());
+    Box::new(
+        res.map(|_| ())
+            .map_err(|e: Error| error!("Got an error in delivering payout events {}", e))
+            .into_actor(cron),
+    )
+}
+
+fn notify_payout_events(cron: &mut Cron, _: &mut Context<Cron>) -> Job {
+    debug!("run notify_payout_events");
+    let res = integrations::notify_payout_events(cron.db.clone(), cron.notifier.clone());
+    Box::new(
+        res.map(|_| ())
+            .map_err(|e: Error| error!("Got an error in pushing payout events {}", e))
+            .into_actor(cron),
+    )
+}
+
+fn send_receipts(cron: &mut Cron, _: &mut Context<Cron>) -> Job {
+    debug!("run send_receipts");
+    let res = mailer::send_receipts(cron.db.clone(), cron.mailer.clone());
+    Box::new(
+        res.map(|_| ())
+            .map_err(|e: Error| error!("Got an error in sending receipts {}", e))
+            .into_actor(cron),
+    )
+}
+
+fn send_payment_reminders(cron: &mut Cron, _: &mut Context<Cron>) -> Job {
+    debug!("run send_payment_reminders");
+    let res = mailer::send_reminders(cron.db.clone(), cron.mailer.clone());
+    Box::new(
+        res.map(|_| ())
+            .map_err(|e: Error| error!("Got an error in sending payment reminders {}", e))
+            .into_actor(cron),
+    )
+}
+
+fn send_security_alerts(cron: &mut Cron, _: &mut Context<Cron>) -> Job {
+    debug!("run send_security_alerts");
+    let res = security_events::send_alerts(cron.db.clone(), cron.mailer.clone());
+    Box::new(
+        res.map(|_| ())
+            .map_err(|e: Error| error!("Got an error in sending security alerts {}", e))
+            .into_actor(cron),
+    )
+}
+
+fn reconcile_with_wallet(cron: &mut Cron, _: &mut Context<Cron>) -> Job {
+    debug!("run reconcile_with_wallet");
+    let res = reconciliation::reconcile(cron.db.clone(), cron.wallet.clone());
+    Box::new(
+        res.map(|_| ())
+            .map_err(|e: Error| error!("Got an error in reconciliation with wallet {}", e))
+            .into_actor(cron),
+    )
+}
+
+fn cancel_stale_wallet_txs(cron: &mut Cron, _: &mut Context<Cron>) -> Job {
+    debug!("run cancel_stale_wallet_txs");
+    let res = reconciliation::cancel_stale(cron.db.clone(), cron.wallet.clone());
+    Box::new(
+        res.map(|_| ())
+            .map_err(|e: Error| {
+                error!("Got an error in cancelling stale wallet transactions {}", e)
+            })
+            .into_actor(cron),
+    )
+}
+
+fn release_pending_credits(cron: &mut Cron, _: &mut Context<Cron>) -> Job {
+    debug!("run release_pending_credits");
+    let res = cron
+        .db
+        .send(ReleasePendingCredits)
+        .from_err()
+        .and_then(|db_response| {
+            let released = db_response?;
+            if released > 0 {
+                info!("Released {} pending credits", released);
             }
+            Ok(())
         });
+    Box::new(
+        res.map_err(|e: Error| error!("Got an error in releasing pending credits {}", e))
+            .into_actor(cron),
+    )
+}
 
-    actix::spawn(res.map_err(|e| {
-        error!("got an error {}", e);
-        ()
-    }));
+fn cleanup_api_requests(cron: &mut Cron, _: &mut Context<Cron>) -> Job {
+    debug!("run cleanup_api_requests");
+    let res = cron
+        .db
+        .send(DeleteApiRequests {
+            created_before: Utc::now().naive_utc() - Duration::days(API_REQUESTS_RETENTION_DAYS),
+        })
+        .from_err()
+        .and_then(|db_response| {
+            db_response?;
+            Ok(())
+        });
+    Box::new(
+        res.map_err(|e: Error| error!("Got an error trying to clean up api requests: {}", e))
+            .into_actor(cron),
+    )
 }
 
-fn process_unreported_rejected_payments(cron: &mut Cron, _: &mut Context<Cron>) {
+fn cleanup_return_nonces(cron: &mut Cron, _: &mut Context<Cron>) -> Job {
+    debug!("run cleanup_return_nonces");
     let res = cron
-        .fsm
-        .send(GetUnreportedRejectedPayments)
-        .map_err(|e| Error::General(s!(e)))
-        .and_then(move |db_response| {
-            let payments = db_response?;
-            Ok(payments)
+        .db
+        .send(DeleteUsedReturnNonces)
+        .from_err()
+        .and_then(|db_response| {
+            db_response?;
+            Ok(())
+        });
+    Box::new(
+        res.map_err(|e: Error| error!("Got an error trying to clean up return nonces: {}", e))
+            .into_actor(cron),
+    )
+}
+
+/// Exports the number of unspent wallet outputs, many small outputs make
+/// payouts slow and expensive until they are consolidated
+fn monitor_wallet_outputs(cron: &mut Cron, _: &mut Context<Cron>) -> Job {
+    debug!("run monitor_wallet_outputs");
+    let threshold = cron.wallet.outputs_config().consolidation_threshold;
+    let res = cron.wallet.retrieve_outputs().and_then(move |outputs| {
+        let unspent = outputs
+            .iter()
+            .filter(|output| output.status == OutputStatus::Unspent)
+            .count();
+        metrics::set("wallet_unspent_outputs", &[], unspent as i64);
+        if unspent > threshold {
+            warn!(
+                "Wallet has {} unspent outputs, more than {}, consolidate them at /admin/wallet",
+                unspent, threshold
+            );
+        }
+        Ok(())
+    });
+    Box::new(
+        res.map_err(|e: Error| error!("Got an error trying to count wallet outputs: {}", e))
+            .into_actor(cron),
+    )
+}
+
+/// Exports node and wallet reachability and how far the sync is behind
+/// the node for the status page
+fn check_health(cron: &mut Cron, ctx: &mut Context<Cron>) {
+    let db = cron.db.clone();
+    let node = cron
+        .node
+        .tip()
+        .and_then(move |tip| {
+            db.send(GetCurrentHeight)
+                .from_err()
+                .and_then(move |db_response| {
+                    let current_height = db_response?;
+                    Ok(tip.height as i64 - current_height)
+                })
         })
-        .and_then({
-            let fsm = cron.fsm.clone();
-            move |payments| {
-                let mut futures = vec![];
-                debug!("Found {} unreported payments", payments.len());
-                for payment in payments {
-                    let payment_id = payment.id.clone();
-                    futures.push(
-                        fsm.send(ReportPayment { payment })
-                            .map_err(|e| Error::General(s!(e)))
-                            .and_then(|db_response| {
-                                db_response?;
-                                Ok(())
-                            })
-                            .or_else({
-                                move |e| {
-                                    warn!("Couldn't report payment {}: {}", payment_id, e);
-                                    Ok(())
-                                }
-                            }),
-                    );
+        .then(|res| {
+            match res {
+                Ok(lag) => status::record_node(Some(lag)),
+                Err(e) => {
+                    debug!("Node health check failed: {}", e);
+                    status::record_node(None);
                 }
-                join_all(futures).map(|_| ()).map_err(|e| {
-                    error!("got an error {}", e);
-                    e
-                })
             }
+            Ok::<_, ()>(())
         });
+    let wallet = cron.wallet.last_confirmed_height().then(|res| {
+        if let Err(ref e) = res {
+            debug!("Wallet health check failed: {}", e);
+        }
+        status::record_wallet(res.is_ok());
+        Ok::<_, ()>(())
+    });
+    ctx.spawn(node.join(wallet).map(|_| ()).into_actor(cron));
+}
 
-    actix::spawn(res.map_err(|e| {
-        error!("got an error {}", e);
-        ()
-    }));
+/// Picks the wallet API by the wallet's version, a wallet which can't be
+/// reached keeps the last known one
+/// Every instance refuses denied buyers, so every one reloads the list
+fn reload_deny_list(cron: &mut Cron, ctx: &mut Context<Cron>) {
+    let res = deny_list::reload(cron.db.clone())
+        .map_err(|e| error!("Cannot reload the deny list: {}", e));
+    ctx.spawn(res.into_actor(cron));
 }
-fn sync_with_node(cron: &mut Cron, _: &mut Context<Cron>) {
-    debug!("run sync_with_node");
-    let pool = cron.pool.clone();
-    let node = cron.node.clone();
-    let res = blocking::run({
-        let pool = pool.clone();
-        move || {
-            use crate::schema::current_height::dsl::*;
-            let conn: &PgConnection = &pool.get().unwrap();
-            let last_height: i64 = current_height.select(height).first(conn)?;
-            Ok(last_height)
+
+fn save_rate_limits(cron: &mut Cron, ctx: &mut Context<Cron>) {
+    let res = rate_limit::save(cron.db.clone())
+        .map_err(|e| error!("Cannot save the payment rate limits: {}", e));
+    ctx.spawn(res.into_actor(cron));
+}
+
+/// Flags are asked on every instance, so every one reloads them
+fn reload_feature_flags(cron: &mut Cron, ctx: &mut Context<Cron>) {
+    let res = feature_flags::reload(cron.db.clone())
+        .map_err(|e| error!("Cannot reload the feature flags: {}", e));
+    ctx.spawn(res.into_actor(cron));
+}
+
+/// Rate limits and callback retries of every instance follow the plans
+fn reload_plans(cron: &mut Cron, ctx: &mut Context<Cron>) {
+    let res = plans::reload(cron.db.clone()).map_err(|e| error!("Cannot reload the plans: {}", e));
+    ctx.spawn(res.into_actor(cron));
+}
+
+fn check_wallet_version(cron: &mut Cron, ctx: &mut Context<Cron>) {
+    let res = cron.wallet.check_version().then(|res| {
+        match res {
+            Ok(version) => wallet_version::record(WalletCapabilities::negotiate(version)),
+            Err(e) => warn!("Cannot check the wallet version: {}", e),
         }
-    })
-    .map_err(|e| e.into())
-    .and_then(move |last_height| {
-        node.blocks(last_height + 1, last_height + 1 + REQUST_BLOCKS_FROM_NODE)
-            .and_then(move |blocks| {
-                let new_height = blocks
-                    .iter()
-                    .fold(last_height as u64, |current_height, block| {
-                        if block.header.height > current_height {
-                            block.header.height
-                        } else {
-                            current_height
-                        }
-                    });
-                let commits: HashMap<String, i64> = blocks
-                    .iter()
-                    .flat_map(|block| block.outputs.iter())
-                    .filter(|o| !o.is_coinbase())
-                    .filter(|o| o.block_height.is_some())
-                    .map(|o| (o.commit.clone(), o.block_height.unwrap() as i64))
-                    .collect();
-                debug!("Found {} non coinbase outputs", commits.len());
-                blocking::run({
-                    let pool = pool.clone();
-                    move || {
-                        use crate::schema::transactions::dsl::*;
-                        let conn: &PgConnection = &pool.get().unwrap();
-                        conn.transaction(move || {
-                            let txs = transactions
-                                .filter(commit.eq_any(commits.keys()))
-                                .load::<Transaction>(conn)?;
-
-                            if txs.len() > 0 {
-                                debug!("Found {} transactions which got into chain", txs.len());
-                            }
-                            for tx in txs {
-                                let query =
-                                    diesel::update(transactions.filter(id.eq(tx.id.clone())));
-
-                                match tx.status {
-                                    TransactionStatus::Pending => query.set((
-                                        status.eq(TransactionStatus::InChain),
-                                        height.eq(commits.get(&tx.commit.unwrap()).unwrap()),
-                                    )),
-                                    TransactionStatus::Rejected => query.set((
-                                        status.eq(TransactionStatus::Refund),
-                                        height.eq(commits.get(&tx.commit.unwrap()).unwrap()),
-                                    )),
-                                    _ => {
-                                        return Err(Error::General(format!(
-                                            "Transaction {} in chain although it has status {}",
-                                            tx.id.clone(),
-                                            tx.status
-                                        )))
-                                    }
-                                }
-                                .get_result(conn)
-                                .map(|_: Transaction| ())
-                                .map_err::<Error, _>(|e| e.into())?;
-                            }
-                            {
-                                debug!("Set new last_height = {}", new_height);
-                                use crate::schema::current_height::dsl::*;
-                                diesel::update(current_height)
-                                    .set(height.eq(new_height as i64))
-                                    .execute(conn)
-                                    .map(|_| ())
-                                    .map_err::<Error, _>(|e| e.into())?;
-                            }
-                            Ok(())
-                        })
-                    }
-                })
-                .from_err()
-            })
+        Ok::<_, ()>(())
     });
-    actix::spawn(res.map_err(|e: Error| error!("Got an error trying to sync with node: {}", e)));
+    ctx.spawn(res.into_actor(cron));
 }
 
-fn autoconfirmation(cron: &mut Cron, _: &mut Context<Cron>) {
-    debug!("run autoconfirmation");
-    let res = blocking::run({
-        let pool = cron.pool.clone();
-        move || {
-            let conn: &PgConnection = &pool.get().unwrap();
-            let last_height = {
-                use crate::schema::current_height::dsl::*;
-                let last_height: i64 = current_height.select(height).first(conn)?;
-                last_height
-            };
-
-            use diesel::sql_query;
-            sql_query(format!(
-                "UPDATE transactions SET status = 'confirmed' WHERE
-            status = 'in_chain' and confirmations < {} - height",
-                last_height
-            ))
-            .execute(conn)?;
-            //use crate::schema::transactions::dsl::*;
-            //diesel::update(
-            //transactions
-            //.filter(status.eq(TransactionStatus::InChain))
-            //.filter(confirmations.lt(last_height - height)),
-            //)
-            //.set(status.eq(TransactionStatus::Confirmed))
-            //.execute(conn)?;
+/// Exports how long ago every rate was fetched, rates are used to price
+/// payments on every instance
+/// Every instance keeps its alerts, only the leader pushes them
+fn check_alerts(cron: &mut Cron, ctx: &mut Context<Cron>) {
+    let changes = cron.alerts.evaluate(
+        &ALERT_CONFIG,
+        &alerts::Observation::current(),
+        Instant::now(),
+    );
+    for change in &changes {
+        if change.firing {
+            warn!("{}", change.text);
+        } else {
+            info!("{}", change.text);
+        }
+    }
+    if changes.is_empty() || !cron.is_leader {
+        return;
+    }
+    let res = alerts::notify_admins(cron.db.clone(), cron.notifier.clone(), changes);
+    ctx.spawn(
+        res.map(|_| ())
+            .map_err(|e: Error| error!("Got an error trying to push alerts {}", e))
+            .into_actor(cron),
+    );
+}
+
+fn check_rate_ages(cron: &mut Cron, ctx: &mut Context<Cron>) {
+    let res = cron.db.send(GetRates).from_err().and_then(|db_response| {
+        rates::record_rate_ages(&db_response?, Utc::now().naive_utc());
+        Ok(())
+    });
+    ctx.spawn(
+        res.map_err(|e: Error| error!("Got an error trying to check rate ages: {}", e))
+            .into_actor(cron),
+    );
+}
 
+/// Refreshes materialized views which are due and exports their age
+fn refresh_views(cron: &mut Cron, _: &mut Context<Cron>) -> Job {
+    debug!("run refresh_views");
+    let res = cron
+        .db
+        .send(RefreshDueViews)
+        .from_err()
+        .and_then(|db_response| {
+            for view in db_response? {
+                if let Some(age_seconds) = view.age_seconds {
+                    metrics::set(
+                        "materialized_view_age_seconds",
+                        &[("view", view.name)],
+                        age_seconds,
+                    );
+                }
+                if let Some(e) = view.error {
+                    error!("Cannot refresh view {}: {}", view.name, e);
+                    metrics::inc(
+                        "materialized_view_refresh_failures_total",
+                        &[("view", view.name)],
+                    );
+                }
+            }
             Ok(())
-        }
-    })
-    .from_err();
-    actix::spawn(res.map_err(|e: Error| error!("Got an error trying to sync with node: {}", e)));
+        });
+    Box::new(
+        res.map_err(|e: Error| error!("Got an error trying to refresh views: {}", e))
+            .into_actor(cron),
+    )
+}
+
+/// Fallback for `sync_with_node` and `autoconfirmation`: when the node is
+/// unavailable, payments the wallet sees confirmed are advanced by the wallet's
+/// view of the chain and flagged as `confirmed_by_wallet`
+fn confirm_by_wallet(cron: &mut Cron, _: &mut Context<Cron>) -> Job {
+    match cron.node_down_since {
+        Some(since) if since.elapsed().as_secs() >= NODE_DOWN_FALLBACK_SECONDS => {}
+        _ => return Box::new(fut::ok(())),
+    }
+    debug!("run confirm_by_wallet");
+    let fsm = cron.fsm.clone();
+    let wallet = cron.wallet.clone();
+    let res = cron
+        .wallet
+        .last_confirmed_height()
+        .and_then({
+            let fsm = fsm.clone();
+            move |wallet_height| {
+                let pending = fsm
+                    .send(GetPendingPayments)
+                    .from_err()
+                    .and_then(|db_response| {
+                        let payments = db_response?;
+                        Ok(payments)
+                    });
+                let in_chain = fsm
+                    .send(GetInChainPayments)
+                    .from_err()
+                    .and_then(|db_response| {
+                        let payments = db_response?;
+                        Ok(payments)
+                    });
+                pending
+                    .join(in_chain)
+                    .map(move |payments| (payments, wallet_height as i64))
+            }
+        })
+        .and_then(move |((pending, in_chain), wallet_height)| {
+            debug!(
+                "Check {} pending and {} in chain payments in wallet",
+                pending.len(),
+                in_chain.len()
+            );
+            let mut futures = vec![];
+            for payment in pending {
+                if let Some(wallet_tx_id) = payment.wallet_tx_id {
+                    futures.push(Either::A(confirm_payment_by_wallet(
+                        fsm.clone(),
+                        &wallet,
+                        wallet_tx_id,
+                        payment,
+                        wallet_height,
+                    )));
+                }
+            }
+            for payment in in_chain {
+                if let Some(wallet_tx_id) = payment.wallet_tx_id {
+                    futures.push(Either::B(confirm_payment_by_wallet(
+                        fsm.clone(),
+                        &wallet,
+                        wallet_tx_id,
+                        payment,
+                        wallet_height,
+                    )));
+                }
+            }
+            join_all(futures).map(|_| ())
+        });
+    Box::new(
+        res.map_err(|e: Error| error!("Got an error trying to confirm payments by wallet: {}", e))
+            .into_actor(cron),
+    )
+}
+
+/// Confirms `payment` by the height the wallet saw its transaction at, if
+/// the wallet saw it
+fn confirm_payment_by_wallet<P>(
+    fsm: Addr<Fsm>,
+    wallet: &Wallet,
+    wallet_tx_id: i64,
+    payment: P,
+    wallet_height: i64,
+) -> impl Future<Item = (), Error = Error>
+where
+    P: Send + 'static,
+    ConfirmByWallet<P>: Message<Result = Result<Transaction, Error>>,
+    Fsm: Handler<ConfirmByWallet<P>>,
+{
+    wallet
+        .tx_height(wallet_tx_id)
+        .and_then(move |height| match height {
+            Some(height) => Either::A(
+                fsm.send(ConfirmByWallet {
+                    payment,
+                    height: height as i64,
+                    wallet_height,
+                })
+                .from_err()
+                .and_then(|db_response| {
+                    db_response?;
+                    Ok(())
+                }),
+            ),
+            None => Either::B(ok(())),
+        })
 }
@@ -1,31 +1,131 @@
+use crate::amount_tags::{self, AMOUNT_TAGS, MAX_AMOUNT_TAG};
+use crate::analytics::{
+    AnalyticsTotals, FeeBucket, Granularity, HeatmapCell, MerchantVolume, PayerWallets,
+    PaymentCountries, UnderpricedPayout, UnreportedPayments, VolumeBucket,
+};
+use crate::callback::{CallbackSettings, DEFAULT_CALLBACK_TIMEOUT_SECONDS};
+use crate::clearing::{CreditAction, CLEARING_CONFIG};
+use crate::clock::SharedClock;
 use crate::errors::*;
+use crate::fsm::TRANSFER_FEE;
+use crate::integrations::Integrations;
+use crate::metrics;
 use crate::models::{
-    Currency, Merchant, Money, Rate, Transaction, TransactionStatus, TransactionType,
-    NEW_PAYMENT_TTL_SECONDS,
+    format_invoice_number, validate_invoice_prefix, ApiRequest, ApiToken, BalanceCredit,
+    BlockHeader, Currency, DeniedNetwork, FeatureFlag, FeatureFlagOverride, InviteCode, Job,
+    Merchant, Money, PaymentSplit, PayoutBatch, PayoutEvent, PayoutEventType, PendingCredit, Plan,
+    Rate, RateLimitBucket, ReconciliationOrphan, RefundAddress, RefundReason, SecondFactor,
+    SecurityEvent, SecurityEventKind, SlateMessageCheck, Transaction, TransactionNote,
+    TransactionStatus, TransactionType, WebauthnCredential, NEW_PAYMENT_TTL_SECONDS,
+    PAYMENT_PROCESSING_SECONDS, RATE_LOCK_SECONDS,
+};
+use crate::payment_state::{
+    self, Confirmed, InChain, New, Payment, Pending, Refund, Rejected, State, Transition,
 };
+use crate::plans::{self, DEFAULT_PLAN};
+use crate::quote::{self, Quote};
+use crate::rates::{check_rate_age, MAX_RATE_AGE_SECONDS};
+use crate::referrals::{check_referral, referral_fee, ReferralMonth};
+use crate::refund_addresses::{check_destination, REFUND_ADDRESS_CONFIG};
+use crate::return_url;
+use crate::security_events::{login_alert, KnownLocation, FAILED_LOGIN_WINDOW_MINUTES};
+use crate::ser;
+use crate::settlement::{SettlementDay, SettlementShares};
+use crate::splits::{self, SplitRecipient};
+use crate::wallet::{OutputSelection, TxLogEntry};
 use actix::{Actor, SyncContext};
 use actix::{Handler, Message};
-use chrono::NaiveDateTime;
-use chrono::{Duration, Local, Utc};
+use chrono::Duration;
+use chrono::{Datelike, NaiveDate, NaiveDateTime};
+use chrono_tz::Tz;
 use data_encoding::BASE32;
+use diesel::connection::SimpleConnection;
 use diesel::pg::PgConnection;
-use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::r2d2::{self, ConnectionManager, CustomizeConnection, Pool};
 use diesel::{self, prelude::*};
-use log::info;
+use log::{debug, info, warn};
 use rand::seq::SliceRandom;
 use rand::{thread_rng, Rng};
+use rust_decimal::Decimal;
 use serde::Deserialize;
 use std::collections::HashMap;
 use uuid::Uuid;
 
-const MAX_REPORT_ATTEMPTS: i32 = 10; //Number or attemps we try to run merchant's callback
+/// Indexes the cron jobs and merchant listings rely on, without them
+/// their queries scan the whole transactions table
+pub const EXPECTED_INDEXES: &[&str] = &[
+    "commit_idx",
+    "transactions_unreported_idx",
+    "transactions_type_status_idx",
+    "transactions_unsent_receipts_idx",
+    "transactions_merchant_idx",
+    "transactions_merchant_status_idx",
+    "transactions_merchant_external_id_idx",
+    "jobs_due_idx",
+    SLATE_ID_INDEX,
+    CLAIMED_SLATE_ID_INDEX,
+];
+
+/// Unique index of slate ids, a slate is received for one payment only
+pub const SLATE_ID_INDEX: &str = "transactions_wallet_tx_slate_id_key";
+/// Unique index of claimed slate ids, a slate is claimed for one payment only
+pub const CLAIMED_SLATE_ID_INDEX: &str = "transactions_claimed_slate_id_key";
+
+/// Materialized view kept up to date by the `refresh_views` cron job.
+/// Views are refreshed concurrently, so each needs a unique index.
+#[derive(Debug, Clone, Copy)]
+pub struct MaterializedView {
+    pub name: &'static str,
+    /// How stale the view is allowed to get
+    pub refresh_seconds: i64,
+}
+
+pub const MATERIALIZED_VIEWS: &[MaterializedView] = &[
+    MaterializedView {
+        name: "analytics_hourly",
+        refresh_seconds: 600,
+    },
+    MaterializedView {
+        name: "analytics_merchant_daily",
+        refresh_seconds: 600,
+    },
+    MaterializedView {
+        name: "analytics_payer_wallets_daily",
+        refresh_seconds: 600,
+    },
+    MaterializedView {
+        name: "analytics_payment_countries_daily",
+        refresh_seconds: 600,
+    },
+    MaterializedView {
+        name: "unreported_summary",
+        refresh_seconds: 60,
+    },
+];
 
-pub struct DbExecutor(pub Pool<ConnectionManager<PgConnection>>);
+pub struct DbExecutor(pub Pool<ConnectionManager<PgConnection>>, pub SharedClock);
 
 impl Actor for DbExecutor {
     type Context = SyncContext<Self>;
 }
 
+/// Sets postgres `statement_timeout` (in milliseconds) on every pooled
+/// connection, so a slow query can't hold a DB executor forever. The
+/// session time zone is set to UTC too, timestamps are stored in UTC and
+/// `now()` in queries has to agree with them.
+#[derive(Debug)]
+pub struct StatementTimeout(pub u64);
+
+impl CustomizeConnection<PgConnection, r2d2::Error> for StatementTimeout {
+    fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), r2d2::Error> {
+        conn.batch_execute(&format!(
+            "SET statement_timeout = {}; SET TIME ZONE 'UTC'",
+            self.0
+        ))
+        .map_err(r2d2::Error::QueryError)
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct CreateMerchant {
     pub id: String,
@@ -33,6 +133,9 @@ pub struct CreateMerchant {
     pub password: String,
     pub wallet_url: Option<String>,
     pub callback_url: Option<String>,
+    pub payout_callback_url: Option<String>,
+    /// Used up in the same DB transaction the merchant is created in
+    pub invite_code: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -40,6 +143,21 @@ pub struct GetMerchant {
     pub id: String,
 }
 
+/// Merchants with `is_admin`
+#[derive(Debug, Deserialize)]
+pub struct GetAdmins;
+
+#[derive(Debug, Deserialize)]
+pub struct GetMerchantByOidcSubject {
+    pub subject: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LinkOidcSubject {
+    pub merchant_id: String,
+    pub subject: String,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct GetTransaction {
     pub transaction_id: Uuid,
@@ -50,6 +168,19 @@ pub struct GetTransactions {
     pub merchant_id: String,
     pub offset: i64,
     pub limit: i64,
+    pub transaction_type: Option<TransactionType>,
+    pub metadata_key: Option<String>,
+    pub metadata: Option<serde_json::Value>,
+}
+
+/// Merchant's payments matching any of the ids or order ids, or new
+/// tagged payments matching any of the grin amounts
+#[derive(Debug, Deserialize)]
+pub struct GetPaymentsByIds {
+    pub merchant_id: String,
+    pub ids: Vec<Uuid>,
+    pub external_ids: Vec<String>,
+    pub grin_amounts: Vec<i64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -62,19 +193,35 @@ pub struct CreateTransaction {
     pub message: String,
     pub transaction_type: TransactionType,
     pub redirect_url: Option<String>,
+    pub metadata: Option<serde_json::Value>,
+    /// Only payouts use it
+    #[serde(default)]
+    pub output_selection: Option<OutputSelection>,
+    /// Only payments use it, see `splits`
+    #[serde(default)]
+    pub splits: Vec<SplitRecipient>,
 }
 
+/// Creates all transactions or none, the results tell which ones failed
 #[derive(Debug, Deserialize)]
-pub struct UpdateTransactionStatus {
-    pub id: Uuid,
-    pub status: TransactionStatus,
+pub struct CreateTransactions {
+    pub transactions: Vec<CreateTransaction>,
+}
+
+/// Moves a payment from `F` to `T`, the payment must still be in `F`
+#[derive(Debug)]
+pub struct ChangeStatus<F: State, T: State> {
+    pub transition: Transition<F, T>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct RegisterRate {
-    pub rates: HashMap<String, f64>,
+    pub rates: HashMap<String, Decimal>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct GetRates;
+
 #[derive(Debug, Deserialize)]
 pub struct ConvertCurrency {
     pub amount: Money,
@@ -93,10 +240,12 @@ pub struct GetPaymentsByStatus(pub TransactionStatus);
 pub struct GetPayoutsByStatus(pub TransactionStatus);
 
 pub struct ConfirmTransaction {
-    pub transaction: Transaction,
+    pub transition: Transition<InChain, Confirmed>,
     pub confirmed_at: Option<NaiveDateTime>,
 }
 
+/// Records a failed callback, `next_attempt` is `None` once the merchant's
+/// retry policy gave up on it
 #[derive(Debug, Deserialize)]
 pub struct ReportAttempt {
     pub transaction_id: Uuid,
@@ -106,461 +255,5607 @@ pub struct ReportAttempt {
 #[derive(Debug, Deserialize)]
 pub struct GetUnreportedPaymentsByStatus(pub TransactionStatus);
 
+/// Pending and in chain payments of merchants with verbose callbacks,
+/// which the merchant wasn't called back about in their current status
+#[derive(Debug, Deserialize)]
+pub struct GetUnreportedStatusChanges;
+
+/// The merchant was called back about the payment being in `status`
+#[derive(Debug, Deserialize)]
+pub struct MarkStatusReported {
+    pub transaction_id: Uuid,
+    pub status: TransactionStatus,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Confirm2FA {
     pub merchant_id: String,
 }
 
+/// Also records a 2FA reset security event
 #[derive(Debug, Deserialize)]
 pub struct Reset2FA {
     pub merchant_id: String,
 }
 
+/// Dashboard login attempt, the result is the alert it raised
 #[derive(Debug, Deserialize)]
-pub struct GetCurrentHeight;
+pub struct RecordLogin {
+    pub merchant_id: String,
+    pub ip: Option<String>,
+    pub country: Option<String>,
+    pub success: bool,
+}
 
+/// Alerts of the merchant which weren't dismissed, the latest first
 #[derive(Debug, Deserialize)]
-pub struct RejectExpiredPayments;
-
-impl Message for CreateMerchant {
-    type Result = Result<Merchant, Error>;
+pub struct GetSecurityAlerts {
+    pub merchant_id: String,
 }
 
-impl Message for GetMerchant {
-    type Result = Result<Merchant, Error>;
+#[derive(Debug, Deserialize)]
+pub struct DismissSecurityAlerts {
+    pub merchant_id: String,
 }
 
-impl Message for GetTransaction {
-    type Result = Result<Transaction, Error>;
+#[derive(Debug, Deserialize)]
+pub struct GetUnsentSecurityAlerts {
+    pub limit: i64,
 }
 
-impl Message for GetPayment {
-    type Result = Result<Transaction, Error>;
+#[derive(Debug, Deserialize)]
+pub struct MarkSecurityAlertEmailed {
+    pub id: Uuid,
 }
 
-impl Message for GetPaymentsByStatus {
-    type Result = Result<Vec<Transaction>, Error>;
+#[derive(Debug, Deserialize)]
+pub struct SetSecondFactor {
+    pub merchant_id: String,
+    pub second_factor: SecondFactor,
 }
 
-impl Message for GetPayoutsByStatus {
-    type Result = Result<Vec<Transaction>, Error>;
+#[derive(Debug, Deserialize)]
+pub struct GetWebauthnCredentials {
+    pub merchant_id: String,
 }
 
-impl Message for GetTransactions {
-    type Result = Result<Vec<Transaction>, Error>;
+#[derive(Debug, Deserialize)]
+pub struct GetWebauthnCredential {
+    pub id: String,
+    pub merchant_id: String,
 }
 
-impl Message for CreateTransaction {
-    type Result = Result<Transaction, Error>;
-}
+#[derive(Debug, Deserialize)]
+pub struct CreateWebauthnCredential(pub WebauthnCredential);
 
-impl Message for UpdateTransactionStatus {
-    type Result = Result<Transaction, Error>;
+#[derive(Debug, Deserialize)]
+pub struct UpdateWebauthnSignCount {
+    pub id: String,
+    pub sign_count: i64,
 }
 
-impl Message for RegisterRate {
-    type Result = Result<(), Error>;
+#[derive(Debug, Deserialize)]
+pub struct DeleteWebauthnCredential {
+    pub id: String,
+    pub merchant_id: String,
 }
 
-impl Message for ConvertCurrency {
-    type Result = Result<Money, Error>;
-}
-impl Message for ConfirmTransaction {
-    type Result = Result<Transaction, Error>;
-}
+#[derive(Debug, Deserialize)]
+pub struct GetCurrentHeight;
 
-impl Message for ReportAttempt {
-    type Result = Result<(), Error>;
+#[derive(Debug, Deserialize)]
+pub struct RejectExpiredPayments;
+
+#[derive(Debug, Deserialize)]
+pub struct GetQuotes {
+    pub grin_amount: i64,
 }
 
-impl Message for GetUnreportedPaymentsByStatus {
-    type Result = Result<Vec<Transaction>, Error>;
+#[derive(Debug)]
+pub struct MarkAsPending {
+    pub transition: Transition<New, Pending>,
+    pub wallet_tx: TxLogEntry,
+    pub commit: Vec<u8>,
+    pub payer_public_key: Option<String>,
+    pub payer_message_unverified: bool,
+    pub slate_version: i32,
+    pub payer_user_agent: Option<String>,
 }
 
-impl Message for Confirm2FA {
-    type Result = Result<(), Error>;
+/// Holds a new payment for one slate submission, others fail with
+/// `PaymentProcessing` until it's done or `PAYMENT_PROCESSING_SECONDS`
+/// passed. The slate is recorded with the claim, so a slate another payment
+/// claimed or got already fails with `DuplicateSlate` before it reaches the
+/// wallet.
+#[derive(Debug)]
+pub struct ClaimPayment {
+    pub transaction_id: Uuid,
+    pub slate_id: String,
 }
 
-impl Message for Reset2FA {
-    type Result = Result<(), Error>;
+/// Lets the next slate of a payment through after a submission failed
+#[derive(Debug)]
+pub struct ReleasePayment {
+    pub transaction_id: Uuid,
 }
 
-impl Message for RejectExpiredPayments {
-    type Result = Result<(), Error>;
+/// Where the buyer opened the checkout page from, only the first location
+/// is kept
+#[derive(Debug)]
+pub struct SetPayerLocation {
+    pub transaction_id: Uuid,
+    pub country: Option<String>,
+    pub asn: Option<i64>,
 }
 
-impl Message for GetCurrentHeight {
-    type Result = Result<i64, Error>;
+#[derive(Debug)]
+pub struct MarkAsInChain {
+    pub transition: Transition<Pending, InChain>,
+    pub height: i64,
 }
 
-impl Handler<CreateMerchant> for DbExecutor {
-    type Result = Result<Merchant, Error>;
+/// The rejected payment got in chain, it's reported to the merchant
+/// again as refund
+#[derive(Debug)]
+pub struct MarkAsRefund {
+    pub transition: Transition<Rejected, Refund>,
+}
 
-    fn handle(&mut self, msg: CreateMerchant, _: &mut Self::Context) -> Self::Result {
-        use crate::schema::merchants::dsl::*;
-        let conn: &PgConnection = &self.0.get().unwrap();
-        const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
-    abcdefghijklmnopqrstuvwxyz\
-    0123456789";
+#[derive(Debug, Deserialize)]
+pub struct MarkAsReported {
+    pub transaction_id: Uuid,
+}
 
-        let mut rng = thread_rng();
-        let new_token: Option<String> = (0..64)
-            .map(|_| Some(*CHARSET.choose(&mut rng)? as char))
-            .collect();
-        let new_token_2fa = BASE32.encode(&rng.gen::<[u8; 10]>());
-        let new_merchant = Merchant {
-            id: msg.id,
-            email: msg.email,
-            password: msg.password,
-            wallet_url: msg.wallet_url,
-            balance: 0,
-            created_at: Local::now().naive_local() + Duration::hours(24),
-            callback_url: msg.callback_url,
-            token: new_token.ok_or(Error::General(s!("cannot generate rangom token")))?,
-            token_2fa: Some(new_token_2fa),
-            confirmed_2fa: false,
-        };
+#[derive(Debug, Deserialize)]
+pub struct RequoteTransaction {
+    pub transaction_id: Uuid,
+}
 
-        diesel::insert_into(merchants)
-            .values(&new_merchant)
-            .get_result(conn)
-            .map_err(|e| e.into())
-    }
+#[derive(Debug, Deserialize)]
+pub struct SyncBlocks {
+    /// Commits of outputs found in new blocks mapped to a block height
+    pub commits: HashMap<String, i64>,
+    /// Headers of the new blocks by ascending height
+    pub headers: Vec<BlockHeader>,
+    pub new_height: i64,
 }
 
-impl Handler<GetMerchant> for DbExecutor {
-    type Result = Result<Merchant, Error>;
+#[derive(Debug)]
+pub struct SyncedBlocks {
+    /// Height of the first new block which doesn't follow the synced chain,
+    /// if any
+    pub fork_height: Option<i64>,
+    /// Transactions which were found in chain
+    pub transactions: Vec<Transaction>,
+}
 
-    fn handle(&mut self, msg: GetMerchant, _: &mut Self::Context) -> Self::Result {
-        use crate::schema::merchants::dsl::*;
-        let conn: &PgConnection = &self.0.get().unwrap();
-        merchants
-            .find(msg.id)
-            .get_result(conn)
-            .map_err(|e| e.into())
-    }
+/// Commits of outputs in already synced blocks, only pending and rejected
+/// transactions are matched and `current_height` isn't changed
+#[derive(Debug, Deserialize)]
+pub struct ReplayCommits {
+    pub commits: HashMap<String, i64>,
 }
 
-impl Handler<GetTransaction> for DbExecutor {
-    type Result = Result<Transaction, Error>;
+/// Most recent synced blocks, newest first
+#[derive(Debug, Deserialize)]
+pub struct GetLatestBlocks {
+    pub limit: i64,
+}
 
-    fn handle(&mut self, msg: GetTransaction, _: &mut Self::Context) -> Self::Result {
-        use crate::schema::transactions::dsl::*;
-        let conn: &PgConnection = &self.0.get().unwrap();
-        transactions
-            .find(msg.transaction_id)
-            .get_result(conn)
-            .map_err(|e| e.into())
-    }
+#[derive(Debug, Deserialize)]
+pub struct GetBlock {
+    pub height: i64,
 }
 
-impl Handler<GetPayment> for DbExecutor {
-    type Result = Result<Transaction, Error>;
+#[derive(Debug, Deserialize)]
+pub struct AutoConfirmTransactions;
 
-    fn handle(&mut self, msg: GetPayment, _: &mut Self::Context) -> Self::Result {
-        use crate::schema::transactions::dsl::*;
-        let conn: &PgConnection = &self.0.get().unwrap();
-        transactions
-            .filter(id.eq(msg.transaction_id))
-            .filter(transaction_type.eq(TransactionType::Payment))
-            .get_result(conn)
-            .map_err(|e| e.into())
-    }
+/// Fallback for `SyncBlocks` and `AutoConfirmTransactions` when the node is
+/// unavailable, heights come from the wallet. The payment stays in chain
+/// while it doesn't have enough confirmations.
+#[derive(Debug)]
+pub struct MarkAsConfirmedByWallet<F: State> {
+    pub transition: Transition<F, Confirmed>,
+    pub height: i64,
+    pub wallet_height: i64,
 }
 
-impl Handler<GetPaymentsByStatus> for DbExecutor {
-    type Result = Result<Vec<Transaction>, Error>;
-
-    fn handle(&mut self, msg: GetPaymentsByStatus, _: &mut Self::Context) -> Self::Result {
-        use crate::schema::transactions::dsl::*;
-        let conn: &PgConnection = &self.0.get().unwrap();
-        transactions
-            .filter(transaction_type.eq(TransactionType::Payment))
-            .filter(status.eq(msg.0))
-            .load::<Transaction>(conn)
-            .map_err(|e| e.into())
-    }
+/// Flags pending payments whose outputs are in the node's transaction pool
+#[derive(Debug, Deserialize)]
+pub struct MarkAsSeenInPool {
+    pub commits: Vec<String>,
 }
 
-impl Handler<GetPayoutsByStatus> for DbExecutor {
-    type Result = Result<Vec<Transaction>, Error>;
+/// Puts up to `max_size` new payouts which are not in a batch yet
+/// into a new batch, oldest first. Payouts left new by a finished batch
+/// are picked up again.
+#[derive(Debug, Deserialize)]
+pub struct CreatePayoutBatch {
+    pub max_size: i64,
+}
 
-    fn handle(&mut self, msg: GetPayoutsByStatus, _: &mut Self::Context) -> Self::Result {
-        use crate::schema::transactions::dsl::*;
-        let conn: &PgConnection = &self.0.get().unwrap();
-        transactions
-            .filter(transaction_type.eq(TransactionType::Payout))
-            .filter(status.eq(msg.0))
-            .load::<Transaction>(conn)
-            .map_err(|e| e.into())
-    }
+#[derive(Debug, Deserialize)]
+pub struct MarkPayoutAsInitialized {
+    pub transaction_id: Uuid,
+    pub slate_id: Uuid,
+    pub fee: i64,
 }
 
-impl Handler<GetTransactions> for DbExecutor {
-    type Result = Result<Vec<Transaction>, Error>;
+#[derive(Debug, Deserialize)]
+pub struct CompletePayoutBatch {
+    pub batch_id: Uuid,
+}
 
-    fn handle(&mut self, msg: GetTransactions, _: &mut Self::Context) -> Self::Result {
-        use crate::schema::transactions::dsl::*;
-        let conn: &PgConnection = &self.0.get().unwrap();
-        transactions
-            .filter(merchant_id.eq(msg.merchant_id))
-            .offset(msg.offset)
-            .limit(msg.limit)
-            .load::<Transaction>(conn)
-            .map_err(|e| e.into())
-    }
+/// Transactions which should have a wallet counterpart, oldest first
+#[derive(Debug, Deserialize)]
+pub struct GetTransactionsWithSlate {
+    pub created_before: NaiveDateTime,
+    pub offset: i64,
+    pub limit: i64,
 }
 
-impl Handler<CreateTransaction> for DbExecutor {
-    type Result = Result<Transaction, Error>;
+/// Replaces orphans found by the previous reconciliation
+#[derive(Debug, Deserialize)]
+pub struct ReplaceReconciliationOrphans(pub Vec<ReconciliationOrphan>);
 
-    fn handle(&mut self, msg: CreateTransaction, _: &mut Self::Context) -> Self::Result {
-        use crate::schema::merchants::dsl::*;
-        use crate::schema::rates::dsl::*;
-        use crate::schema::transactions::dsl::*;
+#[derive(Debug, Deserialize)]
+pub struct GetReconciliationOrphans;
 
-        let conn: &PgConnection = &self.0.get().unwrap();
+/// Takes the lease on a cron job unless another instance holds
+/// an unexpired one, returns whether the lease was taken
+#[derive(Debug, Deserialize)]
+pub struct AcquireJobLease {
+    pub name: String,
+    pub instance: String,
+    pub lease_seconds: i64,
+}
 
-        if !merchants
-            .find(msg.merchant_id.clone())
-            .get_result::<Merchant>(conn)
-            .is_ok()
-        {
-            return Err(Error::InvalidEntity("merchant".to_owned()));
-        }
+#[derive(Debug, Deserialize)]
+pub struct ReleaseJobLease {
+    pub name: String,
+    pub instance: String,
+}
 
-        let exch_rate = match rates
-            .find(&msg.amount.currency.to_string())
-            .get_result::<Rate>(conn)
-            .optional()?
-        {
-            None => return Err(Error::UnsupportedCurrency(msg.amount.currency.to_string())),
-            Some(v) => v,
-        };
+/// Queues background jobs, a job with the key of an unfinished one
+/// isn't queued again
+#[derive(Debug)]
+pub struct EnqueueJobs(pub Vec<Job>);
 
-        let grins = msg.amount.convert_to(Currency::GRIN, exch_rate.rate);
+/// Locks due jobs for `lease_seconds` and counts their attempt, jobs
+/// locked by other workers are skipped
+#[derive(Debug, Deserialize)]
+pub struct ClaimJobs {
+    pub instance: String,
+    pub limit: i64,
+    pub lease_seconds: i64,
+}
 
-        let new_transaction = Transaction {
-            id: uuid::Uuid::new_v4(),
-            external_id: msg.external_id,
-            merchant_id: msg.merchant_id,
-            email: msg.email,
-            amount: msg.amount,
-            grin_amount: grins.amount,
-            status: TransactionStatus::New,
-            confirmations: msg.confirmations,
-            created_at: Local::now().naive_local(),
-            updated_at: Local::now().naive_local(),
-            report_attempts: 0,
-            next_report_attempt: None,
-            reported: false,
-            wallet_tx_id: None,
-            wallet_tx_slate_id: None,
-            message: msg.message,
-            slate_messages: None,
-            transfer_fee: None,
-            knockturn_fee: None,
-            real_transfer_fee: None,
-            transaction_type: msg.transaction_type,
-            height: None,
-            commit: None,
-            redirect_url: msg.redirect_url,
-        };
+#[derive(Debug, Deserialize)]
+pub struct CompleteJob {
+    pub id: Uuid,
+}
 
-        diesel::insert_into(transactions)
-            .values(&new_transaction)
-            .get_result(conn)
-            .map_err(|e| e.into())
-    }
+/// Records the error of a job run, the job runs again at `retry_at`
+/// or fails for good without it
+#[derive(Debug, Deserialize)]
+pub struct FailJob {
+    pub id: Uuid,
+    pub error: String,
+    pub retry_at: Option<NaiveDateTime>,
 }
 
-impl Handler<UpdateTransactionStatus> for DbExecutor {
+/// Queues a payout event, used when the event isn't a side effect
+/// of a status change, e.g. a failed wallet call
+#[derive(Debug, Deserialize)]
+pub struct RecordPayoutEvent {
+    pub transaction_id: Uuid,
+    pub event: PayoutEventType,
+}
+
+/// Payout events due for delivery with their payouts and merchants, oldest first
+#[derive(Debug, Deserialize)]
+pub struct GetUndeliveredPayoutEvents {
+    pub limit: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MarkPayoutEventDelivered {
+    pub id: Uuid,
+}
+
+/// Same as `ReportAttempt` for payout events
+#[derive(Debug, Deserialize)]
+pub struct PayoutEventAttempt {
+    pub id: Uuid,
+    pub next_attempt: Option<NaiveDateTime>,
+}
+
+/// Confirmed payments with a buyer's email whose receipt wasn't sent yet
+#[derive(Debug, Deserialize)]
+pub struct GetUnsentReceipts {
+    pub limit: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MarkReceiptSent {
+    pub transaction_id: Uuid,
+}
+
+/// New payments with a buyer's email which are idle for longer than the
+/// merchant's `payment_reminder_minutes` and weren't reminded of yet
+#[derive(Debug, Deserialize)]
+pub struct GetIdlePayments {
+    pub limit: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MarkReminderSent {
+    pub transaction_id: Uuid,
+}
+
+/// Email the buyer entered on the payment page, payments which already
+/// have an email are left as is
+#[derive(Debug, Deserialize)]
+pub struct SetReceiptEmail {
+    pub transaction_id: Uuid,
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateEmailBranding {
+    pub merchant_id: String,
+    pub logo_url: Option<String>,
+    pub footer: Option<String>,
+    pub reply_to: Option<String>,
+    pub payment_reminder_minutes: Option<i32>,
+}
+
+/// `timezone` is an IANA name, e.g. `Europe/Berlin`
+#[derive(Debug, Deserialize)]
+pub struct UpdateTimezone {
+    pub merchant_id: String,
+    pub timezone: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateSlateMessageCheck {
+    pub merchant_id: String,
+    pub check: SlateMessageCheck,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateInvoicePrefix {
+    pub merchant_id: String,
+    pub invoice_prefix: String,
+}
+
+#[derive(Debug)]
+pub struct UpdateCallbackSettings {
+    pub merchant_id: String,
+    pub settings: CallbackSettings,
+}
+
+#[derive(Debug)]
+pub struct UpdateIntegrations {
+    pub merchant_id: String,
+    pub integrations: Integrations,
+}
+
+/// Payout events not pushed to the merchant's chats yet, oldest first
+#[derive(Debug, Deserialize)]
+pub struct GetUnnotifiedPayoutEvents {
+    pub limit: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MarkPayoutEventNotified {
+    pub id: Uuid,
+}
+
+/// Days with confirmed transactions of a merchant, the latest first
+#[derive(Debug, Deserialize)]
+pub struct GetSettlementDays {
+    pub merchant_id: String,
+    pub offset: i64,
+    pub limit: i64,
+}
+
+/// Transactions of a merchant confirmed on `date`, in order of confirmation
+#[derive(Debug, Deserialize)]
+pub struct GetSettledTransactions {
+    pub merchant_id: String,
+    pub date: NaiveDate,
+}
+
+#[derive(Debug)]
+pub struct CreateNote(pub TransactionNote);
+
+/// Admin moves a stuck payment to `status`, the justification is kept
+/// as a note on the transaction
+#[derive(Debug, Deserialize)]
+pub struct ManualTransition {
+    pub transaction_id: Uuid,
+    pub admin_id: String,
+    pub status: TransactionStatus,
+    pub justification: String,
+}
+
+/// Notes of the transactions, the oldest first
+#[derive(Debug, Deserialize)]
+pub struct GetNotes {
+    pub transaction_ids: Vec<Uuid>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetDashboardStats {
+    pub merchant_id: String,
+}
+
+/// Days of balance history shown on the merchant dashboard
+pub const BALANCE_HISTORY_DAYS: i64 = 30;
+
+#[derive(Debug)]
+pub struct DashboardStats {
+    /// Balance at the end of each of the last `BALANCE_HISTORY_DAYS` days,
+    /// the oldest first
+    pub balance_history: Vec<i64>,
+    pub pending_payouts: Vec<Transaction>,
+    pub unreported_callbacks: i64,
+    pub confirmed_today: i64,
+    pub plan: Option<Plan>,
+    /// Payments created since the start of the month, counted against the
+    /// plan's quota
+    pub payments_this_month: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetApiRequests {
+    pub merchant_id: String,
+    pub limit: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteApiRequests {
+    pub created_before: NaiveDateTime,
+}
+
+/// Records the nonce of a verified return payload, a nonce the merchant
+/// verified before fails
+#[derive(Debug, Deserialize)]
+pub struct UseReturnNonce {
+    pub merchant_id: String,
+    pub nonce: String,
+}
+
+/// Deletes the nonces of return payloads which expired by now
+#[derive(Debug, Deserialize)]
+pub struct DeleteUsedReturnNonces;
+
+#[derive(Debug, Deserialize)]
+pub struct GetApiToken {
+    pub merchant_id: String,
+    pub token_hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetApiTokens {
+    pub merchant_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiToken(pub ApiToken);
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteApiToken {
+    pub id: Uuid,
+    pub merchant_id: String,
+}
+
+/// Refund address book of a merchant, oldest first
+#[derive(Debug, Deserialize)]
+pub struct GetRefundAddresses {
+    pub merchant_id: String,
+}
+
+/// `ip` and `country` are of whoever added the address, for the
+/// security alert
+#[derive(Debug, Deserialize)]
+pub struct CreateRefundAddress {
+    pub address: RefundAddress,
+    pub ip: Option<String>,
+    pub country: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteRefundAddress {
+    pub id: Uuid,
+    pub merchant_id: String,
+    pub ip: Option<String>,
+    pub country: Option<String>,
+}
+
+/// Chooses where a refunded payment is returned to, `address` is parsed
+/// by `refund_addresses::parse_address`
+#[derive(Debug, Deserialize)]
+pub struct SetRefundAddress {
+    pub merchant_id: String,
+    pub transaction_id: Uuid,
+    pub address: String,
+}
+
+/// Attributes the merchant to its referrer with the referrer's share in
+/// percent, `None` removes the attribution
+#[derive(Debug, Deserialize)]
+pub struct SetReferral {
+    pub merchant_id: String,
+    pub referral: Option<(String, Decimal)>,
+}
+
+/// Merchants the merchant referred
+#[derive(Debug, Deserialize)]
+pub struct GetReferredMerchants {
+    pub referrer_id: String,
+}
+
+/// Monthly summaries of a referrer, the latest first
+#[derive(Debug, Deserialize)]
+pub struct GetReferralMonths {
+    pub referrer_id: String,
+    pub limit: i64,
+}
+
+/// Splits of a payment of the platform `merchant_id`
+#[derive(Debug, Deserialize)]
+pub struct GetPaymentSplits {
+    pub merchant_id: String,
+    pub transaction_id: Uuid,
+}
+
+/// Makes the merchant a seller of the platform, `None` leaves it
+#[derive(Debug, Deserialize)]
+pub struct JoinPlatform {
+    pub merchant_id: String,
+    pub platform_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetSellers {
+    pub platform_id: String,
+}
+
+/// Shares the merchant gave and got of payments confirmed on `date`
+#[derive(Debug, Deserialize)]
+pub struct GetSettlementShares {
+    pub merchant_id: String,
+    pub date: NaiveDate,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateInviteCode(pub InviteCode);
+
+#[derive(Debug, Deserialize)]
+pub struct GetInviteCodes;
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteInviteCode {
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateDeniedNetwork(pub DeniedNetwork);
+
+#[derive(Debug, Deserialize)]
+pub struct GetDeniedNetworks;
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteDeniedNetwork {
+    pub network: String,
+}
+
+/// Creates or replaces the flag
+#[derive(Debug, Deserialize)]
+pub struct SetFeatureFlag(pub FeatureFlag);
+
+/// Creates or replaces the merchant's override
+#[derive(Debug, Deserialize)]
+pub struct SetFeatureFlagOverride(pub FeatureFlagOverride);
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteFeatureFlagOverride {
+    pub name: String,
+    pub merchant_id: String,
+}
+
+/// All flags admins set and all overrides
+#[derive(Debug, Deserialize)]
+pub struct GetFeatureFlags;
+
+#[derive(Debug, Deserialize)]
+pub struct GetRateLimitBuckets;
+
+#[derive(Debug, Deserialize)]
+pub struct GetPlans;
+
+/// Creates or replaces the buckets
+#[derive(Debug, Deserialize)]
+pub struct SaveRateLimitBuckets(pub Vec<RateLimitBucket>);
+
+/// Names of `EXPECTED_INDEXES` which don't exist in the database
+#[derive(Debug, Deserialize)]
+pub struct GetMissingIndexes;
+
+/// Moves pending credits which cleared to the available balance of their
+/// merchants, see `clearing`
+#[derive(Debug, Deserialize)]
+pub struct ReleasePendingCredits;
+
+/// Admin holds, releases or voids the pending credits of a payment, the
+/// justification is kept as a note on the transaction
+#[derive(Debug, Deserialize)]
+pub struct ChangePendingCredits {
+    pub transaction_id: Uuid,
+    pub admin_id: String,
+    pub action: CreditAction,
+    pub justification: String,
+}
+
+/// Pending credits of a payment
+#[derive(Debug, Deserialize)]
+pub struct GetPendingCredits {
+    pub transaction_id: Uuid,
+}
+
+/// Refreshes `MATERIALIZED_VIEWS` which are due, a view which failed
+/// doesn't stop the rest
+#[derive(Debug, Deserialize)]
+pub struct RefreshDueViews;
+
+#[derive(Debug)]
+pub struct ViewRefresh {
+    pub name: &'static str,
+    /// Seconds since the last successful refresh, `None` before the first
+    pub age_seconds: Option<i64>,
+    pub error: Option<Error>,
+}
+
+/// Payments created since the start of `since` by hour or day
+#[derive(Debug, Deserialize)]
+pub struct GetAnalyticsVolume {
+    pub granularity: Granularity,
+    pub since: NaiveDate,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetPaymentsHeatmap {
+    pub since: NaiveDate,
+}
+
+/// Merchants with the largest confirmed volume since `since`
+#[derive(Debug, Deserialize)]
+pub struct GetTopMerchants {
+    pub since: NaiveDate,
+    pub limit: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetAnalyticsTotals {
+    pub since: NaiveDate,
+}
+
+/// Slate versions and user agents of buyers' wallets, most used first
+#[derive(Debug, Deserialize)]
+pub struct GetPayerWallets {
+    pub since: NaiveDate,
+}
+
+/// Payments by the buyer's country, most payments first
+#[derive(Debug, Deserialize)]
+pub struct GetPaymentCountries {
+    pub since: NaiveDate,
+}
+
+/// Merchants with unreported payments, oldest first
+#[derive(Debug, Deserialize)]
+pub struct GetUnreportedSummary;
+
+/// Charged and actual transfer fees of payouts created since the start
+/// of `since` by hour or day
+#[derive(Debug, Deserialize)]
+pub struct GetFeeBuckets {
+    pub granularity: Granularity,
+    pub since: NaiveDate,
+}
+
+/// Payouts created since `since` whose wallet fee exceeded the charged
+/// fee, the largest difference first
+#[derive(Debug, Deserialize)]
+pub struct GetUnderpricedPayouts {
+    pub since: NaiveDate,
+    pub limit: i64,
+}
+
+impl Message for CreateMerchant {
+    type Result = Result<Merchant, Error>;
+}
+
+impl Message for GetMerchant {
+    type Result = Result<Merchant, Error>;
+}
+
+impl Message for GetAdmins {
+    type Result = Result<Vec<Merchant>, Error>;
+}
+
+impl Message for GetMerchantByOidcSubject {
+    type Result = Result<Merchant, Error>;
+}
+
+impl Message for LinkOidcSubject {
+    type Result = Result<(), Error>;
+}
+
+impl Message for GetTransaction {
+    type Result = Result<Transaction, Error>;
+}
+
+impl Message for GetPayment {
+    type Result = Result<Transaction, Error>;
+}
+
+impl Message for GetPaymentsByStatus {
+    type Result = Result<Vec<Transaction>, Error>;
+}
+
+impl Message for GetPayoutsByStatus {
+    type Result = Result<Vec<Transaction>, Error>;
+}
+
+impl Message for GetTransactions {
+    type Result = Result<Vec<Transaction>, Error>;
+}
+
+impl Message for GetPaymentsByIds {
+    type Result = Result<Vec<Transaction>, Error>;
+}
+
+impl Message for CreateTransaction {
+    type Result = Result<Transaction, Error>;
+}
+
+impl Message for CreateTransactions {
+    type Result = Result<Vec<Result<Transaction, Error>>, Error>;
+}
+
+impl<F: State, T: State> Message for ChangeStatus<F, T> {
+    type Result = Result<Transaction, Error>;
+}
+
+#[derive(Debug)]
+pub struct RecordApiRequest(pub ApiRequest);
+
+impl Message for RecordApiRequest {
+    type Result = Result<(), Error>;
+}
+
+impl Message for RegisterRate {
+    type Result = Result<(), Error>;
+}
+
+impl Message for GetRates {
+    type Result = Result<Vec<Rate>, Error>;
+}
+
+impl Message for ConvertCurrency {
+    type Result = Result<Money, Error>;
+}
+impl Message for ConfirmTransaction {
+    type Result = Result<Transaction, Error>;
+}
+
+impl Message for ReportAttempt {
+    type Result = Result<(), Error>;
+}
+
+impl Message for GetUnreportedPaymentsByStatus {
+    type Result = Result<Vec<Transaction>, Error>;
+}
+
+impl Message for GetUnreportedStatusChanges {
+    type Result = Result<Vec<Transaction>, Error>;
+}
+
+impl Message for MarkStatusReported {
+    type Result = Result<(), Error>;
+}
+
+impl Message for Confirm2FA {
+    type Result = Result<(), Error>;
+}
+
+impl Message for Reset2FA {
+    type Result = Result<(), Error>;
+}
+
+impl Message for RecordLogin {
+    type Result = Result<Option<SecurityEvent>, Error>;
+}
+
+impl Message for GetSecurityAlerts {
+    type Result = Result<Vec<SecurityEvent>, Error>;
+}
+
+impl Message for DismissSecurityAlerts {
+    type Result = Result<(), Error>;
+}
+
+impl Message for GetUnsentSecurityAlerts {
+    type Result = Result<Vec<(SecurityEvent, Merchant)>, Error>;
+}
+
+impl Message for MarkSecurityAlertEmailed {
+    type Result = Result<(), Error>;
+}
+
+/// The rejected payments
+impl Message for RejectExpiredPayments {
+    type Result = Result<Vec<Transaction>, Error>;
+}
+
+impl Message for SetSecondFactor {
+    type Result = Result<(), Error>;
+}
+
+impl Message for GetWebauthnCredentials {
+    type Result = Result<Vec<WebauthnCredential>, Error>;
+}
+
+impl Message for GetWebauthnCredential {
+    type Result = Result<WebauthnCredential, Error>;
+}
+
+impl Message for CreateWebauthnCredential {
+    type Result = Result<WebauthnCredential, Error>;
+}
+
+impl Message for UpdateWebauthnSignCount {
+    type Result = Result<(), Error>;
+}
+
+impl Message for DeleteWebauthnCredential {
+    type Result = Result<(), Error>;
+}
+
+impl Message for GetCurrentHeight {
+    type Result = Result<i64, Error>;
+}
+
+impl Message for GetQuotes {
+    type Result = Result<Vec<Quote>, Error>;
+}
+
+impl Message for MarkAsPending {
+    type Result = Result<Transaction, Error>;
+}
+
+impl Message for ClaimPayment {
+    type Result = Result<(), Error>;
+}
+
+impl Message for ReleasePayment {
+    type Result = Result<(), Error>;
+}
+
+impl Message for SetPayerLocation {
+    type Result = Result<(), Error>;
+}
+
+impl Message for MarkAsInChain {
+    type Result = Result<Transaction, Error>;
+}
+
+impl Message for MarkAsRefund {
+    type Result = Result<Transaction, Error>;
+}
+
+impl Message for MarkAsReported {
+    type Result = Result<(), Error>;
+}
+
+impl Message for RequoteTransaction {
+    type Result = Result<Transaction, Error>;
+}
+
+impl Message for SyncBlocks {
+    type Result = Result<SyncedBlocks, Error>;
+}
+
+/// Transactions which were found in chain
+impl Message for ReplayCommits {
+    type Result = Result<Vec<Transaction>, Error>;
+}
+
+impl Message for GetLatestBlocks {
+    type Result = Result<Vec<BlockHeader>, Error>;
+}
+
+impl Message for GetBlock {
+    type Result = Result<Option<BlockHeader>, Error>;
+}
+
+/// The confirmed payments
+impl Message for AutoConfirmTransactions {
+    type Result = Result<Vec<Transaction>, Error>;
+}
+
+impl<F: State> Message for MarkAsConfirmedByWallet<F> {
+    type Result = Result<Transaction, Error>;
+}
+
+impl Message for MarkAsSeenInPool {
+    type Result = Result<usize, Error>;
+}
+
+impl Message for CreatePayoutBatch {
+    type Result = Result<Option<(PayoutBatch, Vec<Transaction>)>, Error>;
+}
+
+impl Message for MarkPayoutAsInitialized {
+    type Result = Result<Transaction, Error>;
+}
+
+impl Message for CompletePayoutBatch {
+    type Result = Result<PayoutBatch, Error>;
+}
+
+impl Message for GetTransactionsWithSlate {
+    type Result = Result<Vec<Transaction>, Error>;
+}
+
+impl Message for ReplaceReconciliationOrphans {
+    type Result = Result<(), Error>;
+}
+
+impl Message for GetReconciliationOrphans {
+    type Result = Result<Vec<ReconciliationOrphan>, Error>;
+}
+
+impl Message for AcquireJobLease {
+    type Result = Result<bool, Error>;
+}
+
+impl Message for ReleaseJobLease {
+    type Result = Result<(), Error>;
+}
+
+impl Message for EnqueueJobs {
+    type Result = Result<usize, Error>;
+}
+
+impl Message for ClaimJobs {
+    type Result = Result<Vec<Job>, Error>;
+}
+
+impl Message for CompleteJob {
+    type Result = Result<(), Error>;
+}
+
+impl Message for FailJob {
+    type Result = Result<(), Error>;
+}
+
+impl Message for RecordPayoutEvent {
+    type Result = Result<(), Error>;
+}
+
+impl Message for GetUndeliveredPayoutEvents {
+    type Result = Result<Vec<(PayoutEvent, Transaction, Merchant)>, Error>;
+}
+
+impl Message for MarkPayoutEventDelivered {
+    type Result = Result<(), Error>;
+}
+
+impl Message for PayoutEventAttempt {
+    type Result = Result<(), Error>;
+}
+
+impl Message for GetUnsentReceipts {
+    type Result = Result<Vec<(Transaction, Merchant)>, Error>;
+}
+
+impl Message for MarkReceiptSent {
+    type Result = Result<(), Error>;
+}
+
+impl Message for SetReceiptEmail {
+    type Result = Result<Transaction, Error>;
+}
+
+impl Message for GetIdlePayments {
+    type Result = Result<Vec<(Transaction, Merchant)>, Error>;
+}
+
+impl Message for MarkReminderSent {
+    type Result = Result<(), Error>;
+}
+
+impl Message for UpdateEmailBranding {
+    type Result = Result<Merchant, Error>;
+}
+
+impl Message for UpdateTimezone {
+    type Result = Result<Merchant, Error>;
+}
+
+impl Message for UpdateSlateMessageCheck {
+    type Result = Result<Merchant, Error>;
+}
+
+impl Message for UpdateInvoicePrefix {
+    type Result = Result<Merchant, Error>;
+}
+
+impl Message for UpdateCallbackSettings {
+    type Result = Result<Merchant, Error>;
+}
+
+impl Message for UpdateIntegrations {
+    type Result = Result<Merchant, Error>;
+}
+
+impl Message for GetUnnotifiedPayoutEvents {
+    type Result = Result<Vec<(PayoutEvent, Transaction, Merchant)>, Error>;
+}
+
+impl Message for MarkPayoutEventNotified {
+    type Result = Result<(), Error>;
+}
+
+impl Message for GetSettlementDays {
+    type Result = Result<Vec<SettlementDay>, Error>;
+}
+
+impl Message for GetSettledTransactions {
+    type Result = Result<Vec<Transaction>, Error>;
+}
+
+impl Message for CreateNote {
+    type Result = Result<TransactionNote, Error>;
+}
+
+impl Message for ManualTransition {
+    type Result = Result<Transaction, Error>;
+}
+
+impl Message for GetNotes {
+    type Result = Result<Vec<TransactionNote>, Error>;
+}
+
+impl Message for GetDashboardStats {
+    type Result = Result<DashboardStats, Error>;
+}
+
+impl Message for GetApiRequests {
+    type Result = Result<Vec<ApiRequest>, Error>;
+}
+
+impl Message for DeleteApiRequests {
+    type Result = Result<(), Error>;
+}
+
+impl Message for UseReturnNonce {
+    type Result = Result<(), Error>;
+}
+
+impl Message for DeleteUsedReturnNonces {
+    type Result = Result<(), Error>;
+}
+
+impl Message for GetApiToken {
+    type Result = Result<ApiToken, Error>;
+}
+
+impl Message for GetApiTokens {
+    type Result = Result<Vec<ApiToken>, Error>;
+}
+
+impl Message for CreateApiToken {
+    type Result = Result<ApiToken, Error>;
+}
+
+impl Message for DeleteApiToken {
+    type Result = Result<(), Error>;
+}
+
+impl Message for GetRefundAddresses {
+    type Result = Result<Vec<RefundAddress>, Error>;
+}
+
+impl Message for CreateRefundAddress {
+    type Result = Result<RefundAddress, Error>;
+}
+
+impl Message for DeleteRefundAddress {
+    type Result = Result<(), Error>;
+}
+
+impl Message for SetRefundAddress {
+    type Result = Result<Transaction, Error>;
+}
+
+impl Message for SetReferral {
+    type Result = Result<Merchant, Error>;
+}
+
+impl Message for GetReferredMerchants {
+    type Result = Result<Vec<Merchant>, Error>;
+}
+
+impl Message for GetReferralMonths {
+    type Result = Result<Vec<ReferralMonth>, Error>;
+}
+
+impl Message for ReleasePendingCredits {
+    type Result = Result<usize, Error>;
+}
+
+impl Message for ChangePendingCredits {
+    type Result = Result<Vec<PendingCredit>, Error>;
+}
+
+impl Message for GetPendingCredits {
+    type Result = Result<Vec<PendingCredit>, Error>;
+}
+
+impl Message for GetPaymentSplits {
+    type Result = Result<(Transaction, Vec<PaymentSplit>), Error>;
+}
+
+impl Message for JoinPlatform {
+    type Result = Result<Merchant, Error>;
+}
+
+impl Message for GetSellers {
+    type Result = Result<Vec<Merchant>, Error>;
+}
+
+impl Message for GetSettlementShares {
+    type Result = Result<SettlementShares, Error>;
+}
+
+impl Message for CreateInviteCode {
+    type Result = Result<InviteCode, Error>;
+}
+
+impl Message for GetInviteCodes {
+    type Result = Result<Vec<InviteCode>, Error>;
+}
+
+impl Message for DeleteInviteCode {
+    type Result = Result<(), Error>;
+}
+
+impl Message for CreateDeniedNetwork {
+    type Result = Result<DeniedNetwork, Error>;
+}
+
+impl Message for GetDeniedNetworks {
+    type Result = Result<Vec<DeniedNetwork>, Error>;
+}
+
+impl Message for DeleteDeniedNetwork {
+    type Result = Result<(), Error>;
+}
+
+impl Message for SetFeatureFlag {
+    type Result = Result<FeatureFlag, Error>;
+}
+
+impl Message for SetFeatureFlagOverride {
+    type Result = Result<FeatureFlagOverride, Error>;
+}
+
+impl Message for DeleteFeatureFlagOverride {
+    type Result = Result<(), Error>;
+}
+
+impl Message for GetFeatureFlags {
+    type Result = Result<(Vec<FeatureFlag>, Vec<FeatureFlagOverride>), Error>;
+}
+
+impl Message for GetRateLimitBuckets {
+    type Result = Result<Vec<RateLimitBucket>, Error>;
+}
+
+impl Message for SaveRateLimitBuckets {
+    type Result = Result<(), Error>;
+}
+
+impl Message for GetPlans {
+    type Result = Result<Vec<Plan>, Error>;
+}
+
+impl Message for GetMissingIndexes {
+    type Result = Result<Vec<String>, Error>;
+}
+
+impl Message for RefreshDueViews {
+    type Result = Result<Vec<ViewRefresh>, Error>;
+}
+
+impl Message for GetAnalyticsVolume {
+    type Result = Result<Vec<VolumeBucket>, Error>;
+}
+
+impl Message for GetPaymentsHeatmap {
+    type Result = Result<Vec<HeatmapCell>, Error>;
+}
+
+impl Message for GetTopMerchants {
+    type Result = Result<Vec<MerchantVolume>, Error>;
+}
+
+impl Message for GetAnalyticsTotals {
+    type Result = Result<AnalyticsTotals, Error>;
+}
+
+impl Message for GetPayerWallets {
+    type Result = Result<Vec<PayerWallets>, Error>;
+}
+
+impl Message for GetPaymentCountries {
+    type Result = Result<Vec<PaymentCountries>, Error>;
+}
+
+impl Message for GetUnreportedSummary {
+    type Result = Result<Vec<UnreportedPayments>, Error>;
+}
+
+impl Message for GetFeeBuckets {
+    type Result = Result<Vec<FeeBucket>, Error>;
+}
+
+impl Message for GetUnderpricedPayouts {
+    type Result = Result<Vec<UnderpricedPayout>, Error>;
+}
+
+impl Handler<CreateMerchant> for DbExecutor {
+    type Result = Result<Merchant, Error>;
+
+    fn handle(&mut self, msg: CreateMerchant, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::merchants::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+    abcdefghijklmnopqrstuvwxyz\
+    0123456789";
+
+        let mut rng = thread_rng();
+        let new_token: Option<String> = (0..64)
+            .map(|_| Some(*CHARSET.choose(&mut rng)? as char))
+            .collect();
+        let new_token_2fa = BASE32.encode(&rng.gen::<[u8; 10]>());
+        let invite_code = msg.invite_code;
+        let new_merchant = Merchant {
+            id: msg.id,
+            email: msg.email,
+            password: msg.password,
+            wallet_url: msg.wallet_url,
+            balance: 0,
+            created_at: now,
+            callback_url: msg.callback_url,
+            token: new_token.ok_or(Error::General(s!("cannot generate rangom token")))?,
+            token_2fa: Some(new_token_2fa),
+            confirmed_2fa: false,
+            second_factor: SecondFactor::Totp,
+            oidc_subject: None,
+            is_admin: false,
+            payout_callback_url: msg.payout_callback_url,
+            email_logo_url: None,
+            email_footer: None,
+            email_reply_to: None,
+            timezone: s!("UTC"),
+            invoice_prefix: s!("KT"),
+            invoice_sequence: 0,
+            callback_timeout_seconds: DEFAULT_CALLBACK_TIMEOUT_SECONDS,
+            callback_headers: None,
+            callback_verify_tls: true,
+            telegram_chat_id: None,
+            slack_webhook_url: None,
+            callback_template: None,
+            slate_message_check: SlateMessageCheck::Off.to_string(),
+            verbose_callbacks: false,
+            callback_max_attempts: None,
+            callback_backoff_seconds: None,
+            callback_retry_window_seconds: None,
+            plan: s!(DEFAULT_PLAN),
+            payment_reminder_minutes: None,
+            platform_id: None,
+            referrer_id: None,
+            referral_share_bps: None,
+            pending_balance: 0,
+        };
+
+        conn.transaction(|| {
+            if let Some(ref invite_code) = invite_code {
+                redeem_invite_code(conn, invite_code, now)?;
+            }
+            diesel::insert_into(merchants)
+                .values(&new_merchant)
+                .get_result(conn)
+                .map_err(|e| e.into())
+        })
+    }
+}
+
+/// Counts a use of the code, fails when it's unknown, used up or expired
+fn redeem_invite_code(
+    conn: &PgConnection,
+    invite_code: &str,
+    now: NaiveDateTime,
+) -> Result<(), Error> {
+    use crate::schema::invite_codes::dsl::*;
+    let redeemed = diesel::update(
+        invite_codes
+            .filter(code.eq(invite_code))
+            .filter(uses.lt(max_uses))
+            .filter(expires_at.is_null().or(expires_at.gt(now))),
+    )
+    .set(uses.eq(uses + 1))
+    .execute(conn)?;
+    if redeemed == 0 {
+        return Err(Error::InvalidEntity(s!(
+            "invite code is invalid, used up or expired"
+        )));
+    }
+    Ok(())
+}
+
+impl Handler<GetMerchant> for DbExecutor {
+    type Result = Result<Merchant, Error>;
+
+    fn handle(&mut self, msg: GetMerchant, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::merchants::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        merchants
+            .find(msg.id)
+            .get_result(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetAdmins> for DbExecutor {
+    type Result = Result<Vec<Merchant>, Error>;
+
+    fn handle(&mut self, _: GetAdmins, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::merchants::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        merchants
+            .filter(is_admin.eq(true))
+            .load(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetMerchantByOidcSubject> for DbExecutor {
+    type Result = Result<Merchant, Error>;
+
+    fn handle(&mut self, msg: GetMerchantByOidcSubject, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::merchants::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        merchants
+            .filter(oidc_subject.eq(msg.subject))
+            .get_result(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<LinkOidcSubject> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: LinkOidcSubject, _: &mut Self::Context) -> Self::Result {
+        info!(
+            "Link OpenID Connect subject {} to merchant {}",
+            msg.subject, msg.merchant_id
+        );
+        use crate::schema::merchants::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::update(merchants.filter(id.eq(msg.merchant_id)))
+            .set(oidc_subject.eq(msg.subject))
+            .get_result(conn)
+            .map_err(|e| e.into())
+            .map(|_: Merchant| ())
+    }
+}
+
+impl Handler<GetTransaction> for DbExecutor {
+    type Result = Result<Transaction, Error>;
+
+    fn handle(&mut self, msg: GetTransaction, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        transactions
+            .find(msg.transaction_id)
+            .get_result(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetPayment> for DbExecutor {
+    type Result = Result<Transaction, Error>;
+
+    fn handle(&mut self, msg: GetPayment, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        transactions
+            .filter(id.eq(msg.transaction_id))
+            .filter(transaction_type.eq(TransactionType::Payment))
+            .get_result(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetPaymentsByStatus> for DbExecutor {
+    type Result = Result<Vec<Transaction>, Error>;
+
+    fn handle(&mut self, msg: GetPaymentsByStatus, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        transactions
+            .filter(transaction_type.eq(TransactionType::Payment))
+            .filter(status.eq(msg.0))
+            .load::<Transaction>(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetPayoutsByStatus> for DbExecutor {
+    type Result = Result<Vec<Transaction>, Error>;
+
+    fn handle(&mut self, msg: GetPayoutsByStatus, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        transactions
+            .filter(transaction_type.eq(TransactionType::Payout))
+            .filter(status.eq(msg.0))
+            .load::<Transaction>(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetTransactions> for DbExecutor {
+    type Result = Result<Vec<Transaction>, Error>;
+
+    fn handle(&mut self, msg: GetTransactions, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        use diesel::dsl::sql;
+        use diesel::sql_types::{Bool, Jsonb, Text};
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let mut query = transactions
+            .filter(merchant_id.eq(msg.merchant_id))
+            .into_boxed();
+        if let Some(tx_type) = msg.transaction_type {
+            query = query.filter(transaction_type.eq(tx_type));
+        }
+        if let Some(key) = msg.metadata_key {
+            query = query.filter(sql::<Bool>("metadata ? ").bind::<Text, _>(key));
+        }
+        if let Some(value) = msg.metadata {
+            query = query.filter(sql::<Bool>("metadata @> ").bind::<Jsonb, _>(value));
+        }
+        query
+            .order(created_at.desc())
+            .offset(msg.offset)
+            .limit(msg.limit)
+            .load::<Transaction>(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetPaymentsByIds> for DbExecutor {
+    type Result = Result<Vec<Transaction>, Error>;
+
+    fn handle(&mut self, msg: GetPaymentsByIds, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        transactions
+            .filter(merchant_id.eq(msg.merchant_id))
+            .filter(transaction_type.eq(TransactionType::Payment))
+            .filter(
+                id.eq_any(msg.ids)
+                    .or(external_id.eq_any(msg.external_ids))
+                    .or(amount_tag
+                        .is_not_null()
+                        .and(status.eq(TransactionStatus::New))
+                        .and(grin_amount.eq_any(msg.grin_amounts))),
+            )
+            .order(created_at.asc())
+            .load(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<CreateTransaction> for DbExecutor {
+    type Result = Result<Transaction, Error>;
+
+    fn handle(&mut self, msg: CreateTransaction, _: &mut Self::Context) -> Self::Result {
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        conn.transaction(|| create_transaction(conn, msg, now))
+    }
+}
+
+impl Handler<CreateTransactions> for DbExecutor {
+    type Result = Result<Vec<Result<Transaction, Error>>, Error>;
+
+    fn handle(&mut self, msg: CreateTransactions, _: &mut Self::Context) -> Self::Result {
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        let mut results = Vec::with_capacity(msg.transactions.len());
+        let batch = conn.transaction(|| {
+            for create_tx in msg.transactions {
+                // Savepoint per item, so a failed insert doesn't abort
+                // the transaction and the rest are still checked
+                results.push(conn.transaction(|| create_transaction(conn, create_tx, now)));
+            }
+            if results.iter().any(|res| res.is_err()) {
+                return Err(Error::InvalidEntity(s!("batch")));
+            }
+            Ok(())
+        });
+        match batch {
+            Err(e) if results.iter().all(|res| res.is_ok()) => Err(e),
+            _ => Ok(results),
+        }
+    }
+}
+
+fn create_transaction(
+    conn: &PgConnection,
+    msg: CreateTransaction,
+    now: NaiveDateTime,
+) -> Result<Transaction, Error> {
+    use crate::schema::merchants::dsl::*;
+    use crate::schema::transactions::dsl::*;
+
+    let merchant_plan = match merchants
+        .find(msg.merchant_id.clone())
+        .select(plan)
+        .get_result::<String>(conn)
+    {
+        Ok(merchant_plan) => merchant_plan,
+        Err(_) => return Err(Error::InvalidEntity("merchant".to_owned())),
+    };
+    // Locks the merchant until the payment is inserted, so numbers
+    // are given out in order and without gaps
+    let invoice = if msg.transaction_type == TransactionType::Payment {
+        let merchant: Merchant = diesel::update(merchants.find(msg.merchant_id.clone()))
+            .set(invoice_sequence.eq(invoice_sequence + 1))
+            .get_result(conn)?;
+        Some(format_invoice_number(
+            &merchant.invoice_prefix,
+            now.year(),
+            merchant.invoice_sequence,
+        ))
+    } else {
+        None
+    };
+
+    if let Some(ref selection) = msg.output_selection {
+        selection.validate()?;
+    }
+    if !msg.splits.is_empty() && msg.transaction_type != TransactionType::Payment {
+        return Err(Error::InvalidEntity(s!("only payments can be split")));
+    }
+    let (grins, exch_rate, exch_rate_updated_at) = convert_to_grins(conn, msg.amount, now)?;
+    if msg.transaction_type == TransactionType::Payment {
+        check_plan(conn, &msg.merchant_id, &merchant_plan, grins.amount, now)?;
+    }
+    let tag = if msg.transaction_type == TransactionType::Payment && *AMOUNT_TAGS {
+        Some(free_amount_tag(conn, &msg.merchant_id, grins.amount, None)?)
+    } else {
+        None
+    };
+
+    let mut new_transaction = Transaction {
+        id: uuid::Uuid::new_v4(),
+        external_id: msg.external_id,
+        merchant_id: msg.merchant_id,
+        email: msg.email,
+        amount: msg.amount,
+        grin_amount: grins.amount + tag.unwrap_or(0),
+        status: TransactionStatus::New,
+        confirmations: msg.confirmations,
+        created_at: now,
+        updated_at: now,
+        report_attempts: 0,
+        next_report_attempt: None,
+        reported: false,
+        wallet_tx_id: None,
+        wallet_tx_slate_id: None,
+        message: msg.message,
+        slate_messages: None,
+        transfer_fee: None,
+        knockturn_fee: None,
+        real_transfer_fee: None,
+        transaction_type: msg.transaction_type,
+        height: None,
+        commit: None,
+        redirect_url: msg.redirect_url,
+        exchange_rate: Some(exch_rate),
+        rate_locked_until: Some(now + Duration::seconds(RATE_LOCK_SECONDS)),
+        requotes: 0,
+        metadata: msg.metadata,
+        expires_at: None,
+        confirmed_by_wallet: false,
+        seen_in_pool_at: None,
+        payout_batch_id: None,
+        receipt_sent_at: None,
+        receipt_opt_in: false,
+        output_selection: msg.output_selection,
+        amount_tag: tag,
+        invoice_number: invoice,
+        payer_public_key: None,
+        reported_status: None,
+        refund_reason: None,
+        slate_version: None,
+        payer_user_agent: None,
+        rate_updated_at: exch_rate_updated_at,
+        payer_country: None,
+        payer_asn: None,
+        processing_until: None,
+        reminder_sent_at: None,
+        refund_address: None,
+        referrer_id: None,
+        referral_fee: None,
+        claimed_slate_id: None,
+        payer_message_unverified: false,
+    };
+    new_transaction.expires_at = new_transaction.payment_deadline();
+    let payment_splits = splits::new_splits(new_transaction.id, &msg.splits)?;
+
+    let transaction: Transaction = diesel::insert_into(transactions)
+        .values(&new_transaction)
+        .get_result(conn)?;
+    if !payment_splits.is_empty() {
+        create_splits(conn, &transaction.merchant_id, &payment_splits)?;
+    }
+    Ok(transaction)
+}
+
+/// Stores the splits of a new payment, all recipients must sell on the
+/// payment's merchant
+fn create_splits(
+    conn: &PgConnection,
+    platform: &str,
+    new_splits: &[PaymentSplit],
+) -> Result<(), Error> {
+    use crate::schema::merchants::dsl::*;
+    use crate::schema::payment_splits;
+
+    let recipients: Vec<Merchant> = merchants
+        .filter(id.eq_any(new_splits.iter().map(|split| &split.merchant_id)))
+        .load(conn)?;
+    for split in new_splits {
+        match recipients.iter().find(|m| m.id == split.merchant_id) {
+            Some(recipient) => splits::check_recipient(platform, recipient)?,
+            None => {
+                return Err(Error::InvalidEntity(format!(
+                    "merchant {} doesn't exist",
+                    split.merchant_id
+                )))
+            }
+        }
+    }
+    diesel::insert_into(payment_splits::table)
+        .values(new_splits)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Credits `grin_amount` of the payment less the knockturn fee of the
+/// merchant's plan to the balance of its merchant, split payments to the
+/// recipients of their shares. A referrer of the merchant is credited
+/// with its share of the fee. During the clearing period credits go to
+/// the pending balance. Only for confirmed payments, and only once, see
+/// `mark_as_reported`.
+fn credit_payment(
+    conn: &PgConnection,
+    payment: &Transaction,
+    now: NaiveDateTime,
+) -> Result<(), Error> {
+    use crate::schema::merchants;
+    use crate::schema::payment_splits;
+    use crate::schema::pending_credits;
+    use crate::schema::plans as plans_table;
+    use crate::schema::transactions;
+
+    if payment.transaction_type != TransactionType::Payment
+        || payment.status != TransactionStatus::Confirmed
+    {
+        return Err(Error::InvalidEntity(format!(
+            "{} {} is {}, only confirmed payments are credited",
+            payment.transaction_type, payment.id, payment.status
+        )));
+    }
+    let transaction_id = payment.id;
+    let platform = payment.merchant_id.as_str();
+    let grin_amount = payment.grin_amount;
+    let merchant: Merchant = merchants::table.find(platform).get_result(conn)?;
+    let plan: Plan = plans_table::table.find(&merchant.plan).get_result(conn)?;
+    let fee = plans::payment_fee(&plan, grin_amount);
+    let shares: Vec<PaymentSplit> = payment_splits::table
+        .filter(payment_splits::transaction_id.eq(transaction_id))
+        .order(payment_splits::merchant_id.asc())
+        .load(conn)?;
+    let mut credits = splits::credits(platform, grin_amount - fee, &shares);
+    if fee > 0 {
+        let referral = referral_fee(&merchant, fee);
+        diesel::update(transactions::table.find(transaction_id))
+            .set((
+                transactions::knockturn_fee.eq(fee),
+                transactions::referrer_id.eq(referral.as_ref().map(|(id, _)| id)),
+                transactions::referral_fee.eq(referral.as_ref().map(|(_, amount)| *amount)),
+            ))
+            .execute(conn)?;
+        credits.extend(referral);
+    }
+    let clearing = if CLEARING_CONFIG.is_immediate() {
+        None
+    } else {
+        let height = synced_height(conn)?;
+        Some((
+            CLEARING_CONFIG.available_at(now),
+            CLEARING_CONFIG.available_height(height),
+        ))
+    };
+    for (recipient, amount) in credits {
+        if amount != 0 {
+            record_balance_credit(conn, transaction_id, &recipient, amount, now)?;
+        }
+        let recipient = merchants::table.filter(merchants::columns::id.eq(&recipient));
+        match clearing {
+            Some((available_at, available_height)) if amount > 0 => {
+                let merchant: Merchant = diesel::update(recipient)
+                    .set(
+                        merchants::columns::pending_balance
+                            .eq(merchants::columns::pending_balance + amount),
+                    )
+                    .get_result(conn)?;
+                diesel::insert_into(pending_credits::table)
+                    .values(&PendingCredit {
+                        id: Uuid::new_v4(),
+                        transaction_id,
+                        merchant_id: merchant.id,
+                        grin_amount: amount,
+                        created_at: now,
+                        available_at,
+                        available_height,
+                        held: false,
+                    })
+                    .execute(conn)?;
+            }
+            _ => {
+                diesel::update(recipient)
+                    .set(merchants::columns::balance.eq(merchants::columns::balance + amount))
+                    .get_result(conn)
+                    .map(|_: Merchant| ())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Records a change of the merchant's balance or pending balance by a
+/// payment, the balance history is built from them
+fn record_balance_credit(
+    conn: &PgConnection,
+    transaction_id: Uuid,
+    merchant_id: &str,
+    grin_amount: i64,
+    now: NaiveDateTime,
+) -> Result<(), Error> {
+    use crate::schema::balance_credits;
+    diesel::insert_into(balance_credits::table)
+        .values(&BalanceCredit {
+            id: Uuid::new_v4(),
+            transaction_id,
+            merchant_id: merchant_id.to_owned(),
+            grin_amount,
+            created_at: now,
+        })
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Height the service synced to, 0 before the first sync
+fn synced_height(conn: &PgConnection) -> Result<i64, Error> {
+    use crate::schema::current_height;
+    Ok(current_height::table
+        .select(current_height::height)
+        .first(conn)
+        .optional()?
+        .unwrap_or(0))
+}
+
+/// Moves credits which aren't held and are available at `now` and the
+/// synced height from the pending balance to the available one, returns
+/// how many
+fn release_pending_credits(conn: &PgConnection, now: NaiveDateTime) -> Result<usize, Error> {
+    use crate::schema::merchants;
+    use crate::schema::pending_credits;
+
+    conn.transaction(|| {
+        let height = synced_height(conn)?;
+        let released: Vec<PendingCredit> = diesel::delete(
+            pending_credits::table
+                .filter(pending_credits::available_at.le(now))
+                .filter(pending_credits::available_height.le(height))
+                .filter(pending_credits::held.eq(false)),
+        )
+        .get_results(conn)?;
+        let mut amounts: HashMap<&str, i64> = HashMap::new();
+        for credit in &released {
+            *amounts.entry(&credit.merchant_id).or_insert(0) += credit.grin_amount;
+        }
+        for (merchant, amount) in amounts {
+            diesel::update(merchants::table.filter(merchants::columns::id.eq(merchant)))
+                .set((
+                    merchants::columns::pending_balance
+                        .eq(merchants::columns::pending_balance - amount),
+                    merchants::columns::balance.eq(merchants::columns::balance + amount),
+                ))
+                .execute(conn)?;
+        }
+        Ok(released.len())
+    })
+}
+
+/// Holds, releases or voids the pending credits of a payment, the
+/// justification is kept as a note of the payment. Returns the credits
+/// which were changed.
+fn change_pending_credits(
+    conn: &PgConnection,
+    msg: &ChangePendingCredits,
+    now: NaiveDateTime,
+) -> Result<Vec<PendingCredit>, Error> {
+    use crate::schema::merchants;
+    use crate::schema::pending_credits;
+    use crate::schema::transaction_notes;
+
+    if msg.justification.trim().is_empty() {
+        return Err(Error::InvalidEntity(s!("justification is required")));
+    }
+    conn.transaction(|| {
+        let credits =
+            pending_credits::table.filter(pending_credits::transaction_id.eq(msg.transaction_id));
+        let changed: Vec<PendingCredit> = match msg.action {
+            CreditAction::Hold => diesel::update(credits.filter(pending_credits::held.eq(false)))
+                .set(pending_credits::held.eq(true))
+                .get_results(conn)?,
+            CreditAction::Release => diesel::update(credits.filter(pending_credits::held.eq(true)))
+                .set(pending_credits::held.eq(false))
+                .get_results(conn)?,
+            CreditAction::Void => {
+                let voided: Vec<PendingCredit> = diesel::delete(credits).get_results(conn)?;
+                for credit in &voided {
+                    diesel::update(merchants::table.find(&credit.merchant_id))
+                        .set(
+                            merchants::columns::pending_balance
+                                .eq(merchants::columns::pending_balance - credit.grin_amount),
+                        )
+                        .execute(conn)?;
+                    record_balance_credit(
+                        conn,
+                        credit.transaction_id,
+                        &credit.merchant_id,
+                        -credit.grin_amount,
+                        now,
+                    )?;
+                }
+                voided
+            }
+        };
+        if changed.is_empty() {
+            return Err(Error::InvalidEntity(format!(
+                "payment {} has no pending credits to be {}",
+                msg.transaction_id, msg.action
+            )));
+        }
+        let mut note = TransactionNote::new(
+            msg.transaction_id,
+            &msg.admin_id,
+            &format!(
+                "Pending credits of {} nanogrins {}: {}",
+                changed.iter().map(|credit| credit.grin_amount).sum::<i64>(),
+                msg.action,
+                msg.justification.trim()
+            ),
+        )?;
+        note.created_at = now;
+        diesel::insert_into(transaction_notes::table)
+            .values(&note)
+            .execute(conn)?;
+        Ok(changed)
+    })
+}
+
+/// Refuses a payment over the quotas of the merchant's plan
+fn check_plan(
+    conn: &PgConnection,
+    merchant: &str,
+    plan_name: &str,
+    amount: i64,
+    now: NaiveDateTime,
+) -> Result<(), Error> {
+    use crate::schema::transactions::dsl::*;
+
+    let merchant_plan: Plan = {
+        use crate::schema::plans::dsl::*;
+        plans.find(plan_name).get_result(conn)?
+    };
+    let payments_this_month: i64 = transactions
+        .filter(merchant_id.eq(merchant))
+        .filter(transaction_type.eq(TransactionType::Payment))
+        .filter(created_at.ge(plans::month_start(now)))
+        .count()
+        .get_result(conn)?;
+    plans::check_payment(&merchant_plan, payments_this_month, amount)
+}
+
+/// Smallest tag no other new payment of the merchant with `amount` has,
+/// `except` is a payment being requoted
+fn free_amount_tag(
+    conn: &PgConnection,
+    merchant: &str,
+    amount: i64,
+    except: Option<Uuid>,
+) -> Result<i64, Error> {
+    use crate::schema::transactions::dsl::*;
+
+    let amounts: Vec<i64> = transactions
+        .filter(merchant_id.eq(merchant))
+        .filter(status.eq(TransactionStatus::New))
+        .filter(amount_tag.is_not_null())
+        .filter(id.ne(except.unwrap_or_else(Uuid::nil)))
+        .filter(grin_amount.between(amount + 1, amount + MAX_AMOUNT_TAG))
+        .select(grin_amount)
+        .load(conn)?;
+    amount_tags::free_tag(&amount_tags::taken_tags(amount, &amounts)).ok_or_else(|| {
+        Error::InvalidEntity(s!(
+            "too many new payments of this amount to tag another one"
+        ))
+    })
+}
+
+/// Converts amount to grins using the latest exchange rate, returns
+/// the amount in grins and the applied rate. Refuses a rate which wasn't
+/// updated for `RATE_MAX_AGE_SECONDS`.
+/// Grins, the rate and when the rate was fetched, which is `None` for GRIN
+pub fn convert_to_grins(
+    conn: &PgConnection,
+    amount: Money,
+    now: NaiveDateTime,
+) -> Result<(Money, Decimal, Option<NaiveDateTime>), Error> {
+    use crate::schema::rates::dsl::*;
+
+    if amount.currency == Currency::GRIN {
+        return Ok((amount, Decimal::new(1, 0), None));
+    }
+    let exch_rate = match rates
+        .find(&amount.currency.to_string())
+        .get_result::<Rate>(conn)
+        .optional()?
+    {
+        None => return Err(Error::UnsupportedCurrency(amount.currency.to_string())),
+        Some(v) => v,
+    };
+    check_rate_age(&exch_rate, now, *MAX_RATE_AGE_SECONDS)?;
+
+    let grins = amount
+        .convert_to(Currency::GRIN, exch_rate.rate)
+        .ok_or(Error::InvalidEntity(format!(
+            "cannot convert {} to GRIN with rate {}",
+            amount, exch_rate.rate
+        )))?;
+    Ok((grins, exch_rate.rate, Some(exch_rate.updated_at)))
+}
+
+impl<F: State, T: State> Handler<ChangeStatus<F, T>> for DbExecutor {
+    type Result = Result<Transaction, Error>;
+
+    fn handle(&mut self, msg: ChangeStatus<F, T>, _: &mut Self::Context) -> Self::Result {
+        let conn: &PgConnection = &self.0.get().unwrap();
+        change_status(conn, &msg.transition, self.1.now())
+    }
+}
+
+fn change_status<F: State, T: State>(
+    conn: &PgConnection,
+    transition: &Transition<F, T>,
+    now: NaiveDateTime,
+) -> Result<Transaction, Error> {
+    use crate::schema::transactions::dsl::*;
+    diesel::update(
+        transactions
+            .filter(id.eq(transition.transaction_id()))
+            .filter(status.eq(transition.from())),
+    )
+    .set((status.eq(transition.to()), updated_at.eq(now)))
+    .get_result(conn)
+    .optional()?
+    .ok_or_else(|| moved_on(transition))
+}
+
+/// The payment isn't in the transition's initial state anymore
+fn moved_on<F: State, T: State>(transition: &Transition<F, T>) -> Error {
+    Error::WrongTransactionStatus(format!(
+        "payment {} is not {} anymore",
+        transition.transaction_id(),
+        transition.from()
+    ))
+}
+
+impl Handler<RegisterRate> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: RegisterRate, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::rates::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+
+        for (currency, new_rate) in msg.rates {
+            let new_rate = Rate {
+                id: currency.to_uppercase(),
+                rate: new_rate,
+                updated_at: now,
+            };
+
+            diesel::insert_into(rates)
+                .values(&new_rate)
+                .on_conflict(id)
+                .do_update()
+                .set(&new_rate)
+                .get_result::<Rate>(conn)
+                .map_err(|e| Error::from(e))?;
+        }
+        Ok(())
+    }
+}
+
+impl Handler<GetRates> for DbExecutor {
+    type Result = Result<Vec<Rate>, Error>;
+
+    fn handle(&mut self, _: GetRates, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::rates::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        rates.order(id.asc()).load(conn).map_err(|e| e.into())
+    }
+}
+
+impl Handler<RecordApiRequest> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: RecordApiRequest, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::api_requests::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::insert_into(api_requests)
+            .values(&msg.0)
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<ConfirmTransaction> for DbExecutor {
+    type Result = Result<Transaction, Error>;
+
+    fn handle(&mut self, msg: ConfirmTransaction, _: &mut Self::Context) -> Self::Result {
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        confirm_transaction(conn, &msg.transition, now)
+    }
+}
+
+/// Confirms a transaction which is in chain, the merchant is credited
+/// when it's reported. Overlapping cron runs confirming the same
+/// transaction both succeed.
+fn confirm_transaction(
+    conn: &PgConnection,
+    transition: &Transition<InChain, Confirmed>,
+    now: NaiveDateTime,
+) -> Result<Transaction, Error> {
+    use crate::schema::transactions;
+    conn.transaction(|| {
+        let confirmed: Option<Transaction> = diesel::update(
+            transactions::table
+                .filter(transactions::columns::id.eq(transition.transaction_id()))
+                .filter(transactions::columns::status.eq(transition.from())),
+        )
+        .set((
+            transactions::columns::status.eq(transition.to()),
+            transactions::columns::updated_at.eq(now),
+        ))
+        .get_result(conn)
+        .optional()?;
+        let tx = match confirmed {
+            Some(tx) => tx,
+            None => {
+                let tx: Transaction = transactions::table
+                    .find(transition.transaction_id())
+                    .get_result(conn)?;
+                if tx.status != TransactionStatus::Confirmed {
+                    return Err(Error::InvalidEntity(format!(
+                        "transaction {} is {}, not in chain",
+                        tx.id, tx.status
+                    )));
+                }
+                return Ok(tx);
+            }
+        };
+        Ok(tx)
+    })
+}
+
+impl Handler<ReportAttempt> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: ReportAttempt, _: &mut Self::Context) -> Self::Result {
+        let conn: &PgConnection = &self.0.get().unwrap();
+        report_attempt(conn, &msg)
+    }
+}
+
+fn report_attempt(conn: &PgConnection, msg: &ReportAttempt) -> Result<(), Error> {
+    use crate::schema::transactions::dsl::*;
+    diesel::update(transactions.filter(id.eq(msg.transaction_id)))
+        .set((
+            report_attempts.eq(report_attempts + 1),
+            next_report_attempt.eq(msg.next_attempt),
+        ))
+        .get_result(conn)
+        .map_err(|e| e.into())
+        .map(|_: Transaction| ())
+}
+
+impl Handler<GetUnreportedPaymentsByStatus> for DbExecutor {
+    type Result = Result<Vec<Transaction>, Error>;
+
+    fn handle(
+        &mut self,
+        msg: GetUnreportedPaymentsByStatus,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        use diesel::dsl::not;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+
+        let query = transactions
+            .filter(not(reported))
+            .filter(status.eq(msg.0))
+            // Without a next attempt only payments never tried are due,
+            // the others ran out of retries
+            .filter(
+                next_report_attempt
+                    .le(now)
+                    .or(next_report_attempt.is_null().and(report_attempts.eq(0))),
+            );
+
+        let payments = query
+            .load::<Transaction>(conn)
+            .map_err(|e| Error::Db(s!(e)))?;
+
+        Ok(payments)
+    }
+}
+
+impl Handler<GetUnreportedStatusChanges> for DbExecutor {
+    type Result = Result<Vec<Transaction>, Error>;
+
+    fn handle(&mut self, _: GetUnreportedStatusChanges, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::merchants;
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        transactions
+            .filter(transaction_type.eq(TransactionType::Payment))
+            .filter(status.eq_any(vec![TransactionStatus::Pending, TransactionStatus::InChain]))
+            .filter(
+                reported_status
+                    .is_null()
+                    .or(reported_status.ne(status.nullable())),
+            )
+            .filter(
+                merchant_id.eq_any(
+                    merchants::table
+                        .select(merchants::id)
+                        .filter(merchants::verbose_callbacks)
+                        .filter(merchants::callback_url.is_not_null()),
+                ),
+            )
+            .load(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<MarkStatusReported> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: MarkStatusReported, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::update(
+            transactions
+                .filter(id.eq(msg.transaction_id))
+                .filter(status.eq(msg.status)),
+        )
+        .set(reported_status.eq(Some(msg.status)))
+        .execute(conn)?;
+        Ok(())
+    }
+}
+
+impl Handler<Confirm2FA> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: Confirm2FA, _: &mut Self::Context) -> Self::Result {
+        info!("Confirm 2fa token for merchant {}", msg.merchant_id);
+        use crate::schema::merchants::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::update(merchants.filter(id.eq(msg.merchant_id)))
+            .set((confirmed_2fa.eq(true),))
+            .get_result(conn)
+            .map_err(|e| e.into())
+            .map(|_: Merchant| ())
+    }
+}
+
+impl Handler<Reset2FA> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: Reset2FA, _: &mut Self::Context) -> Self::Result {
+        info!("Reset 2fa token for merchant {}", msg.merchant_id);
+        use crate::schema::merchants::dsl::*;
+        use crate::schema::security_events;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+
+        let new_token_2fa = BASE32.encode(&thread_rng().gen::<[u8; 10]>());
+        conn.transaction(|| {
+            let _: Merchant = diesel::update(merchants.filter(id.eq(&msg.merchant_id)))
+                .set((confirmed_2fa.eq(false), token_2fa.eq(new_token_2fa)))
+                .get_result(conn)?;
+            diesel::insert_into(security_events::table)
+                .values(&SecurityEvent::new(
+                    &msg.merchant_id,
+                    SecurityEventKind::TwoFactorReset,
+                    None,
+                    None,
+                    now,
+                ))
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+}
+
+impl Handler<RecordLogin> for DbExecutor {
+    type Result = Result<Option<SecurityEvent>, Error>;
+
+    fn handle(&mut self, msg: RecordLogin, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::security_events::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        let of_merchant = security_events.filter(merchant_id.eq(&msg.merchant_id));
+        conn.transaction(|| {
+            let (attempt, alert) = if msg.success {
+                let known: Vec<KnownLocation> = of_merchant
+                    .filter(kind.eq(SecurityEventKind::Login.to_string()))
+                    .select((ip, country))
+                    .distinct()
+                    .load(conn)?;
+                let alert = login_alert(
+                    true,
+                    0,
+                    &known,
+                    msg.ip.as_ref().map(String::as_str),
+                    msg.country.as_ref().map(String::as_str),
+                );
+                (SecurityEventKind::Login, alert)
+            } else {
+                let recent_failures: i64 = of_merchant
+                    .filter(kind.eq(SecurityEventKind::LoginFailed.to_string()))
+                    .filter(created_at.gt(now - Duration::minutes(FAILED_LOGIN_WINDOW_MINUTES)))
+                    .count()
+                    .get_result(conn)?;
+                let alert = login_alert(false, recent_failures + 1, &[], None, None);
+                (SecurityEventKind::LoginFailed, alert)
+            };
+            diesel::insert_into(security_events)
+                .values(&SecurityEvent::new(
+                    &msg.merchant_id,
+                    attempt,
+                    msg.ip.clone(),
+                    msg.country.clone(),
+                    now,
+                ))
+                .execute(conn)?;
+            match alert {
+                Some(alert) => {
+                    let event = SecurityEvent::new(
+                        &msg.merchant_id,
+                        alert,
+                        msg.ip.clone(),
+                        msg.country.clone(),
+                        now,
+                    );
+                    diesel::insert_into(security_events)
+                        .values(&event)
+                        .execute(conn)?;
+                    Ok(Some(event))
+                }
+                None => Ok(None),
+            }
+        })
+    }
+}
+
+fn alert_kinds() -> Vec<String> {
+    SecurityEventKind::ALERTS
+        .iter()
+        .map(|kind| kind.to_string())
+        .collect()
+}
+
+impl Handler<GetSecurityAlerts> for DbExecutor {
+    type Result = Result<Vec<SecurityEvent>, Error>;
+
+    fn handle(&mut self, msg: GetSecurityAlerts, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::security_events::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        security_events
+            .filter(merchant_id.eq(msg.merchant_id))
+            .filter(kind.eq_any(alert_kinds()))
+            .filter(dismissed.eq(false))
+            .order(created_at.desc())
+            .limit(10)
+            .load(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<DismissSecurityAlerts> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: DismissSecurityAlerts, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::security_events::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::update(
+            security_events
+                .filter(merchant_id.eq(msg.merchant_id))
+                .filter(dismissed.eq(false)),
+        )
+        .set(dismissed.eq(true))
+        .execute(conn)?;
+        Ok(())
+    }
+}
+
+impl Handler<GetUnsentSecurityAlerts> for DbExecutor {
+    type Result = Result<Vec<(SecurityEvent, Merchant)>, Error>;
+
+    fn handle(&mut self, msg: GetUnsentSecurityAlerts, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::merchants;
+        use crate::schema::security_events::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        security_events
+            .inner_join(merchants::table)
+            .filter(kind.eq_any(alert_kinds()))
+            .filter(emailed_at.is_null())
+            .order(created_at.asc())
+            .limit(msg.limit)
+            .load(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<MarkSecurityAlertEmailed> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: MarkSecurityAlertEmailed, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::security_events::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        diesel::update(security_events.filter(id.eq(msg.id)))
+            .set(emailed_at.eq(now))
+            .execute(conn)?;
+        Ok(())
+    }
+}
+
+impl Handler<SetSecondFactor> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: SetSecondFactor, _: &mut Self::Context) -> Self::Result {
+        info!(
+            "Set second factor {} for merchant {}",
+            msg.second_factor, msg.merchant_id
+        );
+        use crate::schema::merchants::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::update(merchants.filter(id.eq(msg.merchant_id)))
+            .set(second_factor.eq(msg.second_factor))
+            .get_result(conn)
+            .map_err(|e| e.into())
+            .map(|_: Merchant| ())
+    }
+}
+
+impl Handler<GetWebauthnCredentials> for DbExecutor {
+    type Result = Result<Vec<WebauthnCredential>, Error>;
+
+    fn handle(&mut self, msg: GetWebauthnCredentials, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::webauthn_credentials::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        webauthn_credentials
+            .filter(merchant_id.eq(msg.merchant_id))
+            .order(created_at.asc())
+            .load::<WebauthnCredential>(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetWebauthnCredential> for DbExecutor {
+    type Result = Result<WebauthnCredential, Error>;
+
+    fn handle(&mut self, msg: GetWebauthnCredential, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::webauthn_credentials::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        webauthn_credentials
+            .filter(id.eq(msg.id))
+            .filter(merchant_id.eq(msg.merchant_id))
+            .get_result::<WebauthnCredential>(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<CreateWebauthnCredential> for DbExecutor {
+    type Result = Result<WebauthnCredential, Error>;
+
+    fn handle(&mut self, msg: CreateWebauthnCredential, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::webauthn_credentials::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::insert_into(webauthn_credentials)
+            .values(&msg.0)
+            .get_result(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<UpdateWebauthnSignCount> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: UpdateWebauthnSignCount, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::webauthn_credentials::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::update(webauthn_credentials.filter(id.eq(msg.id)))
+            .set(sign_count.eq(msg.sign_count))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<DeleteWebauthnCredential> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: DeleteWebauthnCredential, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::webauthn_credentials::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::delete(
+            webauthn_credentials
+                .filter(id.eq(msg.id))
+                .filter(merchant_id.eq(msg.merchant_id)),
+        )
+        .execute(conn)
+        .map(|_| ())
+        .map_err(|e| e.into())
+    }
+}
+
+impl Handler<RejectExpiredPayments> for DbExecutor {
+    type Result = Result<Vec<Transaction>, Error>;
+
+    fn handle(&mut self, _: RejectExpiredPayments, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        // expiration time depends on the rate lock, so let the model decide
+        let expired: Vec<Transaction> = transactions
+            .filter(status.eq(TransactionStatus::New))
+            .filter(transaction_type.eq(TransactionType::Payment))
+            .filter(created_at.lt(now - Duration::seconds(NEW_PAYMENT_TTL_SECONDS)))
+            .load::<Transaction>(conn)?
+            .into_iter()
+            .filter(|tx| tx.is_expired_at(now))
+            .collect();
+        let mut rejected = vec![];
+        for tx in expired {
+            match change_status(conn, &Payment::<New>::load(tx)?.reject(), now) {
+                Ok(tx) => rejected.push(tx),
+                // Paid meanwhile
+                Err(Error::WrongTransactionStatus(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !rejected.is_empty() {
+            info!("Rejected {} expired new payments", rejected.len());
+        }
+        Ok(rejected)
+    }
+}
+impl Handler<GetCurrentHeight> for DbExecutor {
+    type Result = Result<i64, Error>;
+
+    fn handle(&mut self, _: GetCurrentHeight, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::current_height::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        current_height
+            .select(height)
+            .first(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetQuotes> for DbExecutor {
+    type Result = Result<Vec<Quote>, Error>;
+
+    fn handle(&mut self, msg: GetQuotes, _: &mut Self::Context) -> Self::Result {
+        let conn: &PgConnection = &self.0.get().unwrap();
+        quote::quote(conn, msg.grin_amount)
+    }
+}
+
+impl Handler<MarkAsPending> for DbExecutor {
+    type Result = Result<Transaction, Error>;
+
+    fn handle(&mut self, msg: MarkAsPending, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let messages: Option<Vec<String>> = msg.wallet_tx.messages.map(|pm| {
+            pm.messages
+                .into_iter()
+                .map(|pmd| pmd.message)
+                .filter_map(|x| x)
+                .collect()
+        });
+        let transition = msg.transition;
+        let slate_id = msg.wallet_tx.tx_slate_id.unwrap();
+        conn.transaction(|| {
+            let transaction = diesel::update(
+                transactions
+                    .filter(id.eq(transition.transaction_id()))
+                    .filter(status.eq(transition.from())),
+            )
+            .set((
+                wallet_tx_id.eq(msg.wallet_tx.id as i64),
+                wallet_tx_slate_id.eq(&slate_id),
+                slate_messages.eq(messages),
+                real_transfer_fee.eq(msg.wallet_tx.fee.map(|fee| fee as i64)),
+                status.eq(transition.to()),
+                commit.eq(ser::to_hex(msg.commit)),
+                payer_public_key.eq(msg.payer_public_key),
+                payer_message_unverified.eq(msg.payer_message_unverified),
+                slate_version.eq(Some(msg.slate_version)),
+                payer_user_agent.eq(msg.payer_user_agent),
+                processing_until.eq(None::<NaiveDateTime>),
+            ))
+            .get_result(conn)
+            .optional()
+            // Another payment got the slate since it was claimed
+            .map_err(|e| match e {
+                diesel::result::Error::DatabaseError(
+                    diesel::result::DatabaseErrorKind::UniqueViolation,
+                    ref info,
+                ) if info.constraint_name() == Some(SLATE_ID_INDEX) => {
+                    duplicate_slate(&slate_id, transition.transaction_id(), Uuid::nil())
+                }
+                e => e.into(),
+            })?
+            .ok_or_else(|| moved_on(&transition))?;
+            enqueue_payout_event(conn, &transaction, PayoutEventType::Finalized)?;
+            Ok(transaction)
+        })
+    }
+}
+
+impl Handler<ClaimPayment> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: ClaimPayment, _: &mut Self::Context) -> Self::Result {
+        let conn: &PgConnection = &self.0.get().unwrap();
+        claim_payment(conn, &msg, self.1.now())
+    }
+}
+
+fn claim_payment(conn: &PgConnection, msg: &ClaimPayment, now: NaiveDateTime) -> Result<(), Error> {
+    use crate::schema::transactions::dsl::*;
+    let other_payment: Option<Uuid> = transactions
+        .filter(
+            wallet_tx_slate_id
+                .eq(&msg.slate_id)
+                .or(claimed_slate_id.eq(&msg.slate_id)),
+        )
+        .filter(id.ne(msg.transaction_id))
+        .select(id)
+        .first(conn)
+        .optional()?;
+    if let Some(other_payment) = other_payment {
+        return Err(duplicate_slate(
+            &msg.slate_id,
+            msg.transaction_id,
+            other_payment,
+        ));
+    }
+    let claimed = diesel::update(
+        transactions
+            .filter(id.eq(msg.transaction_id))
+            .filter(status.eq(TransactionStatus::New))
+            .filter(processing_until.is_null().or(processing_until.lt(now))),
+    )
+    .set((
+        processing_until.eq(now + Duration::seconds(PAYMENT_PROCESSING_SECONDS)),
+        claimed_slate_id.eq(&msg.slate_id),
+    ))
+    .execute(conn)
+    // Another payment claimed the slate meanwhile
+    .map_err(|e| match e {
+        diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            ref info,
+        ) if info.constraint_name() == Some(CLAIMED_SLATE_ID_INDEX) => {
+            duplicate_slate(&msg.slate_id, msg.transaction_id, Uuid::nil())
+        }
+        e => e.into(),
+    })?;
+    if claimed == 0 {
+        return Err(Error::PaymentProcessing);
+    }
+    Ok(())
+}
+
+/// Logs a slate posted to a second payment, `other_payment` is nil when
+/// it isn't known
+fn duplicate_slate(slate_id: &str, payment: Uuid, other_payment: Uuid) -> Error {
+    warn!(
+        "Slate {} sent to payment {} was already sent to payment {}",
+        slate_id, payment, other_payment
+    );
+    metrics::inc("duplicate_slates_total", &[]);
+    Error::DuplicateSlate(slate_id.to_owned())
+}
+
+impl Handler<ReleasePayment> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: ReleasePayment, _: &mut Self::Context) -> Self::Result {
+        let conn: &PgConnection = &self.0.get().unwrap();
+        release_payment(conn, &msg)
+    }
+}
+
+fn release_payment(conn: &PgConnection, msg: &ReleasePayment) -> Result<(), Error> {
+    use crate::schema::transactions::dsl::*;
+    diesel::update(transactions.filter(id.eq(msg.transaction_id)))
+        .set((
+            processing_until.eq(None::<NaiveDateTime>),
+            claimed_slate_id.eq(None::<String>),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+impl Handler<SetPayerLocation> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: SetPayerLocation, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::update(
+            transactions
+                .filter(id.eq(msg.transaction_id))
+                .filter(payer_country.is_null())
+                .filter(payer_asn.is_null()),
+        )
+        .set((payer_country.eq(msg.country), payer_asn.eq(msg.asn)))
+        .execute(conn)?;
+        Ok(())
+    }
+}
+
+impl Handler<MarkAsInChain> for DbExecutor {
+    type Result = Result<Transaction, Error>;
+
+    fn handle(&mut self, msg: MarkAsInChain, _: &mut Self::Context) -> Self::Result {
+        let conn: &PgConnection = &self.0.get().unwrap();
+        mark_as_in_chain(conn, &msg.transition, msg.height)
+    }
+}
+
+fn mark_as_in_chain(
+    conn: &PgConnection,
+    transition: &Transition<Pending, InChain>,
+    tx_height: i64,
+) -> Result<Transaction, Error> {
+    use crate::schema::transactions::dsl::*;
+    diesel::update(
+        transactions
+            .filter(id.eq(transition.transaction_id()))
+            .filter(status.eq(transition.from())),
+    )
+    .set((height.eq(tx_height), status.eq(transition.to())))
+    .get_result(conn)
+    .optional()?
+    .ok_or_else(|| moved_on(transition))
+}
+
+impl Handler<MarkAsRefund> for DbExecutor {
+    type Result = Result<Transaction, Error>;
+
+    fn handle(&mut self, msg: MarkAsRefund, _: &mut Self::Context) -> Self::Result {
+        let conn: &PgConnection = &self.0.get().unwrap();
+        mark_as_refund(
+            conn,
+            &msg.transition,
+            RefundReason::PaidAfterRejection,
+            self.1.now(),
+        )
+    }
+}
+
+/// The merchant was told the payment is rejected, now it's reported again
+/// as refund
+fn mark_as_refund(
+    conn: &PgConnection,
+    transition: &Transition<Rejected, Refund>,
+    reason: RefundReason,
+    now: NaiveDateTime,
+) -> Result<Transaction, Error> {
+    use crate::schema::transactions::dsl::*;
+    diesel::update(
+        transactions
+            .filter(id.eq(transition.transaction_id()))
+            .filter(status.eq(transition.from())),
+    )
+    .set((
+        status.eq(transition.to()),
+        refund_reason.eq(reason.to_string()),
+        reported.eq(false),
+        report_attempts.eq(0),
+        next_report_attempt.eq(None::<NaiveDateTime>),
+        updated_at.eq(now),
+    ))
+    .get_result(conn)
+    .optional()?
+    .ok_or_else(|| moved_on(transition))
+}
+
+impl Handler<MarkAsReported> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: MarkAsReported, _: &mut Self::Context) -> Self::Result {
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        mark_as_reported(conn, &msg, now).map(|_| ())
+    }
+}
+
+/// Marks the payment as reported and credits the merchant if it's
+/// confirmed, rejected and refunded payments aren't the merchant's. Only
+/// the run which marks it credits, so a report job which runs again
+/// doesn't credit the payment twice. Returns whether this run marked it.
+fn mark_as_reported(
+    conn: &PgConnection,
+    msg: &MarkAsReported,
+    now: NaiveDateTime,
+) -> Result<bool, Error> {
+    use crate::schema::transactions::dsl::*;
+    conn.transaction(|| {
+        let marked: Option<Transaction> = diesel::update(
+            transactions
+                .filter(id.eq(msg.transaction_id))
+                .filter(reported.eq(false)),
+        )
+        .set(reported.eq(true))
+        .get_result(conn)
+        .optional()?;
+        let tx = match marked {
+            Some(tx) => tx,
+            None => return Ok(false),
+        };
+        if tx.transaction_type == TransactionType::Payment
+            && tx.status == TransactionStatus::Confirmed
+        {
+            credit_payment(conn, &tx, now)?;
+        }
+        Ok(true)
+    })
+}
+
+impl Handler<RequoteTransaction> for DbExecutor {
+    type Result = Result<Transaction, Error>;
+
+    fn handle(&mut self, msg: RequoteTransaction, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        conn.transaction(|| {
+            let transaction: Transaction = transactions
+                .filter(id.eq(msg.transaction_id))
+                .filter(transaction_type.eq(TransactionType::Payment))
+                .for_update()
+                .get_result(conn)?;
+            if transaction.status != TransactionStatus::New {
+                return Err(Error::WrongTransactionStatus(s!(transaction.status)));
+            }
+            // Within the window after the rate lock expired, by the clock the
+            // new lock is computed with
+            if !transaction.can_be_requoted_at(now) {
+                return Err(Error::CannotRequote);
+            }
+            let (grins, rate, fetched_at) = convert_to_grins(conn, transaction.amount, now)?;
+            let tag = match transaction.amount_tag {
+                Some(_) => Some(free_amount_tag(
+                    conn,
+                    &transaction.merchant_id,
+                    grins.amount,
+                    Some(transaction.id),
+                )?),
+                None => None,
+            };
+            let mut requoted = transaction.clone();
+            requoted.rate_locked_until = Some(now + Duration::seconds(RATE_LOCK_SECONDS));
+            requoted.requotes += 1;
+            diesel::update(transactions.filter(id.eq(transaction.id)))
+                .set((
+                    grin_amount.eq(grins.amount + tag.unwrap_or(0)),
+                    amount_tag.eq(tag),
+                    exchange_rate.eq(rate),
+                    rate_updated_at.eq(fetched_at),
+                    rate_locked_until.eq(requoted.rate_locked_until),
+                    requotes.eq(requoted.requotes),
+                    expires_at.eq(requoted.payment_deadline()),
+                ))
+                .get_result(conn)
+                .map_err(|e| e.into())
+        })
+    }
+}
+
+impl Handler<SyncBlocks> for DbExecutor {
+    type Result = Result<SyncedBlocks, Error>;
+
+    fn handle(&mut self, msg: SyncBlocks, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        let commits = msg.commits;
+        let headers = msg.headers;
+        let new_height = msg.new_height;
+        conn.transaction(move || {
+            let mut fork_height = None;
+            {
+                use crate::schema::blocks::dsl::*;
+                let mut last_hash = match headers.first() {
+                    Some(first) => blocks
+                        .filter(height.eq(first.height - 1))
+                        .select(hash)
+                        .first::<String>(conn)
+                        .optional()?,
+                    None => None,
+                };
+                for header in &headers {
+                    if fork_height.is_none()
+                        && last_hash
+                            .as_ref()
+                            .map_or(false, |last| *last != header.previous)
+                    {
+                        fork_height = Some(header.height);
+                    }
+                    last_hash = Some(header.hash.clone());
+                    diesel::insert_into(blocks)
+                        .values(header)
+                        .on_conflict(height)
+                        .do_update()
+                        .set((
+                            hash.eq(&header.hash),
+                            previous.eq(&header.previous),
+                            timestamp.eq(header.timestamp),
+                            synced_at.eq(header.synced_at),
+                        ))
+                        .execute(conn)?;
+                }
+            }
+            let txs = transactions
+                .filter(commit.eq_any(commits.keys()))
+                .load::<Transaction>(conn)?;
+
+            if txs.len() > 0 {
+                debug!("Found {} transactions which got into chain", txs.len());
+            }
+            let txs = txs
+                .into_iter()
+                .map(|tx| mark_in_chain(conn, tx, &commits, now))
+                .collect::<Result<Vec<_>, _>>()?;
+            {
+                debug!("Set new last_height = {}", new_height);
+                use crate::schema::current_height::dsl::*;
+                diesel::update(current_height)
+                    .set(height.eq(new_height))
+                    .execute(conn)
+                    .map(|_| ())
+                    .map_err::<Error, _>(|e| e.into())?;
+            }
+            Ok(SyncedBlocks {
+                fork_height,
+                transactions: txs,
+            })
+        })
+    }
+}
+
+impl Handler<ReplayCommits> for DbExecutor {
+    type Result = Result<Vec<Transaction>, Error>;
+
+    fn handle(&mut self, msg: ReplayCommits, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        let commits = msg.commits;
+        conn.transaction(move || {
+            let txs = transactions
+                .filter(commit.eq_any(commits.keys()))
+                .filter(status.eq_any(vec![
+                    TransactionStatus::Pending,
+                    TransactionStatus::Rejected,
+                ]))
+                .load::<Transaction>(conn)?;
+            txs.into_iter()
+                .map(|tx| {
+                    info!("Replay found transaction {} in chain", tx.id);
+                    mark_in_chain(conn, tx, &commits, now)
+                })
+                .collect()
+        })
+    }
+}
+
+/// Pending payments get in chain, rejected ones have to be refunded.
+/// Payouts aren't payments, they have no transitions and get in chain
+/// without one.
+fn mark_in_chain(
+    conn: &PgConnection,
+    tx: Transaction,
+    commits: &HashMap<String, i64>,
+    now: NaiveDateTime,
+) -> Result<Transaction, Error> {
+    use crate::schema::transactions::dsl::*;
+    let tx_height = tx
+        .commit
+        .as_ref()
+        .and_then(|tx_commit| commits.get(tx_commit))
+        .cloned()
+        .ok_or_else(|| Error::General(format!("Transaction {} has no commit in chain", tx.id)))?;
+    match (tx.transaction_type, tx.status) {
+        (TransactionType::Payment, TransactionStatus::Pending) => {
+            let transition = Payment::<Pending>::load(tx)?.seen_in_chain();
+            mark_as_in_chain(conn, &transition, tx_height)
+        }
+        (TransactionType::Payment, TransactionStatus::Rejected) => {
+            let transition = Payment::<Rejected>::load(tx)?.refund();
+            let refund = mark_as_refund(conn, &transition, RefundReason::PaidAfterRejection, now)?;
+            diesel::update(transactions.filter(id.eq(refund.id)))
+                .set(height.eq(tx_height))
+                .get_result(conn)
+                .map_err(|e| e.into())
+        }
+        (TransactionType::Payout, TransactionStatus::Pending) => {
+            diesel::update(transactions.filter(id.eq(tx.id)))
+                .set((status.eq(TransactionStatus::InChain), height.eq(tx_height)))
+                .get_result(conn)
+                .map_err(|e| e.into())
+        }
+        (TransactionType::Payout, TransactionStatus::Rejected) => {
+            diesel::update(transactions.filter(id.eq(tx.id)))
+                .set((
+                    status.eq(TransactionStatus::Refund),
+                    height.eq(tx_height),
+                    refund_reason.eq(RefundReason::PaidAfterRejection.to_string()),
+                    reported.eq(false),
+                    report_attempts.eq(0),
+                    next_report_attempt.eq(None::<NaiveDateTime>),
+                ))
+                .get_result(conn)
+                .map_err(|e| e.into())
+        }
+        _ => Err(Error::General(format!(
+            "Transaction {} in chain although it has status {}",
+            tx.id, tx.status
+        ))),
+    }
+}
+
+impl Handler<GetLatestBlocks> for DbExecutor {
+    type Result = Result<Vec<BlockHeader>, Error>;
+
+    fn handle(&mut self, msg: GetLatestBlocks, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::blocks::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        blocks
+            .order(height.desc())
+            .limit(msg.limit)
+            .load(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetBlock> for DbExecutor {
+    type Result = Result<Option<BlockHeader>, Error>;
+
+    fn handle(&mut self, msg: GetBlock, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::blocks::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        blocks
+            .find(msg.height)
+            .first(conn)
+            .optional()
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<AutoConfirmTransactions> for DbExecutor {
+    type Result = Result<Vec<Transaction>, Error>;
+
+    fn handle(&mut self, _: AutoConfirmTransactions, _: &mut Self::Context) -> Self::Result {
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        let last_height = {
+            use crate::schema::current_height::dsl::*;
+            let last_height: i64 = current_height.select(height).first(conn)?;
+            last_height
+        };
+        conn.transaction(|| {
+            use crate::schema::transactions::dsl::*;
+            let confirmable: Vec<Transaction> = transactions
+                .filter(status.eq(TransactionStatus::InChain))
+                .load::<Transaction>(conn)?
+                .into_iter()
+                .filter(|tx| match tx.height {
+                    Some(tx_height) => tx.confirmations < last_height - tx_height,
+                    None => false,
+                })
+                .collect();
+            let mut confirmed = vec![];
+            for tx in confirmable {
+                match tx.transaction_type {
+                    TransactionType::Payment => {
+                        let transition = Payment::<InChain>::load(tx)?.confirm();
+                        confirmed.push(confirm_transaction(conn, &transition, now)?);
+                    }
+                    // Payouts aren't payments, they have no transitions
+                    TransactionType::Payout => {
+                        let payout: Transaction = diesel::update(
+                            transactions
+                                .filter(id.eq(tx.id))
+                                .filter(status.eq(TransactionStatus::InChain)),
+                        )
+                        .set((status.eq(TransactionStatus::Confirmed), updated_at.eq(now)))
+                        .get_result(conn)?;
+                        enqueue_payout_event(conn, &payout, PayoutEventType::Confirmed)?;
+                    }
+                }
+            }
+            Ok(confirmed)
+        })
+    }
+}
+
+impl<F: State> Handler<MarkAsConfirmedByWallet<F>> for DbExecutor {
+    type Result = Result<Transaction, Error>;
+
+    fn handle(&mut self, msg: MarkAsConfirmedByWallet<F>, _: &mut Self::Context) -> Self::Result {
+        let conn: &PgConnection = &self.0.get().unwrap();
+        mark_as_confirmed_by_wallet(conn, &msg, self.1.now())
+    }
+}
+
+fn mark_as_confirmed_by_wallet<F: State>(
+    conn: &PgConnection,
+    msg: &MarkAsConfirmedByWallet<F>,
+    now: NaiveDateTime,
+) -> Result<Transaction, Error> {
+    use crate::schema::transactions::dsl::*;
+    conn.transaction(|| {
+        let tx: Transaction = transactions
+            .filter(id.eq(msg.transition.transaction_id()))
+            .for_update()
+            .get_result(conn)?;
+        if tx.status != msg.transition.from() {
+            return Err(moved_on(&msg.transition));
+        }
+        // Keep the height the node reported if we have it
+        let tx_height = tx.height.unwrap_or(msg.height);
+        // Same rule as in AutoConfirmTransactions
+        let new_status = if tx.confirmations < msg.wallet_height - tx_height {
+            TransactionStatus::Confirmed
+        } else {
+            TransactionStatus::InChain
+        };
+        info!(
+            "Wallet saw transaction {} at height {}, new status {}",
+            tx.id, tx_height, new_status
+        );
+        let tx = diesel::update(transactions.filter(id.eq(tx.id)))
+            .set((
+                status.eq(new_status),
+                height.eq(tx_height),
+                confirmed_by_wallet.eq(true),
+                updated_at.eq(now),
+            ))
+            .get_result(conn)?;
+        if new_status == TransactionStatus::Confirmed {
+            enqueue_payout_event(conn, &tx, PayoutEventType::Confirmed)?;
+        }
+        Ok(tx)
+    })
+}
+
+impl Handler<MarkAsSeenInPool> for DbExecutor {
+    type Result = Result<usize, Error>;
+
+    fn handle(&mut self, msg: MarkAsSeenInPool, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        let updated = diesel::update(
+            transactions
+                .filter(status.eq(TransactionStatus::Pending))
+                .filter(seen_in_pool_at.is_null())
+                .filter(commit.eq_any(msg.commits)),
+        )
+        .set(seen_in_pool_at.eq(now))
+        .execute(conn)?;
+        if updated > 0 {
+            debug!("Found {} pending transactions in the pool", updated);
+        }
+        Ok(updated)
+    }
+}
+
+impl Handler<CreatePayoutBatch> for DbExecutor {
+    type Result = Result<Option<(PayoutBatch, Vec<Transaction>)>, Error>;
+
+    fn handle(&mut self, msg: CreatePayoutBatch, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::payout_batches;
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        conn.transaction(|| {
+            let payouts: Vec<Transaction> = transactions
+                .filter(transaction_type.eq(TransactionType::Payout))
+                .filter(status.eq(TransactionStatus::New))
+                .filter(
+                    // Payouts which failed in a finished batch are retried
+                    payout_batch_id.is_null().or(payout_batch_id.eq_any(
+                        payout_batches::table
+                            .select(payout_batches::id)
+                            .filter(payout_batches::processed_at.is_not_null())
+                            .nullable(),
+                    )),
+                )
+                .order(created_at.asc())
+                .limit(msg.max_size)
+                .for_update()
+                .skip_locked()
+                .load(conn)?;
+            if payouts.is_empty() {
+                return Ok(None);
+            }
+            let batch: PayoutBatch = diesel::insert_into(payout_batches::table)
+                .values(&PayoutBatch {
+                    id: Uuid::new_v4(),
+                    size: payouts.len() as i32,
+                    created_at: now,
+                    processed_at: None,
+                })
+                .get_result(conn)?;
+            let ids: Vec<Uuid> = payouts.iter().map(|payout| payout.id).collect();
+            let payouts = diesel::update(transactions.filter(id.eq_any(ids)))
+                .set(payout_batch_id.eq(batch.id))
+                .get_results(conn)?;
+            info!("Created payout batch {} of size {}", batch.id, batch.size);
+            Ok(Some((batch, payouts)))
+        })
+    }
+}
+
+impl Handler<MarkPayoutAsInitialized> for DbExecutor {
+    type Result = Result<Transaction, Error>;
+
+    fn handle(&mut self, msg: MarkPayoutAsInitialized, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        conn.transaction(|| {
+            let payout = diesel::update(
+                transactions
+                    .filter(id.eq(msg.transaction_id))
+                    .filter(status.eq(TransactionStatus::New)),
+            )
+            .set((
+                wallet_tx_slate_id.eq(msg.slate_id.to_string()),
+                real_transfer_fee.eq(msg.fee),
+                status.eq(TransactionStatus::Initialized),
+                updated_at.eq(now),
+            ))
+            .get_result(conn)?;
+            enqueue_payout_event(conn, &payout, PayoutEventType::Initialized)?;
+            Ok(payout)
+        })
+    }
+}
+
+impl Handler<CompletePayoutBatch> for DbExecutor {
+    type Result = Result<PayoutBatch, Error>;
+
+    fn handle(&mut self, msg: CompletePayoutBatch, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::payout_batches::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        diesel::update(payout_batches.filter(id.eq(msg.batch_id)))
+            .set(processed_at.eq(now))
+            .get_result(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetTransactionsWithSlate> for DbExecutor {
+    type Result = Result<Vec<Transaction>, Error>;
+
+    fn handle(&mut self, msg: GetTransactionsWithSlate, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        transactions
+            .filter(wallet_tx_slate_id.is_not_null())
+            .filter(created_at.lt(msg.created_before))
+            .order((created_at.asc(), id.asc()))
+            .offset(msg.offset)
+            .limit(msg.limit)
+            .load(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<ReplaceReconciliationOrphans> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: ReplaceReconciliationOrphans, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::reconciliation_orphans::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        conn.transaction(|| {
+            diesel::delete(reconciliation_orphans).execute(conn)?;
+            diesel::insert_into(reconciliation_orphans)
+                .values(&msg.0)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+}
+
+impl Handler<GetReconciliationOrphans> for DbExecutor {
+    type Result = Result<Vec<ReconciliationOrphan>, Error>;
+
+    fn handle(&mut self, _: GetReconciliationOrphans, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::reconciliation_orphans::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        reconciliation_orphans
+            .order((source.asc(), found_at.desc()))
+            .load(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<AcquireJobLease> for DbExecutor {
+    type Result = Result<bool, Error>;
+
+    fn handle(&mut self, msg: AcquireJobLease, _: &mut Self::Context) -> Self::Result {
+        use diesel::sql_query;
+        use diesel::sql_types::{BigInt, Text};
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let acquired = sql_query(
+            "INSERT INTO cron_jobs (name, locked_by, locked_until, last_started_at)
+            VALUES ($1, $2, now() + $3 * interval '1 second', now())
+            ON CONFLICT (name) DO UPDATE SET
+                locked_by = EXCLUDED.locked_by,
+                locked_until = EXCLUDED.locked_until,
+                last_started_at = EXCLUDED.last_started_at
+            WHERE cron_jobs.locked_until IS NULL
+                OR cron_jobs.locked_until < now()
+                OR cron_jobs.locked_by = EXCLUDED.locked_by",
+        )
+        .bind::<Text, _>(msg.name)
+        .bind::<Text, _>(msg.instance)
+        .bind::<BigInt, _>(msg.lease_seconds)
+        .execute(conn)?;
+        Ok(acquired > 0)
+    }
+}
+
+impl Handler<ReleaseJobLease> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: ReleaseJobLease, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::cron_jobs::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::update(
+            cron_jobs
+                .filter(name.eq(msg.name))
+                .filter(locked_by.eq(msg.instance)),
+        )
+        .set((
+            locked_by.eq(None::<String>),
+            locked_until.eq(None::<NaiveDateTime>),
+            last_finished_at.eq(diesel::dsl::now.nullable()),
+        ))
+        .execute(conn)?;
+        Ok(())
+    }
+}
+
+impl Handler<EnqueueJobs> for DbExecutor {
+    type Result = Result<usize, Error>;
+
+    fn handle(&mut self, msg: EnqueueJobs, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::jobs::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::insert_into(jobs)
+            .values(&msg.0)
+            .on_conflict_do_nothing()
+            .execute(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<ClaimJobs> for DbExecutor {
+    type Result = Result<Vec<Job>, Error>;
+
+    fn handle(&mut self, msg: ClaimJobs, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::jobs::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        conn.transaction(|| {
+            let due: Vec<Uuid> = jobs
+                .select(id)
+                .filter(failed_at.is_null())
+                .filter(run_at.le(now))
+                .filter(locked_until.is_null().or(locked_until.lt(now)))
+                .order(run_at.asc())
+                .limit(msg.limit)
+                .for_update()
+                .skip_locked()
+                .load(conn)?;
+            if due.is_empty() {
+                return Ok(vec![]);
+            }
+            diesel::update(jobs.filter(id.eq_any(due)))
+                .set((
+                    attempts.eq(attempts + 1),
+                    locked_by.eq(msg.instance),
+                    locked_until.eq(now + Duration::seconds(msg.lease_seconds)),
+                ))
+                .get_results(conn)
+                .map_err(|e| e.into())
+        })
+    }
+}
+
+impl Handler<CompleteJob> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: CompleteJob, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::jobs::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::delete(jobs.filter(id.eq(msg.id))).execute(conn)?;
+        Ok(())
+    }
+}
+
+impl Handler<FailJob> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: FailJob, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::jobs::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        diesel::update(jobs.filter(id.eq(msg.id)))
+            .set((
+                last_error.eq(msg.error),
+                locked_by.eq(None::<String>),
+                locked_until.eq(None::<NaiveDateTime>),
+                run_at.eq(msg.retry_at.unwrap_or(now)),
+                failed_at.eq(msg.retry_at.map_or(Some(now), |_| None)),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }
+}
+
+impl Handler<RecordPayoutEvent> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: RecordPayoutEvent, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let payout: Transaction = transactions.find(msg.transaction_id).get_result(conn)?;
+        enqueue_payout_event(conn, &payout, msg.event)
+    }
+}
+
+impl Handler<GetUndeliveredPayoutEvents> for DbExecutor {
+    type Result = Result<Vec<(PayoutEvent, Transaction, Merchant)>, Error>;
+
+    fn handle(&mut self, msg: GetUndeliveredPayoutEvents, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::merchants;
+        use crate::schema::payout_events::dsl::*;
+        use crate::schema::transactions;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        payout_events
+            .inner_join(transactions::table)
+            .inner_join(merchants::table)
+            .filter(merchants::payout_callback_url.is_not_null())
+            .filter(delivered_at.is_null())
+            .filter(
+                next_attempt
+                    .le(now)
+                    .or(next_attempt.is_null().and(attempts.eq(0))),
+            )
+            .order(created_at.asc())
+            .limit(msg.limit)
+            .load(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<MarkPayoutEventDelivered> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: MarkPayoutEventDelivered, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::payout_events::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        diesel::update(payout_events.filter(id.eq(msg.id)))
+            .set(delivered_at.eq(now))
+            .execute(conn)?;
+        Ok(())
+    }
+}
+
+impl Handler<PayoutEventAttempt> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: PayoutEventAttempt, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::payout_events::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::update(payout_events.filter(id.eq(msg.id)))
+            .set((attempts.eq(attempts + 1), next_attempt.eq(msg.next_attempt)))
+            .execute(conn)?;
+        Ok(())
+    }
+}
+
+/// Puts a payout event into the outbox, nothing is queued for payments
+/// and for merchants without `payout_callback_url`
+fn enqueue_payout_event(
+    conn: &PgConnection,
+    transaction: &Transaction,
+    event: PayoutEventType,
+) -> Result<(), Error> {
+    use crate::schema::merchants;
+    use crate::schema::payout_events;
+    if transaction.transaction_type != TransactionType::Payout {
+        return Ok(());
+    }
+    let payout_callback_url: Option<String> = merchants::table
+        .find(&transaction.merchant_id)
+        .select(merchants::payout_callback_url)
+        .get_result(conn)?;
+    if payout_callback_url.is_none() {
+        return Ok(());
+    }
+    debug!("Queue {} event of payout {}", event, transaction.id);
+    diesel::insert_into(payout_events::table)
+        .values(&PayoutEvent::new(transaction, event))
+        .execute(conn)?;
+    Ok(())
+}
+
+impl Handler<GetUnsentReceipts> for DbExecutor {
+    type Result = Result<Vec<(Transaction, Merchant)>, Error>;
+
+    fn handle(&mut self, msg: GetUnsentReceipts, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::merchants;
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        transactions
+            .inner_join(merchants::table)
+            .filter(transaction_type.eq(TransactionType::Payment))
+            .filter(status.eq(TransactionStatus::Confirmed))
+            .filter(email.is_not_null())
+            .filter(receipt_sent_at.is_null())
+            .order(updated_at.asc())
+            .limit(msg.limit)
+            .load(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<MarkReceiptSent> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: MarkReceiptSent, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        diesel::update(transactions.filter(id.eq(msg.transaction_id)))
+            .set(receipt_sent_at.eq(now))
+            .execute(conn)?;
+        Ok(())
+    }
+}
+
+impl Handler<GetIdlePayments> for DbExecutor {
+    type Result = Result<Vec<(Transaction, Merchant)>, Error>;
+
+    fn handle(&mut self, msg: GetIdlePayments, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::merchants;
+        use crate::schema::transactions::dsl::*;
+        use diesel::dsl::sql;
+        use diesel::sql_types::{Bool, Timestamp};
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        transactions
+            .inner_join(merchants::table)
+            .filter(transaction_type.eq(TransactionType::Payment))
+            .filter(status.eq(TransactionStatus::New))
+            .filter(email.is_not_null())
+            .filter(reminder_sent_at.is_null())
+            .filter(expires_at.gt(now))
+            .filter(merchants::payment_reminder_minutes.is_not_null())
+            .filter(
+                sql::<Bool>(
+                    "transactions.created_at + merchants.payment_reminder_minutes * INTERVAL '1 minute' <= ",
+                )
+                .bind::<Timestamp, _>(now),
+            )
+            .order(created_at.asc())
+            .limit(msg.limit)
+            .load(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<MarkReminderSent> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: MarkReminderSent, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        diesel::update(transactions.filter(id.eq(msg.transaction_id)))
+            .set(reminder_sent_at.eq(now))
+            .execute(conn)?;
+        Ok(())
+    }
+}
+
+impl Handler<SetReceiptEmail> for DbExecutor {
+    type Result = Result<Transaction, Error>;
+
+    fn handle(&mut self, msg: SetReceiptEmail, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::update(
+            transactions
+                .filter(id.eq(msg.transaction_id))
+                .filter(transaction_type.eq(TransactionType::Payment))
+                .filter(email.is_null()),
+        )
+        .set((email.eq(msg.email), receipt_opt_in.eq(true)))
+        .get_result(conn)
+        .optional()?
+        .ok_or(Error::InvalidEntity(s!("payment already has an email")))
+    }
+}
+
+impl Handler<UpdateEmailBranding> for DbExecutor {
+    type Result = Result<Merchant, Error>;
+
+    fn handle(&mut self, msg: UpdateEmailBranding, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::merchants::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::update(merchants.filter(id.eq(msg.merchant_id)))
+            .set((
+                email_logo_url.eq(msg.logo_url),
+                email_footer.eq(msg.footer),
+                email_reply_to.eq(msg.reply_to),
+                payment_reminder_minutes.eq(msg.payment_reminder_minutes),
+            ))
+            .get_result(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<UpdateSlateMessageCheck> for DbExecutor {
+    type Result = Result<Merchant, Error>;
+
+    fn handle(&mut self, msg: UpdateSlateMessageCheck, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::merchants::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::update(merchants.filter(id.eq(msg.merchant_id)))
+            .set(slate_message_check.eq(msg.check.to_string()))
+            .get_result(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<UpdateTimezone> for DbExecutor {
+    type Result = Result<Merchant, Error>;
+
+    fn handle(&mut self, msg: UpdateTimezone, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::merchants::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        msg.timezone
+            .parse::<Tz>()
+            .map_err(|_| Error::InvalidEntity(format!("unknown time zone {}", msg.timezone)))?;
+        diesel::update(merchants.filter(id.eq(msg.merchant_id)))
+            .set(timezone.eq(msg.timezone))
+            .get_result(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<UpdateInvoicePrefix> for DbExecutor {
+    type Result = Result<Merchant, Error>;
+
+    fn handle(&mut self, msg: UpdateInvoicePrefix, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::merchants::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        validate_invoice_prefix(&msg.invoice_prefix)?;
+        diesel::update(merchants.filter(id.eq(msg.merchant_id)))
+            .set(invoice_prefix.eq(msg.invoice_prefix))
+            .get_result(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<UpdateCallbackSettings> for DbExecutor {
+    type Result = Result<Merchant, Error>;
+
+    fn handle(&mut self, msg: UpdateCallbackSettings, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::merchants::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let settings = msg.settings;
+        settings.validate()?;
+        let headers = if settings.headers.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_value(&settings.headers)?)
+        };
+        diesel::update(merchants.filter(id.eq(msg.merchant_id)))
+            .set((
+                callback_timeout_seconds.eq(settings.timeout_seconds),
+                callback_headers.eq(headers),
+                callback_verify_tls.eq(settings.verify_tls),
+                callback_template.eq(settings.template),
+                verbose_callbacks.eq(settings.verbose),
+                callback_max_attempts.eq(settings.max_attempts),
+                callback_backoff_seconds.eq(settings.backoff_seconds),
+                callback_retry_window_seconds.eq(settings.retry_window_seconds),
+            ))
+            .get_result(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<UpdateIntegrations> for DbExecutor {
+    type Result = Result<Merchant, Error>;
+
+    fn handle(&mut self, msg: UpdateIntegrations, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::merchants::dsl::*;
+        use crate::schema::payout_events;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        msg.integrations.validate()?;
+        conn.transaction(|| {
+            // Events from before the chats were linked are not pushed
+            diesel::update(
+                payout_events::table
+                    .filter(payout_events::merchant_id.eq(&msg.merchant_id))
+                    .filter(payout_events::notified_at.is_null()),
+            )
+            .set(payout_events::notified_at.eq(now))
+            .execute(conn)?;
+            diesel::update(merchants.filter(id.eq(&msg.merchant_id)))
+                .set((
+                    telegram_chat_id.eq(msg.integrations.telegram_chat_id),
+                    slack_webhook_url.eq(msg.integrations.slack_webhook_url),
+                ))
+                .get_result(conn)
+                .map_err(|e| e.into())
+        })
+    }
+}
+
+impl Handler<GetUnnotifiedPayoutEvents> for DbExecutor {
+    type Result = Result<Vec<(PayoutEvent, Transaction, Merchant)>, Error>;
+
+    fn handle(&mut self, msg: GetUnnotifiedPayoutEvents, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::merchants;
+        use crate::schema::payout_events::dsl::*;
+        use crate::schema::transactions;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        payout_events
+            .inner_join(transactions::table)
+            .inner_join(merchants::table)
+            .filter(notified_at.is_null())
+            .filter(
+                merchants::telegram_chat_id
+                    .is_not_null()
+                    .or(merchants::slack_webhook_url.is_not_null()),
+            )
+            .order(created_at.asc())
+            .limit(msg.limit)
+            .load(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<MarkPayoutEventNotified> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: MarkPayoutEventNotified, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::payout_events::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        diesel::update(payout_events.filter(id.eq(msg.id)))
+            .set(notified_at.eq(now))
+            .execute(conn)?;
+        Ok(())
+    }
+}
+
+impl Handler<GetSettlementDays> for DbExecutor {
+    type Result = Result<Vec<SettlementDay>, Error>;
+
+    fn handle(&mut self, msg: GetSettlementDays, _: &mut Self::Context) -> Self::Result {
+        use diesel::sql_query;
+        use diesel::sql_types::{BigInt, Text};
+        let conn: &PgConnection = &self.0.get().unwrap();
+        sql_query(
+            "SELECT updated_at::date AS date,
+                COUNT(*) AS transactions,
+                COALESCE(SUM(grin_amount) FILTER (WHERE transaction_type = 'payment'), 0)::BIGINT
+                    AS payments,
+                COALESCE(SUM(grin_amount) FILTER (WHERE transaction_type = 'payout'), 0)::BIGINT
+                    AS payouts,
+                COALESCE(SUM(COALESCE(knockturn_fee, 0)
+                    + COALESCE(real_transfer_fee, transfer_fee, 0)), 0)::BIGINT AS fees
+            FROM transactions
+            WHERE merchant_id = $1 AND status = 'confirmed'
+            GROUP BY 1
+            ORDER BY 1 DESC
+            OFFSET $2 LIMIT $3",
+        )
+        .bind::<Text, _>(msg.merchant_id)
+        .bind::<BigInt, _>(msg.offset)
+        .bind::<BigInt, _>(msg.limit)
+        .load(conn)
+        .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetSettledTransactions> for DbExecutor {
+    type Result = Result<Vec<Transaction>, Error>;
+
+    fn handle(&mut self, msg: GetSettledTransactions, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let day_start = msg.date.and_hms(0, 0, 0);
+        transactions
+            .filter(merchant_id.eq(msg.merchant_id))
+            .filter(status.eq(TransactionStatus::Confirmed))
+            .filter(updated_at.ge(day_start))
+            .filter(updated_at.lt(day_start + Duration::days(1)))
+            .order(updated_at.asc())
+            .load(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<CreateNote> for DbExecutor {
+    type Result = Result<TransactionNote, Error>;
+
+    fn handle(&mut self, msg: CreateNote, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transaction_notes::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::insert_into(transaction_notes)
+            .values(&msg.0)
+            .get_result(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<ManualTransition> for DbExecutor {
+    type Result = Result<Transaction, Error>;
+
+    fn handle(&mut self, msg: ManualTransition, _: &mut Self::Context) -> Self::Result {
+        let conn: &PgConnection = &self.0.get().unwrap();
+        manual_transition(conn, &msg, self.1.now())
+    }
+}
+
+fn manual_transition(
+    conn: &PgConnection,
+    msg: &ManualTransition,
+    now: NaiveDateTime,
+) -> Result<Transaction, Error> {
+    use crate::schema::transaction_notes;
+    use crate::schema::transactions::dsl::*;
+    if msg.justification.trim().is_empty() {
+        return Err(Error::InvalidEntity(s!("justification is required")));
+    }
+    conn.transaction(|| {
+        let transaction: Transaction = transactions
+            .filter(id.eq(msg.transaction_id))
+            .filter(transaction_type.eq(TransactionType::Payment))
+            .for_update()
+            .get_result(conn)?;
+        let mut note = TransactionNote::new(
+            transaction.id,
+            &msg.admin_id,
+            &format!(
+                "Status changed manually from {} to {}: {}",
+                transaction.status,
+                msg.status,
+                msg.justification.trim()
+            ),
+        )?;
+        note.created_at = now;
+        // A confirmed payment isn't reported yet and is credited when it
+        // is. The merchant was told a refunded one is rejected, now it's
+        // reported again as refund
+        let updated = match payment_state::ManualTransition::of(transaction, msg.status)? {
+            payment_state::ManualTransition::RejectNew(transition) => {
+                change_status(conn, &transition, now)?
+            }
+            payment_state::ManualTransition::ConfirmPending(transition) => {
+                change_status(conn, &transition, now)?
+            }
+            payment_state::ManualTransition::RejectPending(transition) => {
+                change_status(conn, &transition, now)?
+            }
+            payment_state::ManualTransition::ConfirmInChain(transition) => {
+                change_status(conn, &transition, now)?
+            }
+            payment_state::ManualTransition::Refund(transition) => {
+                mark_as_refund(conn, &transition, RefundReason::Manual, now)?
+            }
+        };
+        diesel::insert_into(transaction_notes::table)
+            .values(&note)
+            .execute(conn)?;
+        Ok(updated)
+    })
+}
+
+impl Handler<GetNotes> for DbExecutor {
+    type Result = Result<Vec<TransactionNote>, Error>;
+
+    fn handle(&mut self, msg: GetNotes, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transaction_notes::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        transaction_notes
+            .filter(transaction_id.eq_any(msg.transaction_ids))
+            .order(created_at.asc())
+            .load(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetDashboardStats> for DbExecutor {
+    type Result = Result<DashboardStats, Error>;
+
+    fn handle(&mut self, msg: GetDashboardStats, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::transactions::dsl::*;
+        use diesel::dsl::not;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+
+        // Pending credits are part of the history, they're only not
+        // available yet
+        let (current_balance, plan_name): (i64, String) = {
+            use crate::schema::merchants::dsl::*;
+            merchants
+                .find(msg.merchant_id.clone())
+                .select((balance + pending_balance, plan))
+                .get_result(conn)?
+        };
+        let merchant_plan: Option<Plan> = {
+            use crate::schema::plans::dsl::*;
+            plans.find(plan_name).get_result(conn).optional()?
+        };
+        let payments_this_month = transactions
+            .filter(merchant_id.eq(msg.merchant_id.clone()))
+            .filter(transaction_type.eq(TransactionType::Payment))
+            .filter(created_at.ge(plans::month_start(now)))
+            .count()
+            .get_result(conn)?;
+
+        // Confirmation time is stored in updated_at
+        let today = now.date();
+        let history_start = today - Duration::days(BALANCE_HISTORY_DAYS - 1);
+        let confirmed_today = transactions
+            .filter(merchant_id.eq(msg.merchant_id.clone()))
+            .filter(transaction_type.eq(TransactionType::Payment))
+            .filter(status.eq(TransactionStatus::Confirmed))
+            .filter(updated_at.ge(today.and_hms(0, 0, 0)))
+            .select(grin_amount)
+            .load::<i64>(conn)?
+            .iter()
+            .sum();
+        // What was credited to the merchant, its shares of split payments
+        // and referral fees included
+        let credited: Vec<(NaiveDateTime, i64)> = {
+            use crate::schema::balance_credits;
+            balance_credits::table
+                .filter(balance_credits::merchant_id.eq(msg.merchant_id.clone()))
+                .filter(balance_credits::created_at.ge(history_start.and_hms(0, 0, 0)))
+                .select((balance_credits::created_at, balance_credits::grin_amount))
+                .load(conn)?
+        };
+        let paid_out: Vec<(NaiveDateTime, i64)> = transactions
+            .filter(merchant_id.eq(msg.merchant_id.clone()))
+            .filter(transaction_type.eq(TransactionType::Payout))
+            .filter(status.ne(TransactionStatus::Rejected))
+            .filter(created_at.ge(history_start.and_hms(0, 0, 0)))
+            .select((created_at, grin_amount))
+            .load(conn)?;
+        let balance_history = balance_history(current_balance, history_start, &credited, &paid_out);
+
+        let pending_payouts = transactions
+            .filter(merchant_id.eq(msg.merchant_id.clone()))
+            .filter(transaction_type.eq(TransactionType::Payout))
+            .filter(status.eq_any(vec![
+                TransactionStatus::New,
+                TransactionStatus::Initialized,
+                TransactionStatus::Pending,
+                TransactionStatus::InChain,
+            ]))
+            .order(created_at.desc())
+            .load::<Transaction>(conn)?;
+
+        let unreported_callbacks = transactions
+            .filter(merchant_id.eq(msg.merchant_id.clone()))
+            .filter(transaction_type.eq(TransactionType::Payment))
+            .filter(status.eq_any(vec![
+                TransactionStatus::Confirmed,
+                TransactionStatus::Rejected,
+            ]))
+            .filter(not(reported))
+            .count()
+            .get_result(conn)?;
+
+        Ok(DashboardStats {
+            confirmed_today,
+            balance_history,
+            pending_payouts,
+            unreported_callbacks,
+            plan: merchant_plan,
+            payments_this_month,
+        })
+    }
+}
+
+/// Balance at the end of each day since `start` worked back from
+/// `current`: payments add what they were credited with on the day they
+/// were confirmed, payouts which weren't rejected take their amount on the
+/// day they were created
+fn balance_history(
+    current: i64,
+    start: NaiveDate,
+    credited: &[(NaiveDateTime, i64)],
+    paid_out: &[(NaiveDateTime, i64)],
+) -> Vec<i64> {
+    let mut daily_change = vec![0; BALANCE_HISTORY_DAYS as usize];
+    let changes = credited
+        .iter()
+        .map(|(at, amount)| (at, *amount))
+        .chain(paid_out.iter().map(|(at, amount)| (at, -amount)));
+    for (at, amount) in changes {
+        let day = (at.date() - start).num_days();
+        if day >= 0 && day < BALANCE_HISTORY_DAYS {
+            daily_change[day as usize] += amount;
+        }
+    }
+    let mut history = vec![0; BALANCE_HISTORY_DAYS as usize];
+    let mut day_balance = current;
+    for day in (0..BALANCE_HISTORY_DAYS as usize).rev() {
+        history[day] = day_balance;
+        day_balance -= daily_change[day];
+    }
+    history
+}
+
+impl Handler<GetApiRequests> for DbExecutor {
+    type Result = Result<Vec<ApiRequest>, Error>;
+
+    fn handle(&mut self, msg: GetApiRequests, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::api_requests::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        api_requests
+            .filter(merchant_id.eq(msg.merchant_id))
+            .order(created_at.desc())
+            .limit(msg.limit)
+            .load::<ApiRequest>(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<DeleteApiRequests> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: DeleteApiRequests, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::api_requests::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::delete(api_requests.filter(created_at.lt(msg.created_before)))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<UseReturnNonce> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: UseReturnNonce, _: &mut Self::Context) -> Self::Result {
+        let conn: &PgConnection = &self.0.get().unwrap();
+        use_return_nonce(conn, &msg, self.1.now())
+    }
+}
+
+fn use_return_nonce(
+    conn: &PgConnection,
+    msg: &UseReturnNonce,
+    now: NaiveDateTime,
+) -> Result<(), Error> {
+    use crate::schema::used_return_nonces::dsl::*;
+    diesel::insert_into(used_return_nonces)
+        .values((
+            merchant_id.eq(&msg.merchant_id),
+            nonce.eq(&msg.nonce),
+            used_at.eq(now),
+        ))
+        .execute(conn)
+        .map_err(|e| match e {
+            diesel::result::Error::DatabaseError(
+                diesel::result::DatabaseErrorKind::UniqueViolation,
+                _,
+            ) => Error::InvalidEntity(s!("payload was already used")),
+            e => e.into(),
+        })?;
+    Ok(())
+}
+
+impl Handler<DeleteUsedReturnNonces> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, _: DeleteUsedReturnNonces, _: &mut Self::Context) -> Self::Result {
+        let conn: &PgConnection = &self.0.get().unwrap();
+        delete_used_return_nonces(conn, self.1.now())
+    }
+}
+
+/// A payload issued up to the clock skew ahead of its use is valid that
+/// much longer
+fn delete_used_return_nonces(conn: &PgConnection, now: NaiveDateTime) -> Result<(), Error> {
+    use crate::schema::used_return_nonces::dsl::*;
+    let used_before =
+        now - Duration::seconds(return_url::MAX_AGE_SECONDS + return_url::MAX_CLOCK_SKEW_SECONDS);
+    diesel::delete(used_return_nonces.filter(used_at.lt(used_before))).execute(conn)?;
+    Ok(())
+}
+
+impl Handler<GetApiToken> for DbExecutor {
+    type Result = Result<ApiToken, Error>;
+
+    fn handle(&mut self, msg: GetApiToken, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::api_tokens::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        api_tokens
+            .filter(merchant_id.eq(msg.merchant_id))
+            .filter(token_hash.eq(msg.token_hash))
+            .get_result(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetApiTokens> for DbExecutor {
+    type Result = Result<Vec<ApiToken>, Error>;
+
+    fn handle(&mut self, msg: GetApiTokens, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::api_tokens::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        api_tokens
+            .filter(merchant_id.eq(msg.merchant_id))
+            .order(created_at.asc())
+            .load::<ApiToken>(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<CreateApiToken> for DbExecutor {
+    type Result = Result<ApiToken, Error>;
+
+    fn handle(&mut self, msg: CreateApiToken, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::api_tokens::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        info!(
+            "Create API token {} with scopes {:?} for merchant {}",
+            msg.0.name, msg.0.scopes, msg.0.merchant_id
+        );
+        diesel::insert_into(api_tokens)
+            .values(&msg.0)
+            .get_result(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<DeleteApiToken> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: DeleteApiToken, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::api_tokens::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::delete(
+            api_tokens
+                .filter(id.eq(msg.id))
+                .filter(merchant_id.eq(msg.merchant_id)),
+        )
+        .execute(conn)
+        .map(|_| ())
+        .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetRefundAddresses> for DbExecutor {
+    type Result = Result<Vec<RefundAddress>, Error>;
+
+    fn handle(&mut self, msg: GetRefundAddresses, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::refund_addresses::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        refund_addresses
+            .filter(merchant_id.eq(msg.merchant_id))
+            .order(created_at.asc())
+            .load(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<CreateRefundAddress> for DbExecutor {
+    type Result = Result<RefundAddress, Error>;
+
+    fn handle(&mut self, msg: CreateRefundAddress, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::refund_addresses::dsl::*;
+        use crate::schema::security_events;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        info!(
+            "Add refund address {} for merchant {}",
+            msg.address.address, msg.address.merchant_id
+        );
+        conn.transaction(|| {
+            let created: RefundAddress = diesel::insert_into(refund_addresses)
+                .values(&msg.address)
+                .get_result(conn)
+                .map_err(|e| match e {
+                    diesel::result::Error::DatabaseError(
+                        diesel::result::DatabaseErrorKind::UniqueViolation,
+                        _,
+                    ) => Error::AlreadyExists(format!(
+                        "{} is already in the refund address book",
+                        msg.address.address
+                    )),
+                    e => e.into(),
+                })?;
+            diesel::insert_into(security_events::table)
+                .values(&SecurityEvent::new(
+                    &created.merchant_id,
+                    SecurityEventKind::RefundAddressAdded,
+                    msg.ip.clone(),
+                    msg.country.clone(),
+                    created.created_at,
+                ))
+                .execute(conn)?;
+            Ok(created)
+        })
+    }
+}
+
+impl Handler<DeleteRefundAddress> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: DeleteRefundAddress, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::refund_addresses::dsl::*;
+        use crate::schema::security_events;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        conn.transaction(|| {
+            let deleted: RefundAddress = diesel::delete(
+                refund_addresses
+                    .filter(id.eq(msg.id))
+                    .filter(merchant_id.eq(&msg.merchant_id)),
+            )
+            .get_result(conn)?;
+            info!(
+                "Delete refund address {} of merchant {}",
+                deleted.address, deleted.merchant_id
+            );
+            diesel::insert_into(security_events::table)
+                .values(&SecurityEvent::new(
+                    &msg.merchant_id,
+                    SecurityEventKind::RefundAddressDeleted,
+                    msg.ip.clone(),
+                    msg.country.clone(),
+                    now,
+                ))
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+}
+
+impl Handler<SetRefundAddress> for DbExecutor {
     type Result = Result<Transaction, Error>;
 
-    fn handle(&mut self, msg: UpdateTransactionStatus, _: &mut Self::Context) -> Self::Result {
-        use crate::schema::transactions::dsl::*;
+    fn handle(&mut self, msg: SetRefundAddress, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::refund_addresses;
+        use crate::schema::transaction_notes;
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        conn.transaction(|| {
+            let transaction: Transaction = transactions
+                .filter(id.eq(msg.transaction_id))
+                .filter(merchant_id.eq(&msg.merchant_id))
+                .filter(transaction_type.eq(TransactionType::Payment))
+                .for_update()
+                .get_result(conn)?;
+            if transaction.status != TransactionStatus::Refund {
+                return Err(Error::InvalidEntity(format!(
+                    "payment is {}, only refunds have a refund address",
+                    transaction.status
+                )));
+            }
+            let book: Vec<RefundAddress> = refund_addresses::table
+                .filter(refund_addresses::merchant_id.eq(&msg.merchant_id))
+                .load(conn)?;
+            check_destination(
+                transaction.grin_amount,
+                &msg.address,
+                &book,
+                now,
+                &REFUND_ADDRESS_CONFIG,
+            )?;
+            let label = book
+                .iter()
+                .find(|entry| entry.address == msg.address)
+                .map(|entry| format!(" ({})", entry.label))
+                .unwrap_or_default();
+            let mut note = TransactionNote::new(
+                transaction.id,
+                &msg.merchant_id,
+                &format!("Refund address set to {}{}", msg.address, label),
+            )?;
+            note.created_at = now;
+            diesel::insert_into(transaction_notes::table)
+                .values(&note)
+                .execute(conn)?;
+            diesel::update(transactions.filter(id.eq(transaction.id)))
+                .set((refund_address.eq(&msg.address), updated_at.eq(now)))
+                .get_result(conn)
+                .map_err(|e| e.into())
+        })
+    }
+}
+
+impl Handler<SetReferral> for DbExecutor {
+    type Result = Result<Merchant, Error>;
+
+    fn handle(&mut self, msg: SetReferral, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::merchants::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        conn.transaction(|| {
+            let merchant: Merchant = merchants
+                .find(&msg.merchant_id)
+                .for_update()
+                .get_result(conn)?;
+            let (new_referrer, share) = match msg.referral {
+                Some((new_referrer, percent)) => {
+                    let referrer: Merchant = merchants
+                        .find(&new_referrer)
+                        .get_result(conn)
+                        .optional()?
+                        .ok_or_else(|| {
+                            Error::InvalidEntity(format!("merchant {} doesn't exist", new_referrer))
+                        })?;
+                    let share = check_referral(&merchant, &referrer, percent)?;
+                    (Some(new_referrer), Some(share))
+                }
+                None => (None, None),
+            };
+            diesel::update(merchants.find(&merchant.id))
+                .set((referrer_id.eq(new_referrer), referral_share_bps.eq(share)))
+                .get_result(conn)
+                .map_err(|e| e.into())
+        })
+    }
+}
+
+impl Handler<GetReferredMerchants> for DbExecutor {
+    type Result = Result<Vec<Merchant>, Error>;
+
+    fn handle(&mut self, msg: GetReferredMerchants, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::merchants::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        merchants
+            .filter(referrer_id.eq(msg.referrer_id))
+            .order(created_at.desc())
+            .load(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetReferralMonths> for DbExecutor {
+    type Result = Result<Vec<ReferralMonth>, Error>;
+
+    fn handle(&mut self, msg: GetReferralMonths, _: &mut Self::Context) -> Self::Result {
+        let conn: &PgConnection = &self.0.get().unwrap();
+        referral_months(conn, &msg.referrer_id, msg.limit)
+    }
+}
+
+/// Monthly summaries of a referrer, see `GetReferralMonths`
+fn referral_months(
+    conn: &PgConnection,
+    referrer_id: &str,
+    limit: i64,
+) -> Result<Vec<ReferralMonth>, Error> {
+    use diesel::sql_query;
+    use diesel::sql_types::{BigInt, Text};
+    sql_query(
+        "SELECT date_trunc('month', updated_at)::date AS month,
+                COUNT(DISTINCT merchant_id) AS merchants,
+                COUNT(*) AS payments,
+                COALESCE(SUM(grin_amount), 0)::BIGINT AS volume,
+                COALESCE(SUM(knockturn_fee), 0)::BIGINT AS fees,
+                COALESCE(SUM(referral_fee), 0)::BIGINT AS earned
+            FROM transactions
+            WHERE referrer_id = $1 AND transaction_type = 'payment' AND status = 'confirmed'
+            GROUP BY 1
+            ORDER BY 1 DESC
+            LIMIT $2",
+    )
+    .bind::<Text, _>(referrer_id)
+    .bind::<BigInt, _>(limit)
+    .load(conn)
+    .map_err(|e| e.into())
+}
+
+impl Handler<ReleasePendingCredits> for DbExecutor {
+    type Result = Result<usize, Error>;
+
+    fn handle(&mut self, _: ReleasePendingCredits, _: &mut Self::Context) -> Self::Result {
+        let conn: &PgConnection = &self.0.get().unwrap();
+        release_pending_credits(conn, self.1.now())
+    }
+}
+
+impl Handler<ChangePendingCredits> for DbExecutor {
+    type Result = Result<Vec<PendingCredit>, Error>;
+
+    fn handle(&mut self, msg: ChangePendingCredits, _: &mut Self::Context) -> Self::Result {
+        let conn: &PgConnection = &self.0.get().unwrap();
+        change_pending_credits(conn, &msg, self.1.now())
+    }
+}
+
+impl Handler<GetPendingCredits> for DbExecutor {
+    type Result = Result<Vec<PendingCredit>, Error>;
+
+    fn handle(&mut self, msg: GetPendingCredits, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::pending_credits::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        pending_credits
+            .filter(transaction_id.eq(msg.transaction_id))
+            .order(merchant_id.asc())
+            .load(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetPaymentSplits> for DbExecutor {
+    type Result = Result<(Transaction, Vec<PaymentSplit>), Error>;
+
+    fn handle(&mut self, msg: GetPaymentSplits, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::payment_splits;
+        use crate::schema::transactions::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let transaction: Transaction = transactions
+            .filter(id.eq(msg.transaction_id))
+            .filter(merchant_id.eq(&msg.merchant_id))
+            .filter(transaction_type.eq(TransactionType::Payment))
+            .get_result(conn)?;
+        let splits = payment_splits::table
+            .filter(payment_splits::transaction_id.eq(transaction.id))
+            .order(payment_splits::merchant_id.asc())
+            .load(conn)?;
+        Ok((transaction, splits))
+    }
+}
+
+impl Handler<JoinPlatform> for DbExecutor {
+    type Result = Result<Merchant, Error>;
+
+    fn handle(&mut self, msg: JoinPlatform, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::merchants::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        conn.transaction(|| {
+            let seller: Merchant = merchants
+                .find(&msg.merchant_id)
+                .for_update()
+                .get_result(conn)?;
+            if let Some(ref new_platform) = msg.platform_id {
+                let platform: Merchant = merchants
+                    .find(new_platform)
+                    .get_result(conn)
+                    .optional()?
+                    .ok_or_else(|| {
+                        Error::InvalidEntity(format!("merchant {} doesn't exist", new_platform))
+                    })?;
+                let sellers: i64 = merchants
+                    .filter(platform_id.eq(&seller.id))
+                    .count()
+                    .get_result(conn)?;
+                splits::check_platform(&seller, &platform, sellers)?;
+            }
+            info!(
+                "Merchant {} moved from platform {:?} to {:?}",
+                seller.id, seller.platform_id, msg.platform_id
+            );
+            diesel::update(merchants.find(&seller.id))
+                .set(platform_id.eq(msg.platform_id))
+                .get_result(conn)
+                .map_err(|e| e.into())
+        })
+    }
+}
+
+impl Handler<GetSellers> for DbExecutor {
+    type Result = Result<Vec<Merchant>, Error>;
+
+    fn handle(&mut self, msg: GetSellers, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::merchants::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        merchants
+            .filter(platform_id.eq(msg.platform_id))
+            .order(id.asc())
+            .load(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetSettlementShares> for DbExecutor {
+    type Result = Result<SettlementShares, Error>;
+
+    fn handle(&mut self, msg: GetSettlementShares, _: &mut Self::Context) -> Self::Result {
+        use diesel::sql_query;
+        use diesel::sql_types::{Text, Timestamp};
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let day_start = msg.date.and_hms(0, 0, 0);
+        // Same rounding as `splits::share_amount`
+        sql_query(
+            "SELECT
+                COALESCE(SUM(FLOOR(t.grin_amount::NUMERIC * s.share_bps / 10000))
+                    FILTER (WHERE t.merchant_id = $1 AND s.merchant_id <> $1), 0)::BIGINT
+                    AS given,
+                COALESCE(SUM(FLOOR(t.grin_amount::NUMERIC * s.share_bps / 10000))
+                    FILTER (WHERE s.merchant_id = $1 AND t.merchant_id <> $1), 0)::BIGINT
+                    AS received
+            FROM payment_splits s
+            JOIN transactions t ON t.id = s.transaction_id
+            WHERE (t.merchant_id = $1 OR s.merchant_id = $1)
+                AND t.status = 'confirmed'
+                AND t.updated_at >= $2 AND t.updated_at < $3",
+        )
+        .bind::<Text, _>(msg.merchant_id)
+        .bind::<Timestamp, _>(day_start)
+        .bind::<Timestamp, _>(day_start + Duration::days(1))
+        .get_result(conn)
+        .map_err(|e| e.into())
+    }
+}
+
+impl Handler<CreateInviteCode> for DbExecutor {
+    type Result = Result<InviteCode, Error>;
+
+    fn handle(&mut self, msg: CreateInviteCode, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::invite_codes::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        info!(
+            "{} created an invite code for {} merchants",
+            msg.0.created_by, msg.0.max_uses
+        );
+        diesel::insert_into(invite_codes)
+            .values(&msg.0)
+            .get_result(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetInviteCodes> for DbExecutor {
+    type Result = Result<Vec<InviteCode>, Error>;
+
+    fn handle(&mut self, _: GetInviteCodes, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::invite_codes::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        invite_codes
+            .order(created_at.desc())
+            .load::<InviteCode>(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<DeleteInviteCode> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: DeleteInviteCode, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::invite_codes::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::delete(invite_codes.filter(code.eq(msg.code)))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<CreateDeniedNetwork> for DbExecutor {
+    type Result = Result<DeniedNetwork, Error>;
+
+    fn handle(&mut self, msg: CreateDeniedNetwork, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::denied_networks::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        info!("{} denied network {}", msg.0.created_by, msg.0.network);
+        diesel::insert_into(denied_networks)
+            .values(&msg.0)
+            .get_result(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetDeniedNetworks> for DbExecutor {
+    type Result = Result<Vec<DeniedNetwork>, Error>;
+
+    fn handle(&mut self, _: GetDeniedNetworks, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::denied_networks::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        denied_networks
+            .order(created_at.desc())
+            .load::<DeniedNetwork>(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<DeleteDeniedNetwork> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: DeleteDeniedNetwork, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::denied_networks::dsl::*;
         let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::delete(denied_networks.filter(network.eq(msg.network)))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| e.into())
+    }
+}
 
-        diesel::update(transactions.filter(id.eq(msg.id)))
-            .set((status.eq(msg.status), updated_at.eq(Utc::now().naive_utc())))
+impl Handler<SetFeatureFlag> for DbExecutor {
+    type Result = Result<FeatureFlag, Error>;
+
+    fn handle(&mut self, msg: SetFeatureFlag, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::feature_flags::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        info!(
+            "{} set flag {} to {} for {}% of merchants",
+            msg.0.updated_by, msg.0.name, msg.0.enabled, msg.0.rollout_percent
+        );
+        diesel::insert_into(feature_flags)
+            .values(&msg.0)
+            .on_conflict(name)
+            .do_update()
+            .set(&msg.0)
             .get_result(conn)
             .map_err(|e| e.into())
     }
 }
 
-impl Handler<RegisterRate> for DbExecutor {
+impl Handler<SetFeatureFlagOverride> for DbExecutor {
+    type Result = Result<FeatureFlagOverride, Error>;
+
+    fn handle(&mut self, msg: SetFeatureFlagOverride, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::feature_flag_overrides::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        info!(
+            "{} set flag {} to {} for merchant {}",
+            msg.0.updated_by, msg.0.name, msg.0.enabled, msg.0.merchant_id
+        );
+        diesel::insert_into(feature_flag_overrides)
+            .values(&msg.0)
+            .on_conflict((name, merchant_id))
+            .do_update()
+            .set(&msg.0)
+            .get_result(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<DeleteFeatureFlagOverride> for DbExecutor {
     type Result = Result<(), Error>;
 
-    fn handle(&mut self, msg: RegisterRate, _: &mut Self::Context) -> Self::Result {
-        use crate::schema::rates::dsl::*;
+    fn handle(&mut self, msg: DeleteFeatureFlagOverride, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::feature_flag_overrides::dsl::*;
         let conn: &PgConnection = &self.0.get().unwrap();
+        diesel::delete(feature_flag_overrides.find((msg.name, msg.merchant_id)))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(|e| e.into())
+    }
+}
 
-        for (currency, new_rate) in msg.rates {
-            let new_rate = Rate {
-                id: currency.to_uppercase(),
-                rate: new_rate,
-                updated_at: Local::now().naive_local(),
-            };
+impl Handler<GetFeatureFlags> for DbExecutor {
+    type Result = Result<(Vec<FeatureFlag>, Vec<FeatureFlagOverride>), Error>;
 
-            diesel::insert_into(rates)
-                .values(&new_rate)
-                .on_conflict(id)
-                .do_update()
-                .set(&new_rate)
-                .get_result::<Rate>(conn)
-                .map_err(|e| Error::from(e))?;
-        }
-        Ok(())
+    fn handle(&mut self, _: GetFeatureFlags, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::{feature_flag_overrides, feature_flags};
+        let conn: &PgConnection = &self.0.get().unwrap();
+        let flags = feature_flags::table
+            .order(feature_flags::name.asc())
+            .load::<FeatureFlag>(conn)?;
+        let overrides = feature_flag_overrides::table
+            .order((
+                feature_flag_overrides::name.asc(),
+                feature_flag_overrides::merchant_id.asc(),
+            ))
+            .load::<FeatureFlagOverride>(conn)?;
+        Ok((flags, overrides))
     }
 }
 
-impl Handler<ConfirmTransaction> for DbExecutor {
-    type Result = Result<Transaction, Error>;
+impl Handler<GetRateLimitBuckets> for DbExecutor {
+    type Result = Result<Vec<RateLimitBucket>, Error>;
 
-    fn handle(&mut self, msg: ConfirmTransaction, _: &mut Self::Context) -> Self::Result {
-        use crate::schema::merchants;
-        use crate::schema::transactions;
+    fn handle(&mut self, _: GetRateLimitBuckets, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::rate_limit_buckets::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        rate_limit_buckets
+            .load::<RateLimitBucket>(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetPlans> for DbExecutor {
+    type Result = Result<Vec<Plan>, Error>;
+
+    fn handle(&mut self, _: GetPlans, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::plans::dsl::*;
         let conn: &PgConnection = &self.0.get().unwrap();
+        plans
+            .order(name.asc())
+            .load::<Plan>(conn)
+            .map_err(|e| e.into())
+    }
+}
+
+impl Handler<SaveRateLimitBuckets> for DbExecutor {
+    type Result = Result<(), Error>;
 
+    fn handle(&mut self, msg: SaveRateLimitBuckets, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::rate_limit_buckets::dsl::*;
+        let conn: &PgConnection = &self.0.get().unwrap();
         conn.transaction(|| {
-            let tx = diesel::update(
-                transactions::table.filter(transactions::columns::id.eq(msg.transaction.id)),
-            )
-            .set((
-                transactions::columns::status.eq(TransactionStatus::Confirmed),
-                transactions::columns::updated_at.eq(Utc::now().naive_utc()),
-            ))
-            .get_result(conn)?;
-            diesel::update(
-                merchants::table.filter(merchants::columns::id.eq(msg.transaction.merchant_id)),
-            )
-            .set(
-                merchants::columns::balance
-                    .eq(merchants::columns::balance + msg.transaction.grin_amount),
-            )
-            .get_result(conn)
-            .map(|_: Merchant| ())?;
-            Ok(tx)
+            for bucket in &msg.0 {
+                diesel::insert_into(rate_limit_buckets)
+                    .values(bucket)
+                    .on_conflict(merchant_id)
+                    .do_update()
+                    .set(bucket)
+                    .execute(conn)?;
+            }
+            Ok(())
         })
     }
 }
 
-impl Handler<ReportAttempt> for DbExecutor {
-    type Result = Result<(), Error>;
+#[derive(QueryableByName)]
+struct IndexName {
+    #[sql_type = "diesel::sql_types::Text"]
+    indexname: String,
+}
 
-    fn handle(&mut self, msg: ReportAttempt, _: &mut Self::Context) -> Self::Result {
-        use crate::schema::transactions::dsl::*;
+impl Handler<GetMissingIndexes> for DbExecutor {
+    type Result = Result<Vec<String>, Error>;
+
+    fn handle(&mut self, _: GetMissingIndexes, _: &mut Self::Context) -> Self::Result {
+        use diesel::sql_query;
         let conn: &PgConnection = &self.0.get().unwrap();
-        let next_attempt = msg
-            .next_attempt
-            .unwrap_or(Utc::now().naive_utc() + Duration::seconds(10));
-        diesel::update(transactions.filter(id.eq(msg.transaction_id)))
-            .set((
-                report_attempts.eq(report_attempts + 1),
-                next_report_attempt.eq(next_attempt),
-            ))
-            .get_result(conn)
-            .map_err(|e| e.into())
-            .map(|_: Transaction| ())
+        let existing: Vec<IndexName> =
+            sql_query("SELECT indexname FROM pg_indexes WHERE schemaname = current_schema()")
+                .load(conn)?;
+        Ok(EXPECTED_INDEXES
+            .iter()
+            .filter(|name| !existing.iter().any(|index| index.indexname == **name))
+            .map(|name| s!(name))
+            .collect())
     }
 }
 
-impl Handler<GetUnreportedPaymentsByStatus> for DbExecutor {
-    type Result = Result<Vec<Transaction>, Error>;
+impl Handler<RefreshDueViews> for DbExecutor {
+    type Result = Result<Vec<ViewRefresh>, Error>;
 
-    fn handle(
-        &mut self,
-        msg: GetUnreportedPaymentsByStatus,
-        _: &mut Self::Context,
-    ) -> Self::Result {
-        use crate::schema::transactions::dsl::*;
+    fn handle(&mut self, _: RefreshDueViews, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::view_refreshes::dsl::*;
         let conn: &PgConnection = &self.0.get().unwrap();
+        let now = self.1.now();
+        let last_refreshes: HashMap<String, NaiveDateTime> = view_refreshes
+            .select((name, refreshed_at))
+            .load::<(String, NaiveDateTime)>(conn)?
+            .into_iter()
+            .collect();
+        let mut results = vec![];
+        for view in MATERIALIZED_VIEWS {
+            let last_refresh = last_refreshes.get(view.name).cloned();
+            let is_due = match last_refresh {
+                Some(last_refresh) => (now - last_refresh).num_seconds() >= view.refresh_seconds,
+                None => true,
+            };
+            if !is_due {
+                results.push(ViewRefresh {
+                    name: view.name,
+                    age_seconds: last_refresh
+                        .map(|last_refresh| (now - last_refresh).num_seconds()),
+                    error: None,
+                });
+                continue;
+            }
+            // Unlike a plain refresh, readers of the view aren't blocked
+            let refreshed = conn
+                .batch_execute(&format!(
+                    "REFRESH MATERIALIZED VIEW CONCURRENTLY {}",
+                    view.name
+                ))
+                .and_then(|_| {
+                    diesel::insert_into(view_refreshes)
+                        .values((name.eq(view.name), refreshed_at.eq(now)))
+                        .on_conflict(name)
+                        .do_update()
+                        .set(refreshed_at.eq(now))
+                        .execute(conn)
+                });
+            results.push(match refreshed {
+                Ok(_) => ViewRefresh {
+                    name: view.name,
+                    age_seconds: Some((self.1.now() - now).num_seconds()),
+                    error: None,
+                },
+                Err(e) => ViewRefresh {
+                    name: view.name,
+                    age_seconds: last_refresh
+                        .map(|last_refresh| (now - last_refresh).num_seconds()),
+                    error: Some(e.into()),
+                },
+            });
+        }
+        Ok(results)
+    }
+}
 
-        let query = transactions
-            .filter(reported.ne(true))
-            .filter(status.eq(msg.0))
-            .filter(report_attempts.lt(MAX_REPORT_ATTEMPTS))
-            .filter(
-                next_report_attempt
-                    .le(Utc::now().naive_utc())
-                    .or(next_report_attempt.is_null()),
-            );
+impl Handler<GetAnalyticsVolume> for DbExecutor {
+    type Result = Result<Vec<VolumeBucket>, Error>;
 
-        let payments = query
-            .load::<Transaction>(conn)
-            .map_err(|e| Error::Db(s!(e)))?;
+    fn handle(&mut self, msg: GetAnalyticsVolume, _: &mut Self::Context) -> Self::Result {
+        use diesel::sql_query;
+        use diesel::sql_types::{Date, Text};
+        let conn: &PgConnection = &self.0.get().unwrap();
+        sql_query(
+            "SELECT date_trunc($1, hour) AS period,
+                SUM(created)::BIGINT AS created,
+                SUM(confirmed)::BIGINT AS confirmed,
+                SUM(volume)::BIGINT AS volume
+            FROM analytics_hourly
+            WHERE hour >= $2
+            GROUP BY 1
+            ORDER BY 1",
+        )
+        .bind::<Text, _>(msg.granularity.as_str())
+        .bind::<Date, _>(msg.since)
+        .load(conn)
+        .map_err(|e| e.into())
+    }
+}
 
-        Ok(payments)
+impl Handler<GetPaymentsHeatmap> for DbExecutor {
+    type Result = Result<Vec<HeatmapCell>, Error>;
+
+    fn handle(&mut self, msg: GetPaymentsHeatmap, _: &mut Self::Context) -> Self::Result {
+        use diesel::sql_query;
+        use diesel::sql_types::Date;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        sql_query(
+            "SELECT EXTRACT(ISODOW FROM hour)::INTEGER AS weekday,
+                EXTRACT(HOUR FROM hour)::INTEGER AS hour,
+                SUM(created)::BIGINT AS created
+            FROM analytics_hourly
+            WHERE hour >= $1
+            GROUP BY 1, 2
+            ORDER BY 1, 2",
+        )
+        .bind::<Date, _>(msg.since)
+        .load(conn)
+        .map_err(|e| e.into())
     }
 }
 
-impl Handler<Confirm2FA> for DbExecutor {
-    type Result = Result<(), Error>;
+impl Handler<GetTopMerchants> for DbExecutor {
+    type Result = Result<Vec<MerchantVolume>, Error>;
 
-    fn handle(&mut self, msg: Confirm2FA, _: &mut Self::Context) -> Self::Result {
-        info!("Confirm 2fa token for merchant {}", msg.merchant_id);
-        use crate::schema::merchants::dsl::*;
+    fn handle(&mut self, msg: GetTopMerchants, _: &mut Self::Context) -> Self::Result {
+        use diesel::sql_query;
+        use diesel::sql_types::{BigInt, Date};
         let conn: &PgConnection = &self.0.get().unwrap();
-        diesel::update(merchants.filter(id.eq(msg.merchant_id)))
-            .set((confirmed_2fa.eq(true),))
-            .get_result(conn)
-            .map_err(|e| e.into())
-            .map(|_: Merchant| ())
+        sql_query(
+            "SELECT merchant_id,
+                SUM(payments)::BIGINT AS payments,
+                SUM(volume)::BIGINT AS volume
+            FROM analytics_merchant_daily
+            WHERE day >= $1
+            GROUP BY 1
+            ORDER BY 3 DESC
+            LIMIT $2",
+        )
+        .bind::<Date, _>(msg.since)
+        .bind::<BigInt, _>(msg.limit)
+        .load(conn)
+        .map_err(|e| e.into())
     }
 }
 
-impl Handler<Reset2FA> for DbExecutor {
-    type Result = Result<(), Error>;
+impl Handler<GetAnalyticsTotals> for DbExecutor {
+    type Result = Result<AnalyticsTotals, Error>;
 
-    fn handle(&mut self, msg: Reset2FA, _: &mut Self::Context) -> Self::Result {
-        info!("Confirm 2fa token for merchant {}", msg.merchant_id);
-        use crate::schema::merchants::dsl::*;
+    fn handle(&mut self, msg: GetAnalyticsTotals, _: &mut Self::Context) -> Self::Result {
+        use diesel::sql_query;
+        use diesel::sql_types::Date;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        sql_query(
+            "SELECT COALESCE(SUM(created), 0)::BIGINT AS created,
+                COALESCE(SUM(confirmed), 0)::BIGINT AS confirmed,
+                COALESCE(SUM(confirmation_seconds), 0)::BIGINT AS confirmation_seconds,
+                COALESCE(SUM(callbacks_succeeded), 0)::BIGINT AS callbacks_succeeded,
+                COALESCE(SUM(callbacks_failed), 0)::BIGINT AS callbacks_failed
+            FROM analytics_hourly
+            WHERE hour >= $1",
+        )
+        .bind::<Date, _>(msg.since)
+        .get_result(conn)
+        .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetPayerWallets> for DbExecutor {
+    type Result = Result<Vec<PayerWallets>, Error>;
+
+    fn handle(&mut self, msg: GetPayerWallets, _: &mut Self::Context) -> Self::Result {
+        use diesel::sql_query;
+        use diesel::sql_types::Date;
         let conn: &PgConnection = &self.0.get().unwrap();
+        sql_query(
+            "SELECT slate_version, user_agent,
+                SUM(payments)::BIGINT AS payments,
+                MAX(day) AS last_seen
+            FROM analytics_payer_wallets_daily
+            WHERE day >= $1
+            GROUP BY 1, 2
+            ORDER BY 3 DESC",
+        )
+        .bind::<Date, _>(msg.since)
+        .load(conn)
+        .map_err(|e| e.into())
+    }
+}
 
-        let new_token_2fa = BASE32.encode(&thread_rng().gen::<[u8; 10]>());
-        diesel::update(merchants.filter(id.eq(msg.merchant_id)))
-            .set((confirmed_2fa.eq(false), token_2fa.eq(new_token_2fa)))
-            .get_result(conn)
-            .map_err(|e| e.into())
-            .map(|_: Merchant| ())
+impl Handler<GetPaymentCountries> for DbExecutor {
+    type Result = Result<Vec<PaymentCountries>, Error>;
+
+    fn handle(&mut self, msg: GetPaymentCountries, _: &mut Self::Context) -> Self::Result {
+        use diesel::sql_query;
+        use diesel::sql_types::Date;
+        let conn: &PgConnection = &self.0.get().unwrap();
+        sql_query(
+            "SELECT country,
+                SUM(payments)::BIGINT AS payments,
+                SUM(confirmed)::BIGINT AS confirmed,
+                SUM(volume)::BIGINT AS volume
+            FROM analytics_payment_countries_daily
+            WHERE day >= $1
+            GROUP BY 1
+            ORDER BY 2 DESC",
+        )
+        .bind::<Date, _>(msg.since)
+        .load(conn)
+        .map_err(|e| e.into())
     }
 }
 
-impl Handler<RejectExpiredPayments> for DbExecutor {
-    type Result = Result<(), Error>;
+impl Handler<GetUnreportedSummary> for DbExecutor {
+    type Result = Result<Vec<UnreportedPayments>, Error>;
 
-    fn handle(&mut self, _: RejectExpiredPayments, _: &mut Self::Context) -> Self::Result {
-        use crate::schema::transactions::dsl::*;
+    fn handle(&mut self, _: GetUnreportedSummary, _: &mut Self::Context) -> Self::Result {
+        use diesel::sql_query;
         let conn: &PgConnection = &self.0.get().unwrap();
-        diesel::update(
-            transactions
-                .filter(status.eq(TransactionStatus::New))
-                .filter(transaction_type.eq(TransactionType::Payment))
-                .filter(
-                    created_at
-                        .lt(Utc::now().naive_utc() - Duration::seconds(NEW_PAYMENT_TTL_SECONDS)),
-                ),
+        sql_query(
+            "SELECT merchant_id, status::TEXT AS status, payments, oldest_updated_at,
+                max_report_attempts
+            FROM unreported_summary
+            ORDER BY oldest_updated_at",
         )
-        .set(status.eq(TransactionStatus::Rejected))
-        .execute(conn)
+        .load(conn)
         .map_err(|e| e.into())
-        .map(|n| {
-            if n > 0 {
-                info!("Rejected {} expired new payments", n);
-            }
-            ()
-        })
     }
 }
-impl Handler<GetCurrentHeight> for DbExecutor {
-    type Result = Result<i64, Error>;
 
-    fn handle(&mut self, _: GetCurrentHeight, _: &mut Self::Context) -> Self::Result {
-        use crate::schema::current_height::dsl::*;
+impl Handler<GetFeeBuckets> for DbExecutor {
+    type Result = Result<Vec<FeeBucket>, Error>;
+
+    fn handle(&mut self, msg: GetFeeBuckets, _: &mut Self::Context) -> Self::Result {
+        use diesel::sql_query;
+        use diesel::sql_types::{BigInt, Date, Text};
         let conn: &PgConnection = &self.0.get().unwrap();
-        current_height
-            .select(height)
-            .first(conn)
-            .map_err(|e| e.into())
+        sql_query(
+            "SELECT date_trunc($1, created_at) AS period,
+                COUNT(*) AS payouts,
+                SUM(COALESCE(transfer_fee, $3))::BIGINT AS charged,
+                SUM(real_transfer_fee)::BIGINT AS actual,
+                COUNT(*) FILTER (WHERE real_transfer_fee > COALESCE(transfer_fee, $3))
+                    AS underpriced
+            FROM transactions
+            WHERE transaction_type = 'payout' AND status <> 'rejected'
+                AND real_transfer_fee IS NOT NULL AND created_at >= $2
+            GROUP BY 1
+            ORDER BY 1",
+        )
+        .bind::<Text, _>(msg.granularity.as_str())
+        .bind::<Date, _>(msg.since)
+        .bind::<BigInt, _>(TRANSFER_FEE)
+        .load(conn)
+        .map_err(|e| e.into())
+    }
+}
+
+impl Handler<GetUnderpricedPayouts> for DbExecutor {
+    type Result = Result<Vec<UnderpricedPayout>, Error>;
+
+    fn handle(&mut self, msg: GetUnderpricedPayouts, _: &mut Self::Context) -> Self::Result {
+        use diesel::sql_query;
+        use diesel::sql_types::{BigInt, Date};
+        let conn: &PgConnection = &self.0.get().unwrap();
+        sql_query(
+            "SELECT id::TEXT AS id, merchant_id, status::TEXT AS status, created_at,
+                COALESCE(transfer_fee, $2) AS charged,
+                real_transfer_fee AS actual
+            FROM transactions
+            WHERE transaction_type = 'payout' AND status <> 'rejected'
+                AND real_transfer_fee > COALESCE(transfer_fee, $2) AND created_at >= $1
+            ORDER BY real_transfer_fee - COALESCE(transfer_fee, $2) DESC, created_at DESC
+            LIMIT $3",
+        )
+        .bind::<Date, _>(msg.since)
+        .bind::<BigInt, _>(TRANSFER_FEE)
+        .bind::<BigInt, _>(msg.limit)
+        .load(conn)
+        .map_err(|e| e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::tests::create_tx;
+    use crate::payment_state::Payment;
+    use chrono::Utc;
+
+    /// Connection to `TEST_DATABASE_URL` with migrations applied, tests
+    /// which need one are ignored unless asked for
+    fn test_connection() -> PgConnection {
+        let url = std::env::var("TEST_DATABASE_URL")
+            .expect("TEST_DATABASE_URL should point to a database with the migrations applied");
+        PgConnection::establish(&url).expect("Cannot connect to TEST_DATABASE_URL")
+    }
+
+    /// Merchant with the given id and nothing else set
+    fn insert_merchant(conn: &PgConnection, id: &str) -> Result<(), Error> {
+        use crate::schema::merchants;
+        diesel::insert_into(merchants::table)
+            .values((
+                merchants::id.eq(id),
+                merchants::email.eq(format!("{}@example.com", id)),
+                merchants::password.eq(""),
+                merchants::created_at.eq(Utc::now().naive_utc()),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    /// Sum of the balance credits of the merchant
+    fn credited(conn: &PgConnection, merchant: &str) -> Result<i64, Error> {
+        use crate::schema::balance_credits;
+        let amounts: Vec<i64> = balance_credits::table
+            .filter(balance_credits::merchant_id.eq(merchant))
+            .select(balance_credits::grin_amount)
+            .load(conn)?;
+        Ok(amounts.iter().sum())
+    }
+
+    #[test]
+    fn test_balance_history() {
+        let start = NaiveDate::from_ymd(2019, 7, 1);
+        let at = |day: u32| NaiveDate::from_ymd(2019, 7, day).and_hms(12, 0, 0);
+        let history = balance_history(
+            100,
+            start,
+            &[(at(2), 50), (at(30), 30)],
+            &[(at(3), 40), (at(30), 10)],
+        );
+        assert_eq!(history.len(), BALANCE_HISTORY_DAYS as usize);
+        assert_eq!(history[0], 70);
+        assert_eq!(history[1], 120);
+        assert_eq!(history[2], 80);
+        assert_eq!(history[28], 80);
+        assert_eq!(history[29], 100);
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn test_report_credits_once() {
+        use crate::schema::{merchants, transactions};
+        let conn = test_connection();
+        conn.test_transaction::<_, Error, _>(|| {
+            let now = Utc::now().naive_utc();
+            insert_merchant(&conn, "confirm-once")?;
+            let mut payment = create_tx();
+            payment.merchant_id = s!("confirm-once");
+            payment.status = TransactionStatus::InChain;
+            diesel::insert_into(transactions::table)
+                .values(&payment)
+                .execute(&conn)?;
+
+            // Both runs of overlapping cron ticks see the payment in chain
+            let in_chain = Payment::<InChain>::load(payment.clone())?;
+            let first = confirm_transaction(&conn, &in_chain.confirm(), now)?;
+            let second = confirm_transaction(&conn, &in_chain.confirm(), now)?;
+            assert_eq!(first.status, TransactionStatus::Confirmed);
+            assert_eq!(second.status, TransactionStatus::Confirmed);
+
+            // The report job runs again after a crash or an expired lock
+            let report = MarkAsReported {
+                transaction_id: payment.id,
+            };
+            assert!(mark_as_reported(&conn, &report, now)?);
+            assert!(!mark_as_reported(&conn, &report, now)?);
+            let balance: i64 = merchants::table
+                .find("confirm-once")
+                .select(merchants::balance)
+                .get_result(&conn)?;
+            assert_eq!(balance, payment.grin_amount);
+
+            // A payment loaded in chain which isn't anymore
+            payment.id = Uuid::new_v4();
+            let in_chain = Payment::<InChain>::load(payment.clone())?;
+            payment.status = TransactionStatus::Pending;
+            diesel::insert_into(transactions::table)
+                .values(&payment)
+                .execute(&conn)?;
+            assert!(confirm_transaction(&conn, &in_chain.confirm(), now).is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn test_report_attempt() {
+        use crate::schema::{merchants, transactions};
+        let conn = test_connection();
+        conn.test_transaction::<_, Error, _>(|| {
+            insert_merchant(&conn, "report-attempt")?;
+            let mut payment = create_tx();
+            payment.merchant_id = s!("report-attempt");
+            payment.status = TransactionStatus::Confirmed;
+            diesel::insert_into(transactions::table)
+                .values(&payment)
+                .execute(&conn)?;
+
+            // The merchant's callback failed twice
+            for attempt in 1..3u32 {
+                let next_attempt = Some(NaiveDate::from_ymd(2019, 7, 1).and_hms(12, attempt, 0));
+                report_attempt(
+                    &conn,
+                    &ReportAttempt {
+                        transaction_id: payment.id,
+                        next_attempt,
+                    },
+                )?;
+                let tx: Transaction = transactions::table.find(payment.id).get_result(&conn)?;
+                assert!(!tx.reported);
+                assert_eq!(tx.report_attempts, attempt as i32);
+                assert_eq!(tx.next_report_attempt, next_attempt);
+            }
+            let balance: i64 = merchants::table
+                .find("report-attempt")
+                .select(merchants::balance)
+                .get_result(&conn)?;
+            assert_eq!(balance, 0);
+            Ok(())
+        });
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn test_manual_transition() {
+        use crate::schema::transactions;
+        let conn = test_connection();
+        conn.test_transaction::<_, Error, _>(|| {
+            let now = Utc::now().naive_utc();
+            insert_merchant(&conn, "manual")?;
+            let mut payment = create_tx();
+            payment.merchant_id = s!("manual");
+            payment.status = TransactionStatus::Rejected;
+            payment.reported = true;
+            diesel::insert_into(transactions::table)
+                .values(&payment)
+                .execute(&conn)?;
+            let transition = |status: TransactionStatus| {
+                manual_transition(
+                    &conn,
+                    &ManualTransition {
+                        transaction_id: payment.id,
+                        admin_id: s!("manual"),
+                        status,
+                        justification: s!("paid to the wallet"),
+                    },
+                    now,
+                )
+            };
+            assert!(transition(TransactionStatus::Confirmed).is_err());
+            let tx = transition(TransactionStatus::Refund)?;
+            assert_eq!(tx.status, TransactionStatus::Refund);
+            assert_eq!(tx.refund_reason, Some(RefundReason::Manual.to_string()));
+            assert!(!tx.reported);
+            assert!(transition(TransactionStatus::Refund).is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn test_mark_as_confirmed_by_wallet() {
+        use crate::schema::transactions;
+        let conn = test_connection();
+        conn.test_transaction::<_, Error, _>(|| {
+            let now = Utc::now().naive_utc();
+            insert_merchant(&conn, "by-wallet")?;
+            let mut payment = create_tx();
+            payment.merchant_id = s!("by-wallet");
+            payment.status = TransactionStatus::Pending;
+            diesel::insert_into(transactions::table)
+                .values(&payment)
+                .execute(&conn)?;
+
+            // 3 confirmations, the wallet is 3 blocks above the payment
+            let pending = Payment::<Pending>::load(payment.clone())?;
+            let tx = mark_as_confirmed_by_wallet(
+                &conn,
+                &MarkAsConfirmedByWallet {
+                    transition: pending.confirm_by_wallet(),
+                    height: 100,
+                    wallet_height: 103,
+                },
+                now,
+            )?;
+            assert_eq!(tx.status, TransactionStatus::InChain);
+            assert_eq!(tx.height, Some(100));
+            assert!(tx.confirmed_by_wallet);
+
+            // The height stored for the payment is kept, one more block
+            // confirms it
+            let in_chain = Payment::<InChain>::load(tx)?;
+            let tx = mark_as_confirmed_by_wallet(
+                &conn,
+                &MarkAsConfirmedByWallet {
+                    transition: in_chain.confirm_by_wallet(),
+                    height: 102,
+                    wallet_height: 104,
+                },
+                now,
+            )?;
+            assert_eq!(tx.status, TransactionStatus::Confirmed);
+            assert_eq!(tx.height, Some(100));
+
+            // A payment which moved on is left alone
+            assert!(mark_as_confirmed_by_wallet(
+                &conn,
+                &MarkAsConfirmedByWallet {
+                    transition: in_chain.confirm_by_wallet(),
+                    height: 100,
+                    wallet_height: 110,
+                },
+                now,
+            )
+            .is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn test_claim_payment() {
+        use crate::schema::transactions;
+        let conn = test_connection();
+        conn.test_transaction::<_, Error, _>(|| {
+            let now = Utc::now().naive_utc();
+            insert_merchant(&conn, "claim")?;
+            let mut payment = create_tx();
+            payment.merchant_id = s!("claim");
+            diesel::insert_into(transactions::table)
+                .values(&payment)
+                .execute(&conn)?;
+            let claim = ClaimPayment {
+                transaction_id: payment.id,
+                slate_id: Uuid::new_v4().hyphenated().to_string(),
+            };
+            let is_processing = |res: Result<(), Error>| match res {
+                Err(Error::PaymentProcessing) => true,
+                _ => false,
+            };
+
+            // A second submission waits for the first one
+            claim_payment(&conn, &claim, now)?;
+            assert!(is_processing(claim_payment(&conn, &claim, now)));
+            let later = now + Duration::seconds(PAYMENT_PROCESSING_SECONDS + 1);
+            claim_payment(&conn, &claim, later)?;
+
+            // The wallet refused the slate, the next one is let through
+            release_payment(
+                &conn,
+                &ReleasePayment {
+                    transaction_id: payment.id,
+                },
+            )?;
+            claim_payment(&conn, &claim, now)?;
+
+            // The slate is being received for this payment
+            let mut claimed = create_tx();
+            claimed.merchant_id = s!("claim");
+            diesel::insert_into(transactions::table)
+                .values(&claimed)
+                .execute(&conn)?;
+            let second = ClaimPayment {
+                transaction_id: claimed.id,
+                slate_id: claim.slate_id.clone(),
+            };
+            match claim_payment(&conn, &second, now) {
+                Err(Error::DuplicateSlate(_)) => (),
+                res => panic!("expected a duplicate slate, got {:?}", res),
+            }
+
+            // The slate was received for another payment
+            let mut other = create_tx();
+            other.merchant_id = s!("claim");
+            other.status = TransactionStatus::Pending;
+            other.wallet_tx_slate_id = Some(claim.slate_id.clone());
+            // Only the received slate is left to find
+            release_payment(
+                &conn,
+                &ReleasePayment {
+                    transaction_id: payment.id,
+                },
+            )?;
+            diesel::insert_into(transactions::table)
+                .values(&other)
+                .execute(&conn)?;
+            match claim_payment(&conn, &claim, later) {
+                Err(Error::DuplicateSlate(_)) => (),
+                res => panic!("expected a duplicate slate, got {:?}", res),
+            }
+
+            // Paid payments aren't claimed anymore
+            let paid = ClaimPayment {
+                transaction_id: other.id,
+                slate_id: Uuid::new_v4().hyphenated().to_string(),
+            };
+            assert!(is_processing(claim_payment(&conn, &paid, now)));
+            Ok(())
+        });
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn test_use_return_nonce() {
+        let conn = test_connection();
+        conn.test_transaction::<_, Error, _>(|| {
+            // Microseconds in the DB, a whole second compares exactly
+            let now = NaiveDate::from_ymd(2019, 7, 1).and_hms(12, 0, 0);
+            insert_merchant(&conn, "return-shop")?;
+            insert_merchant(&conn, "return-other")?;
+            let used = |merchant_id: &str| {
+                use_return_nonce(
+                    &conn,
+                    &UseReturnNonce {
+                        merchant_id: merchant_id.to_owned(),
+                        nonce: s!("00ff"),
+                    },
+                    now,
+                )
+            };
+            used("return-shop")?;
+            // A replayed return link
+            assert!(used("return-shop").is_err());
+            // Nonces are random per payload, but only checked per merchant
+            used("return-other")?;
+            // Kept while a payload using them could still verify
+            let expired = return_url::MAX_AGE_SECONDS + return_url::MAX_CLOCK_SKEW_SECONDS;
+            delete_used_return_nonces(&conn, now + Duration::seconds(expired))?;
+            assert!(used("return-shop").is_err());
+            delete_used_return_nonces(&conn, now + Duration::seconds(expired + 1))?;
+            used("return-shop")?;
+            Ok(())
+        });
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn test_report_credits_splits() {
+        use crate::schema::{merchants, transactions};
+        let conn = test_connection();
+        conn.test_transaction::<_, Error, _>(|| {
+            let now = Utc::now().naive_utc();
+            for (merchant, platform) in &[
+                ("split-platform", None),
+                ("split-seller", Some("split-platform")),
+                ("split-other", None),
+            ] {
+                insert_merchant(&conn, merchant)?;
+                diesel::update(merchants::table.find(*merchant))
+                    .set(merchants::platform_id.eq(*platform))
+                    .execute(&conn)?;
+            }
+            let mut payment = create_tx();
+            payment.merchant_id = s!("split-platform");
+            payment.status = TransactionStatus::InChain;
+            payment.grin_amount = 1_000_000_001;
+            diesel::insert_into(transactions::table)
+                .values(&payment)
+                .execute(&conn)?;
+
+            let recipient = |merchant_id: &str, percent: &str| SplitRecipient {
+                merchant_id: s!(merchant_id),
+                percent: percent.parse().unwrap(),
+            };
+            let other = splits::new_splits(payment.id, &[recipient("split-other", "10")])?;
+            assert!(create_splits(&conn, "split-platform", &other).is_err());
+            let shares = splits::new_splits(payment.id, &[recipient("split-seller", "12.5")])?;
+            create_splits(&conn, "split-platform", &shares)?;
+
+            let in_chain = Payment::<InChain>::load(payment.clone())?;
+            confirm_transaction(&conn, &in_chain.confirm(), now)?;
+            let report = MarkAsReported {
+                transaction_id: payment.id,
+            };
+            mark_as_reported(&conn, &report, now)?;
+            let balance = |merchant: &str| -> Result<i64, Error> {
+                Ok(merchants::table
+                    .find(merchant)
+                    .select(merchants::balance)
+                    .get_result(&conn)?)
+            };
+            assert_eq!(balance("split-seller")?, 125_000_000);
+            assert_eq!(balance("split-platform")?, 875_000_001);
+            assert_eq!(balance("split-other")?, 0);
+            // The balance history adds up to the balances
+            for merchant in &["split-seller", "split-platform", "split-other"] {
+                assert_eq!(credited(&conn, merchant)?, balance(merchant)?);
+            }
+
+            // Rejected payments were never paid, nobody is credited
+            let mut rejected = create_tx();
+            rejected.merchant_id = s!("split-platform");
+            rejected.status = TransactionStatus::Rejected;
+            diesel::insert_into(transactions::table)
+                .values(&rejected)
+                .execute(&conn)?;
+            let shares = splits::new_splits(rejected.id, &[recipient("split-seller", "50")])?;
+            create_splits(&conn, "split-platform", &shares)?;
+            let report = MarkAsReported {
+                transaction_id: rejected.id,
+            };
+            assert!(mark_as_reported(&conn, &report, now)?);
+            assert_eq!(balance("split-seller")?, 125_000_000);
+            assert_eq!(balance("split-platform")?, 875_000_001);
+            Ok(())
+        });
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn test_release_pending_credits() {
+        use crate::schema::{merchants, pending_credits, transactions};
+        let conn = test_connection();
+        conn.test_transaction::<_, Error, _>(|| {
+            let now = Utc::now().naive_utc();
+            let height = synced_height(&conn)?;
+            insert_merchant(&conn, "clearing")?;
+            diesel::update(merchants::table.find("clearing"))
+                .set(merchants::pending_balance.eq(15))
+                .execute(&conn)?;
+            let mut disputed = create_tx();
+            disputed.merchant_id = s!("clearing");
+            let mut held = disputed.clone();
+            held.id = Uuid::new_v4();
+            diesel::insert_into(transactions::table)
+                .values(&vec![disputed.clone(), held.clone()])
+                .execute(&conn)?;
+            for (payment, amount, hours, blocks, is_held) in &[
+                (&disputed, 1, -1, 0, false),
+                (&disputed, 2, 1, 0, false),
+                (&disputed, 4, -1, height + 1, false),
+                (&held, 8, -1, 0, true),
+            ] {
+                diesel::insert_into(pending_credits::table)
+                    .values(&PendingCredit {
+                        id: Uuid::new_v4(),
+                        transaction_id: payment.id,
+                        merchant_id: s!("clearing"),
+                        grin_amount: *amount,
+                        created_at: now,
+                        available_at: now + Duration::hours(*hours),
+                        available_height: *blocks,
+                        held: *is_held,
+                    })
+                    .execute(&conn)?;
+            }
+
+            let balances = || -> Result<(i64, i64), Error> {
+                Ok(merchants::table
+                    .find("clearing")
+                    .select((merchants::balance, merchants::pending_balance))
+                    .get_result(&conn)?)
+            };
+            assert_eq!(release_pending_credits(&conn, now)?, 1);
+            assert_eq!(balances()?, (1, 14));
+            assert_eq!(release_pending_credits(&conn, now)?, 0);
+            // Neither the credit above the synced height nor the held one
+            assert_eq!(release_pending_credits(&conn, now + Duration::hours(2))?, 1);
+            assert_eq!(balances()?, (3, 12));
+
+            let change = |payment: &Transaction, action: CreditAction, justification: &str| {
+                change_pending_credits(
+                    &conn,
+                    &ChangePendingCredits {
+                        transaction_id: payment.id,
+                        admin_id: s!("clearing"),
+                        action,
+                        justification: s!(justification),
+                    },
+                    now,
+                )
+            };
+            assert!(change(&held, CreditAction::Release, " ").is_err());
+            assert_eq!(change(&held, CreditAction::Release, "resolved")?.len(), 1);
+            assert!(change(&held, CreditAction::Release, "resolved").is_err());
+            assert_eq!(release_pending_credits(&conn, now)?, 1);
+            assert_eq!(balances()?, (11, 4));
+
+            assert_eq!(
+                change(&disputed, CreditAction::Hold, "chargeback")?.len(),
+                1
+            );
+            assert_eq!(
+                change(&disputed, CreditAction::Void, "chargeback")?.len(),
+                1
+            );
+            assert_eq!(balances()?, (11, 0));
+            assert!(change(&disputed, CreditAction::Void, "chargeback").is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    #[ignore = "needs TEST_DATABASE_URL"]
+    fn test_report_credits_referrer() {
+        use crate::schema::{merchants, plans, transactions};
+        let conn = test_connection();
+        conn.test_transaction::<_, Error, _>(|| {
+            let now = Utc::now().naive_utc();
+            diesel::update(plans::table.find("free"))
+                .set(plans::fee_bps.eq(100))
+                .execute(&conn)?;
+            for (merchant, referrer) in &[("referrer", None), ("referred", Some("referrer"))] {
+                insert_merchant(&conn, merchant)?;
+                diesel::update(merchants::table.find(*merchant))
+                    .set((
+                        merchants::plan.eq("free"),
+                        merchants::referrer_id.eq(*referrer),
+                        merchants::referral_share_bps.eq(referrer.map(|_| 2_500)),
+                    ))
+                    .execute(&conn)?;
+            }
+            let mut confirmed = create_tx();
+            confirmed.merchant_id = s!("referred");
+            confirmed.status = TransactionStatus::Confirmed;
+            confirmed.grin_amount = 10_000_000_001;
+            let mut rejected = confirmed.clone();
+            rejected.id = Uuid::new_v4();
+            rejected.status = TransactionStatus::Rejected;
+            diesel::insert_into(transactions::table)
+                .values(&vec![confirmed.clone(), rejected.clone()])
+                .execute(&conn)?;
+
+            for payment in &[&confirmed, &rejected, &confirmed] {
+                let report = MarkAsReported {
+                    transaction_id: payment.id,
+                };
+                mark_as_reported(&conn, &report, now)?;
+            }
+            let balance = |merchant: &str| -> Result<i64, Error> {
+                Ok(merchants::table
+                    .find(merchant)
+                    .select(merchants::balance)
+                    .get_result(&conn)?)
+            };
+            assert_eq!(balance("referred")?, 9_900_000_001);
+            assert_eq!(balance("referrer")?, 25_000_000);
+            assert_eq!(credited(&conn, "referred")?, balance("referred")?);
+            assert_eq!(credited(&conn, "referrer")?, balance("referrer")?);
+            let fees = |id: Uuid| -> Result<(Option<i64>, Option<i64>), Error> {
+                Ok(transactions::table
+                    .find(id)
+                    .select((transactions::knockturn_fee, transactions::referral_fee))
+                    .get_result(&conn)?)
+            };
+            assert_eq!(fees(confirmed.id)?, (Some(100_000_000), Some(25_000_000)));
+            assert_eq!(fees(rejected.id)?, (None, None));
+
+            let months = referral_months(&conn, "referrer", 1)?;
+            assert_eq!(months.len(), 1);
+            assert_eq!(months[0].earned, balance("referrer")?);
+            Ok(())
+        });
     }
 }
@@ -0,0 +1,660 @@
+//! Demo mode, `knockturn --demo`.
+//!
+//! The gateway runs against a fake wallet and node served in process on
+//! `DEMO_ADDRESS`, so prospective merchants can click through checkout,
+//! callbacks and the dashboard without grin infrastructure. They speak the
+//! v1 APIs the gateway calls, the gateway's code paths are the real ones.
+//! The fake node mines a block every `DEMO_BLOCK_SECONDS` on top of the
+//! height the gateway synced, with the outputs the fake wallet received or
+//! posted since the last one. A fake buyer pays every new payment
+//! `DEMO_PAY_AFTER_SECONDS` after it was created by posting a slate to its
+//! checkout URL on `DOMAIN`, like a wallet would. Keys, commitments and
+//! proofs are random bytes of the right sizes, nothing is signed or
+//! verified. The fake chain lives in memory and is gone on restart.
+
+use crate::checkout::CheckoutToken;
+use crate::db::{DbExecutor, GetCurrentHeight, GetPaymentsByStatus};
+use crate::errors::Error;
+use crate::models::{Transaction, TransactionStatus};
+use crate::ser;
+use crate::wallet::{OutputData, OutputStatus, TxListResp, TxLogEntry, TxLogEntryType, WalletInfo};
+use actix::prelude::*;
+use actix_web::client::{self, ClientResponse, SendRequestError};
+use actix_web::{server, App, HttpMessage, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use futures::future::{ok, Either, Future};
+use log::{info, warn};
+use openssl::sha::sha256;
+use rand::{thread_rng, Rng};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+pub const DEFAULT_DEMO_ADDRESS: &str = "127.0.0.1:3415";
+pub const DEFAULT_DEMO_BLOCK_SECONDS: u64 = 10;
+pub const DEFAULT_DEMO_PAY_AFTER_SECONDS: i64 = 15;
+/// How often the fake buyer looks for new payments
+const PAY_CHECK_SECONDS: u64 = 5;
+/// Fee of the fake slates, 0.008 grin
+const DEMO_FEE: u64 = 8_000_000;
+const KEY_SIZE: usize = 33;
+const SIGNATURE_SIZE: usize = 64;
+const OFFSET_SIZE: usize = 32;
+const PROOF_SIZE: usize = 675;
+const BODY_LIMIT: usize = 10 * 1024 * 1024;
+const PARENT_KEY_ID: &str = "0200000000000000000000000000000000";
+
+#[derive(Debug, Clone)]
+pub struct DemoConfig {
+    pub address: String,
+    pub block_seconds: u64,
+    pub pay_after_seconds: i64,
+}
+
+impl DemoConfig {
+    /// Reads DEMO_ADDRESS, DEMO_BLOCK_SECONDS and DEMO_PAY_AFTER_SECONDS,
+    /// defaults are used for unset ones
+    pub fn from_env() -> Self {
+        DemoConfig {
+            address: env::var("DEMO_ADDRESS").unwrap_or_else(|_| s!(DEFAULT_DEMO_ADDRESS)),
+            block_seconds: env::var("DEMO_BLOCK_SECONDS")
+                .map(|v| v.parse().expect("DEMO_BLOCK_SECONDS must be a number"))
+                .unwrap_or(DEFAULT_DEMO_BLOCK_SECONDS),
+            pay_after_seconds: env::var("DEMO_PAY_AFTER_SECONDS")
+                .map(|v| v.parse().expect("DEMO_PAY_AFTER_SECONDS must be a number"))
+                .unwrap_or(DEFAULT_DEMO_PAY_AFTER_SECONDS),
+        }
+    }
+
+    /// What the gateway's wallet and node clients are pointed at
+    pub fn url(&self) -> String {
+        format!("http://{}", self.address)
+    }
+}
+
+/// Starts the fake wallet and node and the actor mining blocks and paying
+/// new payments
+pub fn start(config: DemoConfig, db: Addr<DbExecutor>) -> Addr<Demo> {
+    let chain = Arc::new(Mutex::new(Chain::default()));
+    server::new({
+        let chain = chain.clone();
+        move || {
+            App::with_state(DemoState {
+                chain: chain.clone(),
+            })
+            .default_resource(|r| r.f(dispatch))
+        }
+    })
+    .workers(1)
+    .bind(&config.address)
+    .unwrap_or_else(|e| panic!("Can not bind demo API to '{}': {}", config.address, e))
+    .start();
+    info!(
+        "Demo mode: fake wallet and node on {}, a block every {} seconds",
+        config.address, config.block_seconds
+    );
+    Demo {
+        db,
+        chain,
+        config,
+        paid: HashSet::new(),
+    }
+    .start()
+}
+
+#[derive(Debug, Default, Clone)]
+struct DemoBlock {
+    timestamp: Option<DateTime<Utc>>,
+    /// Commitments in hex
+    outputs: Vec<String>,
+    /// Kernel excesses in hex
+    kernels: Vec<String>,
+}
+
+#[derive(Debug)]
+struct DemoOutput {
+    tx_id: u32,
+    commit: String,
+    data: OutputData,
+}
+
+/// The fake chain and the fake wallet's transactions and outputs
+#[derive(Debug, Default)]
+struct Chain {
+    height: u64,
+    /// Blocks mined by the demo, the ones below are empty
+    blocks: BTreeMap<u64, DemoBlock>,
+    /// What goes in the next block
+    pool: DemoBlock,
+    txs: Vec<TxLogEntry>,
+    outputs: Vec<DemoOutput>,
+}
+
+impl Chain {
+    fn mine(&mut self, now: DateTime<Utc>) {
+        self.height += 1;
+        let mut block = std::mem::replace(&mut self.pool, DemoBlock::default());
+        block.timestamp = Some(now);
+        let mut confirmed = HashSet::new();
+        for output in &mut self.outputs {
+            if block.outputs.contains(&output.commit) {
+                output.data.status = OutputStatus::Unspent;
+                output.data.height = self.height;
+                confirmed.insert(output.tx_id);
+            }
+        }
+        for tx in &mut self.txs {
+            if confirmed.contains(&tx.id) {
+                tx.confirmed = true;
+                tx.confirmation_ts = Some(now);
+            }
+        }
+        self.blocks.insert(self.height, block);
+    }
+
+    fn tip(&self) -> Value {
+        json!({
+            "height": self.height,
+            "last_block_pushed": block_hash(self.height),
+            "prev_block_to_last": block_hash(self.height.saturating_sub(1)),
+            "total_difficulty": self.height,
+        })
+    }
+
+    /// Blocks as `v1/chain/outputs/byheight` returns them
+    fn blocks(&self, start: u64, end: u64) -> Value {
+        let blocks: Vec<Value> = (start..=end.min(self.height))
+            .map(|height| {
+                let block = self.blocks.get(&height).cloned().unwrap_or_default();
+                let outputs: Vec<Value> = block
+                    .outputs
+                    .iter()
+                    .map(|commit| {
+                        json!({
+                            "output_type": "Transaction",
+                            "commit": commit,
+                            "spent": false,
+                            "proof": null,
+                            "proof_hash": "",
+                            "block_height": height,
+                            "merkle_proof": null,
+                            "mmr_index": 0,
+                        })
+                    })
+                    .collect();
+                json!({
+                    "header": {
+                        "height": height,
+                        "hash": block_hash(height),
+                        "previous": block_hash(height.saturating_sub(1)),
+                        "timestamp": block.timestamp,
+                    },
+                    "outputs": outputs,
+                })
+            })
+            .collect();
+        Value::Array(blocks)
+    }
+
+    fn kernel(&self, excess: &str) -> Option<Value> {
+        self.blocks
+            .iter()
+            .find(|(_, block)| block.kernels.iter().any(|kernel| kernel == excess))
+            .map(|(height, _)| {
+                json!({
+                    "tx_kernel": {
+                        "features": "Plain",
+                        "fee": DEMO_FEE,
+                        "lock_height": 0,
+                        "excess": excess,
+                        "excess_sig": "",
+                    },
+                    "height": height,
+                    "mmr_index": 0,
+                })
+            })
+    }
+
+    fn log_tx(
+        &mut self,
+        tx_type: TxLogEntryType,
+        slate: &Value,
+        amount_credited: u64,
+        amount_debited: u64,
+    ) -> u32 {
+        let id = self.txs.len() as u32 + 1;
+        self.txs.push(TxLogEntry {
+            parent_key_id: s!(PARENT_KEY_ID),
+            id,
+            tx_slate_id: slate["id"].as_str().map(str::to_owned),
+            tx_type,
+            creation_ts: Utc::now(),
+            confirmation_ts: None,
+            confirmed: false,
+            num_inputs: array_len(&slate["tx"]["body"]["inputs"]),
+            num_outputs: array_len(&slate["tx"]["body"]["outputs"]),
+            amount_credited,
+            amount_debited,
+            fee: slate["fee"].as_u64(),
+            messages: None,
+            stored_tx: None,
+        });
+        id
+    }
+
+    /// Adds the receiver's output and data, the buyer is fake too, so the
+    /// transaction goes in the next block as if they had posted it
+    fn receive(&mut self, mut slate: Value) -> Result<Value, Error> {
+        let amount = slate["amount"].as_u64().ok_or_else(unreadable)?;
+        let commit = random_bytes(KEY_SIZE);
+        slate["tx"]["body"]["outputs"]
+            .as_array_mut()
+            .ok_or_else(unreadable)?
+            .insert(0, output(&commit));
+        slate["participant_data"]
+            .as_array_mut()
+            .ok_or_else(unreadable)?
+            .push(participant(1, None, Some(random_bytes(SIGNATURE_SIZE))));
+        let tx_id = self.log_tx(TxLogEntryType::TxReceived, &slate, amount, 0);
+        let commit = ser::to_hex(commit);
+        self.outputs.push(DemoOutput {
+            tx_id,
+            commit: commit.clone(),
+            data: OutputData {
+                status: OutputStatus::Unconfirmed,
+                height: self.height,
+            },
+        });
+        self.pool.outputs.push(commit);
+        self.pool.kernels.extend(kernel_excess(&slate));
+        Ok(slate)
+    }
+
+    /// A payout, the change output is the wallet's
+    fn send(&mut self, amount: u64, message: Option<String>) -> Value {
+        let change = random_bytes(KEY_SIZE);
+        let mut slate = sender_slate(amount, message, self.height);
+        slate["tx"]["body"]["outputs"] = json!([output(&change)]);
+        let tx_id = self.log_tx(TxLogEntryType::TxSent, &slate, 0, amount + DEMO_FEE);
+        self.outputs.push(DemoOutput {
+            tx_id,
+            commit: ser::to_hex(change),
+            data: OutputData {
+                status: OutputStatus::Unconfirmed,
+                height: self.height,
+            },
+        });
+        slate
+    }
+
+    fn finalize(&mut self, mut slate: Value) -> Result<Value, Error> {
+        let sender = slate["participant_data"]
+            .as_array_mut()
+            .and_then(|participants| participants.get_mut(0))
+            .ok_or_else(unreadable)?;
+        sender["part_sig"] = json!(random_bytes(SIGNATURE_SIZE));
+        Ok(slate)
+    }
+
+    fn post(&mut self, slate: &Value) -> Result<(), Error> {
+        let outputs = slate["tx"]["body"]["outputs"]
+            .as_array()
+            .ok_or_else(unreadable)?;
+        for output in outputs {
+            let commit: Vec<u8> =
+                serde_json::from_value(output["commit"].clone()).map_err(|_| unreadable())?;
+            self.pool.outputs.push(ser::to_hex(commit));
+        }
+        self.pool.kernels.extend(kernel_excess(slate));
+        Ok(())
+    }
+
+    fn cancel(&mut self, tx_id: &str) {
+        let mut cancelled = HashSet::new();
+        for tx in self.txs.iter_mut().filter(|tx| is_tx(tx, tx_id)) {
+            tx.tx_type = match tx.tx_type {
+                TxLogEntryType::TxSent => TxLogEntryType::TxSentCancelled,
+                _ => TxLogEntryType::TxReceivedCancelled,
+            };
+            cancelled.insert(tx.id);
+        }
+        let commits: Vec<String> = self
+            .outputs
+            .iter()
+            .filter(|output| cancelled.contains(&output.tx_id))
+            .map(|output| output.commit.clone())
+            .collect();
+        self.pool.outputs.retain(|commit| !commits.contains(commit));
+        self.outputs
+            .retain(|output| !cancelled.contains(&output.tx_id));
+    }
+
+    fn txs(&self, tx_id: Option<&str>) -> TxListResp {
+        TxListResp {
+            updated: true,
+            txs: self
+                .txs
+                .iter()
+                .filter(|tx| tx_id.map_or(true, |tx_id| is_tx(tx, tx_id)))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    fn outputs(&self, tx_id: Option<&str>) -> Vec<(OutputData, Value)> {
+        self.outputs
+            .iter()
+            .filter(|output| tx_id.map_or(true, |tx_id| output.tx_id.to_string() == tx_id))
+            .map(|output| (output.data.clone(), Value::Null))
+            .collect()
+    }
+}
+
+/// The gateway looks transactions up by local id or slate id
+fn is_tx(tx: &TxLogEntry, tx_id: &str) -> bool {
+    tx.id.to_string() == tx_id || tx.tx_slate_id.as_ref().map(String::as_str) == Some(tx_id)
+}
+
+fn unreadable() -> Error {
+    Error::WalletAPIError(s!("the demo wallet cannot read the slate"))
+}
+
+fn array_len(value: &Value) -> usize {
+    value.as_array().map_or(0, Vec::len)
+}
+
+fn kernel_excess(slate: &Value) -> Option<String> {
+    serde_json::from_value::<Vec<u8>>(slate["tx"]["body"]["kernels"][0]["excess"].clone())
+        .ok()
+        .map(ser::to_hex)
+}
+
+/// Same for every demo run, so the synced chain always continues
+fn block_hash(height: u64) -> String {
+    ser::to_hex(sha256(format!("demo|{}", height).as_bytes()).to_vec())
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut rng = thread_rng();
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+fn output(commit: &[u8]) -> Value {
+    json!({
+        "features": "Plain",
+        "commit": commit,
+        "proof": random_bytes(PROOF_SIZE),
+    })
+}
+
+fn participant(id: u64, message: Option<String>, part_sig: Option<Vec<u8>>) -> Value {
+    json!({
+        "id": id,
+        "public_blind_excess": random_bytes(KEY_SIZE),
+        "public_nonce": random_bytes(KEY_SIZE),
+        "part_sig": part_sig,
+        "message": message,
+        "message_sig": null,
+    })
+}
+
+/// V1 slate of a sender without change, shaped to pass `Slate::validate`
+fn sender_slate(amount: u64, message: Option<String>, height: u64) -> Value {
+    json!({
+        "version": 1,
+        "num_participants": 2,
+        "id": Uuid::new_v4(),
+        "tx": {
+            "offset": random_bytes(OFFSET_SIZE),
+            "body": {
+                "inputs": [{"features": "Plain", "commit": random_bytes(KEY_SIZE)}],
+                "outputs": [],
+                "kernels": [{
+                    "features": "Plain",
+                    "fee": DEMO_FEE,
+                    "lock_height": 0,
+                    "excess": random_bytes(KEY_SIZE),
+                    "excess_sig": random_bytes(SIGNATURE_SIZE),
+                }],
+            },
+        },
+        "amount": amount,
+        "fee": DEMO_FEE,
+        "height": height,
+        "lock_height": 0,
+        "participant_data": [participant(0, message, None)],
+    })
+}
+
+struct DemoState {
+    chain: Arc<Mutex<Chain>>,
+}
+
+type DemoResponse = Box<dyn Future<Item = HttpResponse, Error = Error>>;
+
+/// Routes by hand, the gateway joins some wallet paths with a double slash
+fn dispatch(req: &HttpRequest<DemoState>) -> DemoResponse {
+    let chain = req.state().chain.clone();
+    let path = req.path().trim_start_matches('/').to_owned();
+    let query = req.query();
+    let param = |name: &str| query.get(name).cloned();
+    let height = |name: &str| param(name).and_then(|v| v.parse::<u64>().ok());
+    let resp = match (req.method().as_str(), path.as_str()) {
+        ("GET", "v1/chain") => HttpResponse::Ok().json(chain.lock().unwrap().tip()),
+        ("GET", "v1/chain/outputs/byheight") => {
+            let start = height("start_height").unwrap_or(0);
+            let end = height("end_height").unwrap_or(start);
+            HttpResponse::Ok().json(chain.lock().unwrap().blocks(start, end))
+        }
+        ("GET", path) if path.starts_with("v1/chain/kernels/") => {
+            let excess = &path["v1/chain/kernels/".len()..];
+            match chain.lock().unwrap().kernel(excess) {
+                Some(kernel) => HttpResponse::Ok().json(kernel),
+                None => HttpResponse::NotFound().finish(),
+            }
+        }
+        ("POST", "v2/foreign") => HttpResponse::Ok().json(json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": {"Ok": {"foreign_api_version": 2, "supported_slate_versions": ["V1", "V0"]}},
+        })),
+        ("GET", "v1/wallet/owner/retrieve_txs") => HttpResponse::Ok().json(
+            chain
+                .lock()
+                .unwrap()
+                .txs(param("tx_id").as_ref().map(String::as_str)),
+        ),
+        ("GET", "v1/wallet/owner/retrieve_outputs") => HttpResponse::Ok().json((
+            true,
+            chain
+                .lock()
+                .unwrap()
+                .outputs(param("tx_id").as_ref().map(String::as_str)),
+        )),
+        ("GET", "v1/wallet/owner/retrieve_summary_info") => {
+            let info = WalletInfo {
+                last_confirmed_height: chain.lock().unwrap().height,
+            };
+            HttpResponse::Ok().json((true, info))
+        }
+        ("POST", "v1/wallet/owner/cancel_tx") => {
+            if let Some(tx_id) = param("tx_id") {
+                chain.lock().unwrap().cancel(&tx_id);
+            }
+            HttpResponse::Ok().finish()
+        }
+        ("POST", "v1/wallet/foreign/receive_tx") => {
+            return with_body(req, move |slate| chain.lock().unwrap().receive(slate));
+        }
+        ("POST", "v1/wallet/owner/issue_send_tx") => {
+            return with_body(req, move |send| {
+                let amount = send["amount"].as_u64().ok_or_else(unreadable)?;
+                let message = send["message"].as_str().map(str::to_owned);
+                Ok(chain.lock().unwrap().send(amount, message))
+            });
+        }
+        ("POST", "v1/wallet/owner/finalize_tx") => {
+            return with_body(req, move |slate| chain.lock().unwrap().finalize(slate));
+        }
+        ("POST", "v1/wallet/owner/post_tx") => {
+            return with_body(req, move |slate| {
+                chain.lock().unwrap().post(&slate)?;
+                Ok(Value::Null)
+            });
+        }
+        _ => HttpResponse::NotFound().finish(),
+    };
+    Box::new(ok(resp))
+}
+
+fn with_body<F>(req: &HttpRequest<DemoState>, f: F) -> DemoResponse
+where
+    F: FnOnce(Value) -> Result<Value, Error> + 'static,
+{
+    Box::new(
+        req.json::<Value>()
+            .limit(BODY_LIMIT)
+            .map_err(|e| Error::WalletAPIError(s!(e)))
+            .and_then(move |body| Ok(HttpResponse::Ok().json(f(body)?))),
+    )
+}
+
+/// Mines blocks and pays new payments
+pub struct Demo {
+    db: Addr<DbExecutor>,
+    chain: Arc<Mutex<Chain>>,
+    config: DemoConfig,
+    /// Payments the fake buyer sent a slate for, each gets one try
+    paid: HashSet<Uuid>,
+}
+
+impl Actor for Demo {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        // The fake chain goes on from the height the gateway synced
+        let chain = self.chain.clone();
+        let res = self.db.send(GetCurrentHeight).then(move |res| {
+            match res {
+                Ok(Ok(height)) => {
+                    let mut chain = chain.lock().unwrap();
+                    chain.height = chain.height.max(height as u64);
+                    info!("Demo chain starts at height {}", chain.height);
+                }
+                Ok(Err(e)) => warn!("Cannot get the synced height for the demo chain: {}", e),
+                Err(e) => warn!("Cannot get the synced height for the demo chain: {}", e),
+            }
+            Ok::<_, ()>(())
+        });
+        ctx.spawn(res.into_actor(self));
+        ctx.run_interval(Duration::from_secs(self.config.block_seconds), |demo, _| {
+            demo.chain.lock().unwrap().mine(Utc::now())
+        });
+        ctx.run_interval(Duration::from_secs(PAY_CHECK_SECONDS), pay_new_payments);
+    }
+}
+
+fn pay_new_payments(demo: &mut Demo, ctx: &mut Context<Demo>) {
+    let pay_after = chrono::Duration::seconds(demo.config.pay_after_seconds);
+    let res = demo
+        .db
+        .send(GetPaymentsByStatus(TransactionStatus::New))
+        .from_err::<Error>()
+        .and_then(|db_response| db_response)
+        .into_actor(demo)
+        .map(move |payments, demo, ctx| {
+            let now = Utc::now().naive_utc();
+            let height = demo.chain.lock().unwrap().height;
+            for payment in payments {
+                if payment.created_at + pay_after > now || !demo.paid.insert(payment.id) {
+                    continue;
+                }
+                ctx.spawn(pay(&payment, height).into_actor(demo));
+            }
+        })
+        .map_err(|e, _, _| warn!("Demo buyer cannot get new payments: {}", e));
+    ctx.spawn(res);
+}
+
+/// Posts the fake buyer's slate to the payment URL, like
+/// `grin wallet send -d <url>`
+fn pay(payment: &Transaction, height: u64) -> impl Future<Item = (), Error = ()> {
+    let url = match CheckoutToken::new(payment.id, Utc::now().timestamp()).url() {
+        Ok(url) => url,
+        Err(e) => {
+            warn!(
+                "Demo buyer cannot get the checkout URL of {}: {}",
+                payment.id, e
+            );
+            return Either::A(ok(()));
+        }
+    };
+    let slate = sender_slate(
+        payment.grin_amount as u64,
+        Some(payment.message.clone()),
+        height,
+    );
+    let transaction_id = payment.id;
+    Either::B(client::post(&url).json(slate).unwrap().send().then(
+        move |res: Result<ClientResponse, SendRequestError>| {
+            match res {
+                Ok(ref resp) if resp.status().is_success() => {
+                    info!("Demo buyer paid {}", transaction_id)
+                }
+                Ok(resp) => warn!(
+                    "Demo buyer's slate for {} was refused with {}",
+                    transaction_id,
+                    resp.status()
+                ),
+                Err(e) => warn!("Demo buyer cannot pay {}: {}", transaction_id, e),
+            }
+            Ok::<_, ()>(())
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::VersionedSlate;
+
+    #[test]
+    fn test_chain() {
+        let mut chain = Chain::default();
+        chain.height = 100;
+        let slate = sender_slate(1_000_000_000, Some(s!("Order 1")), 100);
+        let versioned = VersionedSlate::parse(slate.clone()).unwrap();
+        versioned.to_valid_slate().unwrap();
+
+        let received = chain.receive(slate).unwrap();
+        let received = VersionedSlate::parse(received).unwrap().to_slate().unwrap();
+        assert_eq!(received.participant_data.len(), 2);
+        let commit = ser::to_hex(received.tx.output_commitments()[0].clone());
+        let tx_id = received.id.hyphenated().to_string();
+        assert_eq!(chain.txs(Some(&tx_id)).txs.len(), 1);
+        assert!(!chain.txs(Some(&tx_id)).txs[0].confirmed);
+
+        chain.mine(Utc::now());
+        assert_eq!(chain.height, 101);
+        let blocks = chain.blocks(100, 105);
+        assert_eq!(blocks.as_array().unwrap().len(), 2);
+        assert_eq!(blocks[0]["outputs"].as_array().unwrap().len(), 0);
+        assert_eq!(blocks[1]["outputs"][0]["commit"], commit);
+        assert_eq!(blocks[1]["header"]["previous"], blocks[0]["header"]["hash"]);
+        assert!(chain.txs(Some(&tx_id)).txs[0].confirmed);
+        assert_eq!(chain.outputs(Some("1"))[0].0.status, OutputStatus::Unspent);
+        let excess = kernel_excess(&serde_json::to_value(&received).unwrap()).unwrap();
+        assert_eq!(chain.kernel(&excess).unwrap()["height"], 101);
+
+        let payout = chain.send(5, None);
+        chain.cancel(payout["id"].as_str().unwrap());
+        assert_eq!(
+            chain.txs(Some("2")).txs[0].tx_type,
+            TxLogEntryType::TxSentCancelled
+        );
+        assert!(chain.outputs(Some("2")).is_empty());
+    }
+}
@@ -0,0 +1,206 @@
+//! Networks whose buyers can't submit payments.
+//!
+//! Scanners hammer the wallet endpoints of payments with garbage slates.
+//! `PAYMENT_DENY_LIST` takes a comma separated list of networks in CIDR
+//! notation, a plain IP is a network of one, admins add more on
+//! `/admin/deny_list`. Every instance reloads the admins' networks every
+//! `DENY_LIST_RELOAD_SECONDS`. `DenyPayers` answers POSTs from a denied
+//! network with 403 before the slate is read and counts them in
+//! `denied_requests_total` by network.
+
+use crate::app::AppState;
+use crate::db::{DbExecutor, GetDeniedNetworks};
+use crate::errors::Error;
+use crate::geoip;
+use crate::metrics;
+use actix::Addr;
+use actix_web::http::Method;
+use actix_web::middleware::{Middleware, Started};
+use actix_web::{HttpRequest, HttpResponse, Result};
+use futures::future::Future;
+use log::{debug, warn};
+use std::env;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::RwLock;
+
+pub const DENY_LIST_RELOAD_SECONDS: u64 = 30;
+
+lazy_static::lazy_static! {
+    /// From `PAYMENT_DENY_LIST`
+    pub static ref CONFIGURED: Vec<Network> = env::var("PAYMENT_DENY_LIST")
+        .map(|v| {
+            v.split(',')
+                .map(str::trim)
+                .filter(|network| !network.is_empty())
+                .map(|network| {
+                    network
+                        .parse()
+                        .unwrap_or_else(|e| panic!("Cannot parse PAYMENT_DENY_LIST: {}", e))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    /// Added by admins, as of the last reload
+    static ref DENIED: RwLock<Vec<Network>> = RwLock::new(Vec::new());
+}
+
+/// Network in CIDR notation, the address has no host bits set
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, unmap(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = mask_v4(self.prefix_len);
+                u32::from(ip) & mask == u32::from(net)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = mask_v6(self.prefix_len);
+                u128::from(ip) & mask == u128::from(net)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let invalid = || Error::InvalidEntity(format!("{} is not a network in CIDR notation", s));
+        let mut parts = s.trim().splitn(2, '/');
+        let addr: IpAddr = parts.next().unwrap_or("").parse().map_err(|_| invalid())?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match parts.next() {
+            Some(len) => len.parse::<u8>().map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        let addr = match addr {
+            IpAddr::V4(addr) => IpAddr::V4(Ipv4Addr::from(u32::from(addr) & mask_v4(prefix_len))),
+            IpAddr::V6(addr) => IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask_v6(prefix_len))),
+        };
+        Ok(Network { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+fn mask_v4(prefix_len: u8) -> u32 {
+    u32::max_value()
+        .checked_shl(32 - u32::from(prefix_len))
+        .unwrap_or(0)
+}
+
+fn mask_v6(prefix_len: u8) -> u128 {
+    u128::max_value()
+        .checked_shl(128 - u32::from(prefix_len))
+        .unwrap_or(0)
+}
+
+/// IPv4 clients of dual stack listeners show up as `::ffff:a.b.c.d`
+fn unmap(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, _, _] => IpAddr::V4(v6.to_ipv4().unwrap()),
+            _ => ip,
+        },
+        v4 => v4,
+    }
+}
+
+/// Network `ip` is denied by, configured ones first
+pub fn denied_by(ip: IpAddr) -> Option<Network> {
+    CONFIGURED
+        .iter()
+        .chain(DENIED.read().unwrap().iter())
+        .find(|network| network.contains(ip))
+        .cloned()
+}
+
+/// Replaces the admins' networks with the ones in the DB, entries which
+/// don't parse are skipped
+pub fn reload(db: Addr<DbExecutor>) -> impl Future<Item = (), Error = Error> {
+    db.send(GetDeniedNetworks)
+        .from_err()
+        .and_then(|db_response| {
+            let networks: Vec<Network> = db_response?
+                .into_iter()
+                .filter_map(|denied| match denied.network.parse() {
+                    Ok(network) => Some(network),
+                    Err(e) => {
+                        warn!("Skipping denied network: {}", e);
+                        None
+                    }
+                })
+                .collect();
+            *DENIED.write().unwrap() = networks;
+            Ok(())
+        })
+}
+
+/// Refuses POSTs of clients in denied networks, registered on the routes
+/// buyers' wallets submit payments to
+pub struct DenyPayers;
+
+impl Middleware<AppState> for DenyPayers {
+    fn start(&self, req: &HttpRequest<AppState>) -> Result<Started> {
+        if req.method() != Method::POST {
+            return Ok(Started::Done);
+        }
+        let network = match geoip::client_ip(req).and_then(denied_by) {
+            Some(network) => network,
+            None => return Ok(Started::Done),
+        };
+        debug!("Refused {} from denied network {}", req.path(), network);
+        metrics::inc(
+            "denied_requests_total",
+            &[("network", &network.to_string())],
+        );
+        Ok(Started::Response(HttpResponse::Forbidden().finish()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_network() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let network: Network = "10.1.2.3/16".parse().unwrap();
+        assert_eq!(network.to_string(), "10.1.0.0/16");
+        assert!(network.contains(ip("10.1.255.1")));
+        assert!(network.contains(ip("::ffff:10.1.0.7")));
+        assert!(!network.contains(ip("10.2.0.1")));
+        assert!(!network.contains(ip("2001:db8::1")));
+
+        let single: Network = "192.0.2.1".parse().unwrap();
+        assert_eq!(single.to_string(), "192.0.2.1/32");
+        assert!(single.contains(ip("192.0.2.1")));
+        assert!(!single.contains(ip("192.0.2.2")));
+
+        let v6: Network = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(ip("2001:db8:ffff::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+
+        let all: Network = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(ip("203.0.113.9")));
+
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("10.0.0/8".parse::<Network>().is_err());
+        assert!("example.com".parse::<Network>().is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use crate::blocking::BlockingError;
+use crate::models::ApiScope;
 use actix::MailboxError;
 use actix_web::{error::ResponseError, HttpResponse};
 use failure::Fail;
@@ -58,20 +58,103 @@ pub enum Error {
 
     #[fail(display = "Not enough funds")]
     NotEnoughFunds,
+
+    #[fail(display = "Exchange rate lock expired, payment should be requoted")]
+    RateLockExpired,
+
+    #[fail(display = "Payment cannot be requoted")]
+    CannotRequote,
+
+    #[fail(display = "Exchange rate of {} is outdated, try again later", _0)]
+    StaleRate(String),
+
+    #[fail(display = "Security key verification failed: {}", _0)]
+    SecurityKey(String),
+
+    #[fail(display = "OpenID Connect login failed: {}", _0)]
+    Oidc(String),
+
+    #[fail(display = "API token doesn't have {} scope", _0)]
+    InsufficientScope(ApiScope),
+
+    #[fail(display = "Admin rights required")]
+    AdminRequired,
+
+    #[fail(display = "Cannot send email: {}", _0)]
+    Mailer(String),
+
+    #[fail(display = "Unsupported slate version {}, send a V4 or older slate", _0)]
+    UnsupportedSlateVersion(String),
+
+    #[fail(display = "Checkout link expired, ask the merchant for a fresh link")]
+    CheckoutExpired,
+
+    #[fail(display = "Invalid slate: {}", _0)]
+    InvalidSlate(String),
+
+    #[fail(display = "Another slate of the payment is being processed")]
+    PaymentProcessing,
+
+    #[fail(display = "Unsupported API version {}", _0)]
+    UnsupportedApiVersion(String),
+
+    #[fail(display = "Too many payments created, retry in {} seconds", _0)]
+    RateLimited(u64),
+
+    #[fail(display = "Plan quota exceeded: {}", _0)]
+    QuotaExceeded(String),
+
+    #[fail(display = "Slate {} was already sent to another payment", _0)]
+    DuplicateSlate(String),
+
+    #[fail(display = "Refund address isn't verified: {}", _0)]
+    UnverifiedRefundAddress(String),
 }
 
-impl From<MailboxError> for Error {
-    fn from(error: MailboxError) -> Self {
-        Error::General(s!(error))
+impl Error {
+    /// Machine readable code, unlike messages it doesn't change
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Error::Db(_) | Error::Template(_) | Error::General(_) | Error::Internal(_) => {
+                "internal_error"
+            }
+            Error::EntityNotFound(_) => "not_found",
+            Error::InvalidEntity(_) => "invalid_request",
+            Error::AlreadyExists(_) => "already_exists",
+            Error::UnsupportedCurrency(_) => "unsupported_currency",
+            Error::WalletAPIError(_) => "wallet_error",
+            Error::NodeAPIError(_) => "node_error",
+            Error::WrongAmount(_, _) => "wrong_amount",
+            Error::WrongTransactionStatus(_) => "wrong_status",
+            Error::MerchantCallbackError { .. } => "callback_error",
+            Error::AuthRequired => "auth_required",
+            Error::NotAuthorized | Error::NotAuthorizedInUI => "not_authorized",
+            Error::MerchantNotFound => "merchant_not_found",
+            Error::NotEnoughFunds => "not_enough_funds",
+            Error::RateLockExpired => "rate_lock_expired",
+            Error::CannotRequote => "cannot_requote",
+            Error::StaleRate(_) => "stale_rate",
+            Error::SecurityKey(_) => "security_key_error",
+            Error::Oidc(_) => "login_error",
+            Error::InsufficientScope(_) => "insufficient_scope",
+            Error::AdminRequired => "admin_required",
+            Error::Mailer(_) => "mailer_error",
+            Error::UnsupportedSlateVersion(_) => "unsupported_slate_version",
+            Error::CheckoutExpired => "checkout_expired",
+            Error::InvalidSlate(_) => "invalid_slate",
+            Error::PaymentProcessing => "payment_processing",
+            Error::UnsupportedApiVersion(_) => "unsupported_api_version",
+            Error::RateLimited(_) => "rate_limited",
+            Error::QuotaExceeded(_) => "quota_exceeded",
+            Error::DuplicateSlate(_) => "duplicate_slate",
+            Error::UnverifiedRefundAddress(_) => "unverified_refund_address",
+        }
     }
 }
 
-impl From<BlockingError> for Error {
-    fn from(error: BlockingError) -> Self {
-        match error {
-            BlockingError::Canceled => Error::General(s!("Got blocking error")),
-            BlockingError::Error(e) => e,
-        }
+impl From<MailboxError> for Error {
+    fn from(error: MailboxError) -> Self {
+        Error::General(s!(error))
     }
 }
 
@@ -119,10 +202,30 @@ impl ResponseError for Error {
             Error::EntityNotFound(ref message) => HttpResponse::NotFound().json(message),
             Error::InvalidEntity(ref message)
             | Error::AlreadyExists(ref message)
-            | Error::UnsupportedCurrency(ref message) => HttpResponse::BadRequest().json(message),
+            | Error::UnsupportedCurrency(ref message)
+            | Error::SecurityKey(ref message) => HttpResponse::BadRequest().json(message),
+            Error::RateLockExpired
+            | Error::CannotRequote
+            | Error::UnsupportedSlateVersion(_)
+            | Error::UnsupportedApiVersion(_) => HttpResponse::BadRequest().json(s!(self)),
+            Error::StaleRate(_) => HttpResponse::ServiceUnavailable().json(s!(self)),
+            Error::CheckoutExpired => HttpResponse::Gone().json(s!(self)),
+            Error::InvalidSlate(_) => HttpResponse::UnprocessableEntity().json(s!(self)),
+            Error::PaymentProcessing | Error::DuplicateSlate(_) => {
+                HttpResponse::Conflict().json(s!(self))
+            }
+            Error::RateLimited(retry_after) => HttpResponse::TooManyRequests()
+                .header("Retry-After", retry_after.to_string())
+                .json(s!(self)),
             Error::AuthRequired => HttpResponse::Unauthorized().finish(),
             Error::NotAuthorized => HttpResponse::Forbidden().finish(),
-            Error::NotAuthorizedInUI => HttpResponse::Found().header("location", "/login").finish(),
+            Error::InsufficientScope(_)
+            | Error::AdminRequired
+            | Error::QuotaExceeded(_)
+            | Error::UnverifiedRefundAddress(_) => HttpResponse::Forbidden().json(s!(self)),
+            Error::NotAuthorizedInUI | Error::Oidc(_) => {
+                HttpResponse::Found().header("location", "/login").finish()
+            }
             _ => HttpResponse::InternalServerError().json("general error".to_owned()),
         }
     }
@@ -0,0 +1,107 @@
+//! Links to a block explorer.
+//!
+//! URL templates are set with `EXPLORER_COMMIT_URL`, e.g.
+//! `https://grinscan.net/output/{commit}`, and `EXPLORER_BLOCK_URL`, e.g.
+//! `https://grinscan.net/block/{height}`, `{hash}` works in the latter too.
+//! Links are left out when their template is not set.
+
+use crate::models::Transaction;
+use serde::Serialize;
+use std::env;
+
+lazy_static::lazy_static! {
+    pub static ref EXPLORER: Explorer = Explorer::from_env();
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Explorer {
+    commit_url: Option<String>,
+    block_url: Option<String>,
+}
+
+impl Explorer {
+    pub fn new(commit_url: Option<String>, block_url: Option<String>) -> Self {
+        Explorer {
+            commit_url,
+            block_url,
+        }
+    }
+
+    pub fn from_env() -> Self {
+        let var = |name| env::var(name).ok().filter(|v: &String| !v.is_empty());
+        Explorer::new(var("EXPLORER_COMMIT_URL"), var("EXPLORER_BLOCK_URL"))
+    }
+
+    pub fn commit_url(&self, commit: &str) -> Option<String> {
+        self.commit_url
+            .as_ref()
+            .map(|template| template.replace("{commit}", commit))
+    }
+
+    /// `None` when the template needs the hash and it's unknown
+    pub fn block_url(&self, height: i64, hash: Option<&str>) -> Option<String> {
+        let template = self.block_url.as_ref()?;
+        let url = template.replace("{height}", &height.to_string());
+        match hash {
+            Some(hash) => Some(url.replace("{hash}", hash)),
+            None if url.contains("{hash}") => None,
+            None => Some(url),
+        }
+    }
+}
+
+/// Explorer pages of a transaction's output and block
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExplorerLinks {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_url: Option<String>,
+}
+
+impl ExplorerLinks {
+    pub fn of(transaction: &Transaction, block_hash: Option<&str>) -> Self {
+        ExplorerLinks::with(&EXPLORER, transaction, block_hash)
+    }
+
+    fn with(explorer: &Explorer, transaction: &Transaction, block_hash: Option<&str>) -> Self {
+        ExplorerLinks {
+            commit_url: transaction
+                .commit
+                .as_ref()
+                .and_then(|commit| explorer.commit_url(commit)),
+            block_url: transaction
+                .height
+                .and_then(|height| explorer.block_url(height, block_hash)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_explorer_urls() {
+        let explorer = Explorer::new(
+            Some(s!("https://grinscan.net/output/{commit}")),
+            Some(s!("https://explorer.example/block/{height}/{hash}")),
+        );
+        assert_eq!(
+            explorer.commit_url("08abc"),
+            Some(s!("https://grinscan.net/output/08abc"))
+        );
+        assert_eq!(
+            explorer.block_url(84586, Some("0773")),
+            Some(s!("https://explorer.example/block/84586/0773"))
+        );
+        assert_eq!(explorer.block_url(84586, None), None);
+
+        let by_height = Explorer::new(None, Some(s!("https://grinscan.net/block/{height}")));
+        assert_eq!(by_height.commit_url("08abc"), None);
+        assert_eq!(
+            by_height.block_url(84586, None),
+            Some(s!("https://grinscan.net/block/84586"))
+        );
+    }
+}
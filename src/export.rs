@@ -0,0 +1,431 @@
+//! Logical export of the gateway's state, `knockturn export`.
+//!
+//! Writes merchants, transactions changed since `--since` and the daily
+//! settlements since then to `merchants`, `transactions` and `settlements`
+//! files, as JSON lines or CSV, for archival and regulatory requests.
+//! Merchants are exported without passwords, tokens, second factors and
+//! callback headers. Everything is read in one read only repeatable read
+//! transaction, so the files match each other even while payments go on.
+//! Rows are read in batches of `--batch` with a `--pause-ms` pause after
+//! each, which keeps the load low enough to run it against production.
+
+use crate::errors::Error;
+use crate::models::{Currency, Merchant, Transaction, TransactionStatus, TransactionType};
+use crate::schema::{merchants, transactions};
+use chrono::{NaiveDate, NaiveDateTime};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::{BigInt, Date, Text, Timestamp};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use serde_json::Value;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use strum_macros::{Display, EnumString};
+use uuid::Uuid;
+
+pub const DEFAULT_EXPORT_BATCH: i64 = 1000;
+pub const DEFAULT_EXPORT_PAUSE_MS: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, EnumString, Display)]
+pub enum ExportFormat {
+    /// A JSON object per line
+    #[strum(serialize = "json")]
+    Json,
+    /// A header line with the field names, nested values are JSON
+    #[strum(serialize = "csv")]
+    Csv,
+}
+
+impl ExportFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Json => "jsonl",
+            ExportFormat::Csv => "csv",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportConfig {
+    pub since: NaiveDate,
+    pub format: ExportFormat,
+    /// Files are written to it
+    pub dir: PathBuf,
+    /// Rows read at once
+    pub batch_size: i64,
+    /// Pause after each batch
+    pub pause: Duration,
+}
+
+impl ExportConfig {
+    /// Reads `--since=YYYY-MM-DD`, which is required, `--format=json|csv`,
+    /// `--dir=PATH`, `--batch=N` and `--pause-ms=N`
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Self, Error> {
+        let mut since = None;
+        let mut config = ExportConfig {
+            since: NaiveDate::from_ymd(1970, 1, 1),
+            format: ExportFormat::Json,
+            dir: PathBuf::from("."),
+            batch_size: DEFAULT_EXPORT_BATCH,
+            pause: Duration::from_millis(DEFAULT_EXPORT_PAUSE_MS),
+        };
+        for arg in args {
+            let mut parts = arg.splitn(2, '=');
+            let name = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("");
+            let number = || {
+                value
+                    .parse::<u64>()
+                    .map_err(|_| Error::InvalidEntity(format!("{} takes a number", name)))
+            };
+            match name {
+                "--since" => {
+                    since = Some(NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| {
+                        Error::InvalidEntity(s!("--since takes a date like 2019-07-01"))
+                    })?)
+                }
+                "--format" => {
+                    config.format = value
+                        .parse()
+                        .map_err(|_| Error::InvalidEntity(s!("--format takes json or csv")))?
+                }
+                "--dir" => config.dir = PathBuf::from(value),
+                "--batch" => config.batch_size = number()?.max(1) as i64,
+                "--pause-ms" => config.pause = Duration::from_millis(number()?),
+                _ => return Err(Error::InvalidEntity(format!("unknown argument {}", arg))),
+            }
+        }
+        config.since = since.ok_or_else(|| Error::InvalidEntity(s!("--since is required")))?;
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ExportSummary {
+    pub merchants: usize,
+    pub transactions: usize,
+    pub settlements: usize,
+}
+
+/// Merchant without its secrets
+#[derive(Debug, Serialize)]
+pub struct MerchantRecord {
+    pub id: String,
+    pub email: String,
+    pub created_at: NaiveDateTime,
+    pub balance: i64,
+    pub pending_balance: i64,
+    pub plan: String,
+    pub wallet_url: Option<String>,
+    pub callback_url: Option<String>,
+    pub payout_callback_url: Option<String>,
+    pub timezone: String,
+    pub invoice_prefix: String,
+    pub platform_id: Option<String>,
+}
+
+impl From<Merchant> for MerchantRecord {
+    fn from(merchant: Merchant) -> Self {
+        MerchantRecord {
+            id: merchant.id,
+            email: merchant.email,
+            created_at: merchant.created_at,
+            balance: merchant.balance,
+            pending_balance: merchant.pending_balance,
+            plan: merchant.plan,
+            wallet_url: merchant.wallet_url,
+            callback_url: merchant.callback_url,
+            payout_callback_url: merchant.payout_callback_url,
+            timezone: merchant.timezone,
+            invoice_prefix: merchant.invoice_prefix,
+            platform_id: merchant.platform_id,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TransactionRecord {
+    pub id: Uuid,
+    pub merchant_id: String,
+    pub external_id: String,
+    pub invoice_number: Option<String>,
+    pub transaction_type: TransactionType,
+    pub status: TransactionStatus,
+    pub grin_amount: i64,
+    /// In the smallest unit of the currency
+    pub amount: i64,
+    pub currency: Currency,
+    pub exchange_rate: Option<Decimal>,
+    pub knockturn_fee: Option<i64>,
+    pub transfer_fee: Option<i64>,
+    pub real_transfer_fee: Option<i64>,
+    pub email: Option<String>,
+    pub height: Option<i64>,
+    pub commit: Option<String>,
+    pub refund_reason: Option<String>,
+    pub refund_address: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl From<Transaction> for TransactionRecord {
+    fn from(tx: Transaction) -> Self {
+        TransactionRecord {
+            id: tx.id,
+            merchant_id: tx.merchant_id,
+            external_id: tx.external_id,
+            invoice_number: tx.invoice_number,
+            transaction_type: tx.transaction_type,
+            status: tx.status,
+            grin_amount: tx.grin_amount,
+            amount: tx.amount.amount,
+            currency: tx.amount.currency,
+            exchange_rate: tx.exchange_rate,
+            knockturn_fee: tx.knockturn_fee,
+            transfer_fee: tx.transfer_fee,
+            real_transfer_fee: tx.real_transfer_fee,
+            email: tx.email,
+            height: tx.height,
+            commit: tx.commit,
+            refund_reason: tx.refund_reason,
+            refund_address: tx.refund_address,
+            created_at: tx.created_at,
+            updated_at: tx.updated_at,
+        }
+    }
+}
+
+/// Totals of a merchant's day like on settlement statements, amounts in
+/// nanogrins
+#[derive(Debug, Serialize, QueryableByName)]
+pub struct SettlementRecord {
+    #[sql_type = "Text"]
+    pub merchant_id: String,
+    #[sql_type = "Date"]
+    pub date: NaiveDate,
+    #[sql_type = "BigInt"]
+    pub transactions: i64,
+    #[sql_type = "BigInt"]
+    pub payments: i64,
+    #[sql_type = "BigInt"]
+    pub payouts: i64,
+    #[sql_type = "BigInt"]
+    pub fees: i64,
+}
+
+/// Writes the files, an existing file is replaced
+pub fn export(conn: &PgConnection, config: &ExportConfig) -> Result<ExportSummary, Error> {
+    let since = config.since.and_hms(0, 0, 0);
+    conn.build_transaction()
+        .read_only()
+        .repeatable_read()
+        .run(|| {
+            let mut summary = ExportSummary::default();
+
+            let mut sink = Sink::create(config, "merchants")?;
+            let mut last: Option<String> = None;
+            loop {
+                let mut query = merchants::table
+                    .order(merchants::id.asc())
+                    .limit(config.batch_size)
+                    .into_boxed();
+                if let Some(last) = last {
+                    query = query.filter(merchants::id.gt(last));
+                }
+                let batch: Vec<Merchant> = query.load(conn)?;
+                let full = batch.len() as i64 == config.batch_size;
+                last = batch.last().map(|merchant| merchant.id.clone());
+                summary.merchants += batch.len();
+                for merchant in batch {
+                    sink.write(&MerchantRecord::from(merchant))?;
+                }
+                if !full {
+                    break;
+                }
+                thread::sleep(config.pause);
+            }
+            sink.finish()?;
+
+            let mut sink = Sink::create(config, "transactions")?;
+            let mut last: Option<Uuid> = None;
+            loop {
+                let mut query = transactions::table
+                    .filter(transactions::updated_at.ge(since))
+                    .order(transactions::id.asc())
+                    .limit(config.batch_size)
+                    .into_boxed();
+                if let Some(last) = last {
+                    query = query.filter(transactions::id.gt(last));
+                }
+                let batch: Vec<Transaction> = query.load(conn)?;
+                let full = batch.len() as i64 == config.batch_size;
+                last = batch.last().map(|tx| tx.id);
+                summary.transactions += batch.len();
+                for tx in batch {
+                    sink.write(&TransactionRecord::from(tx))?;
+                }
+                if !full {
+                    break;
+                }
+                thread::sleep(config.pause);
+            }
+            sink.finish()?;
+
+            let mut sink = Sink::create(config, "settlements")?;
+            let mut offset = 0;
+            loop {
+                let batch: Vec<SettlementRecord> = diesel::sql_query(
+                    "SELECT merchant_id, updated_at::date AS date,
+                        COUNT(*) AS transactions,
+                        COALESCE(SUM(grin_amount) FILTER (WHERE transaction_type = 'payment'), 0)::BIGINT
+                            AS payments,
+                        COALESCE(SUM(grin_amount) FILTER (WHERE transaction_type = 'payout'), 0)::BIGINT
+                            AS payouts,
+                        COALESCE(SUM(COALESCE(knockturn_fee, 0)
+                            + COALESCE(real_transfer_fee, transfer_fee, 0)), 0)::BIGINT AS fees
+                    FROM transactions
+                    WHERE status = 'confirmed' AND updated_at >= $1
+                    GROUP BY 1, 2
+                    ORDER BY 1, 2
+                    OFFSET $2 LIMIT $3",
+                )
+                .bind::<Timestamp, _>(since)
+                .bind::<BigInt, _>(offset)
+                .bind::<BigInt, _>(config.batch_size)
+                .load(conn)?;
+                let full = batch.len() as i64 == config.batch_size;
+                offset += batch.len() as i64;
+                summary.settlements += batch.len();
+                for settlement in &batch {
+                    sink.write(settlement)?;
+                }
+                if !full {
+                    break;
+                }
+                thread::sleep(config.pause);
+            }
+            sink.finish()?;
+
+            Ok(summary)
+        })
+}
+
+struct Sink {
+    out: BufWriter<File>,
+    format: ExportFormat,
+    /// Of the CSV header, taken from the first record
+    columns: Option<Vec<String>>,
+}
+
+impl Sink {
+    fn create(config: &ExportConfig, name: &str) -> Result<Sink, Error> {
+        let path = config
+            .dir
+            .join(format!("{}.{}", name, config.format.extension()));
+        let file = File::create(&path)
+            .map_err(|e| Error::General(format!("cannot create {}: {}", path.display(), e)))?;
+        Ok(Sink {
+            out: BufWriter::new(file),
+            format: config.format,
+            columns: None,
+        })
+    }
+
+    fn write<T: Serialize>(&mut self, record: &T) -> Result<(), Error> {
+        let line = match self.format {
+            ExportFormat::Json => serde_json::to_string(record)?,
+            ExportFormat::Csv => {
+                let value = serde_json::to_value(record)?;
+                let mut line = String::new();
+                if self.columns.is_none() {
+                    let columns: Vec<String> = match value {
+                        Value::Object(ref fields) => fields.keys().cloned().collect(),
+                        _ => vec![],
+                    };
+                    line.push_str(&csv_line(columns.iter().map(String::as_str)));
+                    line.push('\n');
+                    self.columns = Some(columns);
+                }
+                let columns = self.columns.as_ref().unwrap();
+                line.push_str(&csv_row(columns, &value));
+                line
+            }
+        };
+        writeln!(self.out, "{}", line).map_err(|e| Error::General(s!(e)))
+    }
+
+    fn finish(mut self) -> Result<(), Error> {
+        self.out.flush().map_err(|e| Error::General(s!(e)))
+    }
+}
+
+/// Fields of `record` in the order of `columns`
+fn csv_row(columns: &[String], record: &Value) -> String {
+    let fields: Vec<String> = columns
+        .iter()
+        .map(|column| match record.get(column) {
+            None | Some(Value::Null) => String::new(),
+            Some(Value::String(s)) => s.clone(),
+            Some(value) => value.to_string(),
+        })
+        .collect();
+    csv_line(fields.iter().map(String::as_str))
+}
+
+/// Quotes fields with commas, quotes or line breaks
+fn csv_line<'a, I: Iterator<Item = &'a str>>(fields: I) -> String {
+    fields
+        .map(|field| {
+            if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_export_config() {
+        let config = ExportConfig::from_args(vec![
+            s!("--since=2019-07-01"),
+            s!("--format=csv"),
+            s!("--pause-ms=0"),
+        ])
+        .unwrap();
+        assert_eq!(config.since, NaiveDate::from_ymd(2019, 7, 1));
+        assert_eq!(config.format, ExportFormat::Csv);
+        assert_eq!(config.batch_size, DEFAULT_EXPORT_BATCH);
+        assert_eq!(config.pause, Duration::from_millis(0));
+        assert!(ExportConfig::from_args(vec![s!("--format=csv")]).is_err());
+        assert!(ExportConfig::from_args(vec![s!("--since=yesterday")]).is_err());
+        assert!(
+            ExportConfig::from_args(vec![s!("--since=2019-07-01"), s!("--format=xml")]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_csv_row() {
+        let columns = vec![s!("email"), s!("id"), s!("meta"), s!("note")];
+        let record = json!({
+            "id": 7,
+            "email": null,
+            "meta": {"a": 1},
+            "note": "say \"hi\", twice",
+        });
+        assert_eq!(
+            csv_row(&columns, &record),
+            r#",7,"{""a"":1}","say ""hi"", twice""#
+        );
+    }
+}
@@ -1,20 +1,66 @@
 use crate::app::AppState;
-use crate::db::GetMerchant;
+use crate::db::{GetApiToken, GetMerchant};
 use crate::errors::*;
-use crate::models::Merchant;
+use crate::jwt;
+use crate::models::{ApiScope, ApiToken, Merchant};
+use actix_web::http::header;
 use actix_web::middleware::identity::RequestIdentity;
 use actix_web::middleware::session::RequestSession;
 use actix_web::{FromRequest, HttpMessage, HttpRequest};
 use actix_web_httpauth::extractors::basic;
 use bytes::BytesMut;
+use chrono::Utc;
 use derive_deref::Deref;
-use futures::future::{err, ok, Future};
+use futures::future::{err, ok, Either, Future};
 use futures::stream::Stream;
 use serde::de::DeserializeOwned;
 use std::default::Default;
+use std::ops::Deref;
 
-#[derive(Debug, Deref, Clone)]
-pub struct BasicAuth<T>(pub T);
+/// Merchant a request was authenticated as, stored in request extensions
+/// by the extractors for the API request log
+#[derive(Debug, Clone)]
+pub struct AuthenticatedMerchant(pub String);
+
+fn authenticated(req: &HttpRequest<AppState>, merchant: &Merchant) {
+    req.extensions_mut()
+        .insert(AuthenticatedMerchant(merchant.id.clone()));
+}
+
+/// Basic auth extractor, password is either the merchant's token which grants
+/// all scopes or a scoped API token
+#[derive(Debug, Clone)]
+pub struct BasicAuth<T> {
+    inner: T,
+    scopes: Vec<ApiScope>,
+}
+
+impl<T> BasicAuth<T> {
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    pub fn scopes(&self) -> &[ApiScope] {
+        &self.scopes
+    }
+
+    /// Guard which every API handler calls before doing anything
+    pub fn require(&self, scope: ApiScope) -> Result<(), Error> {
+        if self.scopes.contains(&scope) {
+            Ok(())
+        } else {
+            Err(Error::InsufficientScope(scope))
+        }
+    }
+}
+
+impl<T> Deref for BasicAuth<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
 
 pub struct BasicAuthConfig(pub basic::Config);
 impl Default for BasicAuthConfig {
@@ -30,26 +76,116 @@ impl FromRequest<AppState> for BasicAuth<Merchant> {
     type Result = Result<Box<dyn Future<Item = Self, Error = Error>>, Error>;
 
     fn from_request(req: &HttpRequest<AppState>, cfg: &Self::Config) -> Self::Result {
+        if bearer_token(req).is_some() {
+            let bearer = BearerAuth::<Merchant>::from_request(req, &BearerAuthConfig)?;
+            return Ok(Box::new(bearer.map(BasicAuth::from)));
+        }
         let bauth =
             basic::BasicAuth::from_request(&req, &cfg.0).map_err(|_| Error::NotAuthorized)?;
         let username = bauth.username().to_owned();
+        let password = bauth.password().unwrap_or("").to_owned();
+        let db = req.state().db.clone();
+        let req = req.clone();
+
+        Ok(Box::new(
+            db.send(GetMerchant {
+                id: username.clone(),
+            })
+            .from_err()
+            .and_then(move |db_response| {
+                let merchant = match db_response {
+                    Ok(m) => m,
+                    Err(_) => return Either::A(err(Error::NotAuthorized)),
+                };
+                if merchant.token == password {
+                    return Either::A(ok(BasicAuth {
+                        inner: merchant,
+                        scopes: ApiScope::ALL.to_vec(),
+                    }));
+                }
+                Either::B(
+                    db.send(GetApiToken {
+                        merchant_id: username,
+                        token_hash: ApiToken::hash(&password),
+                    })
+                    .from_err()
+                    .and_then(move |db_response| match db_response {
+                        Ok(token) => Ok(BasicAuth {
+                            scopes: token.scopes(),
+                            inner: merchant,
+                        }),
+                        Err(_) => Err(Error::NotAuthorized),
+                    }),
+                )
+            })
+            .map(move |auth| {
+                authenticated(&req, &auth);
+                auth
+            }),
+        ))
+    }
+}
+
+/// JWT bearer extractor, see `jwt` module for the token format.
+/// `BasicAuth` accepts bearer tokens too, so API handlers take either.
+#[derive(Debug, Clone)]
+pub struct BearerAuth<T> {
+    inner: T,
+    scopes: Vec<ApiScope>,
+}
+
+impl<T> From<BearerAuth<T>> for BasicAuth<T> {
+    fn from(bearer: BearerAuth<T>) -> Self {
+        BasicAuth {
+            inner: bearer.inner,
+            scopes: bearer.scopes,
+        }
+    }
+}
+
+const BEARER_PREFIX: &str = "Bearer ";
+
+fn bearer_token(req: &HttpRequest<AppState>) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    if value.starts_with(BEARER_PREFIX) {
+        Some(value[BEARER_PREFIX.len()..].trim().to_owned())
+    } else {
+        None
+    }
+}
+
+#[derive(Default)]
+pub struct BearerAuthConfig;
+
+impl FromRequest<AppState> for BearerAuth<Merchant> {
+    type Config = BearerAuthConfig;
+    type Result = Result<Box<dyn Future<Item = Self, Error = Error>>, Error>;
+
+    fn from_request(req: &HttpRequest<AppState>, _: &Self::Config) -> Self::Result {
+        let token = bearer_token(req).ok_or(Error::NotAuthorized)?;
+        let merchant_id = jwt::unverified_merchant_id(&token)?;
+        let req = req.clone();
 
         Ok(Box::new(
             req.state()
                 .db
-                .send(GetMerchant { id: username })
+                .send(GetMerchant {
+                    id: merchant_id.clone(),
+                })
                 .from_err()
                 .and_then(move |db_response| {
-                    let merchant = match db_response {
-                        Ok(m) => m,
-                        Err(_) => return err(Error::NotAuthorized),
-                    };
-                    let password = bauth.password().unwrap_or("");
-                    if merchant.token != password {
-                        err(Error::NotAuthorized)
-                    } else {
-                        ok(BasicAuth(merchant))
-                    }
+                    let merchant = db_response.map_err(|_| Error::NotAuthorized)?;
+                    let claims = jwt::verify(
+                        &token,
+                        &merchant_id,
+                        &merchant.token,
+                        Utc::now().timestamp(),
+                    )?;
+                    authenticated(&req, &merchant);
+                    Ok(BearerAuth {
+                        inner: merchant,
+                        scopes: claims.scopes.unwrap_or_else(|| ApiScope::ALL.to_vec()),
+                    })
                 }),
         ))
     }
@@ -82,6 +218,7 @@ impl FromRequest<AppState> for Session<Merchant> {
             Ok(Some(v)) => v,
             _ => return Err(Error::NotAuthorizedInUI),
         };
+        let req = req.clone();
 
         Ok(Box::new(
             req.state()
@@ -89,7 +226,10 @@ impl FromRequest<AppState> for Session<Merchant> {
                 .send(GetMerchant { id: merchant_id })
                 .from_err()
                 .and_then(move |db_response| match db_response {
-                    Ok(m) => ok(Session(m)),
+                    Ok(m) => {
+                        authenticated(&req, &m);
+                        ok(Session(m))
+                    }
                     Err(_) => err(Error::NotAuthorizedInUI),
                 }),
         ))
@@ -123,6 +263,7 @@ impl FromRequest<AppState> for Identity<Merchant> {
             Some(v) => v,
             None => return Err(Error::NotAuthorizedInUI),
         };
+        let req = req.clone();
 
         Ok(Box::new(
             req.state()
@@ -130,7 +271,10 @@ impl FromRequest<AppState> for Identity<Merchant> {
                 .send(GetMerchant { id: merchant_id })
                 .from_err()
                 .and_then(move |db_response| match db_response {
-                    Ok(m) => ok(Identity(m)),
+                    Ok(m) => {
+                        authenticated(&req, &m);
+                        ok(Identity(m))
+                    }
                     Err(_) => err(Error::NotAuthorizedInUI),
                 }),
         ))
@@ -0,0 +1,172 @@
+//! Features admins turn on and off without a redeploy.
+//!
+//! Flags are known by the code, `Flag` lists them with their defaults. An
+//! admin sets a flag on `/admin/feature_flags` for everybody, an enabled
+//! flag can be rolled out to a share of merchants only, and overrides it
+//! for single merchants. A merchant's override wins, then the flag, then
+//! its default. Which merchants are in a rollout is fixed by a hash of the
+//! flag and the merchant, raising the share only adds merchants. Where no
+//! merchant is known only a flag rolled out to everybody is on. Every
+//! instance reloads the flags every `FEATURE_FLAG_RELOAD_SECONDS`.
+
+use crate::db::{DbExecutor, GetFeatureFlags};
+use crate::errors::Error;
+use crate::models::{FeatureFlag, FeatureFlagOverride};
+use actix::Addr;
+use futures::future::Future;
+use openssl::sha::sha256;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use strum_macros::{Display, EnumString};
+
+pub const FEATURE_FLAG_RELOAD_SECONDS: u64 = 30;
+
+lazy_static::lazy_static! {
+    /// As of the last reload
+    static ref FLAGS: RwLock<Flags> = RwLock::new(Flags::default());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumString, Display)]
+pub enum Flag {
+    /// The JSON-RPC wallet API on `/v2/foreign` of payment URLs, wallets
+    /// fall back to the v1 path when it's off
+    #[strum(serialize = "foreign_api")]
+    ForeignApi,
+    /// Callbacks of pending and in chain payments to merchants who asked
+    /// for verbose callbacks
+    #[strum(serialize = "verbose_callbacks")]
+    VerboseCallbacks,
+}
+
+impl Flag {
+    pub const ALL: &'static [Flag] = &[Flag::ForeignApi, Flag::VerboseCallbacks];
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Flag::ForeignApi => "JSON-RPC wallet API on payment URLs",
+            Flag::VerboseCallbacks => "Pending and in chain callbacks",
+        }
+    }
+
+    /// Until an admin sets the flag
+    pub fn default_enabled(self) -> bool {
+        match self {
+            Flag::ForeignApi | Flag::VerboseCallbacks => true,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Flags {
+    flags: HashMap<String, FeatureFlag>,
+    overrides: HashMap<(String, String), bool>,
+}
+
+impl Flags {
+    pub fn new(flags: Vec<FeatureFlag>, overrides: Vec<FeatureFlagOverride>) -> Self {
+        Flags {
+            flags: flags
+                .into_iter()
+                .map(|flag| (flag.name.clone(), flag))
+                .collect(),
+            overrides: overrides
+                .into_iter()
+                .map(|o| ((o.name, o.merchant_id), o.enabled))
+                .collect(),
+        }
+    }
+
+    pub fn is_enabled(&self, flag: Flag, merchant_id: Option<&str>) -> bool {
+        let name = flag.to_string();
+        if let Some(merchant_id) = merchant_id {
+            if let Some(enabled) = self.overrides.get(&(name.clone(), merchant_id.to_owned())) {
+                return *enabled;
+            }
+        }
+        match self.flags.get(&name) {
+            None => flag.default_enabled(),
+            Some(state) if !state.enabled => false,
+            Some(state) => match merchant_id {
+                Some(merchant_id) => bucket(flag, merchant_id) < state.rollout_percent,
+                None => state.rollout_percent >= 100,
+            },
+        }
+    }
+}
+
+/// From 0 to 99, the same for a flag and merchant
+pub fn bucket(flag: Flag, merchant_id: &str) -> i32 {
+    let hash = sha256(format!("{}|{}", flag, merchant_id).as_bytes());
+    i32::from(u16::from_be_bytes([hash[0], hash[1]]) % 100)
+}
+
+/// Whether `flag` is on for the merchant, as of the last reload
+pub fn is_enabled(flag: Flag, merchant_id: Option<&str>) -> bool {
+    FLAGS.read().unwrap().is_enabled(flag, merchant_id)
+}
+
+/// Replaces the flags with the ones in the DB, names of flags the code
+/// doesn't know anymore are kept but never asked for
+pub fn reload(db: Addr<DbExecutor>) -> impl Future<Item = (), Error = Error> {
+    db.send(GetFeatureFlags).from_err().and_then(|db_response| {
+        let (flags, overrides) = db_response?;
+        *FLAGS.write().unwrap() = Flags::new(flags, overrides);
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn test_is_enabled() {
+        let now = Utc::now().naive_utc();
+        let flag = |enabled, rollout_percent| FeatureFlag {
+            name: s!("verbose_callbacks"),
+            enabled,
+            rollout_percent,
+            updated_by: s!("admin"),
+            updated_at: now,
+        };
+        let overrides = vec![FeatureFlagOverride {
+            name: s!("verbose_callbacks"),
+            merchant_id: s!("beta"),
+            enabled: true,
+            updated_by: s!("admin"),
+            updated_at: now,
+        }];
+
+        let defaults = Flags::default();
+        assert!(defaults.is_enabled(Flag::VerboseCallbacks, Some("shop")));
+        assert!(defaults.is_enabled(Flag::ForeignApi, None));
+
+        let off = Flags::new(vec![flag(false, 100)], overrides);
+        assert!(!off.is_enabled(Flag::VerboseCallbacks, Some("shop")));
+        assert!(!off.is_enabled(Flag::VerboseCallbacks, None));
+        assert!(off.is_enabled(Flag::VerboseCallbacks, Some("beta")));
+        assert!(off.is_enabled(Flag::ForeignApi, Some("shop")));
+
+        let half = Flags::new(vec![flag(true, 50)], vec![]);
+        let merchants: Vec<String> = (0..200).map(|n| format!("shop{}", n)).collect();
+        let on = merchants
+            .iter()
+            .filter(|m| half.is_enabled(Flag::VerboseCallbacks, Some(m.as_str())))
+            .count();
+        assert!(on > 50 && on < 150);
+        assert!(!half.is_enabled(Flag::VerboseCallbacks, None));
+        // Raising the share keeps the merchants which had it
+        let more = Flags::new(vec![flag(true, 80)], vec![]);
+        for m in &merchants {
+            if half.is_enabled(Flag::VerboseCallbacks, Some(m.as_str())) {
+                assert!(more.is_enabled(Flag::VerboseCallbacks, Some(m.as_str())));
+            }
+        }
+        assert_eq!(
+            bucket(Flag::ForeignApi, "shop"),
+            bucket(Flag::ForeignApi, "shop")
+        );
+        assert_eq!("foreign_api".parse::<Flag>().unwrap(), Flag::ForeignApi);
+    }
+}
@@ -1,16 +1,84 @@
 use crate::models::Money;
 use askama::Error;
-use chrono::{Duration, NaiveDateTime};
+use chrono::{Duration, NaiveDateTime, TimeZone};
 use chrono_humanize::{Accuracy, HumanTime, Tense};
+use chrono_tz::Tz;
 pub fn grin(nanogrins: &i64) -> Result<String, Error> {
     Ok(Money::from_grin(*nanogrins).to_string())
 }
 
+/// Dates are stored in UTC
 pub fn pretty_date(date: &NaiveDateTime) -> Result<String, Error> {
-    Ok(date.format("%d.%m.%Y %H:%M:%S").to_string())
+    Ok(date.format("%d.%m.%Y %H:%M:%S UTC").to_string())
+}
+
+/// UTC date shown in the merchant's time zone
+pub fn local_date(date: &NaiveDateTime, tz: &Tz) -> Result<String, Error> {
+    Ok(tz
+        .from_utc_datetime(date)
+        .format("%d.%m.%Y %H:%M:%S %Z")
+        .to_string())
+}
+
+/// Single quoted shell argument, for commands buyers copy
+pub fn shell_quote(arg: &str) -> Result<String, Error> {
+    Ok(format!("'{}'", arg.replace('\'', "'\\''")))
 }
 
 pub fn duration(duration: &Duration) -> Result<String, Error> {
     let ht = HumanTime::from(*duration);
     Ok(ht.to_text_en(Accuracy::Precise, Tense::Present))
 }
+
+pub const SPARKLINE_WIDTH: usize = 300;
+pub const SPARKLINE_HEIGHT: usize = 50;
+
+/// Points of an svg polyline drawing `values` in a
+/// SPARKLINE_WIDTH x SPARKLINE_HEIGHT box
+pub fn sparkline(values: &Vec<i64>) -> Result<String, Error> {
+    if values.is_empty() {
+        return Ok(String::new());
+    }
+    let min = *values.iter().min().unwrap();
+    let max = *values.iter().max().unwrap();
+    let step = SPARKLINE_WIDTH as f64 / (values.len().max(2) - 1) as f64;
+    let points: Vec<String> = values
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            let y = if max == min {
+                SPARKLINE_HEIGHT as f64 / 2.0
+            } else {
+                SPARKLINE_HEIGHT as f64 * (max - v) as f64 / (max - min) as f64
+            };
+            format!("{:.1},{:.1}", i as f64 * step, y)
+        })
+        .collect();
+    Ok(points.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn test_local_date() {
+        let date = NaiveDate::from_ymd(2019, 6, 27).and_hms(10, 30, 0);
+        assert_eq!(pretty_date(&date).unwrap(), "27.06.2019 10:30:00 UTC");
+        assert_eq!(
+            local_date(&date, &chrono_tz::Europe::Berlin).unwrap(),
+            "27.06.2019 12:30:00 CEST"
+        );
+        assert_eq!(
+            local_date(&date, &chrono_tz::America::New_York).unwrap(),
+            "27.06.2019 06:30:00 EDT"
+        );
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("Order 42").unwrap(), "'Order 42'");
+        assert_eq!(shell_quote("it's $5").unwrap(), "'it'\\''s $5'");
+    }
+}
@@ -0,0 +1,271 @@
+//! The Grin wallet foreign API, as buyers' wallets call it on a payment URL.
+//!
+//! `grin wallet send -d <url>` of wallets 2.0 and newer first calls
+//! `check_version` at `<url>/v2/foreign`, then `receive_tx` with the slate
+//! there, falling back to `<url>/v1/wallet/foreign/receive_tx` with the
+//! plain slate when the JSON-RPC API isn't there. Grin++ and grin-wallet 3
+//! post the `receive_tx` call to the URL itself, it's answered the same.
+//! The gateway only receives payments: `build_coinbase` and
+//! `finalize_invoice_tx` are known but not served. Errors are JSON-RPC
+//! errors, which wallets show to the buyer, with the gateway's error code
+//! in `data`.
+
+use crate::errors::Error;
+use crate::wallet::VersionedSlate;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Foreign API the gateway serves on payment URLs
+pub const FOREIGN_API_VERSION: u16 = 2;
+/// What `VersionedSlate` reads, newest first
+pub const SUPPORTED_SLATE_VERSIONS: &[&str] = &["V4", "V3", "V2", "V1", "V0"];
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// The payment couldn't be received, `data.code` says why
+pub const PAYMENT_ERROR: i64 = -32000;
+
+#[derive(Debug, Deserialize)]
+pub struct RpcRequest {
+    pub jsonrpc: String,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+    #[serde(default)]
+    pub id: Value,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    CheckVersion,
+    ReceiveTx,
+    VerifySlateMessages,
+    /// Methods of the foreign API a payment URL doesn't serve
+    NotServed(&'static str),
+}
+
+impl Method {
+    pub fn parse(method: &str) -> Option<Self> {
+        match method {
+            "check_version" => Some(Method::CheckVersion),
+            "receive_tx" => Some(Method::ReceiveTx),
+            "verify_slate_messages" => Some(Method::VerifySlateMessages),
+            "build_coinbase" => Some(Method::NotServed("build_coinbase")),
+            "finalize_invoice_tx" => Some(Method::NotServed("finalize_invoice_tx")),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Method::CheckVersion => "check_version",
+            Method::ReceiveTx => "receive_tx",
+            Method::VerifySlateMessages => "verify_slate_messages",
+            Method::NotServed(name) => name,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: &str) -> Self {
+        RpcError {
+            code,
+            message: message.to_owned(),
+            data: None,
+        }
+    }
+
+    /// `message` is what the buyer reads, in their language
+    pub fn payment(error: &Error, message: &str) -> Self {
+        RpcError {
+            code: PAYMENT_ERROR,
+            message: message.to_owned(),
+            data: Some(json!({ "code": error.code() })),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RpcResponse {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+impl RpcResponse {
+    /// Foreign API methods return Rust's `Result`, wallets read `Ok`
+    pub fn ok<T: Serialize>(id: Value, result: T) -> Self {
+        RpcResponse {
+            jsonrpc: "2.0",
+            id,
+            result: Some(json!({ "Ok": result })),
+            error: None,
+        }
+    }
+
+    pub fn error(id: Value, error: RpcError) -> Self {
+        RpcResponse {
+            jsonrpc: "2.0",
+            id,
+            result: None,
+            error: Some(error),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    pub foreign_api_version: u16,
+    pub supported_slate_versions: &'static [&'static str],
+}
+
+pub fn version_info() -> VersionInfo {
+    VersionInfo {
+        foreign_api_version: FOREIGN_API_VERSION,
+        supported_slate_versions: SUPPORTED_SLATE_VERSIONS,
+    }
+}
+
+/// Checks the envelope, the method and its params are up to the caller
+pub fn parse_request(body: &str) -> Result<RpcRequest, RpcResponse> {
+    let body: Value = serde_json::from_str(body).map_err(|e| {
+        RpcResponse::error(
+            Value::Null,
+            RpcError::new(PARSE_ERROR, &format!("Parse error: {}", e)),
+        )
+    })?;
+    request_of(body)
+}
+
+/// Senders which only speak JSON-RPC post the envelope to the payment URL
+/// itself, slates have neither of these
+pub fn is_request(body: &Value) -> bool {
+    body.get("jsonrpc").is_some() && body.get("method").is_some()
+}
+
+pub fn request_of(body: Value) -> Result<RpcRequest, RpcResponse> {
+    let id = body.get("id").cloned().unwrap_or(Value::Null);
+    match serde_json::from_value::<RpcRequest>(body) {
+        Ok(ref request) if request.jsonrpc != "2.0" => Err(RpcResponse::error(
+            id,
+            RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\""),
+        )),
+        Ok(request) => Ok(request),
+        Err(e) => Err(RpcResponse::error(
+            id,
+            RpcError::new(INVALID_REQUEST, &format!("Invalid request: {}", e)),
+        )),
+    }
+}
+
+/// Slate of `receive_tx` and `verify_slate_messages`, the first param.
+/// The account and message `receive_tx` takes next are the receiver's,
+/// the gateway ignores them.
+pub fn slate_param(params: &Value) -> Result<Value, RpcError> {
+    let slate = match params {
+        Value::Array(params) => params.get(0),
+        Value::Object(params) => params.get("slate"),
+        _ => None,
+    };
+    slate
+        .filter(|slate| !slate.is_null())
+        .cloned()
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "The slate is missing"))
+}
+
+/// Result of `verify_slate_messages`
+pub fn verify_slate_messages(slate: Value) -> Result<(), Error> {
+    let slate = VersionedSlate::parse(slate)?.to_slate()?;
+    for participant in &slate.participant_data {
+        participant.message_signer()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let request = parse_request(
+            r#"{
+                "jsonrpc": "2.0",
+                "method": "receive_tx",
+                "id": 7,
+                "params": [{"version_info": {"version": 2}}, null, null]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(Method::parse(&request.method), Some(Method::ReceiveTx));
+        assert_eq!(
+            slate_param(&request.params).unwrap(),
+            json!({"version_info": {"version": 2}})
+        );
+        assert_eq!(
+            slate_param(&json!({"slate": {"version": 1}})).unwrap(),
+            json!({"version": 1})
+        );
+        assert_eq!(
+            slate_param(&json!([null])).unwrap_err().code,
+            INVALID_PARAMS
+        );
+        assert_eq!(slate_param(&Value::Null).unwrap_err().code, INVALID_PARAMS);
+
+        assert_eq!(
+            Method::parse("build_coinbase"),
+            Some(Method::NotServed("build_coinbase"))
+        );
+        assert_eq!(Method::parse("get_tip"), None);
+
+        let code = |body: &str| {
+            serde_json::to_value(parse_request(body).unwrap_err()).unwrap()["error"]["code"].clone()
+        };
+        assert_eq!(
+            code(r#"{"jsonrpc": "1.0", "method": "check_version", "id": 1}"#),
+            INVALID_REQUEST
+        );
+        assert_eq!(code("[1, 2]"), INVALID_REQUEST);
+        assert_eq!(code("{\"jsonrpc\""), PARSE_ERROR);
+
+        assert!(is_request(
+            &json!({"jsonrpc": "2.0", "method": "receive_tx", "params": []})
+        ));
+        assert!(!is_request(&json!({"version_info": {"version": 2}})));
+        assert!(!is_request(&json!({"version": 1, "method": "x"})));
+    }
+
+    #[test]
+    fn test_response() {
+        let resp = serde_json::to_value(RpcResponse::ok(json!(1), version_info())).unwrap();
+        assert_eq!(
+            resp,
+            json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "result": {"Ok": {
+                    "foreign_api_version": 2,
+                    "supported_slate_versions": ["V4", "V3", "V2", "V1", "V0"]
+                }}
+            })
+        );
+        let error = RpcError::payment(&Error::PaymentProcessing, "Wait");
+        let resp = serde_json::to_value(RpcResponse::error(json!("a"), error)).unwrap();
+        assert_eq!(resp["error"]["code"], PAYMENT_ERROR);
+        assert_eq!(resp["error"]["data"]["code"], "payment_processing");
+        assert!(resp.get("result").is_none());
+    }
+}
@@ -1,24 +1,36 @@
-use crate::blocking;
+use crate::analytics;
+use crate::callback::{self, CallbackSettings};
+use crate::clock::SharedClock;
+use crate::compat::{self, Future01CompatExt};
 use crate::db::{
-    self, CreateTransaction, DbExecutor, GetMerchant, GetPayment, GetUnreportedPaymentsByStatus,
-    ReportAttempt, UpdateTransactionStatus,
+    self, ChangeStatus, CompletePayoutBatch, CreatePayoutBatch, CreateTransaction,
+    CreateTransactions, DbExecutor, GetCurrentHeight, GetMerchant, GetPayment,
+    GetUnreportedPaymentsByStatus, MarkAsConfirmedByWallet, MarkAsInChain, MarkAsPending,
+    MarkAsRefund, MarkAsReported, MarkPayoutAsInitialized, MarkStatusReported, RecordPayoutEvent,
+    ReportAttempt, RequoteTransaction,
 };
 use crate::errors::Error;
-use crate::models::Merchant;
-use crate::models::{Confirmation, Money, Transaction, TransactionStatus, TransactionType};
-use crate::ser;
+use crate::explorer::ExplorerLinks;
+use crate::feature_flags::{self, Flag};
+use crate::integrations::{self, Integrations, Notifier, Notify};
+use crate::metrics;
+use crate::models::{
+    Confirmation, Currency, Merchant, Money, PayoutBatch, PayoutEventType, Transaction,
+    TransactionStatus, TransactionType,
+};
+use crate::payment_state::{
+    Confirmed, InChain, New, Payment, Pending, Refund, Rejected, State, Transition,
+};
+use crate::splits::SplitRecipient;
+use crate::status;
 use crate::wallet::TxLogEntry;
 use crate::wallet::Wallet;
-use actix::{Actor, Addr, Context, Handler, Message, ResponseFuture};
-use actix_web::client;
-use chrono::{Duration, Utc};
+use actix::{Actor, Addr, Arbiter, Context, Handler, Message, Recipient, ResponseFuture};
+use actix_web::http::header;
 use derive_deref::Deref;
-use diesel::pg::PgConnection;
-use diesel::r2d2::{ConnectionManager, Pool};
-use diesel::{self, prelude::*};
-use futures::future::{ok, Either, Future};
-use log::{debug, error};
-use serde::{Deserialize, Serialize};
+use futures03::stream::{self, StreamExt};
+use log::{debug, error, info, warn};
+use serde::Deserialize;
 use uuid::Uuid;
 
 pub const MINIMAL_WITHDRAW: i64 = 1_000_000_000;
@@ -28,35 +40,143 @@ pub const TRANSFER_FEE: i64 = 8_000_000;
 pub struct Fsm {
     pub db: Addr<DbExecutor>,
     pub wallet: Wallet,
-    pub pool: Pool<ConnectionManager<PgConnection>>,
+    pub clock: SharedClock,
+    pub notifier: Addr<Notifier>,
+    /// Get every `FsmEvent`, registered with `Subscribe`
+    pub observers: Vec<Recipient<FsmEvent>>,
 }
 
 impl Actor for Fsm {
     type Context = Context<Self>;
 }
 
-/*
- * These are messages to control Payments State Machine
- *
- */
+/// A payment the state machine moved, sent to the observers once the
+/// change is stored. Expired payments, autoconfirmations, synced blocks and
+/// manual transitions are updated by the DB, their events are published
+/// with `Publish`.
+#[derive(Debug, Clone)]
+pub enum FsmEvent {
+    Created(Transaction),
+    Requoted(Transaction),
+    Pending(Transaction),
+    InChain(Transaction),
+    Confirmed(Transaction),
+    Rejected(Transaction),
+    Refunded(Transaction),
+    /// The merchant's callback accepted the payment
+    Reported(Transaction),
+}
+
+impl FsmEvent {
+    pub fn transaction(&self) -> &Transaction {
+        match self {
+            FsmEvent::Created(tx)
+            | FsmEvent::Requoted(tx)
+            | FsmEvent::Pending(tx)
+            | FsmEvent::InChain(tx)
+            | FsmEvent::Confirmed(tx)
+            | FsmEvent::Rejected(tx)
+            | FsmEvent::Refunded(tx)
+            | FsmEvent::Reported(tx) => tx,
+        }
+    }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Deref)]
-pub struct NewPayment(Transaction);
+    /// Event of a payment which moved to its current status, none for
+    /// payouts
+    pub fn moved(transaction: Transaction) -> Option<FsmEvent> {
+        if transaction.transaction_type != TransactionType::Payment {
+            return None;
+        }
+        match transaction.status {
+            TransactionStatus::Pending => Some(FsmEvent::Pending(transaction)),
+            TransactionStatus::InChain => Some(FsmEvent::InChain(transaction)),
+            TransactionStatus::Confirmed => Some(FsmEvent::Confirmed(transaction)),
+            TransactionStatus::Rejected => Some(FsmEvent::Rejected(transaction)),
+            TransactionStatus::Refund => Some(FsmEvent::Refunded(transaction)),
+            TransactionStatus::New | TransactionStatus::Initialized => None,
+        }
+    }
 
-#[derive(Debug, Deserialize, Clone, Deref)]
-pub struct PendingPayment(Transaction);
+    pub fn name(&self) -> &'static str {
+        match self {
+            FsmEvent::Created(_) => "created",
+            FsmEvent::Requoted(_) => "requoted",
+            FsmEvent::Pending(_) => "pending",
+            FsmEvent::InChain(_) => "in_chain",
+            FsmEvent::Confirmed(_) => "confirmed",
+            FsmEvent::Rejected(_) => "rejected",
+            FsmEvent::Refunded(_) => "refunded",
+            FsmEvent::Reported(_) => "reported",
+        }
+    }
+}
 
-#[derive(Debug, Deserialize, Clone, Deref)]
-pub struct InChainPayment(Transaction);
+impl Message for FsmEvent {
+    type Result = ();
+}
+
+/// Registers an observer of all transitions, e.g.
+/// `fsm.do_send(Subscribe(observer.recipient()))`
+pub struct Subscribe(pub Recipient<FsmEvent>);
+
+impl Message for Subscribe {
+    type Result = ();
+}
+
+impl Handler<Subscribe> for Fsm {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _: &mut Self::Context) -> Self::Result {
+        self.observers.push(msg.0);
+    }
+}
+
+/// Payments the DB moved outside of the state machine, e.g. in bulk, their
+/// events are sent to the observers
+pub struct Publish(pub Vec<Transaction>);
+
+impl Message for Publish {
+    type Result = ();
+}
+
+impl Handler<Publish> for Fsm {
+    type Result = ();
+
+    fn handle(&mut self, msg: Publish, _: &mut Self::Context) -> Self::Result {
+        for transaction in msg.0 {
+            if let Some(event) = FsmEvent::moved(transaction) {
+                emit(&self.observers, event);
+            }
+        }
+    }
+}
 
-#[derive(Debug, Deserialize, Clone, Deref, Serialize)]
-pub struct ConfirmedPayment(Transaction);
+/// Observers are notified without waiting for them, one with a full
+/// mailbox misses the event
+fn emit(observers: &[Recipient<FsmEvent>], event: FsmEvent) {
+    for observer in observers {
+        if let Err(e) = observer.do_send(event.clone()) {
+            warn!(
+                "Cannot send {} event of payment {}: {}",
+                event.name(),
+                event.transaction().id,
+                e
+            );
+        }
+    }
+}
 
-#[derive(Debug, Deserialize, Clone, Deref)]
-pub struct RejectedPayment(Transaction);
+/*
+ * These are messages to control Payments State Machine
+ *
+ */
 
-#[derive(Debug, Deserialize, Clone, Deref)]
-pub struct RefundPayment(Transaction);
+pub type NewPayment = Payment<New>;
+pub type PendingPayment = Payment<Pending>;
+pub type InChainPayment = Payment<InChain>;
+pub type ConfirmedPayment = Payment<Confirmed>;
+pub type RejectedPayment = Payment<Rejected>;
+pub type RefundPayment = Payment<Refund>;
 
 #[derive(Debug, Deserialize)]
 pub struct CreatePayment {
@@ -67,24 +187,60 @@ pub struct CreatePayment {
     pub email: Option<String>,
     pub message: String,
     pub redirect_url: Option<String>,
+    pub metadata: Option<serde_json::Value>,
+    pub splits: Vec<SplitRecipient>,
 }
 
 impl Message for CreatePayment {
     type Result = Result<NewPayment, Error>;
 }
 
+impl CreatePayment {
+    fn into_transaction(self) -> CreateTransaction {
+        CreateTransaction {
+            merchant_id: self.merchant_id,
+            external_id: self.external_id,
+            amount: self.amount,
+            confirmations: self.confirmations,
+            email: self.email,
+            message: self.message,
+            transaction_type: TransactionType::Payment,
+            redirect_url: self.redirect_url,
+            metadata: self.metadata,
+            output_selection: None,
+            splits: self.splits,
+        }
+    }
+}
+
+/// Creates all payments or none, the results tell which ones failed
 #[derive(Debug, Deserialize)]
+pub struct CreatePayments {
+    pub payments: Vec<CreatePayment>,
+}
+
+impl Message for CreatePayments {
+    type Result = Result<Vec<Result<NewPayment, Error>>, Error>;
+}
+
+#[derive(Debug)]
 pub struct MakePayment {
     pub new_payment: NewPayment,
     pub wallet_tx: TxLogEntry,
     pub commit: Vec<u8>,
+    /// Key which signed the buyer's slate message
+    pub payer_public_key: Option<String>,
+    /// The message was signed but the signature didn't verify
+    pub payer_message_unverified: bool,
+    pub slate_version: i32,
+    pub payer_user_agent: Option<String>,
 }
 
 impl Message for MakePayment {
     type Result = Result<PendingPayment, Error>;
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug)]
 pub struct SeenInChainPayment<T> {
     pub payment: T,
     pub height: i64,
@@ -98,7 +254,7 @@ impl Message for SeenInChainPayment<RejectedPayment> {
     type Result = Result<RefundPayment, Error>;
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug)]
 pub struct ConfirmPayment {
     pub payment: InChainPayment,
 }
@@ -107,7 +263,7 @@ impl Message for ConfirmPayment {
     type Result = Result<ConfirmedPayment, Error>;
 }
 
-#[derive(Debug, Deserialize, Deref)]
+#[derive(Debug, Deref)]
 pub struct RejectPayment<T> {
     pub payment: T,
 }
@@ -120,7 +276,7 @@ impl Message for RejectPayment<PendingPayment> {
     type Result = Result<RejectedPayment, Error>;
 }
 
-#[derive(Debug, Deserialize, Deref)]
+#[derive(Debug, Deref)]
 pub struct ReportPayment<T> {
     pub payment: T,
 }
@@ -133,6 +289,20 @@ impl Message for ReportPayment<RejectedPayment> {
     type Result = Result<(), Error>;
 }
 
+impl Message for ReportPayment<RefundPayment> {
+    type Result = Result<(), Error>;
+}
+
+/// Verbose callback, the payment stays unreported until it's final
+impl Message for ReportPayment<PendingPayment> {
+    type Result = Result<(), Error>;
+}
+
+/// Verbose callback, the payment stays unreported until it's final
+impl Message for ReportPayment<InChainPayment> {
+    type Result = Result<(), Error>;
+}
+
 #[derive(Debug, Deserialize)]
 pub struct GetNewPayment {
     pub transaction_id: Uuid,
@@ -142,6 +312,15 @@ impl Message for GetNewPayment {
     type Result = Result<NewPayment, Error>;
 }
 
+#[derive(Debug, Deserialize)]
+pub struct RequotePayment {
+    pub transaction_id: Uuid,
+}
+
+impl Message for RequotePayment {
+    type Result = Result<NewPayment, Error>;
+}
+
 #[derive(Debug, Deserialize)]
 pub struct GetPendingPayments;
 
@@ -149,6 +328,30 @@ impl Message for GetPendingPayments {
     type Result = Result<Vec<PendingPayment>, Error>;
 }
 
+#[derive(Debug, Deserialize)]
+pub struct GetInChainPayments;
+
+impl Message for GetInChainPayments {
+    type Result = Result<Vec<InChainPayment>, Error>;
+}
+
+/// Payment outputs were confirmed in the wallet at `height`, while the
+/// wallet's chain is at `wallet_height`
+#[derive(Debug)]
+pub struct ConfirmByWallet<T> {
+    pub payment: T,
+    pub height: i64,
+    pub wallet_height: i64,
+}
+
+impl Message for ConfirmByWallet<PendingPayment> {
+    type Result = Result<Transaction, Error>;
+}
+
+impl Message for ConfirmByWallet<InChainPayment> {
+    type Result = Result<Transaction, Error>;
+}
+
 #[derive(Debug, Deserialize)]
 pub struct GetConfirmedPayments;
 
@@ -170,30 +373,63 @@ impl Message for GetUnreportedRejectedPayments {
     type Result = Result<Vec<RejectedPayment>, Error>;
 }
 
+#[derive(Debug, Deserialize)]
+pub struct GetUnreportedRefundPayments;
+
+impl Message for GetUnreportedRefundPayments {
+    type Result = Result<Vec<RefundPayment>, Error>;
+}
+
+/// Sends a made up confirmation with `test` set to the merchant's callback
+/// url, once, the result is the outcome of the delivery
+#[derive(Debug)]
+pub struct TestCallback {
+    pub merchant: Merchant,
+}
+
+impl Message for TestCallback {
+    type Result = Result<(), Error>;
+}
+
 impl Handler<CreatePayment> for Fsm {
     type Result = ResponseFuture<NewPayment, Error>;
 
     fn handle(&mut self, msg: CreatePayment, _: &mut Self::Context) -> Self::Result {
-        let create_transaction = CreateTransaction {
-            merchant_id: msg.merchant_id,
-            external_id: msg.external_id,
-            amount: msg.amount,
-            confirmations: msg.confirmations,
-            email: msg.email.clone(),
-            message: msg.message.clone(),
-            transaction_type: TransactionType::Payment,
-            redirect_url: msg.redirect_url,
-        };
+        let db = self.db.clone();
+        let observers = self.observers.clone();
+        Box::new(compat::to_01(async move {
+            let transaction = db.send(msg.into_transaction()).compat().await??;
+            emit(&observers, FsmEvent::Created(transaction.clone()));
+            NewPayment::load(transaction)
+        }))
+    }
+}
 
-        let res = self
-            .db
-            .send(create_transaction)
-            .from_err()
-            .and_then(move |db_response| {
-                let transaction = db_response?;
-                Ok(NewPayment(transaction))
-            });
-        Box::new(res)
+impl Handler<CreatePayments> for Fsm {
+    type Result = ResponseFuture<Vec<Result<NewPayment, Error>>, Error>;
+
+    fn handle(&mut self, msg: CreatePayments, _: &mut Self::Context) -> Self::Result {
+        let create_transactions = CreateTransactions {
+            transactions: msg
+                .payments
+                .into_iter()
+                .map(CreatePayment::into_transaction)
+                .collect(),
+        };
+        let db = self.db.clone();
+        let observers = self.observers.clone();
+        Box::new(compat::to_01(async move {
+            let results = db.send(create_transactions).compat().await??;
+            for transaction in results.iter().filter_map(|res| res.as_ref().ok()) {
+                emit(&observers, FsmEvent::Created(transaction.clone()));
+            }
+            Ok::<_, Error>(
+                results
+                    .into_iter()
+                    .map(|res| res.and_then(NewPayment::load))
+                    .collect::<Vec<_>>(),
+            )
+        }))
     }
 }
 
@@ -201,20 +437,20 @@ impl Handler<GetNewPayment> for Fsm {
     type Result = ResponseFuture<NewPayment, Error>;
 
     fn handle(&mut self, msg: GetNewPayment, _: &mut Self::Context) -> Self::Result {
-        let res = self
-            .db
-            .send(GetPayment {
-                transaction_id: msg.transaction_id,
-            })
-            .from_err()
-            .and_then(move |db_response| {
-                let transaction = db_response?;
-                if transaction.status != TransactionStatus::New {
-                    return Err(Error::WrongTransactionStatus(s!(transaction.status)));
-                }
-                Ok(NewPayment(transaction))
-            });
-        Box::new(res)
+        let db = self.db.clone();
+        Box::new(compat::to_01(async move {
+            let transaction = db
+                .send(GetPayment {
+                    transaction_id: msg.transaction_id,
+                })
+                .compat()
+                .await??;
+            let payment = NewPayment::load(transaction)?;
+            if payment.is_rate_lock_expired() {
+                return Err(Error::RateLockExpired);
+            }
+            Ok(payment)
+        }))
     }
 }
 
@@ -222,38 +458,41 @@ impl Handler<MakePayment> for Fsm {
     type Result = ResponseFuture<PendingPayment, Error>;
 
     fn handle(&mut self, msg: MakePayment, _: &mut Self::Context) -> Self::Result {
-        let transaction_id = msg.new_payment.id.clone();
-        let wallet_tx = msg.wallet_tx.clone();
-        let messages: Option<Vec<String>> = wallet_tx.messages.map(|pm| {
-            pm.messages
-                .into_iter()
-                .map(|pmd| pmd.message)
-                .filter_map(|x| x)
-                .collect()
-        });
+        let db = self.db.clone();
+        let observers = self.observers.clone();
+        let mark_as_pending = MarkAsPending {
+            transition: msg.new_payment.make_pending(),
+            wallet_tx: msg.wallet_tx,
+            commit: msg.commit,
+            payer_public_key: msg.payer_public_key,
+            payer_message_unverified: msg.payer_message_unverified,
+            slate_version: msg.slate_version,
+            payer_user_agent: msg.payer_user_agent,
+        };
+        Box::new(compat::to_01(async move {
+            let transaction = db.send(mark_as_pending).compat().await??;
+            emit(&observers, FsmEvent::Pending(transaction.clone()));
+            PendingPayment::load(transaction)
+        }))
+    }
+}
 
-        let pool = self.pool.clone();
-
-        let res = blocking::run(move || {
-            use crate::schema::transactions::dsl::*;
-            let conn: &PgConnection = &pool.get().unwrap();
-
-            let transaction = diesel::update(transactions.filter(id.eq(transaction_id.clone())))
-                .set((
-                    wallet_tx_id.eq(msg.wallet_tx.id as i64),
-                    wallet_tx_slate_id.eq(msg.wallet_tx.tx_slate_id.unwrap()),
-                    slate_messages.eq(messages),
-                    real_transfer_fee.eq(msg.wallet_tx.fee.map(|fee| fee as i64)),
-                    status.eq(TransactionStatus::Pending),
-                    commit.eq(ser::to_hex(msg.commit)),
-                ))
-                .get_result(conn)
-                .map_err::<Error, _>(|e| e.into())?;
-            Ok(PendingPayment(transaction))
-        })
-        .from_err();
+impl Handler<RequotePayment> for Fsm {
+    type Result = ResponseFuture<NewPayment, Error>;
 
-        Box::new(res)
+    fn handle(&mut self, msg: RequotePayment, _: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        let observers = self.observers.clone();
+        Box::new(compat::to_01(async move {
+            let transaction = db
+                .send(RequoteTransaction {
+                    transaction_id: msg.transaction_id,
+                })
+                .compat()
+                .await??;
+            emit(&observers, FsmEvent::Requoted(transaction.clone()));
+            NewPayment::load(transaction)
+        }))
     }
 }
 
@@ -261,16 +500,197 @@ impl Handler<GetPendingPayments> for Fsm {
     type Result = ResponseFuture<Vec<PendingPayment>, Error>;
 
     fn handle(&mut self, _: GetPendingPayments, _: &mut Self::Context) -> Self::Result {
-        Box::new(
-            self.db
-                .send(db::GetPaymentsByStatus(TransactionStatus::Pending))
-                .from_err()
-                .and_then(|db_response| {
-                    let data = db_response?;
-                    Ok(data.into_iter().map(PendingPayment).collect())
-                }),
+        Box::new(compat::to_01(load_payments(
+            self.db.clone(),
+            db::GetPaymentsByStatus(TransactionStatus::Pending),
+        )))
+    }
+}
+
+impl Handler<GetInChainPayments> for Fsm {
+    type Result = ResponseFuture<Vec<InChainPayment>, Error>;
+
+    fn handle(&mut self, _: GetInChainPayments, _: &mut Self::Context) -> Self::Result {
+        Box::new(compat::to_01(load_payments(
+            self.db.clone(),
+            db::GetPaymentsByStatus(TransactionStatus::InChain),
+        )))
+    }
+}
+
+/// Payments the DB message loads, all of them in state `S`
+async fn load_payments<M, S>(db: Addr<DbExecutor>, msg: M) -> Result<Vec<Payment<S>>, Error>
+where
+    M: Message<Result = Result<Vec<Transaction>, Error>> + Send + 'static,
+    DbExecutor: Handler<M>,
+    S: State,
+{
+    let transactions = db.send(msg).compat().await??;
+    transactions.into_iter().map(Payment::load).collect()
+}
+
+impl Handler<ConfirmByWallet<PendingPayment>> for Fsm {
+    type Result = ResponseFuture<Transaction, Error>;
+
+    fn handle(
+        &mut self,
+        msg: ConfirmByWallet<PendingPayment>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        Box::new(compat::to_01(confirm_by_wallet(
+            self.db.clone(),
+            self.observers.clone(),
+            msg.payment.confirm_by_wallet(),
+            msg.height,
+            msg.wallet_height,
+        )))
+    }
+}
+
+impl Handler<ConfirmByWallet<InChainPayment>> for Fsm {
+    type Result = ResponseFuture<Transaction, Error>;
+
+    fn handle(
+        &mut self,
+        msg: ConfirmByWallet<InChainPayment>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        Box::new(compat::to_01(confirm_by_wallet(
+            self.db.clone(),
+            self.observers.clone(),
+            msg.payment.confirm_by_wallet(),
+            msg.height,
+            msg.wallet_height,
+        )))
+    }
+}
+
+/// Creates wallet transactions for a batch of new payouts, one per payout:
+/// a slate is finalized by its one receiver, so merchants can't share a
+/// transaction. At most `concurrency` wallet calls run at the same time, a
+/// payout which failed stays new and goes into one of the next batches.
+#[derive(Debug, Deserialize)]
+pub struct InitializePayoutBatch {
+    pub max_size: i64,
+    pub concurrency: usize,
+}
+
+impl Message for InitializePayoutBatch {
+    type Result = Result<Option<PayoutBatch>, Error>;
+}
+
+impl Handler<InitializePayoutBatch> for Fsm {
+    type Result = ResponseFuture<Option<PayoutBatch>, Error>;
+
+    fn handle(&mut self, msg: InitializePayoutBatch, _: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        let wallet = self.wallet.clone();
+        let concurrency = msg.concurrency.max(1);
+        Box::new(compat::to_01(async move {
+            let created = db
+                .send(CreatePayoutBatch {
+                    max_size: msg.max_size,
+                })
+                .compat()
+                .await??;
+            let (batch, payouts) = match created {
+                Some(created) => created,
+                None => return Ok(None),
+            };
+            stream::iter(payouts)
+                .map(|payout| initialize_payout(db.clone(), wallet.clone(), payout))
+                .buffer_unordered(concurrency)
+                .collect::<Vec<()>>()
+                .await;
+            let batch = db
+                .send(CompletePayoutBatch { batch_id: batch.id })
+                .compat()
+                .await??;
+            info!("Processed payout batch {}", batch.id);
+            Ok::<_, Error>(Some(batch))
+        }))
+    }
+}
+
+/// Never fails, so one payout can't stop the rest of the batch
+async fn initialize_payout(db: Addr<DbExecutor>, wallet: Wallet, payout: Transaction) {
+    let payout_id = payout.id;
+    if let Err(e) = create_payout_slate(&db, &wallet, payout).await {
+        error!("Cannot initialize payout {}: {}", payout_id, e);
+        let recorded = async {
+            db.send(RecordPayoutEvent {
+                transaction_id: payout_id,
+                event: PayoutEventType::Failed,
+            })
+            .compat()
+            .await??;
+            Ok::<_, Error>(())
+        };
+        if let Err(e) = recorded.await {
+            error!("Cannot record failure of payout {}: {}", payout_id, e);
+        }
+    }
+}
+
+async fn create_payout_slate(
+    db: &Addr<DbExecutor>,
+    wallet: &Wallet,
+    payout: Transaction,
+) -> Result<(), Error> {
+    let slate = wallet
+        .create_slate(
+            payout.grin_amount as u64,
+            payout.message.clone(),
+            payout
+                .output_selection
+                .unwrap_or(wallet.outputs_config().selection),
         )
+        .compat()
+        .await?;
+    let payout = db
+        .send(MarkPayoutAsInitialized {
+            transaction_id: payout.id,
+            slate_id: slate.id,
+            fee: slate.fee as i64,
+        })
+        .compat()
+        .await??;
+    if analytics::is_underpriced(&payout) {
+        warn!(
+            "Wallet fee {} of payout {} exceeds the charged fee {}",
+            payout.real_transfer_fee.unwrap_or(0),
+            payout.id,
+            analytics::charged_fee(&payout)
+        );
+        metrics::inc("underpriced_payouts_total", &[]);
     }
+    Ok(())
+}
+
+async fn confirm_by_wallet<F: State>(
+    db: Addr<DbExecutor>,
+    observers: Vec<Recipient<FsmEvent>>,
+    transition: Transition<F, Confirmed>,
+    height: i64,
+    wallet_height: i64,
+) -> Result<Transaction, Error> {
+    let old_status = transition.from();
+    let tx = db
+        .send(MarkAsConfirmedByWallet {
+            transition,
+            height,
+            wallet_height,
+        })
+        .compat()
+        .await??;
+    match tx.status {
+        TransactionStatus::Confirmed => emit(&observers, FsmEvent::Confirmed(tx.clone())),
+        TransactionStatus::InChain if old_status != TransactionStatus::InChain => {
+            emit(&observers, FsmEvent::InChain(tx.clone()))
+        }
+        _ => {}
+    }
+    Ok(tx)
 }
 
 impl Handler<SeenInChainPayment<PendingPayment>> for Fsm {
@@ -281,23 +701,19 @@ impl Handler<SeenInChainPayment<PendingPayment>> for Fsm {
         msg: SeenInChainPayment<PendingPayment>,
         _: &mut Self::Context,
     ) -> Self::Result {
-        Box::new(
-            blocking::run({
-                let pool = self.pool.clone();
-                move || {
-                    use crate::schema::transactions::dsl::*;
-                    let conn: &PgConnection = &pool.get().unwrap();
-                    Ok(
-                        diesel::update(transactions.filter(id.eq(msg.payment.id.clone())))
-                            .set((height.eq(msg.height), status.eq(TransactionStatus::InChain)))
-                            .get_result(conn)
-                            .map(|tx: Transaction| InChainPayment(tx))
-                            .map_err::<Error, _>(|e| e.into())?,
-                    )
-                }
-            })
-            .from_err(),
-        )
+        let db = self.db.clone();
+        let observers = self.observers.clone();
+        Box::new(compat::to_01(async move {
+            let tx = db
+                .send(MarkAsInChain {
+                    transition: msg.payment.seen_in_chain(),
+                    height: msg.height,
+                })
+                .compat()
+                .await??;
+            emit(&observers, FsmEvent::InChain(tx.clone()));
+            InChainPayment::load(tx)
+        }))
     }
 }
 
@@ -309,23 +725,18 @@ impl Handler<SeenInChainPayment<RejectedPayment>> for Fsm {
         msg: SeenInChainPayment<RejectedPayment>,
         _: &mut Self::Context,
     ) -> Self::Result {
-        Box::new(
-            blocking::run({
-                let pool = self.pool.clone();
-                move || {
-                    use crate::schema::transactions::dsl::*;
-                    let conn: &PgConnection = &pool.get().unwrap();
-                    Ok(
-                        diesel::update(transactions.filter(id.eq(msg.payment.id.clone())))
-                            .set(status.eq(TransactionStatus::Refund))
-                            .get_result(conn)
-                            .map(|tx: Transaction| RefundPayment(tx))
-                            .map_err::<Error, _>(|e| e.into())?,
-                    )
-                }
-            })
-            .from_err(),
-        )
+        let db = self.db.clone();
+        let observers = self.observers.clone();
+        Box::new(compat::to_01(async move {
+            let tx = db
+                .send(MarkAsRefund {
+                    transition: msg.payment.refund(),
+                })
+                .compat()
+                .await??;
+            emit(&observers, FsmEvent::Refunded(tx.clone()));
+            RefundPayment::load(tx)
+        }))
     }
 }
 
@@ -334,12 +745,15 @@ impl Handler<ConfirmPayment> for Fsm {
 
     fn handle(&mut self, msg: ConfirmPayment, _: &mut Self::Context) -> Self::Result {
         let tx_msg = db::ConfirmTransaction {
-            transaction: msg.payment.0,
-            confirmed_at: Some(Utc::now().naive_utc()),
+            transition: msg.payment.confirm(),
+            confirmed_at: Some(self.clock.now()),
         };
-        Box::new(self.db.send(tx_msg).from_err().and_then(|res| {
-            let tx = res?;
-            Ok(ConfirmedPayment(tx))
+        let db = self.db.clone();
+        let observers = self.observers.clone();
+        Box::new(compat::to_01(async move {
+            let tx = db.send(tx_msg).compat().await??;
+            emit(&observers, FsmEvent::Confirmed(tx.clone()));
+            ConfirmedPayment::load(tx)
         }))
     }
 }
@@ -348,15 +762,10 @@ impl Handler<GetConfirmedPayments> for Fsm {
     type Result = ResponseFuture<Vec<ConfirmedPayment>, Error>;
 
     fn handle(&mut self, _: GetConfirmedPayments, _: &mut Self::Context) -> Self::Result {
-        Box::new(
-            self.db
-                .send(db::GetPaymentsByStatus(TransactionStatus::Confirmed))
-                .from_err()
-                .and_then(|db_response| {
-                    let data = db_response?;
-                    Ok(data.into_iter().map(ConfirmedPayment).collect())
-                }),
-        )
+        Box::new(compat::to_01(load_payments(
+            self.db.clone(),
+            db::GetPaymentsByStatus(TransactionStatus::Confirmed),
+        )))
     }
 }
 
@@ -364,15 +773,10 @@ impl Handler<GetUnreportedConfirmedPayments> for Fsm {
     type Result = ResponseFuture<Vec<ConfirmedPayment>, Error>;
 
     fn handle(&mut self, _: GetUnreportedConfirmedPayments, _: &mut Self::Context) -> Self::Result {
-        Box::new(
-            self.db
-                .send(GetUnreportedPaymentsByStatus(TransactionStatus::Confirmed))
-                .from_err()
-                .and_then(|db_response| {
-                    let data = db_response?;
-                    Ok(data.into_iter().map(ConfirmedPayment).collect())
-                }),
-        )
+        Box::new(compat::to_01(load_payments(
+            self.db.clone(),
+            GetUnreportedPaymentsByStatus(TransactionStatus::Confirmed),
+        )))
     }
 }
 
@@ -380,63 +784,100 @@ impl Handler<GetUnreportedRejectedPayments> for Fsm {
     type Result = ResponseFuture<Vec<RejectedPayment>, Error>;
 
     fn handle(&mut self, _: GetUnreportedRejectedPayments, _: &mut Self::Context) -> Self::Result {
-        Box::new(
-            self.db
-                .send(GetUnreportedPaymentsByStatus(TransactionStatus::Rejected))
-                .from_err()
-                .and_then(|db_response| {
-                    let data = db_response?;
-                    Ok(data.into_iter().map(RejectedPayment).collect())
-                }),
-        )
+        Box::new(compat::to_01(load_payments(
+            self.db.clone(),
+            GetUnreportedPaymentsByStatus(TransactionStatus::Rejected),
+        )))
+    }
+}
+
+impl Handler<GetUnreportedRefundPayments> for Fsm {
+    type Result = ResponseFuture<Vec<RefundPayment>, Error>;
+
+    fn handle(&mut self, _: GetUnreportedRefundPayments, _: &mut Self::Context) -> Self::Result {
+        Box::new(compat::to_01(load_payments(
+            self.db.clone(),
+            GetUnreportedPaymentsByStatus(TransactionStatus::Refund),
+        )))
+    }
+}
+
+impl Handler<TestCallback> for Fsm {
+    type Result = ResponseFuture<(), Error>;
+
+    fn handle(&mut self, msg: TestCallback, _: &mut Self::Context) -> Self::Result {
+        Box::new(compat::to_01(async move {
+            let merchant = msg.merchant;
+            let callback_url = match merchant.callback_url {
+                Some(ref callback_url) => callback_url,
+                None => return Err(Error::InvalidEntity(s!("callback_url is not set"))),
+            };
+            let id = Uuid::new_v4();
+            let amount = Money::new(1000, Currency::USD);
+            let confirmation = Confirmation {
+                id: &id,
+                token: &merchant.token,
+                external_id: "test",
+                invoice_number: &None,
+                merchant_id: &merchant.id,
+                grin_amount: 1_000_000_000,
+                amount: &amount,
+                status: TransactionStatus::Confirmed,
+                confirmations: 10,
+                current_confirmations: None,
+                refund: None,
+                metadata: &None,
+                expires_at: None,
+                explorer: ExplorerLinks::default(),
+                test: true,
+            };
+            run_callback(
+                callback_url,
+                &CallbackSettings::of(&merchant),
+                &confirmation,
+            )
+            .await
+        }))
     }
 }
 
-fn run_callback(
+/// Posts the confirmation to the merchant's callback url, any status
+/// but 2xx is an error
+async fn run_callback(
     callback_url: &str,
-    token: &str,
-    transaction: &Transaction,
-) -> impl Future<Item = (), Error = Error> {
-    client::post(callback_url)
-        .json(Confirmation {
-            id: &transaction.id,
-            external_id: &transaction.external_id,
-            merchant_id: &transaction.merchant_id,
-            grin_amount: transaction.grin_amount,
-            amount: &transaction.amount,
-            status: transaction.status,
-            confirmations: transaction.confirmations,
-            token: token,
-        })
+    settings: &CallbackSettings,
+    confirmation: &Confirmation<'_>,
+) -> Result<(), Error> {
+    let body = settings.body(confirmation)?;
+    let resp = callback::post(callback_url, settings)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(body)
         .unwrap()
         .send()
-        .map_err({
-            let callback_url = callback_url.to_owned();
-            move |e| Error::MerchantCallbackError {
-                callback_url: callback_url,
-                error: s!(e),
-            }
-        })
-        .and_then({
-            let callback_url = callback_url.to_owned();
-            |resp| {
-                if resp.status().is_success() {
-                    Ok(())
-                } else {
-                    Err(Error::MerchantCallbackError {
-                        callback_url: callback_url,
-                        error: s!("aaa"),
-                    })
-                }
-            }
-        })
+        .compat()
+        .await
+        .map_err(|e| Error::MerchantCallbackError {
+            callback_url: callback_url.to_owned(),
+            error: s!(e),
+        })?;
+    if !resp.status().is_success() {
+        return Err(Error::MerchantCallbackError {
+            callback_url: callback_url.to_owned(),
+            error: format!("response status {}", resp.status()),
+        });
+    }
+    Ok(())
 }
 
 impl Handler<RejectPayment<NewPayment>> for Fsm {
     type Result = ResponseFuture<RejectedPayment, Error>;
 
     fn handle(&mut self, msg: RejectPayment<NewPayment>, _: &mut Self::Context) -> Self::Result {
-        Box::new(reject_transaction(&self.db, &msg.payment.id).map(RejectedPayment))
+        Box::new(compat::to_01(reject_transaction(
+            self.db.clone(),
+            self.observers.clone(),
+            msg.payment.reject(),
+        )))
     }
 }
 
@@ -448,23 +889,22 @@ impl Handler<RejectPayment<PendingPayment>> for Fsm {
         msg: RejectPayment<PendingPayment>,
         _: &mut Self::Context,
     ) -> Self::Result {
-        Box::new(reject_transaction(&self.db, &msg.payment.id).map(RejectedPayment))
+        Box::new(compat::to_01(reject_transaction(
+            self.db.clone(),
+            self.observers.clone(),
+            msg.payment.reject(),
+        )))
     }
 }
 
-fn reject_transaction(
-    db: &Addr<DbExecutor>,
-    id: &Uuid,
-) -> impl Future<Item = Transaction, Error = Error> {
-    db.send(UpdateTransactionStatus {
-        id: id.clone(),
-        status: TransactionStatus::Rejected,
-    })
-    .from_err()
-    .and_then(|db_response| {
-        let tx = db_response?;
-        Ok(tx)
-    })
+async fn reject_transaction<F: State>(
+    db: Addr<DbExecutor>,
+    observers: Vec<Recipient<FsmEvent>>,
+    transition: Transition<F, Rejected>,
+) -> Result<RejectedPayment, Error> {
+    let tx = db.send(ChangeStatus { transition }).compat().await??;
+    emit(&observers, FsmEvent::Rejected(tx.clone()));
+    RejectedPayment::load(tx)
 }
 
 impl Handler<ReportPayment<ConfirmedPayment>> for Fsm {
@@ -475,36 +915,7 @@ impl Handler<ReportPayment<ConfirmedPayment>> for Fsm {
         msg: ReportPayment<ConfirmedPayment>,
         _: &mut Self::Context,
     ) -> Self::Result {
-        Box::new(
-            report_transaction(self.db.clone(), msg.payment.0.clone()).and_then({
-                let pool = self.pool.clone();
-                move |_| {
-                    blocking::run({
-                        move || {
-                            let conn: &PgConnection = &pool.get().unwrap();
-                            conn.transaction(|| {
-                                {
-                                    use crate::schema::merchants::dsl::*;
-                                    diesel::update(
-                                        merchants.filter(id.eq(msg.payment.merchant_id.clone())),
-                                    )
-                                    .set(balance.eq(balance + msg.payment.grin_amount))
-                                    .get_result::<Merchant>(conn)
-                                    .map_err::<Error, _>(|e| e.into())?;
-                                };
-                                use crate::schema::transactions::dsl::*;
-                                diesel::update(transactions.filter(id.eq(msg.payment.id)))
-                                    .set(reported.eq(true))
-                                    .get_result::<Transaction>(conn)
-                                    .map_err::<Error, _>(|e| e.into())?;
-                                Ok(())
-                            })
-                        }
-                    })
-                    .from_err()
-                }
-            }),
-        )
+        self.report_payment(msg.payment)
     }
 }
 
@@ -516,84 +927,240 @@ impl Handler<ReportPayment<RejectedPayment>> for Fsm {
         msg: ReportPayment<RejectedPayment>,
         _: &mut Self::Context,
     ) -> Self::Result {
-        Box::new(
-            report_transaction(self.db.clone(), msg.payment.0.clone()).and_then({
-                let pool = self.pool.clone();
-                move |_| {
-                    blocking::run({
-                        move || {
-                            let conn: &PgConnection = &pool.get().unwrap();
-                            conn.transaction(|| {
-                                {
-                                    use crate::schema::merchants::dsl::*;
-                                    diesel::update(
-                                        merchants.filter(id.eq(msg.payment.merchant_id.clone())),
-                                    )
-                                    .set(balance.eq(balance + msg.payment.grin_amount))
-                                    .get_result::<Merchant>(conn)
-                                    .map_err::<Error, _>(|e| e.into())?;
-                                };
-                                use crate::schema::transactions::dsl::*;
-                                diesel::update(transactions.filter(id.eq(msg.payment.id)))
-                                    .set(reported.eq(true))
-                                    .get_result::<Transaction>(conn)
-                                    .map_err::<Error, _>(|e| e.into())?;
-
-                                Ok(())
-                            })
-                        }
-                    })
-                    .from_err()
-                }
-            }),
-        )
+        self.report_payment(msg.payment)
+    }
+}
+
+impl Handler<ReportPayment<RefundPayment>> for Fsm {
+    type Result = ResponseFuture<(), Error>;
+
+    fn handle(&mut self, msg: ReportPayment<RefundPayment>, _: &mut Self::Context) -> Self::Result {
+        self.report_payment(msg.payment)
     }
 }
 
-fn report_transaction(
+impl Handler<ReportPayment<PendingPayment>> for Fsm {
+    type Result = ResponseFuture<(), Error>;
+
+    fn handle(
+        &mut self,
+        msg: ReportPayment<PendingPayment>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        Box::new(compat::to_01(report_status(
+            self.db.clone(),
+            msg.payment.into_inner(),
+        )))
+    }
+}
+
+impl Handler<ReportPayment<InChainPayment>> for Fsm {
+    type Result = ResponseFuture<(), Error>;
+
+    fn handle(
+        &mut self,
+        msg: ReportPayment<InChainPayment>,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        Box::new(compat::to_01(report_status(
+            self.db.clone(),
+            msg.payment.into_inner(),
+        )))
+    }
+}
+
+impl Fsm {
+    /// Calls the merchant back about a final payment and marks it reported
+    fn report_payment<S: State>(&self, payment: Payment<S>) -> ResponseFuture<(), Error> {
+        let db = self.db.clone();
+        let clock = self.clock.clone();
+        let notifier = self.notifier.clone();
+        let observers = self.observers.clone();
+        Box::new(compat::to_01(async move {
+            let transaction = payment.into_inner();
+            report_transaction(db.clone(), clock, notifier, transaction.clone()).await?;
+            mark_as_reported(db, observers, transaction).await
+        }))
+    }
+}
+
+/// Calls the merchant back about a payment which isn't final yet, with
+/// its confirmations so far. Only merchants with verbose callbacks get it.
+/// While `Flag::VerboseCallbacks` is off for the merchant the status is
+/// marked reported without a call, it isn't sent later.
+async fn report_status(db: Addr<DbExecutor>, transaction: Transaction) -> Result<(), Error> {
+    debug!(
+        "Report {} status of transaction {}",
+        transaction.status, transaction.id
+    );
+    let merchant = db
+        .send(GetMerchant {
+            id: transaction.merchant_id.clone(),
+        })
+        .compat()
+        .await??;
+    let current_height = db.send(GetCurrentHeight).compat().await??;
+    let callback_url = match merchant.callback_url {
+        Some(ref callback_url) if merchant.verbose_callbacks => callback_url,
+        _ => return Ok(()),
+    };
+    let status_reported = MarkStatusReported {
+        transaction_id: transaction.id,
+        status: transaction.status,
+    };
+    if !feature_flags::is_enabled(Flag::VerboseCallbacks, Some(&merchant.id)) {
+        debug!("Verbose callbacks are off for {}", merchant.id);
+        return db.send(status_reported).compat().await?;
+    }
+    let mut confirmation = Confirmation::new(&transaction, &merchant.token);
+    confirmation.current_confirmations = Some(transaction.current_confirmations(current_height));
+    let res = run_callback(
+        callback_url,
+        &CallbackSettings::of(&merchant),
+        &confirmation,
+    )
+    .await;
+    status::record_callback(&merchant.id, res.is_ok());
+    res?;
+    db.send(status_reported).compat().await?
+}
+
+async fn mark_as_reported(
+    db: Addr<DbExecutor>,
+    observers: Vec<Recipient<FsmEvent>>,
+    mut transaction: Transaction,
+) -> Result<(), Error> {
+    db.send(MarkAsReported {
+        transaction_id: transaction.id,
+    })
+    .compat()
+    .await??;
+    transaction.reported = true;
+    emit(&observers, FsmEvent::Reported(transaction));
+    Ok(())
+}
+
+/// Pushes the payment to the merchant's chats, failures are only logged
+fn notify(notifier: &Addr<Notifier>, merchant: &Merchant, transaction: &Transaction) {
+    if Integrations::of(merchant).is_empty() {
+        return;
+    }
+    let transaction_id = transaction.id;
+    let push = notifier.send(Notify {
+        merchant: merchant.clone(),
+        text: integrations::payment_text(transaction),
+    });
+    Arbiter::spawn(compat::to_01(async move {
+        match push.compat().await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Cannot push payment {}: {}", transaction_id, e),
+            Err(e) => warn!("Cannot push payment {}: {}", transaction_id, e),
+        }
+        Ok::<_, ()>(())
+    }));
+}
+
+/// Resolves to the callback's error once the failed attempt is recorded,
+/// so the payment isn't marked reported nor credited
+async fn fail_report<F>(attempt: F, callback_err: Error) -> Result<(), Error>
+where
+    F: std::future::Future<Output = Result<(), Error>>,
+{
+    if let Err(e) = attempt.await {
+        error!("Get error in ReportAttempt {}", e);
+    }
+    Err(callback_err)
+}
+
+async fn report_transaction(
     db: Addr<DbExecutor>,
+    clock: SharedClock,
+    notifier: Addr<Notifier>,
     transaction: Transaction,
-) -> impl Future<Item = (), Error = Error> {
+) -> Result<(), Error> {
     debug!("Try to report transaction {}", transaction.id);
-    db.send(GetMerchant {
-        id: transaction.merchant_id.clone(),
-    })
-    .from_err()
-    .and_then(|res| {
-        let merchant = res?;
-        Ok(merchant)
-    })
-    .and_then(move |merchant| {
-        if let Some(callback_url) = merchant.callback_url.clone() {
-            debug!("Run callback for merchant {}", merchant.email);
-            let res = run_callback(&callback_url, &merchant.token, &transaction).or_else({
-                let db = db.clone();
-                let report_attempts = transaction.report_attempts.clone();
-                let transaction_id = transaction.id.clone();
-                move |callback_err| {
-                    // try call ReportAttempt but ignore errors and return
-                    // error from callback
-                    let next_attempt = Utc::now().naive_utc()
-                        + Duration::seconds(10 * (report_attempts + 1).pow(2) as i64);
-                    db.send(ReportAttempt {
-                        transaction_id: transaction_id,
-                        next_attempt: Some(next_attempt),
-                    })
-                    .map_err(|e| Error::General(s!(e)))
-                    .and_then(|db_response| {
-                        db_response?;
-                        Ok(())
-                    })
-                    .or_else(|e| {
-                        error!("Get error in ReportAttempt {}", e);
-                        Ok(())
-                    })
-                    .and_then(|_| Err(callback_err))
-                }
-            });
-            Either::A(res)
-        } else {
-            Either::B(ok(()))
+    let merchant = db
+        .send(GetMerchant {
+            id: transaction.merchant_id.clone(),
+        })
+        .compat()
+        .await??;
+    // Counted and pushed once, not again on callback retries
+    if transaction.report_attempts == 0 {
+        match transaction.status {
+            TransactionStatus::Confirmed => status::record_confirmed(&transaction),
+            TransactionStatus::Rejected => status::record_rejected(&transaction),
+            _ => {}
         }
-    })
+        notify(&notifier, &merchant, &transaction);
+    }
+    let callback_url = match merchant.callback_url {
+        Some(ref callback_url) => callback_url,
+        None => return Ok(()),
+    };
+    debug!("Run callback for merchant {}", merchant.email);
+    let confirmation = Confirmation::new(&transaction, &merchant.token);
+    let settings = CallbackSettings::of(&merchant);
+    let res = run_callback(callback_url, &settings, &confirmation).await;
+    status::record_callback(&merchant.id, res.is_ok());
+    let callback_err = match res {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+    // Once the attempt is recorded the cron queues the report again when
+    // it's due, otherwise the error is returned and the report job is
+    // retried
+    let next_attempt = settings.retry_policy().next_attempt(
+        transaction.report_attempts + 1,
+        transaction.updated_at,
+        clock.now(),
+    );
+    warn!(
+        "Callback of transaction {} failed, next attempt {:?}: {}",
+        transaction.id, next_attempt, callback_err
+    );
+    let attempt = async {
+        db.send(ReportAttempt {
+            transaction_id: transaction.id,
+            next_attempt,
+        })
+        .compat()
+        .await?
+    };
+    fail_report(attempt, callback_err).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::tests::create_tx;
+    use futures::Future;
+
+    #[test]
+    fn test_moved() {
+        let mut tx = create_tx();
+        assert!(FsmEvent::moved(tx.clone()).is_none());
+        tx.status = TransactionStatus::Refund;
+        assert_eq!(FsmEvent::moved(tx.clone()).unwrap().name(), "refunded");
+        tx.status = TransactionStatus::Confirmed;
+        assert_eq!(FsmEvent::moved(tx.clone()).unwrap().name(), "confirmed");
+        tx.transaction_type = TransactionType::Payout;
+        assert!(FsmEvent::moved(tx).is_none());
+    }
+
+    #[test]
+    fn test_fail_report() {
+        let callback_err = || Error::General(s!("callback failed"));
+        // The attempt was recorded, the callback's error is still returned
+        let recorded = async { Ok(()) };
+        match compat::to_01(fail_report(recorded, callback_err())).wait() {
+            Err(Error::General(e)) => assert_eq!(e, "callback failed"),
+            res => panic!("expected the callback error, got {:?}", res),
+        }
+        let failed = async { Err(Error::General(s!("db down"))) };
+        match compat::to_01(fail_report(failed, callback_err())).wait() {
+            Err(Error::General(e)) => assert_eq!(e, "callback failed"),
+            res => panic!("expected the callback error, got {:?}", res),
+        }
+    }
 }
@@ -0,0 +1,259 @@
+//! Coarse location of clients.
+//!
+//! With `GEOIP_COUNTRY_DB` set to a MaxMind country database, e.g.
+//! GeoLite2-Country.mmdb, and optionally `GEOIP_ASN_DB` to an ASN one, the
+//! country and the autonomous system of buyers opening checkout pages, of
+//! merchant API calls and of dashboard logins are recorded. Nothing finer
+//! than a country is looked up. `GEOIP_ENABLED=false` turns it off without
+//! removing the databases, e.g. for privacy. The databases are read into
+//! memory on start.
+//!
+//! The client is the peer of the connection. `Forwarded` and
+//! `X-Forwarded-For` are only believed when the peer is one of the
+//! `TRUSTED_PROXIES`, a comma separated list of networks in CIDR notation,
+//! and then the client is the last hop which isn't a trusted proxy.
+
+use crate::deny_list::Network;
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use log::info;
+use maxminddb::Reader;
+use serde::Deserialize;
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+
+lazy_static::lazy_static! {
+    static ref GEOIP: Option<GeoIp> = GeoIp::from_env();
+    /// From `TRUSTED_PROXIES`
+    static ref TRUSTED_PROXIES: Vec<Network> = env::var("TRUSTED_PROXIES")
+        .map(|v| {
+            v.split(',')
+                .map(str::trim)
+                .filter(|network| !network.is_empty())
+                .map(|network| {
+                    network
+                        .parse()
+                        .unwrap_or_else(|e| panic!("Cannot parse TRUSTED_PROXIES: {}", e))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Location {
+    /// ISO 3166-1 alpha-2 code
+    pub country: Option<String>,
+    pub asn: Option<i64>,
+}
+
+impl Location {
+    pub fn is_known(&self) -> bool {
+        self.country.is_some() || self.asn.is_some()
+    }
+}
+
+/// Parts of the MaxMind records we read
+#[derive(Debug, Deserialize)]
+struct CountryRecord {
+    country: Option<CountryCode>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CountryCode {
+    iso_code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AsnRecord {
+    autonomous_system_number: Option<u32>,
+}
+
+pub struct GeoIp {
+    country: Reader<Vec<u8>>,
+    asn: Option<Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    /// `None` when disabled or no database is set
+    pub fn from_env() -> Option<Self> {
+        if env::var("GEOIP_ENABLED").map_or(false, |v| v == "false") {
+            info!("GeoIP is disabled");
+            return None;
+        }
+        let open = |var: &str| {
+            env::var(var)
+                .ok()
+                .filter(|path| !path.is_empty())
+                .map(|path| {
+                    Reader::open_readfile(&path)
+                        .unwrap_or_else(|e| panic!("Cannot read {} {}: {}", var, path, e))
+                })
+        };
+        let country = open("GEOIP_COUNTRY_DB")?;
+        let asn = open("GEOIP_ASN_DB");
+        info!("GeoIP is enabled, ASN lookups: {}", asn.is_some());
+        Some(GeoIp { country, asn })
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Location {
+        let country = self
+            .country
+            .lookup::<CountryRecord>(ip)
+            .ok()
+            .and_then(|record| record.country)
+            .and_then(|country| country.iso_code);
+        let asn = self
+            .asn
+            .as_ref()
+            .and_then(|reader| reader.lookup::<AsnRecord>(ip).ok())
+            .and_then(|record| record.autonomous_system_number)
+            .map(i64::from);
+        Location { country, asn }
+    }
+}
+
+pub fn is_enabled() -> bool {
+    GEOIP.is_some()
+}
+
+/// Unknown when GeoIP is disabled
+pub fn lookup(ip: IpAddr) -> Location {
+    GEOIP
+        .as_ref()
+        .map(|geoip| geoip.lookup(ip))
+        .unwrap_or_default()
+}
+
+/// IP of the client, behind a trusted proxy the one it forwarded
+pub fn client_ip<S>(req: &HttpRequest<S>) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    if !is_trusted(&TRUSTED_PROXIES, peer) {
+        return Some(peer);
+    }
+    Some(forwarded_client(
+        peer,
+        &forwarded_for(req),
+        &TRUSTED_PROXIES,
+    ))
+}
+
+fn is_trusted(proxies: &[Network], ip: IpAddr) -> bool {
+    proxies.iter().any(|proxy| proxy.contains(ip))
+}
+
+/// Hops the request was forwarded for, the client first, from `Forwarded`
+/// or, without it, `X-Forwarded-For`
+fn forwarded_for<S>(req: &HttpRequest<S>) -> Vec<IpAddr> {
+    let values = |name| -> Vec<&str> {
+        req.headers()
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect()
+    };
+    let forwarded = values(header::FORWARDED.as_str());
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let mut pair = pair.trim().splitn(2, '=');
+                    match (pair.next(), pair.next()) {
+                        (Some(key), Some(value)) if key.eq_ignore_ascii_case("for") => {
+                            parse_ip(value.trim_matches('"'))
+                        }
+                        _ => None,
+                    }
+                })
+            })
+            .collect();
+    }
+    values("x-forwarded-for")
+        .into_iter()
+        .filter_map(|hop| parse_ip(hop.trim()))
+        .collect()
+}
+
+/// Walks the hops back from the trusted `peer`, proxies append the address
+/// they got the request from, so hops before the last untrusted one may be
+/// made up by the client
+fn forwarded_client(peer: IpAddr, hops: &[IpAddr], proxies: &[Network]) -> IpAddr {
+    let mut client = peer;
+    for hop in hops.iter().rev() {
+        client = *hop;
+        if !is_trusted(proxies, client) {
+            break;
+        }
+    }
+    client
+}
+
+/// Location of the client
+pub fn locate<S>(req: &HttpRequest<S>) -> Location {
+    client_ip(req).map(lookup).unwrap_or_default()
+}
+
+/// Remote addresses come with or without a port, IPv6 ones without it
+/// may still be in brackets
+fn parse_ip(remote: &str) -> Option<IpAddr> {
+    remote
+        .parse::<SocketAddr>()
+        .map(|addr| addr.ip())
+        .or_else(|_| {
+            remote
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+        })
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ip() {
+        assert_eq!(parse_ip("10.0.0.1:4321"), "10.0.0.1".parse().ok());
+        assert_eq!(parse_ip("10.0.0.1"), "10.0.0.1".parse().ok());
+        assert_eq!(parse_ip("[2001:db8::1]:443"), "2001:db8::1".parse().ok());
+        assert_eq!(parse_ip("2001:db8::1"), "2001:db8::1".parse().ok());
+        assert_eq!(parse_ip("[2001:db8::1]"), "2001:db8::1".parse().ok());
+        assert_eq!(parse_ip("unknown"), None);
+        assert!(!lookup("10.0.0.1".parse().unwrap()).is_known());
+    }
+
+    #[test]
+    fn test_forwarded_client() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let proxies: Vec<Network> = vec!["10.0.0.0/8".parse().unwrap()];
+        let peer = ip("10.0.0.2");
+        assert_eq!(forwarded_client(peer, &[], &proxies), peer);
+        assert_eq!(
+            forwarded_client(peer, &[ip("203.0.113.9")], &proxies),
+            ip("203.0.113.9")
+        );
+        // The client put a made up hop in front
+        assert_eq!(
+            forwarded_client(peer, &[ip("192.0.2.1"), ip("203.0.113.9")], &proxies),
+            ip("203.0.113.9")
+        );
+        // Through a chain of trusted proxies
+        assert_eq!(
+            forwarded_client(
+                peer,
+                &[ip("203.0.113.9"), ip("10.0.0.5"), ip("10.0.0.3")],
+                &proxies
+            ),
+            ip("203.0.113.9")
+        );
+        // Only proxies, the first one is as close to the client as it gets
+        assert_eq!(
+            forwarded_client(peer, &[ip("10.0.0.5"), ip("10.0.0.3")], &proxies),
+            ip("10.0.0.5")
+        );
+        assert!(!is_trusted(&[], peer));
+    }
+}
@@ -1,52 +1,255 @@
 use crate::app::AppState;
+use crate::captcha;
+use crate::compat::{self, Future01CompatExt};
 use crate::db::{CreateMerchant, GetMerchant};
 use crate::errors::*;
-use crate::extractor::SimpleJson;
-use crate::models::{Merchant, Transaction, TransactionStatus, TransactionType};
+use crate::extractor::{BasicAuth, SimpleJson};
+use crate::fsm::TestCallback;
+use crate::jwt;
+use crate::metrics;
+use crate::models::{
+    ApiScope, Merchant, MerchantProfile, Transaction, TransactionStatus, TransactionType,
+};
+use crate::status::GatewayHealth;
 use crate::totp::Totp;
-use actix_web::{AsyncResponder, FutureResponse, HttpResponse, Path, State};
+use actix_web::{FutureResponse, HttpResponse, Path, State};
 use askama::Template;
 use bcrypt;
-use futures::future::{ok, result, Future};
+use futures::future::{err, ok, Either, Future};
 use mime_guess::get_mime_type;
+use serde::{Deserialize, Serialize};
 
+pub mod admin;
+pub mod api_token;
+pub mod callback_settings;
+pub mod checkout;
+pub mod email_branding;
+pub mod integrations;
+pub mod invoice_numbers;
+pub mod marketplace;
+pub mod meta;
 pub mod mfa;
+pub mod note;
+pub mod oidc;
 pub mod payment;
+pub mod rates;
+pub mod referral;
+pub mod refund_address;
+pub mod security_key;
+pub mod settlement;
+pub mod slate_message;
+pub mod timezone;
 pub mod webui;
 
+#[derive(Debug, Deserialize)]
+pub struct RegisterMerchant {
+    #[serde(flatten)]
+    pub merchant: CreateMerchant,
+    /// Token of the CAPTCHA solved by the merchant, when it's enabled
+    pub captcha_response: Option<String>,
+}
+
+/// Checked against the registration policy unless an admin calls it
 pub fn create_merchant(
-    (create_merchant, state): (SimpleJson<CreateMerchant>, State<AppState>),
+    (register, admin, state): (
+        SimpleJson<RegisterMerchant>,
+        Option<BasicAuth<Merchant>>,
+        State<AppState>,
+    ),
 ) -> FutureResponse<HttpResponse> {
-    let mut create_merchant = create_merchant.into_inner();
-    create_merchant.password = match bcrypt::hash(&create_merchant.password, bcrypt::DEFAULT_COST) {
-        Ok(v) => v,
-        Err(_) => return result(Ok(HttpResponse::InternalServerError().finish())).responder(),
+    let RegisterMerchant {
+        merchant: mut create_merchant,
+        captcha_response,
+    } = register.into_inner();
+    let allowed = if admin.map_or(false, |admin| admin.is_admin) {
+        create_merchant.invite_code = None;
+        Either::A(ok(()))
+    } else {
+        let invite_code = create_merchant.invite_code.as_ref().map(String::as_str);
+        if let Err(e) = state.registration.check(invite_code) {
+            return Box::new(err(e.into()));
+        }
+        Either::B(captcha::check(
+            state.captcha.as_ref(),
+            captcha_response.as_ref().map(String::as_str),
+            "signup",
+        ))
     };
-    state
-        .db
-        .send(create_merchant)
-        .from_err()
-        .and_then(|db_response| {
-            let merchant = db_response?;
-            Ok(HttpResponse::Created().json(merchant))
+    let db = state.db.clone();
+    Box::new(
+        compat::to_01(async move {
+            allowed.compat().await?;
+            // Hashed only once the request passed, bcrypt is slow on purpose
+            create_merchant.password =
+                bcrypt::hash(&create_merchant.password, bcrypt::DEFAULT_COST)
+                    .map_err(|e| Error::General(s!(e)))?;
+            let merchant = db.send(create_merchant).compat().await??;
+            Ok::<_, Error>(HttpResponse::Created().json(CreateMerchantResponse {
+                merchant: &merchant,
+                token: &merchant.token,
+            }))
         })
-        .responder()
+        .from_err(),
+    )
+}
+
+#[derive(Debug, Serialize)]
+struct CreateMerchantResponse<'a> {
+    #[serde(flatten)]
+    merchant: &'a Merchant,
+    /// Not shown anywhere else
+    token: &'a str,
 }
 
+/// Merchants get only themselves, admins anyone
 pub fn get_merchant(
+    (merchant, merchant_id, state): (BasicAuth<Merchant>, Path<String>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let merchant_id = merchant_id.into_inner();
+    if merchant.id == merchant_id {
+        return Box::new(ok(HttpResponse::Ok().json(merchant.into_inner())));
+    }
+    if !merchant.is_admin {
+        return Box::new(err(Error::NotAuthorized.into()));
+    }
+    let db = state.db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let merchant = db.send(GetMerchant { id: merchant_id }).compat().await??;
+            Ok::<_, Error>(HttpResponse::Ok().json(merchant))
+        })
+        .from_err(),
+    )
+}
+
+/// Public, what the checkout may show of the merchant
+pub fn get_merchant_profile(
     (merchant_id, state): (Path<String>, State<AppState>),
 ) -> FutureResponse<HttpResponse> {
-    state
-        .db
-        .send(GetMerchant {
-            id: merchant_id.to_owned(),
+    let db = state.db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let merchant = db
+                .send(GetMerchant {
+                    id: merchant_id.into_inner(),
+                })
+                .compat()
+                .await??;
+            Ok::<_, Error>(HttpResponse::Ok().json(MerchantProfile::of(&merchant)))
         })
-        .from_err()
-        .and_then(|db_response| {
-            let merchant = db_response?;
-            Ok(HttpResponse::Ok().json(merchant))
+        .from_err(),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IssueJwtRequest {
+    pub ttl_seconds: Option<i64>,
+    /// Narrows scopes down, by default the JWT gets all scopes of the caller
+    pub scopes: Option<Vec<ApiScope>>,
+}
+
+#[derive(Debug, Serialize)]
+struct IssueJwtResponse {
+    token: String,
+    expires_at: i64,
+}
+
+/// Mints a short-lived JWT, merchants can also sign one themselves
+/// with their API token
+pub fn issue_jwt(
+    (merchant, merchant_id, jwt_req): (
+        BasicAuth<Merchant>,
+        Path<String>,
+        SimpleJson<IssueJwtRequest>,
+    ),
+) -> Result<HttpResponse, Error> {
+    if merchant.id != merchant_id.into_inner() {
+        return Ok(HttpResponse::BadRequest().finish());
+    }
+    let jwt_req = jwt_req.into_inner();
+    let scopes = match jwt_req.scopes {
+        Some(scopes) => {
+            for scope in &scopes {
+                merchant.require(*scope)?;
+            }
+            scopes
+        }
+        None => merchant.scopes().to_vec(),
+    };
+    let claims = jwt::Claims::new(
+        &merchant.id,
+        jwt_req.ttl_seconds.unwrap_or(jwt::MAX_TTL_SECONDS),
+        Some(scopes),
+    );
+    Ok(HttpResponse::Created().json(IssueJwtResponse {
+        token: jwt::sign(&claims, &merchant.token)?,
+        expires_at: claims.exp,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+struct TestCallbackResponse {
+    delivered: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Sends a test confirmation to the merchant's callback url right away,
+/// so integrators can check their endpoint before going live
+pub fn test_callback(
+    (merchant, merchant_id, state): (BasicAuth<Merchant>, Path<String>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    if merchant.id != merchant_id.into_inner() {
+        return Box::new(ok(HttpResponse::BadRequest().finish()));
+    }
+    if let Err(e) = merchant.require(ApiScope::CreatePayments) {
+        return Box::new(err(e.into()));
+    }
+    let merchant = merchant.into_inner();
+    if merchant.callback_url.is_none() {
+        return Box::new(err(
+            Error::InvalidEntity(s!("callback_url is not set")).into()
+        ));
+    }
+    let fsm = state.fsm.clone();
+    Box::new(
+        compat::to_01(async move {
+            let response = match fsm.send(TestCallback { merchant }).compat().await? {
+                Ok(()) => TestCallbackResponse {
+                    delivered: true,
+                    error: None,
+                },
+                Err(e) => TestCallbackResponse {
+                    delivered: false,
+                    error: Some(s!(e)),
+                },
+            };
+            Ok::<_, Error>(HttpResponse::Ok().json(response))
         })
-        .responder()
+        .from_err(),
+    )
+}
+
+/// Counters in Prometheus text format
+pub fn get_metrics(_: State<AppState>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render())
+}
+
+#[derive(Template)]
+#[template(path = "status.html")]
+struct StatusTemplate {
+    health: GatewayHealth,
+}
+
+/// Public gateway health, without anything merchant specific
+pub fn get_status(_: State<AppState>) -> Result<HttpResponse, Error> {
+    let html = StatusTemplate {
+        health: GatewayHealth::current(),
+    }
+    .render()?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
 }
 
 fn check_2fa_code(merchant: &Merchant, code: &str) -> Result<bool, Error> {
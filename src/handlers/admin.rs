@@ -0,0 +1,1118 @@
+use crate::alerts::{self, ALERT_CONFIG};
+use crate::analytics::{AnalyticsSummary, FeeReport, Granularity};
+use crate::app::AppState;
+use crate::clearing::CreditAction;
+use crate::compat::{self, Future01CompatExt};
+use crate::cron::ReplayBlocks;
+use crate::db::{
+    ChangePendingCredits, CreateDeniedNetwork, CreateInviteCode, DeleteDeniedNetwork,
+    DeleteFeatureFlagOverride, DeleteInviteCode, GetAnalyticsTotals, GetAnalyticsVolume,
+    GetCurrentHeight, GetDeniedNetworks, GetFeatureFlags, GetFeeBuckets, GetInviteCodes,
+    GetLatestBlocks, GetPayerWallets, GetPaymentCountries, GetPaymentsHeatmap,
+    GetReconciliationOrphans, GetTopMerchants, GetUnderpricedPayouts, GetUnreportedSummary,
+    ManualTransition, Reset2FA, SetFeatureFlag, SetFeatureFlagOverride, SetReferral,
+};
+use crate::deny_list::{self, Network};
+use crate::errors::*;
+use crate::extractor::Identity;
+use crate::feature_flags::{self, Flag};
+use crate::filters;
+use crate::fsm::Publish;
+use crate::metrics;
+use crate::models::{
+    BlockHeader, DeniedNetwork, FeatureFlag, FeatureFlagOverride, InviteCode, Merchant,
+    ReconciliationOrphan, TransactionStatus,
+};
+use crate::reconciliation;
+use crate::registration::new_invite_code;
+use crate::wallet::{OutputStatus, OutputsConfig};
+use crate::wallet_version::{self, Compatibility, WalletCapabilities};
+use actix_web::{Form, FutureResponse, HttpRequest, HttpResponse, Path, Query};
+use askama::Template;
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
+use futures::future::{err, Future};
+use log::{info, warn};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use uuid::Uuid;
+
+const DEFAULT_ANALYTICS_DAYS: i64 = 30;
+const MAX_ANALYTICS_DAYS: i64 = 366;
+/// Blocks listed on the chain status page
+const CHAIN_STATUS_BLOCKS: i64 = 20;
+const DEFAULT_TOP_MERCHANTS: i64 = 10;
+const MAX_TOP_MERCHANTS: i64 = 100;
+const DEFAULT_UNDERPRICED_PAYOUTS: i64 = 50;
+const MAX_UNDERPRICED_PAYOUTS: i64 = 500;
+
+#[derive(Template)]
+#[template(path = "admin/reconciliation.html")]
+struct ReconciliationTemplate {
+    orphans: Vec<ReconciliationOrphan>,
+}
+
+/// Orphans found by the last reconciliation of the wallet with the DB
+pub fn reconciliation(
+    (merchant, req): (Identity<Merchant>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let orphans = db.send(GetReconciliationOrphans).compat().await??;
+            let html = ReconciliationTemplate { orphans }
+                .render()
+                .map_err(|e| Error::from(e))?;
+            Ok::<_, Error>(HttpResponse::Ok().content_type("text/html").body(html))
+        })
+        .from_err(),
+    )
+}
+
+/// Alerting rules with the thresholds the gateway alerts admins at, for
+/// a Prometheus which scrapes `/metrics`
+pub fn alert_rules(merchant: Identity<Merchant>) -> Result<HttpResponse, Error> {
+    if !merchant.is_admin {
+        return Err(Error::AdminRequired);
+    }
+    Ok(HttpResponse::Ok()
+        .content_type("text/yaml")
+        .body(alerts::prometheus_rules(&ALERT_CONFIG)))
+}
+
+/// Cancels stale wallet transactions now instead of waiting for the
+/// nightly run
+pub fn cancel_stale_transactions(
+    (merchant, req): (Identity<Merchant>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let admin_id = merchant.id.clone();
+    let cancelled =
+        reconciliation::cancel_stale(req.state().db.clone(), req.state().wallet.clone());
+    Box::new(
+        compat::to_01(async move {
+            let cancelled = cancelled.compat().await?;
+            info!(
+                "{} cancelled {} stale wallet transactions",
+                admin_id, cancelled
+            );
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", "/admin/reconciliation")
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
+
+/// `days` is how many days back, today included, the numbers cover
+#[derive(Debug, Deserialize)]
+pub struct AnalyticsQuery {
+    pub days: Option<i64>,
+    pub granularity: Option<Granularity>,
+    pub limit: Option<i64>,
+}
+
+impl AnalyticsQuery {
+    fn since(&self) -> NaiveDate {
+        let days = self
+            .days
+            .unwrap_or(DEFAULT_ANALYTICS_DAYS)
+            .max(1)
+            .min(MAX_ANALYTICS_DAYS);
+        Utc::now().naive_utc().date() - Duration::days(days - 1)
+    }
+}
+
+/// Created and confirmed payments and confirmed volume by hour or day
+pub fn analytics_volume(
+    (merchant, query, req): (
+        Identity<Merchant>,
+        Query<AnalyticsQuery>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let buckets = db
+                .send(GetAnalyticsVolume {
+                    granularity: query.granularity.unwrap_or(Granularity::Day),
+                    since: query.since(),
+                })
+                .compat()
+                .await??;
+            Ok::<_, Error>(HttpResponse::Ok().json(buckets))
+        })
+        .from_err(),
+    )
+}
+
+/// Created payments by weekday and hour of the day
+pub fn analytics_heatmap(
+    (merchant, query, req): (
+        Identity<Merchant>,
+        Query<AnalyticsQuery>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let cells = db
+                .send(GetPaymentsHeatmap {
+                    since: query.since(),
+                })
+                .compat()
+                .await??;
+            Ok::<_, Error>(HttpResponse::Ok().json(cells))
+        })
+        .from_err(),
+    )
+}
+
+pub fn analytics_merchants(
+    (merchant, query, req): (
+        Identity<Merchant>,
+        Query<AnalyticsQuery>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let merchants = db
+                .send(GetTopMerchants {
+                    since: query.since(),
+                    limit: query
+                        .limit
+                        .unwrap_or(DEFAULT_TOP_MERCHANTS)
+                        .max(1)
+                        .min(MAX_TOP_MERCHANTS),
+                })
+                .compat()
+                .await??;
+            Ok::<_, Error>(HttpResponse::Ok().json(merchants))
+        })
+        .from_err(),
+    )
+}
+
+/// Conversion, confirmation latency and callback success rate
+pub fn analytics_summary(
+    (merchant, query, req): (
+        Identity<Merchant>,
+        Query<AnalyticsQuery>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let since = query.since();
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let totals = db.send(GetAnalyticsTotals { since }).compat().await??;
+            Ok::<_, Error>(HttpResponse::Ok().json(AnalyticsSummary::new(since, totals)))
+        })
+        .from_err(),
+    )
+}
+
+/// Which slate versions and wallets buyers pay with, to know who is left
+/// before dropping an old slate format
+pub fn analytics_wallets(
+    (merchant, query, req): (
+        Identity<Merchant>,
+        Query<AnalyticsQuery>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let wallets = db
+                .send(GetPayerWallets {
+                    since: query.since(),
+                })
+                .compat()
+                .await??;
+            Ok::<_, Error>(HttpResponse::Ok().json(wallets))
+        })
+        .from_err(),
+    )
+}
+
+/// Payments by the buyer's country, see `geoip`
+pub fn analytics_countries(
+    (merchant, query, req): (
+        Identity<Merchant>,
+        Query<AnalyticsQuery>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let countries = db
+                .send(GetPaymentCountries {
+                    since: query.since(),
+                })
+                .compat()
+                .await??;
+            Ok::<_, Error>(HttpResponse::Ok().json(countries))
+        })
+        .from_err(),
+    )
+}
+
+/// Merchants whose callbacks are behind
+pub fn analytics_unreported(
+    (merchant, req): (Identity<Merchant>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let unreported = db.send(GetUnreportedSummary).compat().await??;
+            Ok::<_, Error>(HttpResponse::Ok().json(unreported))
+        })
+        .from_err(),
+    )
+}
+
+/// Transfer fees charged for payouts against what the wallet paid
+pub fn analytics_fees(
+    (merchant, query, req): (
+        Identity<Merchant>,
+        Query<AnalyticsQuery>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let since = query.since();
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let buckets = db
+                .send(GetFeeBuckets {
+                    granularity: query.granularity.unwrap_or(Granularity::Day),
+                    since,
+                })
+                .compat()
+                .await??;
+            Ok::<_, Error>(HttpResponse::Ok().json(FeeReport::new(since, buckets)))
+        })
+        .from_err(),
+    )
+}
+
+/// Payouts the wallet paid a higher fee for than the merchant was charged
+pub fn analytics_underpriced_payouts(
+    (merchant, query, req): (
+        Identity<Merchant>,
+        Query<AnalyticsQuery>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let payouts = db
+                .send(GetUnderpricedPayouts {
+                    since: query.since(),
+                    limit: query
+                        .limit
+                        .unwrap_or(DEFAULT_UNDERPRICED_PAYOUTS)
+                        .max(1)
+                        .min(MAX_UNDERPRICED_PAYOUTS),
+                })
+                .compat()
+                .await??;
+            Ok::<_, Error>(HttpResponse::Ok().json(payouts))
+        })
+        .from_err(),
+    )
+}
+
+#[derive(Template)]
+#[template(path = "admin/wallet.html")]
+struct WalletTemplate<'a> {
+    unspent: usize,
+    locked: usize,
+    config: &'a OutputsConfig,
+    /// `None` until the wallet answered a version check
+    capabilities: Option<WalletCapabilities>,
+}
+
+/// Output counts of the wallet and its output selection settings
+pub fn wallet_outputs(
+    (merchant, req): (Identity<Merchant>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let wallet = req.state().wallet.clone();
+    Box::new(
+        compat::to_01(async move {
+            let outputs = wallet.retrieve_outputs().compat().await?;
+            let count = |status| {
+                outputs
+                    .iter()
+                    .filter(|output| output.status == status)
+                    .count()
+            };
+            let html = WalletTemplate {
+                unspent: count(OutputStatus::Unspent),
+                locked: count(OutputStatus::Locked),
+                config: wallet.outputs_config(),
+                capabilities: wallet_version::current(),
+            }
+            .render()
+            .map_err(|e| Error::from(e))?;
+            Ok::<_, Error>(HttpResponse::Ok().content_type("text/html").body(html))
+        })
+        .from_err(),
+    )
+}
+
+/// Sends the wallet's outputs to itself, allowed only above the
+/// consolidation threshold
+pub fn consolidate_outputs(
+    (merchant, req): (Identity<Merchant>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let wallet = req.state().wallet.clone();
+    let threshold = wallet.outputs_config().consolidation_threshold;
+    let admin_id = merchant.id.clone();
+    Box::new(
+        compat::to_01(async move {
+            let outputs = wallet.retrieve_outputs().compat().await?;
+            let unspent = outputs
+                .iter()
+                .filter(|output| output.status == OutputStatus::Unspent)
+                .count();
+            if unspent <= threshold {
+                return Err(Error::InvalidEntity(format!(
+                    "wallet has {} unspent outputs, consolidation starts above {}",
+                    unspent, threshold
+                )));
+            }
+            let slate = wallet.consolidate().compat().await?;
+            info!(
+                "{} consolidated {} wallet outputs in transaction {}",
+                admin_id, unspent, slate.id
+            );
+            Ok(HttpResponse::Found()
+                .header("location", "/admin/wallet")
+                .finish())
+        })
+        .from_err(),
+    )
+}
+
+#[derive(Template)]
+#[template(path = "admin/chain.html")]
+struct ChainTemplate {
+    current_height: i64,
+    blocks: Vec<BlockHeader>,
+}
+
+/// Height we synced to and the latest synced blocks
+pub fn chain_status(
+    (merchant, req): (Identity<Merchant>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let current_height = db.send(GetCurrentHeight).compat().await??;
+            let blocks = db
+                .send(GetLatestBlocks {
+                    limit: CHAIN_STATUS_BLOCKS,
+                })
+                .compat()
+                .await??;
+            let html = ChainTemplate {
+                current_height,
+                blocks,
+            }
+            .render()
+            .map_err(|e| Error::from(e))?;
+            Ok::<_, Error>(HttpResponse::Ok().content_type("text/html").body(html))
+        })
+        .from_err(),
+    )
+}
+
+/// Matches outputs of already synced blocks again to recover payments
+/// the sync missed, `current_height` doesn't move
+pub fn replay_blocks(
+    (merchant, query, req): (
+        Identity<Merchant>,
+        Query<ReplayBlocks>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let query = query.into_inner();
+    info!(
+        "{} replays blocks {} to {}",
+        merchant.id, query.from, query.to
+    );
+    let cron = req.state().cron.clone();
+    Box::new(
+        compat::to_01(async move {
+            let replayed = cron.send(query).compat().await??;
+            Ok::<_, Error>(HttpResponse::Ok().json(replayed))
+        })
+        .from_err(),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TransitionForm {
+    pub status: TransactionStatus,
+    pub justification: String,
+    /// Id of the transaction typed again
+    pub confirm: String,
+}
+
+/// Moves a stuck payment to another status, e.g. one which is verifiably
+/// in chain. Only the transitions of `TransactionStatus::manual_transitions`
+/// are allowed, the justification is added to the payment's notes.
+pub fn transition_transaction(
+    (merchant, transaction_id, form, req): (
+        Identity<Merchant>,
+        Path<Uuid>,
+        Form<TransitionForm>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let transaction_id = transaction_id.into_inner();
+    let form = form.into_inner();
+    if form.confirm.trim() != transaction_id.to_string() {
+        return Box::new(err(Error::InvalidEntity(s!(
+            "type the transaction id to confirm the change"
+        ))
+        .into()));
+    }
+    let admin_id = merchant.id.clone();
+    let fsm = req.state().fsm.clone();
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let transaction = db
+                .send(ManualTransition {
+                    transaction_id,
+                    admin_id: admin_id.clone(),
+                    status: form.status,
+                    justification: form.justification,
+                })
+                .compat()
+                .await??;
+            fsm.do_send(Publish(vec![transaction.clone()]));
+            metrics::inc(
+                "manual_transitions_total",
+                &[("status", &transaction.status.to_string())],
+            );
+            warn!(
+                "{} manually moved transaction {} to {}",
+                admin_id, transaction.id, transaction.status
+            );
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", format!("/transactions/{}", transaction.id))
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PendingCreditsForm {
+    pub action: CreditAction,
+    pub justification: String,
+}
+
+/// Holds the pending credits of a disputed payment so they don't clear,
+/// releases held ones or voids them, see `clearing`. The justification is
+/// added to the payment's notes.
+pub fn change_pending_credits(
+    (merchant, transaction_id, form, req): (
+        Identity<Merchant>,
+        Path<Uuid>,
+        Form<PendingCreditsForm>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let transaction_id = transaction_id.into_inner();
+    let form = form.into_inner();
+    let admin_id = merchant.id.clone();
+    let action = form.action;
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let credits = db
+                .send(ChangePendingCredits {
+                    transaction_id,
+                    admin_id: admin_id.clone(),
+                    action,
+                    justification: form.justification,
+                })
+                .compat()
+                .await??;
+            warn!(
+                "{} {} {} pending credits of transaction {}",
+                admin_id,
+                action,
+                credits.len(),
+                transaction_id
+            );
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", format!("/transactions/{}", transaction_id))
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
+
+#[derive(Template)]
+#[template(path = "admin/invite_codes.html")]
+struct InviteCodesTemplate {
+    invite_codes: Vec<InviteCode>,
+    now: NaiveDateTime,
+}
+
+/// Invite codes merchants register with under the `invite` policy
+pub fn invite_codes(
+    (merchant, req): (Identity<Merchant>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let invite_codes = db.send(GetInviteCodes).compat().await??;
+            let html = InviteCodesTemplate {
+                invite_codes,
+                now: Utc::now().naive_utc(),
+            }
+            .render()
+            .map_err(|e| Error::from(e))?;
+            Ok::<_, Error>(HttpResponse::Ok().content_type("text/html").body(html))
+        })
+        .from_err(),
+    )
+}
+
+/// `expires_in_days` is empty for codes which don't expire
+#[derive(Debug, Deserialize)]
+pub struct InviteCodeForm {
+    pub max_uses: i32,
+    pub expires_in_days: String,
+}
+
+pub fn create_invite_code(
+    (merchant, form, req): (
+        Identity<Merchant>,
+        Form<InviteCodeForm>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    if form.max_uses < 1 {
+        return Box::new(err(Error::InvalidEntity(s!(
+            "invite code should allow at least one registration"
+        ))
+        .into()));
+    }
+    let expires_in_days = match form.expires_in_days.trim() {
+        "" => None,
+        days => match days.parse::<i64>() {
+            Ok(days) if days > 0 => Some(days),
+            _ => {
+                return Box::new(err(Error::InvalidEntity(s!(
+                    "expiry should be a positive number of days"
+                ))
+                .into()));
+            }
+        },
+    };
+    let now = Utc::now().naive_utc();
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            db.send(CreateInviteCode(InviteCode {
+                code: new_invite_code(),
+                max_uses: form.max_uses,
+                uses: 0,
+                expires_at: expires_in_days.map(|days| now + Duration::days(days)),
+                created_by: merchant.id.clone(),
+                created_at: now,
+            }))
+            .compat()
+            .await??;
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", "/admin/invite_codes")
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
+
+pub fn delete_invite_code(
+    (merchant, code, req): (Identity<Merchant>, Path<String>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let code = code.into_inner();
+    info!("{} deleted invite code {}", merchant.id, code);
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            db.send(DeleteInviteCode { code }).compat().await??;
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", "/admin/invite_codes")
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
+
+/// `denied` is `None` for networks of `PAYMENT_DENY_LIST`
+struct DenyListRow {
+    network: String,
+    denied: Option<DeniedNetwork>,
+    /// Requests this instance refused since it started
+    refused: u64,
+}
+
+impl DenyListRow {
+    fn new(network: String, denied: Option<DeniedNetwork>) -> Self {
+        let refused = metrics::get("denied_requests_total", &[("network", &network)]);
+        DenyListRow {
+            network,
+            denied,
+            refused,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "admin/deny_list.html")]
+struct DenyListTemplate {
+    rows: Vec<DenyListRow>,
+}
+
+/// Networks whose buyers can't submit payments
+pub fn deny_list(
+    (merchant, req): (Identity<Merchant>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let denied = db.send(GetDeniedNetworks).compat().await??;
+            let rows = deny_list::CONFIGURED
+                .iter()
+                .map(|network| DenyListRow::new(network.to_string(), None))
+                .chain(
+                    denied
+                        .into_iter()
+                        .map(|denied| DenyListRow::new(denied.network.clone(), Some(denied))),
+                )
+                .collect();
+            let html = DenyListTemplate { rows }
+                .render()
+                .map_err(|e| Error::from(e))?;
+            Ok::<_, Error>(HttpResponse::Ok().content_type("text/html").body(html))
+        })
+        .from_err(),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeniedNetworkForm {
+    pub network: String,
+    #[serde(default)]
+    pub reason: String,
+}
+
+pub fn create_denied_network(
+    (merchant, form, req): (
+        Identity<Merchant>,
+        Form<DeniedNetworkForm>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let network: Network = match form.network.parse() {
+        Ok(network) => network,
+        Err(e) => return Box::new(err(e.into())),
+    };
+    let denied = CreateDeniedNetwork(DeniedNetwork {
+        network: network.to_string(),
+        reason: form.reason.trim().to_owned(),
+        created_by: merchant.id.clone(),
+        created_at: Utc::now().naive_utc(),
+    });
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            db.send(denied).compat().await??;
+            // Other instances pick it up on their next reload
+            deny_list::reload(db).compat().await?;
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", "/admin/deny_list")
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
+
+pub fn delete_denied_network(
+    (merchant, form, req): (
+        Identity<Merchant>,
+        Form<DeniedNetworkForm>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let network = form.into_inner().network;
+    info!("{} allowed network {} again", merchant.id, network);
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            db.send(DeleteDeniedNetwork { network }).compat().await??;
+            deny_list::reload(db).compat().await?;
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", "/admin/deny_list")
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
+
+struct FeatureFlagRow {
+    name: String,
+    description: &'static str,
+    enabled: bool,
+    rollout_percent: i32,
+    /// `None` while the flag is at its default
+    state: Option<FeatureFlag>,
+    overrides: Vec<FeatureFlagOverride>,
+}
+
+#[derive(Template)]
+#[template(path = "admin/feature_flags.html")]
+struct FeatureFlagsTemplate {
+    rows: Vec<FeatureFlagRow>,
+}
+
+/// Flags with their overrides
+pub fn feature_flags(
+    (merchant, req): (Identity<Merchant>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let (flags, overrides) = db.send(GetFeatureFlags).compat().await??;
+            let rows = Flag::ALL
+                .iter()
+                .map(|flag| {
+                    let name = flag.to_string();
+                    let state = flags.iter().find(|state| state.name == name).cloned();
+                    FeatureFlagRow {
+                        description: flag.description(),
+                        enabled: state
+                            .as_ref()
+                            .map_or(flag.default_enabled(), |state| state.enabled),
+                        rollout_percent: state.as_ref().map_or(100, |state| state.rollout_percent),
+                        state,
+                        overrides: overrides
+                            .iter()
+                            .filter(|o| o.name == name)
+                            .cloned()
+                            .collect(),
+                        name,
+                    }
+                })
+                .collect();
+            let html = FeatureFlagsTemplate { rows }
+                .render()
+                .map_err(|e| Error::from(e))?;
+            Ok::<_, Error>(HttpResponse::Ok().content_type("text/html").body(html))
+        })
+        .from_err(),
+    )
+}
+
+fn parse_flag(name: &str) -> Result<Flag, Error> {
+    name.parse()
+        .map_err(|_| Error::InvalidEntity(format!("unknown flag {}", name)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FeatureFlagForm {
+    pub name: String,
+    /// Checkbox, missing when unchecked
+    #[serde(default)]
+    pub enabled: Option<String>,
+    pub rollout_percent: i32,
+}
+
+pub fn set_feature_flag(
+    (merchant, form, req): (
+        Identity<Merchant>,
+        Form<FeatureFlagForm>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let flag = match parse_flag(&form.name) {
+        Ok(flag) => flag,
+        Err(e) => return Box::new(err(e.into())),
+    };
+    if form.rollout_percent < 0 || form.rollout_percent > 100 {
+        return Box::new(err(Error::InvalidEntity(s!(
+            "rollout must be from 0 to 100 percent"
+        ))
+        .into()));
+    }
+    let set_flag = SetFeatureFlag(FeatureFlag {
+        name: flag.to_string(),
+        enabled: form.enabled.is_some(),
+        rollout_percent: form.rollout_percent,
+        updated_by: merchant.id.clone(),
+        updated_at: Utc::now().naive_utc(),
+    });
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            db.send(set_flag).compat().await??;
+            // Other instances pick it up on their next reload
+            feature_flags::reload(db).compat().await?;
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", "/admin/feature_flags")
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FeatureFlagOverrideForm {
+    pub name: String,
+    pub merchant_id: String,
+    /// Not needed to delete the override
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+pub fn set_feature_flag_override(
+    (merchant, form, req): (
+        Identity<Merchant>,
+        Form<FeatureFlagOverrideForm>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let flag = match parse_flag(&form.name) {
+        Ok(flag) => flag,
+        Err(e) => return Box::new(err(e.into())),
+    };
+    let set_override = SetFeatureFlagOverride(FeatureFlagOverride {
+        name: flag.to_string(),
+        merchant_id: form.merchant_id.trim().to_owned(),
+        enabled: form.enabled,
+        updated_by: merchant.id.clone(),
+        updated_at: Utc::now().naive_utc(),
+    });
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            db.send(set_override).compat().await??;
+            feature_flags::reload(db).compat().await?;
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", "/admin/feature_flags")
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
+
+pub fn delete_feature_flag_override(
+    (merchant, form, req): (
+        Identity<Merchant>,
+        Form<FeatureFlagOverrideForm>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let form = form.into_inner();
+    info!(
+        "{} removed the override of flag {} for {}",
+        merchant.id, form.name, form.merchant_id
+    );
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            db.send(DeleteFeatureFlagOverride {
+                name: form.name,
+                merchant_id: form.merchant_id,
+            })
+            .compat()
+            .await??;
+            feature_flags::reload(db).compat().await?;
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", "/admin/feature_flags")
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
+
+/// For merchants who lost their second factor, they set up TOTP again on
+/// the next login and are emailed about the reset
+pub fn reset_2fa(
+    (merchant, merchant_id, req): (Identity<Merchant>, Path<String>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let merchant_id = merchant_id.into_inner();
+    warn!("{} reset 2FA of merchant {}", merchant.id, merchant_id);
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            db.send(Reset2FA { merchant_id }).compat().await??;
+            Ok::<_, Error>(HttpResponse::NoContent().finish())
+        })
+        .from_err(),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReferralForm {
+    pub referrer_id: String,
+    pub percent: String,
+}
+
+/// Attributes a merchant to its referrer, an empty referrer removes the
+/// attribution, see `referrals`
+pub fn set_referral(
+    (merchant, merchant_id, form, req): (
+        Identity<Merchant>,
+        Path<String>,
+        Form<ReferralForm>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    if !merchant.is_admin {
+        return Box::new(err(Error::AdminRequired.into()));
+    }
+    let merchant_id = merchant_id.into_inner();
+    let referrer_id = form.referrer_id.trim().to_owned();
+    let referral = if referrer_id.is_empty() {
+        None
+    } else {
+        match form.percent.trim().parse::<Decimal>() {
+            Ok(percent) => Some((referrer_id, percent)),
+            Err(_) => return Box::new(err(Error::InvalidEntity(s!("percent")).into())),
+        }
+    };
+    info!(
+        "{} set the referral of merchant {} to {:?}",
+        merchant.id, merchant_id, referral
+    );
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            db.send(SetReferral {
+                merchant_id,
+                referral,
+            })
+            .compat()
+            .await??;
+            Ok::<_, Error>(HttpResponse::NoContent().finish())
+        })
+        .from_err(),
+    )
+}
@@ -0,0 +1,136 @@
+use crate::app::AppState;
+use crate::compat::{self, Future01CompatExt};
+use crate::db::{CreateApiToken, DbExecutor, DeleteApiToken, GetApiTokens};
+use crate::errors::*;
+use crate::extractor::Identity;
+use crate::filters;
+use crate::models::{ApiScope, ApiToken, Merchant};
+use actix::Addr;
+use actix_web::{Form, FutureResponse, HttpRequest, HttpResponse, Path};
+use askama::Template;
+use chrono::Utc;
+use data_encoding::BASE64URL_NOPAD;
+use futures::future::{err, Future};
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+use uuid::Uuid;
+
+#[derive(Template)]
+#[template(path = "api_tokens.html")]
+struct ApiTokensTemplate<'a> {
+    merchant: &'a Merchant,
+    tokens: Vec<ApiToken>,
+    scopes: &'a [ApiScope],
+    /// Key of a just created token, it's shown only once
+    new_token: Option<String>,
+}
+
+async fn render(
+    db: Addr<DbExecutor>,
+    merchant: Merchant,
+    new_token: Option<String>,
+) -> Result<HttpResponse, Error> {
+    let tokens = db
+        .send(GetApiTokens {
+            merchant_id: merchant.id.clone(),
+        })
+        .compat()
+        .await??;
+    let html = ApiTokensTemplate {
+        merchant: &merchant,
+        tokens,
+        scopes: &ApiScope::ALL,
+        new_token,
+    }
+    .render()
+    .map_err(|e| Error::from(e))?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+pub fn api_tokens(
+    (merchant, req): (Identity<Merchant>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let db = req.state().db.clone();
+    Box::new(compat::to_01(render(db, merchant.into_inner(), None)).from_err())
+}
+
+/// Checked scopes come as `scope_name=on`, unchecked ones are missing
+#[derive(Debug, Deserialize)]
+pub struct ApiTokenForm {
+    pub name: String,
+    pub create_payments: Option<String>,
+    pub read_payments: Option<String>,
+    pub create_payouts: Option<String>,
+    pub read_stats: Option<String>,
+}
+
+impl ApiTokenForm {
+    fn scopes(&self) -> Vec<String> {
+        vec![
+            (ApiScope::CreatePayments, &self.create_payments),
+            (ApiScope::ReadPayments, &self.read_payments),
+            (ApiScope::CreatePayouts, &self.create_payouts),
+            (ApiScope::ReadStats, &self.read_stats),
+        ]
+        .into_iter()
+        .filter(|(_, checked)| checked.is_some())
+        .map(|(scope, _)| scope.to_string())
+        .collect()
+    }
+}
+
+pub fn create(
+    (merchant, req, form): (
+        Identity<Merchant>,
+        HttpRequest<AppState>,
+        Form<ApiTokenForm>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let merchant = merchant.into_inner();
+    let scopes = form.scopes();
+    if scopes.is_empty() {
+        return Box::new(err(Error::InvalidEntity(s!(
+            "token should have at least one scope"
+        ))
+        .into()));
+    }
+    let token = BASE64URL_NOPAD.encode(&thread_rng().gen::<[u8; 32]>());
+    let api_token = ApiToken {
+        id: Uuid::new_v4(),
+        merchant_id: merchant.id.clone(),
+        name: form.into_inner().name,
+        token_hash: ApiToken::hash(&token),
+        scopes,
+        created_at: Utc::now().naive_utc(),
+    };
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            db.send(CreateApiToken(api_token)).compat().await??;
+            render(db, merchant, Some(token)).await
+        })
+        .from_err(),
+    )
+}
+
+pub fn delete(
+    (merchant, req, token_id): (Identity<Merchant>, HttpRequest<AppState>, Path<Uuid>),
+) -> FutureResponse<HttpResponse> {
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            db.send(DeleteApiToken {
+                id: token_id.into_inner(),
+                merchant_id: merchant.into_inner().id,
+            })
+            .compat()
+            .await??;
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", "/api_tokens")
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
@@ -0,0 +1,122 @@
+use crate::app::AppState;
+use crate::callback::{
+    CallbackSettings, RetryPolicy, DEFAULT_RETRY_POLICY, MAX_CALLBACK_ATTEMPTS,
+    MAX_CALLBACK_BACKOFF_SECONDS, MAX_CALLBACK_HEADERS, MAX_CALLBACK_RETRY_WINDOW_SECONDS,
+    MAX_CALLBACK_TIMEOUT_SECONDS,
+};
+use crate::callback_template::{self, MAX_TEMPLATE_LENGTH};
+use crate::compat::{self, Future01CompatExt};
+use crate::db::UpdateCallbackSettings;
+use crate::errors::*;
+use crate::extractor::Identity;
+use crate::models::Merchant;
+use actix_web::{Form, FutureResponse, HttpRequest, HttpResponse};
+use askama::Template;
+use futures::future::{err, Future};
+use serde::Deserialize;
+
+#[derive(Template)]
+#[template(path = "callback_settings.html")]
+struct CallbackSettingsTemplate<'a> {
+    callback_url: &'a Option<String>,
+    payout_callback_url: &'a Option<String>,
+    settings: CallbackSettings,
+    max_timeout_seconds: i32,
+    max_headers: usize,
+    max_template_length: usize,
+    /// Shown as placeholders of the retry overrides
+    default_retry: RetryPolicy,
+    max_attempts: i32,
+    max_backoff_seconds: i32,
+    max_retry_window_seconds: i32,
+}
+
+pub fn callback_settings(merchant: Identity<Merchant>) -> Result<HttpResponse, Error> {
+    let html = CallbackSettingsTemplate {
+        callback_url: &merchant.callback_url,
+        payout_callback_url: &merchant.payout_callback_url,
+        settings: CallbackSettings::of(&merchant),
+        max_timeout_seconds: MAX_CALLBACK_TIMEOUT_SECONDS,
+        max_headers: MAX_CALLBACK_HEADERS,
+        max_template_length: MAX_TEMPLATE_LENGTH,
+        default_retry: *DEFAULT_RETRY_POLICY,
+        max_attempts: MAX_CALLBACK_ATTEMPTS,
+        max_backoff_seconds: MAX_CALLBACK_BACKOFF_SECONDS,
+        max_retry_window_seconds: MAX_CALLBACK_RETRY_WINDOW_SECONDS,
+    }
+    .render()?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackSettingsForm {
+    pub timeout_seconds: i32,
+    pub headers: String,
+    /// Checkbox, only sent when ticked
+    pub verify_tls: Option<String>,
+    /// Empty to send callbacks as they are
+    pub template: String,
+    /// Checkbox, only sent when ticked
+    pub verbose: Option<String>,
+    /// Retry overrides, empty to keep the defaults
+    pub max_attempts: String,
+    pub backoff_seconds: String,
+    pub retry_window_seconds: String,
+}
+
+pub fn update_callback_settings(
+    (merchant, req, form): (
+        Identity<Merchant>,
+        HttpRequest<AppState>,
+        Form<CallbackSettingsForm>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let form = form.into_inner();
+    let headers = match CallbackSettings::parse_headers(&form.headers) {
+        Ok(headers) => headers,
+        Err(e) => return Box::new(err(e.into())),
+    };
+    let template = match callback_template::parse(&form.template) {
+        Ok(template) => template,
+        Err(e) => return Box::new(err(e.into())),
+    };
+    let retry = CallbackSettings::parse_override("max attempts", &form.max_attempts).and_then(
+        |max_attempts| {
+            Ok((
+                max_attempts,
+                CallbackSettings::parse_override("retry backoff", &form.backoff_seconds)?,
+                CallbackSettings::parse_override("retry window", &form.retry_window_seconds)?,
+            ))
+        },
+    );
+    let (max_attempts, backoff_seconds, retry_window_seconds) = match retry {
+        Ok(retry) => retry,
+        Err(e) => return Box::new(err(e.into())),
+    };
+    let update = UpdateCallbackSettings {
+        merchant_id: merchant.into_inner().id,
+        settings: CallbackSettings {
+            timeout_seconds: form.timeout_seconds,
+            headers,
+            verify_tls: form.verify_tls.is_some(),
+            template,
+            verbose: form.verbose.is_some(),
+            max_attempts,
+            backoff_seconds,
+            retry_window_seconds,
+            min_backoff_seconds: 0,
+        },
+    };
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            db.send(update).compat().await??;
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", "/callback_settings")
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
@@ -0,0 +1,129 @@
+//! Buyer facing routes under `/checkout/{token}`, they resolve the checkout
+//! token to the payment. Payments aren't served by id to buyers.
+
+use crate::app::AppState;
+use crate::checkout::CheckoutToken;
+use crate::errors::*;
+use crate::extractor::SimpleJson;
+use crate::feature_flags::{self, Flag};
+use crate::foreign_api;
+use crate::handlers::payment::{self, ReceiptEmailForm};
+use crate::i18n::{self, Language};
+use actix_web::http::header;
+use actix_web::{Form, FutureResponse, HttpRequest, HttpResponse, Path};
+use askama::Template;
+use chrono::Utc;
+use futures::future::{err, ok};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct CheckoutPath {
+    pub token: String,
+}
+
+impl CheckoutPath {
+    fn decode(&self) -> Result<CheckoutToken, Error> {
+        CheckoutToken::decode(&self.token, Utc::now().timestamp())
+    }
+}
+
+#[derive(Template)]
+#[template(path = "checkout_link.html")]
+struct CheckoutLinkTemplate {
+    expired: bool,
+    message: &'static str,
+}
+
+/// Payment page, or what to do about the link when the token is expired or
+/// invalid
+pub fn get_checkout(
+    (path, req): (Path<CheckoutPath>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let e = match path.decode() {
+        Ok(token) => return payment::payment_page(token.transaction_id, token, &req),
+        Err(e) => e,
+    };
+    let language = Language::of(&req);
+    let expired = match e {
+        Error::CheckoutExpired => true,
+        _ => false,
+    };
+    let html = match (CheckoutLinkTemplate {
+        expired,
+        message: i18n::message(e.code(), language),
+    })
+    .render()
+    {
+        Ok(html) => html,
+        Err(e) => return Box::new(err(Error::from(e).into())),
+    };
+    let mut resp = if expired {
+        HttpResponse::Gone()
+    } else {
+        HttpResponse::NotFound()
+    };
+    Box::new(ok(resp
+        .header(header::CONTENT_LANGUAGE, language.to_string())
+        .content_type("text/html")
+        .body(html)))
+}
+
+pub fn get_checkout_status(
+    (path, req): (Path<CheckoutPath>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    match path.decode() {
+        Ok(token) => payment::payment_status(token.transaction_id, None, &req),
+        Err(e) => i18n::localize(Language::of(&req), err(e)),
+    }
+}
+
+pub fn requote_checkout(
+    (path, req): (Path<CheckoutPath>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    match path.decode() {
+        Ok(token) => payment::requote(token.transaction_id, None, &req),
+        Err(e) => i18n::localize(Language::of(&req), err(e)),
+    }
+}
+
+pub fn set_checkout_receipt_email(
+    (path, form, req): (
+        Path<CheckoutPath>,
+        Form<ReceiptEmailForm>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    match path.decode() {
+        // Back to the page with the token it was opened with
+        Ok(token) => payment::receipt_email(
+            token.transaction_id,
+            form.into_inner(),
+            format!("/checkout/{}", path.token),
+            &req,
+        ),
+        Err(e) => i18n::localize(Language::of(&req), err(e)),
+    }
+}
+
+pub fn make_checkout_payment(
+    (slate, path, req): (
+        SimpleJson<serde_json::Value>,
+        Path<CheckoutPath>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse, Error> {
+    let transaction_id = path.decode().map(|token| token.transaction_id);
+    payment::receive_payment(slate.into_inner(), transaction_id, &req)
+}
+
+pub fn checkout_foreign_rpc(
+    (body, path, req): (String, Path<CheckoutPath>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse, Error> {
+    if !feature_flags::is_enabled(Flag::ForeignApi, None) {
+        return Box::new(ok(HttpResponse::NotFound().finish()));
+    }
+    // An expired link still answers check_version, receive_tx tells the
+    // buyer to get a fresh one
+    let transaction_id = path.decode().map(|token| token.transaction_id);
+    payment::foreign_api_request(foreign_api::parse_request(&body), transaction_id, &req)
+}
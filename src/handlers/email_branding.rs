@@ -0,0 +1,115 @@
+use crate::app::AppState;
+use crate::compat::{self, Future01CompatExt};
+use crate::db::UpdateEmailBranding;
+use crate::errors::*;
+use crate::extractor::Identity;
+use crate::mailer::{self, EmailBranding};
+use crate::models::Merchant;
+use actix_web::{Form, FutureResponse, HttpRequest, HttpResponse};
+use askama::Template;
+use futures::future::{err, Future};
+use serde::Deserialize;
+
+#[derive(Template)]
+#[template(path = "email_branding.html")]
+struct EmailBrandingTemplate<'a> {
+    logo_url: &'a str,
+    footer: &'a str,
+    reply_to: &'a str,
+    payment_reminder_minutes: String,
+}
+
+fn setting(value: &Option<String>) -> &str {
+    value.as_ref().map(|value| value.as_str()).unwrap_or("")
+}
+
+pub fn email_branding(merchant: Identity<Merchant>) -> Result<HttpResponse, Error> {
+    let html = EmailBrandingTemplate {
+        logo_url: setting(&merchant.email_logo_url),
+        footer: setting(&merchant.email_footer),
+        reply_to: setting(&merchant.email_reply_to),
+        payment_reminder_minutes: merchant
+            .payment_reminder_minutes
+            .map(|minutes| minutes.to_string())
+            .unwrap_or_default(),
+    }
+    .render()?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+/// Empty fields come as empty strings, they clear the setting
+#[derive(Debug, Deserialize)]
+pub struct EmailBrandingForm {
+    pub logo_url: String,
+    pub footer: String,
+    pub reply_to: String,
+    /// Empty sends no reminders
+    pub payment_reminder_minutes: String,
+}
+
+fn non_empty(value: String) -> Option<String> {
+    let value = value.trim();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_owned())
+    }
+}
+
+fn reminder_minutes(value: String) -> Result<Option<i32>, Error> {
+    let minutes = match non_empty(value) {
+        Some(value) => Some(value.parse::<i32>().map_err(|_| {
+            Error::InvalidEntity(s!("payment reminder minutes should be a number"))
+        })?),
+        None => None,
+    };
+    mailer::validate_reminder_minutes(minutes)?;
+    Ok(minutes)
+}
+
+pub fn update_email_branding(
+    (merchant, req, form): (
+        Identity<Merchant>,
+        HttpRequest<AppState>,
+        Form<EmailBrandingForm>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let form = form.into_inner();
+    let branding = EmailBranding {
+        logo_url: non_empty(form.logo_url),
+        footer: non_empty(form.footer),
+        reply_to: non_empty(form.reply_to),
+    };
+    if let Err(e) = branding.validate() {
+        return Box::new(err(e.into()));
+    }
+    let payment_reminder_minutes = match reminder_minutes(form.payment_reminder_minutes) {
+        Ok(minutes) => minutes,
+        Err(e) => return Box::new(err(e.into())),
+    };
+    let update = UpdateEmailBranding {
+        merchant_id: merchant.into_inner().id,
+        logo_url: branding.logo_url,
+        footer: branding.footer,
+        reply_to: branding.reply_to,
+        payment_reminder_minutes,
+    };
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            db.send(update).compat().await??;
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", "/email_branding")
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
+
+/// Sample payment confirmation as the merchant's buyers would get it
+pub fn preview(merchant: Identity<Merchant>) -> Result<HttpResponse, Error> {
+    let html = mailer::preview(&merchant)?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
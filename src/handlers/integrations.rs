@@ -0,0 +1,96 @@
+use crate::app::AppState;
+use crate::compat::{self, Future01CompatExt};
+use crate::db::UpdateIntegrations;
+use crate::errors::*;
+use crate::extractor::Identity;
+use crate::integrations::{Integrations, Notify, MAX_MESSAGES_PER_MINUTE};
+use crate::models::Merchant;
+use actix_web::{Form, FutureResponse, HttpRequest, HttpResponse};
+use askama::Template;
+use futures::future::{ok, Future};
+use serde::Deserialize;
+
+#[derive(Template)]
+#[template(path = "integrations.html")]
+struct IntegrationsTemplate {
+    integrations: Integrations,
+    max_messages_per_minute: usize,
+    /// Whether a test message was just sent and why it failed
+    tested: bool,
+    test_error: Option<String>,
+}
+
+fn render(
+    merchant: &Merchant,
+    test_result: Option<Result<(), String>>,
+) -> Result<HttpResponse, Error> {
+    let html = IntegrationsTemplate {
+        integrations: Integrations::of(merchant),
+        max_messages_per_minute: MAX_MESSAGES_PER_MINUTE,
+        tested: test_result.is_some(),
+        test_error: test_result.and_then(|res| res.err()),
+    }
+    .render()?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+pub fn integrations(merchant: Identity<Merchant>) -> Result<HttpResponse, Error> {
+    render(&merchant, None)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IntegrationsForm {
+    pub telegram_chat_id: String,
+    pub slack_webhook_url: String,
+}
+
+pub fn update_integrations(
+    (merchant, req, form): (
+        Identity<Merchant>,
+        HttpRequest<AppState>,
+        Form<IntegrationsForm>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let update = UpdateIntegrations {
+        merchant_id: merchant.into_inner().id,
+        integrations: Integrations::new(&form.telegram_chat_id, &form.slack_webhook_url),
+    };
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            db.send(update).compat().await??;
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", "/integrations")
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
+
+/// Sends a message to the linked chats and shows whether it went through
+pub fn test_integrations(
+    (merchant, req): (Identity<Merchant>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let merchant = merchant.into_inner();
+    if Integrations::of(&merchant).is_empty() {
+        return Box::new(ok(HttpResponse::Found()
+            .header("location", "/integrations")
+            .finish()));
+    }
+    let notifier = req.state().notifier.clone();
+    Box::new(
+        compat::to_01(async move {
+            let notifier_response = notifier
+                .send(Notify {
+                    merchant: merchant.clone(),
+                    text: format!("Test message from the payment gateway for {}", merchant.id),
+                })
+                .compat()
+                .await?;
+            render(&merchant, Some(notifier_response.map_err(|e| s!(e))))
+        })
+        .from_err(),
+    )
+}
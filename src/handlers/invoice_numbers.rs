@@ -0,0 +1,61 @@
+use crate::app::AppState;
+use crate::compat::{self, Future01CompatExt};
+use crate::db::UpdateInvoicePrefix;
+use crate::errors::*;
+use crate::extractor::Identity;
+use crate::models::{format_invoice_number, Merchant};
+use actix_web::{Form, FutureResponse, HttpRequest, HttpResponse};
+use askama::Template;
+use chrono::{Datelike, Utc};
+use futures::future::Future;
+use serde::Deserialize;
+
+#[derive(Template)]
+#[template(path = "invoice_numbers.html")]
+struct InvoiceNumbersTemplate<'a> {
+    invoice_prefix: &'a str,
+    next_invoice_number: String,
+}
+
+pub fn invoice_numbers(merchant: Identity<Merchant>) -> Result<HttpResponse, Error> {
+    let html = InvoiceNumbersTemplate {
+        invoice_prefix: &merchant.invoice_prefix,
+        next_invoice_number: format_invoice_number(
+            &merchant.invoice_prefix,
+            Utc::now().year(),
+            merchant.invoice_sequence + 1,
+        ),
+    }
+    .render()?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InvoicePrefixForm {
+    pub invoice_prefix: String,
+}
+
+pub fn update_invoice_prefix(
+    (merchant, req, form): (
+        Identity<Merchant>,
+        HttpRequest<AppState>,
+        Form<InvoicePrefixForm>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let update = UpdateInvoicePrefix {
+        merchant_id: merchant.into_inner().id,
+        invoice_prefix: form.into_inner().invoice_prefix.trim().to_owned(),
+    };
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            db.send(update).compat().await??;
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", "/invoice_numbers")
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
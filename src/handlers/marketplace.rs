@@ -0,0 +1,127 @@
+use crate::app::AppState;
+use crate::compat::{self, Future01CompatExt};
+use crate::db::{GetPaymentSplits, GetSellers, JoinPlatform};
+use crate::errors::*;
+use crate::extractor::{BasicAuth, Identity};
+use crate::filters;
+use crate::models::{ApiScope, Merchant};
+use crate::splits::SplitShare;
+use actix_web::{Form, FutureResponse, HttpRequest, HttpResponse, Path};
+use askama::Template;
+use futures::future::{err, ok, Future};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Template)]
+#[template(path = "marketplace.html")]
+struct MarketplaceTemplate<'a> {
+    merchant: &'a Merchant,
+    sellers: Vec<Merchant>,
+}
+
+pub fn marketplace(
+    (merchant, req): (Identity<Merchant>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let merchant = merchant.into_inner();
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let sellers = db
+                .send(GetSellers {
+                    platform_id: merchant.id.clone(),
+                })
+                .compat()
+                .await??;
+            let html = MarketplaceTemplate {
+                merchant: &merchant,
+                sellers,
+            }
+            .render()
+            .map_err(|e| Error::from(e))?;
+            Ok::<_, Error>(HttpResponse::Ok().content_type("text/html").body(html))
+        })
+        .from_err(),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PlatformForm {
+    pub platform_id: String,
+}
+
+/// Joins the platform, an empty id leaves the current one
+pub fn join_platform(
+    (merchant, req, form): (
+        Identity<Merchant>,
+        HttpRequest<AppState>,
+        Form<PlatformForm>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let platform_id = form.into_inner().platform_id.trim().to_owned();
+    let join_platform = JoinPlatform {
+        merchant_id: merchant.into_inner().id,
+        platform_id: if platform_id.is_empty() {
+            None
+        } else {
+            Some(platform_id)
+        },
+    };
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            db.send(join_platform).compat().await??;
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", "/marketplace")
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
+
+#[derive(Debug, Serialize)]
+struct PaymentSplitsResponse {
+    transaction_id: Uuid,
+    grin_amount: i64,
+    splits: Vec<SplitShare>,
+}
+
+pub fn get_payment_splits(
+    (merchant, path, req): (
+        BasicAuth<Merchant>,
+        Path<(String, Uuid)>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let (merchant_id, transaction_id) = path.into_inner();
+    if merchant.id != merchant_id {
+        return Box::new(ok(HttpResponse::BadRequest().finish()));
+    }
+    if let Err(e) = merchant.require(ApiScope::ReadPayments) {
+        return Box::new(err(e.into()));
+    }
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let (transaction, splits) = db
+                .send(GetPaymentSplits {
+                    merchant_id,
+                    transaction_id,
+                })
+                .compat()
+                .await??;
+            Ok::<_, Error>(
+                HttpResponse::Ok().json(PaymentSplitsResponse {
+                    transaction_id: transaction.id,
+                    grin_amount: transaction.grin_amount,
+                    splits: splits
+                        .iter()
+                        .map(|split| SplitShare::new(split, transaction.grin_amount))
+                        .collect(),
+                }),
+            )
+        })
+        .from_err(),
+    )
+}
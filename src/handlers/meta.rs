@@ -0,0 +1,101 @@
+use crate::api_version::{self, ApiVersion, VersionInfo};
+use crate::app::AppState;
+use crate::callback::{CallbackSettings, RetryPolicy, DEFAULT_RETRY_POLICY};
+use crate::errors::*;
+use crate::extractor::BasicAuth;
+use crate::models::{
+    Merchant, TransactionStatus, MAX_REQUOTES, NEW_PAYMENT_TTL_SECONDS,
+    PENDING_PAYMENT_TTL_SECONDS, RATE_LOCK_SECONDS, REQUOTE_WINDOW_SECONDS,
+    WAIT_PER_CONFIRMATION_SECONDS,
+};
+use crate::payment_state::{is_final, TransitionInfo, TRANSITIONS};
+use actix_web::{HttpRequest, HttpResponse, State};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+struct StateInfo {
+    status: TransactionStatus,
+    #[serde(rename = "final")]
+    is_final: bool,
+    /// A payment which stays this long in the status is rejected
+    ttl_seconds: Option<i64>,
+    /// Field of the payment the TTL counts from
+    ttl_since: Option<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+struct PaymentStatesResponse {
+    states: Vec<StateInfo>,
+    transitions: &'static [TransitionInfo],
+    /// The rate of a new payment is kept this long, then it can be
+    /// requoted `max_requotes` times within `requote_window_seconds`
+    rate_lock_seconds: i64,
+    requote_window_seconds: i64,
+    max_requotes: i32,
+    /// An in chain payment is expected to be confirmed within this times
+    /// its required confirmations
+    seconds_per_confirmation: i64,
+    /// Of the authenticated merchant, the defaults otherwise
+    callback_retries: RetryPolicy,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    merchant_id: Option<String>,
+}
+
+fn state(status: TransactionStatus) -> StateInfo {
+    let (ttl_seconds, ttl_since) = match status {
+        TransactionStatus::New => (Some(NEW_PAYMENT_TTL_SECONDS), Some("created_at")),
+        TransactionStatus::Pending => (Some(PENDING_PAYMENT_TTL_SECONDS), Some("updated_at")),
+        _ => (None, None),
+    };
+    StateInfo {
+        status,
+        is_final: is_final(status),
+        ttl_seconds,
+        ttl_since,
+    }
+}
+
+/// Payment states, transitions and timings in effect, with the merchant's
+/// overrides when called with their credentials
+pub fn get_payment_states(
+    (merchant, _): (Option<BasicAuth<Merchant>>, State<AppState>),
+) -> Result<HttpResponse, Error> {
+    use TransactionStatus::*;
+    let (callback_retries, merchant_id) = match merchant {
+        Some(merchant) => (
+            CallbackSettings::of(&merchant).retry_policy(),
+            Some(merchant.id.clone()),
+        ),
+        None => (*DEFAULT_RETRY_POLICY, None),
+    };
+    Ok(HttpResponse::Ok().json(PaymentStatesResponse {
+        states: [New, Pending, InChain, Confirmed, Rejected, Refund]
+            .iter()
+            .map(|status| state(*status))
+            .collect(),
+        transitions: TRANSITIONS,
+        rate_lock_seconds: RATE_LOCK_SECONDS,
+        requote_window_seconds: REQUOTE_WINDOW_SECONDS,
+        max_requotes: MAX_REQUOTES,
+        seconds_per_confirmation: WAIT_PER_CONFIRMATION_SECONDS,
+        callback_retries,
+        merchant_id,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+struct ApiVersionsResponse {
+    /// Version of this request
+    current: ApiVersion,
+    latest: ApiVersion,
+    versions: Vec<VersionInfo>,
+}
+
+/// Versions of the merchant API and which one unversioned routes answer in
+pub fn get_api_versions(req: HttpRequest<AppState>) -> Result<HttpResponse, Error> {
+    Ok(HttpResponse::Ok().json(ApiVersionsResponse {
+        current: ApiVersion::of(&req),
+        latest: ApiVersion::LATEST,
+        versions: api_version::versions(),
+    }))
+}
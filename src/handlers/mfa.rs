@@ -1,4 +1,5 @@
 use crate::app::AppState;
+use crate::compat::{self, Future01CompatExt};
 use crate::db::{Confirm2FA, GetMerchant};
 use crate::errors::*;
 use crate::extractor::Session;
@@ -8,10 +9,9 @@ use crate::totp::Totp;
 use actix_web::http::Method;
 use actix_web::middleware::identity::RequestIdentity;
 use actix_web::middleware::session::RequestSession;
-use actix_web::{AsyncResponder, Form, FutureResponse, HttpRequest, HttpResponse};
+use actix_web::{Form, FutureResponse, HttpRequest, HttpResponse};
 use askama::Template;
 use data_encoding::BASE64;
-use futures::future::Future;
 use futures::future::{err, ok};
 use serde::Deserialize;
 
@@ -30,10 +30,17 @@ pub struct TotpRequest {
 
 #[derive(Template)]
 #[template(path = "2fa.html")]
-struct TwoFATemplate;
+struct TwoFATemplate {
+    totp: bool,
+    security_key: bool,
+}
 
-pub fn form_2fa(_: HttpRequest<AppState>) -> Result<HttpResponse, Error> {
-    TwoFATemplate {}.into_response()
+pub fn form_2fa(merchant: Session<Merchant>) -> Result<HttpResponse, Error> {
+    TwoFATemplate {
+        totp: merchant.second_factor.allows_totp(),
+        security_key: merchant.second_factor.allows_security_key(),
+    }
+    .into_response()
 }
 
 pub fn get_totp(merchant: Session<Merchant>) -> Result<HttpResponse, Error> {
@@ -68,19 +75,15 @@ pub fn post_totp(
     if req.method() == Method::POST {
         match totp.check(&totp_form.code) {
             Ok(true) => {
-                let resp = HttpResponse::Found().header("location", "/").finish();
-                return req
-                    .state()
-                    .db
-                    .send(Confirm2FA {
+                let db = req.state().db.clone();
+                return Box::new(compat::to_01(async move {
+                    db.send(Confirm2FA {
                         merchant_id: merchant.id,
                     })
-                    .from_err()
-                    .and_then(move |db_response| {
-                        db_response?;
-                        Ok(resp)
-                    })
-                    .responder();
+                    .compat()
+                    .await??;
+                    Ok::<_, Error>(HttpResponse::Found().header("location", "/").finish())
+                }));
             }
             _ => msg.push_str("Incorrect code, please try one more time"),
         }
@@ -102,7 +105,7 @@ pub fn post_totp(
         Ok(v) => v,
     };
     let resp = HttpResponse::Ok().content_type("text/html").body(html);
-    ok(resp).responder()
+    Box::new(ok(resp))
 }
 
 pub fn post_2fa(
@@ -116,26 +119,28 @@ pub fn post_2fa(
                 .finish()));
         }
     };
-    req.state()
-        .db
-        .send(GetMerchant {
-            id: merchant_id.clone(),
-        })
-        .from_err()
-        .and_then(move |db_response| {
-            let merchant = db_response?;
+    let db = req.state().db.clone();
+    Box::new(compat::to_01(async move {
+        let merchant = db
+            .send(GetMerchant {
+                id: merchant_id.clone(),
+            })
+            .compat()
+            .await??;
 
-            let token = merchant
-                .token_2fa
-                .ok_or(Error::General(s!("No 2fa token")))?;
-            let totp = Totp::new(merchant.id.clone(), token.clone());
+        if !merchant.second_factor.allows_totp() {
+            return Ok::<_, Error>(HttpResponse::Found().header("location", "/2fa").finish());
+        }
+        let token = merchant
+            .token_2fa
+            .ok_or(Error::General(s!("No 2fa token")))?;
+        let totp = Totp::new(merchant.id.clone(), token.clone());
 
-            if totp.check(&totp_form.code)? {
-                req.remember(merchant.id);
-                return Ok(HttpResponse::Found().header("location", "/").finish());
-            } else {
-                Ok(HttpResponse::Found().header("location", "/2fa").finish())
-            }
-        })
-        .responder()
+        if totp.check(&totp_form.code)? {
+            req.remember(merchant.id);
+            return Ok(HttpResponse::Found().header("location", "/").finish());
+        } else {
+            Ok(HttpResponse::Found().header("location", "/2fa").finish())
+        }
+    }))
 }
@@ -0,0 +1,215 @@
+use crate::app::AppState;
+use crate::compat::{self, Future01CompatExt};
+use crate::db::{CreateNote, DbExecutor, GetBlock, GetNotes, GetPendingCredits, GetTransaction};
+use crate::errors::*;
+use crate::explorer::ExplorerLinks;
+use crate::extractor::{BasicAuth, Identity, SimpleJson};
+use crate::filters;
+use crate::handlers::BootstrapColor;
+use crate::models::{
+    ApiScope, BlockHeader, Merchant, PendingCredit, Transaction, TransactionNote,
+    TransactionStatus, TransactionType, MAX_NOTE_LENGTH,
+};
+use crate::refund_addresses::REFUND_ADDRESS_CONFIG;
+use actix::Addr;
+use actix_web::{Form, FutureResponse, HttpRequest, HttpResponse, Path, State};
+use askama::Template;
+use chrono_tz::Tz;
+use futures::future::{err, ok, Future};
+use serde::Deserialize;
+use uuid::Uuid;
+
+/// Transaction with its notes, visible to the transaction's merchant and,
+/// with `is_admin`, to anybody. API tokens only reach their own merchant's
+/// transactions, admin rights apply to the web UI.
+async fn load_transaction(
+    db: Addr<DbExecutor>,
+    transaction_id: Uuid,
+    merchant_id: String,
+    is_admin: bool,
+) -> Result<(Transaction, Vec<TransactionNote>), Error> {
+    let transaction = db
+        .send(GetTransaction { transaction_id })
+        .compat()
+        .await??;
+    if transaction.merchant_id != merchant_id && !is_admin {
+        return Err(Error::EntityNotFound(s!("transaction")));
+    }
+    let notes = db
+        .send(GetNotes {
+            transaction_ids: vec![transaction.id],
+        })
+        .compat()
+        .await??;
+    Ok((transaction, notes))
+}
+
+#[derive(Template)]
+#[template(path = "transaction.html")]
+struct TransactionTemplate<'a> {
+    transaction: &'a Transaction,
+    notes: &'a [TransactionNote],
+    /// Block the transaction was confirmed in
+    block: Option<BlockHeader>,
+    explorer: ExplorerLinks,
+    max_note_length: usize,
+    /// Time zone of the merchant looking at the page
+    tz: Tz,
+    /// Statuses an admin can move the payment to
+    manual_statuses: &'static [TransactionStatus],
+    /// Credits of the payment which didn't clear yet, admins only
+    pending_credits: Vec<PendingCredit>,
+    /// The merchant's own refund, it can be given a refund address
+    refund_form: bool,
+    verified_refund_above: i64,
+}
+
+pub fn transaction(
+    (merchant, get_transaction, req): (
+        Identity<Merchant>,
+        Path<GetTransaction>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let db = req.state().db.clone();
+    let tz = merchant.tz();
+    let is_admin = merchant.is_admin;
+    let merchant_id = merchant.id.clone();
+    let transaction_id = get_transaction.transaction_id;
+    Box::new(
+        compat::to_01(async move {
+            let (transaction, notes) =
+                load_transaction(db.clone(), transaction_id, merchant_id.clone(), is_admin).await?;
+            let block: Option<BlockHeader> = match transaction.height {
+                Some(height) => db.send(GetBlock { height }).compat().await??,
+                None => None,
+            };
+            let pending_credits: Vec<PendingCredit> = if is_admin {
+                db.send(GetPendingCredits {
+                    transaction_id: transaction.id,
+                })
+                .compat()
+                .await??
+            } else {
+                vec![]
+            };
+            let explorer = ExplorerLinks::of(
+                &transaction,
+                block.as_ref().map(|block| block.hash.as_str()),
+            );
+            let manual_statuses: &[TransactionStatus] =
+                if is_admin && transaction.transaction_type == TransactionType::Payment {
+                    transaction.status.manual_transitions()
+                } else {
+                    &[]
+                };
+            let html = TransactionTemplate {
+                transaction: &transaction,
+                notes: &notes,
+                block,
+                explorer,
+                max_note_length: MAX_NOTE_LENGTH,
+                tz,
+                manual_statuses,
+                pending_credits,
+                refund_form: transaction.status == TransactionStatus::Refund
+                    && transaction.merchant_id == merchant_id,
+                verified_refund_above: REFUND_ADDRESS_CONFIG.verified_above,
+            }
+            .render()
+            .map_err(|e| Error::from(e))?;
+            Ok::<_, Error>(HttpResponse::Ok().content_type("text/html").body(html))
+        })
+        .from_err(),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NoteForm {
+    pub body: String,
+}
+
+pub fn add_note(
+    (merchant, get_transaction, req, form): (
+        Identity<Merchant>,
+        Path<GetTransaction>,
+        HttpRequest<AppState>,
+        Form<NoteForm>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let transaction_id = get_transaction.transaction_id;
+    let note = match TransactionNote::new(transaction_id, &merchant.id, &form.body) {
+        Ok(note) => note,
+        Err(e) => return Box::new(err(e.into())),
+    };
+    let db = req.state().db.clone();
+    let merchant_id = merchant.id.clone();
+    let is_admin = merchant.is_admin;
+    Box::new(
+        compat::to_01(async move {
+            load_transaction(db.clone(), transaction_id, merchant_id, is_admin).await?;
+            db.send(CreateNote(note)).compat().await??;
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", format!("/transactions/{}", transaction_id))
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
+
+pub fn get_notes(
+    (merchant, path, state): (BasicAuth<Merchant>, Path<(String, Uuid)>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let (merchant_id, transaction_id) = path.into_inner();
+    if merchant.id != merchant_id {
+        return Box::new(ok(HttpResponse::BadRequest().finish()));
+    }
+    if let Err(e) = merchant.require(ApiScope::ReadPayments) {
+        return Box::new(err(e.into()));
+    }
+    let db = state.db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let (_, notes) = load_transaction(db, transaction_id, merchant_id, false).await?;
+            Ok::<_, Error>(HttpResponse::Ok().json(notes))
+        })
+        .from_err(),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateNoteRequest {
+    pub body: String,
+}
+
+pub fn create_note(
+    (merchant, path, note_req, state): (
+        BasicAuth<Merchant>,
+        Path<(String, Uuid)>,
+        SimpleJson<CreateNoteRequest>,
+        State<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let (merchant_id, transaction_id) = path.into_inner();
+    if merchant.id != merchant_id {
+        return Box::new(ok(HttpResponse::BadRequest().finish()));
+    }
+    if let Err(e) = merchant.require(ApiScope::WriteNotes) {
+        return Box::new(err(e.into()));
+    }
+    let note = match TransactionNote::new(transaction_id, &merchant_id, &note_req.body) {
+        Ok(note) => note,
+        Err(e) => return Box::new(err(e.into())),
+    };
+    let db = state.db.clone();
+    Box::new(
+        compat::to_01(async move {
+            load_transaction(db.clone(), transaction_id, merchant_id, false).await?;
+            let note = db.send(CreateNote(note)).compat().await??;
+            Ok::<_, Error>(HttpResponse::Created().json(note))
+        })
+        .from_err(),
+    )
+}
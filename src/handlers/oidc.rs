@@ -0,0 +1,114 @@
+use crate::app::AppState;
+use crate::compat::{self, Future01CompatExt};
+use crate::db::{GetMerchantByOidcSubject, LinkOidcSubject};
+use crate::errors::*;
+use crate::handlers::webui::second_factor_redirect;
+use crate::oidc::{PendingLogin, SESSION_KEY};
+use actix_web::middleware::identity::RequestIdentity;
+use actix_web::middleware::session::RequestSession;
+use actix_web::{FutureResponse, HttpRequest, HttpResponse, Query};
+use futures::future::err;
+use log::warn;
+use serde::Deserialize;
+
+/// Redirects to the provider's login page. When a merchant is already
+/// logged in the external account gets linked to them instead.
+pub fn login(req: HttpRequest<AppState>) -> FutureResponse<HttpResponse, Error> {
+    let oidc = match req.state().oidc.clone() {
+        Some(oidc) => oidc,
+        None => return Box::new(err(Error::NotAuthorizedInUI)),
+    };
+    let pending = PendingLogin::new();
+    if let Err(e) = req.session().set(SESSION_KEY, &pending) {
+        return Box::new(err(Error::General(s!(e))));
+    }
+    Box::new(compat::to_01(async move {
+        let metadata = oidc.discover().compat().await?;
+        let url = oidc.authorization_url(&metadata, &pending)?;
+        Ok::<_, Error>(HttpResponse::Found().header("location", url).finish())
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+pub fn callback(
+    (req, query): (HttpRequest<AppState>, Query<CallbackQuery>),
+) -> FutureResponse<HttpResponse, Error> {
+    let query = query.into_inner();
+    let oidc = match req.state().oidc.clone() {
+        Some(oidc) => oidc,
+        None => return Box::new(err(Error::NotAuthorizedInUI)),
+    };
+    // State and nonce are good for one attempt only
+    let pending = match req.session().get::<PendingLogin>(SESSION_KEY) {
+        Ok(Some(pending)) => pending,
+        _ => return Box::new(err(Error::Oidc(s!("no pending login")))),
+    };
+    req.session().remove(SESSION_KEY);
+    if let Some(error) = query.error {
+        warn!("OpenID provider rejected login: {}", error);
+        return Box::new(err(Error::Oidc(error)));
+    }
+    if query.state.as_ref() != Some(&pending.state) {
+        return Box::new(err(Error::Oidc(s!("state mismatch"))));
+    }
+    let code = match query.code {
+        Some(code) => code,
+        None => return Box::new(err(Error::Oidc(s!("no authorization code")))),
+    };
+
+    Box::new(compat::to_01(async move {
+        let metadata = oidc.discover().compat().await?;
+        let subject = oidc
+            .authenticate(&metadata, &code, pending.nonce)
+            .compat()
+            .await?;
+        match req.identity() {
+            Some(merchant_id) => link_subject(&req, merchant_id, subject).await,
+            None => login_by_subject(&req, subject).await,
+        }
+    }))
+}
+
+async fn link_subject(
+    req: &HttpRequest<AppState>,
+    merchant_id: String,
+    subject: String,
+) -> Result<HttpResponse, Error> {
+    req.state()
+        .db
+        .send(LinkOidcSubject {
+            merchant_id,
+            subject,
+        })
+        .compat()
+        .await??;
+    Ok(HttpResponse::Found().header("location", "/").finish())
+}
+
+async fn login_by_subject(
+    req: &HttpRequest<AppState>,
+    subject: String,
+) -> Result<HttpResponse, Error> {
+    let merchant = req
+        .state()
+        .db
+        .send(GetMerchantByOidcSubject { subject })
+        .compat()
+        .await?
+        .map_err(|e| match e {
+            Error::EntityNotFound(_) => Error::Oidc(s!("account is not linked to any merchant")),
+            e => e,
+        })?;
+    // External account replaces the password only,
+    // the second factor is still required
+    req.session()
+        .set("merchant", &merchant.id)
+        .map_err(|e| Error::General(s!(e)))?;
+    Ok(second_factor_redirect(&merchant))
+}
@@ -1,21 +1,45 @@
 use crate::app::AppState;
-use crate::db::{GetCurrentHeight, GetTransaction};
+use crate::checkout::CheckoutToken;
+use crate::compat::{self, Future01CompatExt};
+use crate::db::{
+    ClaimPayment, GetCurrentHeight, GetMerchant, GetPaymentsByIds, GetQuotes, GetTransaction,
+    GetTransactions, ReleasePayment, SetPayerLocation, SetReceiptEmail, UseReturnNonce,
+};
 use crate::errors::*;
+use crate::explorer::ExplorerLinks;
 use crate::extractor::{BasicAuth, SimpleJson};
 use crate::filters;
-use crate::fsm::{CreatePayment, GetNewPayment, MakePayment};
+use crate::foreign_api::{self, Method, RpcError, RpcRequest, RpcResponse};
+use crate::fsm::{CreatePayment, CreatePayments, GetNewPayment, MakePayment, RequotePayment};
+use crate::geoip;
 use crate::handlers::BootstrapColor;
-use crate::models::{Merchant, Money, Transaction, TransactionStatus};
+use crate::i18n::{self, Language};
+use crate::mailer;
+use crate::metrics;
+use crate::models::{
+    fill_payment_message, validate_payment_message, ApiScope, Conversion, Merchant, Money,
+    Transaction, TransactionStatus, TransactionType, CONVERSION_ROUNDING_NAME, MAX_METADATA_SIZE,
+    MAX_USER_AGENT_LENGTH,
+};
 use crate::qrcode;
-use crate::wallet::Slate;
-use actix_web::{AsyncResponder, FutureResponse, HttpResponse, Path, State};
+use crate::quote::Quote;
+use crate::rate_limit;
+use crate::return_url::ReturnPayload;
+use crate::splits::{self, SplitRecipient};
+use crate::trace::{self, FutureTraceExt, Span};
+use crate::wallet::{ParticipantData, VersionedSlate};
+use actix_web::http::header;
+use actix_web::{FutureResponse, HttpRequest, HttpResponse, Path, Query, State};
 use askama::Template;
+use chrono::{DateTime, NaiveDateTime, Utc};
 use chrono_humanize::{Accuracy, HumanTime, Tense};
 use data_encoding::BASE64;
-use futures::future::ok;
 use futures::future::Future;
+use futures::future::{err, ok};
+use log::warn;
 use serde::{Deserialize, Serialize};
 use std::env;
+use uuid::Uuid;
 
 #[derive(Debug, Deserialize)]
 pub struct CreatePaymentRequest {
@@ -25,6 +49,46 @@ pub struct CreatePaymentRequest {
     pub email: Option<String>,
     pub message: String,
     pub redirect_url: Option<String>,
+    pub metadata: Option<serde_json::Value>,
+    /// Shares of the platform's sellers, see `splits`
+    #[serde(default)]
+    pub splits: Vec<SplitRecipient>,
+}
+
+impl CreatePaymentRequest {
+    /// Checks which don't need the DB
+    fn validate(&self, merchant_id: &str) -> Result<(), Error> {
+        validate_payment_message(&self.message(merchant_id))?;
+        if let Some(ref metadata) = self.metadata {
+            if metadata.to_string().len() > MAX_METADATA_SIZE {
+                return Err(Error::InvalidEntity(format!(
+                    "metadata is bigger than {} bytes",
+                    MAX_METADATA_SIZE
+                )));
+            }
+        }
+        splits::new_splits(Uuid::nil(), &self.splits)?;
+        Ok(())
+    }
+
+    /// Message with the variables filled in
+    fn message(&self, merchant_id: &str) -> String {
+        fill_payment_message(&self.message, &self.order_id, &self.amount, merchant_id)
+    }
+
+    fn into_payment(self, merchant_id: String) -> CreatePayment {
+        CreatePayment {
+            message: self.message(&merchant_id),
+            merchant_id,
+            external_id: self.order_id,
+            amount: self.amount,
+            confirmations: self.confirmations,
+            email: self.email,
+            redirect_url: self.redirect_url,
+            metadata: self.metadata,
+            splits: self.splits,
+        }
+    }
 }
 
 pub fn create_payment(
@@ -39,120 +103,588 @@ pub fn create_payment(
     if merchant.id != merchant_id {
         return Box::new(ok(HttpResponse::BadRequest().finish()));
     }
-    let create_transaction = CreatePayment {
-        merchant_id: merchant_id,
-        external_id: payment_req.order_id.clone(),
-        amount: payment_req.amount,
-        confirmations: payment_req.confirmations,
-        email: payment_req.email.clone(),
-        message: payment_req.message.clone(),
-        redirect_url: payment_req.redirect_url.clone(),
+    if let Err(e) = merchant.require(ApiScope::CreatePayments) {
+        return Box::new(err(e.into()));
+    }
+    if let Err(e) = payment_req.validate(&merchant_id) {
+        return Box::new(err(e.into()));
+    }
+    if let Err(e) = rate_limit::take(&merchant, 1, Utc::now().naive_utc()) {
+        return Box::new(err(e.into()));
+    }
+    let create_transaction = payment_req.into_inner().into_payment(merchant_id);
+    let fsm = state.fsm.clone();
+    let db = state.db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let new_payment = fsm.send(create_transaction).compat().await??;
+            let quotes = db
+                .send(GetQuotes {
+                    grin_amount: new_payment.grin_amount,
+                })
+                .compat()
+                .await??;
+            let checkout_url = CheckoutToken::new(new_payment.id, Utc::now().timestamp()).url()?;
+            Ok::<_, Error>(HttpResponse::Created().json(CreatePaymentResponse {
+                payment: &new_payment,
+                checkout_url,
+                expires_at: new_payment.expires_at_utc(),
+                rounding: CONVERSION_ROUNDING_NAME,
+                quotes,
+            }))
+        })
+        .from_err(),
+    )
+}
+
+/// Most payments accepted in one batch
+pub const MAX_BATCH_SIZE: usize = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct CreatePaymentsRequest {
+    pub payments: Vec<CreatePaymentRequest>,
+}
+
+#[derive(Debug, Serialize)]
+struct CreatePaymentsResponse {
+    payments: Vec<BatchItemResult>,
+}
+
+/// Result of one payment in the batch, in the order of the request. `id`
+/// is set only when the whole batch was created, `error` only for items
+/// which failed.
+#[derive(Debug, Serialize)]
+struct BatchItemResult {
+    order_id: String,
+    id: Option<Uuid>,
+    invoice_number: Option<String>,
+    grin_amount: Option<i64>,
+    expires_at: Option<DateTime<Utc>>,
+    error: Option<String>,
+}
+
+impl BatchItemResult {
+    fn created(order_id: String, payment: &Transaction) -> Self {
+        BatchItemResult {
+            order_id,
+            id: Some(payment.id),
+            invoice_number: payment.invoice_number.clone(),
+            grin_amount: Some(payment.grin_amount),
+            expires_at: payment.expires_at_utc(),
+            error: None,
+        }
+    }
+
+    fn not_created(order_id: String, error: Option<String>) -> Self {
+        BatchItemResult {
+            order_id,
+            id: None,
+            invoice_number: None,
+            grin_amount: None,
+            expires_at: None,
+            error,
+        }
+    }
+}
+
+/// Creates up to `MAX_BATCH_SIZE` payments at once, all of them or none.
+/// Responds with `201` when the batch was created and `400` with the
+/// errors of the failed items otherwise.
+pub fn create_payments_batch(
+    (merchant, merchant_id, batch_req, state): (
+        BasicAuth<Merchant>,
+        Path<String>,
+        SimpleJson<CreatePaymentsRequest>,
+        State<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let merchant_id = merchant_id.into_inner();
+    if merchant.id != merchant_id {
+        return Box::new(ok(HttpResponse::BadRequest().finish()));
+    }
+    if let Err(e) = merchant.require(ApiScope::CreatePayments) {
+        return Box::new(err(e.into()));
+    }
+    let payments = batch_req.into_inner().payments;
+    if payments.is_empty() || payments.len() > MAX_BATCH_SIZE {
+        return Box::new(err(Error::InvalidEntity(format!(
+            "batch should have from 1 to {} payments",
+            MAX_BATCH_SIZE
+        ))
+        .into()));
+    }
+    let errors: Vec<Option<String>> = payments
+        .iter()
+        .map(|payment_req| payment_req.validate(&merchant_id).err().map(|e| s!(e)))
+        .collect();
+    if errors.iter().any(|error| error.is_some()) {
+        let results = payments
+            .into_iter()
+            .zip(errors)
+            .map(|(payment_req, error)| BatchItemResult::not_created(payment_req.order_id, error))
+            .collect();
+        return Box::new(ok(
+            HttpResponse::BadRequest().json(CreatePaymentsResponse { payments: results })
+        ));
+    }
+    // Taken up front so an empty bucket stops the batch, given back
+    // unless the batch is created
+    let count = payments.len();
+    if let Err(e) = rate_limit::take(&merchant, count, Utc::now().naive_utc()) {
+        return Box::new(err(e.into()));
+    }
+    let order_ids: Vec<String> = payments
+        .iter()
+        .map(|payment_req| payment_req.order_id.clone())
+        .collect();
+    let create_payments = CreatePayments {
+        payments: payments
+            .into_iter()
+            .map(|payment_req| payment_req.into_payment(merchant_id.clone()))
+            .collect(),
+    };
+    let fsm = state.fsm.clone();
+    Box::new(
+        compat::to_01(async move {
+            let fsm_response = fsm.send(create_payments).compat().await;
+            let created = match fsm_response {
+                Ok(Ok(ref results)) => results.iter().all(|res| res.is_ok()),
+                _ => false,
+            };
+            if !created {
+                rate_limit::refund(&merchant, count);
+            }
+            let results = fsm_response??;
+            if created {
+                let results = order_ids
+                    .into_iter()
+                    .zip(results)
+                    .map(|(order_id, res)| BatchItemResult::created(order_id, &res.unwrap()))
+                    .collect();
+                return Ok(
+                    HttpResponse::Created().json(CreatePaymentsResponse { payments: results })
+                );
+            }
+            // Nothing was created, the items which passed only lack an id
+            let results = order_ids
+                .into_iter()
+                .zip(results)
+                .map(|(order_id, res)| {
+                    BatchItemResult::not_created(order_id, res.err().map(|e| s!(e)))
+                })
+                .collect();
+            Ok::<_, Error>(
+                HttpResponse::BadRequest().json(CreatePaymentsResponse { payments: results }),
+            )
+        })
+        .from_err(),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListPaymentsQuery {
+    pub offset: Option<i64>,
+    pub limit: Option<i64>,
+    pub metadata_key: Option<String>,
+    pub metadata_value: Option<String>,
+}
+
+const MAX_PAYMENTS_PER_PAGE: i64 = 100;
+
+pub fn get_payments(
+    (merchant, merchant_id, query, state): (
+        BasicAuth<Merchant>,
+        Path<String>,
+        Query<ListPaymentsQuery>,
+        State<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let merchant_id = merchant_id.into_inner();
+    if merchant.id != merchant_id {
+        return Box::new(ok(HttpResponse::BadRequest().finish()));
+    }
+    if let Err(e) = merchant.require(ApiScope::ReadPayments) {
+        return Box::new(err(e.into()));
+    }
+    let query = query.into_inner();
+    // metadata_value narrows metadata_key down to an exact match
+    let (metadata_key, metadata) = match (query.metadata_key, query.metadata_value) {
+        (Some(key), Some(value)) => {
+            let mut filter = serde_json::Map::new();
+            filter.insert(key, serde_json::Value::String(value));
+            (None, Some(serde_json::Value::Object(filter)))
+        }
+        (key, _) => (key, None),
     };
-    state
-        .fsm
-        .send(create_transaction)
-        .from_err()
-        .and_then(|db_response| {
-            let new_payment = db_response?;
-
-            Ok(HttpResponse::Created().json(new_payment))
+    let get_transactions = GetTransactions {
+        merchant_id,
+        offset: query.offset.unwrap_or(0),
+        limit: query
+            .limit
+            .unwrap_or(MAX_PAYMENTS_PER_PAGE)
+            .min(MAX_PAYMENTS_PER_PAGE),
+        transaction_type: Some(TransactionType::Payment),
+        metadata_key,
+        metadata,
+    };
+    let db = state.db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let payments = db.send(get_transactions).compat().await??;
+            Ok::<_, Error>(HttpResponse::Ok().json(payments))
         })
-        .responder()
+        .from_err(),
+    )
+}
+
+#[derive(Debug, Serialize)]
+struct CreatePaymentResponse<'a> {
+    #[serde(flatten)]
+    payment: &'a Transaction,
+    /// Payment page for the buyer
+    checkout_url: String,
+    expires_at: Option<DateTime<Utc>>,
+    rounding: &'static str,
+    quotes: Vec<Quote>,
 }
 
 #[derive(Debug, Serialize)]
 struct PaymentStatus {
     pub transaction_id: String,
     pub status: String,
+    /// Transaction is in the node's pool, awaiting a block
+    pub seen_in_pool: bool,
     pub reported: bool,
     pub seconds_until_expired: Option<i64>,
     pub expired_in: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
     pub current_confirmations: i64,
     pub required_confirmations: i64,
+    pub quotes: Vec<Quote>,
+    pub rate_locked_until: Option<NaiveDateTime>,
+    pub can_be_requoted: bool,
 }
 
+/// Responses may be kept by the browser only, and have to be revalidated
+const STATUS_CACHE_CONTROL: &str = "private, no-cache";
+
+/// Version of what the status shows, `seconds_until_expired` and quotes
+/// aren't part of it, so the tag is weak
+fn status_etag(tx: &Transaction, current_height: i64) -> String {
+    format!(
+        "W/\"{}-{}-{}-{}-{}\"",
+        tx.status,
+        current_height,
+        tx.reported,
+        tx.is_seen_in_pool(),
+        tx.requotes
+    )
+}
+
+/// `If-None-Match` lists tags separated by commas, weak comparison is used
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == opaque(etag))
+}
+
+/// Status of the merchant's payment by id, what the payment page polls
 pub fn get_payment_status(
-    (get_transaction, state): (Path<GetTransaction>, State<AppState>),
+    (merchant, path, req): (
+        BasicAuth<Merchant>,
+        Path<(String, Uuid)>,
+        HttpRequest<AppState>,
+    ),
 ) -> FutureResponse<HttpResponse> {
-    state
-        .db
-        .send(GetCurrentHeight)
-        .from_err()
-        .and_then(|db_response| {
-            let height = db_response?;
-            Ok(height)
-        })
-        .and_then({
-            let db = state.db.clone();
-            move |current_height| {
-                db.send(get_transaction.into_inner())
-                    .from_err()
-                    .and_then(move |db_response| {
-                        let tx = db_response?;
-                        let payment_status = PaymentStatus {
-                            transaction_id: tx.id.to_string(),
-                            status: tx.status.to_string(),
-                            seconds_until_expired: tx.time_until_expired().map(|d| d.num_seconds()),
-
-                            expired_in: tx.time_until_expired().map(|d| {
-                                HumanTime::from(d).to_text_en(Accuracy::Precise, Tense::Present)
-                            }),
-                            current_confirmations: tx.current_confirmations(current_height),
-                            required_confirmations: tx.confirmations,
-                            reported: tx.reported,
-                        };
-                        Ok(HttpResponse::Ok().json(payment_status))
-                    })
+    let (merchant_id, transaction_id) = path.into_inner();
+    if merchant.id != merchant_id {
+        return Box::new(ok(HttpResponse::BadRequest().finish()));
+    }
+    if let Err(e) = merchant.require(ApiScope::ReadPayments) {
+        return Box::new(err(e.into()));
+    }
+    payment_status(transaction_id, Some(merchant_id), &req)
+}
+
+/// Polled by the payment page, merchants pass their id and only get their
+/// own payments
+pub fn payment_status(
+    transaction_id: Uuid,
+    merchant_id: Option<String>,
+    req: &HttpRequest<AppState>,
+) -> FutureResponse<HttpResponse> {
+    let db = req.state().db.clone();
+    let if_none_match = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_owned());
+    let trace = trace::request_context(req);
+    let language = Language::of(req);
+    i18n::localize(
+        language,
+        compat::to_01(async move {
+            let current_height = db
+                .send(GetCurrentHeight)
+                .traced(Span::child("db GetCurrentHeight", trace.as_ref()))
+                .compat()
+                .await??;
+            let get_transaction = GetTransaction { transaction_id };
+            let span =
+                Span::child("db GetTransaction", trace.as_ref()).with_params(&get_transaction);
+            let tx = db.send(get_transaction).traced(span).compat().await??;
+            if merchant_id.map_or(false, |merchant_id| tx.merchant_id != merchant_id) {
+                return Err(Error::EntityNotFound(s!("payment")));
             }
-        })
-        .responder()
+            // Pollers revalidate every time, an unchanged payment costs
+            // neither the quotes query nor the body
+            let etag = status_etag(&tx, current_height);
+            if let Some(ref if_none_match) = if_none_match {
+                if etag_matches(if_none_match, &etag) {
+                    return Ok(HttpResponse::NotModified()
+                        .header(header::ETAG, etag)
+                        .header(header::CACHE_CONTROL, STATUS_CACHE_CONTROL)
+                        .finish());
+                }
+            }
+            let get_quotes = GetQuotes {
+                grin_amount: tx.grin_amount,
+            };
+            let span = Span::child("db GetQuotes", trace.as_ref()).with_params(&get_quotes);
+            let quotes = db.send(get_quotes).traced(span).compat().await??;
+            let payment_status = PaymentStatus {
+                transaction_id: tx.id.to_string(),
+                status: tx.status.to_string(),
+                seen_in_pool: tx.is_seen_in_pool(),
+                seconds_until_expired: tx.time_until_expired().map(|d| d.num_seconds()),
+                expired_in: tx
+                    .time_until_expired()
+                    .map(|d| HumanTime::from(d).to_text_en(Accuracy::Precise, Tense::Present)),
+                expires_at: tx.expires_at_utc(),
+                current_confirmations: tx.current_confirmations(current_height),
+                required_confirmations: tx.confirmations,
+                reported: tx.reported,
+                quotes,
+                rate_locked_until: tx.rate_locked_until,
+                can_be_requoted: tx.can_be_requoted_at(Utc::now().naive_utc()),
+            };
+            Ok::<_, Error>(
+                HttpResponse::Ok()
+                    .header(header::ETAG, etag)
+                    .header(header::CACHE_CONTROL, STATUS_CACHE_CONTROL)
+                    .json(payment_status),
+            )
+        }),
+    )
 }
 
-pub fn get_payment(
-    (get_transaction, state): (Path<GetTransaction>, State<AppState>),
+/// Most ids accepted in one status query
+pub const MAX_STATUS_IDS: usize = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct PaymentsStatusRequest {
+    #[serde(default)]
+    pub ids: Vec<Uuid>,
+    /// Order ids, i.e. `order_id` the payments were created with
+    #[serde(default)]
+    pub order_ids: Vec<String>,
+    /// Amounts which arrived in the merchant's wallet, match new payments
+    /// with amount tags
+    #[serde(default)]
+    pub grin_amounts: Vec<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct CompactPaymentStatus {
+    id: Uuid,
+    order_id: String,
+    invoice_number: Option<String>,
+    status: String,
+    grin_amount: i64,
+    seen_in_pool: bool,
+    current_confirmations: i64,
+    required_confirmations: i64,
+    reported: bool,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+struct PaymentsStatusResponse {
+    payments: Vec<CompactPaymentStatus>,
+    /// Requested ids and order ids without a payment
+    not_found: Vec<String>,
+}
+
+/// Statuses of many payments in one call, for merchants' backends
+/// which would poll every payment otherwise
+pub fn get_payments_status(
+    (merchant, merchant_id, status_req, state): (
+        BasicAuth<Merchant>,
+        Path<String>,
+        SimpleJson<PaymentsStatusRequest>,
+        State<AppState>,
+    ),
 ) -> FutureResponse<HttpResponse> {
-    state
-        .db
-        .send(GetCurrentHeight)
-        .from_err()
-        .and_then(|db_response| {
-            let height = db_response?;
-            Ok(height)
+    let merchant_id = merchant_id.into_inner();
+    if merchant.id != merchant_id {
+        return Box::new(ok(HttpResponse::BadRequest().finish()));
+    }
+    if let Err(e) = merchant.require(ApiScope::ReadPayments) {
+        return Box::new(err(e.into()));
+    }
+    let status_req = status_req.into_inner();
+    let requested =
+        status_req.ids.len() + status_req.order_ids.len() + status_req.grin_amounts.len();
+    if requested == 0 || requested > MAX_STATUS_IDS {
+        return Box::new(err(Error::InvalidEntity(format!(
+            "query should have from 1 to {} ids",
+            MAX_STATUS_IDS
+        ))
+        .into()));
+    }
+    let db = state.db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let current_height = db.send(GetCurrentHeight).compat().await??;
+            let payments = db
+                .send(GetPaymentsByIds {
+                    merchant_id,
+                    ids: status_req.ids.clone(),
+                    external_ids: status_req.order_ids.clone(),
+                    grin_amounts: status_req.grin_amounts.clone(),
+                })
+                .compat()
+                .await??;
+            let mut not_found: Vec<String> = status_req
+                .ids
+                .iter()
+                .filter(|id| !payments.iter().any(|tx| tx.id == **id))
+                .map(|id| id.to_string())
+                .collect();
+            not_found.extend(
+                status_req
+                    .order_ids
+                    .into_iter()
+                    .filter(|order_id| !payments.iter().any(|tx| tx.external_id == *order_id)),
+            );
+            not_found.extend(
+                status_req
+                    .grin_amounts
+                    .iter()
+                    .filter(|amount| !payments.iter().any(|tx| tx.is_tagged_with(**amount)))
+                    .map(|amount| amount.to_string()),
+            );
+            let payments = payments
+                .iter()
+                .map(|tx| CompactPaymentStatus {
+                    id: tx.id,
+                    order_id: tx.external_id.clone(),
+                    invoice_number: tx.invoice_number.clone(),
+                    status: tx.status.to_string(),
+                    grin_amount: tx.grin_amount,
+                    seen_in_pool: tx.is_seen_in_pool(),
+                    current_confirmations: tx.current_confirmations(current_height),
+                    required_confirmations: tx.confirmations,
+                    reported: tx.reported,
+                    expires_at: tx.expires_at_utc(),
+                })
+                .collect();
+            Ok::<_, Error>(HttpResponse::Ok().json(PaymentsStatusResponse {
+                payments,
+                not_found,
+            }))
         })
-        .and_then({
-            let db = state.db.clone();
-            move |current_height| {
-                db.send(get_transaction.into_inner())
-                    .from_err()
-                    .and_then(move |db_response| {
-                        let transaction = db_response?;
-
-                        let payment_url = format!(
-                            "{}/merchants/{}/payments/{}",
-                            env::var("DOMAIN").unwrap().trim_end_matches('/'),
-                            transaction.merchant_id,
-                            transaction.id.to_string()
-                        );
-                        let ironbelly_link = format!(
-                            "grin://send?amount={}&destination={}&message={}",
-                            transaction.grin_amount,
-                            payment_url,
-                            BASE64.encode(transaction.message.as_bytes())
-                        );
-                        let html = PaymentTemplate {
-                            payment: &transaction,
-                            payment_url: payment_url,
-                            current_height: current_height,
-                            ironbelly_link: &ironbelly_link,
-                            ironbelly_qrcode: &BASE64.encode(&qrcode::as_png(&ironbelly_link)?),
-                        }
-                        .render()
-                        .map_err(|e| Error::from(e))?;
-                        Ok(HttpResponse::Ok().content_type("text/html").body(html))
-                    })
+        .from_err(),
+    )
+}
+
+/// `token` is the checkout token the page was opened with, the page's
+/// routes and wallet URL use it
+pub fn payment_page(
+    transaction_id: Uuid,
+    token: CheckoutToken,
+    req: &HttpRequest<AppState>,
+) -> FutureResponse<HttpResponse> {
+    let state = req.state();
+    let trace = trace::request_context(req);
+    let language = Language::of(req);
+    let location = geoip::locate(req);
+    if location.is_known() {
+        state.db.do_send(SetPayerLocation {
+            transaction_id,
+            country: location.country,
+            asn: location.asn,
+        });
+    }
+    let checkout_path = match token.path() {
+        Ok(checkout_path) => checkout_path,
+        Err(e) => return i18n::localize(language, err(e)),
+    };
+    let db = state.db.clone();
+    i18n::localize(
+        language,
+        compat::to_01(async move {
+            let current_height = db
+                .send(GetCurrentHeight)
+                .traced(Span::child("db GetCurrentHeight", trace.as_ref()))
+                .compat()
+                .await??;
+            let get_transaction = GetTransaction { transaction_id };
+            let span =
+                Span::child("db GetTransaction", trace.as_ref()).with_params(&get_transaction);
+            let transaction = db.send(get_transaction).traced(span).compat().await??;
+            let get_merchant = GetMerchant {
+                id: transaction.merchant_id.clone(),
+            };
+            let span = Span::child("db GetMerchant", trace.as_ref()).with_params(&get_merchant);
+            let merchant = db.send(get_merchant).traced(span).compat().await??;
+            let return_url = match transaction.redirect_url {
+                Some(ref redirect_url) => Some(
+                    ReturnPayload::new(
+                        transaction.id,
+                        transaction.status.to_string(),
+                        transaction.grin_amount,
+                        Utc::now().timestamp(),
+                        &merchant.token,
+                    )?
+                    .append_to(redirect_url)?,
+                ),
+                None => None,
+            };
+            let get_quotes = GetQuotes {
+                grin_amount: transaction.grin_amount,
+            };
+            let span = Span::child("db GetQuotes", trace.as_ref()).with_params(&get_quotes);
+            let quotes = db.send(get_quotes).traced(span).compat().await??;
+
+            let payment_url = format!(
+                "{}{}",
+                env::var("DOMAIN").unwrap().trim_end_matches('/'),
+                checkout_path
+            );
+            let ironbelly_link = format!(
+                "grin://send?amount={}&destination={}&message={}",
+                transaction.grin_amount,
+                payment_url,
+                BASE64.encode(transaction.message.as_bytes())
+            );
+            let html = PaymentTemplate {
+                payment: &transaction,
+                payment_url: payment_url,
+                checkout_path: &checkout_path,
+                current_height: current_height,
+                ironbelly_link: &ironbelly_link,
+                ironbelly_qrcode: &BASE64.encode(&qrcode::as_png(&ironbelly_link)?),
+                quotes: &quotes,
+                return_url,
+                explorer: ExplorerLinks::of(&transaction, None),
             }
-        })
-        .responder()
+            .render()
+            .map_err(|e| Error::from(e))?;
+            Ok::<_, Error>(HttpResponse::Ok().content_type("text/html").body(html))
+        }),
+    )
 }
 
 #[derive(Template)]
@@ -160,52 +692,411 @@ pub fn get_payment(
 struct PaymentTemplate<'a> {
     payment: &'a Transaction,
     payment_url: String,
+    /// The page's routes are below it
+    checkout_path: &'a str,
     current_height: i64,
     ironbelly_link: &'a str,
     ironbelly_qrcode: &'a str,
+    quotes: &'a Vec<Quote>,
+    /// Merchant's redirect url with the signed payment result
+    return_url: Option<String>,
+    explorer: ExplorerLinks,
 }
 
-pub fn make_payment(
-    (slate, payment, state): (SimpleJson<Slate>, Path<GetNewPayment>, State<AppState>),
+#[derive(Debug, Deserialize)]
+pub struct ReceiptEmailForm {
+    pub email: String,
+    /// Checkbox, only sent when ticked
+    pub consent: Option<String>,
+}
+
+/// Buyer asks for a receipt of a payment the merchant created without
+/// email, then is sent back to `location`
+pub fn receipt_email(
+    transaction_id: Uuid,
+    form: ReceiptEmailForm,
+    location: String,
+    req: &HttpRequest<AppState>,
+) -> FutureResponse<HttpResponse> {
+    let language = Language::of(req);
+    let email = form.email.trim().to_owned();
+    if !mailer::is_email(&email) {
+        return i18n::localize(language, err(Error::InvalidEntity(s!("email"))));
+    }
+    if form.consent.is_none() {
+        return i18n::localize(
+            language,
+            err(Error::InvalidEntity(s!(
+                "consent to receive the receipt is required"
+            ))),
+        );
+    }
+    let db = req.state().db.clone();
+    i18n::localize(
+        language,
+        compat::to_01(async move {
+            db.send(SetReceiptEmail {
+                transaction_id,
+                email,
+            })
+            .compat()
+            .await??;
+            Ok::<_, Error>(HttpResponse::Found().header("location", location).finish())
+        }),
+    )
+}
+
+/// The v2 foreign API of the payment URL, the buyer's wallet calls it on
+/// the checkout token
+pub fn foreign_api_request(
+    request: Result<RpcRequest, RpcResponse>,
+    transaction_id: Result<Uuid, Error>,
+    req: &HttpRequest<AppState>,
 ) -> FutureResponse<HttpResponse, Error> {
-    let slate_amount = slate.amount;
-    state
-        .fsm
-        .send(payment.into_inner())
-        .from_err()
-        .and_then(move |db_response| {
-            let new_payment = db_response?;
-            let payment_amount = new_payment.grin_amount as u64;
-            if new_payment.is_invalid_amount(slate_amount) {
-                return Err(Error::WrongAmount(payment_amount, slate_amount));
+    let request = match request {
+        Ok(request) => request,
+        Err(resp) => return Box::new(ok(HttpResponse::Ok().json(resp))),
+    };
+    let id = request.id;
+    let method = match Method::parse(&request.method) {
+        Some(method) => method,
+        None => {
+            let error = RpcError::new(
+                foreign_api::METHOD_NOT_FOUND,
+                &format!("Method not found: {}", request.method),
+            );
+            return Box::new(ok(HttpResponse::Ok().json(RpcResponse::error(id, error))));
+        }
+    };
+    metrics::inc("foreign_api_requests_total", &[("method", method.name())]);
+    let language = Language::of(req);
+    let payment_error = move |e: &Error| RpcError::payment(e, i18n::message(e.code(), language));
+    let resp = match method {
+        Method::CheckVersion => RpcResponse::ok(id, foreign_api::version_info()),
+        Method::NotServed(name) => RpcResponse::error(
+            id,
+            RpcError::new(
+                foreign_api::METHOD_NOT_FOUND,
+                &format!(
+                    "{} isn't served, the payment URL only receives payments",
+                    name
+                ),
+            ),
+        ),
+        Method::VerifySlateMessages => {
+            match foreign_api::slate_param(&request.params).and_then(|slate| {
+                foreign_api::verify_slate_messages(slate).map_err(|e| payment_error(&e))
+            }) {
+                Ok(()) => RpcResponse::ok(id, ()),
+                Err(e) => RpcResponse::error(id, e),
             }
-            Ok(new_payment)
+        }
+        Method::ReceiveTx => {
+            let slate = match foreign_api::slate_param(&request.params) {
+                Ok(slate) => slate,
+                Err(e) => return Box::new(ok(HttpResponse::Ok().json(RpcResponse::error(id, e)))),
+            };
+            let received =
+                transaction_id.map(|transaction_id| receive_slate(slate, transaction_id, req));
+            // Failures are JSON-RPC errors, which the wallet shows the buyer
+            return Box::new(compat::to_01(async move {
+                let res = match received {
+                    Ok(received) => received.await,
+                    Err(e) => Err(e),
+                };
+                let resp = match res {
+                    Ok(slate) => RpcResponse::ok(id, slate),
+                    Err(e) => RpcResponse::error(id, payment_error(&e)),
+                };
+                Ok::<_, Error>(HttpResponse::Ok().json(resp))
+            }));
+        }
+    };
+    Box::new(ok(HttpResponse::Ok().json(resp)))
+}
+
+/// The buyer's wallet sent the slate, or a `receive_tx` call, to the
+/// checkout token
+pub fn receive_payment(
+    body: serde_json::Value,
+    transaction_id: Result<Uuid, Error>,
+    req: &HttpRequest<AppState>,
+) -> FutureResponse<HttpResponse, Error> {
+    if foreign_api::is_request(&body) {
+        return foreign_api_request(foreign_api::request_of(body), transaction_id, req);
+    }
+    let transaction_id = match transaction_id {
+        Ok(transaction_id) => transaction_id,
+        Err(e) => return Box::new(err(e)),
+    };
+    let received = receive_slate(body, transaction_id, req);
+    Box::new(compat::to_01(async move {
+        let slate = received.await?;
+        Ok::<_, Error>(HttpResponse::Ok().json(slate))
+    }))
+}
+
+/// Has the wallet receive the slate and answers it in the version it was
+/// sent in. The request is only read here, the returned future doesn't
+/// borrow it
+pub fn receive_slate(
+    slate: serde_json::Value,
+    transaction_id: Uuid,
+    req: &HttpRequest<AppState>,
+) -> impl std::future::Future<Output = Result<VersionedSlate, Error>> {
+    let payer_user_agent = user_agent(req);
+    let state = req.state();
+    let db = state.db.clone();
+    let fsm = state.fsm.clone();
+    let wallet = state.wallet.clone();
+    let trace = trace::request_context(req);
+    async move {
+        // The buyer gets the answer in the version of their slate
+        let versioned = VersionedSlate::parse(slate)?;
+        // Crafted slates never reach the wallet
+        let slate = versioned.to_valid_slate()?;
+        let slate_version = versioned.version() as i32;
+        let slate_id = slate.id.hyphenated().to_string();
+        let slate_amount = slate.amount;
+        let sender = slate
+            .participant_data
+            .iter()
+            .find(|participant| participant.id == 0);
+        let slate_message = sender.and_then(|sender| sender.message.clone());
+        // A signature which doesn't verify doesn't stop the payment, it's only
+        // not taken as evidence of who paid
+        let (payer_public_key, payer_message_unverified) =
+            match sender.map(ParticipantData::message_signer) {
+                Some(Err(e)) => {
+                    warn!(
+                        "Slate message of payment {} is unverified: {}",
+                        transaction_id, e
+                    );
+                    metrics::inc("unverified_slate_messages_total", &[]);
+                    (None, true)
+                }
+                Some(Ok(public_key)) => (public_key, false),
+                None => (None, false),
+            };
+        let new_payment = fsm
+            .send(GetNewPayment { transaction_id })
+            .traced(Span::child("fsm GetNewPayment", trace.as_ref()))
+            .compat()
+            .await??;
+        let payment_amount = new_payment.grin_amount as u64;
+        if new_payment.is_invalid_amount(slate_amount) {
+            return Err(Error::WrongAmount(payment_amount, slate_amount));
+        }
+        let merchant = db
+            .send(GetMerchant {
+                id: new_payment.merchant_id.clone(),
+            })
+            .compat()
+            .await??;
+        merchant
+            .slate_message_check()
+            .check(&new_payment, slate_message.as_ref().map(String::as_str))?;
+        // A buyer posting twice, to this or another payment, must not make
+        // the wallet receive twice
+        db.send(ClaimPayment {
+            transaction_id,
+            slate_id,
         })
-        .and_then({
-            let wallet = state.wallet.clone();
-            let fsm = state.fsm.clone();
-            move |new_payment| {
-                let slate = wallet.receive(&slate);
-                slate.and_then(move |slate| {
-                    let commit = slate.tx.output_commitments()[0].clone();
-                    wallet
-                        .get_tx(&slate.id.hyphenated().to_string())
-                        .and_then(move |wallet_tx| {
-                            fsm.send(MakePayment {
-                                new_payment,
-                                wallet_tx,
-                                commit,
-                            })
-                            .from_err()
-                            .and_then(|db_response| {
-                                db_response?;
-                                Ok(())
-                            })
-                        })
-                        .and_then(|_| ok(slate))
-                })
+        .compat()
+        .await??;
+        // Once the wallet received the slate the claim is kept, resubmitting
+        // it must not have it received again
+        let slate = match wallet
+            .receive(&slate)
+            .traced(Span::child("wallet receive", trace.as_ref()))
+            .compat()
+            .await
+        {
+            Ok(slate) => slate,
+            Err(e) => {
+                db.do_send(ReleasePayment { transaction_id });
+                return Err(e);
             }
+        };
+        let commit = slate.tx.output_commitments()[0].clone();
+        let tx_slate_id = slate.id.hyphenated().to_string();
+        let wallet_tx = wallet
+            .get_tx(&tx_slate_id)
+            .traced(Span::child("wallet get_tx", trace.as_ref()).with_params(&tx_slate_id))
+            .compat()
+            .await?;
+        fsm.send(MakePayment {
+            new_payment,
+            wallet_tx,
+            commit,
+            payer_public_key,
+            payer_message_unverified,
+            slate_version,
+            payer_user_agent,
+        })
+        .traced(Span::child("fsm MakePayment", trace.as_ref()))
+        .compat()
+        .await??;
+        versioned.answer(slate)
+    }
+}
+
+/// `User-Agent` of the buyer's wallet, cut to `MAX_USER_AGENT_LENGTH`
+fn user_agent(req: &HttpRequest<AppState>) -> Option<String> {
+    req.headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(|value| value.chars().take(MAX_USER_AGENT_LENGTH).collect())
+}
+
+/// How the payment's grin amount was calculated, for merchants answering
+/// buyers who dispute the price
+pub fn get_payment_conversion(
+    (merchant, path, state): (BasicAuth<Merchant>, Path<(String, Uuid)>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let (merchant_id, transaction_id) = path.into_inner();
+    if merchant.id != merchant_id {
+        return Box::new(ok(HttpResponse::BadRequest().finish()));
+    }
+    if let Err(e) = merchant.require(ApiScope::ReadPayments) {
+        return Box::new(err(e.into()));
+    }
+    let db = state.db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let payment = db
+                .send(GetTransaction { transaction_id })
+                .compat()
+                .await??;
+            if payment.merchant_id != merchant_id
+                || payment.transaction_type != TransactionType::Payment
+            {
+                return Err(Error::EntityNotFound(s!("payment")));
+            }
+            Ok(HttpResponse::Ok().json(Conversion::of(&payment)))
+        })
+        .from_err(),
+    )
+}
+
+#[derive(Debug, Serialize)]
+struct CheckoutLink {
+    checkout_url: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Fresh checkout link for a buyer whose link expired
+pub fn create_checkout_link(
+    (merchant, path, state): (BasicAuth<Merchant>, Path<(String, Uuid)>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let (merchant_id, transaction_id) = path.into_inner();
+    if merchant.id != merchant_id {
+        return Box::new(ok(HttpResponse::BadRequest().finish()));
+    }
+    if let Err(e) = merchant.require(ApiScope::CreatePayments) {
+        return Box::new(err(e.into()));
+    }
+    let db = state.db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let payment = db
+                .send(GetTransaction { transaction_id })
+                .compat()
+                .await??;
+            if payment.merchant_id != merchant_id
+                || payment.transaction_type != TransactionType::Payment
+            {
+                return Err(Error::EntityNotFound(s!("payment")));
+            }
+            let token = CheckoutToken::new(payment.id, Utc::now().timestamp());
+            Ok(HttpResponse::Created().json(CheckoutLink {
+                checkout_url: token.url()?,
+                expires_at: token.expires_at_utc(),
+            }))
+        })
+        .from_err(),
+    )
+}
+
+/// Requotes the merchant's payment by id, as the buyer does on the page
+pub fn requote_payment(
+    (merchant, path, req): (
+        BasicAuth<Merchant>,
+        Path<(String, Uuid)>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let (merchant_id, transaction_id) = path.into_inner();
+    if merchant.id != merchant_id {
+        return Box::new(ok(HttpResponse::BadRequest().finish()));
+    }
+    if let Err(e) = merchant.require(ApiScope::ReadPayments) {
+        return Box::new(err(e.into()));
+    }
+    requote(transaction_id, Some(merchant_id), &req)
+}
+
+/// Merchants pass their id and only requote their own payments
+pub fn requote(
+    transaction_id: Uuid,
+    merchant_id: Option<String>,
+    req: &HttpRequest<AppState>,
+) -> FutureResponse<HttpResponse> {
+    let db = req.state().db.clone();
+    let fsm = req.state().fsm.clone();
+    i18n::localize(
+        Language::of(req),
+        compat::to_01(async move {
+            if let Some(merchant_id) = merchant_id {
+                let tx = db
+                    .send(GetTransaction { transaction_id })
+                    .compat()
+                    .await??;
+                if tx.merchant_id != merchant_id {
+                    return Err(Error::EntityNotFound(s!("payment")));
+                }
+            }
+            let new_payment = fsm
+                .send(RequotePayment { transaction_id })
+                .compat()
+                .await??;
+            Ok::<_, Error>(HttpResponse::Ok().json(new_payment))
+        }),
+    )
+}
+
+/// Each payload verifies once, a replayed return link fails
+pub fn verify_return_payload(
+    (merchant, merchant_id, payload, state): (
+        BasicAuth<Merchant>,
+        Path<String>,
+        SimpleJson<ReturnPayload>,
+        State<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    if merchant.id != merchant_id.into_inner() {
+        return Box::new(ok(HttpResponse::BadRequest().finish()));
+    }
+    if let Err(e) = merchant.require(ApiScope::ReadPayments) {
+        return Box::new(err(e.into()));
+    }
+    let payload = payload.into_inner();
+    if let Err(e) = payload.verify(&merchant.token, Utc::now().timestamp()) {
+        return Box::new(err(e.into()));
+    }
+    let db = state.db.clone();
+    Box::new(
+        compat::to_01(async move {
+            db.send(UseReturnNonce {
+                merchant_id: merchant.id.clone(),
+                nonce: payload.nonce.clone(),
+            })
+            .compat()
+            .await??;
+            Ok::<_, Error>(HttpResponse::Ok().json(payload))
         })
-        .and_then(|slate| Ok(HttpResponse::Ok().json(slate)))
-        .responder()
+        .from_err(),
+    )
 }
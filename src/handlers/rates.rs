@@ -0,0 +1,89 @@
+use crate::app::AppState;
+use crate::compat::{self, Future01CompatExt};
+use crate::db::GetRates;
+use crate::errors::*;
+use crate::models::{Currency, Rate};
+use crate::rates::{rate_age_seconds, MAX_RATE_AGE_SECONDS};
+use actix_web::http::header;
+use actix_web::{FutureResponse, HttpResponse, Query, State};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use futures::future::{err, Future};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+/// Rates are fetched every 5 seconds
+const RATES_CACHE_CONTROL: &str = "public, max-age=5";
+
+#[derive(Debug, Deserialize)]
+pub struct RatesQuery {
+    /// Comma separated currencies, all rated ones when missing
+    pub currency: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct RateResponse {
+    currency: String,
+    /// Price of 1 grin in the currency
+    rate: Decimal,
+    updated_at: DateTime<Utc>,
+    /// Payments in the currency are refused
+    stale: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct RatesResponse {
+    rates: Vec<RateResponse>,
+}
+
+impl RateResponse {
+    fn of(rate: Rate, now: NaiveDateTime) -> Self {
+        RateResponse {
+            stale: rate_age_seconds(&rate, now) > *MAX_RATE_AGE_SECONDS,
+            updated_at: DateTime::from_utc(rate.updated_at, Utc),
+            currency: rate.id,
+            rate: rate.rate,
+        }
+    }
+}
+
+fn parse_currencies(currency: &str) -> Result<Vec<String>, Error> {
+    currency
+        .split(',')
+        .filter(|c| !c.trim().is_empty())
+        .map(|c| match c.parse::<Currency>() {
+            Ok(Currency::GRIN) | Err(_) => Err(Error::UnsupportedCurrency(c.trim().to_owned())),
+            Ok(c) => Ok(c.to_string()),
+        })
+        .collect()
+}
+
+/// Current GRIN rates for merchant frontends to show estimated prices,
+/// no auth as they're public anyway
+pub fn get_rates(
+    (query, state): (Query<RatesQuery>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let currencies = match query.currency.as_ref().map(|c| parse_currencies(c)) {
+        Some(Err(e)) => return Box::new(err(e.into())),
+        Some(Ok(currencies)) => Some(currencies),
+        None => None,
+    };
+    let db = state.db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let rates = db.send(GetRates).compat().await??;
+            let now = Utc::now().naive_utc();
+            let rates = rates
+                .into_iter()
+                .filter(|rate| currencies.as_ref().map_or(true, |c| c.contains(&rate.id)))
+                .map(|rate| RateResponse::of(rate, now))
+                .collect();
+            Ok::<_, Error>(
+                HttpResponse::Ok()
+                    .header(header::CACHE_CONTROL, RATES_CACHE_CONTROL)
+                    .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+                    .json(RatesResponse { rates }),
+            )
+        })
+        .from_err(),
+    )
+}
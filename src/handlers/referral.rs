@@ -0,0 +1,99 @@
+use crate::app::AppState;
+use crate::compat::{self, Future01CompatExt};
+use crate::db::{DbExecutor, GetReferralMonths, GetReferredMerchants};
+use crate::errors::*;
+use crate::extractor::{BasicAuth, Identity};
+use crate::filters;
+use crate::models::{ApiScope, Merchant};
+use crate::referrals::{ReferralMonth, SUMMARY_MONTHS};
+use actix::Addr;
+use actix_web::{FutureResponse, HttpRequest, HttpResponse, Path};
+use askama::Template;
+use futures::future::{err, ok, Future};
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+struct ReferredMerchant {
+    id: String,
+    /// Of the merchant's knockturn fees
+    percent: Decimal,
+}
+
+impl From<Merchant> for ReferredMerchant {
+    fn from(merchant: Merchant) -> Self {
+        ReferredMerchant {
+            id: merchant.id,
+            percent: Decimal::new(i64::from(merchant.referral_share_bps.unwrap_or(0)), 2),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Referrals {
+    merchants: Vec<ReferredMerchant>,
+    months: Vec<ReferralMonth>,
+}
+
+async fn load_referrals(db: Addr<DbExecutor>, referrer_id: String) -> Result<Referrals, Error> {
+    let merchants = db
+        .send(GetReferredMerchants {
+            referrer_id: referrer_id.clone(),
+        })
+        .compat()
+        .await??;
+    let months = db
+        .send(GetReferralMonths {
+            referrer_id,
+            limit: SUMMARY_MONTHS,
+        })
+        .compat()
+        .await??;
+    Ok(Referrals {
+        merchants: merchants.into_iter().map(ReferredMerchant::from).collect(),
+        months,
+    })
+}
+
+#[derive(Template)]
+#[template(path = "referrals.html")]
+struct ReferralsTemplate {
+    referrals: Referrals,
+}
+
+/// Merchants the merchant referred and what it earned per month
+pub fn referrals(
+    (merchant, req): (Identity<Merchant>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let referrals = load_referrals(db, merchant.into_inner().id).await?;
+            let html = ReferralsTemplate { referrals }
+                .render()
+                .map_err(|e| Error::from(e))?;
+            Ok::<_, Error>(HttpResponse::Ok().content_type("text/html").body(html))
+        })
+        .from_err(),
+    )
+}
+
+pub fn get_referrals(
+    (merchant, merchant_id, req): (BasicAuth<Merchant>, Path<String>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let merchant_id = merchant_id.into_inner();
+    if merchant.id != merchant_id {
+        return Box::new(ok(HttpResponse::BadRequest().finish()));
+    }
+    if let Err(e) = merchant.require(ApiScope::ReadStats) {
+        return Box::new(err(e.into()));
+    }
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let referrals = load_referrals(db, merchant_id).await?;
+            Ok::<_, Error>(HttpResponse::Ok().json(referrals))
+        })
+        .from_err(),
+    )
+}
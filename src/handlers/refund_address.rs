@@ -0,0 +1,299 @@
+use crate::app::AppState;
+use crate::compat::{self, Future01CompatExt};
+use crate::db::{CreateRefundAddress, DeleteRefundAddress, GetRefundAddresses, SetRefundAddress};
+use crate::errors::*;
+use crate::extractor::{BasicAuth, Identity, SimpleJson};
+use crate::filters;
+use crate::geoip;
+use crate::models::{ApiScope, Merchant, RefundAddress, Transaction};
+use crate::refund_addresses::{
+    self, RefundAddressConfig, MAX_ADDRESS_LENGTH, MAX_LABEL_LENGTH, REFUND_ADDRESS_CONFIG,
+};
+use actix_web::{Form, FutureResponse, HttpRequest, HttpResponse, Path};
+use askama::Template;
+use chrono::{NaiveDateTime, Utc};
+use futures::future::{err, ok, Future};
+use serde::Deserialize;
+use uuid::Uuid;
+
+#[derive(Template)]
+#[template(path = "refund_addresses.html")]
+struct RefundAddressesTemplate<'a> {
+    merchant: &'a Merchant,
+    addresses: Vec<RefundAddress>,
+    config: &'a RefundAddressConfig,
+    now: NaiveDateTime,
+    max_label_length: usize,
+    max_address_length: usize,
+}
+
+pub fn refund_addresses(
+    (merchant, req): (Identity<Merchant>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let merchant = merchant.into_inner();
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let addresses = db
+                .send(GetRefundAddresses {
+                    merchant_id: merchant.id.clone(),
+                })
+                .compat()
+                .await??;
+            let html = RefundAddressesTemplate {
+                merchant: &merchant,
+                addresses,
+                config: &REFUND_ADDRESS_CONFIG,
+                now: Utc::now().naive_utc(),
+                max_label_length: MAX_LABEL_LENGTH,
+                max_address_length: MAX_ADDRESS_LENGTH,
+            }
+            .render()
+            .map_err(|e| Error::from(e))?;
+            Ok::<_, Error>(HttpResponse::Ok().content_type("text/html").body(html))
+        })
+        .from_err(),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefundAddressForm {
+    pub label: String,
+    pub address: String,
+}
+
+/// The request is only read here, the returned future doesn't borrow it
+fn create_address(
+    req: &HttpRequest<AppState>,
+    merchant_id: &str,
+    form: &RefundAddressForm,
+) -> impl std::future::Future<Output = Result<RefundAddress, Error>> {
+    let db = req.state().db.clone();
+    let ip = geoip::client_ip(req).map(|ip| ip.to_string());
+    let country = geoip::locate(req).country;
+    let address = refund_addresses::new_address(
+        merchant_id,
+        &form.label,
+        &form.address,
+        Utc::now().naive_utc(),
+        &REFUND_ADDRESS_CONFIG,
+    );
+    async move {
+        db.send(CreateRefundAddress {
+            address: address?,
+            ip,
+            country,
+        })
+        .compat()
+        .await?
+    }
+}
+
+fn delete_address(
+    req: &HttpRequest<AppState>,
+    merchant_id: String,
+    id: Uuid,
+) -> impl std::future::Future<Output = Result<(), Error>> {
+    let delete = DeleteRefundAddress {
+        id,
+        merchant_id,
+        ip: geoip::client_ip(req).map(|ip| ip.to_string()),
+        country: geoip::locate(req).country,
+    };
+    let db = req.state().db.clone();
+    async move { db.send(delete).compat().await? }
+}
+
+fn set_address(
+    req: &HttpRequest<AppState>,
+    merchant_id: String,
+    transaction_id: Uuid,
+    address: &str,
+) -> impl std::future::Future<Output = Result<Transaction, Error>> {
+    let db = req.state().db.clone();
+    let address = refund_addresses::parse_address(address);
+    async move {
+        db.send(SetRefundAddress {
+            merchant_id,
+            transaction_id,
+            address: address?,
+        })
+        .compat()
+        .await?
+    }
+}
+
+pub fn create(
+    (merchant, req, form): (
+        Identity<Merchant>,
+        HttpRequest<AppState>,
+        Form<RefundAddressForm>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let create = create_address(&req, &merchant.id, &form);
+    Box::new(
+        compat::to_01(async move {
+            create.await?;
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", "/refund_addresses")
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
+
+pub fn delete(
+    (merchant, req, address_id): (Identity<Merchant>, HttpRequest<AppState>, Path<Uuid>),
+) -> FutureResponse<HttpResponse> {
+    let delete = delete_address(&req, merchant.into_inner().id, address_id.into_inner());
+    Box::new(
+        compat::to_01(async move {
+            delete.await?;
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", "/refund_addresses")
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefundDestinationForm {
+    pub address: String,
+}
+
+/// Sets the refund address from the transaction page
+pub fn set_refund_address(
+    (merchant, req, transaction_id, form): (
+        Identity<Merchant>,
+        HttpRequest<AppState>,
+        Path<Uuid>,
+        Form<RefundDestinationForm>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let transaction_id = transaction_id.into_inner();
+    let set = set_address(
+        &req,
+        merchant.into_inner().id,
+        transaction_id,
+        &form.address,
+    );
+    Box::new(
+        compat::to_01(async move {
+            set.await?;
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", format!("/transactions/{}", transaction_id))
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
+
+pub fn get_refund_addresses(
+    (merchant, merchant_id, req): (BasicAuth<Merchant>, Path<String>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let merchant_id = merchant_id.into_inner();
+    if merchant.id != merchant_id {
+        return Box::new(ok(HttpResponse::BadRequest().finish()));
+    }
+    if let Err(e) = merchant.require(ApiScope::ReadPayments) {
+        return Box::new(err(e.into()));
+    }
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let addresses = db
+                .send(GetRefundAddresses { merchant_id })
+                .compat()
+                .await??;
+            Ok::<_, Error>(HttpResponse::Ok().json(addresses))
+        })
+        .from_err(),
+    )
+}
+
+pub fn create_refund_address(
+    (merchant, merchant_id, address_req, req): (
+        BasicAuth<Merchant>,
+        Path<String>,
+        SimpleJson<RefundAddressForm>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let merchant_id = merchant_id.into_inner();
+    if merchant.id != merchant_id {
+        return Box::new(ok(HttpResponse::BadRequest().finish()));
+    }
+    if let Err(e) = merchant.require(ApiScope::CreatePayouts) {
+        return Box::new(err(e.into()));
+    }
+    let create = create_address(&req, &merchant_id, &address_req);
+    Box::new(
+        compat::to_01(async move {
+            let address = create.await?;
+            Ok::<_, Error>(HttpResponse::Created().json(address))
+        })
+        .from_err(),
+    )
+}
+
+pub fn delete_refund_address(
+    (merchant, path, req): (
+        BasicAuth<Merchant>,
+        Path<(String, Uuid)>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let (merchant_id, address_id) = path.into_inner();
+    if merchant.id != merchant_id {
+        return Box::new(ok(HttpResponse::BadRequest().finish()));
+    }
+    if let Err(e) = merchant.require(ApiScope::CreatePayouts) {
+        return Box::new(err(e.into()));
+    }
+    let delete = delete_address(&req, merchant_id, address_id);
+    Box::new(
+        compat::to_01(async move {
+            delete.await?;
+            Ok::<_, Error>(HttpResponse::NoContent().finish())
+        })
+        .from_err(),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetRefundAddressRequest {
+    pub address: String,
+}
+
+/// Where a refunded payment is returned to
+pub fn set_payment_refund_address(
+    (merchant, path, address_req, req): (
+        BasicAuth<Merchant>,
+        Path<(String, Uuid)>,
+        SimpleJson<SetRefundAddressRequest>,
+        HttpRequest<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let (merchant_id, transaction_id) = path.into_inner();
+    if merchant.id != merchant_id {
+        return Box::new(ok(HttpResponse::BadRequest().finish()));
+    }
+    if let Err(e) = merchant.require(ApiScope::CreatePayouts) {
+        return Box::new(err(e.into()));
+    }
+    let set = set_address(&req, merchant_id, transaction_id, &address_req.address);
+    Box::new(
+        compat::to_01(async move {
+            let transaction = set.await?;
+            Ok::<_, Error>(HttpResponse::Ok().json(transaction))
+        })
+        .from_err(),
+    )
+}
@@ -0,0 +1,314 @@
+use crate::app::AppState;
+use crate::compat::{self, Future01CompatExt};
+use crate::db::{
+    CreateWebauthnCredential, DbExecutor, DeleteWebauthnCredential, GetWebauthnCredential,
+    GetWebauthnCredentials, SetSecondFactor, UpdateWebauthnSignCount,
+};
+use crate::errors::*;
+use crate::extractor::{Identity, Session, SimpleJson};
+use crate::filters;
+use crate::models::{Merchant, SecondFactor, WebauthnCredential};
+use crate::webauthn::{self, RelyingParty, CHALLENGE_SESSION_KEY};
+use actix::Addr;
+use actix_web::middleware::identity::RequestIdentity;
+use actix_web::middleware::session::RequestSession;
+use actix_web::{Form, FutureResponse, HttpRequest, HttpResponse, Path};
+use askama::Template;
+use chrono::Utc;
+use data_encoding::BASE64URL_NOPAD;
+use futures::future::{err, Future};
+use serde::{Deserialize, Serialize};
+use std::env;
+
+fn relying_party() -> RelyingParty {
+    RelyingParty::from_domain(&env::var("DOMAIN").unwrap())
+}
+
+fn store_challenge(req: &HttpRequest<AppState>) -> Result<String, Error> {
+    let challenge = webauthn::new_challenge();
+    req.session()
+        .set(CHALLENGE_SESSION_KEY, challenge.clone())
+        .map_err(|e| Error::General(s!(e)))?;
+    Ok(challenge)
+}
+
+/// Challenge can be used only once
+fn take_challenge(req: &HttpRequest<AppState>) -> Result<String, Error> {
+    let challenge = req
+        .session()
+        .get::<String>(CHALLENGE_SESSION_KEY)
+        .map_err(|e| Error::General(s!(e)))?
+        .ok_or(Error::SecurityKey(s!("no pending challenge")))?;
+    req.session().remove(CHALLENGE_SESSION_KEY);
+    Ok(challenge)
+}
+
+async fn load_credentials(
+    db: &Addr<DbExecutor>,
+    merchant_id: String,
+) -> Result<Vec<WebauthnCredential>, Error> {
+    db.send(GetWebauthnCredentials { merchant_id })
+        .compat()
+        .await?
+}
+
+#[derive(Template)]
+#[template(path = "security_keys.html")]
+struct SecurityKeysTemplate<'a> {
+    merchant: &'a Merchant,
+    credentials: Vec<WebauthnCredential>,
+}
+
+pub fn security_keys(
+    (merchant, req): (Identity<Merchant>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let merchant = merchant.into_inner();
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let credentials = load_credentials(&db, merchant.id.clone()).await?;
+            let html = SecurityKeysTemplate {
+                merchant: &merchant,
+                credentials,
+            }
+            .render()
+            .map_err(|e| Error::from(e))?;
+            Ok::<_, Error>(HttpResponse::Ok().content_type("text/html").body(html))
+        })
+        .from_err(),
+    )
+}
+
+#[derive(Debug, Serialize)]
+struct RegistrationChallenge {
+    challenge: String,
+    rp_id: String,
+    user_id: String,
+    user_name: String,
+    exclude_credentials: Vec<String>,
+}
+
+pub fn registration_challenge(
+    (merchant, req): (Identity<Merchant>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let merchant = merchant.into_inner();
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let credentials = load_credentials(&db, merchant.id.clone()).await?;
+            Ok::<_, Error>(HttpResponse::Ok().json(RegistrationChallenge {
+                challenge: store_challenge(&req)?,
+                rp_id: relying_party().id,
+                user_id: BASE64URL_NOPAD.encode(merchant.id.as_bytes()),
+                user_name: merchant.id,
+                exclude_credentials: credentials.into_iter().map(|c| c.id).collect(),
+            }))
+        })
+        .from_err(),
+    )
+}
+
+/// Binary fields are base64url encoded without padding
+#[derive(Debug, Deserialize)]
+pub struct RegistrationRequest {
+    pub id: String,
+    pub name: String,
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub public_key: String,
+}
+
+pub fn register(
+    (merchant, req, registration): (
+        Identity<Merchant>,
+        HttpRequest<AppState>,
+        SimpleJson<RegistrationRequest>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let merchant = merchant.into_inner();
+    let registration = registration.into_inner();
+    let credential = take_challenge(&req).and_then(|challenge| {
+        let public_key = webauthn::decode(&registration.public_key)?;
+        let sign_count = relying_party().verify_registration(
+            &challenge,
+            &webauthn::decode(&registration.client_data_json)?,
+            &webauthn::decode(&registration.authenticator_data)?,
+            &public_key,
+        )?;
+        Ok(WebauthnCredential {
+            id: BASE64URL_NOPAD.encode(&webauthn::decode(&registration.id)?),
+            merchant_id: merchant.id,
+            name: registration.name,
+            public_key,
+            sign_count: sign_count as i64,
+            created_at: Utc::now().naive_utc(),
+        })
+    });
+    let credential = match credential {
+        Ok(v) => v,
+        Err(e) => return Box::new(err(e.into())),
+    };
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let credential = db
+                .send(CreateWebauthnCredential(credential))
+                .compat()
+                .await??;
+            Ok::<_, Error>(HttpResponse::Created().json(credential))
+        })
+        .from_err(),
+    )
+}
+
+pub fn delete(
+    (merchant, req, credential_id): (Identity<Merchant>, HttpRequest<AppState>, Path<String>),
+) -> FutureResponse<HttpResponse> {
+    let merchant = merchant.into_inner();
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let credentials = load_credentials(&db, merchant.id.clone()).await?;
+            // the last key can't be removed while it's the only second factor
+            if merchant.second_factor == SecondFactor::SecurityKey && credentials.len() <= 1 {
+                return Err(Error::InvalidEntity(s!(
+                    "can't remove the last security key, switch to TOTP first"
+                )));
+            }
+            db.send(DeleteWebauthnCredential {
+                id: credential_id.into_inner(),
+                merchant_id: merchant.id,
+            })
+            .compat()
+            .await??;
+            Ok(HttpResponse::Found()
+                .header("location", "/security_keys")
+                .finish())
+        })
+        .from_err(),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SecondFactorForm {
+    pub second_factor: SecondFactor,
+}
+
+pub fn set_second_factor(
+    (merchant, req, form): (
+        Identity<Merchant>,
+        HttpRequest<AppState>,
+        Form<SecondFactorForm>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let merchant = merchant.into_inner();
+    let second_factor = form.second_factor;
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let credentials = load_credentials(&db, merchant.id.clone()).await?;
+            // don't let merchants lock themselves out
+            if second_factor == SecondFactor::SecurityKey && credentials.is_empty() {
+                return Err(Error::InvalidEntity(s!("register a security key first")));
+            }
+            if second_factor.allows_totp() && !merchant.confirmed_2fa {
+                return Err(Error::InvalidEntity(s!("set up TOTP first")));
+            }
+            db.send(SetSecondFactor {
+                merchant_id: merchant.id,
+                second_factor,
+            })
+            .compat()
+            .await??;
+            Ok(HttpResponse::Found()
+                .header("location", "/security_keys")
+                .finish())
+        })
+        .from_err(),
+    )
+}
+
+#[derive(Debug, Serialize)]
+struct AuthenticationChallenge {
+    challenge: String,
+    rp_id: String,
+    allow_credentials: Vec<String>,
+}
+
+pub fn authentication_challenge(
+    (merchant, req): (Session<Merchant>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let merchant = merchant.into_inner();
+    if !merchant.second_factor.allows_security_key() {
+        return Box::new(err(Error::NotAuthorized.into()));
+    }
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let credentials = load_credentials(&db, merchant.id.clone()).await?;
+            Ok::<_, Error>(HttpResponse::Ok().json(AuthenticationChallenge {
+                challenge: store_challenge(&req)?,
+                rp_id: relying_party().id,
+                allow_credentials: credentials.into_iter().map(|c| c.id).collect(),
+            }))
+        })
+        .from_err(),
+    )
+}
+
+/// Binary fields are base64url encoded without padding
+#[derive(Debug, Deserialize)]
+pub struct AuthenticationRequest {
+    pub id: String,
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+pub fn authenticate(
+    (merchant, req, assertion): (
+        Session<Merchant>,
+        HttpRequest<AppState>,
+        SimpleJson<AuthenticationRequest>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let merchant = merchant.into_inner();
+    if !merchant.second_factor.allows_security_key() {
+        return Box::new(err(Error::NotAuthorized.into()));
+    }
+    let assertion = assertion.into_inner();
+    let challenge = match take_challenge(&req) {
+        Ok(v) => v,
+        Err(e) => return Box::new(err(e.into())),
+    };
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let credential = db
+                .send(GetWebauthnCredential {
+                    id: assertion.id.clone(),
+                    merchant_id: merchant.id.clone(),
+                })
+                .compat()
+                .await?
+                .map_err(|_| Error::NotAuthorized)?;
+            let sign_count = relying_party().verify_assertion(
+                &challenge,
+                &webauthn::decode(&assertion.client_data_json)?,
+                &webauthn::decode(&assertion.authenticator_data)?,
+                &webauthn::decode(&assertion.signature)?,
+                &credential.public_key,
+                credential.sign_count,
+            )?;
+            db.send(UpdateWebauthnSignCount {
+                id: credential.id,
+                sign_count: sign_count as i64,
+            })
+            .compat()
+            .await??;
+            req.remember(merchant.id);
+            Ok::<_, Error>(HttpResponse::Ok().json(serde_json::json!({ "redirect": "/" })))
+        })
+        .from_err(),
+    )
+}
@@ -0,0 +1,110 @@
+use crate::app::AppState;
+use crate::compat::{self, Future01CompatExt};
+use crate::db::{GetNotes, GetSettledTransactions, GetSettlementDays, GetSettlementShares};
+use crate::errors::*;
+use crate::extractor::BasicAuth;
+use crate::models::{ApiScope, Merchant};
+use crate::pdf;
+use crate::settlement::Settlement;
+use actix_web::http::header;
+use actix_web::{FutureResponse, HttpResponse, Path, Query, State};
+use chrono::NaiveDate;
+use futures::future::{err, ok, Future};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct ListSettlementsQuery {
+    pub offset: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+const MAX_SETTLEMENTS_PER_PAGE: i64 = 100;
+
+pub fn get_settlements(
+    (merchant, merchant_id, query, state): (
+        BasicAuth<Merchant>,
+        Path<String>,
+        Query<ListSettlementsQuery>,
+        State<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let merchant_id = merchant_id.into_inner();
+    if merchant.id != merchant_id {
+        return Box::new(ok(HttpResponse::BadRequest().finish()));
+    }
+    if let Err(e) = merchant.require(ApiScope::ReadPayments) {
+        return Box::new(err(e.into()));
+    }
+    let get_days = GetSettlementDays {
+        merchant_id,
+        offset: query.offset.unwrap_or(0),
+        limit: query
+            .limit
+            .unwrap_or(MAX_SETTLEMENTS_PER_PAGE)
+            .min(MAX_SETTLEMENTS_PER_PAGE),
+    };
+    let db = state.db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let days = db.send(get_days).compat().await??;
+            Ok::<_, Error>(HttpResponse::Ok().json(days))
+        })
+        .from_err(),
+    )
+}
+
+/// Signed statement of one day as PDF, `date` is `YYYY-MM-DD` in UTC
+pub fn get_settlement_pdf(
+    (merchant, path, state): (BasicAuth<Merchant>, Path<(String, String)>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let (merchant_id, date) = path.into_inner();
+    if merchant.id != merchant_id {
+        return Box::new(ok(HttpResponse::BadRequest().finish()));
+    }
+    if let Err(e) = merchant.require(ApiScope::ReadPayments) {
+        return Box::new(err(e.into()));
+    }
+    let date = match NaiveDate::parse_from_str(&date, "%Y-%m-%d") {
+        Ok(date) => date,
+        Err(_) => return Box::new(err(Error::InvalidEntity(s!("date")).into())),
+    };
+    let merchant = merchant.into_inner();
+    let db = state.db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let transactions = db
+                .send(GetSettledTransactions {
+                    merchant_id: merchant_id.clone(),
+                    date,
+                })
+                .compat()
+                .await??;
+            let notes = db
+                .send(GetNotes {
+                    transaction_ids: transactions.iter().map(|tx| tx.id).collect(),
+                })
+                .compat()
+                .await??;
+            let shares = db
+                .send(GetSettlementShares {
+                    merchant_id: merchant_id.clone(),
+                    date,
+                })
+                .compat()
+                .await??;
+            let settlement =
+                Settlement::new(&merchant_id, date, transactions, notes).with_shares(shares);
+            let lines = settlement.signed_lines(&merchant.token)?;
+            Ok::<_, Error>(
+                HttpResponse::Ok()
+                    .content_type("application/pdf")
+                    .header(
+                        header::CONTENT_DISPOSITION,
+                        format!("attachment; filename=\"settlement-{}.pdf\"", date),
+                    )
+                    .body(pdf::render(&lines)),
+            )
+        })
+        .from_err(),
+    )
+}
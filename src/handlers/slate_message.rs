@@ -0,0 +1,72 @@
+use crate::app::AppState;
+use crate::compat::{self, Future01CompatExt};
+use crate::db::UpdateSlateMessageCheck;
+use crate::errors::*;
+use crate::extractor::Identity;
+use crate::models::{Merchant, SlateMessageCheck};
+use actix_web::{Form, FutureResponse, HttpRequest, HttpResponse};
+use askama::Template;
+use futures::future::Future;
+use serde::Deserialize;
+
+#[derive(Template)]
+#[template(path = "slate_message.html")]
+struct SlateMessageTemplate {
+    checks: Vec<CheckOption>,
+}
+
+struct CheckOption {
+    value: SlateMessageCheck,
+    description: &'static str,
+    selected: bool,
+}
+
+pub fn slate_message(merchant: Identity<Merchant>) -> Result<HttpResponse, Error> {
+    let current = merchant.slate_message_check();
+    let html = SlateMessageTemplate {
+        checks: SlateMessageCheck::ALL
+            .iter()
+            .map(|check| CheckOption {
+                value: *check,
+                description: match check {
+                    SlateMessageCheck::Off => "Accept any message",
+                    SlateMessageCheck::Exact => "Message must be the payment's message",
+                    SlateMessageCheck::ContainsId => "Message must contain the payment id",
+                },
+                selected: *check == current,
+            })
+            .collect(),
+    }
+    .render()?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SlateMessageForm {
+    pub check: SlateMessageCheck,
+}
+
+pub fn update_slate_message(
+    (merchant, req, form): (
+        Identity<Merchant>,
+        HttpRequest<AppState>,
+        Form<SlateMessageForm>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let update = UpdateSlateMessageCheck {
+        merchant_id: merchant.into_inner().id,
+        check: form.into_inner().check,
+    };
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            db.send(update).compat().await??;
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", "/slate_message")
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
@@ -0,0 +1,66 @@
+use crate::app::AppState;
+use crate::compat::{self, Future01CompatExt};
+use crate::db::UpdateTimezone;
+use crate::errors::*;
+use crate::extractor::Identity;
+use crate::models::Merchant;
+use actix_web::{Form, FutureResponse, HttpRequest, HttpResponse};
+use askama::Template;
+use chrono_tz::TZ_VARIANTS;
+use futures::future::Future;
+use serde::Deserialize;
+
+#[derive(Template)]
+#[template(path = "timezone.html")]
+struct TimezoneTemplate {
+    timezones: Vec<TimezoneOption>,
+}
+
+struct TimezoneOption {
+    name: &'static str,
+    selected: bool,
+}
+
+pub fn timezone(merchant: Identity<Merchant>) -> Result<HttpResponse, Error> {
+    let html = TimezoneTemplate {
+        timezones: TZ_VARIANTS
+            .iter()
+            .map(|tz| TimezoneOption {
+                name: tz.name(),
+                selected: tz.name() == merchant.timezone,
+            })
+            .collect(),
+    }
+    .render()?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TimezoneForm {
+    pub timezone: String,
+}
+
+pub fn update_timezone(
+    (merchant, req, form): (
+        Identity<Merchant>,
+        HttpRequest<AppState>,
+        Form<TimezoneForm>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let update = UpdateTimezone {
+        merchant_id: merchant.into_inner().id,
+        timezone: form.into_inner().timezone,
+    };
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            db.send(update).compat().await??;
+            Ok::<_, Error>(
+                HttpResponse::Found()
+                    .header("location", "/timezone")
+                    .finish(),
+            )
+        })
+        .from_err(),
+    )
+}
@@ -1,19 +1,26 @@
 use crate::app::AppState;
-use crate::blocking;
-use crate::db::GetMerchant;
+use crate::captcha::{self, Captcha};
+use crate::compat::{self, Future01CompatExt};
+use crate::db::{
+    DashboardStats, DbExecutor, DismissSecurityAlerts, GetApiRequests, GetCurrentHeight,
+    GetDashboardStats, GetMerchant, GetSecurityAlerts, GetTransactions, RecordLogin,
+};
 use crate::errors::*;
 use crate::extractor::Identity;
 use crate::filters;
+use crate::geoip;
 use crate::handlers::BootstrapColor;
 use crate::handlers::TemplateIntoResponse;
-use crate::models::{Merchant, Transaction, TransactionType};
+use crate::models::{ApiRequest, Merchant, SecurityEvent, Transaction, TransactionType};
+use crate::status::MerchantSla;
+use actix::Addr;
 use actix_web::middleware::identity::RequestIdentity;
 use actix_web::middleware::session::RequestSession;
-use actix_web::{AsyncResponder, Form, FutureResponse, HttpRequest, HttpResponse};
+use actix_web::{Form, FutureResponse, HttpRequest, HttpResponse};
 use askama::Template;
-use diesel::pg::PgConnection;
-use diesel::{self, prelude::*};
+use chrono_tz::Tz;
 use futures::future::Future;
+use log::{debug, info, warn};
 use serde::Deserialize;
 
 #[derive(Template)]
@@ -22,94 +29,183 @@ struct IndexTemplate<'a> {
     merchant: &'a Merchant,
     transactions: Vec<Transaction>,
     current_height: i64,
+    stats: DashboardStats,
+    sla: MerchantSla,
+    /// Shown as a banner until dismissed
+    security_alerts: Vec<SecurityEvent>,
+    tz: Tz,
 }
 
 pub fn index(
     (merchant, req): (Identity<Merchant>, HttpRequest<AppState>),
 ) -> FutureResponse<HttpResponse> {
     let merchant = merchant.into_inner();
-    blocking::run({
-        let merch_id = merchant.id.clone();
-        let pool = req.state().pool.clone();
-        move || {
-            let conn: &PgConnection = &pool.get().unwrap();
-            let txs = {
-                use crate::schema::transactions::dsl::*;
-                transactions
-                    .filter(merchant_id.eq(merch_id.clone()))
-                    .offset(0)
-                    .limit(10)
-                    .order(created_at.desc())
-                    .load::<Transaction>(conn)
-                    .map_err::<Error, _>(|e| e.into())
-            }?;
-                      let current_height = {
-                use crate::schema::current_height::dsl::*;
-                current_height
-                    .select(height)
-                    .first(conn)
-                    .map_err::<Error, _>(|e| e.into())
-            }?;
-            Ok((txs, current_height))
-        }
-    })
-    .from_err()
-    .and_then(move |(transactions,  current_height)| {
-        let html = IndexTemplate {
-            merchant: &merchant,
-            transactions: transactions,
-            current_height: current_height,
-        }
-        .render()
-        .map_err(|e| Error::from(e))?;
-        Ok(HttpResponse::Ok().content_type("text/html").body(html))
-    })
-    .responder()
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let (transactions, current_height) = recent_transactions(&db, &merchant).await?;
+            let stats = db
+                .send(GetDashboardStats {
+                    merchant_id: merchant.id.clone(),
+                })
+                .compat()
+                .await??;
+            let security_alerts = db
+                .send(GetSecurityAlerts {
+                    merchant_id: merchant.id.clone(),
+                })
+                .compat()
+                .await??;
+            let html = IndexTemplate {
+                merchant: &merchant,
+                transactions: transactions,
+                current_height: current_height,
+                stats: stats,
+                sla: MerchantSla::of(&merchant.id),
+                security_alerts,
+                tz: merchant.tz(),
+            }
+            .render()
+            .map_err(|e| Error::from(e))?;
+            Ok::<_, Error>(HttpResponse::Ok().content_type("text/html").body(html))
+        })
+        .from_err(),
+    )
+}
+
+/// Loads last transactions of the merchant together with the current height
+async fn recent_transactions(
+    db: &Addr<DbExecutor>,
+    merchant: &Merchant,
+) -> Result<(Vec<Transaction>, i64), Error> {
+    let transactions = db
+        .send(GetTransactions {
+            merchant_id: merchant.id.clone(),
+            offset: 0,
+            limit: 10,
+            transaction_type: None,
+            metadata_key: None,
+            metadata: None,
+        })
+        .compat()
+        .await??;
+    let current_height = db.send(GetCurrentHeight).compat().await??;
+    Ok((transactions, current_height))
 }
 
 #[derive(Debug, Deserialize)]
 pub struct LoginRequest {
     pub login: String,
     pub password: String,
+    /// Posted by the CAPTCHA widget, named by the provider
+    #[serde(rename = "h-captcha-response")]
+    pub h_captcha_response: Option<String>,
+    #[serde(rename = "g-recaptcha-response")]
+    pub g_recaptcha_response: Option<String>,
+}
+
+impl LoginRequest {
+    fn captcha_response(&self) -> Option<&str> {
+        self.h_captcha_response
+            .as_ref()
+            .or(self.g_recaptcha_response.as_ref())
+            .map(String::as_str)
+    }
 }
+
 pub fn login(
     (req, login_form): (HttpRequest<AppState>, Form<LoginRequest>),
 ) -> FutureResponse<HttpResponse> {
-    req.state()
-        .db
-        .send(GetMerchant {
-            id: login_form.login.clone(),
-        })
-        .from_err()
-        .and_then(move |db_response| {
-            let merchant = db_response?;
-            match bcrypt::verify(&login_form.password, &merchant.password) {
-                Ok(res) => {
-                    if res {
-                        req.session().set("merchant", merchant.id)?;
-                        if merchant.confirmed_2fa {
-                            Ok(HttpResponse::Found().header("location", "/2fa").finish())
-                        } else {
-                            Ok(HttpResponse::Found()
-                                .header("location", "/set_2fa")
-                                .finish())
-                        }
-                    } else {
-                        Ok(HttpResponse::Found().header("location", "/login").finish())
-                    }
+    let captcha = captcha::check(
+        req.state().captcha.as_ref(),
+        login_form.captcha_response(),
+        "login",
+    );
+    let db = req.state().db.clone();
+    let ip = geoip::client_ip(&req).map(|ip| ip.to_string());
+    let country = geoip::locate(&req).country;
+    Box::new(
+        compat::to_01(async move {
+            if let Err(e) = captcha.compat().await {
+                debug!("Login of {} without CAPTCHA: {}", login_form.login, e);
+                return Ok(HttpResponse::Found().header("location", "/login").finish());
+            }
+            let merchant = db
+                .send(GetMerchant {
+                    id: login_form.login.clone(),
+                })
+                .compat()
+                .await??;
+            let success = bcrypt::verify(&login_form.password, &merchant.password).unwrap_or(false);
+            let recorded = db
+                .send(RecordLogin {
+                    merchant_id: merchant.id.clone(),
+                    ip,
+                    country,
+                    success,
+                })
+                .compat()
+                .await;
+            // A login which can't be recorded isn't refused
+            match recorded {
+                Ok(Ok(Some(alert))) => {
+                    info!("Security alert {} for merchant {}", alert.kind, merchant.id)
                 }
-                Err(_) => Ok(HttpResponse::Found().header("location", "/login").finish()),
+                Ok(Ok(None)) => {}
+                Ok(Err(e)) => warn!("Cannot record login of {}: {}", merchant.id, e),
+                Err(e) => warn!("Cannot record login of {}: {}", merchant.id, e),
             }
+            if !success {
+                return Ok(HttpResponse::Found().header("location", "/login").finish());
+            }
+            req.session().set("merchant", &merchant.id)?;
+            Ok::<_, Error>(second_factor_redirect(&merchant))
         })
-        .responder()
+        .from_err(),
+    )
+}
+
+/// Where a merchant who passed the first factor continues the login
+pub fn second_factor_redirect(merchant: &Merchant) -> HttpResponse {
+    if merchant.confirmed_2fa || merchant.second_factor.allows_security_key() {
+        HttpResponse::Found().header("location", "/2fa").finish()
+    } else {
+        HttpResponse::Found()
+            .header("location", "/set_2fa")
+            .finish()
+    }
 }
 
 #[derive(Template)]
 #[template(path = "login.html")]
-struct LoginTemplate;
+struct LoginTemplate<'a> {
+    oidc_enabled: bool,
+    captcha: Option<&'a Captcha>,
+}
 
-pub fn login_form(_: HttpRequest<AppState>) -> Result<HttpResponse, Error> {
-    LoginTemplate.into_response()
+pub fn login_form(req: HttpRequest<AppState>) -> Result<HttpResponse, Error> {
+    LoginTemplate {
+        oidc_enabled: req.state().oidc.is_some(),
+        captcha: req.state().captcha.as_ref(),
+    }
+    .into_response()
+}
+
+pub fn dismiss_security_alerts(
+    (merchant, req): (Identity<Merchant>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            db.send(DismissSecurityAlerts {
+                merchant_id: merchant.into_inner().id,
+            })
+            .compat()
+            .await??;
+            Ok::<_, Error>(HttpResponse::Found().header("location", "/").finish())
+        })
+        .from_err(),
+    )
 }
 
 pub fn logout(req: HttpRequest<AppState>) -> Result<HttpResponse, Error> {
@@ -123,44 +219,60 @@ pub fn logout(req: HttpRequest<AppState>) -> Result<HttpResponse, Error> {
 struct TransactionsTemplate {
     transactions: Vec<Transaction>,
     current_height: i64,
+    tz: Tz,
 }
 
 pub fn get_transactions(
     (merchant, req): (Identity<Merchant>, HttpRequest<AppState>),
 ) -> FutureResponse<HttpResponse> {
     let merchant = merchant.into_inner();
-    blocking::run({
-        let merch_id = merchant.id.clone();
-        let pool = req.state().pool.clone();
-        move || {
-            use crate::schema::transactions::dsl::*;
-            let conn: &PgConnection = &pool.get().unwrap();
-            let txs = transactions
-                .filter(merchant_id.eq(merch_id))
-                .offset(0)
-                .limit(10)
-                .load::<Transaction>(conn)
-                .map_err::<Error, _>(|e| e.into())?;
-
-            let current_height = {
-                use crate::schema::current_height::dsl::*;
-                current_height
-                    .select(height)
-                    .first(conn)
-                    .map_err::<Error, _>(|e| e.into())
-            }?;
-            Ok((txs, current_height))
-        }
-    })
-    .from_err()
-    .and_then(|(transactions, current_height)| {
-        let html = TransactionsTemplate {
-            transactions,
-            current_height,
-        }
-        .render()
-        .map_err(|e| Error::from(e))?;
-        Ok(HttpResponse::Ok().content_type("text/html").body(html))
-    })
-    .responder()
+    let tz = merchant.tz();
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let (transactions, current_height) = recent_transactions(&db, &merchant).await?;
+            let html = TransactionsTemplate {
+                transactions,
+                current_height,
+                tz,
+            }
+            .render()
+            .map_err(|e| Error::from(e))?;
+            Ok::<_, Error>(HttpResponse::Ok().content_type("text/html").body(html))
+        })
+        .from_err(),
+    )
+}
+
+const API_REQUESTS_PER_PAGE: i64 = 50;
+
+#[derive(Template)]
+#[template(path = "api_requests.html")]
+struct ApiRequestsTemplate {
+    api_requests: Vec<ApiRequest>,
+    tz: Tz,
+}
+
+pub fn get_api_requests(
+    (merchant, req): (Identity<Merchant>, HttpRequest<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let merchant = merchant.into_inner();
+    let tz = merchant.tz();
+    let db = req.state().db.clone();
+    Box::new(
+        compat::to_01(async move {
+            let api_requests = db
+                .send(GetApiRequests {
+                    merchant_id: merchant.id,
+                    limit: API_REQUESTS_PER_PAGE,
+                })
+                .compat()
+                .await??;
+            let html = ApiRequestsTemplate { api_requests, tz }
+                .render()
+                .map_err(|e| Error::from(e))?;
+            Ok::<_, Error>(HttpResponse::Ok().content_type("text/html").body(html))
+        })
+        .from_err(),
+    )
 }
@@ -0,0 +1,215 @@
+//! Error messages in the buyer's language.
+//!
+//! Buyer facing endpoints (the payment page, its status and requote) answer
+//! errors with `{"code": ..., "message": ...}`. The `code` is
+//! `Error::code` and doesn't depend on the language, merchant backends
+//! should only look at it. The message is picked by `Accept-Language`,
+//! English when none of the buyer's languages is translated.
+
+use crate::errors::Error;
+use actix_web::http::header;
+use actix_web::{FutureResponse, HttpRequest, HttpResponse};
+use futures::future::Future;
+use serde::Serialize;
+use strum_macros::{Display, EnumString};
+
+#[derive(Debug, Clone, Copy, PartialEq, EnumString, Display)]
+pub enum Language {
+    #[strum(serialize = "en")]
+    En,
+    #[strum(serialize = "de")]
+    De,
+    #[strum(serialize = "es")]
+    Es,
+    #[strum(serialize = "ru")]
+    Ru,
+}
+
+impl Language {
+    /// Most preferred translated language of an `Accept-Language` header
+    pub fn negotiate(accept_language: Option<&str>) -> Self {
+        let mut best = None;
+        for item in accept_language.unwrap_or("").split(',') {
+            let mut parts = item.split(';');
+            let tag = parts.next().unwrap_or("").trim();
+            let quality = parts
+                .filter_map(|param| {
+                    let param = param.trim();
+                    if param.starts_with("q=") {
+                        param[2..].parse::<f32>().ok()
+                    } else {
+                        None
+                    }
+                })
+                .next()
+                .unwrap_or(1.0);
+            // "de-AT" is answered in German
+            let primary = tag.split('-').next().unwrap_or("").to_lowercase();
+            let language = match primary.parse::<Language>() {
+                Ok(language) if quality > 0.0 => language,
+                _ => continue,
+            };
+            match best {
+                Some((_, best_quality)) if best_quality >= quality => {}
+                _ => best = Some((language, quality)),
+            }
+        }
+        best.map(|(language, _)| language).unwrap_or(Language::En)
+    }
+
+    pub fn of<S>(req: &HttpRequest<S>) -> Self {
+        Language::negotiate(
+            req.headers()
+                .get(header::ACCEPT_LANGUAGE)
+                .and_then(|value| value.to_str().ok()),
+        )
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Language::En => 0,
+            Language::De => 1,
+            Language::Es => 2,
+            Language::Ru => 3,
+        }
+    }
+}
+
+/// Messages of the codes buyers can run into, in the order of `Language`
+fn translations(code: &str) -> [&'static str; 4] {
+    match code {
+        "not_found" => [
+            "The payment was not found.",
+            "Die Zahlung wurde nicht gefunden.",
+            "No se encontró el pago.",
+            "Платёж не найден.",
+        ],
+        "invalid_request" => [
+            "The request is invalid, please check what you entered.",
+            "Die Anfrage ist ungültig, bitte überprüfen Sie Ihre Eingabe.",
+            "La solicitud no es válida, revise lo que ha introducido.",
+            "Неверный запрос, проверьте введённые данные.",
+        ],
+        "wrong_amount" => [
+            "The amount sent doesn't match the payment.",
+            "Der gesendete Betrag passt nicht zur Zahlung.",
+            "La cantidad enviada no coincide con el pago.",
+            "Отправленная сумма не совпадает с суммой платежа.",
+        ],
+        "wrong_status" => [
+            "The payment can't be changed anymore.",
+            "Die Zahlung kann nicht mehr geändert werden.",
+            "El pago ya no se puede modificar.",
+            "Платёж больше нельзя изменить.",
+        ],
+        "rate_lock_expired" => [
+            "The price has expired, please get a new quote.",
+            "Der Preis ist abgelaufen, bitte fordern Sie ein neues Angebot an.",
+            "El precio ha caducado, solicite una nueva cotización.",
+            "Срок действия цены истёк, запросите новую.",
+        ],
+        "cannot_requote" => [
+            "The payment can't get a new quote.",
+            "Für die Zahlung kann kein neues Angebot erstellt werden.",
+            "El pago no puede recibir una nueva cotización.",
+            "Для этого платежа нельзя получить новую цену.",
+        ],
+        "stale_rate" => [
+            "The exchange rate is outdated, please try again later.",
+            "Der Wechselkurs ist veraltet, bitte versuchen Sie es später erneut.",
+            "El tipo de cambio está desactualizado, inténtelo más tarde.",
+            "Курс обмена устарел, попробуйте позже.",
+        ],
+        "unsupported_slate_version" => [
+            "Your wallet is not supported, please update it.",
+            "Ihre Wallet wird nicht unterstützt, bitte aktualisieren Sie sie.",
+            "Su billetera no es compatible, actualícela.",
+            "Ваш кошелёк не поддерживается, обновите его.",
+        ],
+        "checkout_expired" => [
+            "This payment link has expired, please ask the merchant for a fresh link.",
+            "Dieser Zahlungslink ist abgelaufen, bitte fordern Sie beim Händler einen neuen an.",
+            "Este enlace de pago ha caducado, pida al comerciante uno nuevo.",
+            "Срок действия ссылки на оплату истёк, попросите у продавца новую.",
+        ],
+        "invalid_slate" => [
+            "Your wallet sent a malformed transaction, please try again or use another wallet.",
+            "Ihre Wallet hat eine fehlerhafte Transaktion gesendet, bitte versuchen Sie es erneut oder nutzen Sie eine andere Wallet.",
+            "Su billetera envió una transacción mal formada, inténtelo de nuevo o use otra billetera.",
+            "Ваш кошелёк отправил некорректную транзакцию, попробуйте ещё раз или используйте другой кошелёк.",
+        ],
+        "payment_processing" => [
+            "The payment is already being processed, please wait.",
+            "Die Zahlung wird bereits verarbeitet, bitte warten Sie.",
+            "El pago ya se está procesando, espere por favor.",
+            "Платёж уже обрабатывается, подождите.",
+        ],
+        "duplicate_slate" => [
+            "This transaction was already sent to another payment, please create a new one in your wallet.",
+            "Diese Transaktion wurde bereits an eine andere Zahlung gesendet, bitte erstellen Sie eine neue in Ihrer Wallet.",
+            "Esta transacción ya se envió a otro pago, cree una nueva en su billetera.",
+            "Эта транзакция уже отправлена в другой платёж, создайте новую в кошельке.",
+        ],
+        _ => [
+            "Something went wrong, please try again later.",
+            "Etwas ist schiefgelaufen, bitte versuchen Sie es später erneut.",
+            "Algo salió mal, inténtelo más tarde.",
+            "Что-то пошло не так, попробуйте позже.",
+        ],
+    }
+}
+
+pub fn message(code: &str, language: Language) -> &'static str {
+    translations(code)[language.index()]
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorEnvelope {
+    code: &'static str,
+    message: &'static str,
+}
+
+/// Same status as `error_response`, with the message in `language`
+pub fn error_response(error: &Error, language: Language) -> HttpResponse {
+    let code = error.code();
+    HttpResponse::build(actix_web::ResponseError::error_response(error).status())
+        .header(header::CONTENT_LANGUAGE, language.to_string())
+        .json(ErrorEnvelope {
+            code,
+            message: message(code, language),
+        })
+}
+
+/// Answers the errors of a buyer facing handler in `language`
+pub fn localize<F>(language: Language, res: F) -> FutureResponse<HttpResponse>
+where
+    F: Future<Item = HttpResponse, Error = Error> + 'static,
+{
+    Box::new(res.or_else(move |e| Ok::<_, actix_web::Error>(error_response(&e, language))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Language::negotiate(None), Language::En);
+        assert_eq!(Language::negotiate(Some("de-AT")), Language::De);
+        assert_eq!(
+            Language::negotiate(Some("fr-FR, ru;q=0.8, de;q=0.9")),
+            Language::De
+        );
+        assert_eq!(Language::negotiate(Some("fr, ja;q=0.5")), Language::En);
+        assert_eq!(Language::negotiate(Some("es;q=0, en;q=0.1")), Language::En);
+        assert_eq!(Language::negotiate(Some("ES")), Language::Es);
+        assert_eq!(
+            message("rate_lock_expired", Language::Ru),
+            "Срок действия цены истёк, запросите новую."
+        );
+        assert_eq!(
+            message("internal_error", Language::En),
+            "Something went wrong, please try again later."
+        );
+    }
+}
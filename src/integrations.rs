@@ -0,0 +1,350 @@
+//! Chats merchants get payment and payout events in.
+//!
+//! A merchant links a Telegram chat, which talks to the gateway's bot
+//! `TELEGRAM_BOT_TOKEN`, a Slack incoming webhook or both. Confirmed and
+//! rejected payments are pushed when they are reported to the merchant,
+//! payout events by a cron job. Messages are best effort: a merchant gets
+//! at most `MAX_MESSAGES_PER_MINUTE` of them a minute, the rest and the
+//! ones the chat didn't accept are dropped.
+
+use crate::db::{DbExecutor, GetUnnotifiedPayoutEvents, MarkPayoutEventNotified};
+use crate::errors::Error;
+use crate::metrics;
+use crate::models::{Merchant, Money, PayoutEvent, Transaction, TransactionStatus};
+use actix::{Actor, Addr, Context, Handler, Message, ResponseFuture};
+use actix_web::client;
+use futures::future::{err, join_all, ok, Either, Future};
+use log::{debug, warn};
+use serde_json::json;
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::time::{Duration, Instant};
+
+pub const MAX_MESSAGES_PER_MINUTE: usize = 20;
+const SLACK_WEBHOOK_PREFIX: &str = "https://hooks.slack.com/";
+const REQUEST_TIMEOUT_SECONDS: u64 = 10;
+/// Number of payout events pushed in one cron run
+const NOTIFY_BATCH_SIZE: i64 = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Integrations {
+    pub telegram_chat_id: Option<String>,
+    pub slack_webhook_url: Option<String>,
+}
+
+impl Integrations {
+    pub fn of(merchant: &Merchant) -> Self {
+        Integrations {
+            telegram_chat_id: merchant.telegram_chat_id.clone(),
+            slack_webhook_url: merchant.slack_webhook_url.clone(),
+        }
+    }
+
+    /// Empty form fields unlink the chat
+    pub fn new(telegram_chat_id: &str, slack_webhook_url: &str) -> Self {
+        let non_empty = |v: &str| Some(v.trim().to_owned()).filter(|v| !v.is_empty());
+        Integrations {
+            telegram_chat_id: non_empty(telegram_chat_id),
+            slack_webhook_url: non_empty(slack_webhook_url),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.telegram_chat_id.is_none() && self.slack_webhook_url.is_none()
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(ref chat_id) = self.telegram_chat_id {
+            if !is_telegram_chat_id(chat_id) {
+                return Err(Error::InvalidEntity(s!(
+                    "Telegram chat id should be a number or @channel"
+                )));
+            }
+        }
+        if let Some(ref url) = self.slack_webhook_url {
+            if !url.starts_with(SLACK_WEBHOOK_PREFIX) || url.len() == SLACK_WEBHOOK_PREFIX.len() {
+                return Err(Error::InvalidEntity(format!(
+                    "Slack webhook url should start with {}",
+                    SLACK_WEBHOOK_PREFIX
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Numeric id, negative for groups, or the username of a public channel
+fn is_telegram_chat_id(chat_id: &str) -> bool {
+    let digits = chat_id.trim_start_matches('-');
+    let numeric = !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit());
+    let channel = chat_id.starts_with('@')
+        && chat_id.len() > 1
+        && chat_id[1..]
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_');
+    numeric || channel
+}
+
+/// Sliding window of sent messages per merchant
+pub struct RateLimiter {
+    limit: usize,
+    window: Duration,
+    sent: HashMap<String, VecDeque<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: usize, window: Duration) -> Self {
+        RateLimiter {
+            limit,
+            window,
+            sent: HashMap::new(),
+        }
+    }
+
+    /// Counts the message if it's allowed
+    pub fn allow(&mut self, merchant_id: &str, now: Instant) -> bool {
+        let window = self.window;
+        let sent = self
+            .sent
+            .entry(merchant_id.to_owned())
+            .or_insert_with(VecDeque::new);
+        while sent
+            .front()
+            .map_or(false, |at| now.duration_since(*at) >= window)
+        {
+            sent.pop_front();
+        }
+        if sent.len() >= self.limit {
+            return false;
+        }
+        sent.push_back(now);
+        true
+    }
+}
+
+pub struct Notifier {
+    telegram_bot_token: Option<String>,
+    limiter: RateLimiter,
+}
+
+impl Notifier {
+    pub fn new(telegram_bot_token: Option<String>) -> Self {
+        Notifier {
+            telegram_bot_token,
+            limiter: RateLimiter::new(MAX_MESSAGES_PER_MINUTE, Duration::from_secs(60)),
+        }
+    }
+
+    /// Without `TELEGRAM_BOT_TOKEN` only Slack works
+    pub fn from_env() -> Self {
+        Notifier::new(
+            env::var("TELEGRAM_BOT_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+        )
+    }
+}
+
+impl Actor for Notifier {
+    type Context = Context<Self>;
+}
+
+#[derive(Debug)]
+pub struct Notify {
+    pub merchant: Merchant,
+    pub text: String,
+}
+
+impl Message for Notify {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<Notify> for Notifier {
+    type Result = ResponseFuture<(), Error>;
+
+    fn handle(&mut self, msg: Notify, _: &mut Self::Context) -> Self::Result {
+        let integrations = Integrations::of(&msg.merchant);
+        if integrations.is_empty() {
+            return Box::new(ok(()));
+        }
+        if !self.limiter.allow(&msg.merchant.id, Instant::now()) {
+            metrics::inc(
+                "notifications_dropped_total",
+                &[("merchant", &msg.merchant.id)],
+            );
+            return Box::new(err(Error::General(format!(
+                "more than {} messages a minute",
+                MAX_MESSAGES_PER_MINUTE
+            ))));
+        }
+        debug!("Notify merchant {}", msg.merchant.id);
+        let telegram = match (&self.telegram_bot_token, integrations.telegram_chat_id) {
+            (Some(token), Some(chat_id)) => {
+                Either::A(send_telegram(token, &chat_id, &msg.text).map_err(|e| vec![e]))
+            }
+            (None, Some(_)) => Either::B(err(vec![s!("Telegram bot is not configured")])),
+            (_, None) => Either::B(ok(())),
+        };
+        let slack = match integrations.slack_webhook_url {
+            Some(url) => Either::A(send_slack(&url, &msg.text).map_err(|e| vec![e])),
+            None => Either::B(ok(())),
+        };
+        Box::new(telegram.then(move |telegram| {
+            slack.then(move |slack| {
+                let errors: Vec<String> = telegram
+                    .err()
+                    .into_iter()
+                    .chain(slack.err())
+                    .flatten()
+                    .collect();
+                if errors.is_empty() {
+                    Ok(())
+                } else {
+                    Err(Error::General(errors.join(", ")))
+                }
+            })
+        }))
+    }
+}
+
+fn send_telegram(token: &str, chat_id: &str, text: &str) -> impl Future<Item = (), Error = String> {
+    let request = client::post(format!("https://api.telegram.org/bot{}/sendMessage", token))
+        .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECONDS))
+        .json(json!({ "chat_id": chat_id, "text": text }));
+    send(request, "Telegram")
+}
+
+fn send_slack(url: &str, text: &str) -> impl Future<Item = (), Error = String> {
+    let request = client::post(url)
+        .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECONDS))
+        .json(json!({ "text": text }));
+    send(request, "Slack")
+}
+
+/// Errors never hold the url, the bot token and the webhook are secrets
+fn send(
+    request: Result<client::ClientRequest, actix_web::Error>,
+    service: &'static str,
+) -> impl Future<Item = (), Error = String> {
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => return Either::A(err(format!("{}: {}", service, e))),
+    };
+    Either::B(
+        request
+            .send()
+            .map_err(move |e| format!("{}: {}", service, e))
+            .and_then(move |resp| {
+                if resp.status().is_success() {
+                    Ok(())
+                } else {
+                    Err(format!("{} responded with {}", service, resp.status()))
+                }
+            }),
+    )
+}
+
+pub fn payment_text(payment: &Transaction) -> String {
+    let status = match payment.status {
+        TransactionStatus::Confirmed => "confirmed",
+        TransactionStatus::Rejected => "rejected",
+        TransactionStatus::Refund => "to be refunded",
+        _ => "updated",
+    };
+    let reference = payment
+        .invoice_number
+        .clone()
+        .unwrap_or_else(|| payment.external_id.clone());
+    format!(
+        "Payment {} of {} ({}) {}",
+        reference,
+        payment.amount,
+        Money::from_grin(payment.grin_amount),
+        status
+    )
+}
+
+pub fn payout_text(event: &PayoutEvent, payout: &Transaction) -> String {
+    format!(
+        "Payout {} of {} {}",
+        payout.external_id,
+        Money::from_grin(payout.grin_amount),
+        event.event
+    )
+}
+
+/// Pushes new payout events, returns how many of them were sent
+pub fn notify_payout_events(
+    db: Addr<DbExecutor>,
+    notifier: Addr<Notifier>,
+) -> impl Future<Item = usize, Error = Error> {
+    db.send(GetUnnotifiedPayoutEvents {
+        limit: NOTIFY_BATCH_SIZE,
+    })
+    .from_err()
+    .and_then(|db_response| {
+        let events = db_response?;
+        Ok(events)
+    })
+    .and_then(move |events| {
+        let futures: Vec<_> = events
+            .into_iter()
+            .map(|(event, payout, merchant)| {
+                let id = event.id;
+                notifier
+                    .send(Notify {
+                        text: payout_text(&event, &payout),
+                        merchant,
+                    })
+                    .from_err()
+                    .and_then(|notifier_response| notifier_response)
+                    .then(move |res| {
+                        if let Err(ref e) = res {
+                            warn!("Cannot push payout event {}: {}", id, e);
+                        }
+                        // Not retried, a late message is worse than none
+                        db.send(MarkPayoutEventNotified { id }).from_err().and_then(
+                            move |db_response| {
+                                db_response?;
+                                Ok(res.is_ok())
+                            },
+                        )
+                    })
+            })
+            .collect();
+        join_all(futures).map(|sent| sent.into_iter().filter(|sent| *sent).count())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+        assert!(limiter.allow("a", start));
+        assert!(limiter.allow("a", start + Duration::from_secs(1)));
+        assert!(!limiter.allow("a", start + Duration::from_secs(2)));
+        assert!(limiter.allow("b", start + Duration::from_secs(2)));
+        assert!(limiter.allow("a", start + Duration::from_secs(60)));
+        assert!(!limiter.allow("a", start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_validate_integrations() {
+        assert!(Integrations::new("", " ").is_empty());
+        assert!(
+            Integrations::new("-1001234", "https://hooks.slack.com/services/T/B/x")
+                .validate()
+                .is_ok()
+        );
+        assert!(Integrations::new("@shop_events", "").validate().is_ok());
+        assert!(Integrations::new("12a", "").validate().is_err());
+        assert!(Integrations::new("@", "").validate().is_err());
+        assert!(Integrations::new("", "https://example.com/hook")
+            .validate()
+            .is_err());
+    }
+}
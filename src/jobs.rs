@@ -0,0 +1,344 @@
+//! Durable background jobs.
+//!
+//! Jobs are rows of the `jobs` table, so work queued before a restart or
+//! a failed run isn't lost the way a skipped cron tick is. Every instance
+//! runs a `JobWorker` which claims due jobs with `FOR UPDATE SKIP LOCKED`,
+//! two workers never run the same job. A claimed job is locked for
+//! `JOB_LEASE_SECONDS`, jobs of a crashed worker are claimed again once the
+//! lock expires, so a job can run more than once and has to be idempotent.
+//!
+//! Failed runs are retried with a growing delay. After `MAX_JOB_ATTEMPTS`
+//! the job is kept with `failed_at` set and isn't run anymore.
+
+use crate::cron::PayoutBatchConfig;
+use crate::db::{ClaimJobs, CompleteJob, DbExecutor, FailJob, GetPayment};
+use crate::errors::Error;
+use crate::fsm::{
+    ConfirmedPayment, Fsm, InChainPayment, InitializePayoutBatch, PendingPayment, RefundPayment,
+    RejectedPayment, ReportPayment,
+};
+use crate::metrics;
+use crate::models::{Job, JobType, Transaction, TransactionStatus};
+use actix::prelude::*;
+use chrono::{Duration, NaiveDateTime, Utc};
+use futures::future::{err, join_all, ok, result, Either, Future};
+use log::*;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+/// How often a worker looks for due jobs
+const POLL_SECONDS: u64 = 1;
+/// Most jobs a worker runs at once
+const CLAIM_LIMIT: i64 = 20;
+/// A claimed job outlives a crashed worker by this long at most
+const JOB_LEASE_SECONDS: i64 = 10 * 60;
+pub const MAX_JOB_ATTEMPTS: i32 = 10;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReportPaymentJob {
+    pub transaction_id: Uuid,
+}
+
+/// Reports a confirmed, rejected or refunded payment to the merchant, a
+/// payment has one such job queued at most
+pub fn report_payment(transaction_id: Uuid) -> Job {
+    Job::new(
+        JobType::ReportPayment,
+        Some(format!("{}:{}", JobType::ReportPayment, transaction_id)),
+        json!(ReportPaymentJob { transaction_id }),
+    )
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReportStatusJob {
+    pub transaction_id: Uuid,
+    pub status: TransactionStatus,
+}
+
+/// Verbose callback of the payment in its current status
+pub fn report_status(payment: &Transaction) -> Job {
+    Job::new(
+        JobType::ReportStatus,
+        Some(format!(
+            "{}:{}:{}",
+            JobType::ReportStatus,
+            payment.id,
+            payment.status
+        )),
+        json!(ReportStatusJob {
+            transaction_id: payment.id,
+            status: payment.status,
+        }),
+    )
+}
+
+/// Sends due payouts to the wallet, one batch is queued at most
+pub fn process_payout_batch() -> Job {
+    Job::new(
+        JobType::ProcessPayoutBatch,
+        Some(JobType::ProcessPayoutBatch.to_string()),
+        json!({}),
+    )
+}
+
+/// When a job which failed on its `attempts`th run runs again, `None`
+/// once it's out of attempts
+pub fn retry_at(attempts: i32, now: NaiveDateTime) -> Option<NaiveDateTime> {
+    if attempts >= MAX_JOB_ATTEMPTS {
+        return None;
+    }
+    Some(now + Duration::seconds(10 * (attempts as i64).pow(2)))
+}
+
+pub struct JobWorker {
+    db: Addr<DbExecutor>,
+    fsm: Addr<Fsm>,
+    payout_batches: PayoutBatchConfig,
+    /// Identifies this worker in job locks
+    instance: String,
+    /// A poll is skipped while jobs of the previous one still run
+    busy: bool,
+}
+
+impl JobWorker {
+    pub fn new(db: Addr<DbExecutor>, fsm: Addr<Fsm>, payout_batches: PayoutBatchConfig) -> Self {
+        JobWorker {
+            db,
+            fsm,
+            payout_batches,
+            instance: Uuid::new_v4().to_string(),
+            busy: false,
+        }
+    }
+}
+
+impl Actor for JobWorker {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!("Starting job worker {}", self.instance);
+        ctx.run_interval(std::time::Duration::new(POLL_SECONDS, 0), poll);
+    }
+}
+
+fn poll(worker: &mut JobWorker, ctx: &mut Context<JobWorker>) {
+    if worker.busy {
+        return;
+    }
+    worker.busy = true;
+    let db = worker.db.clone();
+    let fsm = worker.fsm.clone();
+    let payout_batches = worker.payout_batches.clone();
+    let res = worker
+        .db
+        .send(ClaimJobs {
+            instance: worker.instance.clone(),
+            limit: CLAIM_LIMIT,
+            lease_seconds: JOB_LEASE_SECONDS,
+        })
+        .from_err()
+        .and_then(|db_response| db_response)
+        .map_err(|e: Error| error!("Cannot claim jobs: {}", e))
+        .and_then(move |jobs| {
+            let runs: Vec<_> = jobs
+                .into_iter()
+                .map(|job| {
+                    let run = run(&db, &fsm, &payout_batches, &job);
+                    finish(db.clone(), job, run)
+                })
+                .collect();
+            join_all(runs).map(|_| ())
+        });
+    ctx.spawn(res.into_actor(worker).then(|_, worker, _| {
+        worker.busy = false;
+        fut::ok(())
+    }));
+}
+
+fn run(
+    db: &Addr<DbExecutor>,
+    fsm: &Addr<Fsm>,
+    payout_batches: &PayoutBatchConfig,
+    job: &Job,
+) -> Box<dyn Future<Item = (), Error = Error>> {
+    debug!("Run {} job {}", job.job_type, job.id);
+    match job.job_type.parse::<JobType>() {
+        Ok(JobType::ReportPayment) => {
+            match serde_json::from_value::<ReportPaymentJob>(job.payload.clone()) {
+                Ok(payload) => Box::new(run_report_payment(
+                    db.clone(),
+                    fsm.clone(),
+                    payload.transaction_id,
+                )),
+                Err(e) => Box::new(err(e.into())),
+            }
+        }
+        Ok(JobType::ReportStatus) => {
+            match serde_json::from_value::<ReportStatusJob>(job.payload.clone()) {
+                Ok(payload) => Box::new(run_report_status(db.clone(), fsm.clone(), payload)),
+                Err(e) => Box::new(err(e.into())),
+            }
+        }
+        Ok(JobType::ProcessPayoutBatch) => Box::new(
+            fsm.send(InitializePayoutBatch {
+                max_size: payout_batches.size,
+                concurrency: payout_batches.wallet_concurrency,
+            })
+            .from_err()
+            .and_then(|fsm_response| {
+                fsm_response?;
+                Ok(())
+            }),
+        ),
+        Err(_) => Box::new(err(Error::General(format!(
+            "unknown job type {}",
+            job.job_type
+        )))),
+    }
+}
+
+/// Payments which were reported meanwhile are left alone
+fn run_report_payment(
+    db: Addr<DbExecutor>,
+    fsm: Addr<Fsm>,
+    transaction_id: Uuid,
+) -> impl Future<Item = (), Error = Error> {
+    db.send(GetPayment { transaction_id })
+        .from_err()
+        .and_then(|db_response| db_response)
+        .and_then(
+            move |payment| -> Box<dyn Future<Item = (), Error = Error>> {
+                if payment.reported {
+                    return Box::new(ok(()));
+                }
+                match payment.status {
+                    TransactionStatus::Confirmed => Box::new(
+                        result(ConfirmedPayment::load(payment)).and_then(move |payment| {
+                            fsm.send(ReportPayment { payment })
+                                .from_err()
+                                .and_then(|fsm_response| fsm_response)
+                        }),
+                    ),
+                    TransactionStatus::Rejected => Box::new(
+                        result(RejectedPayment::load(payment)).and_then(move |payment| {
+                            fsm.send(ReportPayment { payment })
+                                .from_err()
+                                .and_then(|fsm_response| fsm_response)
+                        }),
+                    ),
+                    TransactionStatus::Refund => Box::new(
+                        result(RefundPayment::load(payment)).and_then(move |payment| {
+                            fsm.send(ReportPayment { payment })
+                                .from_err()
+                                .and_then(|fsm_response| fsm_response)
+                        }),
+                    ),
+                    _ => Box::new(ok(())),
+                }
+            },
+        )
+}
+
+/// Payments which moved on to another status are left alone, the merchant
+/// is called back about that one
+fn run_report_status(
+    db: Addr<DbExecutor>,
+    fsm: Addr<Fsm>,
+    job: ReportStatusJob,
+) -> impl Future<Item = (), Error = Error> {
+    db.send(GetPayment {
+        transaction_id: job.transaction_id,
+    })
+    .from_err()
+    .and_then(|db_response| db_response)
+    .and_then(
+        move |payment| -> Box<dyn Future<Item = (), Error = Error>> {
+            if payment.status != job.status {
+                return Box::new(ok(()));
+            }
+            match payment.status {
+                TransactionStatus::Pending => Box::new(
+                    result(PendingPayment::load(payment)).and_then(move |payment| {
+                        fsm.send(ReportPayment { payment })
+                            .from_err()
+                            .and_then(|fsm_response| fsm_response)
+                    }),
+                ),
+                TransactionStatus::InChain => Box::new(
+                    result(InChainPayment::load(payment)).and_then(move |payment| {
+                        fsm.send(ReportPayment { payment })
+                            .from_err()
+                            .and_then(|fsm_response| fsm_response)
+                    }),
+                ),
+                _ => Box::new(ok(())),
+            }
+        },
+    )
+}
+
+/// Deletes the job after a successful run, otherwise schedules the retry
+fn finish<F>(db: Addr<DbExecutor>, job: Job, run: F) -> impl Future<Item = (), Error = ()>
+where
+    F: Future<Item = (), Error = Error>,
+{
+    run.then(move |res| {
+        let update = match res {
+            Ok(()) => {
+                metrics::inc(
+                    "job_runs_total",
+                    &[("type", job.job_type.as_str()), ("result", "ok")],
+                );
+                Either::A(db.send(CompleteJob { id: job.id }))
+            }
+            Err(e) => {
+                let retry_at = retry_at(job.attempts, Utc::now().naive_utc());
+                let outcome = match retry_at {
+                    Some(_) => {
+                        warn!("{} job {} failed, retrying: {}", job.job_type, job.id, e);
+                        "retry"
+                    }
+                    None => {
+                        error!("{} job {} failed for good: {}", job.job_type, job.id, e);
+                        "failed"
+                    }
+                };
+                metrics::inc(
+                    "job_runs_total",
+                    &[("type", job.job_type.as_str()), ("result", outcome)],
+                );
+                Either::B(db.send(FailJob {
+                    id: job.id,
+                    error: s!(e),
+                    retry_at,
+                }))
+            }
+        };
+        let job_id = job.id;
+        update
+            .from_err()
+            .and_then(|db_response| db_response)
+            .map_err(move |e: Error| error!("Cannot finish job {}: {}", job_id, e))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_at() {
+        let now = Utc::now().naive_utc();
+        assert_eq!(retry_at(1, now), Some(now + Duration::seconds(10)));
+        assert_eq!(retry_at(3, now), Some(now + Duration::seconds(90)));
+        assert_eq!(retry_at(MAX_JOB_ATTEMPTS, now), None);
+
+        let id = Uuid::new_v4();
+        let job = report_payment(id);
+        assert_eq!(job.job_type, "report_payment");
+        assert_eq!(job.key, Some(format!("report_payment:{}", id)));
+        let payload: ReportPaymentJob = serde_json::from_value(job.payload).unwrap();
+        assert_eq!(payload.transaction_id, id);
+    }
+}
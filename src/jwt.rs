@@ -0,0 +1,109 @@
+//! Short-lived JWTs for the server API.
+//!
+//! Tokens are HS256 signed with the merchant's API token and carry
+//! `merchant_id`, `iat`, `exp` and optionally `scopes` claims. Without
+//! `scopes` the JWT grants everything the merchant token does.
+
+use crate::errors::Error;
+use crate::models::ApiScope;
+use chrono::Utc;
+use data_encoding::BASE64URL_NOPAD;
+use frank_jwt::{decode, encode, Algorithm};
+use serde::{Deserialize, Serialize};
+
+/// Longest lifetime of a JWT, tokens with `exp - iat` above it are rejected
+pub const MAX_TTL_SECONDS: i64 = 60 * 60;
+/// Allowed difference between merchant's clock and ours
+const CLOCK_SKEW_SECONDS: i64 = 60;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Claims {
+    pub merchant_id: String,
+    pub iat: i64,
+    pub exp: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<ApiScope>>,
+}
+
+impl Claims {
+    pub fn new(merchant_id: &str, ttl_seconds: i64, scopes: Option<Vec<ApiScope>>) -> Self {
+        let now = Utc::now().timestamp();
+        Claims {
+            merchant_id: merchant_id.to_owned(),
+            iat: now,
+            exp: now + ttl_seconds.min(MAX_TTL_SECONDS),
+            scopes,
+        }
+    }
+}
+
+pub fn sign(claims: &Claims, secret: &str) -> Result<String, Error> {
+    let payload = serde_json::to_value(claims)?;
+    encode(
+        serde_json::json!({}),
+        &s!(secret),
+        &payload,
+        Algorithm::HS256,
+    )
+    .map_err(|e| Error::General(format!("cannot sign jwt: {:?}", e)))
+}
+
+/// Reads claims without checking the signature, only to find out
+/// whose secret the token should be verified with
+pub fn unverified_merchant_id(token: &str) -> Result<String, Error> {
+    let payload = token.split('.').nth(1).ok_or(Error::NotAuthorized)?;
+    let payload = BASE64URL_NOPAD
+        .decode(payload.trim_end_matches('=').as_bytes())
+        .map_err(|_| Error::NotAuthorized)?;
+    let claims: Claims = serde_json::from_slice(&payload).map_err(|_| Error::NotAuthorized)?;
+    Ok(claims.merchant_id)
+}
+
+pub fn verify(token: &str, merchant_id: &str, secret: &str, now: i64) -> Result<Claims, Error> {
+    let (_, payload) =
+        decode(&s!(token), &s!(secret), Algorithm::HS256).map_err(|_| Error::NotAuthorized)?;
+    let claims: Claims = serde_json::from_value(payload).map_err(|_| Error::NotAuthorized)?;
+    if claims.merchant_id != merchant_id {
+        return Err(Error::NotAuthorized);
+    }
+    if claims.exp < now - CLOCK_SKEW_SECONDS || claims.iat > now + CLOCK_SKEW_SECONDS {
+        return Err(Error::NotAuthorized);
+    }
+    if claims.exp - claims.iat > MAX_TTL_SECONDS {
+        return Err(Error::NotAuthorized);
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let claims = Claims::new("merchant", 300, Some(vec![ApiScope::ReadPayments]));
+        let token = sign(&claims, "secret").unwrap();
+        assert_eq!(unverified_merchant_id(&token).unwrap(), "merchant");
+
+        let verified = verify(&token, "merchant", "secret", claims.iat).unwrap();
+        assert_eq!(verified.scopes, Some(vec![ApiScope::ReadPayments]));
+
+        assert!(verify(&token, "merchant", "another secret", claims.iat).is_err());
+        assert!(verify(&token, "another merchant", "secret", claims.iat).is_err());
+        assert!(verify(
+            &token,
+            "merchant",
+            "secret",
+            claims.exp + 2 * CLOCK_SKEW_SECONDS
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_long_lived_token_is_rejected() {
+        let mut claims = Claims::new("merchant", 300, None);
+        claims.exp = claims.iat + MAX_TTL_SECONDS + 1;
+        let token = sign(&claims, "secret").unwrap();
+        assert!(verify(&token, "merchant", "secret", claims.iat).is_err());
+    }
+}
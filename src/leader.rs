@@ -0,0 +1,85 @@
+//! Leader election for active-passive deployments.
+//!
+//! Every instance serves HTTP, but only the one holding a Postgres advisory
+//! lock runs cron jobs and merchant callbacks. The lock is taken on a
+//! dedicated connection, so when the leader dies Postgres releases it and
+//! a standby instance takes over on its next attempt.
+
+use crate::errors::Error;
+use actix::{Actor, Handler, Message, SyncContext};
+use diesel::dsl::sql;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::sql_types::Bool;
+use log::{info, warn};
+
+/// Advisory lock key shared by all instances working with the same DB
+const LEADER_LOCK_KEY: i64 = 0x6b6e_6f63_6b74;
+
+pub struct LeaderElection {
+    database_url: String,
+    conn: Option<PgConnection>,
+    is_leader: bool,
+}
+
+impl LeaderElection {
+    pub fn new(database_url: &str) -> Self {
+        LeaderElection {
+            database_url: database_url.to_owned(),
+            conn: None,
+            is_leader: false,
+        }
+    }
+
+    fn try_lead(&mut self) -> Result<bool, Error> {
+        if self.conn.is_none() {
+            let conn = PgConnection::establish(&self.database_url).map_err(|e| Error::Db(s!(e)))?;
+            self.conn = Some(conn);
+        }
+        let conn = self.conn.as_ref().unwrap();
+        if self.is_leader {
+            // The lock lives as long as the connection
+            diesel::select(sql::<Bool>("TRUE")).get_result::<bool>(conn)?;
+            return Ok(true);
+        }
+        let acquired = diesel::select(sql::<Bool>(&format!(
+            "pg_try_advisory_lock({})",
+            LEADER_LOCK_KEY
+        )))
+        .get_result::<bool>(conn)?;
+        if acquired {
+            info!("This instance became the leader");
+        }
+        self.is_leader = acquired;
+        Ok(acquired)
+    }
+}
+
+impl Actor for LeaderElection {
+    type Context = SyncContext<Self>;
+}
+
+/// Takes the leadership if nobody holds it, returns whether
+/// this instance is the leader now
+#[derive(Debug)]
+pub struct TryLead;
+
+impl Message for TryLead {
+    type Result = Result<bool, Error>;
+}
+
+impl Handler<TryLead> for LeaderElection {
+    type Result = Result<bool, Error>;
+
+    fn handle(&mut self, _: TryLead, _: &mut Self::Context) -> Self::Result {
+        self.try_lead().map_err(|e| {
+            if self.is_leader {
+                warn!("Lost connection holding the leader lock: {}", e);
+            }
+            // Lock is gone together with the connection
+            self.conn = None;
+            self.is_leader = false;
+            e
+        })
+    }
+}
@@ -1,25 +1,76 @@
 #[macro_use]
 mod macros;
 
+pub mod alerts;
+pub mod amount_tags;
+pub mod analytics;
+pub mod api_version;
 pub mod app;
-pub mod blocking;
+pub mod callback;
+pub mod callback_template;
+pub mod captcha;
+pub mod checkout;
+pub mod clearing;
+pub mod clock;
 pub mod clients;
+pub mod compat;
+pub mod compression;
 pub mod cron;
 pub mod db;
+pub mod demo;
+pub mod deny_list;
 pub mod errors;
+pub mod export;
+pub mod explorer;
 pub mod extractor;
+pub mod feature_flags;
 pub mod filters;
+pub mod foreign_api;
 pub mod fsm;
+pub mod geoip;
 pub mod handlers;
+pub mod i18n;
+pub mod integrations;
+pub mod jobs;
+pub mod jwt;
+pub mod leader;
+pub mod mailer;
+pub mod metrics;
+pub mod middleware;
 pub mod models;
 pub mod node;
+pub mod oidc;
+pub mod pdf;
+pub mod payment_state;
+pub mod payout_webhook;
+pub mod plans;
 pub mod qrcode;
+pub mod quote;
+pub mod rate_limit;
 pub mod rates;
+pub mod reconciliation;
+pub mod referrals;
+pub mod retry_queue;
+pub mod redact;
+pub mod refund_addresses;
+pub mod registration;
+pub mod return_url;
 #[allow(unused_imports)]
 pub mod schema;
+pub mod security_events;
+pub mod seed;
 mod ser;
+pub mod server;
+pub mod settlement;
+pub mod slatepack;
+pub mod slow_log;
+pub mod splits;
+pub mod status;
 pub mod totp;
+pub mod trace;
 pub mod wallet;
+pub mod wallet_version;
+pub mod webauthn;
 
 #[macro_use]
 extern crate diesel;
@@ -0,0 +1,518 @@
+//! Emails sent to buyers on behalf of merchants.
+//!
+//! Messages are handed over to the local MTA with `sendmail -t`, the binary
+//! is `SENDMAIL_PATH` and the sender address is `MAIL_FROM`. Without
+//! `MAIL_FROM` the mailer is disabled and emails are only logged. Every
+//! merchant can brand the emails with a logo, a footer and a reply-to address.
+//! Merchants who opt in get their buyers reminded of payments which stay new
+//! for `payment_reminder_minutes`, once per payment and before it expires.
+
+use crate::checkout::CheckoutToken;
+use crate::db::{
+    DbExecutor, GetIdlePayments, GetUnsentReceipts, MarkReceiptSent, MarkReminderSent,
+};
+use crate::errors::Error;
+use crate::explorer::ExplorerLinks;
+use crate::filters;
+use crate::models::{format_invoice_number, Currency, Merchant, Money, Transaction};
+use actix::{Actor, Addr, Handler, Message, SyncContext};
+use askama::Template;
+use chrono::{NaiveDateTime, Utc};
+use data_encoding::BASE64;
+use futures::future::{join_all, result, Future};
+use log::{debug, error, info};
+use std::env;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use uuid::Uuid;
+
+/// Number of receipts sent in one cron run
+const RECEIPTS_BATCH_SIZE: i64 = 50;
+/// Number of payment reminders sent in one cron run
+const REMINDERS_BATCH_SIZE: i64 = 50;
+/// Bounds of the merchant's `payment_reminder_minutes`
+pub const MIN_REMINDER_MINUTES: i32 = 1;
+pub const MAX_REMINDER_MINUTES: i32 = 60;
+const MAX_FOOTER_LENGTH: usize = 1000;
+const MAX_EMAIL_LENGTH: usize = 254;
+
+#[derive(Clone)]
+pub struct MailerConfig {
+    pub sendmail_path: String,
+    pub from: String,
+}
+
+impl MailerConfig {
+    pub fn from_env() -> Option<Self> {
+        let from = env::var("MAIL_FROM").ok().filter(|from| !from.is_empty())?;
+        Some(MailerConfig {
+            sendmail_path: env::var("SENDMAIL_PATH").unwrap_or(s!("/usr/sbin/sendmail")),
+            from,
+        })
+    }
+}
+
+pub struct Mailer {
+    config: Option<MailerConfig>,
+}
+
+impl Mailer {
+    pub fn new(config: Option<MailerConfig>) -> Self {
+        Mailer { config }
+    }
+}
+
+impl Actor for Mailer {
+    type Context = SyncContext<Self>;
+}
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub reply_to: Option<String>,
+    pub subject: String,
+    pub html: String,
+}
+
+#[derive(Debug)]
+pub struct SendEmail(pub Email);
+
+impl Message for SendEmail {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<SendEmail> for Mailer {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: SendEmail, _: &mut Self::Context) -> Self::Result {
+        let config = match self.config {
+            Some(ref config) => config,
+            None => {
+                info!("Mailer is disabled, drop email {:?}", msg.0.subject);
+                return Ok(());
+            }
+        };
+        debug!("Send email {:?}", msg.0.subject);
+        let mut sendmail = Command::new(&config.sendmail_path)
+            .arg("-t")
+            .arg("-i")
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| Error::Mailer(s!(e)))?;
+        sendmail
+            .stdin
+            .as_mut()
+            .unwrap()
+            .write_all(format_message(&config.from, &msg.0).as_bytes())
+            .map_err(|e| Error::Mailer(s!(e)))?;
+        let status = sendmail.wait().map_err(|e| Error::Mailer(s!(e)))?;
+        if !status.success() {
+            return Err(Error::Mailer(format!("sendmail exited with {}", status)));
+        }
+        Ok(())
+    }
+}
+
+/// Branding of buyer-facing emails, missing parts are left out
+#[derive(Debug, Clone, Default)]
+pub struct EmailBranding {
+    pub logo_url: Option<String>,
+    pub footer: Option<String>,
+    pub reply_to: Option<String>,
+}
+
+impl EmailBranding {
+    pub fn of(merchant: &Merchant) -> Self {
+        EmailBranding {
+            logo_url: merchant.email_logo_url.clone(),
+            footer: merchant.email_footer.clone(),
+            reply_to: merchant.email_reply_to.clone(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(ref logo_url) = self.logo_url {
+            if !logo_url.starts_with("https://") {
+                return Err(Error::InvalidEntity(s!(
+                    "logo url should start with https://"
+                )));
+            }
+        }
+        if let Some(ref footer) = self.footer {
+            if footer.len() > MAX_FOOTER_LENGTH {
+                return Err(Error::InvalidEntity(format!(
+                    "footer is longer than {} characters",
+                    MAX_FOOTER_LENGTH
+                )));
+            }
+        }
+        if let Some(ref reply_to) = self.reply_to {
+            if !is_email(reply_to) {
+                return Err(Error::InvalidEntity(s!(
+                    "reply-to should be an email address"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Loose check of a single address, the MTA rejects what slips through
+pub fn is_email(value: &str) -> bool {
+    let mut parts = value.split('@');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(local), Some(domain), None) => {
+            !local.is_empty()
+                && domain.contains('.')
+                && value.len() <= MAX_EMAIL_LENGTH
+                && !value.contains(|c: char| c.is_whitespace() || c == ',' || c == '<')
+        }
+        _ => false,
+    }
+}
+
+#[derive(Template)]
+#[template(path = "emails/payment_confirmed.html")]
+pub struct PaymentConfirmedEmail<'a> {
+    pub merchant_id: &'a str,
+    pub branding: &'a EmailBranding,
+    pub order_id: &'a str,
+    pub invoice_number: Option<&'a str>,
+    pub amount: &'a Money,
+    pub grin_amount: i64,
+    pub transaction_id: &'a Uuid,
+    /// Key which signed the slate message, proves who paid
+    pub payer_public_key: Option<&'a str>,
+    pub explorer: ExplorerLinks,
+}
+
+/// Receipt sent to the buyer once the payment is confirmed
+pub fn payment_confirmed(merchant: &Merchant, transaction: &Transaction) -> Result<Email, Error> {
+    let to = transaction
+        .email
+        .clone()
+        .ok_or(Error::InvalidEntity(s!("payment has no email")))?;
+    let branding = EmailBranding::of(merchant);
+    let html = PaymentConfirmedEmail {
+        merchant_id: &merchant.id,
+        branding: &branding,
+        order_id: &transaction.external_id,
+        invoice_number: transaction.invoice_number.as_ref().map(|n| n.as_str()),
+        amount: &transaction.amount,
+        grin_amount: transaction.grin_amount,
+        transaction_id: &transaction.id,
+        payer_public_key: transaction.payer_public_key.as_ref().map(|k| k.as_str()),
+        explorer: ExplorerLinks::of(transaction, None),
+    }
+    .render()?;
+    Ok(Email {
+        to,
+        reply_to: branding.reply_to,
+        subject: format!("Payment for order {} is confirmed", transaction.external_id),
+        html,
+    })
+}
+
+#[derive(Template)]
+#[template(path = "emails/payment_reminder.html")]
+pub struct PaymentReminderEmail<'a> {
+    pub merchant_id: &'a str,
+    pub branding: &'a EmailBranding,
+    pub order_id: &'a str,
+    pub amount: &'a Money,
+    pub grin_amount: i64,
+    pub payment_url: &'a str,
+    pub minutes_left: i64,
+}
+
+/// Reminder of a payment the buyer hasn't paid yet, with the link to its
+/// payment page
+pub fn payment_reminder(
+    merchant: &Merchant,
+    transaction: &Transaction,
+    payment_url: &str,
+    now: NaiveDateTime,
+) -> Result<Email, Error> {
+    let to = transaction
+        .email
+        .clone()
+        .ok_or(Error::InvalidEntity(s!("payment has no email")))?;
+    let expires_at = transaction
+        .expires_at
+        .ok_or(Error::InvalidEntity(s!("payment doesn't expire")))?;
+    let branding = EmailBranding::of(merchant);
+    let html = PaymentReminderEmail {
+        merchant_id: &merchant.id,
+        branding: &branding,
+        order_id: &transaction.external_id,
+        amount: &transaction.amount,
+        grin_amount: transaction.grin_amount,
+        payment_url,
+        minutes_left: (expires_at - now).num_minutes().max(1),
+    }
+    .render()?;
+    Ok(Email {
+        to,
+        reply_to: branding.reply_to,
+        subject: format!(
+            "Payment for order {} is waiting for you",
+            transaction.external_id
+        ),
+        html,
+    })
+}
+
+/// Checks the merchant's `payment_reminder_minutes`
+pub fn validate_reminder_minutes(minutes: Option<i32>) -> Result<(), Error> {
+    match minutes {
+        Some(minutes) if minutes < MIN_REMINDER_MINUTES || minutes > MAX_REMINDER_MINUTES => {
+            Err(Error::InvalidEntity(format!(
+                "payment reminders should be sent after {} to {} minutes",
+                MIN_REMINDER_MINUTES, MAX_REMINDER_MINUTES
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Confirmation email with made up payment details and the merchant's branding
+pub fn preview(merchant: &Merchant) -> Result<String, Error> {
+    let branding = EmailBranding::of(merchant);
+    PaymentConfirmedEmail {
+        merchant_id: &merchant.id,
+        branding: &branding,
+        order_id: "1001",
+        invoice_number: Some(format_invoice_number(&merchant.invoice_prefix, 2019, 123).as_str()),
+        amount: &Money::new(2500, Currency::USD),
+        grin_amount: 7_350_000_000,
+        transaction_id: &Uuid::nil(),
+        payer_public_key: None,
+        explorer: ExplorerLinks::default(),
+    }
+    .render()
+    .map_err(|e| e.into())
+}
+
+/// Emails receipts of confirmed payments, returns how many were sent
+pub fn send_receipts(
+    db: Addr<DbExecutor>,
+    mailer: Addr<Mailer>,
+) -> impl Future<Item = usize, Error = Error> {
+    db.send(GetUnsentReceipts {
+        limit: RECEIPTS_BATCH_SIZE,
+    })
+    .from_err()
+    .and_then(|db_response| {
+        let receipts = db_response?;
+        Ok(receipts)
+    })
+    .and_then(move |receipts| {
+        let futures: Vec<_> = receipts
+            .into_iter()
+            .map(|(transaction, merchant)| {
+                send_receipt(db.clone(), mailer.clone(), transaction, merchant)
+            })
+            .collect();
+        join_all(futures).map(|sent| sent.into_iter().filter(|sent| *sent).count())
+    })
+}
+
+/// Never fails, a receipt which wasn't sent is retried on the next run
+fn send_receipt(
+    db: Addr<DbExecutor>,
+    mailer: Addr<Mailer>,
+    transaction: Transaction,
+    merchant: Merchant,
+) -> impl Future<Item = bool, Error = Error> {
+    let transaction_id = transaction.id;
+    result(payment_confirmed(&merchant, &transaction))
+        .and_then(move |email| {
+            mailer
+                .send(SendEmail(email))
+                .from_err()
+                .and_then(|mailer_response| mailer_response)
+        })
+        .and_then(move |_| {
+            db.send(MarkReceiptSent { transaction_id })
+                .from_err()
+                .and_then(|db_response| db_response)
+        })
+        .then(move |res| match res {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                error!("Cannot send receipt of payment {}: {}", transaction_id, e);
+                Ok(false)
+            }
+        })
+}
+
+/// Reminds buyers of idle payments, returns how many were reminded
+pub fn send_reminders(
+    db: Addr<DbExecutor>,
+    mailer: Addr<Mailer>,
+) -> impl Future<Item = usize, Error = Error> {
+    db.send(GetIdlePayments {
+        limit: REMINDERS_BATCH_SIZE,
+    })
+    .from_err()
+    .and_then(|db_response| {
+        let payments = db_response?;
+        Ok(payments)
+    })
+    .and_then(move |payments| {
+        let futures: Vec<_> = payments
+            .into_iter()
+            .map(|(transaction, merchant)| {
+                send_reminder(db.clone(), mailer.clone(), transaction, merchant)
+            })
+            .collect();
+        join_all(futures).map(|sent| sent.into_iter().filter(|sent| *sent).count())
+    })
+}
+
+/// Never fails, a reminder which wasn't sent is retried on the next run
+fn send_reminder(
+    db: Addr<DbExecutor>,
+    mailer: Addr<Mailer>,
+    transaction: Transaction,
+    merchant: Merchant,
+) -> impl Future<Item = bool, Error = Error> {
+    let transaction_id = transaction.id;
+    let now = Utc::now();
+    result(
+        CheckoutToken::new(transaction_id, now.timestamp())
+            .url()
+            .and_then(|payment_url| {
+                payment_reminder(&merchant, &transaction, &payment_url, now.naive_utc())
+            }),
+    )
+    .and_then(move |email| {
+        mailer
+            .send(SendEmail(email))
+            .from_err()
+            .and_then(|mailer_response| mailer_response)
+    })
+    .and_then(move |_| {
+        db.send(MarkReminderSent { transaction_id })
+            .from_err()
+            .and_then(|db_response| db_response)
+    })
+    .then(move |res| match res {
+        Ok(_) => Ok(true),
+        Err(e) => {
+            error!("Cannot send reminder of payment {}: {}", transaction_id, e);
+            Ok(false)
+        }
+    })
+}
+
+/// Message in the format `sendmail -t` reads, recipients come from the headers
+fn format_message(from: &str, email: &Email) -> String {
+    let mut headers = vec![
+        format!("From: {}", header_value(from)),
+        format!("To: {}", header_value(&email.to)),
+    ];
+    if let Some(ref reply_to) = email.reply_to {
+        headers.push(format!("Reply-To: {}", header_value(reply_to)));
+    }
+    headers.push(format!("Subject: {}", encode_subject(&email.subject)));
+    headers.push(s!("MIME-Version: 1.0"));
+    headers.push(s!("Content-Type: text/html; charset=utf-8"));
+    headers.push(s!("Content-Transfer-Encoding: base64"));
+    let body = BASE64.encode(email.html.as_bytes());
+    let lines: Vec<&str> = body
+        .as_bytes()
+        .chunks(76)
+        .map(|line| std::str::from_utf8(line).unwrap())
+        .collect();
+    format!("{}\n\n{}\n", headers.join("\n"), lines.join("\n"))
+}
+
+/// Values come from merchants and buyers, a line break would let
+/// them add headers
+fn header_value(value: &str) -> String {
+    value.replace(|c| c == '\r' || c == '\n', " ")
+}
+
+fn encode_subject(subject: &str) -> String {
+    let subject = header_value(subject);
+    if subject.is_ascii() {
+        subject
+    } else {
+        format!("=?utf-8?B?{}?=", BASE64.encode(subject.as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::tests::{create_merchant, create_tx};
+
+    #[test]
+    fn test_format_message() {
+        let email = Email {
+            to: s!("buyer@example.com\nBcc: victim@example.com"),
+            reply_to: Some(s!("shop@example.com")),
+            subject: s!("Payment for order 1 is confirmed"),
+            html: s!("<p>Thanks</p>"),
+        };
+        let message = format_message("knockturn@example.com", &email);
+        assert_eq!(
+            message,
+            "From: knockturn@example.com\n\
+             To: buyer@example.com Bcc: victim@example.com\n\
+             Reply-To: shop@example.com\n\
+             Subject: Payment for order 1 is confirmed\n\
+             MIME-Version: 1.0\n\
+             Content-Type: text/html; charset=utf-8\n\
+             Content-Transfer-Encoding: base64\n\
+             \n\
+             PHA+VGhhbmtzPC9wPg==\n"
+        );
+        assert_eq!(encode_subject("Заказ"), "=?utf-8?B?0JfQsNC60LDQtw==?=");
+    }
+
+    #[test]
+    fn test_validate_branding() {
+        let mut branding = EmailBranding::default();
+        assert!(branding.validate().is_ok());
+        branding.logo_url = Some(s!("http://shop.com/logo.png"));
+        assert!(branding.validate().is_err());
+        branding.logo_url = Some(s!("https://shop.com/logo.png"));
+        branding.reply_to = Some(s!("support@shop.com"));
+        assert!(branding.validate().is_ok());
+        branding.reply_to = Some(s!("support@shop.com, other@shop.com"));
+        assert!(branding.validate().is_err());
+    }
+
+    #[test]
+    fn test_payment_reminder() {
+        let merchant = create_merchant();
+        let mut transaction = create_tx();
+        transaction.external_id = s!("1001");
+        let now = Utc::now().naive_utc();
+        transaction.expires_at = Some(now + chrono::Duration::minutes(10));
+        assert!(payment_reminder(&merchant, &transaction, "https://pay", now).is_err());
+        transaction.email = Some(s!("buyer@example.com"));
+        let email = payment_reminder(&merchant, &transaction, "https://pay", now).unwrap();
+        assert_eq!(email.to, "buyer@example.com");
+        assert_eq!(email.subject, "Payment for order 1001 is waiting for you");
+        assert!(email.html.contains("href=\"https://pay\""));
+        assert!(email.html.contains("10 more minutes"));
+
+        assert!(validate_reminder_minutes(None).is_ok());
+        assert!(validate_reminder_minutes(Some(10)).is_ok());
+        assert!(validate_reminder_minutes(Some(0)).is_err());
+        assert!(validate_reminder_minutes(Some(MAX_REMINDER_MINUTES + 1)).is_err());
+    }
+
+    #[test]
+    fn test_is_email() {
+        assert!(is_email("buyer@example.com"));
+        assert!(!is_email("buyer@localhost"));
+        assert!(!is_email("@example.com"));
+        assert!(!is_email("buyer@example.com, other@example.com"));
+        assert!(!is_email("a@b@example.com"));
+        assert!(!is_email("buyer@example.com\nBcc: victim@example.com"));
+    }
+}
@@ -1,48 +1,217 @@
 use actix::prelude::*;
-use actix_web::server;
-use diesel::{r2d2::ConnectionManager, PgConnection};
+use actix_web::{server, App};
+use diesel::{r2d2::ConnectionManager, Connection, PgConnection};
 use dotenv::dotenv;
 use env_logger;
-use knockturn::db::DbExecutor;
-use knockturn::fsm::Fsm;
-use knockturn::node::Node;
-use knockturn::wallet::Wallet;
-use knockturn::{app, cron};
-use log::info;
+use knockturn::db::{DbExecutor, GetMissingIndexes, StatementTimeout};
+use knockturn::demo::{self, DemoConfig};
+use knockturn::export::{self, ExportConfig};
+use knockturn::fsm::{Fsm, Subscribe};
+use knockturn::integrations::Notifier;
+use knockturn::jobs::JobWorker;
+use knockturn::leader::LeaderElection;
+use knockturn::mailer::{Mailer, MailerConfig};
+use knockturn::node;
+use knockturn::oidc::OidcClient;
+use knockturn::registration::Registration;
+use knockturn::retry_queue::{self, RetryQueue};
+use knockturn::seed::{self, SeedConfig};
+use knockturn::server::ServerConfig;
+use knockturn::status::TransitionMetrics;
+use knockturn::trace::{self, TraceConfig, TraceExporter};
+use knockturn::wallet::{OutputsConfig, Wallet};
+use knockturn::app::{AppState, Routes};
+use knockturn::captcha::Captcha;
+use knockturn::{app, clock, cron};
+use futures::Future;
+use log::{info, warn};
 use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
 use sentry;
 use std::env;
+use std::time::Duration;
 
 fn main() {
     dotenv().ok();
 
     env_logger::init();
 
+    // `knockturn seed [--merchants=N] [--transactions=N] [--days=N]` fills
+    // a development database and exits
+    if env::args().nth(1).as_ref().map(String::as_str) == Some("seed") {
+        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let config = SeedConfig::from_args(env::args().skip(2))
+            .unwrap_or_else(|e| panic!("Cannot parse seed arguments: {}", e));
+        let conn = PgConnection::establish(&database_url).expect("Cannot connect to DATABASE_URL");
+        let summary = seed::seed(&conn, &config).unwrap_or_else(|e| panic!("Cannot seed: {}", e));
+        println!(
+            "Seeded {} transactions of merchants {}, their password is \"{}\"",
+            summary.transactions,
+            summary.merchants.join(", "),
+            seed::SEED_PASSWORD
+        );
+        return;
+    }
+
+    // `knockturn export --since=YYYY-MM-DD [--format=json|csv] [--dir=PATH]`
+    // dumps merchants, transactions and settlements and exits
+    if env::args().nth(1).as_ref().map(String::as_str) == Some("export") {
+        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let config = ExportConfig::from_args(env::args().skip(2))
+            .unwrap_or_else(|e| panic!("Cannot parse export arguments: {}", e));
+        let conn = PgConnection::establish(&database_url).expect("Cannot connect to DATABASE_URL");
+        let summary = export::export(&conn, &config).unwrap_or_else(|e| panic!("Cannot export: {}", e));
+        println!(
+            "Exported {} merchants, {} transactions and {} settlements to {}",
+            summary.merchants,
+            summary.transactions,
+            summary.settlements,
+            config.dir.display()
+        );
+        return;
+    }
+
+    // `knockturn retry-queue export|import FILE` saves the callbacks owed
+    // to merchants before a database restore and queues them again after it
+    if env::args().nth(1).as_ref().map(String::as_str) == Some("retry-queue") {
+        let command = env::args().nth(2).unwrap_or_default();
+        let path = env::args().nth(3).expect("Usage: knockturn retry-queue export|import FILE");
+        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let conn = PgConnection::establish(&database_url).expect("Cannot connect to DATABASE_URL");
+        let now = chrono::Utc::now().naive_utc();
+        match command.as_str() {
+            "export" => {
+                let queue = retry_queue::export(&conn, now)
+                    .unwrap_or_else(|e| panic!("Cannot export the retry queue: {}", e));
+                let file = std::fs::File::create(&path)
+                    .unwrap_or_else(|e| panic!("Cannot create {}: {}", path, e));
+                serde_json::to_writer_pretty(file, &queue)
+                    .unwrap_or_else(|e| panic!("Cannot write {}: {}", path, e));
+                println!("Exported {} callbacks to {}", queue.entries.len(), path);
+            }
+            "import" => {
+                let file = std::fs::File::open(&path)
+                    .unwrap_or_else(|e| panic!("Cannot open {}: {}", path, e));
+                let queue: RetryQueue = serde_json::from_reader(file)
+                    .unwrap_or_else(|e| panic!("Cannot read {}: {}", path, e));
+                let summary = retry_queue::import(&conn, &queue, now)
+                    .unwrap_or_else(|e| panic!("Cannot import the retry queue: {}", e));
+                for (transaction_id, reason) in &summary.skipped {
+                    println!("Skipped {}: {}", transaction_id, reason);
+                }
+                println!(
+                    "Queued {} callbacks, skipped {}",
+                    summary.queued,
+                    summary.skipped.len()
+                );
+            }
+            _ => panic!("Usage: knockturn retry-queue export|import FILE"),
+        }
+        return;
+    }
+
+    // Fake wallet and node, nothing is needed but Postgres
+    let demo_config = if env::args().any(|arg| arg == "--demo") {
+        Some(DemoConfig::from_env())
+    } else {
+        None
+    };
+
     let cookie_secret = env::var("COOKIE_SECRET").expect("COOKIE_SECRET must be set");
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let host = env::var("HOST").unwrap_or("0.0.0.0:3000".to_owned());
     let _ = env::var("DOMAIN").expect("DOMAIN must be set");
     let sys = actix::System::new("Knockout");
 
+    let pool_size: u32 = env::var("DATABASE_POOL_SIZE")
+        .map(|v| v.parse().expect("DATABASE_POOL_SIZE must be a number"))
+        .unwrap_or(10);
+    let statement_timeout: u64 = env::var("DATABASE_STATEMENT_TIMEOUT_MS")
+        .map(|v| v.parse().expect("DATABASE_STATEMENT_TIMEOUT_MS must be a number"))
+        .unwrap_or(30_000);
+
+    // Dedicated connection holding the leader lock
+    let leader: Addr<LeaderElection> = SyncArbiter::start(1, {
+        let database_url = database_url.clone();
+        move || LeaderElection::new(&database_url)
+    });
+
+    let clock = clock::system();
     let manager = ConnectionManager::<PgConnection>::new(database_url);
     let pool = r2d2::Pool::builder()
+        .max_size(pool_size)
+        .connection_timeout(Duration::from_secs(5))
+        .connection_customizer(Box::new(StatementTimeout(statement_timeout)))
         .build(manager)
         .expect("Failed to create pool.");
 
-    let pool_clone = pool.clone();
-    let address: Addr<DbExecutor> = SyncArbiter::start(10, move || DbExecutor(pool_clone.clone()));
+    // One executor per connection, so DB work never waits on a connection
+    // and a full executor mailbox pushes back on callers instead
+    let address: Addr<DbExecutor> =
+        SyncArbiter::start(pool_size as usize, {
+            let clock = clock.clone();
+            move || DbExecutor(pool.clone(), clock.clone())
+        });
+
+    // Cron queries still work without the indexes, just slowly
+    Arbiter::spawn(address.send(GetMissingIndexes).then(|res| {
+        match res {
+            Ok(Ok(missing)) => {
+                for index in missing {
+                    warn!("Index {} is missing, queries on it will be slow", index);
+                }
+            }
+            Ok(Err(e)) => warn!("Cannot check indexes: {}", e),
+            Err(e) => warn!("Cannot check indexes: {}", e),
+        }
+        Ok(())
+    }));
 
-    let wallet_url = env::var("WALLET_URL").expect("WALLET_URL must be set");
-    let wallet_user = env::var("WALLET_USER").expect("WALLET_USER must be set");
-    let wallet_pass = env::var("WALLET_PASS").expect("WALLET_PASS must be set");
+    let (wallet, node) = match demo_config {
+        Some(demo_config) => {
+            warn!("Running in demo mode, the wallet, the node and the buyers are fake");
+            let url = demo_config.url();
+            demo::start(demo_config, address.clone());
+            let wallet = Wallet::new(&url, "demo", "demo")
+                .with_outputs_config(OutputsConfig::from_env());
+            let node = node::connect("v1", &url, "demo", "demo").unwrap();
+            (wallet, node)
+        }
+        None => {
+            let wallet_url = env::var("WALLET_URL").expect("WALLET_URL must be set");
+            let wallet_user = env::var("WALLET_USER").expect("WALLET_USER must be set");
+            let wallet_pass = env::var("WALLET_PASS").expect("WALLET_PASS must be set");
 
-    let wallet = Wallet::new(&wallet_url, &wallet_user, &wallet_pass);
+            let wallet = Wallet::new(&wallet_url, &wallet_user, &wallet_pass)
+                .with_outputs_config(OutputsConfig::from_env());
 
-    let node_url = env::var("NODE_URL").expect("NODE_URL must be set");
-    let node_user = env::var("NODE_USER").expect("NODE_USER must be set");
-    let node_pass = env::var("NODE_PASS").expect("NODE_PASS must be set");
+            let node_url = env::var("NODE_URL").expect("NODE_URL must be set");
+            let node_user = env::var("NODE_USER").expect("NODE_USER must be set");
+            let node_pass = env::var("NODE_PASS").expect("NODE_PASS must be set");
+            let node_api_version = env::var("NODE_API_VERSION").unwrap_or("".to_owned());
+            let node = node::connect(&node_api_version, &node_url, &node_user, &node_pass)
+                .expect("NODE_API_VERSION must be v1 or v2");
+            (wallet, node)
+        }
+    };
     let sentry_url = env::var("SENTRY_URL").unwrap_or("".to_owned());
-    let node = Node::new(&node_url, &node_user, &node_pass);
+
+    let payout_batches = cron::PayoutBatchConfig::from_env();
+
+    let mailer_config = MailerConfig::from_env();
+    if mailer_config.is_none() {
+        info!("MAIL_FROM is not set, emails to buyers won't be sent");
+    }
+    // sendmail blocks, so the mailer gets its own thread
+    let mailer: Addr<Mailer> = SyncArbiter::start(1, move || Mailer::new(mailer_config.clone()));
+
+    let oidc = OidcClient::from_env();
+    if oidc.is_none() {
+        info!("OpenID Connect is not configured, only password login is enabled");
+    }
+    let registration = Registration::from_env();
+    let captcha = Captcha::from_env();
+    if registration == Registration::Open && captcha.is_none() {
+        panic!("CAPTCHA_SECRET must be set for open registration");
+    }
 
     if sentry_url != "" {
         let _ = sentry::init("https://3a46c4de68e54de9ab7e86e7547a4073@sentry.io/1464519");
@@ -50,47 +219,119 @@ fn main() {
         sentry::integrations::panic::register_panic_handler();
     }
 
+    match TraceConfig::from_env() {
+        Some(trace_config) => {
+            trace::init(&trace_config);
+            let _ = Arbiter::start(move |_| TraceExporter::new(trace_config));
+        }
+        None => info!("OTEL_EXPORTER_OTLP_ENDPOINT is not set, traces won't be exported"),
+    }
+
     info!("Starting");
     let cron_db = address.clone();
 
+    let notifier: Addr<Notifier> = Arbiter::start(|_| Notifier::from_env());
+
     let fsm: Addr<Fsm> = Arbiter::start({
         let wallet = wallet.clone();
         let db = address.clone();
-        let pool = pool.clone();
-        move |_| Fsm { db, wallet, pool }
+        let clock = clock.clone();
+        let notifier = notifier.clone();
+        move |_| Fsm { db, wallet, clock, notifier, observers: vec![] }
+    });
+    fsm.do_send(Subscribe(TransitionMetrics.start().recipient()));
+    // Jobs are claimed with SKIP LOCKED, so every instance works on them
+    let _: Addr<JobWorker> = Arbiter::start({
+        let db = address.clone();
+        let fsm = fsm.clone();
+        let payout_batches = payout_batches.clone();
+        move |_| JobWorker::new(db, fsm, payout_batches)
     });
-       let _cron = Arbiter::start({
+       let cron: Addr<cron::Cron> = Arbiter::start({
         let fsm = fsm.clone();
-        let pool = pool.clone();
         let cron_db = cron_db.clone();
-        move |_| cron::Cron::new(cron_db, fsm, node, pool)
+        let wallet = wallet.clone();
+        let notifier = notifier.clone();
+        move |_| {
+            cron::Cron::new(
+                cron_db,
+                fsm,
+                node,
+                wallet,
+                payout_batches,
+                leader,
+                mailer,
+                notifier,
+            )
+        }
     });
   
-    let mut srv = server::new(move || {
-        app::create_app(
-            address.clone(),
-            wallet.clone(),
-            fsm.clone(),
-            pool.clone(),
-            cookie_secret.as_bytes(),
-            sentry_url != "",
-        )
-    });
-
-    srv = if let Ok(folder) = env::var("TLS_FOLDER") {
-        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
-        builder
-            .set_private_key_file(format!("{}/privkey.pem", folder), SslFiletype::PEM)
-            .unwrap();
-        builder
-            .set_certificate_chain_file(format!("{}/fullchain.pem", folder))
-            .unwrap();
-        srv.bind_ssl(&host, builder)
-            .expect(&format!("Can not bind_ssl to '{}'", &host))
-    } else {
-        srv.bind(&host)
-            .expect(&format!("Can not bind to '{}'", &host))
+    let server_config = ServerConfig::from_env();
+    let enable_sentry = sentry_url != "";
+    let app_factory = move |routes: Routes| {
+        let address = address.clone();
+        let wallet = wallet.clone();
+        let fsm = fsm.clone();
+        let cron = cron.clone();
+        let notifier = notifier.clone();
+        let oidc = oidc.clone();
+        let captcha = captcha.clone();
+        let cookie_secret = cookie_secret.clone();
+        move || {
+            app::create_app(
+                address.clone(),
+                wallet.clone(),
+                fsm.clone(),
+                cron.clone(),
+                notifier.clone(),
+                oidc.clone(),
+                registration,
+                captcha.clone(),
+                cookie_secret.as_bytes(),
+                enable_sentry,
+                routes,
+            )
+        }
     };
-    srv.start();
+    if server_config.internal_hosts.is_empty() {
+        serve(&server_config, &server_config.hosts, app_factory(Routes::All));
+    } else {
+        serve(&server_config, &server_config.hosts, app_factory(Routes::Checkout));
+        serve(&server_config, &server_config.internal_hosts, app_factory(Routes::Internal));
+    }
     sys.run();
 }
+
+/// Starts a server with the app on every one of `hosts`
+fn serve<F>(config: &ServerConfig, hosts: &[String], app: F)
+where
+    F: Fn() -> App<AppState> + Send + Clone + 'static,
+{
+    let mut srv = server::new(app)
+        .keep_alive(config.keep_alive())
+        .client_timeout(config.client_timeout_ms)
+        .maxconn(config.max_connections)
+        .backlog(config.backlog);
+    if let Some(workers) = config.workers {
+        srv = srv.workers(workers);
+    }
+
+    let tls_folder = env::var("TLS_FOLDER").ok();
+    for host in hosts {
+        srv = if let Some(ref folder) = tls_folder {
+            let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+            builder
+                .set_private_key_file(format!("{}/privkey.pem", folder), SslFiletype::PEM)
+                .unwrap();
+            builder
+                .set_certificate_chain_file(format!("{}/fullchain.pem", folder))
+                .unwrap();
+            srv.bind_ssl(host, builder)
+                .expect(&format!("Can not bind_ssl to '{}'", host))
+        } else {
+            srv.bind(host)
+                .expect(&format!("Can not bind to '{}'", host))
+        };
+    }
+    srv.start();
+}
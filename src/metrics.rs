@@ -0,0 +1,212 @@
+//! Process wide counters, gauges and summaries, rendered in the Prometheus
+//! text format by the `/metrics` endpoint.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::sync::Mutex;
+
+lazy_static::lazy_static! {
+    static ref COUNTERS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+    static ref GAUGES: Mutex<BTreeMap<String, i64>> = Mutex::new(BTreeMap::new());
+    static ref SUMMARIES: Mutex<BTreeMap<String, Summary>> = Mutex::new(BTreeMap::new());
+}
+
+/// Sum and count of observed values, without quantiles
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Summary {
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Summary {
+    pub fn mean(&self) -> Option<f64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.sum / self.count as f64)
+        }
+    }
+}
+
+fn key(name: &str, labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return name.to_owned();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(label, value)| format!("{}=\"{}\"", label, value.replace('"', "\\\"")))
+        .collect();
+    format!("{}{{{}}}", name, labels.join(","))
+}
+
+pub fn inc(name: &str, labels: &[(&str, &str)]) {
+    let mut counters = COUNTERS.lock().unwrap();
+    *counters.entry(key(name, labels)).or_insert(0) += 1;
+}
+
+pub fn get(name: &str, labels: &[(&str, &str)]) -> u64 {
+    let counters = COUNTERS.lock().unwrap();
+    counters.get(&key(name, labels)).cloned().unwrap_or(0)
+}
+
+/// Sum of the counters of `name` with the label `label`, whatever their
+/// other labels are
+pub fn get_total(name: &str, label: (&str, &str)) -> u64 {
+    let label = format!("{}=\"{}\"", label.0, label.1);
+    let counters = COUNTERS.lock().unwrap();
+    counters
+        .iter()
+        .filter(|(key, _)| {
+            let mut parts = key.splitn(2, '{');
+            parts.next() == Some(name)
+                && parts.next().map_or(false, |labels| {
+                    labels.split(',').any(|l| l.trim_end_matches('}') == label)
+                })
+        })
+        .map(|(_, value)| value)
+        .sum()
+}
+
+/// Replaces the gauge's value
+pub fn set(name: &str, labels: &[(&str, &str)], value: i64) {
+    let mut gauges = GAUGES.lock().unwrap();
+    gauges.insert(key(name, labels), value);
+}
+
+pub fn get_gauge(name: &str, labels: &[(&str, &str)]) -> Option<i64> {
+    let gauges = GAUGES.lock().unwrap();
+    gauges.get(&key(name, labels)).cloned()
+}
+
+pub fn observe(name: &str, labels: &[(&str, &str)], value: f64) {
+    let mut summaries = SUMMARIES.lock().unwrap();
+    let summary = summaries.entry(key(name, labels)).or_default();
+    summary.sum += value;
+    summary.count += 1;
+}
+
+pub fn get_summary(name: &str, labels: &[(&str, &str)]) -> Summary {
+    let summaries = SUMMARIES.lock().unwrap();
+    summaries
+        .get(&key(name, labels))
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Summary over all label values of `name`
+pub fn get_summary_total(name: &str) -> Summary {
+    let summaries = SUMMARIES.lock().unwrap();
+    summaries
+        .iter()
+        .filter(|(key, _)| key.split('{').next() == Some(name))
+        .fold(Summary::default(), |total, (_, summary)| Summary {
+            sum: total.sum + summary.sum,
+            count: total.count + summary.count,
+        })
+}
+
+pub fn render() -> String {
+    let mut out = String::new();
+    render_family(&mut out, "counter", &COUNTERS.lock().unwrap());
+    render_family(&mut out, "gauge", &GAUGES.lock().unwrap());
+    render_summaries(&mut out, &SUMMARIES.lock().unwrap());
+    out
+}
+
+fn render_summaries(out: &mut String, summaries: &BTreeMap<String, Summary>) {
+    let mut last_name = "";
+    for (key, summary) in summaries.iter() {
+        let mut parts = key.splitn(2, '{');
+        let name = parts.next().unwrap_or(key);
+        let labels = parts
+            .next()
+            .map(|labels| format!("{{{}", labels))
+            .unwrap_or_default();
+        if name != last_name {
+            out.push_str(&format!("# TYPE {} summary\n", name));
+            last_name = name;
+        }
+        out.push_str(&format!("{}_sum{} {}\n", name, labels, summary.sum));
+        out.push_str(&format!("{}_count{} {}\n", name, labels, summary.count));
+    }
+}
+
+fn render_family<T: Display>(out: &mut String, metric_type: &str, values: &BTreeMap<String, T>) {
+    let mut by_name: BTreeMap<&str, Vec<(&String, &T)>> = BTreeMap::new();
+    for (key, value) in values.iter() {
+        let name = key.split('{').next().unwrap_or(key);
+        by_name
+            .entry(name)
+            .or_insert_with(Vec::new)
+            .push((key, value));
+    }
+    for (name, samples) in by_name {
+        out.push_str(&format!("# TYPE {} {}\n", name, metric_type));
+        for (key, value) in samples {
+            out.push_str(&format!("{} {}\n", key, value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters() {
+        inc("test_ticks_total", &[("job", "sync")]);
+        inc("test_ticks_total", &[("job", "sync")]);
+        inc("test_ticks_total", &[("job", "pool")]);
+        assert_eq!(get("test_ticks_total", &[("job", "sync")]), 2);
+        assert_eq!(get("test_ticks_total", &[("job", "other")]), 0);
+        inc(
+            "test_calls_total",
+            &[("merchant", "a"), ("result", "failed")],
+        );
+        inc(
+            "test_calls_total",
+            &[("merchant", "b"), ("result", "failed")],
+        );
+        inc("test_calls_total", &[("merchant", "b"), ("result", "ok")]);
+        assert_eq!(get_total("test_calls_total", ("result", "failed")), 2);
+        assert_eq!(get_total("test_calls", ("result", "failed")), 0);
+        assert!(render().contains(
+            "# TYPE test_ticks_total counter\n\
+             test_ticks_total{job=\"pool\"} 1\n\
+             test_ticks_total{job=\"sync\"} 2\n"
+        ));
+    }
+
+    #[test]
+    fn test_gauges() {
+        set("test_age_seconds", &[("view", "daily")], 30);
+        set("test_age_seconds", &[("view", "daily")], 5);
+        assert_eq!(get_gauge("test_age_seconds", &[("view", "daily")]), Some(5));
+        assert_eq!(get_gauge("test_age_seconds", &[("view", "other")]), None);
+        assert!(render().contains(
+            "# TYPE test_age_seconds gauge\n\
+             test_age_seconds{view=\"daily\"} 5\n"
+        ));
+    }
+
+    #[test]
+    fn test_summaries() {
+        observe("test_latency_seconds", &[("shop", "a")], 1.5);
+        observe("test_latency_seconds", &[("shop", "a")], 2.5);
+        observe("test_latency_seconds", &[("shop", "b")], 5.0);
+        let a = get_summary("test_latency_seconds", &[("shop", "a")]);
+        assert_eq!(a.count, 2);
+        assert_eq!(a.mean(), Some(2.0));
+        assert_eq!(
+            get_summary("test_latency_seconds", &[("shop", "c")]).mean(),
+            None
+        );
+        assert_eq!(get_summary_total("test_latency_seconds").mean(), Some(3.0));
+        assert!(render().contains(
+            "# TYPE test_latency_seconds summary\n\
+             test_latency_seconds_sum{shop=\"a\"} 4\n\
+             test_latency_seconds_count{shop=\"a\"} 2\n\
+             test_latency_seconds_sum{shop=\"b\"} 5\n"
+        ));
+    }
+}
@@ -0,0 +1,154 @@
+//! Middleware which records merchant API calls into `api_requests` table
+
+use crate::api_version::VERSIONED_PREFIX;
+use crate::app::{self, AppState};
+use crate::db::RecordApiRequest;
+use crate::extractor::AuthenticatedMerchant;
+use crate::geoip;
+use crate::models::ApiRequest;
+use crate::slow_log::{self, SlowKind};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::{Finished, Middleware, Response, Started};
+use actix_web::{HttpRequest, HttpResponse, Result};
+use chrono::Utc;
+use rand::{thread_rng, Rng};
+use std::time::Instant;
+use uuid::Uuid;
+
+/// Env variable with a share (0.0 - 1.0) of successful API calls to record
+const ENV_SAMPLE_RATE_VAR: &str = "API_LOG_SAMPLE_RATE";
+/// Env variable with a share (0.0 - 1.0) of failed (4xx and 5xx) API calls to record
+const ENV_ERROR_SAMPLE_RATE_VAR: &str = "API_LOG_ERROR_SAMPLE_RATE";
+
+/// Only calls to these paths and versioned ones are considered to be API
+/// calls, checkout routes below it aren't
+const API_PATH_PREFIX: &str = "/merchants";
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+fn sample_rate(var: &str, default: f64) -> f64 {
+    match std::env::var(var) {
+        Ok(val) => match val.parse::<f64>() {
+            Ok(val) => val.max(0.0).min(1.0),
+            Err(_) => {
+                log::error!("Can not parse {} value", var);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+fn is_api_call(path: &str) -> bool {
+    (path.starts_with(API_PATH_PREFIX) || path.starts_with(VERSIONED_PREFIX))
+        && !app::is_checkout_path(path)
+}
+
+/// Merchant the request was authenticated as, not the one of the path,
+/// which a caller may make up
+fn merchant_id<S>(req: &HttpRequest<S>) -> Option<String> {
+    req.extensions()
+        .get::<AuthenticatedMerchant>()
+        .map(|merchant| merchant.0.clone())
+}
+
+lazy_static::lazy_static! {
+    static ref SAMPLE_RATE: f64 = sample_rate(ENV_SAMPLE_RATE_VAR, 0.0);
+    static ref ERROR_SAMPLE_RATE: f64 = sample_rate(ENV_ERROR_SAMPLE_RATE_VAR, 1.0);
+}
+
+/// Id and start time of a request, stored in request extensions
+pub struct RequestStart {
+    pub id: Uuid,
+    pub started_at: Instant,
+}
+
+pub struct ApiRequestLogger;
+
+impl Middleware<AppState> for ApiRequestLogger {
+    fn start(&self, req: &HttpRequest<AppState>) -> Result<Started> {
+        req.extensions_mut().insert(RequestStart {
+            id: Uuid::new_v4(),
+            started_at: Instant::now(),
+        });
+        Ok(Started::Done)
+    }
+
+    fn response(&self, req: &HttpRequest<AppState>, mut resp: HttpResponse) -> Result<Response> {
+        if let Some(start) = req.extensions().get::<RequestStart>() {
+            if let Ok(value) = HeaderValue::from_str(&start.id.to_string()) {
+                resp.headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+        }
+        Ok(Response::Done(resp))
+    }
+
+    fn finish(&self, req: &HttpRequest<AppState>, resp: &HttpResponse) -> Finished {
+        if let Some(start) = req.extensions().get::<RequestStart>() {
+            // Query strings may carry tokens, only the path is logged
+            slow_log::check(
+                SlowKind::Handler,
+                &format!("{} {}", req.method(), req.path()),
+                start.started_at.elapsed(),
+                None,
+                Some(start.id),
+            );
+        }
+        if !is_api_call(req.path()) {
+            return Finished::Done;
+        }
+        let status = resp.status();
+        let rate = if status.is_client_error() || status.is_server_error() {
+            *ERROR_SAMPLE_RATE
+        } else {
+            *SAMPLE_RATE
+        };
+        if rate <= 0.0 || !thread_rng().gen_bool(rate) {
+            return Finished::Done;
+        }
+        let (id, latency) = match req.extensions().get::<RequestStart>() {
+            Some(start) => (start.id, start.started_at.elapsed()),
+            None => return Finished::Done,
+        };
+        let location = geoip::locate(req);
+        let api_request = ApiRequest {
+            id,
+            merchant_id: merchant_id(req),
+            method: req.method().to_string(),
+            path: req.path().to_owned(),
+            status_code: status.as_u16() as i32,
+            latency_ms: (latency.as_secs() * 1000 + latency.subsec_millis() as u64) as i64,
+            error: resp.error().map(|e| e.to_string()),
+            created_at: Utc::now().naive_utc(),
+            country: location.country,
+            asn: location.asn,
+        };
+        req.state().db.do_send(RecordApiRequest(api_request));
+        Finished::Done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_is_api_call() {
+        assert!(is_api_call("/merchants/shop/payments"));
+        assert!(is_api_call("/api/v1/merchants/shop/payments"));
+        assert!(!is_api_call("/merchants/shop/profile"));
+        assert!(!is_api_call("/checkout/abc/v2/foreign"));
+        assert!(!is_api_call("/login"));
+    }
+
+    #[test]
+    fn test_merchant_id() {
+        let req = TestRequest::with_uri("/merchants/other/payments").finish();
+        assert_eq!(merchant_id(&req), None);
+        req.extensions_mut()
+            .insert(AuthenticatedMerchant(s!("shop")));
+        assert_eq!(merchant_id(&req), Some(s!("shop")));
+    }
+}
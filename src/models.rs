@@ -1,10 +1,23 @@
-use crate::schema::{current_height, merchants, rates, transactions};
-use chrono::{Duration, NaiveDateTime, Utc};
+use crate::errors::Error;
+use crate::explorer::ExplorerLinks;
+use crate::schema::{
+    api_requests, api_tokens, balance_credits, blocks, current_height, denied_networks,
+    feature_flag_overrides, feature_flags, invite_codes, jobs, merchants, payment_splits,
+    payout_batches, payout_events, pending_credits, plans, rate_limit_buckets, rates,
+    reconciliation_orphans, refund_addresses, security_events, transaction_notes, transactions,
+    webauthn_credentials,
+};
+use crate::wallet::OutputSelection;
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use data_encoding::HEXLOWER;
 use diesel::deserialize::{self, FromSql};
 use diesel::pg::Pg;
 use diesel::serialize::{self, Output, ToSql};
 use diesel::sql_types::Jsonb;
 use diesel_derive_enum::DbEnum;
+use openssl::sha::sha256;
+use rust_decimal::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::fmt;
 use strum_macros::{Display, EnumString};
@@ -17,23 +30,650 @@ pub const NEW_PAYOUT_TTL_SECONDS: i64 = 5 * 60; //5  minutes since creation time
 pub const INITIALIZED_PAYOUT_TTL_SECONDS: i64 = 5 * 60; //5  minutes since creation time
 pub const PENDING_PAYOUT_TTL_SECONDS: i64 = 15 * 60; //15 minutes since became pending
 
+pub const RATE_LOCK_SECONDS: i64 = NEW_PAYMENT_TTL_SECONDS; // How long the exchange rate of a new payment is guaranteed
+pub const REQUOTE_WINDOW_SECONDS: i64 = 15 * 60; // How long a payment with expired rate lock can be requoted
+pub const MAX_REQUOTES: i32 = 1;
+pub const PAYMENT_PROCESSING_SECONDS: i64 = 2 * 60; // How long a slate submission holds the payment, in case its instance dies
+
+pub const MAX_METADATA_SIZE: usize = 4096; // Max size of merchant's metadata serialized as json
+
+pub const MAX_MESSAGE_LENGTH: usize = 256; // Max length of the payment message in bytes, it's signed into the slate
+
+pub const MAX_USER_AGENT_LENGTH: usize = 256; // Max length of the stored user agent of the buyer's wallet, in chars
+
 pub const WAIT_PER_CONFIRMATION_SECONDS: i64 = 5 * 60; // How long we wait per confirmation. E.g. if payment requires 5 confirmations we will wail 5 * WAIT_PER_CONFIRMATION_SECONDS
 
+pub const CONVERSION_ROUNDING: RoundingStrategy = RoundingStrategy::AwayFromZero; // Round converted amounts up, so the merchant is never underpaid
+pub const CONVERSION_ROUNDING_NAME: &'static str = "away_from_zero";
+
 #[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Identifiable, Clone)]
 #[table_name = "merchants"]
 pub struct Merchant {
     pub id: String,
     pub email: String,
+    /// Bcrypt hash
+    #[serde(skip_serializing)]
     pub password: String,
     pub wallet_url: Option<String>,
     pub balance: i64,
     pub created_at: NaiveDateTime,
+    /// API token, returned once when the merchant is created
+    #[serde(skip_serializing)]
     pub token: String,
     pub callback_url: Option<String>,
     #[serde(skip_serializing)]
     pub token_2fa: Option<String>,
     #[serde(skip_serializing)]
     pub confirmed_2fa: bool,
+    #[serde(skip_serializing)]
+    pub second_factor: SecondFactor,
+    /// Subject of the linked OpenID Connect account
+    #[serde(skip_serializing)]
+    pub oidc_subject: Option<String>,
+    /// Operators of the service, set directly in the DB
+    #[serde(skip_serializing)]
+    pub is_admin: bool,
+    /// Receives payout events, payment callbacks go to `callback_url`
+    pub payout_callback_url: Option<String>,
+    /// Branding of emails sent to buyers on the merchant's behalf
+    pub email_logo_url: Option<String>,
+    pub email_footer: Option<String>,
+    pub email_reply_to: Option<String>,
+    /// IANA time zone dates are shown in on the merchant's pages
+    pub timezone: String,
+    /// Starts the invoice numbers of the merchant's payments
+    pub invoice_prefix: String,
+    /// Last invoice number given out
+    #[serde(skip_serializing)]
+    pub invoice_sequence: i64,
+    /// How callbacks are sent, see `callback::CallbackSettings`
+    pub callback_timeout_seconds: i32,
+    #[serde(skip_serializing)]
+    pub callback_headers: Option<serde_json::Value>,
+    pub callback_verify_tls: bool,
+    /// Chats events are pushed to, see `integrations`
+    pub telegram_chat_id: Option<String>,
+    #[serde(skip_serializing)]
+    pub slack_webhook_url: Option<String>,
+    /// Shape of callback bodies, see `callback_template`
+    pub callback_template: Option<serde_json::Value>,
+    /// See `SlateMessageCheck`
+    pub slate_message_check: String,
+    /// Pending and in chain payments are reported too, not only
+    /// confirmed and rejected ones
+    pub verbose_callbacks: bool,
+    /// Overrides of the callback retries, see `callback::RetryPolicy`
+    pub callback_max_attempts: Option<i32>,
+    pub callback_backoff_seconds: Option<i32>,
+    pub callback_retry_window_seconds: Option<i32>,
+    /// Picks the payment rate limit, see `rate_limit`
+    #[serde(skip_serializing)]
+    pub plan: String,
+    /// Buyers who left an email are reminded of a new payment after that
+    /// many minutes, `None` sends no reminders
+    #[serde(skip_serializing)]
+    pub payment_reminder_minutes: Option<i32>,
+    /// Marketplace the merchant sells on, which may give it shares of its
+    /// payments, see `splits`
+    pub platform_id: Option<String>,
+    /// Who referred the merchant and their share of its knockturn fees in
+    /// hundredths of a percent, see `referrals`
+    #[serde(skip_serializing)]
+    pub referrer_id: Option<String>,
+    #[serde(skip_serializing)]
+    pub referral_share_bps: Option<i32>,
+    /// Credited but still clearing, `balance` is what's available, see
+    /// `clearing`
+    pub pending_balance: i64,
+}
+
+impl Merchant {
+    /// Falls back to UTC if the stored zone is unknown
+    pub fn tz(&self) -> Tz {
+        self.timezone.parse().unwrap_or(Tz::UTC)
+    }
+
+    /// Falls back to not checking if the stored value is unknown
+    pub fn slate_message_check(&self) -> SlateMessageCheck {
+        self.slate_message_check
+            .parse()
+            .unwrap_or(SlateMessageCheck::Off)
+    }
+}
+
+/// What anyone may see of a merchant, for branding the checkout
+#[derive(Debug, Serialize)]
+pub struct MerchantProfile {
+    pub id: String,
+    pub logo_url: Option<String>,
+    pub footer: Option<String>,
+}
+
+impl MerchantProfile {
+    pub fn of(merchant: &Merchant) -> Self {
+        MerchantProfile {
+            id: merchant.id.clone(),
+            logo_url: merchant.email_logo_url.clone(),
+            footer: merchant.email_footer.clone(),
+        }
+    }
+}
+
+pub const MAX_INVOICE_PREFIX_LENGTH: usize = 16;
+
+/// Letters and digits only, so the number reads well on receipts
+pub fn validate_invoice_prefix(prefix: &str) -> Result<(), Error> {
+    if prefix.is_empty()
+        || prefix.len() > MAX_INVOICE_PREFIX_LENGTH
+        || !prefix.chars().all(|c| c.is_ascii_alphanumeric())
+    {
+        return Err(Error::InvalidEntity(format!(
+            "invoice prefix should be 1 to {} letters or digits",
+            MAX_INVOICE_PREFIX_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+/// Invoice number like `KT-2019-000123`, the sequence doesn't restart
+/// with a new year
+pub fn format_invoice_number(prefix: &str, year: i32, sequence: i64) -> String {
+    format!("{}-{}-{:06}", prefix, year, sequence)
+}
+
+/// Fills `{order_id}`, `{amount}` and `{merchant}` in the payment message,
+/// other braces are kept as they are
+pub fn fill_payment_message(
+    message: &str,
+    order_id: &str,
+    amount: &Money,
+    merchant_id: &str,
+) -> String {
+    let mut filled = String::with_capacity(message.len());
+    let mut rest = message;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = if rest.starts_with("{order_id}") {
+            Some(("{order_id}".len(), order_id.to_owned()))
+        } else if rest.starts_with("{amount}") {
+            Some(("{amount}".len(), amount.to_string()))
+        } else if rest.starts_with("{merchant}") {
+            Some(("{merchant}".len(), merchant_id.to_owned()))
+        } else {
+            None
+        };
+        match value {
+            Some((len, value)) => {
+                filled.push_str(&value);
+                rest = &rest[len..];
+            }
+            None => {
+                filled.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+pub fn validate_payment_message(message: &str) -> Result<(), Error> {
+    if message.len() > MAX_MESSAGE_LENGTH {
+        return Err(Error::InvalidEntity(format!(
+            "message should be at most {} bytes with variables filled in",
+            MAX_MESSAGE_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+/// Second factors a merchant accepts on login and payout approval
+#[derive(Debug, PartialEq, DbEnum, Serialize, Deserialize, Clone, Copy, Display)]
+#[DieselType = "Second_factor"]
+#[serde(rename_all = "snake_case")]
+pub enum SecondFactor {
+    Totp,
+    SecurityKey,
+    /// Either TOTP or a security key
+    Both,
+}
+
+impl SecondFactor {
+    pub fn allows_totp(&self) -> bool {
+        *self != SecondFactor::SecurityKey
+    }
+
+    pub fn allows_security_key(&self) -> bool {
+        *self != SecondFactor::Totp
+    }
+}
+
+/// How the message the buyer put in the slate is checked before the
+/// payment is received
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, EnumString, Display)]
+#[serde(rename_all = "snake_case")]
+pub enum SlateMessageCheck {
+    #[strum(serialize = "off")]
+    Off,
+    /// Same as the payment's message, whitespace around it aside
+    #[strum(serialize = "exact")]
+    Exact,
+    /// Has the payment id somewhere in it
+    #[strum(serialize = "contains_id")]
+    ContainsId,
+}
+
+impl SlateMessageCheck {
+    pub const ALL: [SlateMessageCheck; 3] = [
+        SlateMessageCheck::Off,
+        SlateMessageCheck::Exact,
+        SlateMessageCheck::ContainsId,
+    ];
+
+    /// `message` is the sender's message from the slate
+    pub fn check(&self, payment: &Transaction, message: Option<&str>) -> Result<(), Error> {
+        let message = message.unwrap_or("").trim();
+        match self {
+            SlateMessageCheck::Off => Ok(()),
+            SlateMessageCheck::Exact if message != payment.message.trim() => {
+                Err(Error::InvalidEntity(format!(
+                    "slate message should be \"{}\"",
+                    payment.message.trim()
+                )))
+            }
+            SlateMessageCheck::ContainsId
+                if !message.to_lowercase().contains(&payment.id.to_string()) =>
+            {
+                Err(Error::InvalidEntity(format!(
+                    "slate message should contain the payment id {}",
+                    payment.id
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// WebAuthn credential (security key) registered by a merchant
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Clone)]
+#[table_name = "webauthn_credentials"]
+pub struct WebauthnCredential {
+    pub id: String,
+    pub merchant_id: String,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub public_key: Vec<u8>,
+    pub sign_count: i64,
+    pub created_at: NaiveDateTime,
+}
+
+/// Permissions an API token can be granted
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, EnumString, Display)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiScope {
+    #[strum(serialize = "create_payments")]
+    CreatePayments,
+    #[strum(serialize = "read_payments")]
+    ReadPayments,
+    #[strum(serialize = "create_payouts")]
+    CreatePayouts,
+    #[strum(serialize = "read_stats")]
+    ReadStats,
+    #[strum(serialize = "write_notes")]
+    WriteNotes,
+}
+
+impl ApiScope {
+    pub const ALL: [ApiScope; 5] = [
+        ApiScope::CreatePayments,
+        ApiScope::ReadPayments,
+        ApiScope::CreatePayouts,
+        ApiScope::ReadStats,
+        ApiScope::WriteNotes,
+    ];
+}
+
+/// Refund destination in a merchant's address book, see `refund_addresses`
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Clone)]
+#[table_name = "refund_addresses"]
+pub struct RefundAddress {
+    pub id: Uuid,
+    pub merchant_id: String,
+    pub label: String,
+    pub address: String,
+    pub created_at: NaiveDateTime,
+    pub verified_at: NaiveDateTime,
+}
+
+impl RefundAddress {
+    pub fn is_verified(&self, now: NaiveDateTime) -> bool {
+        self.verified_at <= now
+    }
+}
+
+/// Share of a payment credited to a seller of the platform it was made
+/// to, see `splits`
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Clone, PartialEq)]
+#[table_name = "payment_splits"]
+pub struct PaymentSplit {
+    pub transaction_id: Uuid,
+    pub merchant_id: String,
+    /// In hundredths of a percent
+    pub share_bps: i32,
+}
+
+/// Change of a merchant's balance by a payment, negative when an admin
+/// voided pending credits
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Clone)]
+#[table_name = "balance_credits"]
+pub struct BalanceCredit {
+    pub id: Uuid,
+    pub transaction_id: Uuid,
+    pub merchant_id: String,
+    pub grin_amount: i64,
+    pub created_at: NaiveDateTime,
+}
+
+/// Credit of a merchant's pending balance until it clears, see `clearing`
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Clone)]
+#[table_name = "pending_credits"]
+pub struct PendingCredit {
+    pub id: Uuid,
+    pub transaction_id: Uuid,
+    pub merchant_id: String,
+    pub grin_amount: i64,
+    pub created_at: NaiveDateTime,
+    pub available_at: NaiveDateTime,
+    /// Also clears only from this height on
+    pub available_height: i64,
+    /// Held by an admin, it isn't released until an admin releases it
+    pub held: bool,
+}
+
+/// Scoped API token. Only a hash of the key is stored, the key itself
+/// is shown to the merchant once on creation.
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Clone)]
+#[table_name = "api_tokens"]
+pub struct ApiToken {
+    pub id: Uuid,
+    pub merchant_id: String,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub token_hash: String,
+    pub scopes: Vec<String>,
+    pub created_at: NaiveDateTime,
+}
+
+impl ApiToken {
+    pub fn hash(token: &str) -> String {
+        HEXLOWER.encode(&sha256(token.as_bytes()))
+    }
+
+    /// Unknown scopes (e.g. removed ones) are ignored
+    pub fn scopes(&self) -> Vec<ApiScope> {
+        self.scopes.iter().filter_map(|s| s.parse().ok()).collect()
+    }
+}
+
+/// Lets up to `max_uses` merchants register while registration is invite only
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Clone)]
+#[table_name = "invite_codes"]
+pub struct InviteCode {
+    pub code: String,
+    pub max_uses: i32,
+    pub uses: i32,
+    pub expires_at: Option<NaiveDateTime>,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl InviteCode {
+    pub fn is_usable(&self, now: NaiveDateTime) -> bool {
+        self.uses < self.max_uses && self.expires_at.map_or(true, |expires_at| expires_at > now)
+    }
+}
+
+/// Network an admin denied submitting payments, see `deny_list`
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Clone)]
+#[table_name = "denied_networks"]
+pub struct DeniedNetwork {
+    /// In CIDR notation
+    pub network: String,
+    pub reason: String,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// Flag as an admin set it, see `feature_flags`
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, AsChangeset, Clone)]
+#[table_name = "feature_flags"]
+pub struct FeatureFlag {
+    /// `Flag`
+    pub name: String,
+    pub enabled: bool,
+    /// Share of merchants an enabled flag is on for, from 0 to 100
+    pub rollout_percent: i32,
+    pub updated_by: String,
+    pub updated_at: NaiveDateTime,
+}
+
+/// Flag turned on or off for one merchant
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, AsChangeset, Clone)]
+#[table_name = "feature_flag_overrides"]
+pub struct FeatureFlagOverride {
+    pub name: String,
+    pub merchant_id: String,
+    pub enabled: bool,
+    pub updated_by: String,
+    pub updated_at: NaiveDateTime,
+}
+
+/// Limits of merchants on the plan, see `plans`
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Clone)]
+#[table_name = "plans"]
+pub struct Plan {
+    pub name: String,
+    /// Payments created per calendar month, `None` is unlimited
+    pub monthly_payments: Option<i64>,
+    /// Largest payment in nanogrins, `None` is unlimited
+    pub max_payment_amount: Option<i64>,
+    /// Lower bound of the callback retry backoff
+    pub min_callback_backoff_seconds: i32,
+    /// Token bucket of payment creation, see `rate_limit`
+    pub api_burst: i32,
+    pub api_per_minute: i32,
+    /// Knockturn fee of payments in hundredths of a percent
+    pub fee_bps: i32,
+}
+
+impl Plan {
+    pub fn max_payment(&self) -> Option<Money> {
+        self.max_payment_amount.map(Money::from_grin)
+    }
+}
+
+/// Payment creation bucket of a merchant, see `rate_limit`
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, AsChangeset, Clone)]
+#[table_name = "rate_limit_buckets"]
+pub struct RateLimitBucket {
+    pub merchant_id: String,
+    pub tokens: f64,
+    pub updated_at: NaiveDateTime,
+}
+
+/// Payouts which were due in the same window and sent to the wallet
+/// together. Each has its own wallet transaction, a slate has one receiver.
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Clone)]
+#[table_name = "payout_batches"]
+pub struct PayoutBatch {
+    pub id: Uuid,
+    pub size: i32,
+    pub created_at: NaiveDateTime,
+    pub processed_at: Option<NaiveDateTime>,
+}
+
+/// Which side of the reconciliation has a transaction the other one lacks
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, EnumString, Display)]
+pub enum OrphanSource {
+    /// Wallet transaction without a DB record
+    Wallet,
+    /// DB record without a wallet transaction
+    Db,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Clone)]
+#[table_name = "reconciliation_orphans"]
+pub struct ReconciliationOrphan {
+    pub id: Uuid,
+    pub source: String,
+    pub slate_id: String,
+    pub wallet_tx_id: Option<i64>,
+    pub transaction_id: Option<Uuid>,
+    pub grin_amount: i64,
+    pub found_at: NaiveDateTime,
+}
+
+/// Stage of a payout reported to the merchant's `payout_callback_url`
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, EnumString, Display)]
+#[serde(rename_all = "snake_case")]
+pub enum PayoutEventType {
+    /// Slate was created by the wallet
+    #[strum(serialize = "initialized")]
+    Initialized,
+    /// Slate was finalized and the transaction posted
+    #[strum(serialize = "finalized")]
+    Finalized,
+    #[strum(serialize = "confirmed")]
+    Confirmed,
+    /// Wallet couldn't create the slate, the payout will be retried
+    #[strum(serialize = "failed")]
+    Failed,
+}
+
+/// Payout event waiting in the outbox to be delivered to the merchant
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Identifiable, Clone)]
+#[table_name = "payout_events"]
+pub struct PayoutEvent {
+    pub id: Uuid,
+    pub transaction_id: Uuid,
+    pub merchant_id: String,
+    pub event: String,
+    pub created_at: NaiveDateTime,
+    pub delivered_at: Option<NaiveDateTime>,
+    pub attempts: i32,
+    pub next_attempt: Option<NaiveDateTime>,
+    /// When the event was pushed to the merchant's chats
+    pub notified_at: Option<NaiveDateTime>,
+}
+
+impl PayoutEvent {
+    pub fn new(payout: &Transaction, event: PayoutEventType) -> Self {
+        PayoutEvent {
+            id: Uuid::new_v4(),
+            transaction_id: payout.id,
+            merchant_id: payout.merchant_id.clone(),
+            event: event.to_string(),
+            created_at: Utc::now().naive_utc(),
+            delivered_at: None,
+            attempts: 0,
+            next_attempt: None,
+            notified_at: None,
+        }
+    }
+}
+
+/// Kind of background job, decides how the payload is run
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, EnumString, Display)]
+#[serde(rename_all = "snake_case")]
+pub enum JobType {
+    /// Runs the merchant's callback of a confirmed, rejected or refunded payment
+    #[strum(serialize = "report_payment")]
+    ReportPayment,
+    /// Verbose callback of a pending or in chain payment
+    #[strum(serialize = "report_status")]
+    ReportStatus,
+    /// Sends due payouts to the wallet
+    #[strum(serialize = "process_payout_batch")]
+    ProcessPayoutBatch,
+}
+
+/// Background job waiting in the `jobs` table to be claimed by a worker,
+/// finished jobs are deleted
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Clone)]
+#[table_name = "jobs"]
+pub struct Job {
+    pub id: Uuid,
+    pub job_type: String,
+    /// Only one unfinished job with the same key is queued
+    pub key: Option<String>,
+    pub payload: serde_json::Value,
+    pub run_at: NaiveDateTime,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub locked_by: Option<String>,
+    pub locked_until: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    /// Set when the job ran out of attempts, it isn't run anymore
+    pub failed_at: Option<NaiveDateTime>,
+}
+
+impl Job {
+    pub fn new(job_type: JobType, key: Option<String>, payload: serde_json::Value) -> Self {
+        let now = Utc::now().naive_utc();
+        Job {
+            id: Uuid::new_v4(),
+            job_type: job_type.to_string(),
+            key,
+            payload,
+            run_at: now,
+            attempts: 0,
+            last_error: None,
+            locked_by: None,
+            locked_until: None,
+            created_at: now,
+            failed_at: None,
+        }
+    }
+}
+
+/// Longest note accepted, in characters
+pub const MAX_NOTE_LENGTH: usize = 2000;
+
+/// Timestamped note a merchant or an admin attached to a transaction,
+/// e.g. about a dispute with the buyer
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Clone)]
+#[table_name = "transaction_notes"]
+pub struct TransactionNote {
+    pub id: Uuid,
+    pub transaction_id: Uuid,
+    /// Id of the merchant who wrote the note, differs from the transaction's
+    /// merchant for notes left by admins
+    pub author: String,
+    pub body: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl TransactionNote {
+    pub fn new(transaction_id: Uuid, author: &str, body: &str) -> Result<Self, Error> {
+        let body = body.trim();
+        if body.is_empty() {
+            return Err(Error::InvalidEntity(s!("note is empty")));
+        }
+        if body.chars().count() > MAX_NOTE_LENGTH {
+            return Err(Error::InvalidEntity(format!(
+                "note is longer than {} characters",
+                MAX_NOTE_LENGTH
+            )));
+        }
+        Ok(TransactionNote {
+            id: Uuid::new_v4(),
+            transaction_id,
+            author: author.to_owned(),
+            body: body.to_owned(),
+            created_at: Utc::now().naive_utc(),
+        })
+    }
 }
 
 /*
@@ -63,6 +703,21 @@ pub enum TransactionStatus {
     Refund,
 }
 
+impl TransactionStatus {
+    /// Statuses an admin can move a stuck payment to. These are transitions
+    /// of the FSM which don't need anything from the chain or the wallet.
+    pub fn manual_transitions(self) -> &'static [TransactionStatus] {
+        use TransactionStatus::*;
+        match self {
+            New => &[Rejected],
+            Pending => &[Confirmed, Rejected],
+            InChain => &[Confirmed],
+            Rejected => &[Refund],
+            Confirmed | Initialized | Refund => &[],
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, DbEnum, Serialize, Deserialize, Clone, Copy, EnumString, Display)]
 #[DieselType = "Transaction_type"]
 pub enum TransactionType {
@@ -107,20 +762,91 @@ pub struct Transaction {
     #[serde(skip_serializing)]
     pub commit: Option<String>,
     pub redirect_url: Option<String>,
+    pub exchange_rate: Option<Decimal>,
+    pub rate_locked_until: Option<NaiveDateTime>,
+    pub requotes: i32,
+    pub metadata: Option<serde_json::Value>,
+    #[serde(skip_serializing)]
+    pub expires_at: Option<NaiveDateTime>,
+    /// Payment was seen in chain by our wallet while the node was unavailable
+    pub confirmed_by_wallet: bool,
+    /// When the payment transaction showed up in the node's pool
+    pub seen_in_pool_at: Option<NaiveDateTime>,
+    /// Batch the payout was initialized in
+    pub payout_batch_id: Option<Uuid>,
+    /// When the buyer was emailed a receipt of the confirmed payment
+    pub receipt_sent_at: Option<NaiveDateTime>,
+    /// The buyer entered the email on the payment page to get a receipt
+    #[serde(skip_serializing)]
+    pub receipt_opt_in: bool,
+    /// Payout's own output selection instead of the wallet's
+    #[serde(skip_serializing)]
+    pub output_selection: Option<OutputSelection>,
+    /// Nanogrins added to `grin_amount` to tell payments apart by amount
+    pub amount_tag: Option<i64>,
+    /// Human friendly number of a payment, unique per merchant
+    pub invoice_number: Option<String>,
+    /// Hex of the key which signed the buyer's slate message, `None` when
+    /// the message wasn't signed
+    pub payer_public_key: Option<String>,
+    /// Last pending or in chain status reported by a verbose callback
+    #[serde(skip_serializing)]
+    pub reported_status: Option<TransactionStatus>,
+    /// See `RefundReason`, set when the payment is moved to refund
+    pub refund_reason: Option<String>,
+    /// Version of the slate the buyer's wallet sent
+    #[serde(skip_serializing)]
+    pub slate_version: Option<i32>,
+    /// `User-Agent` the slate was sent with, cut to `MAX_USER_AGENT_LENGTH`
+    #[serde(skip_serializing)]
+    pub payer_user_agent: Option<String>,
+    /// When the rate of the last quote was fetched, `None` for GRIN payments
+    #[serde(skip_serializing)]
+    pub rate_updated_at: Option<NaiveDateTime>,
+    /// Where the buyer opened the checkout page from, see `geoip`
+    #[serde(skip_serializing)]
+    pub payer_country: Option<String>,
+    #[serde(skip_serializing)]
+    pub payer_asn: Option<i64>,
+    /// A slate of the buyer is being received until then
+    #[serde(skip_serializing)]
+    pub processing_until: Option<NaiveDateTime>,
+    /// When the buyer was reminded of the unpaid payment
+    #[serde(skip_serializing)]
+    pub reminder_sent_at: Option<NaiveDateTime>,
+    /// Where a refunded payment is returned to, see `refund_addresses`
+    pub refund_address: Option<String>,
+    /// Credited with `referral_fee` of the `knockturn_fee`, see `referrals`
+    #[serde(skip_serializing)]
+    pub referrer_id: Option<String>,
+    #[serde(skip_serializing)]
+    pub referral_fee: Option<i64>,
+    /// The slate of the buyer which is, or was, received for the payment
+    #[serde(skip_serializing)]
+    pub claimed_slate_id: Option<String>,
+    /// The buyer signed the slate message but the signature didn't verify,
+    /// there's no `payer_public_key` then
+    pub payer_message_unverified: bool,
 }
 
 impl Transaction {
     pub fn is_expired(&self) -> bool {
-        match self.time_until_expired() {
+        self.is_expired_at(Utc::now().naive_utc())
+    }
+
+    /// Whether the transaction is expired at `now` (UTC)
+    pub fn is_expired_at(&self, now: NaiveDateTime) -> bool {
+        match self.time_until_expired_at(now) {
             Some(time) => time < Duration::zero(),
             None => false,
         }
     }
 
-    pub fn time_until_expired(&self) -> Option<Duration> {
-        let expiration_time = match (self.transaction_type, self.status) {
+    /// Absolute time when the transaction expires in its current status
+    pub fn expiration_time(&self) -> Option<NaiveDateTime> {
+        match (self.transaction_type, self.status) {
             (TransactionType::Payment, TransactionStatus::New) => {
-                Some(self.created_at + Duration::seconds(NEW_PAYMENT_TTL_SECONDS))
+                self.expires_at.or_else(|| self.payment_deadline())
             }
             (TransactionType::Payment, TransactionStatus::Pending) => {
                 Some(self.updated_at + Duration::seconds(PENDING_PAYMENT_TTL_SECONDS))
@@ -139,8 +865,70 @@ impl Transaction {
                     + Duration::seconds(self.confirmations * WAIT_PER_CONFIRMATION_SECONDS),
             ),
             (_, _) => None,
-        };
-        expiration_time.map(|exp_time| exp_time - Utc::now().naive_utc())
+        }
+    }
+
+    /// Same as `expiration_time` but as UTC, which is what we show to clients.
+    /// Timestamps are stored in UTC, so this only labels them; rows written in
+    /// a server's local time before that are off by its offset.
+    pub fn expires_at_utc(&self) -> Option<DateTime<Utc>> {
+        self.expiration_time()
+            .map(|exp_time| DateTime::<Utc>::from_utc(exp_time, Utc))
+    }
+
+    /// Time until a new payment must be paid, it's derived from the rate lock
+    /// and stored in `expires_at` when the payment is created or requoted
+    pub fn payment_deadline(&self) -> Option<NaiveDateTime> {
+        if self.transaction_type != TransactionType::Payment {
+            return None;
+        }
+        match self.rate_locked_until {
+            Some(locked_until) if self.can_be_requoted() => {
+                Some(locked_until + Duration::seconds(REQUOTE_WINDOW_SECONDS))
+            }
+            Some(locked_until) => Some(locked_until),
+            None => Some(self.created_at + Duration::seconds(NEW_PAYMENT_TTL_SECONDS)),
+        }
+    }
+
+    pub fn time_until_expired(&self) -> Option<Duration> {
+        self.time_until_expired_at(Utc::now().naive_utc())
+    }
+
+    pub fn time_until_expired_at(&self, now: NaiveDateTime) -> Option<Duration> {
+        self.expiration_time().map(|exp_time| exp_time - now)
+    }
+
+    pub fn is_rate_lock_expired(&self) -> bool {
+        self.is_rate_lock_expired_at(Utc::now().naive_utc())
+    }
+
+    pub fn is_rate_lock_expired_at(&self, now: NaiveDateTime) -> bool {
+        match self.rate_locked_until {
+            Some(locked_until) => locked_until < now,
+            None => false,
+        }
+    }
+
+    /// Whether the grin amount of the payment can be recalculated
+    /// with the current exchange rate
+    pub fn can_be_requoted(&self) -> bool {
+        self.transaction_type == TransactionType::Payment
+            && self.amount.currency != Currency::GRIN
+            && self.requotes < MAX_REQUOTES
+    }
+
+    /// Whether the payment gets a new quote at `now`: its rate lock expired
+    /// less than `REQUOTE_WINDOW_SECONDS` ago and it has requotes left
+    pub fn can_be_requoted_at(&self, now: NaiveDateTime) -> bool {
+        match self.rate_locked_until {
+            Some(locked_until) => {
+                self.can_be_requoted()
+                    && locked_until < now
+                    && now <= locked_until + Duration::seconds(REQUOTE_WINDOW_SECONDS)
+            }
+            None => false,
+        }
     }
 
     pub fn grins(&self) -> Money {
@@ -154,38 +942,209 @@ impl Transaction {
         }
     }
 
+    /// Payment transaction is known to the network but not mined yet
+    pub fn is_seen_in_pool(&self) -> bool {
+        self.status == TransactionStatus::Pending && self.seen_in_pool_at.is_some()
+    }
+
+    /// New payment waiting for exactly `amount` thanks to its amount tag
+    pub fn is_tagged_with(&self, amount: i64) -> bool {
+        self.amount_tag.is_some()
+            && self.status == TransactionStatus::New
+            && self.grin_amount == amount
+    }
+
     pub fn is_invalid_amount(&self, payment_amount: u64) -> bool {
         let amount = self.grin_amount as u64;
         (payment_amount < amount) || (payment_amount - amount > 1_000_000)
     }
 }
 
+/// How the grin amount of a payment was calculated from its amount
+#[derive(Debug, Serialize)]
+pub struct Conversion {
+    pub amount: Money,
+    pub currency: Currency,
+    /// Price of 1 grin in `currency` of the last quote
+    pub exchange_rate: Option<Decimal>,
+    /// When the rate was fetched
+    pub rate_updated_at: Option<NaiveDateTime>,
+    /// When the amount was last quoted
+    pub quoted_at: Option<NaiveDateTime>,
+    pub requotes: i32,
+    /// Nanogrins before rounding
+    pub exact_grin_amount: Option<Decimal>,
+    pub rounding: &'static str,
+    /// Nanogrins added by rounding
+    pub rounding_difference: Option<Decimal>,
+    pub amount_tag: Option<i64>,
+    /// Nanogrins the buyer pays, the rounded amount plus the tag
+    pub grin_amount: i64,
+}
+
+impl Conversion {
+    pub fn of(payment: &Transaction) -> Self {
+        let exact_grin_amount = payment
+            .exchange_rate
+            .and_then(|rate| payment.amount.exact_conversion(Currency::GRIN, rate));
+        let rounded = Decimal::from(payment.grin_amount - payment.amount_tag.unwrap_or(0));
+        Conversion {
+            amount: payment.amount,
+            currency: payment.amount.currency,
+            exchange_rate: payment.exchange_rate,
+            rate_updated_at: payment.rate_updated_at,
+            quoted_at: payment
+                .rate_locked_until
+                .map(|locked_until| locked_until - Duration::seconds(RATE_LOCK_SECONDS)),
+            requotes: payment.requotes,
+            exact_grin_amount,
+            rounding: CONVERSION_ROUNDING_NAME,
+            rounding_difference: exact_grin_amount.map(|exact| rounded - exact),
+            amount_tag: payment.amount_tag,
+            grin_amount: payment.grin_amount,
+        }
+    }
+}
+
+/// Why a payment has to be refunded to the buyer
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy, EnumString, Display)]
+#[serde(rename_all = "snake_case")]
+pub enum RefundReason {
+    /// The payment got in chain after it was rejected
+    #[strum(serialize = "paid_after_rejection")]
+    PaidAfterRejection,
+    /// An admin moved the rejected payment to refund
+    #[strum(serialize = "manual")]
+    Manual,
+}
+
+/// Sent in callbacks of refunded payments
+#[derive(Debug, Serialize, Clone)]
+pub struct RefundDetails<'a> {
+    pub reason: &'a Option<String>,
+    pub original_tx: OriginalTx<'a>,
+}
+
+/// Chain transaction of the rejected payment which is returned
+#[derive(Debug, Serialize, Clone)]
+pub struct OriginalTx<'a> {
+    pub commit: &'a Option<String>,
+    pub height: Option<i64>,
+}
+
+impl<'a> RefundDetails<'a> {
+    /// `None` unless the transaction is refunded
+    pub fn of(transaction: &'a Transaction) -> Option<Self> {
+        if transaction.status != TransactionStatus::Refund {
+            return None;
+        }
+        Some(RefundDetails {
+            reason: &transaction.refund_reason,
+            original_tx: OriginalTx {
+                commit: &transaction.commit,
+                height: transaction.height,
+            },
+        })
+    }
+}
+
 #[derive(Debug, Serialize, Clone)]
 pub struct Confirmation<'a> {
     pub id: &'a Uuid,
     pub token: &'a str,
     pub external_id: &'a str,
+    pub invoice_number: &'a Option<String>,
     pub merchant_id: &'a str,
     pub grin_amount: i64,
     pub amount: &'a Money,
     pub status: TransactionStatus,
     pub confirmations: i64,
+    /// Confirmations so far, only sent by verbose callbacks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_confirmations: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refund: Option<RefundDetails<'a>>,
+    pub metadata: &'a Option<serde_json::Value>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub explorer: ExplorerLinks,
+    /// Sent by the callback test, not about a real payment
+    pub test: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+impl<'a> Confirmation<'a> {
+    pub fn new(transaction: &'a Transaction, token: &'a str) -> Self {
+        Confirmation {
+            id: &transaction.id,
+            token,
+            external_id: &transaction.external_id,
+            invoice_number: &transaction.invoice_number,
+            merchant_id: &transaction.merchant_id,
+            grin_amount: transaction.grin_amount,
+            amount: &transaction.amount,
+            status: transaction.status,
+            confirmations: transaction.confirmations,
+            current_confirmations: None,
+            refund: RefundDetails::of(transaction),
+            metadata: &transaction.metadata,
+            expires_at: transaction.expires_at_utc(),
+            explorer: ExplorerLinks::of(transaction, None),
+            test: false,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub enum Currency {
     GRIN = 0,
     BTC = 1,
     EUR = 2,
     USD = 3,
+    JPY = 4,
+    GBP = 5,
+    CAD = 6,
+    AUD = 7,
+    CHF = 8,
+    CNY = 9,
 }
 
+/// All currencies we accept in payments
+pub const CURRENCIES: [Currency; 10] = [
+    Currency::GRIN,
+    Currency::BTC,
+    Currency::EUR,
+    Currency::USD,
+    Currency::JPY,
+    Currency::GBP,
+    Currency::CAD,
+    Currency::AUD,
+    Currency::CHF,
+    Currency::CNY,
+];
+
 impl Currency {
+    /// Number of minor units in one unit of currency
     pub fn precision(&self) -> i64 {
         match self {
             Currency::BTC => 100_000_000,
             Currency::GRIN => 1_000_000_000,
-            Currency::EUR | Currency::USD => 100,
+            Currency::JPY => 1,
+            Currency::EUR
+            | Currency::USD
+            | Currency::GBP
+            | Currency::CAD
+            | Currency::AUD
+            | Currency::CHF
+            | Currency::CNY => 100,
+        }
+    }
+
+    /// Number of digits after the decimal point
+    pub fn decimals(&self) -> usize {
+        match self {
+            Currency::BTC => 8,
+            Currency::GRIN => 9,
+            Currency::JPY => 0,
+            _ => 2,
         }
     }
 
@@ -195,8 +1154,31 @@ impl Currency {
             Currency::GRIN => "ツ",
             Currency::EUR => "€",
             Currency::USD => "$",
+            Currency::JPY => "¥",
+            Currency::GBP => "£",
+            Currency::CAD => "C$",
+            Currency::AUD => "A$",
+            Currency::CHF => "CHF",
+            Currency::CNY => "CN¥",
         }
     }
+
+    /// Currencies which need an exchange rate to be converted to GRIN
+    pub fn rated() -> impl Iterator<Item = Currency> {
+        CURRENCIES.iter().cloned().filter(|c| *c != Currency::GRIN)
+    }
+}
+
+impl FromStr for Currency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CURRENCIES
+            .iter()
+            .find(|c| c.to_string().eq_ignore_ascii_case(s.trim()))
+            .cloned()
+            .ok_or(format!("Unknown currency {}", s))
+    }
 }
 
 impl fmt::Display for Currency {
@@ -206,6 +1188,12 @@ impl fmt::Display for Currency {
             Currency::GRIN => s!("GRIN"),
             Currency::EUR => s!("EUR"),
             Currency::USD => s!("USD"),
+            Currency::JPY => s!("JPY"),
+            Currency::GBP => s!("GBP"),
+            Currency::CAD => s!("CAD"),
+            Currency::AUD => s!("AUD"),
+            Currency::CHF => s!("CHF"),
+            Currency::CNY => s!("CNY"),
         };
         write!(f, "{}", s)
     }
@@ -236,13 +1224,40 @@ impl Money {
         }
     }
 
-    pub fn convert_to(&self, currency: Currency, rate: f64) -> Money {
-        let amount =
-            self.amount * currency.precision() / (self.currency.precision() as f64 * rate) as i64;
-        Money {
+    /// Converts money to `currency`, `rate` is the price of one unit of
+    /// `currency` expressed in the currency of this amount.
+    /// Returns None if the rate is zero or the result doesn't fit into i64.
+    pub fn convert_to(&self, currency: Currency, rate: Decimal) -> Option<Money> {
+        let amount = self
+            .exact_conversion(currency, rate)?
+            .round_dp_with_strategy(0, CONVERSION_ROUNDING)
+            .to_i64()?;
+        Some(Money {
             amount,
             currency: currency,
-        }
+        })
+    }
+
+    /// Amount `convert_to` rounds, in the smallest unit of `currency`
+    pub fn exact_conversion(&self, currency: Currency, rate: Decimal) -> Option<Decimal> {
+        Decimal::from(self.amount)
+            .checked_mul(Decimal::from(currency.precision()))?
+            .checked_div(Decimal::from(self.currency.precision()).checked_mul(rate)?)
+    }
+
+    /// Converts money to `currency`, `price` is the price of one unit of
+    /// the currency of this amount expressed in `currency`.
+    pub fn convert_at_price(&self, currency: Currency, price: Decimal) -> Option<Money> {
+        let amount = Decimal::from(self.amount)
+            .checked_mul(price)?
+            .checked_mul(Decimal::from(currency.precision()))?
+            .checked_div(Decimal::from(self.currency.precision()))?
+            .round_dp_with_strategy(0, CONVERSION_ROUNDING)
+            .to_i64()?;
+        Some(Money {
+            amount,
+            currency: currency,
+        })
     }
 
     pub fn amount(&self) -> String {
@@ -250,12 +1265,12 @@ impl Money {
         let grins = self.amount / pr;
         let mgrins = self.amount % pr;
         match self.currency {
-            Currency::BTC => format!("{}.{:08}", grins, mgrins),
             Currency::GRIN => {
                 let short = (mgrins as f64 / 1_000_000.0).ceil() as i64;
                 format!("{}.{:03}", grins, short)
             }
-            _ => format!("{}.{:02}", grins, mgrins),
+            Currency::JPY => format!("{}", grins),
+            currency => format!("{}.{:0width$}", grins, mgrins, width = currency.decimals()),
         }
     }
 }
@@ -289,7 +1304,7 @@ impl fmt::Display for Money {
 #[table_name = "rates"]
 pub struct Rate {
     pub id: String,
-    pub rate: f64,
+    pub rate: Decimal,
     pub updated_at: NaiveDateTime,
 }
 #[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
@@ -298,11 +1313,128 @@ pub struct CurrentHeight {
     pub height: i64,
 }
 
+/// Header of a block synced from the node. `timestamp` is set when the
+/// node's API reports it, `synced_at` is when we got the block.
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Clone)]
+#[table_name = "blocks"]
+pub struct BlockHeader {
+    pub height: i64,
+    pub hash: String,
+    pub previous: String,
+    pub timestamp: Option<NaiveDateTime>,
+    pub synced_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable, Clone)]
+#[table_name = "api_requests"]
+pub struct ApiRequest {
+    pub id: Uuid,
+    pub merchant_id: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub status_code: i32,
+    pub latency_ms: i64,
+    pub error: Option<String>,
+    pub created_at: NaiveDateTime,
+    /// See `geoip`
+    pub country: Option<String>,
+    pub asn: Option<i64>,
+}
+
+/// What happened to a merchant's account, see `security_events`
+#[derive(Debug, Clone, Copy, PartialEq, Display, EnumString)]
+pub enum SecurityEventKind {
+    #[strum(serialize = "login")]
+    Login,
+    #[strum(serialize = "login_failed")]
+    LoginFailed,
+    #[strum(serialize = "repeated_failed_logins")]
+    RepeatedFailedLogins,
+    #[strum(serialize = "new_location")]
+    NewLocation,
+    #[strum(serialize = "2fa_reset")]
+    TwoFactorReset,
+    #[strum(serialize = "refund_address_added")]
+    RefundAddressAdded,
+    #[strum(serialize = "refund_address_deleted")]
+    RefundAddressDeleted,
+}
+
+impl SecurityEventKind {
+    /// Events the merchant is emailed about and shown on the dashboard
+    pub const ALERTS: [SecurityEventKind; 4] = [
+        SecurityEventKind::RepeatedFailedLogins,
+        SecurityEventKind::NewLocation,
+        SecurityEventKind::TwoFactorReset,
+        SecurityEventKind::RefundAddressAdded,
+    ];
+
+    pub fn description(self) -> &'static str {
+        match self {
+            SecurityEventKind::Login => "Login",
+            SecurityEventKind::LoginFailed => "Failed login",
+            SecurityEventKind::RepeatedFailedLogins => "Repeated failed logins to your account",
+            SecurityEventKind::NewLocation => "Login to your account from a new location",
+            SecurityEventKind::TwoFactorReset => {
+                "The two-factor authentication of your account was reset"
+            }
+            SecurityEventKind::RefundAddressAdded => {
+                "A refund address was added to your account, delete it if it wasn't you"
+            }
+            SecurityEventKind::RefundAddressDeleted => "A refund address was deleted",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Queryable, Insertable, Clone)]
+#[table_name = "security_events"]
+pub struct SecurityEvent {
+    pub id: Uuid,
+    pub merchant_id: String,
+    /// `SecurityEventKind`
+    pub kind: String,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+    /// Set for alerts once the merchant was emailed
+    pub emailed_at: Option<NaiveDateTime>,
+    /// The merchant closed the alert's banner
+    pub dismissed: bool,
+    pub country: Option<String>,
+}
+
+impl SecurityEvent {
+    pub fn new(
+        merchant_id: &str,
+        kind: SecurityEventKind,
+        ip: Option<String>,
+        country: Option<String>,
+        now: NaiveDateTime,
+    ) -> Self {
+        SecurityEvent {
+            id: Uuid::new_v4(),
+            merchant_id: merchant_id.to_owned(),
+            kind: kind.to_string(),
+            ip,
+            created_at: now,
+            emailed_at: None,
+            dismissed: false,
+            country,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        self.kind
+            .parse::<SecurityEventKind>()
+            .map(SecurityEventKind::description)
+            .unwrap_or("Security event")
+    }
+}
+
 #[cfg(test)]
-mod tests {
+pub mod tests {
 
     use crate::models::*;
-    fn create_tx() -> Transaction {
+    pub fn create_tx() -> Transaction {
         Transaction {
             id: Uuid::new_v4(),
             external_id: s!(""),
@@ -328,9 +1460,114 @@ mod tests {
             height: None,
             commit: None,
             redirect_url: Some(s!("https://store.cycle42.com")),
+            exchange_rate: None,
+            rate_locked_until: None,
+            requotes: 0,
+            metadata: None,
+            expires_at: None,
+            confirmed_by_wallet: false,
+            seen_in_pool_at: None,
+            payout_batch_id: None,
+            receipt_sent_at: None,
+            receipt_opt_in: false,
+            output_selection: None,
+            amount_tag: None,
+            invoice_number: None,
+            payer_public_key: None,
+            reported_status: None,
+            refund_reason: None,
+            slate_version: None,
+            payer_user_agent: None,
+            rate_updated_at: None,
+            payer_country: None,
+            payer_asn: None,
+            processing_until: None,
+            reminder_sent_at: None,
+            refund_address: None,
+            referrer_id: None,
+            referral_fee: None,
+            claimed_slate_id: None,
+            payer_message_unverified: false,
         }
     }
 
+    pub fn create_merchant() -> Merchant {
+        Merchant {
+            id: s!("shop"),
+            email: s!("shop@example.com"),
+            password: s!("$2y$12$hash"),
+            wallet_url: Some(s!("http://wallet")),
+            balance: 1_000_000_000,
+            created_at: Utc::now().naive_utc(),
+            token: s!("api-token"),
+            callback_url: Some(s!("https://shop/callback")),
+            token_2fa: Some(s!("totp-secret")),
+            confirmed_2fa: true,
+            second_factor: SecondFactor::Totp,
+            oidc_subject: Some(s!("oidc-subject")),
+            is_admin: false,
+            payout_callback_url: None,
+            email_logo_url: Some(s!("https://shop/logo.png")),
+            email_footer: Some(s!("Shop Ltd")),
+            email_reply_to: None,
+            timezone: s!("UTC"),
+            invoice_prefix: s!("KT"),
+            invoice_sequence: 1,
+            callback_timeout_seconds: 10,
+            callback_headers: Some(serde_json::json!({"Authorization": "secret-header"})),
+            callback_verify_tls: true,
+            telegram_chat_id: None,
+            slack_webhook_url: Some(s!("https://hooks.slack.com/secret-hook")),
+            callback_template: None,
+            slate_message_check: s!("off"),
+            verbose_callbacks: false,
+            callback_max_attempts: None,
+            callback_backoff_seconds: None,
+            callback_retry_window_seconds: None,
+            plan: s!("standard"),
+            payment_reminder_minutes: None,
+            platform_id: None,
+            referrer_id: None,
+            referral_share_bps: None,
+            pending_balance: 0,
+        }
+    }
+
+    #[test]
+    fn test_merchant_privacy() {
+        let merchant = create_merchant();
+        let json = serde_json::to_value(&merchant).unwrap();
+        for field in &[
+            "password",
+            "token",
+            "token_2fa",
+            "confirmed_2fa",
+            "second_factor",
+            "oidc_subject",
+            "is_admin",
+            "invoice_sequence",
+            "callback_headers",
+            "slack_webhook_url",
+        ] {
+            assert!(json.get(field).is_none(), "{} is serialized", field);
+        }
+        let text = json.to_string();
+        for secret in &[
+            "$2y$12$hash",
+            "api-token",
+            "totp-secret",
+            "secret-header",
+            "secret-hook",
+        ] {
+            assert!(!text.contains(secret), "{} is serialized", secret);
+        }
+
+        let profile = serde_json::to_value(&MerchantProfile::of(&merchant)).unwrap();
+        let fields: Vec<&String> = profile.as_object().unwrap().keys().collect();
+        assert_eq!(fields, vec!["footer", "id", "logo_url"]);
+        assert_eq!(profile["logo_url"], "https://shop/logo.png");
+    }
+
     fn approximately(expect: i64, real: i64) -> bool {
         let ratio = expect as f64 / real as f64;
         ratio > 0.99 && ratio < 1.01
@@ -372,6 +1609,100 @@ mod tests {
         assert!(tx.time_until_expired() == None);
     }
 
+    /// The deadline stored when a payment is created is sent to clients as
+    /// the same instant in UTC
+    #[test]
+    fn test_expires_at_utc() {
+        let now = Utc::now();
+        let mut tx = create_tx();
+        tx.amount = Money::new(1000, Currency::EUR);
+        tx.created_at = now.naive_utc();
+        tx.rate_locked_until = Some(tx.created_at + Duration::seconds(RATE_LOCK_SECONDS));
+        tx.expires_at = tx.payment_deadline();
+        let expires_at = tx.expires_at_utc().unwrap();
+        assert_eq!(
+            expires_at,
+            now + Duration::seconds(RATE_LOCK_SECONDS + REQUOTE_WINDOW_SECONDS)
+        );
+        assert_eq!(expires_at.naive_utc(), tx.expires_at.unwrap());
+    }
+
+    /// Expiry is plain UTC arithmetic, switching to or from summer time in
+    /// the server's or the merchant's zone doesn't move it
+    #[test]
+    fn test_expiration_around_dst() {
+        use crate::clock::{Clock, ManualClock};
+        use chrono::NaiveDate;
+
+        // Europe switched to summer time at 01:00 UTC on 31.03.2019 and
+        // back on 27.10.2019
+        for created_at in &[
+            NaiveDate::from_ymd(2019, 3, 31).and_hms(0, 45, 0),
+            NaiveDate::from_ymd(2019, 10, 27).and_hms(0, 45, 0),
+        ] {
+            let clock = ManualClock::new(*created_at);
+            let mut tx = create_tx();
+            tx.created_at = *created_at;
+            tx.updated_at = *created_at;
+            assert_eq!(
+                tx.time_until_expired_at(clock.now()),
+                Some(Duration::seconds(NEW_PAYMENT_TTL_SECONDS))
+            );
+            clock.advance(Duration::seconds(NEW_PAYMENT_TTL_SECONDS - 1));
+            assert!(!tx.is_expired_at(clock.now()));
+            clock.advance(Duration::seconds(2));
+            assert!(tx.is_expired_at(clock.now()));
+
+            tx.rate_locked_until = Some(*created_at + Duration::seconds(RATE_LOCK_SECONDS));
+            clock.set(*created_at + Duration::seconds(RATE_LOCK_SECONDS - 1));
+            assert!(!tx.is_rate_lock_expired_at(clock.now()));
+            clock.advance(Duration::seconds(2));
+            assert!(tx.is_rate_lock_expired_at(clock.now()));
+        }
+    }
+
+    #[test]
+    fn test_rate_lock_expiration() {
+        let mut tx = create_tx();
+        tx.amount = Money::new(1000, Currency::EUR);
+        tx.rate_locked_until = Some(Utc::now().naive_utc() + Duration::seconds(RATE_LOCK_SECONDS));
+        assert!(!tx.is_rate_lock_expired());
+        assert!(approximately(
+            tx.time_until_expired().unwrap().num_seconds(),
+            RATE_LOCK_SECONDS + REQUOTE_WINDOW_SECONDS
+        ));
+
+        tx.requotes = MAX_REQUOTES;
+        assert!(approximately(
+            tx.time_until_expired().unwrap().num_seconds(),
+            RATE_LOCK_SECONDS
+        ));
+
+        tx.rate_locked_until = Some(Utc::now().naive_utc() - Duration::seconds(1));
+        assert!(tx.is_rate_lock_expired());
+        assert!(tx.is_expired());
+    }
+
+    #[test]
+    fn test_requote_window() {
+        let mut tx = create_tx();
+        tx.amount = Money::new(1000, Currency::EUR);
+        let locked_until = Utc::now().naive_utc();
+        tx.rate_locked_until = Some(locked_until);
+        assert!(!tx.can_be_requoted_at(locked_until));
+        assert!(tx.can_be_requoted_at(locked_until + Duration::seconds(1)));
+        assert!(tx.can_be_requoted_at(locked_until + Duration::seconds(REQUOTE_WINDOW_SECONDS)));
+        assert!(
+            !tx.can_be_requoted_at(locked_until + Duration::seconds(REQUOTE_WINDOW_SECONDS + 1))
+        );
+
+        tx.requotes = MAX_REQUOTES;
+        assert!(!tx.can_be_requoted_at(locked_until + Duration::seconds(1)));
+        tx.requotes = 0;
+        tx.rate_locked_until = None;
+        assert!(!tx.can_be_requoted_at(locked_until + Duration::seconds(1)));
+    }
+
     #[test]
     fn test_money_amount() {
         let mut m = Money::new(1000, Currency::EUR);
@@ -380,6 +1711,67 @@ mod tests {
         assert_eq!(&m.amount(), "2.00000001");
         m = Money::new(2_000_000_01, Currency::GRIN);
         assert_eq!(&m.amount(), "0.201");
+        m = Money::new(1500, Currency::JPY);
+        assert_eq!(&m.amount(), "1500");
+        m = Money::new(1505, Currency::GBP);
+        assert_eq!(&m.amount(), "15.05");
+    }
+
+    #[test]
+    fn test_money_convert_to() {
+        let rate = Decimal::from_str("3.3").unwrap();
+        let grins = Money::new(1000, Currency::EUR)
+            .convert_to(Currency::GRIN, rate)
+            .unwrap();
+        assert_eq!(grins.amount, 3_030_303_031);
+
+        let rate = Decimal::from_str("0.00012345").unwrap();
+        let grins = Money::new(100_000_000_000, Currency::BTC)
+            .convert_to(Currency::GRIN, rate)
+            .unwrap();
+        assert_eq!(grins.amount, 8_100_445_524_503_848);
+
+        assert!(Money::new(1000, Currency::EUR)
+            .convert_to(Currency::GRIN, Decimal::zero())
+            .is_none());
+    }
+
+    #[test]
+    fn test_conversion() {
+        let mut tx = create_tx();
+        tx.amount = Money::new(1000, Currency::EUR);
+        tx.exchange_rate = Some(Decimal::from_str("3.3").unwrap());
+        tx.amount_tag = Some(7);
+        tx.grin_amount = 3_030_303_031 + 7;
+        let conversion = Conversion::of(&tx);
+        assert_eq!(conversion.currency, Currency::EUR);
+        assert_eq!(conversion.quoted_at, None);
+        assert_eq!(
+            conversion.exact_grin_amount.unwrap().round_dp(3),
+            Decimal::from_str("3030303030.303").unwrap()
+        );
+        assert_eq!(
+            conversion.rounding_difference.unwrap().round_dp(3),
+            Decimal::from_str("0.697").unwrap()
+        );
+        assert_eq!(conversion.grin_amount, 3_030_303_038);
+    }
+
+    #[test]
+    fn test_money_convert_at_price() {
+        let price = Decimal::from_str("0.00012345").unwrap();
+        let btc = Money::from_grin(2_500_000_000)
+            .convert_at_price(Currency::BTC, price)
+            .unwrap();
+        assert_eq!(btc.amount, 30_863);
+        assert_eq!(&btc.amount(), "0.00030863");
+    }
+
+    #[test]
+    fn test_currency_from_str() {
+        assert_eq!(Currency::from_str("btc"), Ok(Currency::BTC));
+        assert_eq!(Currency::from_str(" CHF"), Ok(Currency::CHF));
+        assert!(Currency::from_str("XYZ").is_err());
     }
 
     #[test]
@@ -392,4 +1784,89 @@ mod tests {
         assert!(tx.is_invalid_amount(1_002_000_000));
         assert!(!tx.is_invalid_amount(1_000_100_000));
     }
+
+    #[test]
+    fn test_invoice_number() {
+        assert_eq!(format_invoice_number("KT", 2019, 123), "KT-2019-000123");
+        assert_eq!(
+            format_invoice_number("SHOP", 2020, 1_234_567),
+            "SHOP-2020-1234567"
+        );
+        assert!(validate_invoice_prefix("KT").is_ok());
+        assert!(validate_invoice_prefix("").is_err());
+        assert!(validate_invoice_prefix("KT-").is_err());
+        assert!(validate_invoice_prefix(&"K".repeat(MAX_INVOICE_PREFIX_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn test_manual_transitions() {
+        use TransactionStatus::*;
+        assert!(Pending.manual_transitions().contains(&Confirmed));
+        assert!(InChain.manual_transitions().contains(&Confirmed));
+        assert!(!Confirmed.manual_transitions().contains(&Pending));
+        assert!(!New.manual_transitions().contains(&Confirmed));
+        assert!(Refund.manual_transitions().is_empty());
+    }
+
+    #[test]
+    fn test_new_note() {
+        let tx = create_tx();
+        let note =
+            TransactionNote::new(tx.id, "merchant", "  customer says they sent twice\n").unwrap();
+        assert_eq!(note.body, "customer says they sent twice");
+        assert!(TransactionNote::new(tx.id, "merchant", " \n").is_err());
+        let long = "ツ".repeat(MAX_NOTE_LENGTH);
+        assert!(TransactionNote::new(tx.id, "merchant", &long).is_ok());
+        assert!(TransactionNote::new(tx.id, "merchant", &(long + "!")).is_err());
+    }
+
+    #[test]
+    fn test_fill_payment_message() {
+        let amount = Money::from_grin(1_000_000);
+        assert_eq!(
+            fill_payment_message(
+                "Order {order_id} at {merchant}: {amount}",
+                "42",
+                &amount,
+                "shop"
+            ),
+            format!("Order 42 at shop: {}", amount)
+        );
+        assert_eq!(
+            fill_payment_message(
+                "{order_id}{ {unknown} {order_id",
+                "{merchant}",
+                &amount,
+                "shop"
+            ),
+            "{merchant}{ {unknown} {order_id"
+        );
+        assert!(validate_payment_message(&"a".repeat(MAX_MESSAGE_LENGTH)).is_ok());
+        assert!(validate_payment_message(&"a".repeat(MAX_MESSAGE_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn test_slate_message_check() {
+        let mut tx = create_tx();
+        tx.message = s!("Order 42");
+        let with_id = format!("paying {}", tx.id.to_string().to_uppercase());
+        assert!(SlateMessageCheck::Off.check(&tx, None).is_ok());
+        assert!(SlateMessageCheck::Exact
+            .check(&tx, Some(" Order 42\n"))
+            .is_ok());
+        assert!(SlateMessageCheck::Exact
+            .check(&tx, Some("Order 43"))
+            .is_err());
+        assert!(SlateMessageCheck::Exact.check(&tx, None).is_err());
+        assert!(SlateMessageCheck::ContainsId
+            .check(&tx, Some(&with_id))
+            .is_ok());
+        assert!(SlateMessageCheck::ContainsId
+            .check(&tx, Some("Order 42"))
+            .is_err());
+        assert_eq!(
+            "contains_id".parse::<SlateMessageCheck>().unwrap(),
+            SlateMessageCheck::ContainsId
+        );
+    }
 }
@@ -1,77 +1,444 @@
 use crate::clients::PlainHttpAuth;
+use crate::compat::{self, Future01CompatExt};
 use crate::errors::Error;
+use crate::redact::{self, Credentials};
 use actix::{Actor, Addr};
-use actix_web::client::{self, ClientConnector};
+use actix_web::client::{self, ClientConnector, ClientRequest, ClientResponse};
+use actix_web::http::StatusCode;
 use actix_web::HttpMessage;
-use futures::Future;
+use chrono::{DateTime, Utc};
+use futures::future::{ok, Future};
 use log::{debug, error};
-use serde::Deserialize;
-use serde_json::from_slice;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, json, Value};
+use std::fmt;
 use std::str::from_utf8;
 use std::time::Duration;
 
+const CHAIN_TIP: &'static str = "v1/chain";
 const CHAIN_OUTPUTS_BY_HEIGHT: &'static str = "v1/chain/outputs/byheight";
+const CHAIN_KERNELS: &'static str = "v1/chain/kernels";
+const FOREIGN_RPC: &'static str = "v2/foreign";
+/// Largest response we are ready to read from the node
+const BODY_LIMIT: usize = 10 * 1024 * 1024;
+/// Blocks fetched in one request, keeps a response of full blocks well
+/// below `BODY_LIMIT`
+const BLOCKS_PER_REQUEST: i64 = 5;
 
+/// A block which came in the node's response, or why it couldn't be
+/// decoded
+pub type BlockResult = Result<Block, Error>;
+
+/// Node API used to follow the chain, either the v1 REST API or
+/// the v2 JSON-RPC foreign API which newer nodes provide instead
+pub trait NodeClient: Send {
+    fn tip(&self) -> Box<dyn Future<Item = Tip, Error = Error>>;
+
+    /// Blocks with their outputs from `start` to `end` height inclusive,
+    /// fetched `BLOCKS_PER_REQUEST` at a time. A block which can't be
+    /// decoded is returned as an error in its place, the rest of the
+    /// range is still usable. Fetching stops after a request with such
+    /// a block.
+    fn blocks(
+        &self,
+        start: i64,
+        end: i64,
+    ) -> Box<dyn Future<Item = Vec<BlockResult>, Error = Error>>;
+
+    /// Looks up a kernel by its excess, `None` if it's not in the chain yet
+    fn kernel(&self, excess: &str) -> Box<dyn Future<Item = Option<LocatedKernel>, Error = Error>>;
+
+    /// Transactions in the node's pool waiting for a block. Stem phase
+    /// transactions are not visible until they get fluffed.
+    fn pool_transactions(&self) -> Box<dyn Future<Item = Vec<PoolTransaction>, Error = Error>>;
+
+    fn box_clone(&self) -> Box<dyn NodeClient>;
+}
+
+impl Clone for Box<dyn NodeClient> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// Creates a client for the node API version from NODE_API_VERSION,
+/// `v1` is used when it's not set
+pub fn connect(
+    api_version: &str,
+    url: &str,
+    username: &str,
+    password: &str,
+) -> Result<Box<dyn NodeClient>, Error> {
+    match api_version {
+        "" | "v1" => Ok(Box::new(Node::new(url, username, password))),
+        "v2" => Ok(Box::new(NodeRpc::new(url, username, password))),
+        _ => Err(Error::General(format!(
+            "Unknown node API version {}, expected v1 or v2",
+            api_version
+        ))),
+    }
+}
+
+fn connector() -> Addr<ClientConnector> {
+    ClientConnector::default()
+        .conn_lifetime(Duration::from_secs(300))
+        .conn_keep_alive(Duration::from_secs(300))
+        .start()
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+    from_slice(bytes).map_err(|e| {
+        error!(
+            "Cannot decode json {:?}:\n with error {} ",
+            from_utf8(bytes),
+            e
+        );
+        Error::NodeAPIError(format!("Cannot decode json {}", e))
+    })
+}
+
+/// Decodes blocks one by one, so a malformed block doesn't fail the others
+fn decode_blocks(values: Vec<Value>) -> Vec<BlockResult> {
+    values
+        .into_iter()
+        .map(|value| {
+            let height = value
+                .pointer("/header/height")
+                .and_then(Value::as_u64)
+                .map(|height| height.to_string())
+                .unwrap_or_else(|| s!("of unknown height"));
+            serde_json::from_value(value)
+                .map_err(|e| Error::NodeAPIError(format!("Cannot decode block {}: {}", height, e)))
+        })
+        .collect()
+}
+
+/// Fetches `start..=end` in requests of `BLOCKS_PER_REQUEST` blocks, one
+/// after another
+fn fetch_paged<F, R>(
+    start: i64,
+    end: i64,
+    fetch: F,
+) -> Box<dyn Future<Item = Vec<BlockResult>, Error = Error>>
+where
+    F: Fn(i64, i64) -> R + 'static,
+    R: std::future::Future<Output = Result<Vec<BlockResult>, Error>> + 'static,
+{
+    Box::new(compat::to_01(async move {
+        let mut blocks: Vec<BlockResult> = Vec::new();
+        let mut page_start = start;
+        while page_start <= end && !blocks.iter().any(|block| block.is_err()) {
+            let page_end = (page_start + BLOCKS_PER_REQUEST - 1).min(end);
+            blocks.extend(fetch(page_start, page_end).await?);
+            page_start += BLOCKS_PER_REQUEST;
+        }
+        Ok(blocks)
+    }))
+}
+
+/// Sends the request, fails unless the node answered with a success status
+async fn send(request: ClientRequest) -> Result<ClientResponse, Error> {
+    let resp = request
+        .send()
+        .compat()
+        .await
+        .map_err(|e| Error::NodeAPIError(s!(e)))?;
+    if !resp.status().is_success() {
+        return Err(Error::NodeAPIError(format!(
+            "Error status: {:?}",
+            redact::Response(&resp)
+        )));
+    }
+    Ok(resp)
+}
+
+async fn read_body<T: DeserializeOwned>(resp: ClientResponse) -> Result<T, Error> {
+    let bytes = resp
+        .body()
+        .limit(BODY_LIMIT)
+        .compat()
+        .await
+        .map_err(|e| Error::NodeAPIError(s!(e)))?;
+    decode(&bytes)
+}
+
+/// Client for the v1 REST API
 #[derive(Clone)]
 pub struct Node {
     conn: Addr<ClientConnector>,
-    username: String,
-    password: String,
+    credentials: Credentials,
     url: String,
 }
 
+impl fmt::Debug for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Node")
+            .field("url", &self.url)
+            .field("credentials", &self.credentials)
+            .finish()
+    }
+}
+
 impl Node {
     pub fn new(url: &str, username: &str, password: &str) -> Self {
-        let connector = ClientConnector::default()
-            .conn_lifetime(Duration::from_secs(300))
-            .conn_keep_alive(Duration::from_secs(300));
         Node {
             url: url.trim_end_matches('/').to_owned(),
-            username: username.to_owned(),
-            password: password.to_owned(),
-            conn: connector.start(),
+            credentials: Credentials::new(username, password),
+            conn: connector(),
         }
     }
 
-    pub fn blocks(&self, start: i64, end: i64) -> impl Future<Item = Vec<Block>, Error = Error> {
-        let url = format!(
-            "{}/{}?start_height={}&end_height={}",
-            self.url, CHAIN_OUTPUTS_BY_HEIGHT, start, end
-        );
-        debug!("Get latest blocks from node {}", url);
-        client::get(&url) // <- Create request builder
-            .auth(&self.username, &self.password)
+    fn get(&self, url: &str) -> ClientRequest {
+        debug!("Get from node {}", url);
+        client::get(url)
+            .with_connector(self.conn.clone())
+            .auth(&self.credentials)
             .finish()
             .unwrap()
-            .send() // <- Send http request
-            .map_err(|e| Error::NodeAPIError(s!(e)))
-            .and_then(|resp| {
-                if !resp.status().is_success() {
-                    Err(Error::NodeAPIError(format!("Error status: {:?}", resp)))
-                } else {
-                    Ok(resp)
-                }
-            })
-            .and_then(|resp| {
-                // <- server http response
-                resp.body()
-                    .limit(10 * 1024 * 1024)
-                    .map_err(|e| Error::NodeAPIError(s!(e)))
-                    .and_then(move |bytes| {
-                        let blocks: Vec<Block> = from_slice(&bytes).map_err(|e| {
-                            error!(
-                                "Cannot decode json {:?}:\n with error {} ",
-                                from_utf8(&bytes),
-                                e
-                            );
-                            Error::NodeAPIError(format!("Cannot decode json {}", e))
-                        })?;
-                        Ok(blocks)
-                    })
+    }
+}
+
+impl NodeClient for Node {
+    fn tip(&self) -> Box<dyn Future<Item = Tip, Error = Error>> {
+        let request = self.get(&format!("{}/{}", self.url, CHAIN_TIP));
+        Box::new(compat::to_01(async move {
+            read_body(send(request).await?).await
+        }))
+    }
+
+    fn blocks(
+        &self,
+        start: i64,
+        end: i64,
+    ) -> Box<dyn Future<Item = Vec<BlockResult>, Error = Error>> {
+        let node = self.clone();
+        fetch_paged(start, end, move |start, end| {
+            let url = format!(
+                "{}/{}?start_height={}&end_height={}",
+                node.url, CHAIN_OUTPUTS_BY_HEIGHT, start, end
+            );
+            let request = node.get(&url);
+            async move { Ok(decode_blocks(read_body(send(request).await?).await?)) }
+        })
+    }
+
+    fn kernel(&self, excess: &str) -> Box<dyn Future<Item = Option<LocatedKernel>, Error = Error>> {
+        let url = format!("{}/{}/{}", self.url, CHAIN_KERNELS, excess);
+        let request = self.get(&url);
+        Box::new(compat::to_01(async move {
+            let resp = request
+                .send()
+                .compat()
+                .await
+                .map_err(|e| Error::NodeAPIError(s!(e)))?;
+            match resp.status() {
+                StatusCode::NOT_FOUND => Ok(None),
+                status if status.is_success() => Ok(Some(read_body(resp).await?)),
+                _ => Err(Error::NodeAPIError(format!("Error status: {:?}", resp))),
+            }
+        }))
+    }
+
+    /// v1 API reports only the pool size, so nothing can be matched
+    fn pool_transactions(&self) -> Box<dyn Future<Item = Vec<PoolTransaction>, Error = Error>> {
+        Box::new(ok(Vec::new()))
+    }
+
+    fn box_clone(&self) -> Box<dyn NodeClient> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct RpcRequest<'a> {
+    jsonrpc: &'static str,
+    method: &'a str,
+    params: Value,
+    id: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct RpcResponse<T> {
+    result: Option<RpcResult<T>>,
+    error: Option<RpcError>,
+}
+
+/// Foreign API methods return Rust's `Result` serialized as is
+#[derive(Deserialize, Debug)]
+enum RpcResult<T> {
+    Ok(T),
+    Err(Value),
+}
+
+#[derive(Deserialize, Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+/// Blocks are decoded with `decode_blocks`
+#[derive(Deserialize, Debug)]
+struct BlockListing {
+    blocks: Vec<Value>,
+}
+
+/// Client for the v2 JSON-RPC foreign API
+#[derive(Clone)]
+pub struct NodeRpc {
+    conn: Addr<ClientConnector>,
+    credentials: Credentials,
+    url: String,
+}
+
+impl fmt::Debug for NodeRpc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NodeRpc")
+            .field("url", &self.url)
+            .field("credentials", &self.credentials)
+            .finish()
+    }
+}
+
+impl NodeRpc {
+    pub fn new(url: &str, username: &str, password: &str) -> Self {
+        NodeRpc {
+            url: format!("{}/{}", url.trim_end_matches('/'), FOREIGN_RPC),
+            credentials: Credentials::new(username, password),
+            conn: connector(),
+        }
+    }
+
+    /// Calls a foreign API method, the inner result holds the error
+    /// returned by the method itself
+    fn call<T: DeserializeOwned + 'static>(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> impl std::future::Future<Output = Result<Result<T, Value>, Error>> {
+        debug!("Call node method {} {}", method, params);
+        let request = client::post(&self.url)
+            .with_connector(self.conn.clone())
+            .auth(&self.credentials)
+            .json(RpcRequest {
+                jsonrpc: "2.0",
+                method,
+                params,
+                id: 1,
             })
+            .unwrap();
+        async move {
+            let resp: RpcResponse<T> = read_body(send(request).await?).await?;
+            match (resp.result, resp.error) {
+                (Some(RpcResult::Ok(result)), _) => Ok(Ok(result)),
+                (Some(RpcResult::Err(e)), _) => Ok(Err(e)),
+                (None, Some(e)) => Err(Error::NodeAPIError(format!(
+                    "JSON-RPC error {}: {}",
+                    e.code, e.message
+                ))),
+                (None, None) => Err(Error::NodeAPIError(s!("empty JSON-RPC response"))),
+            }
+        }
+    }
+}
+
+impl NodeClient for NodeRpc {
+    fn tip(&self) -> Box<dyn Future<Item = Tip, Error = Error>> {
+        let call = self.call("get_tip", json!([]));
+        Box::new(compat::to_01(async move {
+            call.await?.map_err(|e| Error::NodeAPIError(e.to_string()))
+        }))
+    }
+
+    fn blocks(
+        &self,
+        start: i64,
+        end: i64,
+    ) -> Box<dyn Future<Item = Vec<BlockResult>, Error = Error>> {
+        let node = self.clone();
+        fetch_paged(start, end, move |start, end| {
+            let max = end - start + 1;
+            let call = node.call("get_blocks", json!([start, end, max, false]));
+            async move {
+                let listing: BlockListing = call
+                    .await?
+                    .map_err(|e| Error::NodeAPIError(e.to_string()))?;
+                Ok(decode_blocks(listing.blocks))
+            }
+        })
+    }
+
+    fn kernel(&self, excess: &str) -> Box<dyn Future<Item = Option<LocatedKernel>, Error = Error>> {
+        let call = self.call("get_kernel", json!([excess, null, null]));
+        Box::new(compat::to_01(async move {
+            match call.await? {
+                Ok(kernel) => Ok(Some(kernel)),
+                Err(ref e) if e.to_string().contains("NotFound") => Ok(None),
+                Err(e) => Err(Error::NodeAPIError(e.to_string())),
+            }
+        }))
+    }
+
+    fn pool_transactions(&self) -> Box<dyn Future<Item = Vec<PoolTransaction>, Error = Error>> {
+        let call = self.call("get_unconfirmed_transactions", json!([]));
+        Box::new(compat::to_01(async move {
+            let entries: Vec<PoolEntry> = call
+                .await?
+                .map_err(|e| Error::NodeAPIError(e.to_string()))?;
+            Ok(entries.into_iter().map(|entry| entry.tx).collect())
+        }))
+    }
+
+    fn box_clone(&self) -> Box<dyn NodeClient> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Tip {
+    pub height: u64,
+    pub last_block_pushed: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LocatedKernel {
+    pub tx_kernel: Kernel,
+    pub height: u64,
+    pub mmr_index: u64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Kernel {
+    pub excess: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct PoolEntry {
+    tx: PoolTransaction,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PoolTransaction {
+    pub body: TransactionBody,
+}
+
+impl PoolTransaction {
+    pub fn commits(&self) -> impl Iterator<Item = &str> {
+        self.body.outputs.iter().map(|o| o.commit.as_str())
     }
 }
 
+#[derive(Deserialize, Debug)]
+pub struct TransactionBody {
+    pub outputs: Vec<TransactionOutput>,
+    pub kernels: Vec<Kernel>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TransactionOutput {
+    pub commit: String,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Block {
     pub header: Header,
@@ -81,6 +448,11 @@ pub struct Block {
 #[derive(Deserialize, Debug)]
 pub struct Header {
     pub height: u64,
+    pub hash: String,
+    pub previous: String,
+    /// Not reported by every API version
+    #[serde(default)]
+    pub timestamp: Option<DateTime<Utc>>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -381,4 +753,68 @@ mod tests {
             Err(_) => assert!(false),
         }
     }
+
+    #[test]
+    fn malformed_block_test() {
+        let mut values: Vec<Value> = from_slice(SAMPLE2.as_bytes()).unwrap();
+        values[1]["outputs"] = json!("not a list");
+        let blocks = decode_blocks(values);
+        assert_eq!(blocks.len(), 11);
+        assert!(blocks[0].is_ok());
+        assert!(blocks[1].is_err());
+        assert!(blocks[2].is_ok());
+    }
+
+    #[test]
+    fn rpc_blocks_load_test() {
+        let resp = format!(
+            r#"{{"id": 1, "jsonrpc": "2.0", "result": {{"Ok": {{"last_retrieved_height": 85025, "blocks": {}}}}}}}"#,
+            SAMPLE2
+        );
+        match from_slice::<RpcResponse<BlockListing>>(resp.as_bytes()) {
+            Ok(RpcResponse {
+                result: Some(RpcResult::Ok(listing)),
+                ..
+            }) => assert_eq!(listing.blocks.len(), 11),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn rpc_error_load_test() {
+        let resp = r#"{"id": 1, "jsonrpc": "2.0", "result": {"Err": {"NotFound": "kernel"}}}"#;
+        match from_slice::<RpcResponse<LocatedKernel>>(resp.as_bytes()) {
+            Ok(RpcResponse {
+                result: Some(RpcResult::Err(e)),
+                ..
+            }) => assert!(e.to_string().contains("NotFound")),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn rpc_pool_load_test() {
+        let resp = r#"{"id": 1, "jsonrpc": "2.0", "result": {"Ok": [{
+            "src": "Broadcast",
+            "tx_at": "2019-06-14T10:12:31.224454Z",
+            "tx": {
+                "offset": "d202964900000000d302964900000000d402964900000000d502964900000000",
+                "body": {
+                    "inputs": [{"features": "Plain", "commit": "087df32304c5d4ae8b2af0bc31e700019d722910ef87dd4eec3197b80b207e3045"}],
+                    "outputs": [{"features": "Plain", "commit": "099b48cfb1f80a2347dc89818449e68e76a3c6817a532a8e9ef2b4a5ccf4363850", "proof": "29701ceae262cac77b79b868c883a292e61e6de8192b868edcd1300b0973d91396b156ace6bd673402a303de10ddd8a5e6b7f17ba6557a574a672bd04cc273ab04ed8e2ca80bac483345c0ec843f521814ce1301ec9adc38956a12b4d948acce71295a4f52bcdeb8a1c9f2d6b2da5d731262a5e9c0276ef904df9ef8d48001420cd59f75a2f1ae5c7a1c7c6b9f140e7613e52ef9e249f29f9340b7efb80699e460164324616f98fd4cde3db52497c919e95222fffeacb7e65deca7e368a80ce713c19de7da5369726228ee336f5bd494538c12ccbffeb1a57de6f8b4b6ca0d3d7eb6e8d7d1ac6d47a7ec1f50b7cea1e8b04a1c8bd0d6a9f2fb4c5b4e8d0e5b3a8e9e5f2b1a6f7b8f2a8b4c27def1fc3945f0c1f4c8a04ee6c7be3e8b7c4b8d6e1c1c5e5e4b1c2e4d"}],
+                    "kernels": [{"features": "Plain", "fee": 7000000, "lock_height": 0, "excess": "08d09187cb93cf5d6b97b28e8ca529912bf35ec8773d3e9af9b3c174a270dc7f05", "excess_sig": "66074d25a751c4743342c90ad8ead9454daa00d9b9aed29bca321036d16c4b4da1e9c6ebd7e8f8f13a4e07ef3a2e4ec72e16fc8f1c8e0d1d0d5a1da8c4c1c6b4"}]
+                }
+            }
+        }]}}"#;
+        match from_slice::<RpcResponse<Vec<PoolEntry>>>(resp.as_bytes()) {
+            Ok(RpcResponse {
+                result: Some(RpcResult::Ok(entries)),
+                ..
+            }) => assert_eq!(
+                entries[0].tx.commits().collect::<Vec<_>>(),
+                vec!["099b48cfb1f80a2347dc89818449e68e76a3c6817a532a8e9ef2b4a5ccf4363850"]
+            ),
+            _ => assert!(false),
+        }
+    }
 }
@@ -0,0 +1,363 @@
+//! OpenID Connect relying party used for SSO into the merchant dashboard.
+//!
+//! Only the authorization code flow is implemented. The id token is received
+//! straight from the token endpoint over TLS, so per OpenID Connect Core
+//! 3.1.3.7 its signature is not checked, only the claims are.
+
+use crate::errors::Error;
+use crate::redact::{self, Secret};
+use actix_web::client;
+use actix_web::http::header;
+use actix_web::HttpMessage;
+use chrono::Utc;
+use data_encoding::BASE64URL_NOPAD;
+use futures::future::{result, Future};
+use log::{debug, error};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::from_slice;
+use std::env;
+use std::str::from_utf8;
+
+const DISCOVERY_PATH: &str = ".well-known/openid-configuration";
+const CALLBACK_PATH: &str = "auth/oidc/callback";
+const SCOPE: &str = "openid email";
+
+/// Session key where state and nonce are stored until the provider
+/// redirects back to us
+pub const SESSION_KEY: &str = "oidc";
+
+/// Allowed difference between our clock and the provider's one
+const CLOCK_SKEW_SECS: i64 = 60;
+
+pub fn random_token() -> String {
+    BASE64URL_NOPAD.encode(&thread_rng().gen::<[u8; 32]>())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PendingLogin {
+    pub state: String,
+    pub nonce: String,
+}
+
+impl PendingLogin {
+    pub fn new() -> Self {
+        PendingLogin {
+            state: random_token(),
+            nonce: random_token(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    Single(String),
+    Many(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, client_id: &str) -> bool {
+        match self {
+            Audience::Single(aud) => aud == client_id,
+            Audience::Many(auds) => auds.iter().any(|aud| aud == client_id),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    exp: i64,
+    nonce: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct AuthorizationRequest<'a> {
+    response_type: &'static str,
+    client_id: &'a str,
+    redirect_uri: &'a str,
+    scope: &'static str,
+    state: &'a str,
+    nonce: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+struct TokenRequest<'a> {
+    grant_type: &'static str,
+    code: &'a str,
+    redirect_uri: &'a str,
+    client_id: &'a str,
+    client_secret: &'a str,
+}
+
+#[derive(Debug, Clone)]
+pub struct OidcClient {
+    issuer: String,
+    client_id: String,
+    client_secret: Secret<String>,
+    redirect_uri: String,
+}
+
+impl OidcClient {
+    pub fn new(issuer: &str, client_id: &str, client_secret: &str, domain: &str) -> Self {
+        OidcClient {
+            issuer: issuer.trim_end_matches('/').to_owned(),
+            client_id: client_id.to_owned(),
+            client_secret: Secret::new(client_secret.to_owned()),
+            redirect_uri: format!("{}/{}", domain.trim_end_matches('/'), CALLBACK_PATH),
+        }
+    }
+
+    /// SSO is enabled only when OIDC_ISSUER, OIDC_CLIENT_ID and
+    /// OIDC_CLIENT_SECRET are all set
+    pub fn from_env() -> Option<Self> {
+        let var = |name| env::var(name).ok().filter(|v: &String| !v.is_empty());
+        let issuer = var("OIDC_ISSUER")?;
+        let client_id = var("OIDC_CLIENT_ID")?;
+        let client_secret = var("OIDC_CLIENT_SECRET")?;
+        let domain = var("DOMAIN")?;
+        Some(OidcClient::new(
+            &issuer,
+            &client_id,
+            &client_secret,
+            &domain,
+        ))
+    }
+
+    pub fn discover(&self) -> impl Future<Item = ProviderMetadata, Error = Error> {
+        let url = format!("{}/{}", self.issuer, DISCOVERY_PATH);
+        debug!("Get OpenID provider configuration {}", url);
+        client::get(&url)
+            .finish()
+            .unwrap()
+            .send()
+            .map_err(|e| Error::Oidc(s!(e)))
+            .and_then(|resp| {
+                if !resp.status().is_success() {
+                    Err(Error::Oidc(format!(
+                        "Error status: {:?}",
+                        redact::Response(&resp)
+                    )))
+                } else {
+                    Ok(resp)
+                }
+            })
+            .and_then(|resp| {
+                resp.body()
+                    .map_err(|e| Error::Oidc(s!(e)))
+                    .and_then(move |bytes| {
+                        let metadata: ProviderMetadata = from_slice(&bytes).map_err(|e| {
+                            error!(
+                                "Cannot decode json {:?}:\n with error {} ",
+                                from_utf8(&bytes),
+                                e
+                            );
+                            Error::Oidc(format!("Cannot decode json {}", e))
+                        })?;
+                        Ok(metadata)
+                    })
+            })
+    }
+
+    pub fn authorization_url(
+        &self,
+        metadata: &ProviderMetadata,
+        pending: &PendingLogin,
+    ) -> Result<String, Error> {
+        let query = serde_urlencoded::to_string(AuthorizationRequest {
+            response_type: "code",
+            client_id: &self.client_id,
+            redirect_uri: &self.redirect_uri,
+            scope: SCOPE,
+            state: &pending.state,
+            nonce: &pending.nonce,
+        })
+        .map_err(|e| Error::Oidc(s!(e)))?;
+        let separator = if metadata.authorization_endpoint.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        Ok(format!(
+            "{}{}{}",
+            metadata.authorization_endpoint, separator, query
+        ))
+    }
+
+    /// Exchanges authorization code for an id token and returns
+    /// the subject it was issued for
+    pub fn authenticate(
+        &self,
+        metadata: &ProviderMetadata,
+        code: &str,
+        nonce: String,
+    ) -> impl Future<Item = String, Error = Error> {
+        let oidc = self.clone();
+        let issuer = metadata.issuer.clone();
+        let body = serde_urlencoded::to_string(TokenRequest {
+            grant_type: "authorization_code",
+            code,
+            redirect_uri: &self.redirect_uri,
+            client_id: &self.client_id,
+            client_secret: self.client_secret.expose(),
+        })
+        .map_err(|e| Error::Oidc(s!(e)));
+        result(body)
+            .and_then({
+                let token_endpoint = metadata.token_endpoint.clone();
+                move |body| {
+                    client::post(&token_endpoint)
+                        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                        .body(body)
+                        .unwrap()
+                        .send()
+                        .map_err(|e| Error::Oidc(s!(e)))
+                }
+            })
+            .and_then(|resp| {
+                if !resp.status().is_success() {
+                    Err(Error::Oidc(format!(
+                        "Error status: {:?}",
+                        redact::Response(&resp)
+                    )))
+                } else {
+                    Ok(resp)
+                }
+            })
+            .and_then(move |resp| {
+                resp.body()
+                    .map_err(|e| Error::Oidc(s!(e)))
+                    .and_then(move |bytes| {
+                        let token: TokenResponse = from_slice(&bytes)
+                            .map_err(|e| Error::Oidc(format!("Cannot decode json {}", e)))?;
+                        oidc.verify_id_token(
+                            &token.id_token,
+                            &issuer,
+                            &nonce,
+                            Utc::now().timestamp(),
+                        )
+                    })
+            })
+    }
+
+    fn verify_id_token(
+        &self,
+        id_token: &str,
+        issuer: &str,
+        nonce: &str,
+        now: i64,
+    ) -> Result<String, Error> {
+        let payload = id_token
+            .split('.')
+            .nth(1)
+            .ok_or(Error::Oidc(s!("malformed id token")))?;
+        let payload = BASE64URL_NOPAD
+            .decode(payload.trim_end_matches('=').as_bytes())
+            .map_err(|e| Error::Oidc(format!("malformed id token: {}", e)))?;
+        let claims: IdTokenClaims =
+            from_slice(&payload).map_err(|e| Error::Oidc(format!("malformed id token: {}", e)))?;
+        if claims.iss.trim_end_matches('/') != issuer.trim_end_matches('/') {
+            return Err(Error::Oidc(format!("unexpected issuer {}", claims.iss)));
+        }
+        if !claims.aud.contains(&self.client_id) {
+            return Err(Error::Oidc(s!("id token is issued for another client")));
+        }
+        if claims.exp + CLOCK_SKEW_SECS < now {
+            return Err(Error::Oidc(s!("id token expired")));
+        }
+        if claims.nonce.as_ref().map(|n| n.as_str()) != Some(nonce) {
+            return Err(Error::Oidc(s!("nonce mismatch")));
+        }
+        Ok(claims.sub)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ISSUER: &str = "https://accounts.example.com";
+
+    fn oidc() -> OidcClient {
+        OidcClient::new(ISSUER, "knockturn", "secret", "http://domain.com:3000/")
+    }
+
+    fn id_token(claims: serde_json::Value) -> String {
+        format!(
+            "{}.{}.signature",
+            BASE64URL_NOPAD.encode(br#"{"alg":"RS256"}"#),
+            BASE64URL_NOPAD.encode(claims.to_string().as_bytes())
+        )
+    }
+
+    #[test]
+    fn test_authorization_url() {
+        let metadata = ProviderMetadata {
+            issuer: ISSUER.to_owned(),
+            authorization_endpoint: format!("{}/auth", ISSUER),
+            token_endpoint: format!("{}/token", ISSUER),
+        };
+        let pending = PendingLogin {
+            state: s!("state"),
+            nonce: s!("nonce"),
+        };
+        assert_eq!(
+            oidc().authorization_url(&metadata, &pending).unwrap(),
+            "https://accounts.example.com/auth?response_type=code&client_id=knockturn\
+             &redirect_uri=http%3A%2F%2Fdomain.com%3A3000%2Fauth%2Foidc%2Fcallback\
+             &scope=openid+email&state=state&nonce=nonce"
+        );
+    }
+
+    #[test]
+    fn test_verify_id_token() {
+        let token = id_token(serde_json::json!({
+            "iss": ISSUER,
+            "sub": "1234",
+            "aud": ["other", "knockturn"],
+            "exp": 1000,
+            "nonce": "nonce",
+        }));
+        assert_eq!(
+            oidc()
+                .verify_id_token(&token, ISSUER, "nonce", 900)
+                .unwrap(),
+            "1234"
+        );
+        assert!(oidc()
+            .verify_id_token(&token, ISSUER, "another nonce", 900)
+            .is_err());
+        assert!(oidc()
+            .verify_id_token(&token, "https://evil.com", "nonce", 900)
+            .is_err());
+        assert!(oidc()
+            .verify_id_token(&token, ISSUER, "nonce", 2000)
+            .is_err());
+
+        let token = id_token(serde_json::json!({
+            "iss": ISSUER,
+            "sub": "1234",
+            "aud": "other",
+            "exp": 1000,
+            "nonce": "nonce",
+        }));
+        assert!(oidc()
+            .verify_id_token(&token, ISSUER, "nonce", 900)
+            .is_err());
+    }
+}
@@ -0,0 +1,326 @@
+//! Typestate of payments.
+//!
+//! A `Payment<S>` is a payment known to be in state `S`, it's only made by
+//! `Payment::load` which checks the status. Every status change of a
+//! payment goes through a `Transition<From, To>`, which only the methods of
+//! the payment in the `From` state make, so a transition the state machine
+//! doesn't allow doesn't compile. Status changing DB messages take one, the
+//! bulk ones (expired payments, autoconfirmation, synced blocks) and admin
+//! transitions make them from the payments they load. The DB still updates
+//! only rows in the `From` status, a payment which moved on since it was
+//! loaded isn't changed again.
+//!
+//! `TRANSITIONS` describes the same transitions for integrators, it's
+//! served by `GET /meta/payment-states` and has to be kept in line with
+//! the methods below.
+
+use crate::errors::Error;
+use crate::models::{Transaction, TransactionStatus, TransactionType};
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use uuid::Uuid;
+
+pub trait State: Send + 'static {
+    const STATUS: TransactionStatus;
+}
+
+macro_rules! states {
+    ($($state:ident),*) => {
+        $(
+            #[derive(Debug)]
+            pub enum $state {}
+
+            impl State for $state {
+                const STATUS: TransactionStatus = TransactionStatus::$state;
+            }
+        )*
+    };
+}
+
+states!(New, Pending, InChain, Confirmed, Rejected, Refund);
+
+pub struct Payment<S: State> {
+    transaction: Transaction,
+    state: PhantomData<S>,
+}
+
+impl<S: State> Payment<S> {
+    /// Fails unless `transaction` is a payment in state `S`
+    pub fn load(transaction: Transaction) -> Result<Self, Error> {
+        if transaction.transaction_type != TransactionType::Payment {
+            return Err(Error::InvalidEntity(format!(
+                "transaction {} is not a payment",
+                transaction.id
+            )));
+        }
+        if transaction.status != S::STATUS {
+            return Err(Error::WrongTransactionStatus(s!(transaction.status)));
+        }
+        Ok(Payment {
+            transaction,
+            state: PhantomData,
+        })
+    }
+
+    pub fn into_inner(self) -> Transaction {
+        self.transaction
+    }
+
+    fn transition<T: State>(&self) -> Transition<S, T> {
+        Transition {
+            transaction_id: self.transaction.id,
+            states: PhantomData,
+        }
+    }
+}
+
+impl Payment<New> {
+    /// The buyer's wallet sent the slate
+    pub fn make_pending(&self) -> Transition<New, Pending> {
+        self.transition()
+    }
+
+    pub fn reject(&self) -> Transition<New, Rejected> {
+        self.transition()
+    }
+}
+
+impl Payment<Pending> {
+    pub fn seen_in_chain(&self) -> Transition<Pending, InChain> {
+        self.transition()
+    }
+
+    /// An admin confirmed the payment, e.g. one verifiably in chain
+    pub fn confirm_manually(&self) -> Transition<Pending, Confirmed> {
+        self.transition()
+    }
+
+    /// The wallet saw the output, the payment stays in chain until it has
+    /// enough confirmations
+    pub fn confirm_by_wallet(&self) -> Transition<Pending, Confirmed> {
+        self.transition()
+    }
+
+    pub fn reject(&self) -> Transition<Pending, Rejected> {
+        self.transition()
+    }
+}
+
+impl Payment<InChain> {
+    pub fn confirm(&self) -> Transition<InChain, Confirmed> {
+        self.transition()
+    }
+
+    pub fn confirm_by_wallet(&self) -> Transition<InChain, Confirmed> {
+        self.transition()
+    }
+}
+
+impl Payment<Rejected> {
+    /// A rejected payment showed up in chain anyway
+    pub fn refund(&self) -> Transition<Rejected, Refund> {
+        self.transition()
+    }
+}
+
+impl<S: State> Deref for Payment<S> {
+    type Target = Transaction;
+
+    fn deref(&self) -> &Transaction {
+        &self.transaction
+    }
+}
+
+impl<S: State> Clone for Payment<S> {
+    fn clone(&self) -> Self {
+        Payment {
+            transaction: self.transaction.clone(),
+            state: PhantomData,
+        }
+    }
+}
+
+impl<S: State> fmt::Debug for Payment<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.transaction, f)
+    }
+}
+
+impl<S: State> Serialize for Payment<S> {
+    fn serialize<Z: Serializer>(&self, serializer: Z) -> Result<Z::Ok, Z::Error> {
+        self.transaction.serialize(serializer)
+    }
+}
+
+/// A payment status change and what makes it happen
+#[derive(Debug, Serialize)]
+pub struct TransitionInfo {
+    pub from: TransactionStatus,
+    pub to: TransactionStatus,
+    pub trigger: &'static str,
+}
+
+pub const TRANSITIONS: &[TransitionInfo] = &[
+    TransitionInfo {
+        from: TransactionStatus::New,
+        to: TransactionStatus::Pending,
+        trigger: "the buyer's wallet sent the slate",
+    },
+    TransitionInfo {
+        from: TransactionStatus::New,
+        to: TransactionStatus::Rejected,
+        trigger: "the payment wasn't paid in time",
+    },
+    TransitionInfo {
+        from: TransactionStatus::Pending,
+        to: TransactionStatus::InChain,
+        trigger: "the payment's output was seen in chain",
+    },
+    TransitionInfo {
+        from: TransactionStatus::Pending,
+        to: TransactionStatus::Confirmed,
+        trigger: "the wallet confirmed the output",
+    },
+    TransitionInfo {
+        from: TransactionStatus::Pending,
+        to: TransactionStatus::Rejected,
+        trigger: "the transaction didn't get in chain in time",
+    },
+    TransitionInfo {
+        from: TransactionStatus::InChain,
+        to: TransactionStatus::Confirmed,
+        trigger: "the payment has the required confirmations or the wallet confirmed it",
+    },
+    TransitionInfo {
+        from: TransactionStatus::Rejected,
+        to: TransactionStatus::Refund,
+        trigger: "the rejected payment got in chain anyway",
+    },
+];
+
+/// Statuses a payment doesn't leave, except rejected ones which are paid
+/// anyway
+pub fn is_final(status: TransactionStatus) -> bool {
+    !TRANSITIONS
+        .iter()
+        .any(|t| t.from == status && t.to != TransactionStatus::Refund)
+}
+
+/// Transition an admin makes, one of `TransactionStatus::manual_transitions`
+#[derive(Debug)]
+pub enum ManualTransition {
+    RejectNew(Transition<New, Rejected>),
+    ConfirmPending(Transition<Pending, Confirmed>),
+    RejectPending(Transition<Pending, Rejected>),
+    ConfirmInChain(Transition<InChain, Confirmed>),
+    Refund(Transition<Rejected, Refund>),
+}
+
+impl ManualTransition {
+    /// Fails unless the payment can be moved to `to` by hand
+    pub fn of(transaction: Transaction, to: TransactionStatus) -> Result<Self, Error> {
+        let from = transaction.status;
+        Ok(match (from, to) {
+            (TransactionStatus::New, TransactionStatus::Rejected) => {
+                ManualTransition::RejectNew(Payment::<New>::load(transaction)?.reject())
+            }
+            (TransactionStatus::Pending, TransactionStatus::Confirmed) => {
+                ManualTransition::ConfirmPending(
+                    Payment::<Pending>::load(transaction)?.confirm_manually(),
+                )
+            }
+            (TransactionStatus::Pending, TransactionStatus::Rejected) => {
+                ManualTransition::RejectPending(Payment::<Pending>::load(transaction)?.reject())
+            }
+            (TransactionStatus::InChain, TransactionStatus::Confirmed) => {
+                ManualTransition::ConfirmInChain(Payment::<InChain>::load(transaction)?.confirm())
+            }
+            (TransactionStatus::Rejected, TransactionStatus::Refund) => {
+                ManualTransition::Refund(Payment::<Rejected>::load(transaction)?.refund())
+            }
+            _ => {
+                return Err(Error::InvalidEntity(format!(
+                    "payment can't be moved from {} to {}",
+                    from, to
+                )))
+            }
+        })
+    }
+}
+
+/// Allowed status change of one payment
+pub struct Transition<F: State, T: State> {
+    transaction_id: Uuid,
+    states: PhantomData<(F, T)>,
+}
+
+impl<F: State, T: State> Transition<F, T> {
+    pub fn transaction_id(&self) -> Uuid {
+        self.transaction_id
+    }
+
+    pub fn from(&self) -> TransactionStatus {
+        F::STATUS
+    }
+
+    pub fn to(&self) -> TransactionStatus {
+        T::STATUS
+    }
+}
+
+impl<F: State, T: State> fmt::Debug for Transition<F, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Transition({}: {} -> {})",
+            self.transaction_id,
+            F::STATUS,
+            T::STATUS
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::tests::create_tx;
+
+    #[test]
+    fn test_load() {
+        let mut tx = create_tx();
+        let payment = Payment::<New>::load(tx.clone()).unwrap();
+        let transition = payment.make_pending();
+        assert_eq!(transition.transaction_id(), tx.id);
+        assert_eq!(transition.from(), TransactionStatus::New);
+        assert_eq!(transition.to(), TransactionStatus::Pending);
+        assert!(Payment::<Pending>::load(tx.clone()).is_err());
+        tx.status = TransactionStatus::Rejected;
+        assert_eq!(
+            Payment::<Rejected>::load(tx.clone()).unwrap().refund().to(),
+            TransactionStatus::Refund
+        );
+        tx.transaction_type = TransactionType::Payout;
+        assert!(Payment::<Rejected>::load(tx).is_err());
+    }
+
+    #[test]
+    fn test_transitions() {
+        use TransactionStatus::*;
+        for status in &[New, Pending, InChain, Confirmed, Rejected, Refund] {
+            let mut tx = create_tx();
+            tx.status = *status;
+            for to in status.manual_transitions() {
+                assert!(TRANSITIONS.iter().any(|t| t.from == *status && t.to == *to));
+                assert!(ManualTransition::of(tx.clone(), *to).is_ok());
+            }
+            assert!(ManualTransition::of(tx, New).is_err());
+        }
+        assert!(!is_final(New));
+        assert!(!is_final(InChain));
+        assert!(is_final(Confirmed));
+        assert!(is_final(Rejected));
+        assert!(is_final(Refund));
+    }
+}
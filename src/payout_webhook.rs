@@ -0,0 +1,194 @@
+//! Delivery of payout events to the merchant's `payout_callback_url`.
+//!
+//! Events are put into the `payout_events` outbox in the same DB transaction
+//! which changes the payout, a cron job posts them as JSON `PayoutNotification`
+//! and retries with a growing delay until the merchant answers with 2xx or
+//! the merchant's `callback::RetryPolicy` gives up.
+//!
+//! The `X-Knockturn-Signature` header holds hex encoded HMAC-SHA256 of the
+//! request body keyed with the merchant's API token. Unlike payment callbacks
+//! the token itself is never sent. An event can be delivered more than once,
+//! merchants should deduplicate by `id`.
+
+use crate::callback::{self, CallbackSettings};
+use crate::db::{
+    DbExecutor, GetUndeliveredPayoutEvents, MarkPayoutEventDelivered, PayoutEventAttempt,
+};
+use crate::errors::Error;
+use crate::models::{Merchant, PayoutEvent, Transaction};
+use crate::return_url::hmac;
+use actix::Addr;
+use actix_web::http::header;
+use chrono::Utc;
+use data_encoding::HEXLOWER;
+use futures::future::{join_all, ok, result, Either, Future};
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const SIGNATURE_HEADER: &'static str = "X-Knockturn-Signature";
+/// Number of events sent in one cron run
+const DELIVERY_BATCH_SIZE: i64 = 100;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PayoutNotification {
+    /// Id of the event, the same on every delivery attempt
+    pub id: Uuid,
+    pub event: String,
+    pub transaction_id: Uuid,
+    pub external_id: String,
+    pub merchant_id: String,
+    pub grin_amount: i64,
+    pub slate_id: Option<String>,
+    /// Unix time in seconds
+    pub occurred_at: i64,
+}
+
+impl PayoutNotification {
+    pub fn new(event: &PayoutEvent, payout: &Transaction) -> Self {
+        PayoutNotification {
+            id: event.id,
+            event: event.event.clone(),
+            transaction_id: payout.id,
+            external_id: payout.external_id.clone(),
+            merchant_id: payout.merchant_id.clone(),
+            grin_amount: payout.grin_amount,
+            slate_id: payout.wallet_tx_slate_id.clone(),
+            occurred_at: event.created_at.timestamp(),
+        }
+    }
+}
+
+pub fn sign(body: &str, secret: &str) -> Result<String, Error> {
+    Ok(HEXLOWER.encode(&hmac(secret, body)?))
+}
+
+/// Sends due events, returns how many of them were delivered
+pub fn deliver(db: Addr<DbExecutor>) -> impl Future<Item = usize, Error = Error> {
+    db.send(GetUndeliveredPayoutEvents {
+        limit: DELIVERY_BATCH_SIZE,
+    })
+    .from_err()
+    .and_then(|db_response| {
+        let events = db_response?;
+        Ok(events)
+    })
+    .and_then(move |events| {
+        let futures: Vec<_> = events
+            .into_iter()
+            .map(|(event, payout, merchant)| deliver_event(db.clone(), event, payout, merchant))
+            .collect();
+        join_all(futures).map(|delivered| delivered.into_iter().filter(|d| *d).count())
+    })
+}
+
+/// Never fails, so one unreachable merchant can't hold back the rest
+fn deliver_event(
+    db: Addr<DbExecutor>,
+    event: PayoutEvent,
+    payout: Transaction,
+    merchant: Merchant,
+) -> impl Future<Item = bool, Error = Error> {
+    let callback_url = match merchant.payout_callback_url.clone() {
+        Some(url) => url,
+        None => return Either::A(ok(false)),
+    };
+    debug!("Deliver {} event of payout {}", event.event, payout.id);
+    let notification = PayoutNotification::new(&event, &payout);
+    let settings = CallbackSettings::of(&merchant);
+    let retry_policy = settings.retry_policy();
+    let request = settings
+        .body(&notification)
+        .and_then(|body| Ok((sign(&body, &merchant.token)?, body)));
+    let res = result(request)
+        .and_then({
+            let callback_url = callback_url.clone();
+            move |(signature, body)| {
+                callback::post(&callback_url, &settings)
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .header(SIGNATURE_HEADER, signature)
+                    .body(body)
+                    .unwrap()
+                    .send()
+                    .map_err(move |e| Error::MerchantCallbackError {
+                        callback_url,
+                        error: s!(e),
+                    })
+            }
+        })
+        .and_then(move |resp| {
+            if resp.status().is_success() {
+                Ok(())
+            } else {
+                Err(Error::MerchantCallbackError {
+                    callback_url,
+                    error: format!("Error status: {}", resp.status()),
+                })
+            }
+        })
+        .then(move |res| match res {
+            Ok(_) => Either::A(
+                db.send(MarkPayoutEventDelivered { id: event.id })
+                    .from_err()
+                    .and_then(|db_response| {
+                        db_response?;
+                        Ok(true)
+                    }),
+            ),
+            Err(e) => {
+                let next_attempt = retry_policy.next_attempt(
+                    event.attempts + 1,
+                    event.created_at,
+                    Utc::now().naive_utc(),
+                );
+                warn!(
+                    "Cannot deliver payout event {}, next attempt {:?}: {}",
+                    event.id, next_attempt, e
+                );
+                Either::B(
+                    db.send(PayoutEventAttempt {
+                        id: event.id,
+                        next_attempt,
+                    })
+                    .from_err()
+                    .and_then(|db_response| {
+                        db_response?;
+                        Ok(false)
+                    }),
+                )
+            }
+        })
+        .or_else(|e: Error| {
+            error!("Cannot record payout event delivery: {}", e);
+            Ok(false)
+        });
+    Either::B(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::tests::create_tx;
+    use crate::models::{PayoutEventType, TransactionType};
+
+    #[test]
+    fn test_sign() {
+        assert_eq!(
+            sign("The quick brown fox jumps over the lazy dog", "key").unwrap(),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn test_notification() {
+        let mut payout = create_tx();
+        payout.transaction_type = TransactionType::Payout;
+        payout.wallet_tx_slate_id = Some(s!("slate"));
+        let event = PayoutEvent::new(&payout, PayoutEventType::Initialized);
+        let notification = PayoutNotification::new(&event, &payout);
+        assert_eq!(notification.id, event.id);
+        assert_eq!(notification.event, "initialized");
+        assert_eq!(notification.transaction_id, payout.id);
+        assert_eq!(notification.slate_id, Some(s!("slate")));
+    }
+}
@@ -0,0 +1,131 @@
+//! Minimal PDF writer for text documents like settlement statements.
+//!
+//! Lines are set in Courier, so columns padded with spaces stay aligned,
+//! and split into A4 pages. Only ASCII is supported, other characters
+//! are replaced with `?`.
+
+/// A4 in points
+const PAGE_WIDTH: u32 = 595;
+const PAGE_HEIGHT: u32 = 842;
+const MARGIN: u32 = 40;
+const FONT_SIZE: u32 = 9;
+const LEADING: u32 = 11;
+const LINES_PER_PAGE: usize = 68;
+
+pub fn render(lines: &[String]) -> Vec<u8> {
+    let pages: Vec<&[String]> = if lines.is_empty() {
+        vec![&[]]
+    } else {
+        lines.chunks(LINES_PER_PAGE).collect()
+    };
+    // Catalog, page tree and font come first, then a page and
+    // its content stream for every page
+    let kids: Vec<String> = (0..pages.len())
+        .map(|i| format!("{} 0 R", 4 + 2 * i))
+        .collect();
+    let mut objects = vec![
+        s!("<< /Type /Catalog /Pages 2 0 R >>"),
+        format!(
+            "<< /Type /Pages /Kids [{}] /Count {} >>",
+            kids.join(" "),
+            pages.len()
+        ),
+        s!("<< /Type /Font /Subtype /Type1 /BaseFont /Courier >>"),
+    ];
+    for (i, page) in pages.iter().enumerate() {
+        objects.push(format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] \
+             /Resources << /Font << /F1 3 0 R >> >> /Contents {} 0 R >>",
+            PAGE_WIDTH,
+            PAGE_HEIGHT,
+            5 + 2 * i
+        ));
+        let content = page_content(page);
+        objects.push(format!(
+            "<< /Length {} >>\nstream\n{}\nendstream",
+            content.len(),
+            content
+        ));
+    }
+
+    let mut out = s!("%PDF-1.4\n");
+    let mut offsets = Vec::with_capacity(objects.len());
+    for (i, object) in objects.iter().enumerate() {
+        offsets.push(out.len());
+        out.push_str(&format!("{} 0 obj\n{}\nendobj\n", i + 1, object));
+    }
+    let xref = out.len();
+    out.push_str(&format!(
+        "xref\n0 {}\n0000000000 65535 f \n",
+        objects.len() + 1
+    ));
+    for offset in offsets {
+        out.push_str(&format!("{:010} 00000 n \n", offset));
+    }
+    out.push_str(&format!(
+        "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+        objects.len() + 1,
+        xref
+    ));
+    out.into_bytes()
+}
+
+fn page_content(lines: &[String]) -> String {
+    let mut content = format!(
+        "BT\n/F1 {} Tf\n{} TL\n{} {} Td\n",
+        FONT_SIZE,
+        LEADING,
+        MARGIN,
+        PAGE_HEIGHT - MARGIN
+    );
+    for line in lines {
+        content.push_str(&format!("({}) Tj T*\n", escape(line)));
+    }
+    content.push_str("ET");
+    content
+}
+
+fn escape(line: &str) -> String {
+    let mut escaped = String::with_capacity(line.len());
+    for c in line.chars() {
+        match c {
+            '(' | ')' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            ' '..='~' => escaped.push(c),
+            _ => escaped.push('?'),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("fee (1%) a\\b"), "fee \\(1%\\) a\\\\b");
+        assert_eq!(escape("1 ツ"), "1 ?");
+    }
+
+    #[test]
+    fn test_render() {
+        let lines: Vec<String> = (0..LINES_PER_PAGE + 1)
+            .map(|i| format!("line {}", i))
+            .collect();
+        let pdf = String::from_utf8(render(&lines)).unwrap();
+        assert!(pdf.starts_with("%PDF-1.4\n"));
+        assert!(pdf.ends_with("%%EOF\n"));
+        assert!(pdf.contains("/Count 2"));
+
+        // Every xref entry points at its object
+        let xref = pdf.find("xref\n").unwrap();
+        let entries: Vec<&str> = pdf[xref..].lines().skip(3).take(7).collect();
+        for (i, entry) in entries.iter().enumerate() {
+            let offset: usize = entry[..10].parse().unwrap();
+            assert!(pdf[offset..].starts_with(&format!("{} 0 obj", i + 1)));
+        }
+    }
+}
@@ -0,0 +1,124 @@
+//! Merchant plans and their quotas.
+//!
+//! A merchant is on one of the plans of the `plans` table, `free`,
+//! `standard` or `enterprise` out of the box, and new merchants on
+//! `DEFAULT_PLAN`. A plan caps the payments created per calendar month
+//! (UTC) and the amount of a single payment, both checked when a payment
+//! is created, the payment creation rate, see `rate_limit`, and how often
+//! callbacks are retried, a floor of the merchant's backoff. Payouts
+//! aren't limited. The dashboard shows the usage against the quotas. The
+//! plan's `fee_bps` of every payment credited to the merchant is kept as
+//! the knockturn fee, none by default.
+//! Every instance reloads the plans every `PLAN_RELOAD_SECONDS`, quotas of
+//! payment creation are checked against the DB.
+
+use crate::db::{DbExecutor, GetPlans};
+use crate::errors::Error;
+use crate::models::Plan;
+use crate::splits::share_amount;
+use actix::Addr;
+use chrono::{Datelike, NaiveDate, NaiveDateTime};
+use futures::future::Future;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+pub const DEFAULT_PLAN: &str = "standard";
+pub const PLAN_RELOAD_SECONDS: u64 = 60;
+
+lazy_static::lazy_static! {
+    /// As of the last reload
+    static ref PLANS: RwLock<HashMap<String, Plan>> = RwLock::new(HashMap::new());
+}
+
+/// `None` until the plans were loaded, callers don't limit then
+pub fn get(name: &str) -> Option<Plan> {
+    PLANS.read().unwrap().get(name).cloned()
+}
+
+pub fn reload(db: Addr<DbExecutor>) -> impl Future<Item = (), Error = Error> {
+    db.send(GetPlans).from_err().and_then(|db_response| {
+        let plans = db_response?;
+        *PLANS.write().unwrap() = plans
+            .into_iter()
+            .map(|plan| (plan.name.clone(), plan))
+            .collect();
+        Ok(())
+    })
+}
+
+/// Monthly quotas count from it
+pub fn month_start(now: NaiveDateTime) -> NaiveDateTime {
+    NaiveDate::from_ymd(now.year(), now.month(), 1).and_hms(0, 0, 0)
+}
+
+/// Whether a payment of `grin_amount` can be created with
+/// `payments_this_month` already created
+pub fn check_payment(plan: &Plan, payments_this_month: i64, grin_amount: i64) -> Result<(), Error> {
+    if let Some(max) = plan.monthly_payments {
+        if payments_this_month >= max {
+            return Err(Error::QuotaExceeded(format!(
+                "the {} plan allows {} payments a month",
+                plan.name, max
+            )));
+        }
+    }
+    if let Some(max) = plan.max_payment() {
+        if grin_amount > max.amount {
+            return Err(Error::QuotaExceeded(format!(
+                "the {} plan allows payments up to {}",
+                plan.name, max
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Knockturn fee of a payment credited with `grin_amount`, rounded down
+pub fn payment_fee(plan: &Plan, grin_amount: i64) -> i64 {
+    share_amount(grin_amount, plan.fee_bps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_payment() {
+        let plan = Plan {
+            name: s!("free"),
+            monthly_payments: Some(100),
+            max_payment_amount: Some(100_000_000_000),
+            min_callback_backoff_seconds: 60,
+            api_burst: 10,
+            api_per_minute: 10,
+            fee_bps: 0,
+        };
+        assert!(check_payment(&plan, 99, 100_000_000_000).is_ok());
+        assert_eq!(
+            check_payment(&plan, 100, 1).unwrap_err().code(),
+            "quota_exceeded"
+        );
+        assert!(check_payment(&plan, 0, 100_000_000_001).is_err());
+
+        let unlimited = Plan {
+            name: s!("enterprise"),
+            monthly_payments: None,
+            max_payment_amount: None,
+            ..plan
+        };
+        assert!(check_payment(&unlimited, 1_000_000, i64::max_value()).is_ok());
+
+        assert_eq!(payment_fee(&plan, 1_000_000_999), 0);
+        let paid = Plan {
+            fee_bps: 150,
+            ..plan
+        };
+        assert_eq!(payment_fee(&paid, 1_000_000_999), 15_000_014);
+
+        let now = NaiveDate::from_ymd(2019, 7, 20).and_hms(8, 5, 12);
+        assert_eq!(
+            month_start(now),
+            NaiveDate::from_ymd(2019, 7, 1).and_hms(0, 0, 0)
+        );
+    }
+}
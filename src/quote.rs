@@ -0,0 +1,81 @@
+//! Quotes of grin amounts in display currencies (e.g. BTC) based on stored rates
+
+use crate::errors::Error;
+use crate::models::{Currency, Money, Rate};
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::{self, prelude::*};
+use log::warn;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::str::FromStr;
+
+/// Env variable with a comma separated list of display currencies
+const ENV_DISPLAY_CURRENCIES_VAR: &str = "DISPLAY_CURRENCIES";
+
+/// Rates older than this are not used for quotes
+pub const MAX_QUOTE_RATE_AGE_SECONDS: i64 = 10 * 60;
+
+lazy_static::lazy_static! {
+    pub static ref DISPLAY_CURRENCIES: Vec<Currency> = {
+        match std::env::var(ENV_DISPLAY_CURRENCIES_VAR) {
+            Ok(val) => val
+                .split(',')
+                .filter(|c| !c.trim().is_empty())
+                .filter_map(|c| match Currency::from_str(c) {
+                    Ok(currency) => Some(currency),
+                    Err(e) => {
+                        log::error!("Can not parse DISPLAY_CURRENCIES value: {}", e);
+                        None
+                    }
+                })
+                .collect(),
+            Err(_) => vec![Currency::BTC],
+        }
+    };
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct Quote {
+    pub amount: Money,
+    pub rate: Decimal,
+    pub rate_updated_at: NaiveDateTime,
+}
+
+/// Quotes `grin_amount` in every display currency which has a fresh rate,
+/// currencies with missing or stale rates are skipped.
+pub fn quote(conn: &PgConnection, grin_amount: i64) -> Result<Vec<Quote>, Error> {
+    use crate::schema::rates::dsl::*;
+
+    let currencies: Vec<String> = DISPLAY_CURRENCIES.iter().map(|c| c.to_string()).collect();
+    let fresh_since = Utc::now().naive_utc() - Duration::seconds(MAX_QUOTE_RATE_AGE_SECONDS);
+    let stored_rates = rates
+        .filter(id.eq_any(currencies))
+        .load::<Rate>(conn)
+        .map_err::<Error, _>(|e| e.into())?;
+
+    let mut quotes = vec![];
+    for currency in DISPLAY_CURRENCIES.iter() {
+        let stored_rate = match stored_rates.iter().find(|r| r.id == currency.to_string()) {
+            Some(r) => r,
+            None => continue,
+        };
+        if stored_rate.updated_at < fresh_since {
+            warn!(
+                "Rate for {} is stale (updated at {}), skip quote",
+                currency, stored_rate.updated_at
+            );
+            continue;
+        }
+        if let Some(amount) =
+            Money::from_grin(grin_amount).convert_at_price(*currency, stored_rate.rate)
+        {
+            quotes.push(Quote {
+                amount,
+                rate: stored_rate.rate,
+                rate_updated_at: stored_rate.updated_at,
+            });
+        }
+    }
+    Ok(quotes)
+}
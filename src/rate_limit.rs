@@ -0,0 +1,198 @@
+//! Soft rate limit of payment creation per merchant.
+//!
+//! Every merchant has a token bucket of its plan's `api_burst` size, which
+//! refills at the plan's `api_per_minute` and is taken a token from per
+//! created payment. An empty bucket answers 429 with `Retry-After`. Buckets
+//! live in memory of each instance and are saved
+//! to the DB every `RATE_LIMIT_SYNC_SECONDS` and loaded on start, so limits
+//! survive restarts approximately and several instances let a merchant
+//! through a bit more than the plan.
+
+use crate::db::{DbExecutor, GetRateLimitBuckets, SaveRateLimitBuckets};
+use crate::errors::Error;
+use crate::metrics;
+use crate::models::{Merchant, Plan, RateLimitBucket};
+use crate::plans;
+use actix::Addr;
+use chrono::NaiveDateTime;
+use futures::future::{ok, Either, Future};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+pub const RATE_LIMIT_SYNC_SECONDS: u64 = 60;
+
+lazy_static::lazy_static! {
+    static ref BUCKETS: Mutex<Buckets> = Mutex::new(Buckets::default());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlanLimit {
+    /// Payments created at once with a full bucket
+    pub burst: f64,
+    pub per_minute: f64,
+}
+
+impl PlanLimit {
+    pub fn of(plan: &Plan) -> Self {
+        PlanLimit {
+            burst: f64::from(plan.api_burst.max(1)),
+            per_minute: f64::from(plan.api_per_minute.max(1)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenBucket {
+    pub tokens: f64,
+    pub updated_at: NaiveDateTime,
+}
+
+impl TokenBucket {
+    pub fn full(limit: &PlanLimit, now: NaiveDateTime) -> Self {
+        TokenBucket {
+            tokens: limit.burst,
+            updated_at: now,
+        }
+    }
+
+    /// Takes `n` tokens, at most a full bucket, or tells in how many
+    /// seconds there will be enough
+    pub fn take(&mut self, limit: &PlanLimit, n: f64, now: NaiveDateTime) -> Result<(), u64> {
+        let elapsed = (now - self.updated_at).num_milliseconds().max(0) as f64 / 1000.0;
+        let per_second = limit.per_minute / 60.0;
+        self.tokens = (self.tokens + elapsed * per_second).min(limit.burst);
+        self.updated_at = now;
+        let n = n.min(limit.burst);
+        if self.tokens >= n {
+            self.tokens -= n;
+            Ok(())
+        } else {
+            Err(((n - self.tokens) / per_second).ceil().max(1.0) as u64)
+        }
+    }
+
+    /// Gives back `n` tokens taken for payments which weren't created
+    pub fn refund(&mut self, limit: &PlanLimit, n: f64) {
+        self.tokens = (self.tokens + n.min(limit.burst)).min(limit.burst);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    buckets: HashMap<String, TokenBucket>,
+    /// Changed since the last save
+    changed: HashSet<String>,
+}
+
+/// Takes a token per payment from the merchant's bucket
+pub fn take(merchant: &Merchant, payments: usize, now: NaiveDateTime) -> Result<(), Error> {
+    let limit = match plans::get(&merchant.plan) {
+        Some(plan) => PlanLimit::of(&plan),
+        None => return Ok(()),
+    };
+    let mut buckets = BUCKETS.lock().unwrap();
+    let res = buckets
+        .buckets
+        .entry(merchant.id.clone())
+        .or_insert_with(|| TokenBucket::full(&limit, now))
+        .take(&limit, payments as f64, now);
+    buckets.changed.insert(merchant.id.clone());
+    res.map_err(|retry_after| {
+        metrics::inc("payment_rate_limited_total", &[("plan", &merchant.plan)]);
+        Error::RateLimited(retry_after)
+    })
+}
+
+/// Gives back the tokens of payments which weren't created after all
+pub fn refund(merchant: &Merchant, payments: usize) {
+    let limit = match plans::get(&merchant.plan) {
+        Some(plan) => PlanLimit::of(&plan),
+        None => return,
+    };
+    let mut buckets = BUCKETS.lock().unwrap();
+    if let Some(bucket) = buckets.buckets.get_mut(&merchant.id) {
+        bucket.refund(&limit, payments as f64);
+        buckets.changed.insert(merchant.id.clone());
+    }
+}
+
+/// Loads the saved buckets, ones this instance already used are kept
+pub fn load(db: Addr<DbExecutor>) -> impl Future<Item = (), Error = Error> {
+    db.send(GetRateLimitBuckets)
+        .from_err()
+        .and_then(|db_response| {
+            let saved = db_response?;
+            let mut buckets = BUCKETS.lock().unwrap();
+            for bucket in saved {
+                buckets
+                    .buckets
+                    .entry(bucket.merchant_id)
+                    .or_insert(TokenBucket {
+                        tokens: bucket.tokens,
+                        updated_at: bucket.updated_at,
+                    });
+            }
+            Ok(())
+        })
+}
+
+/// Saves the buckets changed since the last save
+pub fn save(db: Addr<DbExecutor>) -> impl Future<Item = (), Error = Error> {
+    let changed: Vec<RateLimitBucket> = {
+        let mut buckets = BUCKETS.lock().unwrap();
+        let changed: Vec<String> = buckets.changed.drain().collect();
+        changed
+            .into_iter()
+            .filter_map(|merchant_id| {
+                let bucket = *buckets.buckets.get(&merchant_id)?;
+                Some(RateLimitBucket {
+                    merchant_id,
+                    tokens: bucket.tokens,
+                    updated_at: bucket.updated_at,
+                })
+            })
+            .collect()
+    };
+    if changed.is_empty() {
+        return Either::A(ok(()));
+    }
+    Either::B(
+        db.send(SaveRateLimitBuckets(changed))
+            .from_err()
+            .and_then(|db_response| db_response),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, Utc};
+
+    #[test]
+    fn test_take() {
+        let limit = PlanLimit {
+            burst: 2.0,
+            per_minute: 6.0,
+        };
+        let now = Utc::now().naive_utc();
+        let mut bucket = TokenBucket::full(&limit, now);
+        assert!(bucket.take(&limit, 1.0, now).is_ok());
+        assert!(bucket.take(&limit, 1.0, now).is_ok());
+        // A token every 10 seconds
+        assert_eq!(bucket.take(&limit, 1.0, now), Err(10));
+        assert_eq!(bucket.take(&limit, 1.0, now + Duration::seconds(4)), Err(6));
+        assert!(bucket
+            .take(&limit, 1.0, now + Duration::seconds(10))
+            .is_ok());
+        // Refills up to the burst only, a batch takes at most a full bucket
+        let later = now + Duration::hours(1);
+        assert!(bucket.take(&limit, 100.0, later).is_ok());
+        assert_eq!(bucket.take(&limit, 1.0, later), Err(10));
+        // A batch which wasn't created gives its tokens back, up to the burst
+        bucket.refund(&limit, 100.0);
+        assert_eq!(bucket.tokens, 2.0);
+        assert!(bucket.take(&limit, 1.0, later).is_ok());
+        bucket.refund(&limit, 1.0);
+        assert_eq!(bucket.tokens, 2.0);
+    }
+}
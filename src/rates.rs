@@ -1,18 +1,69 @@
 use crate::db::{DbExecutor, RegisterRate};
+use crate::errors::Error;
+use crate::metrics;
+use crate::models::{Currency, Rate};
 use actix::prelude::*;
 use actix_web::client;
 use actix_web::HttpMessage;
+use chrono::NaiveDateTime;
 use futures;
 use futures::future::{err, ok, result, Future};
 use log::*;
+use rust_decimal::Decimal;
 use serde::Deserialize;
 use serde_json;
 use std::collections::HashMap;
+use std::env;
 use std::str;
 
+const DEFAULT_MAX_RATE_AGE_SECONDS: i64 = 3600;
+
+lazy_static::lazy_static! {
+    /// Rates older than this aren't used to price payments
+    pub static ref MAX_RATE_AGE_SECONDS: i64 = max_rate_age_from_env();
+}
+
+/// Reads RATE_MAX_AGE_SECONDS, an hour by default
+fn max_rate_age_from_env() -> i64 {
+    env::var("RATE_MAX_AGE_SECONDS")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .map(|v| match v.parse() {
+            Ok(seconds) if seconds > 0 => seconds,
+            _ => panic!("RATE_MAX_AGE_SECONDS must be a positive number"),
+        })
+        .unwrap_or(DEFAULT_MAX_RATE_AGE_SECONDS)
+}
+
+pub fn rate_age_seconds(rate: &Rate, now: NaiveDateTime) -> i64 {
+    (now - rate.updated_at).num_seconds().max(0)
+}
+
+/// Refuses to convert with a rate the fetcher didn't update for too long
+pub fn check_rate_age(rate: &Rate, now: NaiveDateTime, max_age_seconds: i64) -> Result<(), Error> {
+    if rate_age_seconds(rate, now) > max_age_seconds {
+        return Err(Error::StaleRate(rate.id.clone()));
+    }
+    Ok(())
+}
+
+/// Exports the age of every rate, warns about the stale ones
+pub fn record_rate_ages(rates: &[Rate], now: NaiveDateTime) {
+    for rate in rates {
+        let age = rate_age_seconds(rate, now);
+        metrics::set("rate_age_seconds", &[("currency", &rate.id)], age);
+        if age > *MAX_RATE_AGE_SECONDS {
+            warn!(
+                "Rate of {} wasn't updated for {}s, payments in {} are refused",
+                rate.id, age, rate.id
+            );
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct Rates {
-    grin: HashMap<String, f64>,
+    grin: HashMap<String, Decimal>,
 }
 
 pub struct RatesFetcher {
@@ -26,9 +77,13 @@ impl RatesFetcher {
 
     pub fn fetch(&self) {
         let db = self.db.clone();
-        let f = client::get(
-            "https://api.coingecko.com/api/v3/simple/price?ids=grin&vs_currencies=btc%2Cusd%2Ceur",
-        )
+        let vs_currencies: Vec<String> = Currency::rated()
+            .map(|c| c.to_string().to_lowercase())
+            .collect();
+        let f = client::get(format!(
+            "https://api.coingecko.com/api/v3/simple/price?ids=grin&vs_currencies={}",
+            vs_currencies.join("%2C")
+        ))
         .header("Accept", "application/json")
         .finish()
         .unwrap()
@@ -75,3 +130,33 @@ impl RatesFetcher {
         actix::spawn(f);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, Utc};
+
+    #[test]
+    fn test_check_rate_age() {
+        let now = Utc::now().naive_utc();
+        let mut rate = Rate {
+            id: s!("USD"),
+            rate: Decimal::new(3, 0),
+            updated_at: now - Duration::seconds(60),
+        };
+        assert_eq!(rate_age_seconds(&rate, now), 60);
+        assert!(check_rate_age(&rate, now, 3600).is_ok());
+        rate.updated_at = now - Duration::seconds(3601);
+        match check_rate_age(&rate, now, 3600) {
+            Err(Error::StaleRate(currency)) => assert_eq!(currency, "USD"),
+            res => panic!("stale rate was accepted: {:?}", res),
+        }
+        rate.updated_at = now + Duration::seconds(5);
+        assert_eq!(rate_age_seconds(&rate, now), 0);
+        record_rate_ages(&[rate], now);
+        assert_eq!(
+            metrics::get_gauge("rate_age_seconds", &[("currency", "USD")]),
+            Some(0)
+        );
+    }
+}
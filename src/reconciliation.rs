@@ -0,0 +1,301 @@
+//! Comparison of the wallet's transaction log with the transactions table.
+//!
+//! Records are matched by slate id. A wallet transaction without a DB record
+//! or a DB record whose slate the wallet doesn't know is an orphan, orphans
+//! are shown to admins. Transactions younger than `GRACE_MINUTES` are skipped,
+//! one side may not have caught up with the other yet.
+//!
+//! Unconfirmed wallet transactions of rejected payments or without any
+//! record are stale, they keep their outputs locked until cancelled.
+//! `cancel_stale` cancels them `CANCEL_BATCH_SIZE` at a time.
+
+use crate::db::{DbExecutor, GetTransactionsWithSlate, ReplaceReconciliationOrphans};
+use crate::errors::Error;
+use crate::models::{OrphanSource, ReconciliationOrphan, Transaction, TransactionStatus};
+use crate::wallet::{TxLogEntry, TxLogEntryType, Wallet};
+use actix::Addr;
+use chrono::{Duration, NaiveDateTime, Utc};
+use futures::future::{join_all, loop_fn, Future, Loop};
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+/// Number of DB transactions loaded at once
+const PAGE_SIZE: i64 = 500;
+const GRACE_MINUTES: i64 = 60;
+/// Wallet transactions cancelled at once
+const CANCEL_BATCH_SIZE: usize = 10;
+
+fn load_transactions(
+    db: Addr<DbExecutor>,
+    created_before: NaiveDateTime,
+) -> impl Future<Item = Vec<Transaction>, Error = Error> {
+    loop_fn(
+        (Vec::new(), 0),
+        move |(mut loaded, offset): (Vec<Transaction>, i64)| {
+            db.send(GetTransactionsWithSlate {
+                created_before,
+                offset,
+                limit: PAGE_SIZE,
+            })
+            .from_err()
+            .and_then(move |db_response| {
+                let page = db_response?;
+                let done = (page.len() as i64) < PAGE_SIZE;
+                loaded.extend(page);
+                if done {
+                    Ok(Loop::Break(loaded))
+                } else {
+                    Ok(Loop::Continue((loaded, offset + PAGE_SIZE)))
+                }
+            })
+        },
+    )
+}
+
+pub fn reconcile(db: Addr<DbExecutor>, wallet: Wallet) -> impl Future<Item = usize, Error = Error> {
+    let now = Utc::now().naive_utc();
+    let created_before = now - Duration::minutes(GRACE_MINUTES);
+    wallet
+        .list_txs()
+        .join(load_transactions(db.clone(), created_before))
+        .and_then(move |(wallet_txs, transactions)| {
+            let orphans = find_orphans(&wallet_txs, &transactions, created_before, now);
+            let found = orphans.len();
+            if found > 0 {
+                warn!("Reconciliation found {} orphan transactions", found);
+            } else {
+                info!("Reconciliation found no orphan transactions");
+            }
+            db.send(ReplaceReconciliationOrphans(orphans))
+                .from_err()
+                .and_then(move |db_response| {
+                    db_response?;
+                    Ok(found)
+                })
+        })
+}
+
+/// Cancels stale wallet transactions, returns how many were cancelled.
+/// A transaction the wallet refuses to cancel is logged and skipped.
+pub fn cancel_stale(
+    db: Addr<DbExecutor>,
+    wallet: Wallet,
+) -> impl Future<Item = usize, Error = Error> {
+    let created_before = Utc::now().naive_utc() - Duration::minutes(GRACE_MINUTES);
+    wallet
+        .list_txs()
+        .join(load_transactions(db, created_before))
+        .and_then(move |(wallet_txs, transactions)| {
+            let stale = find_stale(&wallet_txs, &transactions, created_before);
+            info!("Found {} stale wallet transactions", stale.len());
+            loop_fn(
+                (stale, 0),
+                move |(mut stale, cancelled): (Vec<String>, usize)| {
+                    let rest = stale.split_off(CANCEL_BATCH_SIZE.min(stale.len()));
+                    let batch: Vec<_> = stale
+                        .into_iter()
+                        .map(|slate_id| {
+                            wallet
+                                .cancel_tx(&slate_id)
+                                .then(move |res| -> Result<usize, Error> {
+                                    match res {
+                                        Ok(()) => {
+                                            info!(
+                                                "Cancelled stale wallet transaction {}",
+                                                slate_id
+                                            );
+                                            Ok(1)
+                                        }
+                                        Err(e) => {
+                                            warn!(
+                                                "Cannot cancel wallet transaction {}: {}",
+                                                slate_id, e
+                                            );
+                                            Ok(0)
+                                        }
+                                    }
+                                })
+                        })
+                        .collect();
+                    join_all(batch).map(move |results: Vec<usize>| {
+                        let cancelled = cancelled + results.iter().sum::<usize>();
+                        if rest.is_empty() {
+                            Loop::Break(cancelled)
+                        } else {
+                            Loop::Continue((rest, cancelled))
+                        }
+                    })
+                },
+            )
+        })
+}
+
+/// Slate ids of unconfirmed wallet transactions which belong to a rejected
+/// payment or to nothing the DB knows about
+pub fn find_stale(
+    wallet_txs: &[TxLogEntry],
+    transactions: &[Transaction],
+    created_before: NaiveDateTime,
+) -> Vec<String> {
+    let in_db: HashMap<&str, &Transaction> = transactions
+        .iter()
+        .filter_map(|tx| tx.wallet_tx_slate_id.as_ref().map(|id| (id.as_str(), tx)))
+        .collect();
+    wallet_txs
+        .iter()
+        .filter(|tx| !tx.confirmed && tx.creation_ts.naive_utc() < created_before)
+        .filter(|tx| match tx.tx_type {
+            TxLogEntryType::TxReceived | TxLogEntryType::TxSent => true,
+            _ => false,
+        })
+        .filter_map(|tx| {
+            let slate_id = tx.tx_slate_id.as_ref()?;
+            match in_db.get(slate_id.as_str()) {
+                Some(payment) if payment.status != TransactionStatus::Rejected => None,
+                _ => Some(slate_id.clone()),
+            }
+        })
+        .collect()
+}
+
+pub fn find_orphans(
+    wallet_txs: &[TxLogEntry],
+    transactions: &[Transaction],
+    created_before: NaiveDateTime,
+    now: NaiveDateTime,
+) -> Vec<ReconciliationOrphan> {
+    let in_wallet: HashSet<&str> = wallet_txs
+        .iter()
+        .filter_map(|tx| tx.tx_slate_id.as_ref().map(|id| id.as_str()))
+        .collect();
+    let in_db: HashMap<&str, &Transaction> = transactions
+        .iter()
+        .filter_map(|tx| tx.wallet_tx_slate_id.as_ref().map(|id| (id.as_str(), tx)))
+        .collect();
+
+    let wallet_orphans = wallet_txs
+        .iter()
+        .filter(|tx| tx.creation_ts.naive_utc() < created_before)
+        .filter_map(|tx| {
+            let slate_id = tx.tx_slate_id.as_ref()?;
+            if in_db.contains_key(slate_id.as_str()) {
+                return None;
+            }
+            Some(ReconciliationOrphan {
+                id: Uuid::new_v4(),
+                source: OrphanSource::Wallet.to_string(),
+                slate_id: slate_id.clone(),
+                wallet_tx_id: Some(tx.id as i64),
+                transaction_id: None,
+                grin_amount: tx.amount_credited as i64 - tx.amount_debited as i64,
+                found_at: now,
+            })
+        });
+    let db_orphans = in_db
+        .into_iter()
+        .filter(|(slate_id, _)| !in_wallet.contains(slate_id))
+        .map(|(slate_id, tx)| ReconciliationOrphan {
+            id: Uuid::new_v4(),
+            source: OrphanSource::Db.to_string(),
+            slate_id: slate_id.to_owned(),
+            wallet_tx_id: tx.wallet_tx_id,
+            transaction_id: Some(tx.id),
+            grin_amount: tx.grin_amount,
+            found_at: now,
+        });
+    wallet_orphans.chain(db_orphans).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::tests::create_tx;
+    use crate::wallet::TxLogEntryType;
+    use chrono::DateTime;
+
+    fn wallet_tx(id: u32, slate_id: Option<&str>, created_at: NaiveDateTime) -> TxLogEntry {
+        TxLogEntry {
+            parent_key_id: s!("0200000000000000000000000000000000"),
+            id,
+            tx_slate_id: slate_id.map(|id| id.to_owned()),
+            tx_type: TxLogEntryType::TxReceived,
+            creation_ts: DateTime::from_utc(created_at, Utc),
+            confirmation_ts: None,
+            confirmed: false,
+            num_inputs: 0,
+            num_outputs: 1,
+            amount_credited: 1_000_000_000,
+            amount_debited: 0,
+            fee: None,
+            messages: None,
+            stored_tx: None,
+        }
+    }
+
+    #[test]
+    fn test_find_orphans() {
+        let now = Utc::now().naive_utc();
+        let created_before = now - Duration::minutes(GRACE_MINUTES);
+        let old = created_before - Duration::minutes(1);
+
+        let mut matched = create_tx();
+        matched.wallet_tx_slate_id = Some(s!("matched"));
+        let mut db_only = create_tx();
+        db_only.wallet_tx_slate_id = Some(s!("db_only"));
+
+        let wallet_txs = vec![
+            wallet_tx(1, Some("matched"), old),
+            wallet_tx(2, Some("wallet_only"), old),
+            // Too young to be reported
+            wallet_tx(3, Some("just_received"), now),
+            // Coinbase and the like have no slate
+            wallet_tx(4, None, old),
+        ];
+        let orphans = find_orphans(
+            &wallet_txs,
+            &[matched, db_only.clone()],
+            created_before,
+            now,
+        );
+
+        assert_eq!(orphans.len(), 2);
+        assert_eq!(orphans[0].source, "Wallet");
+        assert_eq!(orphans[0].slate_id, "wallet_only");
+        assert_eq!(orphans[0].wallet_tx_id, Some(2));
+        assert_eq!(orphans[1].source, "Db");
+        assert_eq!(orphans[1].slate_id, "db_only");
+        assert_eq!(orphans[1].transaction_id, Some(db_only.id));
+    }
+
+    #[test]
+    fn test_find_stale() {
+        let now = Utc::now().naive_utc();
+        let created_before = now - Duration::minutes(GRACE_MINUTES);
+        let old = created_before - Duration::minutes(1);
+
+        let mut pending = create_tx();
+        pending.wallet_tx_slate_id = Some(s!("pending"));
+        pending.status = TransactionStatus::Pending;
+        let mut rejected = create_tx();
+        rejected.wallet_tx_slate_id = Some(s!("rejected"));
+        rejected.status = TransactionStatus::Rejected;
+
+        let mut confirmed = wallet_tx(4, Some("confirmed_unknown"), old);
+        confirmed.confirmed = true;
+        let mut cancelled = wallet_tx(5, Some("cancelled_unknown"), old);
+        cancelled.tx_type = TxLogEntryType::TxReceivedCancelled;
+        let wallet_txs = vec![
+            wallet_tx(1, Some("pending"), old),
+            wallet_tx(2, Some("rejected"), old),
+            wallet_tx(3, Some("unknown"), old),
+            confirmed,
+            cancelled,
+            wallet_tx(6, Some("just_received"), now),
+        ];
+        assert_eq!(
+            find_stale(&wallet_txs, &[pending, rejected], created_before),
+            vec![s!("rejected"), s!("unknown")]
+        );
+    }
+}
@@ -0,0 +1,114 @@
+//! Wrappers which keep secrets out of logs.
+//!
+//! Their `Debug` and `Display` print a mask instead of the value, so a
+//! credential ends up in a log line only when it's taken out explicitly
+//! with `expose`.
+
+use actix_web::client::ClientResponse;
+use actix_web::http::header::HeaderMap;
+use std::fmt;
+
+const MASK: &str = "***";
+
+/// Headers whose values are never logged
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+];
+
+#[derive(Clone, PartialEq)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(MASK)
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(MASK)
+    }
+}
+
+/// Basic auth credentials of the wallet or the node API
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: Secret<String>,
+}
+
+impl Credentials {
+    pub fn new(username: &str, password: &str) -> Self {
+        Credentials {
+            username: username.to_owned(),
+            password: Secret::new(password.to_owned()),
+        }
+    }
+}
+
+/// Headers with the values of auth and cookie headers masked
+pub struct Headers<'a>(pub &'a HeaderMap);
+
+impl<'a> fmt::Debug for Headers<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut map = f.debug_map();
+        for (name, value) in self.0.iter() {
+            if SENSITIVE_HEADERS.contains(&name.as_str()) {
+                map.entry(name, &MASK);
+            } else {
+                map.entry(name, value);
+            }
+        }
+        map.finish()
+    }
+}
+
+/// Status and headers of a response, the body is left out
+pub struct Response<'a>(pub &'a ClientResponse);
+
+impl<'a> fmt::Debug for Response<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:?}", self.0.status(), Headers(self.0.headers()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{self, HeaderValue};
+
+    #[test]
+    fn test_redact() {
+        let credentials = Credentials::new("grin", "wallet password");
+        let logged = format!("{:?}", credentials);
+        assert!(logged.contains("grin"));
+        assert!(!logged.contains("wallet password"));
+        assert_eq!(credentials.password.expose(), "wallet password");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Basic Z3Jpbg=="),
+        );
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        let logged = format!("{:?}", Headers(&headers));
+        assert!(!logged.contains("Z3Jpbg=="));
+        assert!(logged.contains("application/json"));
+    }
+}
@@ -0,0 +1,104 @@
+//! Referral attribution of merchants.
+//!
+//! An admin attributes a merchant to the merchant who referred it, with
+//! the referrer's share of the knockturn fees of its payments, see
+//! `plans::payment_fee`. Whenever a payment of the referred merchant is
+//! credited the referrer's balance gets the share of its fee, rounded
+//! down, the gateway keeps the rest. The referrer and its fee are kept on
+//! the payment, so changing the attribution doesn't change what was
+//! earned. Referrers see the merchants they referred and monthly summaries
+//! on their Referrals page.
+
+use crate::errors::Error;
+use crate::models::Merchant;
+use crate::splits::{share_amount, share_bps};
+use chrono::NaiveDate;
+use diesel::sql_types::{BigInt, Date};
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+/// Months shown on the Referrals page
+pub const SUMMARY_MONTHS: i64 = 12;
+
+/// Payments of the merchants a referrer referred confirmed in one month
+/// (UTC), amounts in nanogrins
+#[derive(Debug, Serialize, QueryableByName)]
+pub struct ReferralMonth {
+    #[sql_type = "Date"]
+    pub month: NaiveDate,
+    /// Merchants with payments that month
+    #[sql_type = "BigInt"]
+    pub merchants: i64,
+    #[sql_type = "BigInt"]
+    pub payments: i64,
+    #[sql_type = "BigInt"]
+    pub volume: i64,
+    #[sql_type = "BigInt"]
+    pub fees: i64,
+    /// The referrer's share of the fees
+    #[sql_type = "BigInt"]
+    pub earned: i64,
+}
+
+/// Referrer credited with its share of `knockturn_fee` of a payment of
+/// `merchant`, if it was referred
+pub fn referral_fee(merchant: &Merchant, knockturn_fee: i64) -> Option<(String, i64)> {
+    match (&merchant.referrer_id, merchant.referral_share_bps) {
+        (Some(referrer), Some(share)) => {
+            Some((referrer.clone(), share_amount(knockturn_fee, share)))
+        }
+        _ => None,
+    }
+}
+
+/// Share of `merchant`'s fees `referrer` may get, in hundredths of a
+/// percent
+pub fn check_referral(
+    merchant: &Merchant,
+    referrer: &Merchant,
+    percent: Decimal,
+) -> Result<i32, Error> {
+    if merchant.id == referrer.id {
+        return Err(Error::InvalidEntity(s!("a merchant can't refer itself")));
+    }
+    if referrer.referrer_id.as_ref() == Some(&merchant.id) {
+        return Err(Error::InvalidEntity(format!(
+            "{} was referred by {}",
+            referrer.id, merchant.id
+        )));
+    }
+    share_bps(percent).ok_or_else(|| {
+        Error::InvalidEntity(s!(
+            "the referral share should be between 0.01 and 100 percent with at most two decimals"
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::tests::create_merchant;
+
+    #[test]
+    fn test_referral_fee() {
+        let mut merchant = create_merchant();
+        let mut referrer = create_merchant();
+        referrer.id = s!("referrer");
+        assert_eq!(referral_fee(&merchant, 10_000_001), None);
+
+        let percent: Decimal = "25".parse().unwrap();
+        let share = check_referral(&merchant, &referrer, percent).unwrap();
+        assert_eq!(share, 2_500);
+        merchant.referrer_id = Some(s!("referrer"));
+        merchant.referral_share_bps = Some(share);
+        assert_eq!(
+            referral_fee(&merchant, 10_000_001),
+            Some((s!("referrer"), 2_500_000))
+        );
+
+        assert!(check_referral(&merchant, &merchant, percent).is_err());
+        assert!(check_referral(&referrer, &merchant, percent).is_err());
+        assert!(check_referral(&merchant, &referrer, "0".parse().unwrap()).is_err());
+        assert!(check_referral(&merchant, &referrer, "100.5".parse().unwrap()).is_err());
+    }
+}
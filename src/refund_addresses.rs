@@ -0,0 +1,218 @@
+//! Refund address books of merchants.
+//!
+//! A merchant keeps the wallet addresses, http(s) URLs or Tor onion
+//! addresses, refunded payments may be returned to, each with a label. A
+//! new address is verified after `REFUND_ADDRESS_HOLD_HOURS`, meanwhile the
+//! merchant gets a security alert about it and can delete it, so a
+//! hijacked session can't redirect refunds right away. Refunds of more than
+//! `REFUND_VERIFIED_ABOVE` nanogrins may only go to a verified address of
+//! the book, smaller ones to any valid address. Additions and deletions are
+//! recorded as security events, the chosen destination as a note of the
+//! payment.
+
+use crate::errors::Error;
+use crate::models::RefundAddress;
+use chrono::{Duration, NaiveDateTime};
+use http::Uri;
+use std::env;
+use uuid::Uuid;
+
+pub const MAX_LABEL_LENGTH: usize = 100;
+pub const MAX_ADDRESS_LENGTH: usize = 255;
+/// Length of a v3 onion address without `.onion`
+const ONION_LENGTH: usize = 56;
+
+lazy_static::lazy_static! {
+    pub static ref REFUND_ADDRESS_CONFIG: RefundAddressConfig = RefundAddressConfig::from_env();
+}
+
+#[derive(Debug, Clone)]
+pub struct RefundAddressConfig {
+    /// Larger refunds need a verified address, in nanogrins
+    pub verified_above: i64,
+    pub hold_hours: i64,
+}
+
+impl Default for RefundAddressConfig {
+    fn default() -> Self {
+        RefundAddressConfig {
+            verified_above: 10_000_000_000,
+            hold_hours: 24,
+        }
+    }
+}
+
+impl RefundAddressConfig {
+    pub fn from_env() -> Self {
+        let default = RefundAddressConfig::default();
+        RefundAddressConfig {
+            verified_above: env::var("REFUND_VERIFIED_ABOVE")
+                .map(|v| v.parse().expect("REFUND_VERIFIED_ABOVE must be a number"))
+                .unwrap_or(default.verified_above),
+            hold_hours: env::var("REFUND_ADDRESS_HOLD_HOURS")
+                .map(|v| {
+                    v.parse()
+                        .expect("REFUND_ADDRESS_HOLD_HOURS must be a number")
+                })
+                .unwrap_or(default.hold_hours),
+        }
+    }
+}
+
+/// Wallet address in the form it's stored and compared in. A bare onion
+/// address becomes its http URL, a trailing slash is dropped.
+pub fn parse_address(address: &str) -> Result<String, Error> {
+    let address = address.trim().trim_end_matches('/');
+    if address.is_empty() {
+        return Err(Error::InvalidEntity(s!("refund address is empty")));
+    }
+    if address.chars().count() > MAX_ADDRESS_LENGTH {
+        return Err(Error::InvalidEntity(format!(
+            "refund address is longer than {} characters",
+            MAX_ADDRESS_LENGTH
+        )));
+    }
+    if !address.contains("://") {
+        let lowercase = address.to_lowercase();
+        let onion = lowercase.trim_end_matches(".onion");
+        if onion.len() == ONION_LENGTH
+            && onion
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || ('2'..='7').contains(&c))
+        {
+            return Ok(format!("http://{}.onion", onion));
+        }
+        return Err(Error::InvalidEntity(format!(
+            "refund address {} is neither a URL nor an onion address",
+            address
+        )));
+    }
+    let uri = address
+        .parse::<Uri>()
+        .map_err(|_| Error::InvalidEntity(format!("refund address {} isn't a URL", address)))?;
+    match uri.scheme_str() {
+        Some("http") | Some("https") if uri.host().is_some() => Ok(address.to_owned()),
+        _ => Err(Error::InvalidEntity(format!(
+            "refund address {} should be an http or https URL",
+            address
+        ))),
+    }
+}
+
+pub fn new_address(
+    merchant_id: &str,
+    label: &str,
+    address: &str,
+    now: NaiveDateTime,
+    config: &RefundAddressConfig,
+) -> Result<RefundAddress, Error> {
+    let label = label.trim();
+    if label.is_empty() {
+        return Err(Error::InvalidEntity(s!("label is empty")));
+    }
+    if label.chars().count() > MAX_LABEL_LENGTH {
+        return Err(Error::InvalidEntity(format!(
+            "label is longer than {} characters",
+            MAX_LABEL_LENGTH
+        )));
+    }
+    Ok(RefundAddress {
+        id: Uuid::new_v4(),
+        merchant_id: merchant_id.to_owned(),
+        label: label.to_owned(),
+        address: parse_address(address)?,
+        created_at: now,
+        verified_at: now + Duration::hours(config.hold_hours),
+    })
+}
+
+/// Whether a refund of `grin_amount` may be returned to `address`, which
+/// is already parsed
+pub fn check_destination(
+    grin_amount: i64,
+    address: &str,
+    book: &[RefundAddress],
+    now: NaiveDateTime,
+    config: &RefundAddressConfig,
+) -> Result<(), Error> {
+    if grin_amount <= config.verified_above {
+        return Ok(());
+    }
+    match book.iter().find(|entry| entry.address == address) {
+        Some(entry) if entry.is_verified(now) => Ok(()),
+        Some(entry) => Err(Error::UnverifiedRefundAddress(format!(
+            "{} is verified from {} UTC",
+            entry.address,
+            entry.verified_at.format("%Y-%m-%d %H:%M")
+        ))),
+        None => Err(Error::UnverifiedRefundAddress(format!(
+            "{} isn't in the refund address book",
+            address
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(
+            parse_address(" https://wallet.example.com:3415/ ").unwrap(),
+            "https://wallet.example.com:3415"
+        );
+        let onion = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd";
+        assert_eq!(
+            parse_address(&format!("{}.onion", onion)).unwrap(),
+            format!("http://{}.onion", onion)
+        );
+        assert_eq!(
+            parse_address(&onion.to_uppercase()).unwrap(),
+            format!("http://{}.onion", onion)
+        );
+        assert!(parse_address("").is_err());
+        assert!(parse_address("wallet.example.com").is_err());
+        assert!(parse_address("ftp://wallet.example.com").is_err());
+        assert!(parse_address(&"a".repeat(ONION_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn test_check_destination() {
+        let config = RefundAddressConfig {
+            verified_above: 1_000,
+            hold_hours: 24,
+        };
+        let now = NaiveDate::from_ymd(2019, 7, 23).and_hms(7, 0, 0);
+        let verified = new_address(
+            "shop",
+            "Cold wallet",
+            "https://a.example.com",
+            now - Duration::days(2),
+            &config,
+        )
+        .unwrap();
+        let held = new_address("shop", "New", "https://b.example.com", now, &config).unwrap();
+        let book = vec![verified, held];
+
+        assert!(check_destination(1_000, "https://c.example.com", &book, now, &config).is_ok());
+        assert!(check_destination(1_001, "https://a.example.com", &book, now, &config).is_ok());
+        assert_eq!(
+            check_destination(1_001, "https://b.example.com", &book, now, &config)
+                .unwrap_err()
+                .code(),
+            "unverified_refund_address"
+        );
+        assert!(check_destination(
+            1_001,
+            "https://b.example.com",
+            &book,
+            now + Duration::hours(24),
+            &config
+        )
+        .is_ok());
+        assert!(check_destination(1_001, "https://c.example.com", &book, now, &config).is_err());
+        assert!(new_address("shop", " ", "https://a.example.com", now, &config).is_err());
+    }
+}
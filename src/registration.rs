@@ -0,0 +1,90 @@
+//! Who may create a merchant with `POST /merchants`.
+//!
+//! `REGISTRATION_POLICY` is `closed` by default, only admins create
+//! merchants then. With `invite` a new merchant needs an invite code made
+//! by an admin, with `open` anybody can register, which needs the CAPTCHA
+//! to be enabled. Admins can create merchants under every policy.
+
+use crate::errors::Error;
+use data_encoding::BASE32_NOPAD;
+use rand::{thread_rng, Rng};
+use std::env;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Registration {
+    Closed,
+    Invite,
+    Open,
+}
+
+impl Registration {
+    /// Reads REGISTRATION_POLICY
+    pub fn from_env() -> Self {
+        let policy = env::var("REGISTRATION_POLICY").unwrap_or(s!("closed"));
+        match policy.as_str() {
+            "closed" => Registration::Closed,
+            "invite" => Registration::Invite,
+            "open" => Registration::Open,
+            _ => panic!("REGISTRATION_POLICY must be closed, invite or open"),
+        }
+    }
+
+    /// Checks a registration by somebody who isn't an admin, the invite
+    /// code itself is used up when the merchant is created
+    pub fn check(&self, invite_code: Option<&str>) -> Result<(), Error> {
+        match self {
+            Registration::Closed => Err(Error::AdminRequired),
+            Registration::Invite => invite_code
+                .filter(|code| !code.is_empty())
+                .map(|_| ())
+                .ok_or_else(|| Error::InvalidEntity(s!("invite_code is required"))),
+            Registration::Open => Ok(()),
+        }
+    }
+}
+
+/// 16 characters, easy to type and to read out
+pub fn new_invite_code() -> String {
+    BASE32_NOPAD.encode(&thread_rng().gen::<[u8; 10]>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::InviteCode;
+    use chrono::{Duration, Utc};
+
+    #[test]
+    fn test_invite_code() {
+        assert_eq!(new_invite_code().len(), 16);
+        assert_ne!(new_invite_code(), new_invite_code());
+        let now = Utc::now().naive_utc();
+        let mut invite = InviteCode {
+            code: new_invite_code(),
+            max_uses: 2,
+            uses: 1,
+            expires_at: None,
+            created_by: s!("admin"),
+            created_at: now,
+        };
+        assert!(invite.is_usable(now));
+        invite.expires_at = Some(now - Duration::seconds(1));
+        assert!(!invite.is_usable(now));
+        invite.expires_at = Some(now + Duration::days(1));
+        assert!(invite.is_usable(now));
+        invite.uses = 2;
+        assert!(!invite.is_usable(now));
+    }
+
+    #[test]
+    fn test_check() {
+        match Registration::Closed.check(Some("CODE")) {
+            Err(Error::AdminRequired) => {}
+            res => panic!("closed registration allowed: {:?}", res),
+        }
+        assert!(Registration::Invite.check(Some("CODE")).is_ok());
+        assert!(Registration::Invite.check(Some("")).is_err());
+        assert!(Registration::Invite.check(None).is_err());
+        assert!(Registration::Open.check(None).is_ok());
+    }
+}
@@ -0,0 +1,312 @@
+//! Pending callbacks across a database restore, `knockturn retry-queue`.
+//!
+//! `export` writes the callbacks the gateway still owes merchants to a
+//! JSON file: unreported confirmed, rejected and refunded payments, status
+//! changes of merchants with verbose callbacks and undelivered payout
+//! events. After the database was restored from a backup `import` puts
+//! them back into the outbox. Every entry is checked against the restored
+//! transaction first, one which is gone, belongs to another merchant, is
+//! in another status or was reported meanwhile is skipped, so nothing is
+//! reported about a state the database doesn't have. Payments which ran
+//! out of retries are due again.
+
+use crate::errors::Error;
+use crate::jobs;
+use crate::models::{
+    PayoutEvent, PayoutEventType, Transaction, TransactionStatus, TransactionType,
+};
+use crate::schema::{jobs as jobs_table, merchants, payout_events, transactions};
+use chrono::NaiveDateTime;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Files of another version are refused
+pub const RETRY_QUEUE_VERSION: u32 = 1;
+
+/// Payment statuses the merchant is called back about once
+const FINAL_STATUSES: &[TransactionStatus] = &[
+    TransactionStatus::Confirmed,
+    TransactionStatus::Rejected,
+    TransactionStatus::Refund,
+];
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RetryQueue {
+    pub version: u32,
+    pub exported_at: NaiveDateTime,
+    pub entries: Vec<RetryEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RetryEntry {
+    /// Callback of a confirmed, rejected or refunded payment
+    Payment {
+        transaction_id: Uuid,
+        merchant_id: String,
+        status: TransactionStatus,
+    },
+    /// Verbose callback of a pending or in chain payment
+    StatusChange {
+        transaction_id: Uuid,
+        merchant_id: String,
+        status: TransactionStatus,
+    },
+    PayoutEvent {
+        transaction_id: Uuid,
+        merchant_id: String,
+        event: PayoutEventType,
+        created_at: NaiveDateTime,
+    },
+}
+
+impl RetryEntry {
+    pub fn transaction_id(&self) -> Uuid {
+        match self {
+            RetryEntry::Payment { transaction_id, .. }
+            | RetryEntry::StatusChange { transaction_id, .. }
+            | RetryEntry::PayoutEvent { transaction_id, .. } => *transaction_id,
+        }
+    }
+
+    /// Why the entry can't be queued against the transaction as it is now
+    pub fn check(&self, current: Option<&Transaction>) -> Result<(), String> {
+        let tx = match current {
+            Some(tx) => tx,
+            None => return Err(s!("the transaction doesn't exist")),
+        };
+        let (merchant_id, transaction_type) = match self {
+            RetryEntry::Payment { merchant_id, .. }
+            | RetryEntry::StatusChange { merchant_id, .. } => {
+                (merchant_id, TransactionType::Payment)
+            }
+            RetryEntry::PayoutEvent { merchant_id, .. } => (merchant_id, TransactionType::Payout),
+        };
+        if tx.merchant_id != *merchant_id {
+            return Err(format!("the transaction belongs to {}", tx.merchant_id));
+        }
+        if tx.transaction_type != transaction_type {
+            return Err(format!("the transaction is a {:?}", tx.transaction_type));
+        }
+        match self {
+            RetryEntry::Payment { status, .. } | RetryEntry::StatusChange { status, .. }
+                if tx.status != *status =>
+            {
+                Err(format!("the payment is {} now", tx.status))
+            }
+            RetryEntry::Payment { .. } if tx.reported => Err(s!("the payment was reported")),
+            RetryEntry::StatusChange { status, .. } if tx.reported_status == Some(*status) => {
+                Err(s!("the status was reported"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub queued: usize,
+    /// Entries left out and why
+    pub skipped: Vec<(Uuid, String)>,
+}
+
+/// Callbacks owed to merchants as of `now`
+pub fn export(conn: &PgConnection, now: NaiveDateTime) -> Result<RetryQueue, Error> {
+    let payments: Vec<Transaction> = transactions::table
+        .filter(transactions::transaction_type.eq(TransactionType::Payment))
+        .filter(transactions::status.eq_any(FINAL_STATUSES.to_vec()))
+        .filter(transactions::reported.eq(false))
+        .order(transactions::created_at.asc())
+        .load(conn)?;
+    let status_changes: Vec<Transaction> = transactions::table
+        .filter(transactions::transaction_type.eq(TransactionType::Payment))
+        .filter(
+            transactions::status
+                .eq_any(vec![TransactionStatus::Pending, TransactionStatus::InChain]),
+        )
+        .filter(
+            transactions::reported_status
+                .is_null()
+                .or(transactions::reported_status.ne(transactions::status.nullable())),
+        )
+        .filter(
+            transactions::merchant_id.eq_any(
+                merchants::table
+                    .select(merchants::id)
+                    .filter(merchants::verbose_callbacks)
+                    .filter(merchants::callback_url.is_not_null()),
+            ),
+        )
+        .order(transactions::created_at.asc())
+        .load(conn)?;
+    let events: Vec<PayoutEvent> = payout_events::table
+        .filter(payout_events::delivered_at.is_null())
+        .order(payout_events::created_at.asc())
+        .load(conn)?;
+
+    let mut entries: Vec<RetryEntry> = payments
+        .into_iter()
+        .map(|tx| RetryEntry::Payment {
+            transaction_id: tx.id,
+            merchant_id: tx.merchant_id,
+            status: tx.status,
+        })
+        .collect();
+    entries.extend(
+        status_changes
+            .into_iter()
+            .map(|tx| RetryEntry::StatusChange {
+                transaction_id: tx.id,
+                merchant_id: tx.merchant_id,
+                status: tx.status,
+            }),
+    );
+    for event in events {
+        let event_type = event.event.parse::<PayoutEventType>().map_err(|_| {
+            Error::General(format!(
+                "unknown payout event {} of {}",
+                event.event, event.id
+            ))
+        })?;
+        entries.push(RetryEntry::PayoutEvent {
+            transaction_id: event.transaction_id,
+            merchant_id: event.merchant_id,
+            event: event_type,
+            created_at: event.created_at,
+        });
+    }
+    Ok(RetryQueue {
+        version: RETRY_QUEUE_VERSION,
+        exported_at: now,
+        entries,
+    })
+}
+
+/// Queues the entries which still hold in one DB transaction
+pub fn import(
+    conn: &PgConnection,
+    queue: &RetryQueue,
+    now: NaiveDateTime,
+) -> Result<ImportSummary, Error> {
+    if queue.version != RETRY_QUEUE_VERSION {
+        return Err(Error::InvalidEntity(format!(
+            "retry queue version {}, expected {}",
+            queue.version, RETRY_QUEUE_VERSION
+        )));
+    }
+    let ids: Vec<Uuid> = queue
+        .entries
+        .iter()
+        .map(RetryEntry::transaction_id)
+        .collect();
+    conn.transaction(|| {
+        let current: HashMap<Uuid, Transaction> = transactions::table
+            .filter(transactions::id.eq_any(ids))
+            .load::<Transaction>(conn)?
+            .into_iter()
+            .map(|tx| (tx.id, tx))
+            .collect();
+        let mut summary = ImportSummary::default();
+        for entry in &queue.entries {
+            let tx = current.get(&entry.transaction_id());
+            if let Err(reason) = entry.check(tx) {
+                summary.skipped.push((entry.transaction_id(), reason));
+                continue;
+            }
+            let tx = tx.unwrap();
+            match entry {
+                RetryEntry::Payment { .. } => {
+                    // Due again even if it ran out of retries
+                    diesel::update(transactions::table.find(tx.id))
+                        .set(transactions::next_report_attempt.eq(now))
+                        .execute(conn)?;
+                    diesel::insert_into(jobs_table::table)
+                        .values(&jobs::report_payment(tx.id))
+                        .on_conflict_do_nothing()
+                        .execute(conn)?;
+                }
+                RetryEntry::StatusChange { .. } => {
+                    diesel::insert_into(jobs_table::table)
+                        .values(&jobs::report_status(tx))
+                        .on_conflict_do_nothing()
+                        .execute(conn)?;
+                }
+                RetryEntry::PayoutEvent {
+                    event, created_at, ..
+                } => {
+                    let queued: i64 = payout_events::table
+                        .filter(payout_events::transaction_id.eq(tx.id))
+                        .filter(payout_events::event.eq(event.to_string()))
+                        .filter(payout_events::delivered_at.is_null())
+                        .count()
+                        .get_result(conn)?;
+                    if queued > 0 {
+                        summary
+                            .skipped
+                            .push((tx.id, s!("the event is already queued")));
+                        continue;
+                    }
+                    let mut payout_event = PayoutEvent::new(tx, *event);
+                    payout_event.created_at = *created_at;
+                    diesel::insert_into(payout_events::table)
+                        .values(&payout_event)
+                        .execute(conn)?;
+                }
+            }
+            summary.queued += 1;
+        }
+        Ok(summary)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::tests::create_tx;
+
+    #[test]
+    fn test_check() {
+        let mut tx = create_tx();
+        tx.merchant_id = s!("shop");
+        tx.transaction_type = TransactionType::Payment;
+        tx.status = TransactionStatus::Confirmed;
+        tx.reported = false;
+        let entry = RetryEntry::Payment {
+            transaction_id: tx.id,
+            merchant_id: s!("shop"),
+            status: TransactionStatus::Confirmed,
+        };
+        assert!(entry.check(Some(&tx)).is_ok());
+        assert!(entry.check(None).is_err());
+
+        let mut restored = tx.clone();
+        restored.status = TransactionStatus::Pending;
+        assert_eq!(
+            entry.check(Some(&restored)),
+            Err(s!("the payment is Pending now"))
+        );
+        restored = tx.clone();
+        restored.reported = true;
+        assert!(entry.check(Some(&restored)).is_err());
+        restored = tx.clone();
+        restored.merchant_id = s!("other");
+        assert!(entry.check(Some(&restored)).is_err());
+
+        let event = RetryEntry::PayoutEvent {
+            transaction_id: tx.id,
+            merchant_id: s!("shop"),
+            event: PayoutEventType::Confirmed,
+            created_at: tx.created_at,
+        };
+        assert!(event.check(Some(&tx)).is_err());
+        tx.transaction_type = TransactionType::Payout;
+        assert!(event.check(Some(&tx)).is_ok());
+
+        let json = serde_json::to_string(&event).unwrap();
+        assert!(json.contains("\"kind\":\"payout_event\""));
+        assert_eq!(serde_json::from_str::<RetryEntry>(&json).unwrap(), event);
+    }
+}
@@ -0,0 +1,155 @@
+//! Signed payment result appended to the merchant's `redirect_url`, so the
+//! shop can trust where the buyer came back from without calling us.
+//!
+//! The buyer is sent to `redirect_url` with these query parameters added:
+//! `transaction_id`, `status`, `amount` (in nanogrins), `nonce`, `issued_at`
+//! (unix time in seconds) and `signature`.
+//!
+//! `signature` is hex encoded HMAC-SHA256 keyed with the merchant's API token
+//! over `transaction_id|status|amount|nonce|issued_at`. A merchant should
+//! reject payloads older than `MAX_AGE_SECONDS` or issued more than
+//! `MAX_CLOCK_SKEW_SECONDS` ahead of its clock and remember nonces seen in
+//! that window, so a return link can't be replayed. The verify endpoint does
+//! both, it keeps used nonces in `used_return_nonces` until their payloads
+//! expired.
+
+use crate::errors::Error;
+use consistenttime::ct_u8_slice_eq;
+use data_encoding::HEXLOWER;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// How long a signed return payload is considered valid
+pub const MAX_AGE_SECONDS: i64 = 10 * 60;
+/// How far ahead of the verifier's clock a payload may be issued
+pub const MAX_CLOCK_SKEW_SECONDS: i64 = 60;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ReturnPayload {
+    pub transaction_id: Uuid,
+    pub status: String,
+    pub amount: i64,
+    pub nonce: String,
+    pub issued_at: i64,
+    pub signature: String,
+}
+
+impl ReturnPayload {
+    pub fn new(
+        transaction_id: Uuid,
+        status: String,
+        amount: i64,
+        issued_at: i64,
+        secret: &str,
+    ) -> Result<Self, Error> {
+        let mut payload = ReturnPayload {
+            transaction_id,
+            status,
+            amount,
+            nonce: HEXLOWER.encode(&thread_rng().gen::<[u8; 16]>()),
+            issued_at,
+            signature: String::new(),
+        };
+        payload.signature = HEXLOWER.encode(&hmac(secret, &payload.message())?);
+        Ok(payload)
+    }
+
+    fn message(&self) -> String {
+        format!(
+            "{}|{}|{}|{}|{}",
+            self.transaction_id, self.status, self.amount, self.nonce, self.issued_at
+        )
+    }
+
+    pub fn verify(&self, secret: &str, now: i64) -> Result<(), Error> {
+        let expected = hmac(secret, &self.message())?;
+        let signature = HEXLOWER
+            .decode(self.signature.as_bytes())
+            .map_err(|_| Error::InvalidEntity(s!("malformed signature")))?;
+        if signature.len() != expected.len() || !ct_u8_slice_eq(&signature, &expected) {
+            return Err(Error::InvalidEntity(s!("wrong signature")));
+        }
+        if now - self.issued_at > MAX_AGE_SECONDS {
+            return Err(Error::InvalidEntity(s!("signature expired")));
+        }
+        // A payload from the future would outlive its nonce
+        if self.issued_at - now > MAX_CLOCK_SKEW_SECONDS {
+            return Err(Error::InvalidEntity(s!("signature issued in the future")));
+        }
+        Ok(())
+    }
+
+    /// Appends the payload to the merchant's redirect url
+    pub fn append_to(&self, redirect_url: &str) -> Result<String, Error> {
+        let query = serde_urlencoded::to_string(self).map_err(|e| Error::General(s!(e)))?;
+        let separator = if redirect_url.contains('?') { '&' } else { '?' };
+        Ok(format!("{}{}{}", redirect_url, separator, query))
+    }
+}
+
+/// HMAC-SHA256 of `message` keyed with `secret`
+pub fn hmac(secret: &str, message: &str) -> Result<Vec<u8>, Error> {
+    let key = PKey::hmac(secret.as_bytes()).map_err(|e| Error::General(s!(e)))?;
+    let mut signer =
+        Signer::new(MessageDigest::sha256(), &key).map_err(|e| Error::General(s!(e)))?;
+    signer
+        .update(message.as_bytes())
+        .map_err(|e| Error::General(s!(e)))?;
+    signer.sign_to_vec().map_err(|e| Error::General(s!(e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload() -> ReturnPayload {
+        ReturnPayload::new(
+            Uuid::parse_str("6f3c4b3e-8c2d-4b3a-9a8e-2b1f0f6a7d11").unwrap(),
+            s!("Confirmed"),
+            1_000_000_000,
+            1_560_000_000,
+            "secret",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let payload = payload();
+        assert!(payload.verify("secret", 1_560_000_060).is_ok());
+        assert!(payload.verify("another secret", 1_560_000_060).is_err());
+        assert!(payload
+            .verify("secret", 1_560_000_000 + MAX_AGE_SECONDS + 1)
+            .is_err());
+        assert!(payload
+            .verify("secret", 1_560_000_000 - MAX_CLOCK_SKEW_SECONDS)
+            .is_ok());
+        assert!(payload
+            .verify("secret", 1_560_000_000 - MAX_CLOCK_SKEW_SECONDS - 1)
+            .is_err());
+
+        let mut tampered = payload.clone();
+        tampered.amount = 1;
+        assert!(tampered.verify("secret", 1_560_000_060).is_err());
+    }
+
+    #[test]
+    fn test_append_to() {
+        let payload = payload();
+        let url = payload
+            .append_to("https://shop.com/return?order=1")
+            .unwrap();
+        assert_eq!(
+            url,
+            format!(
+                "https://shop.com/return?order=1&transaction_id=6f3c4b3e-8c2d-4b3a-9a8e-2b1f0f6a7d11\
+                 &status=Confirmed&amount=1000000000&nonce={}&issued_at=1560000000&signature={}",
+                payload.nonce, payload.signature
+            )
+        );
+    }
+}
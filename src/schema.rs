@@ -2,6 +2,88 @@ table! {
     use diesel::sql_types::*;
     use crate::models::Transaction_status;
     use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    api_requests (id) {
+        id -> Uuid,
+        merchant_id -> Nullable<Text>,
+        method -> Text,
+        path -> Text,
+        status_code -> Int4,
+        latency_ms -> Int8,
+        error -> Nullable<Text>,
+        created_at -> Timestamp,
+        country -> Nullable<Text>,
+        asn -> Nullable<Int8>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    api_tokens (id) {
+        id -> Uuid,
+        merchant_id -> Text,
+        name -> Text,
+        token_hash -> Text,
+        scopes -> Array<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    balance_credits (id) {
+        id -> Uuid,
+        transaction_id -> Uuid,
+        merchant_id -> Text,
+        grin_amount -> Int8,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    blocks (height) {
+        height -> Int8,
+        hash -> Text,
+        previous -> Text,
+        timestamp -> Nullable<Timestamp>,
+        synced_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    cron_jobs (name) {
+        name -> Text,
+        locked_by -> Nullable<Text>,
+        locked_until -> Nullable<Timestamp>,
+        last_started_at -> Nullable<Timestamp>,
+        last_finished_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
 
     current_height (height) {
         height -> Int8,
@@ -12,6 +94,88 @@ table! {
     use diesel::sql_types::*;
     use crate::models::Transaction_status;
     use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    denied_networks (network) {
+        network -> Text,
+        reason -> Text,
+        created_by -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    feature_flag_overrides (name, merchant_id) {
+        name -> Text,
+        merchant_id -> Text,
+        enabled -> Bool,
+        updated_by -> Text,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    feature_flags (name) {
+        name -> Text,
+        enabled -> Bool,
+        rollout_percent -> Int4,
+        updated_by -> Text,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    invite_codes (code) {
+        code -> Text,
+        max_uses -> Int4,
+        uses -> Int4,
+        expires_at -> Nullable<Timestamp>,
+        created_by -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    jobs (id) {
+        id -> Uuid,
+        job_type -> Text,
+        key -> Nullable<Text>,
+        payload -> Jsonb,
+        run_at -> Timestamp,
+        attempts -> Int4,
+        last_error -> Nullable<Text>,
+        locked_by -> Nullable<Text>,
+        locked_until -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        failed_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
 
     merchants (id) {
         id -> Text,
@@ -24,6 +188,46 @@ table! {
         callback_url -> Nullable<Text>,
         token_2fa -> Nullable<Varchar>,
         confirmed_2fa -> Bool,
+        second_factor -> Second_factor,
+        oidc_subject -> Nullable<Text>,
+        is_admin -> Bool,
+        payout_callback_url -> Nullable<Text>,
+        email_logo_url -> Nullable<Text>,
+        email_footer -> Nullable<Text>,
+        email_reply_to -> Nullable<Text>,
+        timezone -> Text,
+        invoice_prefix -> Text,
+        invoice_sequence -> Int8,
+        callback_timeout_seconds -> Int4,
+        callback_headers -> Nullable<Jsonb>,
+        callback_verify_tls -> Bool,
+        telegram_chat_id -> Nullable<Text>,
+        slack_webhook_url -> Nullable<Text>,
+        callback_template -> Nullable<Jsonb>,
+        slate_message_check -> Text,
+        verbose_callbacks -> Bool,
+        callback_max_attempts -> Nullable<Int4>,
+        callback_backoff_seconds -> Nullable<Int4>,
+        callback_retry_window_seconds -> Nullable<Int4>,
+        plan -> Text,
+        payment_reminder_minutes -> Nullable<Int4>,
+        platform_id -> Nullable<Text>,
+        referrer_id -> Nullable<Text>,
+        referral_share_bps -> Nullable<Int4>,
+        pending_balance -> Int8,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    payment_splits (transaction_id, merchant_id) {
+        transaction_id -> Uuid,
+        merchant_id -> Text,
+        share_bps -> Int4,
     }
 }
 
@@ -31,10 +235,92 @@ table! {
     use diesel::sql_types::*;
     use crate::models::Transaction_status;
     use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    payout_batches (id) {
+        id -> Uuid,
+        size -> Int4,
+        created_at -> Timestamp,
+        processed_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    payout_events (id) {
+        id -> Uuid,
+        transaction_id -> Uuid,
+        merchant_id -> Text,
+        event -> Text,
+        created_at -> Timestamp,
+        delivered_at -> Nullable<Timestamp>,
+        attempts -> Int4,
+        next_attempt -> Nullable<Timestamp>,
+        notified_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    pending_credits (id) {
+        id -> Uuid,
+        transaction_id -> Uuid,
+        merchant_id -> Text,
+        grin_amount -> Int8,
+        created_at -> Timestamp,
+        available_at -> Timestamp,
+        available_height -> Int8,
+        held -> Bool,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    plans (name) {
+        name -> Text,
+        monthly_payments -> Nullable<Int8>,
+        max_payment_amount -> Nullable<Int8>,
+        min_callback_backoff_seconds -> Int4,
+        api_burst -> Int4,
+        api_per_minute -> Int4,
+        fee_bps -> Int4,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    rate_limit_buckets (merchant_id) {
+        merchant_id -> Text,
+        tokens -> Float8,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
 
     rates (id) {
         id -> Text,
-        rate -> Float8,
+        rate -> Numeric,
         updated_at -> Timestamp,
     }
 }
@@ -43,6 +329,73 @@ table! {
     use diesel::sql_types::*;
     use crate::models::Transaction_status;
     use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    reconciliation_orphans (id) {
+        id -> Uuid,
+        source -> Text,
+        slate_id -> Text,
+        wallet_tx_id -> Nullable<Int8>,
+        transaction_id -> Nullable<Uuid>,
+        grin_amount -> Int8,
+        found_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    refund_addresses (id) {
+        id -> Uuid,
+        merchant_id -> Text,
+        label -> Text,
+        address -> Text,
+        created_at -> Timestamp,
+        verified_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    security_events (id) {
+        id -> Uuid,
+        merchant_id -> Text,
+        kind -> Text,
+        ip -> Nullable<Text>,
+        created_at -> Timestamp,
+        emailed_at -> Nullable<Timestamp>,
+        dismissed -> Bool,
+        country -> Nullable<Text>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    transaction_notes (id) {
+        id -> Uuid,
+        transaction_id -> Uuid,
+        author -> Text,
+        body -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
 
     transactions (id) {
         id -> Uuid,
@@ -69,6 +422,34 @@ table! {
         height -> Nullable<Int8>,
         commit -> Nullable<Text>,
         redirect_url -> Nullable<Text>,
+        exchange_rate -> Nullable<Numeric>,
+        rate_locked_until -> Nullable<Timestamp>,
+        requotes -> Int4,
+        metadata -> Nullable<Jsonb>,
+        expires_at -> Nullable<Timestamp>,
+        confirmed_by_wallet -> Bool,
+        seen_in_pool_at -> Nullable<Timestamp>,
+        payout_batch_id -> Nullable<Uuid>,
+        receipt_sent_at -> Nullable<Timestamp>,
+        receipt_opt_in -> Bool,
+        output_selection -> Nullable<Jsonb>,
+        amount_tag -> Nullable<Int8>,
+        invoice_number -> Nullable<Text>,
+        payer_public_key -> Nullable<Text>,
+        reported_status -> Nullable<Transaction_status>,
+        refund_reason -> Nullable<Text>,
+        slate_version -> Nullable<Int4>,
+        payer_user_agent -> Nullable<Text>,
+        rate_updated_at -> Nullable<Timestamp>,
+        payer_country -> Nullable<Text>,
+        payer_asn -> Nullable<Int8>,
+        processing_until -> Nullable<Timestamp>,
+        reminder_sent_at -> Nullable<Timestamp>,
+        refund_address -> Nullable<Text>,
+        referrer_id -> Nullable<Text>,
+        referral_fee -> Nullable<Int8>,
+        claimed_slate_id -> Nullable<Text>,
+        payer_message_unverified -> Bool,
     }
 }
 
@@ -76,6 +457,7 @@ table! {
     use diesel::sql_types::*;
     use crate::models::Transaction_status;
     use crate::models::Transaction_type;
+    use crate::models::Second_factor;
 
     txs (slate_id) {
         slate_id -> Text,
@@ -92,13 +474,98 @@ table! {
     }
 }
 
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    used_return_nonces (merchant_id, nonce) {
+        merchant_id -> Text,
+        nonce -> Text,
+        used_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    view_refreshes (name) {
+        name -> Text,
+        refreshed_at -> Timestamp,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::models::Transaction_status;
+    use crate::models::Transaction_type;
+    use crate::models::Second_factor;
+
+    webauthn_credentials (id) {
+        id -> Text,
+        merchant_id -> Text,
+        name -> Text,
+        public_key -> Bytea,
+        sign_count -> Int8,
+        created_at -> Timestamp,
+    }
+}
+
+joinable!(api_tokens -> merchants (merchant_id));
+joinable!(balance_credits -> merchants (merchant_id));
+joinable!(balance_credits -> transactions (transaction_id));
+joinable!(denied_networks -> merchants (created_by));
+joinable!(feature_flags -> merchants (updated_by));
+joinable!(invite_codes -> merchants (created_by));
+joinable!(merchants -> plans (plan));
+joinable!(payment_splits -> merchants (merchant_id));
+joinable!(payment_splits -> transactions (transaction_id));
+joinable!(payout_events -> merchants (merchant_id));
+joinable!(payout_events -> transactions (transaction_id));
+joinable!(pending_credits -> merchants (merchant_id));
+joinable!(pending_credits -> transactions (transaction_id));
+joinable!(rate_limit_buckets -> merchants (merchant_id));
+joinable!(refund_addresses -> merchants (merchant_id));
+joinable!(security_events -> merchants (merchant_id));
+joinable!(transaction_notes -> merchants (author));
+joinable!(transaction_notes -> transactions (transaction_id));
 joinable!(transactions -> merchants (merchant_id));
+joinable!(transactions -> payout_batches (payout_batch_id));
 joinable!(txs -> transactions (order_id));
+joinable!(used_return_nonces -> merchants (merchant_id));
+joinable!(webauthn_credentials -> merchants (merchant_id));
 
 allow_tables_to_appear_in_same_query!(
+    api_requests,
+    api_tokens,
+    balance_credits,
+    blocks,
+    cron_jobs,
     current_height,
+    denied_networks,
+    feature_flag_overrides,
+    feature_flags,
+    invite_codes,
+    jobs,
     merchants,
+    payment_splits,
+    payout_batches,
+    payout_events,
+    pending_credits,
+    plans,
+    rate_limit_buckets,
     rates,
+    reconciliation_orphans,
+    refund_addresses,
+    security_events,
+    transaction_notes,
     transactions,
     txs,
+    used_return_nonces,
+    view_refreshes,
+    webauthn_credentials,
 );
@@ -0,0 +1,188 @@
+//! Security events of merchant accounts.
+//!
+//! Dashboard logins, failed ones included, 2FA resets and changes of the
+//! refund address book are recorded in `security_events` with the client's
+//! IP and, with `geoip` enabled, its country. Some of them raise an alert:
+//! `FAILED_LOGIN_THRESHOLD` failed logins within
+//! `FAILED_LOGIN_WINDOW_MINUTES`, a login from a country (or without GeoIP
+//! an IP) the merchant never logged in from before, not on the first
+//! login, a 2FA reset and a new refund address. Alerts are emailed to the merchant by a
+//! cron job and shown as a banner on the dashboard until dismissed.
+
+use crate::db::{DbExecutor, GetUnsentSecurityAlerts, MarkSecurityAlertEmailed};
+use crate::errors::Error;
+use crate::mailer::{Email, Mailer, SendEmail};
+use crate::models::{Merchant, SecurityEvent, SecurityEventKind};
+use actix::Addr;
+use askama::Template;
+use futures::future::{join_all, result, Future};
+use log::error;
+
+pub const FAILED_LOGIN_THRESHOLD: i64 = 5;
+pub const FAILED_LOGIN_WINDOW_MINUTES: i64 = 15;
+/// Number of alerts emailed in one cron run
+const ALERTS_BATCH_SIZE: i64 = 50;
+
+/// IP and country of earlier successful logins
+pub type KnownLocation = (Option<String>, Option<String>);
+
+/// Alert raised by a login attempt. `recent_failures` counts the failed
+/// logins of the window including this one.
+pub fn login_alert(
+    success: bool,
+    recent_failures: i64,
+    known: &[KnownLocation],
+    ip: Option<&str>,
+    country: Option<&str>,
+) -> Option<SecurityEventKind> {
+    if !success {
+        // Once per burst, not for every failure after the threshold
+        if recent_failures == FAILED_LOGIN_THRESHOLD {
+            return Some(SecurityEventKind::RepeatedFailedLogins);
+        }
+        return None;
+    }
+    if known.is_empty() {
+        return None;
+    }
+    // A new IP in a known country is the same ISP handing out another one.
+    // Countries are compared only once earlier logins have them, i.e. not
+    // right after GeoIP was enabled.
+    let countries_known = known.iter().any(|(_, country)| country.is_some());
+    let seen = match (country, ip) {
+        (Some(country), _) if countries_known => known
+            .iter()
+            .any(|(_, known)| known.as_ref().map(String::as_str) == Some(country)),
+        (_, Some(ip)) => known
+            .iter()
+            .any(|(known, _)| known.as_ref().map(String::as_str) == Some(ip)),
+        _ => true,
+    };
+    if seen {
+        None
+    } else {
+        Some(SecurityEventKind::NewLocation)
+    }
+}
+
+#[derive(Template)]
+#[template(path = "emails/security_alert.html")]
+struct SecurityAlertEmail<'a> {
+    merchant_id: &'a str,
+    description: &'a str,
+    time: String,
+    ip: Option<&'a str>,
+    country: Option<&'a str>,
+}
+
+pub fn alert_email(merchant: &Merchant, event: &SecurityEvent) -> Result<Email, Error> {
+    let description = event.description();
+    let html = SecurityAlertEmail {
+        merchant_id: &merchant.id,
+        description,
+        time: event.created_at.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        ip: event.ip.as_ref().map(String::as_str),
+        country: event.country.as_ref().map(String::as_str),
+    }
+    .render()?;
+    Ok(Email {
+        to: merchant.email.clone(),
+        reply_to: None,
+        subject: format!("Security alert: {}", description),
+        html,
+    })
+}
+
+/// Emails alerts which weren't sent yet, returns how many were sent
+pub fn send_alerts(
+    db: Addr<DbExecutor>,
+    mailer: Addr<Mailer>,
+) -> impl Future<Item = usize, Error = Error> {
+    db.send(GetUnsentSecurityAlerts {
+        limit: ALERTS_BATCH_SIZE,
+    })
+    .from_err()
+    .and_then(|db_response| {
+        let alerts = db_response?;
+        Ok(alerts)
+    })
+    .and_then(move |alerts| {
+        let futures: Vec<_> = alerts
+            .into_iter()
+            .map(|(event, merchant)| send_alert(db.clone(), mailer.clone(), event, merchant))
+            .collect();
+        join_all(futures).map(|sent| sent.into_iter().filter(|sent| *sent).count())
+    })
+}
+
+/// Never fails, an alert which wasn't sent is retried on the next run
+fn send_alert(
+    db: Addr<DbExecutor>,
+    mailer: Addr<Mailer>,
+    event: SecurityEvent,
+    merchant: Merchant,
+) -> impl Future<Item = bool, Error = Error> {
+    let id = event.id;
+    result(alert_email(&merchant, &event))
+        .and_then(move |email| {
+            mailer
+                .send(SendEmail(email))
+                .from_err()
+                .and_then(|mailer_response| mailer_response)
+        })
+        .and_then(move |_| {
+            db.send(MarkSecurityAlertEmailed { id })
+                .from_err()
+                .and_then(|db_response| db_response)
+        })
+        .then(move |res| match res {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                error!("Cannot email security alert {}: {}", id, e);
+                Ok(false)
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_alert() {
+        let known = vec![(Some(s!("10.0.0.1")), Some(s!("DE"))), (None, None)];
+        assert_eq!(login_alert(true, 0, &known, Some("10.0.0.1"), None), None);
+        assert_eq!(
+            login_alert(true, 0, &known, Some("10.0.0.2"), None),
+            Some(SecurityEventKind::NewLocation)
+        );
+        assert_eq!(
+            login_alert(true, 0, &known, Some("10.0.0.2"), Some("DE")),
+            None
+        );
+        assert_eq!(
+            login_alert(true, 0, &known, Some("10.0.0.1"), Some("NL")),
+            Some(SecurityEventKind::NewLocation)
+        );
+        assert_eq!(
+            login_alert(
+                true,
+                0,
+                &[(Some(s!("10.0.0.1")), None)],
+                Some("10.0.0.1"),
+                Some("NL")
+            ),
+            None
+        );
+        // The first login has nothing to compare with
+        assert_eq!(login_alert(true, 0, &[], Some("10.0.0.2"), None), None);
+        assert_eq!(login_alert(true, 0, &known, None, None), None);
+
+        assert_eq!(login_alert(false, 4, &known, Some("10.0.0.2"), None), None);
+        assert_eq!(
+            login_alert(false, 5, &known, Some("10.0.0.2"), None),
+            Some(SecurityEventKind::RepeatedFailedLogins)
+        );
+        assert_eq!(login_alert(false, 6, &known, Some("10.0.0.2"), None), None);
+    }
+}
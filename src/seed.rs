@@ -0,0 +1,389 @@
+//! Development data, `knockturn seed`.
+//!
+//! Fills the database `DATABASE_URL` points to with merchants and their
+//! payments and payouts, so the dashboard, analytics and exports can be
+//! worked on without running payments through a wallet. Transactions are
+//! spread over every status and over the last `--days`, their fields are
+//! set like the FSM would have left them in that status. Every merchant
+//! logs in with `SEED_PASSWORD`. Rows are added next to what's already
+//! there, run it against a development database only.
+
+use crate::callback::DEFAULT_CALLBACK_TIMEOUT_SECONDS;
+use crate::errors::Error;
+use crate::models::{
+    format_invoice_number, BalanceCredit, Currency, Merchant, Money, RefundReason, SecondFactor,
+    SlateMessageCheck, Transaction, TransactionStatus, TransactionType,
+};
+use crate::plans::DEFAULT_PLAN;
+use crate::schema::{balance_credits, merchants, transactions};
+use crate::ser;
+use chrono::{Datelike, Duration, NaiveDateTime, Utc};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use rand::seq::SliceRandom;
+use rand::{thread_rng, Rng};
+use rust_decimal::Decimal;
+use std::str::FromStr;
+use uuid::Uuid;
+
+pub const DEFAULT_SEED_MERCHANTS: usize = 3;
+pub const DEFAULT_SEED_TRANSACTIONS: usize = 100;
+pub const DEFAULT_SEED_DAYS: i64 = 90;
+pub const SEED_PASSWORD: &str = "password";
+
+/// Statuses in the order transactions are given them
+const STATUSES: &[TransactionStatus] = &[
+    TransactionStatus::New,
+    TransactionStatus::Pending,
+    TransactionStatus::InChain,
+    TransactionStatus::Confirmed,
+    TransactionStatus::Rejected,
+    TransactionStatus::Initialized,
+    TransactionStatus::Refund,
+];
+/// Price of one grin the amounts are made up with
+const PRICES: &[(Currency, &str)] = &[
+    (Currency::GRIN, "1"),
+    (Currency::EUR, "0.45"),
+    (Currency::USD, "0.5"),
+    (Currency::BTC, "0.000012"),
+];
+const TOKEN_CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeedConfig {
+    pub merchants: usize,
+    /// Per merchant
+    pub transactions: usize,
+    /// Transactions are created up to that many days ago
+    pub days: i64,
+}
+
+impl Default for SeedConfig {
+    fn default() -> Self {
+        SeedConfig {
+            merchants: DEFAULT_SEED_MERCHANTS,
+            transactions: DEFAULT_SEED_TRANSACTIONS,
+            days: DEFAULT_SEED_DAYS,
+        }
+    }
+}
+
+impl SeedConfig {
+    /// Reads `--merchants=N`, `--transactions=N` and `--days=N`, defaults
+    /// are used for missing ones
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Self, Error> {
+        let mut config = SeedConfig::default();
+        for arg in args {
+            let mut parts = arg.splitn(2, '=');
+            let name = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("");
+            let number = || {
+                value
+                    .parse::<usize>()
+                    .map_err(|_| Error::InvalidEntity(format!("{} takes a number", name)))
+            };
+            match name {
+                "--merchants" => config.merchants = number()?,
+                "--transactions" => config.transactions = number()?,
+                "--days" => config.days = number()?.max(1) as i64,
+                _ => return Err(Error::InvalidEntity(format!("unknown argument {}", arg))),
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SeedSummary {
+    pub merchants: Vec<String>,
+    pub transactions: usize,
+}
+
+/// Inserts the merchants and their transactions in one DB transaction
+pub fn seed(conn: &PgConnection, config: &SeedConfig) -> Result<SeedSummary, Error> {
+    let now = Utc::now().naive_utc();
+    let password =
+        bcrypt::hash(SEED_PASSWORD, bcrypt::DEFAULT_COST).map_err(|e| Error::General(s!(e)))?;
+    conn.transaction(|| {
+        let mut summary = SeedSummary::default();
+        for _ in 0..config.merchants {
+            let mut merchant = merchant(&password, now);
+            let mut txs: Vec<Transaction> = (0..config.transactions)
+                .map(|n| transaction(&merchant, n, config.days, now))
+                .collect();
+            txs.sort_by_key(|tx| tx.created_at);
+            for tx in txs.iter_mut() {
+                if tx.transaction_type == TransactionType::Payment {
+                    merchant.invoice_sequence += 1;
+                    tx.invoice_number = Some(format_invoice_number(
+                        &merchant.invoice_prefix,
+                        tx.created_at.year(),
+                        merchant.invoice_sequence,
+                    ));
+                }
+            }
+            merchant.balance = balance(&txs);
+            diesel::insert_into(merchants::table)
+                .values(&merchant)
+                .execute(conn)?;
+            // Postgres takes at most 65535 parameters per statement
+            for chunk in txs.chunks(500) {
+                diesel::insert_into(transactions::table)
+                    .values(chunk)
+                    .execute(conn)?;
+            }
+            for chunk in credits(&txs).chunks(500) {
+                diesel::insert_into(balance_credits::table)
+                    .values(chunk)
+                    .execute(conn)?;
+            }
+            summary.transactions += txs.len();
+            summary.merchants.push(merchant.id);
+        }
+        Ok(summary)
+    })
+}
+
+fn merchant(password: &str, now: NaiveDateTime) -> Merchant {
+    let mut rng = thread_rng();
+    let token: String = (0..64)
+        .map(|_| *TOKEN_CHARSET.choose(&mut rng).unwrap() as char)
+        .collect();
+    let id = format!("seed-{}", &token[..8].to_lowercase());
+    Merchant {
+        email: format!("{}@example.com", id),
+        password: password.to_owned(),
+        wallet_url: None,
+        balance: 0,
+        created_at: now,
+        token,
+        callback_url: None,
+        token_2fa: None,
+        confirmed_2fa: false,
+        second_factor: SecondFactor::Totp,
+        oidc_subject: None,
+        is_admin: false,
+        payout_callback_url: None,
+        email_logo_url: None,
+        email_footer: None,
+        email_reply_to: None,
+        timezone: s!("UTC"),
+        invoice_prefix: s!("KT"),
+        invoice_sequence: 0,
+        callback_timeout_seconds: DEFAULT_CALLBACK_TIMEOUT_SECONDS,
+        callback_headers: None,
+        callback_verify_tls: true,
+        telegram_chat_id: None,
+        slack_webhook_url: None,
+        callback_template: None,
+        slate_message_check: SlateMessageCheck::Off.to_string(),
+        verbose_callbacks: false,
+        callback_max_attempts: None,
+        callback_backoff_seconds: None,
+        callback_retry_window_seconds: None,
+        plan: s!(DEFAULT_PLAN),
+        payment_reminder_minutes: None,
+        platform_id: None,
+        referrer_id: None,
+        referral_share_bps: None,
+        pending_balance: 0,
+        id,
+    }
+}
+
+/// `n`th transaction of the merchant, created at a random time of the
+/// last `days` and in the `n`th status. Payouts are never refunded and
+/// payments never initialized.
+fn transaction(merchant: &Merchant, n: usize, days: i64, now: NaiveDateTime) -> Transaction {
+    let mut rng = thread_rng();
+    let status = STATUSES[n % STATUSES.len()];
+    let transaction_type = match status {
+        TransactionStatus::Initialized => TransactionType::Payout,
+        TransactionStatus::Refund => TransactionType::Payment,
+        _ if n % 5 == 4 => TransactionType::Payout,
+        _ => TransactionType::Payment,
+    };
+    let created_at = now - Duration::seconds(rng.gen_range(0, days * 24 * 3600));
+    let updated_at = (created_at + Duration::seconds(rng.gen_range(0, 3600))).min(now);
+    let grin_amount = rng.gen_range(100_000_000, 50_000_000_000);
+    let (currency, price) = match transaction_type {
+        TransactionType::Payment => *PRICES.choose(&mut rng).unwrap(),
+        TransactionType::Payout => PRICES[0],
+    };
+    let price = Decimal::from_str(price).unwrap();
+    let amount = Money::from_grin(grin_amount)
+        .convert_at_price(currency, price)
+        .unwrap_or_else(|| Money::from_grin(grin_amount));
+    let paid = match status {
+        TransactionStatus::New | TransactionStatus::Rejected => false,
+        TransactionStatus::Initialized => false,
+        _ => true,
+    };
+    let in_chain = match status {
+        TransactionStatus::InChain | TransactionStatus::Confirmed | TransactionStatus::Refund => {
+            true
+        }
+        _ => false,
+    };
+    let fee = grin_amount / 100;
+    Transaction {
+        id: Uuid::new_v4(),
+        external_id: format!("order-{}", n + 1),
+        merchant_id: merchant.id.clone(),
+        grin_amount,
+        amount,
+        status,
+        confirmations: 10,
+        email: if n % 3 == 0 {
+            Some(format!("buyer{}@example.com", n + 1))
+        } else {
+            None
+        },
+        created_at,
+        updated_at,
+        reported: status == TransactionStatus::Confirmed,
+        report_attempts: 0,
+        next_report_attempt: None,
+        wallet_tx_id: if paid { Some(n as i64 + 1) } else { None },
+        wallet_tx_slate_id: if paid {
+            Some(Uuid::new_v4().to_string())
+        } else {
+            None
+        },
+        message: format!("Order {}", n + 1),
+        slate_messages: None,
+        knockturn_fee: if paid { Some(fee) } else { None },
+        transfer_fee: if paid && transaction_type == TransactionType::Payout {
+            Some(8_000_000)
+        } else {
+            None
+        },
+        real_transfer_fee: None,
+        transaction_type,
+        height: if in_chain {
+            Some(rng.gen_range(500_000, 600_000))
+        } else {
+            None
+        },
+        commit: if in_chain {
+            Some(ser::to_hex(rng.gen::<[u8; 32]>().to_vec()))
+        } else {
+            None
+        },
+        redirect_url: None,
+        exchange_rate: Some(price),
+        rate_locked_until: Some(created_at + Duration::minutes(15)),
+        requotes: 0,
+        metadata: None,
+        expires_at: None,
+        confirmed_by_wallet: false,
+        seen_in_pool_at: if paid { Some(updated_at) } else { None },
+        payout_batch_id: None,
+        receipt_sent_at: None,
+        receipt_opt_in: false,
+        output_selection: None,
+        amount_tag: None,
+        invoice_number: None,
+        payer_public_key: None,
+        reported_status: None,
+        refund_reason: if status == TransactionStatus::Refund {
+            Some(RefundReason::PaidAfterRejection.to_string())
+        } else {
+            None
+        },
+        slate_version: if paid { Some(2) } else { None },
+        payer_user_agent: None,
+        rate_updated_at: if currency == Currency::GRIN {
+            None
+        } else {
+            Some(created_at)
+        },
+        payer_country: None,
+        payer_asn: None,
+        processing_until: None,
+        reminder_sent_at: None,
+        refund_address: None,
+        referrer_id: None,
+        referral_fee: None,
+        claimed_slate_id: None,
+        payer_message_unverified: false,
+    }
+}
+
+/// Confirmed payments less fees and the payouts not rejected
+fn balance(txs: &[Transaction]) -> i64 {
+    let balance: i64 = txs
+        .iter()
+        .map(|tx| match (tx.transaction_type, tx.status) {
+            (TransactionType::Payment, TransactionStatus::Confirmed) => {
+                tx.grin_amount - tx.knockturn_fee.unwrap_or(0)
+            }
+            (TransactionType::Payout, TransactionStatus::Rejected) => 0,
+            (TransactionType::Payout, _) => -tx.grin_amount,
+            _ => 0,
+        })
+        .sum();
+    balance.max(0)
+}
+
+/// What confirmed payments credited, for the dashboard's balance history
+fn credits(txs: &[Transaction]) -> Vec<BalanceCredit> {
+    txs.iter()
+        .filter(|tx| {
+            tx.transaction_type == TransactionType::Payment
+                && tx.status == TransactionStatus::Confirmed
+        })
+        .map(|tx| BalanceCredit {
+            id: Uuid::new_v4(),
+            transaction_id: tx.id,
+            merchant_id: tx.merchant_id.clone(),
+            grin_amount: tx.grin_amount - tx.knockturn_fee.unwrap_or(0),
+            created_at: tx.updated_at,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transactions() {
+        let config = SeedConfig::from_args(vec![s!("--transactions=70"), s!("--days=30")]).unwrap();
+        assert_eq!(config.merchants, DEFAULT_SEED_MERCHANTS);
+        assert_eq!(config.transactions, 70);
+        assert!(SeedConfig::from_args(vec![s!("--merchants=many")]).is_err());
+        assert!(SeedConfig::from_args(vec![s!("--users=1")]).is_err());
+
+        let now = Utc::now().naive_utc();
+        let merchant = merchant("hash", now);
+        assert!(merchant.id.starts_with("seed-"));
+        let txs: Vec<Transaction> = (0..config.transactions)
+            .map(|n| transaction(&merchant, n, config.days, now))
+            .collect();
+        for status in STATUSES {
+            assert!(txs.iter().any(|tx| tx.status == *status));
+        }
+        for tx in &txs {
+            assert!(tx.created_at <= now);
+            assert!(tx.created_at > now - Duration::days(config.days));
+            assert_eq!(tx.height.is_some(), tx.commit.is_some());
+        }
+        assert!(txs
+            .iter()
+            .any(|tx| tx.transaction_type == TransactionType::Payout));
+        assert!(!txs
+            .iter()
+            .any(|tx| tx.transaction_type == TransactionType::Payout
+                && tx.status == TransactionStatus::Refund));
+        assert!(balance(&txs) >= 0);
+        assert_eq!(
+            credits(&txs).len(),
+            txs.iter()
+                .filter(|tx| tx.transaction_type == TransactionType::Payment
+                    && tx.status == TransactionStatus::Confirmed)
+                .count()
+        );
+    }
+}
@@ -106,3 +106,56 @@ pub mod opt_string_or_u64 {
         deserializer.deserialize_any(Visitor)
     }
 }
+
+/// Bytes as hex strings, how slates from V2 on carry keys, commitments
+/// and signatures
+pub mod hex_bytes {
+    use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&HEXLOWER.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let hex = String::deserialize(deserializer)?;
+        HEXLOWER_PERMISSIVE
+            .decode(hex.as_bytes())
+            .map_err(de::Error::custom)
+    }
+}
+
+/// As above, for Options
+pub mod opt_hex_bytes {
+    use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match bytes {
+            Some(bytes) => serializer.serialize_str(&HEXLOWER.encode(bytes)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(hex) => HEXLOWER_PERMISSIVE
+                .decode(hex.as_bytes())
+                .map(Some)
+                .map_err(de::Error::custom),
+            None => Ok(None),
+        }
+    }
+}
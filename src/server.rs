@@ -0,0 +1,120 @@
+//! Tuning of the HTTP server.
+//!
+//! Read from the environment, unset values keep actix-web defaults. `HOST`
+//! takes a comma separated list of addresses, the app is served on each.
+//! With `INTERNAL_HOST` set `HOST` serves only the checkout and the
+//! merchant API, admin pages and metrics are served on `INTERNAL_HOST`.
+
+use actix_web::server::KeepAlive;
+use std::env;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    pub hosts: Vec<String>,
+    /// Listeners of the internal app, all routes are served on `hosts`
+    /// when empty
+    pub internal_hosts: Vec<String>,
+    /// Worker threads, one per CPU when `None`
+    pub workers: Option<usize>,
+    /// Seconds an idle connection is kept open, 0 turns keep-alive off
+    pub keep_alive_seconds: usize,
+    /// Milliseconds a client has to send the request headers
+    pub client_timeout_ms: u64,
+    /// Open connections per worker, new ones wait above it
+    pub max_connections: usize,
+    /// Connections waiting to be accepted
+    pub backlog: i32,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            hosts: vec![s!("0.0.0.0:3000")],
+            internal_hosts: vec![],
+            workers: None,
+            keep_alive_seconds: 5,
+            client_timeout_ms: 5000,
+            max_connections: 25_000,
+            backlog: 2048,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads HOST, INTERNAL_HOST, HTTP_WORKERS, HTTP_KEEP_ALIVE_SECONDS,
+    /// HTTP_CLIENT_TIMEOUT_MS, HTTP_MAX_CONNECTIONS and HTTP_BACKLOG
+    pub fn from_env() -> Self {
+        let default = ServerConfig::default();
+        ServerConfig {
+            hosts: env::var("HOST")
+                .map(|v| parse_hosts("HOST", &v))
+                .unwrap_or(default.hosts),
+            internal_hosts: env::var("INTERNAL_HOST")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .map(|v| parse_hosts("INTERNAL_HOST", &v))
+                .unwrap_or(default.internal_hosts),
+            workers: env::var("HTTP_WORKERS")
+                .ok()
+                .map(|v| v.parse().expect("HTTP_WORKERS must be a number")),
+            keep_alive_seconds: env::var("HTTP_KEEP_ALIVE_SECONDS")
+                .map(|v| v.parse().expect("HTTP_KEEP_ALIVE_SECONDS must be a number"))
+                .unwrap_or(default.keep_alive_seconds),
+            client_timeout_ms: env::var("HTTP_CLIENT_TIMEOUT_MS")
+                .map(|v| v.parse().expect("HTTP_CLIENT_TIMEOUT_MS must be a number"))
+                .unwrap_or(default.client_timeout_ms),
+            max_connections: env::var("HTTP_MAX_CONNECTIONS")
+                .map(|v| v.parse().expect("HTTP_MAX_CONNECTIONS must be a number"))
+                .unwrap_or(default.max_connections),
+            backlog: env::var("HTTP_BACKLOG")
+                .map(|v| v.parse().expect("HTTP_BACKLOG must be a number"))
+                .unwrap_or(default.backlog),
+        }
+    }
+
+    pub fn keep_alive(&self) -> KeepAlive {
+        if self.keep_alive_seconds == 0 {
+            KeepAlive::Disabled
+        } else {
+            KeepAlive::Timeout(self.keep_alive_seconds)
+        }
+    }
+}
+
+fn parse_hosts(name: &str, hosts: &str) -> Vec<String> {
+    let hosts: Vec<String> = hosts
+        .split(',')
+        .map(str::trim)
+        .filter(|host| !host.is_empty())
+        .map(str::to_owned)
+        .collect();
+    if hosts.is_empty() {
+        panic!("{} must list at least one address", name);
+    }
+    hosts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hosts() {
+        assert_eq!(
+            parse_hosts("HOST", "0.0.0.0:3000"),
+            vec![s!("0.0.0.0:3000")]
+        );
+        assert_eq!(
+            parse_hosts("HOST", " 0.0.0.0:3000, 127.0.0.1:3001 ,"),
+            vec![s!("0.0.0.0:3000"), s!("127.0.0.1:3001")]
+        );
+        let config = ServerConfig {
+            keep_alive_seconds: 0,
+            ..ServerConfig::default()
+        };
+        match config.keep_alive() {
+            KeepAlive::Disabled => {}
+            _ => panic!("keep-alive should be off"),
+        }
+    }
+}
@@ -0,0 +1,305 @@
+//! Daily settlement statements for merchants' bookkeeping.
+//!
+//! A settlement covers the merchant's transactions confirmed during one UTC
+//! day, confirmation time is stored in `updated_at`. The statement ends with
+//! hex encoded HMAC-SHA256 keyed with the merchant's API token over the
+//! statement lines joined with `\n`, so the merchant can check a statement
+//! wasn't altered after it was issued. Notes attached to a transaction are
+//! listed under it. Shares of split payments, see `splits`, are listed
+//! for platforms and sellers which had any that day.
+
+use crate::errors::Error;
+use crate::models::{Transaction, TransactionNote, TransactionType};
+use crate::return_url::hmac;
+use chrono::NaiveDate;
+use data_encoding::HEXLOWER;
+use diesel::sql_types::{BigInt, Date};
+use serde::Serialize;
+
+/// Totals of one day, all amounts in nanogrins
+#[derive(Debug, Serialize, QueryableByName)]
+pub struct SettlementDay {
+    #[sql_type = "Date"]
+    pub date: NaiveDate,
+    #[sql_type = "BigInt"]
+    pub transactions: i64,
+    #[sql_type = "BigInt"]
+    pub payments: i64,
+    #[sql_type = "BigInt"]
+    pub payouts: i64,
+    #[sql_type = "BigInt"]
+    pub fees: i64,
+}
+
+/// Shares of split payments confirmed on one day, in nanogrins
+#[derive(Debug, Default, Serialize, QueryableByName)]
+pub struct SettlementShares {
+    /// Of the merchant's payments to its sellers
+    #[sql_type = "BigInt"]
+    pub given: i64,
+    /// Of payments of the merchant's platform
+    #[sql_type = "BigInt"]
+    pub received: i64,
+}
+
+/// Width of wrapped note text, keeps notes inside the PDF page
+const NOTE_WIDTH: usize = 72;
+const NOTE_INDENT: &str = "          ";
+
+#[derive(Debug)]
+pub struct Settlement {
+    pub merchant_id: String,
+    pub date: NaiveDate,
+    pub transactions: Vec<Transaction>,
+    pub notes: Vec<TransactionNote>,
+    pub payments: i64,
+    pub payouts: i64,
+    pub knockturn_fees: i64,
+    pub network_fees: i64,
+    pub shares: SettlementShares,
+}
+
+impl Settlement {
+    pub fn new(
+        merchant_id: &str,
+        date: NaiveDate,
+        transactions: Vec<Transaction>,
+        notes: Vec<TransactionNote>,
+    ) -> Self {
+        let sum = |transaction_type: TransactionType| -> i64 {
+            transactions
+                .iter()
+                .filter(|tx| tx.transaction_type == transaction_type)
+                .map(|tx| tx.grin_amount)
+                .sum()
+        };
+        let payments = sum(TransactionType::Payment);
+        let payouts = sum(TransactionType::Payout);
+        let knockturn_fees = transactions.iter().filter_map(|tx| tx.knockturn_fee).sum();
+        let network_fees = transactions.iter().filter_map(network_fee).sum();
+        Settlement {
+            merchant_id: merchant_id.to_owned(),
+            date,
+            transactions,
+            notes,
+            payments,
+            payouts,
+            knockturn_fees,
+            network_fees,
+            shares: SettlementShares::default(),
+        }
+    }
+
+    pub fn with_shares(self, shares: SettlementShares) -> Self {
+        Settlement { shares, ..self }
+    }
+
+    pub fn net(&self) -> i64 {
+        self.payments - self.shares.given + self.shares.received
+            - self.payouts
+            - self.knockturn_fees
+            - self.network_fees
+    }
+
+    /// Statement text, one entry per line
+    pub fn lines(&self) -> Vec<String> {
+        let count = |transaction_type: TransactionType| {
+            self.transactions
+                .iter()
+                .filter(|tx| tx.transaction_type == transaction_type)
+                .count()
+        };
+        let mut lines = vec![
+            s!("Knockturn Allee settlement statement"),
+            format!("Merchant: {}", self.merchant_id),
+            format!("Date: {} (UTC)", self.date),
+            s!(""),
+            format!(
+                "{:<20} {:>6} {:>22}",
+                "Payments received",
+                count(TransactionType::Payment),
+                format_grin(self.payments)
+            ),
+            format!(
+                "{:<20} {:>6} {:>22}",
+                "Payouts sent",
+                count(TransactionType::Payout),
+                format_grin(self.payouts)
+            ),
+        ];
+        if self.shares.given != 0 {
+            lines.push(format!(
+                "{:<27} {:>22}",
+                "Shares to sellers",
+                format_grin(-self.shares.given)
+            ));
+        }
+        if self.shares.received != 0 {
+            lines.push(format!(
+                "{:<27} {:>22}",
+                "Shares received",
+                format_grin(self.shares.received)
+            ));
+        }
+        lines.extend(vec![
+            format!(
+                "{:<27} {:>22}",
+                "Knockturn fees",
+                format_grin(self.knockturn_fees)
+            ),
+            format!(
+                "{:<27} {:>22}",
+                "Network fees",
+                format_grin(self.network_fees)
+            ),
+            format!("{:<27} {:>22}", "Net", format_grin(self.net())),
+            s!(""),
+            format!(
+                "{:<8}  {:<7}  {:>18}  {:>14}  {}",
+                "Time", "Type", "Amount, GRIN", "Fees, GRIN", "Order"
+            ),
+        ]);
+        for tx in &self.transactions {
+            let fees = tx.knockturn_fee.unwrap_or(0) + network_fee(tx).unwrap_or(0);
+            lines.push(format!(
+                "{:<8}  {:<7}  {:>18}  {:>14}  {}",
+                tx.updated_at.format("%H:%M:%S").to_string(),
+                tx.transaction_type.to_string(),
+                format_grin(tx.grin_amount),
+                format_grin(fees),
+                tx.external_id.chars().take(36).collect::<String>()
+            ));
+            for note in self
+                .notes
+                .iter()
+                .filter(|note| note.transaction_id == tx.id)
+            {
+                lines.extend(note_lines(note));
+            }
+        }
+        lines
+    }
+
+    /// Statement lines followed by the signature
+    pub fn signed_lines(&self, secret: &str) -> Result<Vec<String>, Error> {
+        let mut lines = self.lines();
+        let signature = HEXLOWER.encode(&hmac(secret, &lines.join("\n"))?);
+        lines.push(s!(""));
+        lines.push(format!("Signature (HMAC-SHA256): {}", signature));
+        Ok(lines)
+    }
+}
+
+/// Note header followed by its text wrapped to `NOTE_WIDTH`, all indented
+/// under the transaction line
+fn note_lines(note: &TransactionNote) -> Vec<String> {
+    let mut lines = vec![format!(
+        "{}Note by {} at {}:",
+        NOTE_INDENT,
+        note.author,
+        note.created_at.format("%Y-%m-%d %H:%M:%S")
+    )];
+    let mut line = String::new();
+    for word in note.body.split_whitespace() {
+        for chunk in word.chars().collect::<Vec<_>>().chunks(NOTE_WIDTH) {
+            let chunk: String = chunk.iter().collect();
+            if !line.is_empty() && line.chars().count() + 1 + chunk.chars().count() > NOTE_WIDTH {
+                lines.push(format!("{}{}", NOTE_INDENT, line));
+                line.clear();
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(&chunk);
+        }
+    }
+    if !line.is_empty() {
+        lines.push(format!("{}{}", NOTE_INDENT, line));
+    }
+    lines
+}
+
+/// Fee paid to miners, the estimate is used until the wallet reports the real one
+fn network_fee(tx: &Transaction) -> Option<i64> {
+    tx.real_transfer_fee.or(tx.transfer_fee)
+}
+
+/// Exact amount with all 9 decimals, statements shouldn't round
+pub fn format_grin(nanogrins: i64) -> String {
+    let sign = if nanogrins < 0 { "-" } else { "" };
+    let abs = nanogrins.abs();
+    format!("{}{}.{:09}", sign, abs / 1_000_000_000, abs % 1_000_000_000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::tests::create_tx;
+
+    #[test]
+    fn test_format_grin() {
+        assert_eq!(format_grin(1_500_000_000), "1.500000000");
+        assert_eq!(format_grin(8_000_000), "0.008000000");
+        assert_eq!(format_grin(-1), "-0.000000001");
+    }
+
+    #[test]
+    fn test_note_lines() {
+        let tx = create_tx();
+        let body = format!("{} {}", "a".repeat(70), "b".repeat(80));
+        let note = TransactionNote::new(tx.id, "merchant", &body).unwrap();
+        let lines = note_lines(&note);
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("          Note by merchant at "));
+        assert_eq!(lines[1], format!("{}{}", NOTE_INDENT, "a".repeat(70)));
+        assert_eq!(lines[2], format!("{}{}", NOTE_INDENT, "b".repeat(72)));
+        assert_eq!(lines[3], format!("{}{}", NOTE_INDENT, "b".repeat(8)));
+    }
+
+    #[test]
+    fn test_settlement() {
+        let payment = create_tx();
+        let mut payout = create_tx();
+        payout.transaction_type = TransactionType::Payout;
+        payout.grin_amount = 400_000_000;
+        payout.knockturn_fee = Some(10_000_000);
+        payout.transfer_fee = Some(8_000_000);
+        payout.real_transfer_fee = Some(7_000_000);
+
+        let date = NaiveDate::from_ymd(2019, 6, 18);
+        let note = TransactionNote::new(payout.id, "merchant", "sent twice").unwrap();
+        let settlement = Settlement::new("merchant", date, vec![payment, payout], vec![note]);
+        assert_eq!(settlement.payments, 1_000_000_000);
+        assert_eq!(settlement.payouts, 400_000_000);
+        assert_eq!(settlement.knockturn_fees, 10_000_000);
+        assert_eq!(settlement.network_fees, 7_000_000);
+        assert_eq!(settlement.net(), 583_000_000);
+        assert!(!settlement
+            .lines()
+            .iter()
+            .any(|line| line.starts_with("Shares")));
+
+        let lines = settlement.signed_lines("secret").unwrap();
+        assert_eq!(lines.len(), settlement.lines().len() + 2);
+        assert!(lines.contains(&format!("{}sent twice", NOTE_INDENT)));
+        let signature = lines.last().unwrap();
+        assert_ne!(
+            signature,
+            settlement
+                .signed_lines("another secret")
+                .unwrap()
+                .last()
+                .unwrap()
+        );
+
+        let settlement = settlement.with_shares(SettlementShares {
+            given: 100_000_000,
+            received: 0,
+        });
+        assert_eq!(settlement.net(), 483_000_000);
+        assert!(settlement.lines().contains(&format!(
+            "{:<27} {:>22}",
+            "Shares to sellers", "-0.100000000"
+        )));
+    }
+}
@@ -0,0 +1,449 @@
+//! Slatepacks, how grin wallets 5.0 and newer pass slates as text.
+//!
+//! The armor, `BEGINSLATEPACK. <words>. ENDSLATEPACK.`, is base58 in
+//! words of 15 characters of the binary slatepack behind a checksum, the
+//! first 4 bytes of its double SHA-256. The slatepack holds a binary V4
+//! slate. Slatepacks encrypted to a slatepack address can't be read, the
+//! gateway has none, buyers send them unencrypted.
+
+use crate::errors::Error;
+use crate::wallet::{
+    self, CommitsV4, KernelFeatures, KernelFeaturesArgsV4, ParticipantDataV4, SlateV4, OFFSET_SIZE,
+};
+use openssl::sha::sha256;
+use secp256k1zkp::constants::{
+    COMPACT_SIGNATURE_SIZE, COMPRESSED_PUBLIC_KEY_SIZE, PEDERSEN_COMMITMENT_SIZE,
+};
+use serde::{ser, Serialize, Serializer};
+use uuid::Uuid;
+
+pub const HEADER: &'static str = "BEGINSLATEPACK";
+pub const FOOTER: &'static str = "ENDSLATEPACK";
+/// Longest armored slatepack read, a compact slate takes a few hundred
+/// characters and base58 takes quadratic time
+pub const MAX_LENGTH: usize = 10_000;
+
+const WORD_LENGTH: usize = 15;
+const CHECKSUM_SIZE: usize = 4;
+const BASE58_ALPHABET: &'static [u8] =
+    b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+/// Slatepack version, major and minor
+const VERSION: [u8; 2] = [1, 0];
+const MODE_PLAIN: u8 = 0;
+/// States of V4 slates by their binary value
+const STATES: &[&str] = &["NA", "S1", "S2", "S3", "I1", "I2", "I3"];
+
+/// V4 slate the buyer sent as slatepack, serialized as armored slatepack
+#[derive(Debug, Clone)]
+pub struct Slatepack(pub SlateV4);
+
+impl Slatepack {
+    pub fn decode(armor: &str) -> Result<Self, Error> {
+        let data = dearmor(armor)?;
+        let mut reader = Reader(&data);
+        let version = reader.bytes(VERSION.len())?;
+        if version[0] != VERSION[0] {
+            return Err(Error::UnsupportedSlateVersion(format!(
+                "slatepack {}.{}",
+                version[0], version[1]
+            )));
+        }
+        if reader.u8()? != MODE_PLAIN {
+            return Err(invalid("it's encrypted, send it unencrypted"));
+        }
+        // Optional fields, the sender's slatepack address, aren't needed
+        let _flags = reader.u16()?;
+        let fields_length = reader.u32()?;
+        reader.bytes(fields_length as usize)?;
+        let payload_length = reader.u64()?;
+        let payload = reader.bytes(payload_length as usize)?;
+        Ok(Slatepack(read_slate(payload)?))
+    }
+
+    pub fn encode(&self) -> Result<String, Error> {
+        let payload = write_slate(&self.0)?;
+        let mut data = VERSION.to_vec();
+        data.push(MODE_PLAIN);
+        data.extend_from_slice(&0u16.to_be_bytes());
+        data.extend_from_slice(&0u32.to_be_bytes());
+        data.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        data.extend_from_slice(&payload);
+        Ok(armor(&data))
+    }
+}
+
+impl Serialize for Slatepack {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let armor = self.encode().map_err(ser::Error::custom)?;
+        serializer.serialize_str(&armor)
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidEntity(format!("invalid slatepack: {}", reason))
+}
+
+fn read_slate(data: &[u8]) -> Result<SlateV4, Error> {
+    let mut reader = Reader(data);
+    let version = reader.u16()?;
+    let block_header_version = reader.u16()?;
+    if version != 4 {
+        return Err(Error::UnsupportedSlateVersion(format!("V{}", version)));
+    }
+    let id = Uuid::from_bytes(reader.bytes(16)?).map_err(|_| invalid("malformed id"))?;
+    let sta = STATES
+        .get(reader.u8()? as usize)
+        .ok_or_else(|| invalid("unknown state"))?;
+    let off = reader.bytes(OFFSET_SIZE)?.to_vec();
+
+    let fields = reader.u8()?;
+    let num_parts = if fields & 0x01 != 0 { reader.u8()? } else { 2 };
+    let amt = if fields & 0x02 != 0 { reader.u64()? } else { 0 };
+    let fee = if fields & 0x04 != 0 { reader.u64()? } else { 0 };
+    let feat = if fields & 0x08 != 0 { reader.u8()? } else { 0 };
+    let ttl = if fields & 0x10 != 0 { reader.u64()? } else { 0 };
+
+    let mut sigs = vec![];
+    for _ in 0..reader.u8()? {
+        let has_part = reader.u8()? != 0;
+        let xs = reader.bytes(COMPRESSED_PUBLIC_KEY_SIZE)?.to_vec();
+        let nonce = reader.bytes(COMPRESSED_PUBLIC_KEY_SIZE)?.to_vec();
+        let part = if has_part {
+            Some(wallet::compact_signature(
+                reader.bytes(COMPACT_SIGNATURE_SIZE)?,
+            )?)
+        } else {
+            None
+        };
+        sigs.push(ParticipantDataV4 { xs, nonce, part });
+    }
+
+    let structs = reader.u8()?;
+    let coms = if structs & 0x01 != 0 {
+        let mut coms = vec![];
+        for _ in 0..reader.u16()? {
+            let has_proof = reader.u8()? != 0;
+            let f = reader.u8()?;
+            let c = reader.bytes(PEDERSEN_COMMITMENT_SIZE)?.to_vec();
+            let p = if has_proof {
+                let length = reader.u64()?;
+                Some(reader.bytes(length as usize)?.to_vec())
+            } else {
+                None
+            };
+            coms.push(CommitsV4 { f, c, p });
+        }
+        Some(coms)
+    } else {
+        None
+    };
+    if structs & 0x02 != 0 {
+        return Err(wallet::no_payment_proofs());
+    }
+    let feat_args = if feat == KernelFeatures::HeightLocked as u8 {
+        Some(KernelFeaturesArgsV4 {
+            lock_hgt: reader.u64()?,
+        })
+    } else {
+        None
+    };
+
+    Ok(SlateV4 {
+        ver: format!("{}:{}", version, block_header_version),
+        id,
+        sta: s!(sta),
+        off,
+        num_parts,
+        amt,
+        fee,
+        feat,
+        ttl,
+        sigs,
+        coms,
+        proof: None,
+        feat_args,
+    })
+}
+
+fn write_slate(slate: &SlateV4) -> Result<Vec<u8>, Error> {
+    let mut versions = slate.ver.split(':').map(|v| v.parse::<u16>());
+    let (version, block_header_version) = match (versions.next(), versions.next()) {
+        (Some(Ok(version)), Some(Ok(block_header_version))) => (version, block_header_version),
+        _ => return Err(invalid("malformed version")),
+    };
+    let sta = STATES
+        .iter()
+        .position(|sta| *sta == slate.sta)
+        .ok_or_else(|| invalid("unknown state"))?;
+    if slate.off.len() != OFFSET_SIZE {
+        return Err(invalid("malformed offset"));
+    }
+    let mut data = vec![];
+    data.extend_from_slice(&version.to_be_bytes());
+    data.extend_from_slice(&block_header_version.to_be_bytes());
+    data.extend_from_slice(slate.id.as_bytes());
+    data.push(sta as u8);
+    data.extend_from_slice(&slate.off);
+
+    let mut fields = 0u8;
+    let mut values = vec![];
+    if slate.num_parts != 2 {
+        fields |= 0x01;
+        values.push(slate.num_parts);
+    }
+    if slate.amt > 0 {
+        fields |= 0x02;
+        values.extend_from_slice(&slate.amt.to_be_bytes());
+    }
+    if slate.fee > 0 {
+        fields |= 0x04;
+        values.extend_from_slice(&slate.fee.to_be_bytes());
+    }
+    if slate.feat != 0 {
+        fields |= 0x08;
+        values.push(slate.feat);
+    }
+    if slate.ttl > 0 {
+        fields |= 0x10;
+        values.extend_from_slice(&slate.ttl.to_be_bytes());
+    }
+    data.push(fields);
+    data.extend_from_slice(&values);
+
+    data.push(slate.sigs.len() as u8);
+    for sig in &slate.sigs {
+        data.push(sig.part.is_some() as u8);
+        data.extend_from_slice(&sig.xs);
+        data.extend_from_slice(&sig.nonce);
+        if let Some(ref part) = sig.part {
+            data.extend_from_slice(&wallet::raw_signature(part)?);
+        }
+    }
+
+    if slate.proof.as_ref().map_or(false, |p| !p.is_null()) {
+        return Err(wallet::no_payment_proofs());
+    }
+    match slate.coms {
+        Some(ref coms) => {
+            data.push(0x01);
+            data.extend_from_slice(&(coms.len() as u16).to_be_bytes());
+            for com in coms {
+                data.push(com.p.is_some() as u8);
+                data.push(com.f);
+                data.extend_from_slice(&com.c);
+                if let Some(ref proof) = com.p {
+                    data.extend_from_slice(&(proof.len() as u64).to_be_bytes());
+                    data.extend_from_slice(proof);
+                }
+            }
+        }
+        None => data.push(0),
+    }
+    if slate.feat == KernelFeatures::HeightLocked as u8 {
+        let lock_height = slate.feat_args.as_ref().map_or(0, |args| args.lock_hgt);
+        data.extend_from_slice(&lock_height.to_be_bytes());
+    }
+    Ok(data)
+}
+
+/// Big endian integers and bytes of a binary slatepack
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], Error> {
+        if length > self.0.len() {
+            return Err(invalid("it's cut short"));
+        }
+        let (bytes, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.bytes(2)?);
+        Ok(u16::from_be_bytes(bytes))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+}
+
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let hash = sha256(&sha256(data));
+    let mut checksum = [0; CHECKSUM_SIZE];
+    checksum.copy_from_slice(&hash[..CHECKSUM_SIZE]);
+    checksum
+}
+
+fn armor(data: &[u8]) -> String {
+    let mut checked = checksum(data).to_vec();
+    checked.extend_from_slice(data);
+    let words = to_base58(&checked)
+        .as_bytes()
+        .chunks(WORD_LENGTH)
+        .map(|word| String::from_utf8_lossy(word).into_owned())
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!("{}. {}. {}.", HEADER, words, FOOTER)
+}
+
+fn dearmor(armor: &str) -> Result<Vec<u8>, Error> {
+    if armor.len() > MAX_LENGTH {
+        return Err(invalid("it's too long"));
+    }
+    let start = armor
+        .find(HEADER)
+        .map(|start| start + HEADER.len())
+        .ok_or_else(|| invalid("the header is missing"))?;
+    let end = armor[start..]
+        .find(FOOTER)
+        .map(|end| start + end)
+        .ok_or_else(|| invalid("the footer is missing"))?;
+    let words: String = armor[start..end]
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '.')
+        .collect();
+    let checked = from_base58(&words).ok_or_else(|| invalid("it isn't base58"))?;
+    if checked.len() < CHECKSUM_SIZE {
+        return Err(invalid("it's cut short"));
+    }
+    let (check, data) = checked.split_at(CHECKSUM_SIZE);
+    if check != &checksum(data)[..] {
+        return Err(invalid("the checksum doesn't match"));
+    }
+    Ok(data.to_vec())
+}
+
+fn to_base58(data: &[u8]) -> String {
+    let zeros = data.iter().take_while(|byte| **byte == 0).count();
+    // Least significant first
+    let mut digits: Vec<u8> = vec![];
+    for byte in &data[zeros..] {
+        let mut carry = *byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    let mut text = "1".repeat(zeros);
+    text.extend(
+        digits
+            .iter()
+            .rev()
+            .map(|digit| BASE58_ALPHABET[*digit as usize] as char),
+    );
+    text
+}
+
+fn from_base58(text: &str) -> Option<Vec<u8>> {
+    let zeros = text.bytes().take_while(|c| *c == b'1').count();
+    // Least significant first
+    let mut bytes: Vec<u8> = vec![];
+    for c in text.bytes().skip(zeros) {
+        let mut carry = BASE58_ALPHABET.iter().position(|a| *a == c)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+    let mut data = vec![0; zeros];
+    data.extend(bytes.iter().rev());
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base58() {
+        assert_eq!(to_base58(b"hello world"), "StV1DL6CwTryKyV");
+        assert_eq!(to_base58(&[0, 0, 1]), "112");
+        assert_eq!(from_base58("StV1DL6CwTryKyV").unwrap(), b"hello world");
+        assert_eq!(from_base58("112").unwrap(), vec![0, 0, 1]);
+        assert!(from_base58("0OIl").is_none());
+    }
+
+    #[test]
+    fn test_slatepack() {
+        let slate = SlateV4 {
+            ver: s!("4:3"),
+            id: Uuid::new_v4(),
+            sta: s!("S1"),
+            off: vec![3; OFFSET_SIZE],
+            num_parts: 2,
+            amt: 1_000_000_000,
+            fee: 7_000_000,
+            feat: 0,
+            ttl: 1_440,
+            sigs: vec![ParticipantDataV4 {
+                xs: vec![2; COMPRESSED_PUBLIC_KEY_SIZE],
+                nonce: vec![3; COMPRESSED_PUBLIC_KEY_SIZE],
+                part: None,
+            }],
+            coms: Some(vec![CommitsV4 {
+                f: 0,
+                c: vec![8; PEDERSEN_COMMITMENT_SIZE],
+                p: Some(vec![1; 675]),
+            }]),
+            proof: None,
+            feat_args: None,
+        };
+        let text = Slatepack(slate.clone()).encode().unwrap();
+        assert!(text.starts_with("BEGINSLATEPACK. "));
+        assert!(text.ends_with(". ENDSLATEPACK."));
+
+        let decoded = Slatepack::decode(&text).unwrap().0;
+        assert_eq!(decoded.ver, slate.ver);
+        assert_eq!(decoded.id, slate.id);
+        assert_eq!(decoded.sta, "S1");
+        assert_eq!(decoded.off, slate.off);
+        assert_eq!(decoded.amt, slate.amt);
+        assert_eq!(decoded.fee, slate.fee);
+        assert_eq!(decoded.ttl, slate.ttl);
+        assert_eq!(decoded.sigs[0].xs, slate.sigs[0].xs);
+        assert!(decoded.sigs[0].part.is_none());
+        assert_eq!(decoded.coms.unwrap()[0].p, Some(vec![1; 675]));
+
+        // Wallets break lines and words anywhere
+        let wrapped = text.replace(' ', "\n  ");
+        assert_eq!(Slatepack::decode(&wrapped).unwrap().0.id, slate.id);
+
+        let mut data = dearmor(&text).unwrap();
+        data[2] = 1;
+        match Slatepack::decode(&armor(&data)) {
+            Err(Error::InvalidEntity(_)) => {}
+            res => panic!("encrypted slatepack was read: {:?}", res),
+        }
+        let tampered = text.replacen("BEGINSLATEPACK. ", "BEGINSLATEPACK. 2", 1);
+        assert!(Slatepack::decode(&tampered).is_err());
+        assert!(Slatepack::decode("BEGINSLATEPACK. abc. ENDSLATEPACK.").is_err());
+    }
+}
@@ -0,0 +1,194 @@
+//! Warnings about DB queries, wallet calls and handlers which took longer
+//! than expected.
+//!
+//! Thresholds are set in milliseconds with `SLOW_QUERY_MS`, `SLOW_WALLET_MS`
+//! and `SLOW_HANDLER_MS`. A slow operation is logged with its parameters,
+//! sensitive values masked, and the request id, and counted in the
+//! `slow_operations_total` metric.
+
+use crate::metrics;
+use log::warn;
+use std::fmt;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Parameters are cut to this length, sync messages carry whole blocks
+const MAX_PARAMS_LENGTH: usize = 512;
+const MASK: &str = "***";
+/// Values of fields whose names contain any of these are masked
+const SENSITIVE_FIELDS: &[&str] = &["token", "password", "secret", "email"];
+
+fn threshold_ms(var: &str, default: u64) -> u64 {
+    match std::env::var(var) {
+        Ok(val) => match val.parse::<u64>() {
+            Ok(val) => val,
+            Err(_) => {
+                log::error!("Can not parse {} value", var);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref SLOW_QUERY: Duration = Duration::from_millis(threshold_ms("SLOW_QUERY_MS", 200));
+    static ref SLOW_WALLET: Duration = Duration::from_millis(threshold_ms("SLOW_WALLET_MS", 2000));
+    static ref SLOW_HANDLER: Duration =
+        Duration::from_millis(threshold_ms("SLOW_HANDLER_MS", 1000));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlowKind {
+    Query,
+    Wallet,
+    Handler,
+}
+
+impl SlowKind {
+    fn threshold(self) -> Duration {
+        match self {
+            SlowKind::Query => *SLOW_QUERY,
+            SlowKind::Wallet => *SLOW_WALLET,
+            SlowKind::Handler => *SLOW_HANDLER,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            SlowKind::Query => "query",
+            SlowKind::Wallet => "wallet",
+            SlowKind::Handler => "handler",
+        }
+    }
+}
+
+impl fmt::Display for SlowKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Logs the operation when it took longer than the threshold of its kind
+pub fn check(
+    kind: SlowKind,
+    name: &str,
+    elapsed: Duration,
+    params: Option<&str>,
+    request_id: Option<Uuid>,
+) {
+    let threshold = kind.threshold();
+    if elapsed < threshold {
+        return;
+    }
+    metrics::inc("slow_operations_total", &[("kind", kind.as_str())]);
+    warn!(
+        "Slow {} {} took {}ms (threshold {}ms), request {}, params {}",
+        kind,
+        name,
+        as_millis(elapsed),
+        as_millis(threshold),
+        request_id
+            .map(|id| id.to_string())
+            .unwrap_or_else(|| s!("-")),
+        params.unwrap_or("-")
+    );
+}
+
+fn as_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+/// Masks sensitive field values in the `Debug` output of a message and
+/// cuts it to `MAX_PARAMS_LENGTH`
+pub fn sanitize(params: &str) -> String {
+    let mut out = String::with_capacity(params.len().min(MAX_PARAMS_LENGTH));
+    let mut rest = params;
+    while let Some(pos) = rest.find(": ") {
+        let (head, value) = rest.split_at(pos + 2);
+        out.push_str(head);
+        let field = head[..pos]
+            .rsplit(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .next()
+            .unwrap_or("")
+            .to_lowercase();
+        if SENSITIVE_FIELDS.iter().any(|name| field.contains(name)) {
+            out.push_str(MASK);
+            rest = &value[value[..value_end(value)].trim_end().len()..];
+        } else if value.starts_with('"') {
+            // Strings are copied as is, so a colon inside isn't taken
+            // for a field
+            let end = value_end(value);
+            out.push_str(&value[..end]);
+            rest = &value[end..];
+        } else {
+            rest = value;
+        }
+    }
+    out.push_str(rest);
+    if out.len() > MAX_PARAMS_LENGTH {
+        let mut end = MAX_PARAMS_LENGTH;
+        while !out.is_char_boundary(end) {
+            end -= 1;
+        }
+        out.truncate(end);
+        out.push_str("...");
+    }
+    out
+}
+
+/// Length of the field value at the start of `value`, nested values and
+/// strings included
+fn value_end(value: &str) -> usize {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' if depth == 0 => return i,
+            ')' | ']' | '}' => depth -= 1,
+            ',' if depth == 0 => return i,
+            _ => {}
+        }
+    }
+    value.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(
+            sanitize("GetTransaction { transaction_id: 1c5d0b1a }"),
+            "GetTransaction { transaction_id: 1c5d0b1a }"
+        );
+        assert_eq!(
+            sanitize("CreatePayment { email: Some(\"buyer@example.com\"), message: \"a: b\" }"),
+            "CreatePayment { email: ***, message: \"a: b\" }"
+        );
+        assert_eq!(
+            sanitize("Login { login: \"shop\", password: \"p, \\\"w\", token_2fa: None }"),
+            "Login { login: \"shop\", password: ***, token_2fa: *** }"
+        );
+        assert_eq!(
+            sanitize("Register { merchant: Merchant { id: \"shop\", token: \"t\" } }"),
+            "Register { merchant: Merchant { id: \"shop\", token: *** } }"
+        );
+        let long = sanitize(&"x".repeat(MAX_PARAMS_LENGTH * 2));
+        assert_eq!(long.len(), MAX_PARAMS_LENGTH + 3);
+    }
+}
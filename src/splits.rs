@@ -0,0 +1,229 @@
+//! Payments split between a marketplace and its sellers.
+//!
+//! A seller joins the platform it sells on, `merchants.platform_id`. A
+//! payment created by the platform may then name recipients, the platform
+//! itself or its sellers, each with a share in percent. Whenever the
+//! payment is credited to the platform's balance the recipients get their
+//! shares rounded down and the platform keeps the rest, so rounding never
+//! creates grins. Sellers are ordinary merchants and pay their balance out
+//! with their own credentials. Shares are stored in hundredths of a
+//! percent.
+
+use crate::errors::Error;
+use crate::models::{Merchant, PaymentSplit};
+use rust_decimal::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use uuid::Uuid;
+
+/// Most recipients one payment is split between
+pub const MAX_RECIPIENTS: usize = 20;
+/// The whole payment, in hundredths of a percent
+const FULL_SHARE: i32 = 10_000;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SplitRecipient {
+    pub merchant_id: String,
+    /// With at most two decimals
+    pub percent: Decimal,
+}
+
+/// Share of a payment as shown to the platform
+#[derive(Debug, Serialize)]
+pub struct SplitShare {
+    pub merchant_id: String,
+    pub percent: Decimal,
+    /// Nanogrins of the payment the share is
+    pub grin_amount: i64,
+}
+
+impl SplitShare {
+    pub fn new(split: &PaymentSplit, grin_amount: i64) -> Self {
+        SplitShare {
+            merchant_id: split.merchant_id.clone(),
+            percent: Decimal::new(i64::from(split.share_bps), 2),
+            grin_amount: share_amount(grin_amount, split.share_bps),
+        }
+    }
+}
+
+/// Splits of the payment `transaction_id`, checks which don't need the DB
+pub fn new_splits(
+    transaction_id: Uuid,
+    recipients: &[SplitRecipient],
+) -> Result<Vec<PaymentSplit>, Error> {
+    if recipients.len() > MAX_RECIPIENTS {
+        return Err(Error::InvalidEntity(format!(
+            "a payment is split between at most {} recipients",
+            MAX_RECIPIENTS
+        )));
+    }
+    let mut seen = HashSet::new();
+    let mut total = 0;
+    let mut splits = Vec::with_capacity(recipients.len());
+    for recipient in recipients {
+        if !seen.insert(&recipient.merchant_id) {
+            return Err(Error::InvalidEntity(format!(
+                "{} is a recipient twice",
+                recipient.merchant_id
+            )));
+        }
+        let share_bps = share_bps(recipient.percent).ok_or_else(|| {
+            Error::InvalidEntity(format!(
+                "share of {} should be between 0.01 and 100 percent with at most two decimals",
+                recipient.merchant_id
+            ))
+        })?;
+        total += share_bps;
+        splits.push(PaymentSplit {
+            transaction_id,
+            merchant_id: recipient.merchant_id.clone(),
+            share_bps,
+        });
+    }
+    if total > FULL_SHARE {
+        return Err(Error::InvalidEntity(s!(
+            "shares add up to more than 100 percent"
+        )));
+    }
+    Ok(splits)
+}
+
+/// Whether `recipient` may get a share of payments of `platform_id`
+pub fn check_recipient(platform_id: &str, recipient: &Merchant) -> Result<(), Error> {
+    if recipient.id == platform_id
+        || recipient.platform_id.as_ref().map(String::as_str) == Some(platform_id)
+    {
+        Ok(())
+    } else {
+        Err(Error::InvalidEntity(format!(
+            "{} doesn't sell on {}",
+            recipient.id, platform_id
+        )))
+    }
+}
+
+/// Whether `seller` may join `platform`, platforms don't sell on others
+pub fn check_platform(seller: &Merchant, platform: &Merchant, sellers: i64) -> Result<(), Error> {
+    if seller.id == platform.id {
+        return Err(Error::InvalidEntity(s!("a merchant can't sell on itself")));
+    }
+    if platform.platform_id.is_some() {
+        return Err(Error::InvalidEntity(format!(
+            "{} sells on another platform",
+            platform.id
+        )));
+    }
+    if sellers > 0 {
+        return Err(Error::InvalidEntity(format!(
+            "{} has sellers of its own",
+            seller.id
+        )));
+    }
+    Ok(())
+}
+
+/// Share in hundredths of a percent, `None` unless `percent` is between
+/// 0.01 and 100 with at most two decimals
+pub fn share_bps(percent: Decimal) -> Option<i32> {
+    let bps = percent * Decimal::from(100);
+    match bps.to_i32() {
+        Some(share) if bps.fract().is_zero() && share > 0 && share <= FULL_SHARE => Some(share),
+        _ => None,
+    }
+}
+
+/// Nanogrins of `grin_amount` a share is, rounded down
+pub fn share_amount(grin_amount: i64, share_bps: i32) -> i64 {
+    (i128::from(grin_amount) * i128::from(share_bps) / i128::from(FULL_SHARE)) as i64
+}
+
+/// What each merchant is credited of `grin_amount` the payment of
+/// `platform_id` is credited with
+pub fn credits(platform_id: &str, grin_amount: i64, splits: &[PaymentSplit]) -> Vec<(String, i64)> {
+    let mut credits: Vec<(String, i64)> = splits
+        .iter()
+        .map(|split| {
+            (
+                split.merchant_id.clone(),
+                share_amount(grin_amount, split.share_bps),
+            )
+        })
+        .collect();
+    let rest = grin_amount - credits.iter().map(|(_, amount)| amount).sum::<i64>();
+    match credits.iter_mut().find(|(id, _)| id == platform_id) {
+        Some((_, amount)) => *amount += rest,
+        None => credits.push((platform_id.to_owned(), rest)),
+    }
+    credits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::tests::create_merchant;
+
+    fn recipient(merchant_id: &str, percent: &str) -> SplitRecipient {
+        SplitRecipient {
+            merchant_id: s!(merchant_id),
+            percent: percent.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_new_splits() {
+        let id = Uuid::new_v4();
+        let splits = new_splits(id, &[recipient("a", "12.5"), recipient("b", "0.01")]).unwrap();
+        assert_eq!(splits[0].share_bps, 1_250);
+        assert_eq!(splits[1].share_bps, 1);
+        assert_eq!(splits[1].transaction_id, id);
+        assert!(new_splits(id, &[]).unwrap().is_empty());
+
+        assert!(new_splits(id, &[recipient("a", "0.001")]).is_err());
+        assert!(new_splits(id, &[recipient("a", "0")]).is_err());
+        assert!(new_splits(id, &[recipient("a", "-5")]).is_err());
+        assert!(new_splits(id, &[recipient("a", "60"), recipient("b", "40.01")]).is_err());
+        assert!(new_splits(id, &[recipient("a", "10"), recipient("a", "10")]).is_err());
+        let many: Vec<_> = (0..=MAX_RECIPIENTS)
+            .map(|i| recipient(&i.to_string(), "1"))
+            .collect();
+        assert!(new_splits(id, &many).is_err());
+    }
+
+    #[test]
+    fn test_credits() {
+        let id = Uuid::new_v4();
+        let splits = new_splits(id, &[recipient("a", "33.33"), recipient("b", "33.33")]).unwrap();
+        assert_eq!(
+            credits("shop", 1_000_000_001, &splits),
+            vec![
+                (s!("a"), 333_300_000),
+                (s!("b"), 333_300_000),
+                (s!("shop"), 333_400_001)
+            ]
+        );
+        // The rounding rest goes to the platform also when it's a recipient
+        let splits = new_splits(id, &[recipient("shop", "50"), recipient("a", "50")]).unwrap();
+        assert_eq!(
+            credits("shop", 3, &splits),
+            vec![(s!("shop"), 2), (s!("a"), 1)]
+        );
+        assert_eq!(credits("shop", 5, &[]), vec![(s!("shop"), 5)]);
+    }
+
+    #[test]
+    fn test_check_platform() {
+        let platform = create_merchant();
+        let mut seller = create_merchant();
+        seller.id = s!("seller");
+        assert!(check_recipient("shop", &seller).is_err());
+        assert!(check_recipient("shop", &platform).is_ok());
+        assert!(check_platform(&seller, &platform, 0).is_ok());
+        assert!(check_platform(&seller, &platform, 1).is_err());
+        assert!(check_platform(&platform, &platform, 0).is_err());
+
+        seller.platform_id = Some(s!("shop"));
+        assert!(check_recipient("shop", &seller).is_ok());
+        assert!(check_platform(&platform, &seller, 0).is_err());
+    }
+}
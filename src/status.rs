@@ -0,0 +1,224 @@
+//! Gateway health for the public status page and per-merchant SLA stats.
+//!
+//! Both are read from `metrics`, so they cover the time since the instance
+//! started. Payments are recorded by the cron jobs reporting them, which
+//! run on the leader, node and wallet health is checked on every instance.
+
+use crate::fsm::FsmEvent;
+use crate::metrics;
+use crate::models::Transaction;
+use actix::{Actor, Context, Handler};
+
+const CONFIRMATION_SECONDS: &str = "payment_confirmation_seconds";
+const REJECTED_PAYMENTS: &str = "payments_rejected_total";
+const CALLBACKS: &str = "payment_callbacks_total";
+const NODE_UP: &str = "node_up";
+const NODE_HEIGHT_LAG: &str = "node_height_lag_blocks";
+const WALLET_UP: &str = "wallet_up";
+const TRANSITIONS: &str = "payment_transitions_total";
+
+/// Lag at which the sync is considered behind
+pub const MAX_HEALTHY_HEIGHT_LAG: i64 = 10;
+
+/// Time from creation to confirmation of a payment
+pub fn record_confirmed(payment: &Transaction) {
+    let seconds = (payment.updated_at - payment.created_at)
+        .num_seconds()
+        .max(0);
+    metrics::observe(
+        CONFIRMATION_SECONDS,
+        &[("merchant", &payment.merchant_id)],
+        seconds as f64,
+    );
+}
+
+pub fn record_rejected(payment: &Transaction) {
+    metrics::inc(REJECTED_PAYMENTS, &[("merchant", &payment.merchant_id)]);
+}
+
+pub fn record_callback(merchant_id: &str, delivered: bool) {
+    let result = if delivered { "delivered" } else { "failed" };
+    metrics::inc(CALLBACKS, &[("merchant", merchant_id), ("result", result)]);
+}
+
+/// Delivered and failed callbacks of all merchants
+pub fn callback_totals() -> (u64, u64) {
+    (
+        metrics::get_total(CALLBACKS, ("result", "delivered")),
+        metrics::get_total(CALLBACKS, ("result", "failed")),
+    )
+}
+
+/// `height_lag` is `None` when the node is unreachable
+pub fn record_node(height_lag: Option<i64>) {
+    metrics::set(NODE_UP, &[], height_lag.is_some() as i64);
+    if let Some(lag) = height_lag {
+        metrics::set(NODE_HEIGHT_LAG, &[], lag);
+    }
+}
+
+pub fn record_wallet(reachable: bool) {
+    metrics::set(WALLET_UP, &[], reachable as i64);
+}
+
+/// Counts the state machine's transitions by event
+pub struct TransitionMetrics;
+
+impl Actor for TransitionMetrics {
+    type Context = Context<Self>;
+}
+
+impl Handler<FsmEvent> for TransitionMetrics {
+    type Result = ();
+
+    fn handle(&mut self, event: FsmEvent, _: &mut Self::Context) -> Self::Result {
+        metrics::inc(TRANSITIONS, &[("event", event.name())]);
+    }
+}
+
+/// What the public status page shows, nothing merchant specific.
+/// `None` means not checked yet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GatewayHealth {
+    pub node_up: Option<bool>,
+    pub node_height_lag: Option<i64>,
+    pub wallet_up: Option<bool>,
+    pub avg_confirmation_seconds: Option<f64>,
+}
+
+impl GatewayHealth {
+    pub fn current() -> Self {
+        GatewayHealth {
+            node_up: metrics::get_gauge(NODE_UP, &[]).map(|up| up == 1),
+            node_height_lag: metrics::get_gauge(NODE_HEIGHT_LAG, &[]),
+            wallet_up: metrics::get_gauge(WALLET_UP, &[]).map(|up| up == 1),
+            avg_confirmation_seconds: metrics::get_summary_total(CONFIRMATION_SECONDS).mean(),
+        }
+    }
+
+    pub fn node_text(&self) -> &'static str {
+        up_text(self.node_up)
+    }
+
+    pub fn wallet_text(&self) -> &'static str {
+        up_text(self.wallet_up)
+    }
+
+    pub fn avg_confirmation(&self) -> String {
+        format_seconds(self.avg_confirmation_seconds)
+    }
+
+    pub fn is_operational(&self) -> bool {
+        self.node_up == Some(true)
+            && self.wallet_up == Some(true)
+            && self
+                .node_height_lag
+                .map_or(false, |lag| lag <= MAX_HEALTHY_HEIGHT_LAG)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MerchantSla {
+    pub confirmed: u64,
+    pub rejected: u64,
+    pub avg_confirmation_seconds: Option<f64>,
+    pub callbacks_delivered: u64,
+    pub callbacks_failed: u64,
+}
+
+impl MerchantSla {
+    pub fn of(merchant_id: &str) -> Self {
+        let confirmations =
+            metrics::get_summary(CONFIRMATION_SECONDS, &[("merchant", merchant_id)]);
+        MerchantSla {
+            confirmed: confirmations.count,
+            rejected: metrics::get(REJECTED_PAYMENTS, &[("merchant", merchant_id)]),
+            avg_confirmation_seconds: confirmations.mean(),
+            callbacks_delivered: metrics::get(
+                CALLBACKS,
+                &[("merchant", merchant_id), ("result", "delivered")],
+            ),
+            callbacks_failed: metrics::get(
+                CALLBACKS,
+                &[("merchant", merchant_id), ("result", "failed")],
+            ),
+        }
+    }
+
+    pub fn avg_confirmation(&self) -> String {
+        format_seconds(self.avg_confirmation_seconds)
+    }
+
+    /// e.g. `99.5%`, `-` without callbacks
+    pub fn callback_success(&self) -> String {
+        self.callback_success_percent()
+            .map(|percent| format!("{:.1}%", percent))
+            .unwrap_or_else(|| s!("-"))
+    }
+
+    /// Share of callback attempts the merchant answered with 2xx
+    pub fn callback_success_percent(&self) -> Option<f64> {
+        let total = self.callbacks_delivered + self.callbacks_failed;
+        if total == 0 {
+            None
+        } else {
+            Some(100.0 * self.callbacks_delivered as f64 / total as f64)
+        }
+    }
+}
+
+fn up_text(up: Option<bool>) -> &'static str {
+    match up {
+        Some(true) => "up",
+        Some(false) => "down",
+        None => "unknown",
+    }
+}
+
+/// e.g. `4m 05s`, `-` when nothing was confirmed
+fn format_seconds(seconds: Option<f64>) -> String {
+    match seconds {
+        Some(seconds) => {
+            let seconds = seconds.round() as i64;
+            if seconds < 60 {
+                format!("{}s", seconds)
+            } else {
+                format!("{}m {:02}s", seconds / 60, seconds % 60)
+            }
+        }
+        None => s!("-"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::tests::create_tx;
+    use chrono::Duration;
+
+    #[test]
+    fn test_merchant_sla() {
+        let mut payment = create_tx();
+        payment.merchant_id = s!("sla-test-shop");
+        payment.updated_at = payment.created_at + Duration::seconds(90);
+        record_confirmed(&payment);
+        payment.updated_at = payment.created_at + Duration::seconds(30);
+        record_confirmed(&payment);
+        record_rejected(&payment);
+        record_callback(&payment.merchant_id, true);
+        record_callback(&payment.merchant_id, true);
+        record_callback(&payment.merchant_id, true);
+        record_callback(&payment.merchant_id, false);
+        let sla = MerchantSla::of("sla-test-shop");
+        assert_eq!(sla.confirmed, 2);
+        assert_eq!(sla.rejected, 1);
+        assert_eq!(sla.avg_confirmation_seconds, Some(60.0));
+        assert_eq!(sla.callback_success_percent(), Some(75.0));
+        assert_eq!(sla.avg_confirmation(), "1m 00s");
+        assert_eq!(sla.callback_success(), "75.0%");
+        assert_eq!(
+            MerchantSla::of("sla-other-shop").callback_success_percent(),
+            None
+        );
+    }
+}
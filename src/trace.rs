@@ -0,0 +1,526 @@
+//! Distributed tracing exported to an OpenTelemetry collector.
+//!
+//! Every HTTP request gets a server span, continuing the caller's trace when
+//! it sends a W3C `traceparent` header. Handlers on the checkout path add
+//! child spans around DB, FSM and wallet calls, cron jobs get a root span per
+//! run. Finished spans are buffered and posted every few seconds as OTLP/HTTP
+//! JSON to `OTEL_EXPORTER_OTLP_ENDPOINT`, tracing is disabled without it.
+//! `OTEL_TRACES_SAMPLER_ARG` is the share (0.0 - 1.0) of new traces which are
+//! recorded, traces started by a caller follow the caller's decision.
+//! DB and wallet spans are checked against `slow_log` thresholds whether
+//! they are sampled or not.
+
+use crate::metrics;
+use crate::middleware::RequestStart;
+use crate::slow_log::{self, SlowKind};
+use actix::prelude::*;
+use actix_web::client;
+use actix_web::http::header;
+use actix_web::middleware::{Finished, Middleware, Started};
+use actix_web::{HttpRequest, HttpResponse, Result};
+use data_encoding::HEXLOWER;
+use futures::{Async, Future, Poll};
+use log::{debug, warn};
+use rand::{thread_rng, Rng};
+use serde_json::{json, Value};
+use std::env;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+const TRACEPARENT_HEADER: &str = "traceparent";
+const EXPORT_INTERVAL_SECONDS: u64 = 5;
+/// Spans finished while the collector is slow or down are dropped above this
+const MAX_BUFFERED_SPANS: usize = 4096;
+const MAX_EXPORT_BATCH: usize = 512;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    static ref SAMPLE_RATIO: RwLock<f64> = RwLock::new(1.0);
+    static ref FINISHED: Mutex<Vec<SpanData>> = Mutex::new(Vec::new());
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceConfig {
+    pub endpoint: String,
+    pub service_name: String,
+    pub sample_ratio: f64,
+}
+
+impl TraceConfig {
+    pub fn from_env() -> Option<Self> {
+        let endpoint = env::var("OTEL_EXPORTER_OTLP_ENDPOINT")
+            .ok()
+            .filter(|endpoint| !endpoint.is_empty())?;
+        Some(TraceConfig {
+            endpoint: format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+            service_name: env::var("OTEL_SERVICE_NAME").unwrap_or(s!("knockturn")),
+            sample_ratio: env::var("OTEL_TRACES_SAMPLER_ARG")
+                .map(|v| {
+                    v.parse::<f64>()
+                        .expect("OTEL_TRACES_SAMPLER_ARG must be a number")
+                })
+                .unwrap_or(1.0)
+                .max(0.0)
+                .min(1.0),
+        })
+    }
+}
+
+/// Starts recording spans, they are sent by `TraceExporter`
+pub fn init(config: &TraceConfig) {
+    *SAMPLE_RATIO.write().unwrap() = config.sample_ratio;
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+/// What a child span needs to know about its parent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpanContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+    /// Id of the HTTP request the span belongs to, not propagated to callees
+    pub request_id: Option<Uuid>,
+}
+
+impl SpanContext {
+    /// Parses `00-{trace id}-{parent id}-{flags}`, unknown versions are read
+    /// the same way as the spec requires
+    pub fn from_traceparent(value: &str) -> Option<Self> {
+        let parts: Vec<&str> = value.trim().split('-').collect();
+        if parts.len() < 4 || parts[0].len() != 2 || parts[0] == "ff" {
+            return None;
+        }
+        let mut trace_id = [0; 16];
+        let mut span_id = [0; 8];
+        decode_id(parts[1], &mut trace_id)?;
+        decode_id(parts[2], &mut span_id)?;
+        let flags = HEXLOWER.decode(parts[3].as_bytes()).ok()?;
+        if flags.len() != 1 {
+            return None;
+        }
+        Some(SpanContext {
+            trace_id,
+            span_id,
+            sampled: flags[0] & 1 == 1,
+            request_id: None,
+        })
+    }
+}
+
+/// All-zero ids are invalid
+fn decode_id(hex: &str, id: &mut [u8]) -> Option<()> {
+    let bytes = HEXLOWER.decode(hex.as_bytes()).ok()?;
+    if bytes.len() != id.len() || bytes.iter().all(|b| *b == 0) {
+        return None;
+    }
+    id.copy_from_slice(&bytes);
+    Some(())
+}
+
+#[derive(Debug)]
+struct SpanData {
+    context: SpanContext,
+    parent_span_id: Option<[u8; 8]>,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+    error: bool,
+}
+
+/// Span in progress, it's recorded when dropped
+#[derive(Debug)]
+pub struct Span {
+    context: SpanContext,
+    parent_span_id: Option<[u8; 8]>,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    started_at: Instant,
+    attributes: Vec<(&'static str, String)>,
+    params: Option<String>,
+    error: bool,
+}
+
+impl Span {
+    /// Continues the remote trace or starts a new one
+    pub fn root(name: &str, kind: SpanKind, remote_parent: Option<SpanContext>) -> Self {
+        match remote_parent {
+            Some(parent) => Span::new(name, kind, parent.trace_id, Some(parent)),
+            None => {
+                let sampled = ENABLED.load(Ordering::Relaxed)
+                    && thread_rng().gen_bool(*SAMPLE_RATIO.read().unwrap());
+                let mut span = Span::new(name, kind, thread_rng().gen(), None);
+                span.context.sampled = sampled;
+                span
+            }
+        }
+    }
+
+    /// Span which isn't recorded when there is no parent, so calls outside
+    /// of a traced request don't start traces of their own
+    pub fn child(name: &str, parent: Option<&SpanContext>) -> Self {
+        match parent {
+            Some(parent) => Span::new(name, SpanKind::Internal, parent.trace_id, Some(*parent)),
+            None => Span::new(name, SpanKind::Internal, [0; 16], None),
+        }
+    }
+
+    fn new(name: &str, kind: SpanKind, trace_id: [u8; 16], parent: Option<SpanContext>) -> Self {
+        Span {
+            context: SpanContext {
+                trace_id,
+                span_id: thread_rng().gen(),
+                sampled: ENABLED.load(Ordering::Relaxed)
+                    && parent.map(|parent| parent.sampled).unwrap_or(false),
+                request_id: parent.and_then(|parent| parent.request_id),
+            },
+            parent_span_id: parent.map(|parent| parent.span_id),
+            name: name.to_owned(),
+            kind,
+            start: SystemTime::now(),
+            started_at: Instant::now(),
+            attributes: Vec::new(),
+            params: None,
+            error: false,
+        }
+    }
+
+    /// Message or arguments of the call, logged with sensitive fields
+    /// masked when the call is slow
+    pub fn with_params(mut self, params: &impl fmt::Debug) -> Self {
+        let params = slow_log::sanitize(&format!("{:?}", params));
+        self.set_attribute("params", &params);
+        self.params = Some(params);
+        self
+    }
+
+    pub fn context(&self) -> SpanContext {
+        self.context
+    }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl ToString) {
+        if self.context.sampled {
+            self.attributes.push((key, value.to_string()));
+        }
+    }
+
+    pub fn set_error(&mut self) {
+        self.error = true;
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(kind) = slow_kind(&self.name) {
+            slow_log::check(
+                kind,
+                &self.name,
+                self.started_at.elapsed(),
+                self.params.as_ref().map(|params| params.as_str()),
+                self.context.request_id,
+            );
+        }
+        if !self.context.sampled {
+            return;
+        }
+        let mut finished = FINISHED.lock().unwrap();
+        if finished.len() >= MAX_BUFFERED_SPANS {
+            metrics::inc("trace_dropped_spans_total", &[]);
+            return;
+        }
+        finished.push(SpanData {
+            context: self.context,
+            parent_span_id: self.parent_span_id,
+            name: std::mem::replace(&mut self.name, String::new()),
+            kind: self.kind,
+            start: self.start,
+            end: SystemTime::now(),
+            attributes: std::mem::replace(&mut self.attributes, Vec::new()),
+            error: self.error,
+        });
+    }
+}
+
+/// Spans are named after the callee, `db GetTransaction` or `wallet receive`
+fn slow_kind(name: &str) -> Option<SlowKind> {
+    if name.starts_with("db ") {
+        Some(SlowKind::Query)
+    } else if name.starts_with("wallet ") {
+        Some(SlowKind::Wallet)
+    } else {
+        None
+    }
+}
+
+/// Future which ends the span when it resolves, failures mark the span
+pub struct Traced<F> {
+    inner: F,
+    span: Option<Span>,
+}
+
+impl<F: Future> Future for Traced<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let res = self.inner.poll();
+        match res {
+            Ok(Async::NotReady) => {}
+            Ok(Async::Ready(_)) => {
+                self.span.take();
+            }
+            Err(_) => {
+                if let Some(mut span) = self.span.take() {
+                    span.set_error();
+                }
+            }
+        }
+        res
+    }
+}
+
+pub trait FutureTraceExt: Future + Sized {
+    fn traced(self, span: Span) -> Traced<Self> {
+        Traced {
+            inner: self,
+            span: Some(span),
+        }
+    }
+}
+
+impl<F: Future> FutureTraceExt for F {}
+
+/// Span of the request, kept in the request extensions
+struct RequestSpan(Span);
+
+/// Context of the request's span for child spans of a handler
+pub fn request_context<S>(req: &HttpRequest<S>) -> Option<SpanContext> {
+    let request_id = req.extensions().get::<RequestStart>().map(|start| start.id);
+    req.extensions()
+        .get::<RequestSpan>()
+        .map(|span| SpanContext {
+            request_id,
+            ..span.0.context()
+        })
+}
+
+pub struct TraceRequests;
+
+impl<S> Middleware<S> for TraceRequests {
+    fn start(&self, req: &HttpRequest<S>) -> Result<Started> {
+        let remote_parent = req
+            .headers()
+            .get(TRACEPARENT_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(SpanContext::from_traceparent);
+        let mut span = Span::root(
+            &format!("HTTP {}", req.method()),
+            SpanKind::Server,
+            remote_parent,
+        );
+        // Query strings may carry tokens, only the path is recorded
+        span.set_attribute("http.method", req.method());
+        span.set_attribute("http.target", req.path());
+        if let Some(user_agent) = req.headers().get(header::USER_AGENT) {
+            span.set_attribute("http.user_agent", user_agent.to_str().unwrap_or(""));
+        }
+        req.extensions_mut().insert(RequestSpan(span));
+        Ok(Started::Done)
+    }
+
+    fn finish(&self, req: &HttpRequest<S>, resp: &HttpResponse) -> Finished {
+        if let Some(RequestSpan(mut span)) = req.extensions_mut().remove::<RequestSpan>() {
+            span.set_attribute("http.status_code", resp.status().as_u16());
+            if resp.status().is_server_error() {
+                span.set_error();
+            }
+        }
+        Finished::Done
+    }
+}
+
+/// Posts finished spans to the collector every `EXPORT_INTERVAL_SECONDS`,
+/// spans the collector didn't accept are dropped
+pub struct TraceExporter {
+    config: TraceConfig,
+}
+
+impl TraceExporter {
+    pub fn new(config: TraceConfig) -> Self {
+        TraceExporter { config }
+    }
+
+    fn export(&mut self, ctx: &mut Context<Self>) {
+        let spans: Vec<SpanData> = {
+            let mut finished = FINISHED.lock().unwrap();
+            let batch = finished.len().min(MAX_EXPORT_BATCH);
+            finished.drain(..batch).collect()
+        };
+        if spans.is_empty() {
+            return;
+        }
+        debug!("Export {} spans", spans.len());
+        let count = spans.len();
+        let body = otlp_json(&self.config.service_name, &spans);
+        let request = client::post(&self.config.endpoint)
+            .header(header::CONTENT_TYPE, "application/json")
+            .timeout(Duration::from_secs(EXPORT_INTERVAL_SECONDS))
+            .body(body.to_string());
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("Cannot build trace export request: {}", e);
+                return;
+            }
+        };
+        let res = request.send().then(move |res| {
+            match res {
+                Ok(ref resp) if resp.status().is_success() => {}
+                Ok(resp) => warn!("Collector rejected {} spans: {}", count, resp.status()),
+                Err(e) => warn!("Cannot export {} spans: {}", count, e),
+            }
+            Ok::<_, ()>(())
+        });
+        ctx.spawn(res.into_actor(self));
+    }
+}
+
+impl Actor for TraceExporter {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(
+            Duration::from_secs(EXPORT_INTERVAL_SECONDS),
+            |exporter, ctx| exporter.export(ctx),
+        );
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    s!(since_epoch.as_secs() as u128 * 1_000_000_000 + since_epoch.subsec_nanos() as u128)
+}
+
+/// `ExportTraceServiceRequest` in the OTLP JSON encoding
+fn otlp_json(service_name: &str, spans: &[SpanData]) -> Value {
+    let spans: Vec<Value> = spans
+        .iter()
+        .map(|span| {
+            let attributes: Vec<Value> = span
+                .attributes
+                .iter()
+                .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+                .collect();
+            let mut value = json!({
+                "traceId": HEXLOWER.encode(&span.context.trace_id),
+                "spanId": HEXLOWER.encode(&span.context.span_id),
+                "name": span.name,
+                "kind": span.kind as i32,
+                "startTimeUnixNano": unix_nanos(span.start),
+                "endTimeUnixNano": unix_nanos(span.end),
+                "attributes": attributes,
+                // 2 is STATUS_CODE_ERROR, the rest is left unset
+                "status": {"code": if span.error { 2 } else { 0 }},
+            });
+            if let Some(parent_span_id) = span.parent_span_id {
+                value["parentSpanId"] = json!(HEXLOWER.encode(&parent_span_id));
+            }
+            value
+        })
+        .collect();
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{"key": "service.name", "value": {"stringValue": service_name}}]
+            },
+            "scopeSpans": [{
+                "scope": {"name": "knockturn"},
+                "spans": spans,
+            }]
+        }]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traceparent() {
+        let context = SpanContext::from_traceparent(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        )
+        .unwrap();
+        assert_eq!(
+            HEXLOWER.encode(&context.trace_id),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(HEXLOWER.encode(&context.span_id), "00f067aa0ba902b7");
+        assert!(context.sampled);
+
+        let not_sampled = SpanContext::from_traceparent(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00",
+        )
+        .unwrap();
+        assert!(!not_sampled.sampled);
+
+        assert!(SpanContext::from_traceparent(
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01"
+        )
+        .is_none());
+        assert!(SpanContext::from_traceparent(
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        )
+        .is_none());
+        assert!(SpanContext::from_traceparent("00-4bf92f35-00f067aa0ba902b7-01").is_none());
+    }
+
+    #[test]
+    fn test_child_without_parent_is_not_sampled() {
+        let span = Span::child("db GetTransaction", None);
+        assert!(!span.context().sampled);
+    }
+
+    #[test]
+    fn test_otlp_json() {
+        let parent = SpanContext::from_traceparent(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        )
+        .unwrap();
+        let span = SpanData {
+            context: SpanContext {
+                span_id: [1; 8],
+                ..parent
+            },
+            parent_span_id: Some(parent.span_id),
+            name: s!("HTTP GET"),
+            kind: SpanKind::Server,
+            start: UNIX_EPOCH + Duration::from_millis(1500),
+            end: UNIX_EPOCH + Duration::from_millis(2000),
+            attributes: vec![("http.method", s!("GET"))],
+            error: true,
+        };
+        let value = otlp_json("knockturn", &[span]);
+        let span = &value["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(span["spanId"], "0101010101010101");
+        assert_eq!(span["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(span["kind"], 2);
+        assert_eq!(span["startTimeUnixNano"], "1500000000");
+        assert_eq!(span["status"]["code"], 2);
+        assert_eq!(span["attributes"][0]["value"]["stringValue"], "GET");
+    }
+}
@@ -1,14 +1,28 @@
 use crate::clients::PlainHttpAuth;
+use crate::compat::{self, Future01CompatExt};
 use crate::errors::Error;
+use crate::redact::{self, Credentials};
 use crate::ser;
+use crate::slatepack::{self, Slatepack};
+use crate::wallet_version::WalletVersion;
 use actix::{Actor, Addr};
-use actix_web::client::{self, ClientConnector};
+use actix_web::client::{self, ClientConnector, ClientRequest, ClientResponse};
+use actix_web::http::StatusCode;
 use actix_web::HttpMessage;
+use blake2_rfc::blake2b::blake2b;
 use chrono::{DateTime, Utc};
+use diesel::deserialize::{self, FromSql};
+use diesel::pg::Pg;
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::Jsonb;
 use futures::Future;
 use log::{debug, error};
+use secp256k1zkp::{self as secp, aggsig, ContextFlag, PublicKey, Secp256k1, Signature};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use serde_json::from_slice;
+use serde_json::{from_slice, json};
+use std::env;
+use std::fmt;
 use std::iter::Iterator;
 use std::str::from_utf8;
 use std::time::Duration;
@@ -17,9 +31,19 @@ use uuid::Uuid;
 #[derive(Clone)]
 pub struct Wallet {
     conn: Addr<ClientConnector>,
-    username: String,
-    password: String,
+    credentials: Credentials,
     url: String,
+    outputs: OutputsConfig,
+}
+
+impl fmt::Debug for Wallet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Wallet")
+            .field("url", &self.url)
+            .field("credentials", &self.credentials)
+            .field("outputs", &self.outputs)
+            .finish()
+    }
 }
 
 const RETRIEVE_TXS_URL: &'static str = "v1/wallet/owner/retrieve_txs";
@@ -28,6 +52,135 @@ const SEND_URL: &'static str = "/v1/wallet/owner/issue_send_tx";
 const FINALIZE_URL: &'static str = "/v1/wallet/owner/finalize_tx";
 const CANCEL_TX_URL: &'static str = "/v1/wallet/owner/cancel_tx";
 const POST_TX_URL: &'static str = "/v1/wallet/owner/post_tx?fluff";
+const SUMMARY_INFO_URL: &'static str = "v1/wallet/owner/retrieve_summary_info?refresh";
+const RETRIEVE_OUTPUTS_URL: &'static str = "v1/wallet/owner/retrieve_outputs";
+const FOREIGN_RPC_URL: &'static str = "v2/foreign";
+/// Largest response read from the wallet, actix's default
+const BODY_LIMIT: usize = 256 * 1024;
+/// Largest list of outputs or transactions read from the wallet
+const LIST_BODY_LIMIT: usize = 50 * 1024 * 1024;
+
+/// Splitting change further only grows the output set
+const MAX_CHANGE_OUTPUTS: u8 = 32;
+/// Inputs of a consolidation transaction, keeps it well below the
+/// block weight limit
+const CONSOLIDATION_MAX_INPUTS: u8 = 200;
+/// Amount a consolidation sends to the wallet itself, 1 grin
+const CONSOLIDATION_AMOUNT: u64 = 1_000_000_000;
+/// Bounds of the slates buyers send, see `Slate::validate`
+const MAX_SLATE_INPUTS: usize = 500;
+const MAX_SLATE_OUTPUTS: usize = 32;
+/// 1 grin, wallets pay a fraction of it
+const MAX_SLATE_FEE: u64 = 1_000_000_000;
+/// Length of the kernel offset, a blinding factor
+pub const OFFSET_SIZE: usize = 32;
+
+/// How the wallet picks outputs to spend in a transaction it sends
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[sql_type = "Jsonb"]
+pub struct OutputSelection {
+    /// Most outputs spent in one transaction
+    pub max_outputs: u8,
+    pub num_change_outputs: u8,
+    /// Spend all outputs instead of the smallest ones covering the amount
+    pub use_all: bool,
+}
+
+impl Default for OutputSelection {
+    fn default() -> Self {
+        OutputSelection {
+            max_outputs: 10,
+            num_change_outputs: 1,
+            use_all: false,
+        }
+    }
+}
+
+impl OutputSelection {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.max_outputs == 0 {
+            return Err(Error::InvalidEntity(s!("max_outputs should be positive")));
+        }
+        if self.num_change_outputs == 0 || self.num_change_outputs > MAX_CHANGE_OUTPUTS {
+            return Err(Error::InvalidEntity(format!(
+                "num_change_outputs should be between 1 and {}",
+                MAX_CHANGE_OUTPUTS
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl ToSql<Jsonb, Pg> for OutputSelection {
+    fn to_sql<W: std::io::Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        out.write_all(&[1])?;
+        serde_json::to_writer(out, self)
+            .map(|_| serialize::IsNull::No)
+            .map_err(Into::into)
+    }
+}
+
+impl FromSql<Jsonb, Pg> for OutputSelection {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        let bytes = not_none!(bytes);
+        if bytes[0] != 1 {
+            return Err("Unsupported JSONB encoding version".into());
+        }
+        serde_json::from_slice(&bytes[1..]).map_err(Into::into)
+    }
+}
+
+/// Output selection of payouts without their own and the unspent output
+/// count above which the wallet should be consolidated
+#[derive(Debug, Clone)]
+pub struct OutputsConfig {
+    pub selection: OutputSelection,
+    pub consolidation_threshold: usize,
+}
+
+impl Default for OutputsConfig {
+    fn default() -> Self {
+        OutputsConfig {
+            selection: OutputSelection::default(),
+            consolidation_threshold: 100,
+        }
+    }
+}
+
+impl OutputsConfig {
+    /// Reads WALLET_MAX_OUTPUTS, WALLET_CHANGE_OUTPUTS,
+    /// WALLET_SELECTION_STRATEGY (`smallest` or `all`) and
+    /// WALLET_CONSOLIDATION_THRESHOLD, defaults are used for unset ones
+    pub fn from_env() -> Self {
+        let default = OutputsConfig::default();
+        let selection = OutputSelection {
+            max_outputs: env::var("WALLET_MAX_OUTPUTS")
+                .map(|v| v.parse().expect("WALLET_MAX_OUTPUTS must be a number"))
+                .unwrap_or(default.selection.max_outputs),
+            num_change_outputs: env::var("WALLET_CHANGE_OUTPUTS")
+                .map(|v| v.parse().expect("WALLET_CHANGE_OUTPUTS must be a number"))
+                .unwrap_or(default.selection.num_change_outputs),
+            use_all: match env::var("WALLET_SELECTION_STRATEGY") {
+                Ok(ref strategy) if strategy == "all" => true,
+                Ok(ref strategy) if strategy == "smallest" => false,
+                Ok(_) => panic!("WALLET_SELECTION_STRATEGY must be smallest or all"),
+                Err(_) => default.selection.use_all,
+            },
+        };
+        selection
+            .validate()
+            .expect("Invalid wallet output selection");
+        OutputsConfig {
+            selection,
+            consolidation_threshold: env::var("WALLET_CONSOLIDATION_THRESHOLD")
+                .map(|v| {
+                    v.parse()
+                        .expect("WALLET_CONSOLIDATION_THRESHOLD must be a number")
+                })
+                .unwrap_or(default.consolidation_threshold),
+        }
+    }
+}
 
 impl Wallet {
     pub fn new(url: &str, username: &str, password: &str) -> Self {
@@ -36,211 +189,283 @@ impl Wallet {
             .conn_keep_alive(Duration::from_secs(300));
         Wallet {
             url: url.trim_end_matches('/').to_owned(),
-            username: username.to_owned(),
-            password: password.to_owned(),
+            credentials: Credentials::new(username, password),
             conn: connector.start(),
+            outputs: OutputsConfig::default(),
         }
     }
 
+    pub fn with_outputs_config(mut self, outputs: OutputsConfig) -> Self {
+        self.outputs = outputs;
+        self
+    }
+
+    pub fn outputs_config(&self) -> &OutputsConfig {
+        &self.outputs
+    }
+
+    /// Version and slate versions of the wallet, `None` for wallets before
+    /// 1.1 which have no v2 foreign API to ask
+    pub fn check_version(&self) -> impl Future<Item = Option<WalletVersion>, Error = Error> {
+        let url = format!("{}/{}", self.url, FOREIGN_RPC_URL);
+        debug!("Check wallet version {}", url);
+        let request = client::post(&url)
+            .auth(&self.credentials)
+            .json(json!({
+                "jsonrpc": "2.0",
+                "method": "check_version",
+                "id": 1,
+                "params": []
+            }))
+            .unwrap();
+        compat::to_01(async move {
+            let resp = request
+                .send()
+                .compat()
+                .await
+                .map_err(|e| Error::WalletAPIError(s!(e)))?;
+            if resp.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            let resp: RpcResponse<WalletVersion> =
+                read_json(check_status(resp)?, BODY_LIMIT).await?;
+            Ok(Some(resp.result.ok))
+        })
+    }
+
+    /// Height of the chain as the wallet sees it, used when our node is unavailable
+    pub fn last_confirmed_height(&self) -> impl Future<Item = u64, Error = Error> {
+        let url = format!("{}/{}", self.url, SUMMARY_INFO_URL);
+        debug!("Get wallet summary info {}", url);
+        let request = client::get(&url).auth(&self.credentials).finish().unwrap();
+        compat::to_01(async move {
+            let resp = send(request).await?;
+            let (_, info): (bool, WalletInfo) = read_json(resp, BODY_LIMIT).await?;
+            Ok(info.last_confirmed_height)
+        })
+    }
+
+    /// Height where outputs of the wallet transaction (by local id) were
+    /// confirmed, `None` if they are not confirmed yet
+    pub fn tx_height(&self, wallet_tx_id: i64) -> impl Future<Item = Option<u64>, Error = Error> {
+        let url = format!(
+            "{}/{}?refresh&tx_id={}",
+            self.url, RETRIEVE_OUTPUTS_URL, wallet_tx_id
+        );
+        debug!("Get transaction outputs from wallet {}", url);
+        let request = client::get(&url).auth(&self.credentials).finish().unwrap();
+        compat::to_01(async move {
+            let resp = send(request).await?;
+            let (_, outputs): (bool, Vec<(OutputData, serde_json::Value)>) =
+                read_json(resp, BODY_LIMIT).await?;
+            Ok(outputs
+                .into_iter()
+                .filter(|(output, _)| output.status != OutputStatus::Unconfirmed)
+                .map(|(output, _)| output.height)
+                .max())
+        })
+    }
+
+    /// Outputs the wallet knows about, spent ones included
+    pub fn retrieve_outputs(&self) -> impl Future<Item = Vec<OutputData>, Error = Error> {
+        let url = format!("{}/{}?refresh", self.url, RETRIEVE_OUTPUTS_URL);
+        debug!("Get outputs from wallet {}", url);
+        let request = client::get(&url).auth(&self.credentials).finish().unwrap();
+        compat::to_01(async move {
+            let resp = send(request).await?;
+            let (_, outputs): (bool, Vec<(OutputData, serde_json::Value)>) =
+                read_json(resp, LIST_BODY_LIMIT).await?;
+            Ok(outputs.into_iter().map(|(output, _)| output).collect())
+        })
+    }
+
+    /// Whole transaction log of the wallet, owner API v1 can't page it
+    pub fn list_txs(&self) -> impl Future<Item = Vec<TxLogEntry>, Error = Error> {
+        let url = format!("{}/{}?refresh", self.url, RETRIEVE_TXS_URL);
+        debug!("Get all transactions from wallet {}", url);
+        let request = client::get(&url).auth(&self.credentials).finish().unwrap();
+        compat::to_01(async move {
+            let resp = send(request).await?;
+            let txs: TxListResp = read_json(resp, LIST_BODY_LIMIT).await?;
+            Ok(txs.txs)
+        })
+    }
+
     pub fn get_tx(&self, tx_id: &str) -> impl Future<Item = TxLogEntry, Error = Error> {
         let tx_id = tx_id.to_owned();
         let url = format!("{}/{}?tx_id={}&refresh", self.url, RETRIEVE_TXS_URL, tx_id);
         debug!("Get transaction from wallet {}", url);
-        client::get(&url) // <- Create request builder
-            .auth(&self.username, &self.password)
-            .finish()
-            .unwrap()
-            .send() // <- Send http request
-            .map_err(|e| Error::WalletAPIError(s!(e)))
-            .and_then(|resp| {
-                if !resp.status().is_success() {
-                    Err(Error::WalletAPIError(format!("Error status: {:?}", resp)))
-                } else {
-                    Ok(resp)
-                }
-            })
-            .and_then(|resp| {
-                // <- server http response
-                debug!("Response: {:?}", resp);
-                resp.body()
-                    .map_err(|e| Error::WalletAPIError(s!(e)))
-                    .and_then(move |bytes| {
-                        let txs: TxListResp = from_slice(&bytes).map_err(|e| {
-                            error!(
-                                "Cannot decode json {:?}:\n with error {} ",
-                                from_utf8(&bytes),
-                                e
-                            );
-                            Error::WalletAPIError(format!("Cannot decode json {}", e))
-                        })?;
-                        if txs.txs.len() == 0 {
-                            return Err(Error::WalletAPIError(format!(
-                                "Transaction with slate_id {} not found",
-                                tx_id
-                            )));
-                        }
-                        if txs.txs.len() > 1 {
-                            return Err(Error::WalletAPIError(format!(
-                                "Wallet returned more than one transaction with slate_id {}",
-                                tx_id
-                            )));
-                        }
-                        let tx = txs.txs.into_iter().next().unwrap();
-                        Ok(tx)
-                    })
-            })
+        let request = client::get(&url).auth(&self.credentials).finish().unwrap();
+        compat::to_01(async move {
+            let resp = send(request).await?;
+            debug!("Response: {:?}", redact::Response(&resp));
+            let txs: TxListResp = read_json(resp, BODY_LIMIT).await?;
+            if txs.txs.len() == 0 {
+                return Err(Error::WalletAPIError(format!(
+                    "Transaction with slate_id {} not found",
+                    tx_id
+                )));
+            }
+            if txs.txs.len() > 1 {
+                return Err(Error::WalletAPIError(format!(
+                    "Wallet returned more than one transaction with slate_id {}",
+                    tx_id
+                )));
+            }
+            Ok(txs.txs.into_iter().next().unwrap())
+        })
     }
 
     pub fn receive(&self, slate: &Slate) -> impl Future<Item = Slate, Error = Error> {
         let url = format!("{}/{}", self.url, RECEIVE_URL);
         debug!("Receive slate by wallet  {}", url);
-        client::post(&url)
-            .auth(&self.username, &self.password)
+        let request = client::post(&url)
+            .auth(&self.credentials)
             .json(slate)
-            .unwrap()
-            .send()
-            .map_err(|e| Error::WalletAPIError(s!(e)))
-            .and_then(|resp| {
-                if !resp.status().is_success() {
-                    Err(Error::WalletAPIError(format!("Error status: {:?}", resp)))
-                } else {
-                    Ok(resp)
-                }
-            })
-            .and_then(|resp| {
-                debug!("Response: {:?}", resp);
-                resp.body()
-                    .map_err(|e| Error::WalletAPIError(s!(e)))
-                    .and_then(move |bytes| {
-                        let slate_resp: Slate = from_slice(&bytes).map_err(|e| {
-                            error!(
-                                "Cannot decode json {:?}:\n with error {} ",
-                                from_utf8(&bytes),
-                                e
-                            );
-                            Error::WalletAPIError(format!("Cannot decode json {}", e))
-                        })?;
-                        Ok(slate_resp)
-                    })
-            })
+            .unwrap();
+        compat::to_01(async move {
+            let resp = send(request).await?;
+            debug!("Response: {:?}", redact::Response(&resp));
+            read_json(resp, BODY_LIMIT).await
+        })
     }
 
     pub fn finalize(&self, slate: &Slate) -> impl Future<Item = Slate, Error = Error> {
         let url = format!("{}/{}", self.url, FINALIZE_URL);
         debug!("Finalize slate by wallet {}", url);
-        client::post(&url)
-            .auth(&self.username, &self.password)
+        let request = client::post(&url)
+            .auth(&self.credentials)
             .json(slate)
-            .unwrap()
-            .send()
-            .map_err(|e| Error::WalletAPIError(s!(e)))
-            .and_then(|resp| {
-                if !resp.status().is_success() {
-                    Err(Error::WalletAPIError(format!("Error status: {:?}", resp)))
-                } else {
-                    Ok(resp)
-                }
-            })
-            .and_then(|resp| {
-                debug!("Response: {:?}", resp);
-                resp.body()
-                    .map_err(|e| Error::WalletAPIError(s!(e)))
-                    .and_then(move |bytes| {
-                        let slate_resp: Slate = from_slice(&bytes).map_err(|e| {
-                            error!(
-                                "Cannot decode json {:?}:\n with error {} ",
-                                from_utf8(&bytes),
-                                e
-                            );
-                            Error::WalletAPIError(format!("Cannot decode json {}", e))
-                        })?;
-                        Ok(slate_resp)
-                    })
-            })
+            .unwrap();
+        compat::to_01(async move {
+            let resp = send(request).await?;
+            debug!("Response: {:?}", redact::Response(&resp));
+            read_json(resp, BODY_LIMIT).await
+        })
     }
+
     pub fn cancel_tx(&self, tx_slate_id: &str) -> impl Future<Item = (), Error = Error> {
         let url = format!("{}/{}?tx_id={}", self.url, CANCEL_TX_URL, tx_slate_id);
         debug!("Cancel transaction in wallet {}", url);
-        client::post(&url)
-            .auth(&self.username, &self.password)
-            .finish()
-            .unwrap()
-            .send()
-            .map_err(|e| Error::WalletAPIError(s!(e)))
-            .and_then(|resp| {
-                if !resp.status().is_success() {
-                    Err(Error::WalletAPIError(format!("Error status: {:?}", resp)))
-                } else {
-                    Ok(())
-                }
-            })
+        let request = client::post(&url).auth(&self.credentials).finish().unwrap();
+        compat::to_01(async move {
+            send(request).await?;
+            Ok(())
+        })
     }
 
-    pub fn post_tx(&self) -> impl Future<Item = (), Error = Error> {
+    pub fn post_tx(&self, slate: &Slate) -> impl Future<Item = (), Error = Error> {
         let url = format!("{}/{}", self.url, POST_TX_URL);
         debug!("Post transaction in chain by wallet as {}", url);
-        client::post(&url)
-            .auth(&self.username, &self.password)
-            .finish()
-            .unwrap()
-            .send()
-            .map_err(|e| Error::WalletAPIError(s!(e)))
-            .and_then(|resp| {
-                if !resp.status().is_success() {
-                    Err(Error::WalletAPIError(format!("Error status: {:?}", resp)))
-                } else {
-                    Ok(())
-                }
-            })
+        let request = client::post(&url)
+            .auth(&self.credentials)
+            .json(slate)
+            .unwrap();
+        compat::to_01(async move {
+            send(request).await?;
+            Ok(())
+        })
     }
 
     pub fn create_slate(
         &self,
         amount: u64,
         message: String,
+        selection: OutputSelection,
     ) -> impl Future<Item = Slate, Error = Error> {
         let url = format!("{}/{}", self.url, SEND_URL);
-        debug!("Receive as {} {}: {}", self.username, self.password, url);
+        debug!("Create slate by wallet {}", url);
         let payment = SendTx {
             amount: amount,
             minimum_confirmations: 10,
             method: "file",
             dest: "./gpp_always_pays.grinslate",
-            max_outputs: 10,
-            num_change_outputs: 1,
-            selection_strategy_is_use_all: false,
+            max_outputs: selection.max_outputs,
+            num_change_outputs: selection.num_change_outputs,
+            selection_strategy_is_use_all: selection.use_all,
             message: Some(message),
         };
-        client::post(&url)
-            .auth(&self.username, &self.password)
+        let request = client::post(&url)
+            .auth(&self.credentials)
             .json(&payment)
-            .unwrap()
-            .send()
-            .map_err(|e| Error::WalletAPIError(s!(e)))
-            .and_then(|resp| {
-                if !resp.status().is_success() {
-                    Err(Error::WalletAPIError(format!("Error status: {:?}", resp)))
-                } else {
-                    Ok(resp)
-                }
-            })
-            .and_then(|resp| {
-                debug!("Response: {:?}", resp);
-                resp.body()
-                    .map_err(|e| Error::WalletAPIError(s!(e)))
-                    .and_then(move |bytes| {
-                        let slate_resp: Slate = from_slice(&bytes).map_err(|e| {
-                            error!(
-                                "Cannot decode json {:?}:\n with error {} ",
-                                from_utf8(&bytes),
-                                e
-                            );
-                            Error::WalletAPIError(format!("Cannot decode json {}", e))
-                        })?;
-                        Ok(slate_resp)
-                    })
-            })
+            .unwrap();
+        compat::to_01(async move {
+            let resp = send(request).await?;
+            debug!("Response: {:?}", redact::Response(&resp));
+            read_json(resp, BODY_LIMIT).await
+        })
+    }
+
+    /// Spends up to `CONSOLIDATION_MAX_INPUTS` outputs in a transaction to
+    /// the wallet itself, which receives, finalizes and posts it
+    pub fn consolidate(&self) -> impl Future<Item = Slate, Error = Error> {
+        let selection = OutputSelection {
+            max_outputs: CONSOLIDATION_MAX_INPUTS,
+            num_change_outputs: self.outputs.selection.num_change_outputs,
+            use_all: true,
+        };
+        let wallet = self.clone();
+        compat::to_01(async move {
+            let slate = wallet
+                .create_slate(CONSOLIDATION_AMOUNT, s!("Output consolidation"), selection)
+                .compat()
+                .await?;
+            let slate = wallet.receive(&slate).compat().await?;
+            let slate = wallet.finalize(&slate).compat().await?;
+            wallet.post_tx(&slate).compat().await?;
+            Ok(slate)
+        })
+    }
+}
+
+/// Sends the request, fails unless the wallet answered with a success status
+async fn send(request: ClientRequest) -> Result<ClientResponse, Error> {
+    let resp = request
+        .send()
+        .compat()
+        .await
+        .map_err(|e| Error::WalletAPIError(s!(e)))?;
+    check_status(resp)
+}
+
+fn check_status(resp: ClientResponse) -> Result<ClientResponse, Error> {
+    if !resp.status().is_success() {
+        return Err(Error::WalletAPIError(format!(
+            "Error status: {:?}",
+            redact::Response(&resp)
+        )));
     }
+    Ok(resp)
+}
+
+/// Reads at most `limit` bytes of the response and decodes them
+async fn read_json<T: DeserializeOwned>(resp: ClientResponse, limit: usize) -> Result<T, Error> {
+    let bytes = resp
+        .body()
+        .limit(limit)
+        .compat()
+        .await
+        .map_err(|e| Error::WalletAPIError(s!(e)))?;
+    from_slice(&bytes).map_err(|e| {
+        error!(
+            "Cannot decode json {:?}:\n with error {} ",
+            from_utf8(&bytes),
+            e
+        );
+        Error::WalletAPIError(format!("Cannot decode json {}", e))
+    })
+}
+
+/// JSON-RPC answer of the v2 APIs
+#[derive(Debug, Deserialize)]
+struct RpcResponse<T> {
+    result: RpcResult<T>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcResult<T> {
+    #[serde(rename = "Ok")]
+    ok: T,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -292,6 +517,31 @@ pub struct TxLogEntry {
 
 pub type Identifier = String;
 
+/// Part of wallet summary we are interested in
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WalletInfo {
+    #[serde(with = "ser::string_or_u64")]
+    pub last_confirmed_height: u64,
+}
+
+/// Part of wallet output data we are interested in
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutputData {
+    pub status: OutputStatus,
+    /// Height of the block the output was confirmed in, or the wallet
+    /// height when it was created if unconfirmed
+    #[serde(with = "ser::string_or_u64")]
+    pub height: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub enum OutputStatus {
+    Unconfirmed,
+    Unspent,
+    Locked,
+    Spent,
+}
+
 /*
 #[derive(Clone, PartialEq, Eq, Ord, Hash, PartialOrd)]
 pub struct Identifier([u8; IDENTIFIER_SIZE]);
@@ -349,6 +599,46 @@ pub struct ParticipantData {
     pub message_sig: Option<Vec<u8>>,
 }
 
+impl ParticipantData {
+    /// Hex of `public_blind_excess` when the message is signed with it,
+    /// `None` for an unsigned message. Checked like grin wallets do, the
+    /// signature is over the blake2b hash of the message. Slates carry the
+    /// raw data of the signature, not its compact serialization.
+    pub fn message_signer(&self) -> Result<Option<String>, Error> {
+        let sig = match self.message_sig {
+            Some(ref sig) => sig,
+            None => return Ok(None),
+        };
+        let invalid = || Error::InvalidEntity(s!("slate message signature is invalid"));
+        let message = self.message.as_ref().ok_or_else(invalid)?;
+        if sig.len() != secp::constants::COMPACT_SIGNATURE_SIZE {
+            return Err(invalid());
+        }
+        let secp = Secp256k1::with_caps(ContextFlag::VerifyOnly);
+        let mut raw = [0; secp::constants::COMPACT_SIGNATURE_SIZE];
+        raw.copy_from_slice(sig);
+        let sig = Signature::from_raw_data(&raw).map_err(|_| invalid())?;
+        let public_key =
+            PublicKey::from_slice(&secp, &self.public_blind_excess).map_err(|_| invalid())?;
+        let hash = blake2b(secp::constants::MESSAGE_SIZE, &[], message.as_bytes());
+        let msg = secp::Message::from_slice(hash.as_bytes()).map_err(|_| invalid())?;
+        let valid = aggsig::verify_single(
+            &secp,
+            &sig,
+            &msg,
+            None,
+            &public_key,
+            Some(&public_key),
+            None,
+            false,
+        );
+        if !valid {
+            return Err(invalid());
+        }
+        Ok(Some(ser::to_hex(self.public_blind_excess.clone())))
+    }
+}
+
 /// A 'Slate' is passed around to all parties to build up all of the public
 /// transaction data needed to create a finalized transaction. Callers can pass
 /// the slate around by whatever means they choose, (but we can provide some
@@ -384,6 +674,699 @@ fn no_version() -> u64 {
     0
 }
 
+impl Slate {
+    /// Checks the shape of a slate a buyer sent before the wallet sees it:
+    /// the sender's part of a two party payment with a single kernel which
+    /// matches the slate and sane numbers of inputs and outputs. Signatures
+    /// and proofs are left to the wallet.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.validate_parts(true)
+    }
+
+    /// Same checks for the slate of a V4 one, which leaves the sender's
+    /// inputs and change out
+    pub fn validate_compact(&self) -> Result<(), Error> {
+        self.validate_parts(false)
+    }
+
+    fn validate_parts(&self, with_inputs: bool) -> Result<(), Error> {
+        let invalid = |reason: &str| Err(Error::InvalidSlate(reason.to_owned()));
+        if self.num_participants != 2 {
+            return invalid("only two party transactions are supported");
+        }
+        match self.participant_data.as_slice() {
+            [sender] if sender.id == 0 => {
+                if sender.public_blind_excess.len() != secp::constants::COMPRESSED_PUBLIC_KEY_SIZE
+                    || sender.public_nonce.len() != secp::constants::COMPRESSED_PUBLIC_KEY_SIZE
+                {
+                    return invalid("sender's public keys are malformed");
+                }
+                if sender.part_sig.is_some() {
+                    return invalid("sender signed too early");
+                }
+            }
+            _ => return invalid("slate should only have the sender's data"),
+        }
+        if self.amount == 0 {
+            return invalid("amount is zero");
+        }
+        if self.fee == 0 || self.fee > MAX_SLATE_FEE {
+            return invalid("fee is out of bounds");
+        }
+        if self.amount.checked_add(self.fee).is_none() {
+            return invalid("amount is out of bounds");
+        }
+        // A kernel locked above the sender's height can't be mined before
+        // the payment expires
+        if self.lock_height > self.height {
+            return invalid("lock height is above the current height");
+        }
+
+        let body = &self.tx.body;
+        if self.tx.offset.len() != OFFSET_SIZE {
+            return invalid("kernel offset is malformed");
+        }
+        match body.kernels.as_slice() {
+            [kernel] => {
+                let features = if self.lock_height == 0 {
+                    KernelFeatures::Plain
+                } else {
+                    KernelFeatures::HeightLocked
+                };
+                if kernel.features != features
+                    || kernel.fee != self.fee
+                    || kernel.lock_height != self.lock_height
+                {
+                    return invalid("kernel doesn't match the slate");
+                }
+            }
+            _ => return invalid("transaction should have one kernel"),
+        }
+        if (with_inputs && body.inputs.is_empty()) || body.inputs.len() > MAX_SLATE_INPUTS {
+            return invalid("number of inputs is out of bounds");
+        }
+        if body.outputs.len() > MAX_SLATE_OUTPUTS {
+            return invalid("too many outputs");
+        }
+        let commit_size = secp::constants::PEDERSEN_COMMITMENT_SIZE;
+        if body
+            .inputs
+            .iter()
+            .any(|input| input.commit.len() != commit_size)
+        {
+            return invalid("input commitment is malformed");
+        }
+        if body.outputs.iter().any(|output| {
+            output.features != OutputFeatures::Plain
+                || output.commit.len() != commit_size
+                || output.proof.len() > secp::constants::MAX_PROOF_SIZE
+        }) {
+            return invalid("output is malformed");
+        }
+        Ok(())
+    }
+}
+
+/// Slate of the buyer's wallet in the version it was sent. V0 and V1
+/// slates are what the wallet API takes, V2 and V3 ones only differ in
+/// encoding and are converted. V4 slates, also the payload of slatepacks,
+/// are compact: the sender keeps its inputs and change to itself and the
+/// answer only carries the receiver's part.
+#[derive(Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum VersionedSlate {
+    V1(Slate),
+    V2(SlateV2),
+    V4(SlateV4),
+    Slatepack(Slatepack),
+}
+
+impl VersionedSlate {
+    pub fn parse(value: serde_json::Value) -> Result<Self, Error> {
+        let invalid = |e: serde_json::Error| Error::InvalidEntity(format!("invalid slate: {}", e));
+        if let Some(text) = value.as_str() {
+            if text.contains(slatepack::HEADER) {
+                let slatepack = Slatepack::decode(text)?;
+                slatepack.0.check_payment_proof()?;
+                return Ok(VersionedSlate::Slatepack(slatepack));
+            }
+        }
+        if let Some(ver) = value.get("ver") {
+            let version = ver
+                .as_str()
+                .and_then(|ver| ver.split(':').next())
+                .and_then(|version| version.parse::<u64>().ok());
+            return match version {
+                Some(4) => {
+                    let slate: SlateV4 = serde_json::from_value(value).map_err(invalid)?;
+                    slate.check_payment_proof()?;
+                    Ok(VersionedSlate::V4(slate))
+                }
+                Some(version) => Err(Error::UnsupportedSlateVersion(format!("V{}", version))),
+                None => Err(Error::InvalidEntity(s!("invalid slate: malformed version"))),
+            };
+        }
+        let version = match value.get("version_info") {
+            Some(info) => info.get("version").and_then(|v| v.as_u64()),
+            None => value.get("version").and_then(|v| v.as_u64()).or(Some(0)),
+        };
+        match version {
+            Some(0) | Some(1) => Ok(VersionedSlate::V1(
+                serde_json::from_value(value).map_err(invalid)?,
+            )),
+            Some(2) | Some(3) => {
+                let slate: SlateV2 = serde_json::from_value(value).map_err(invalid)?;
+                if slate.payment_proof.as_ref().map_or(false, |p| !p.is_null()) {
+                    return Err(no_payment_proofs());
+                }
+                Ok(VersionedSlate::V2(slate))
+            }
+            Some(version) => Err(Error::UnsupportedSlateVersion(format!("V{}", version))),
+            None => Err(Error::InvalidEntity(s!("invalid slate: no version"))),
+        }
+    }
+
+    /// Slate version, slatepacks carry V4 slates
+    pub fn version(&self) -> u64 {
+        match self {
+            VersionedSlate::V1(slate) => slate.version,
+            VersionedSlate::V2(slate) => slate.version_info.version as u64,
+            VersionedSlate::V4(_) | VersionedSlate::Slatepack(_) => 4,
+        }
+    }
+
+    /// The slate as the wallet API takes it
+    pub fn to_slate(&self) -> Result<Slate, Error> {
+        match self {
+            VersionedSlate::V1(slate) => Ok(slate.clone()),
+            VersionedSlate::V2(slate) => slate.to_v1(),
+            VersionedSlate::V4(slate) => slate.to_v1(),
+            VersionedSlate::Slatepack(slatepack) => slatepack.0.to_v1(),
+        }
+    }
+
+    /// The slate as the wallet API takes it, if `Slate::validate` lets it
+    /// through. Compact slates don't need the sender's inputs.
+    pub fn to_valid_slate(&self) -> Result<Slate, Error> {
+        let slate = self.to_slate()?;
+        match self {
+            VersionedSlate::V1(_) | VersionedSlate::V2(_) => slate.validate()?,
+            VersionedSlate::V4(_) | VersionedSlate::Slatepack(_) => slate.validate_compact()?,
+        }
+        Ok(slate)
+    }
+
+    /// `slate` of the wallet in the version of this one, so the buyer's
+    /// wallet can read the answer
+    pub fn answer(&self, slate: Slate) -> Result<VersionedSlate, Error> {
+        let answer = match self {
+            VersionedSlate::V1(_) => VersionedSlate::V1(slate),
+            VersionedSlate::V2(request) => VersionedSlate::V2(SlateV2 {
+                version_info: request.version_info.clone(),
+                ttl_cutoff_height: request.ttl_cutoff_height,
+                payment_proof: None,
+                ..SlateV2::from_v1(&slate)?
+            }),
+            VersionedSlate::V4(request) => VersionedSlate::V4(request.answer(&slate)?),
+            VersionedSlate::Slatepack(request) => {
+                VersionedSlate::Slatepack(Slatepack(request.0.answer(&slate)?))
+            }
+        };
+        Ok(answer)
+    }
+}
+
+pub fn no_payment_proofs() -> Error {
+    Error::InvalidEntity(s!(
+        "payment proofs aren't supported, send the slate without one"
+    ))
+}
+
+/// Raw data of a signature in its compact serialization, slates in hex
+/// carry the latter, V0 and V1 ones and binary slates the former
+pub fn raw_signature(compact: &[u8]) -> Result<Vec<u8>, Error> {
+    let secp = Secp256k1::with_caps(ContextFlag::None);
+    Signature::from_compact(&secp, compact)
+        .map(|sig| sig.to_raw_data().to_vec())
+        .map_err(|_| Error::InvalidSlate(s!("signature is malformed")))
+}
+
+/// Compact serialization of the raw data of a signature
+pub fn compact_signature(raw: &[u8]) -> Result<Vec<u8>, Error> {
+    let invalid = || Error::InvalidSlate(s!("signature is malformed"));
+    if raw.len() != secp::constants::COMPACT_SIGNATURE_SIZE {
+        return Err(invalid());
+    }
+    let mut data = [0; secp::constants::COMPACT_SIGNATURE_SIZE];
+    data.copy_from_slice(raw);
+    let secp = Secp256k1::with_caps(ContextFlag::None);
+    Signature::from_raw_data(&data)
+        .map(|sig| sig.serialize_compact(&secp).to_vec())
+        .map_err(|_| invalid())
+}
+
+fn opt_raw_signature(compact: &Option<Vec<u8>>) -> Result<Option<Vec<u8>>, Error> {
+    compact.as_ref().map(|sig| raw_signature(sig)).transpose()
+}
+
+fn opt_compact_signature(raw: &Option<Vec<u8>>) -> Result<Option<Vec<u8>>, Error> {
+    raw.as_ref().map(|sig| compact_signature(sig)).transpose()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VersionInfo {
+    pub version: u16,
+    /// Version the slate was created in
+    pub orig_version: u16,
+    pub block_header_version: u16,
+}
+
+/// V2 slate, V3 adds `ttl_cutoff_height` and `payment_proof`. Numbers are
+/// strings, bytes hex strings and signatures compact, otherwise it's the
+/// same as `Slate`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SlateV2 {
+    pub version_info: VersionInfo,
+    pub num_participants: usize,
+    pub id: Uuid,
+    pub tx: TransactionV2,
+    #[serde(with = "ser::string_or_u64")]
+    pub amount: u64,
+    #[serde(with = "ser::string_or_u64")]
+    pub fee: u64,
+    #[serde(with = "ser::string_or_u64")]
+    pub height: u64,
+    #[serde(with = "ser::string_or_u64")]
+    pub lock_height: u64,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "ser::opt_string_or_u64"
+    )]
+    pub ttl_cutoff_height: Option<u64>,
+    pub participant_data: Vec<ParticipantDataV2>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payment_proof: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ParticipantDataV2 {
+    #[serde(with = "ser::string_or_u64")]
+    pub id: u64,
+    #[serde(with = "ser::hex_bytes")]
+    pub public_blind_excess: Vec<u8>,
+    #[serde(with = "ser::hex_bytes")]
+    pub public_nonce: Vec<u8>,
+    #[serde(default, with = "ser::opt_hex_bytes")]
+    pub part_sig: Option<Vec<u8>>,
+    pub message: Option<String>,
+    #[serde(default, with = "ser::opt_hex_bytes")]
+    pub message_sig: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransactionV2 {
+    #[serde(with = "ser::hex_bytes")]
+    pub offset: Vec<u8>,
+    pub body: TransactionBodyV2,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransactionBodyV2 {
+    pub inputs: Vec<InputV2>,
+    pub outputs: Vec<OutputV2>,
+    pub kernels: Vec<TxKernelV2>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InputV2 {
+    pub features: OutputFeatures,
+    #[serde(with = "ser::hex_bytes")]
+    pub commit: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutputV2 {
+    pub features: OutputFeatures,
+    #[serde(with = "ser::hex_bytes")]
+    pub commit: Vec<u8>,
+    #[serde(with = "ser::hex_bytes")]
+    pub proof: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TxKernelV2 {
+    pub features: KernelFeatures,
+    #[serde(with = "ser::string_or_u64")]
+    pub fee: u64,
+    #[serde(with = "ser::string_or_u64")]
+    pub lock_height: u64,
+    #[serde(with = "ser::hex_bytes")]
+    pub excess: Vec<u8>,
+    #[serde(with = "ser::hex_bytes")]
+    pub excess_sig: Vec<u8>,
+}
+
+impl SlateV2 {
+    /// Same slate as V1, which is what the wallet API takes
+    pub fn to_v1(&self) -> Result<Slate, Error> {
+        Ok(Slate {
+            num_participants: self.num_participants,
+            id: self.id,
+            tx: Transaction {
+                offset: self.tx.offset.clone(),
+                body: TransactionBody {
+                    inputs: self
+                        .tx
+                        .body
+                        .inputs
+                        .iter()
+                        .map(|input| Input {
+                            features: input.features,
+                            commit: input.commit.clone(),
+                        })
+                        .collect(),
+                    outputs: self
+                        .tx
+                        .body
+                        .outputs
+                        .iter()
+                        .map(|output| Output {
+                            features: output.features,
+                            commit: output.commit.clone(),
+                            proof: output.proof.clone(),
+                        })
+                        .collect(),
+                    kernels: self
+                        .tx
+                        .body
+                        .kernels
+                        .iter()
+                        .map(|kernel| {
+                            Ok(TxKernel {
+                                features: kernel.features,
+                                fee: kernel.fee,
+                                lock_height: kernel.lock_height,
+                                excess: kernel.excess.clone(),
+                                excess_sig: raw_signature(&kernel.excess_sig)?,
+                            })
+                        })
+                        .collect::<Result<_, Error>>()?,
+                },
+            },
+            amount: self.amount,
+            fee: self.fee,
+            height: self.height,
+            lock_height: self.lock_height,
+            participant_data: self
+                .participant_data
+                .iter()
+                .map(|participant| {
+                    Ok(ParticipantData {
+                        id: participant.id,
+                        public_blind_excess: participant.public_blind_excess.clone(),
+                        public_nonce: participant.public_nonce.clone(),
+                        part_sig: opt_raw_signature(&participant.part_sig)?,
+                        message: participant.message.clone(),
+                        message_sig: opt_raw_signature(&participant.message_sig)?,
+                    })
+                })
+                .collect::<Result<_, Error>>()?,
+            version: 1,
+        })
+    }
+
+    /// V2 slate of a V1 one, created as V2
+    pub fn from_v1(slate: &Slate) -> Result<Self, Error> {
+        let body = &slate.tx.body;
+        Ok(SlateV2 {
+            version_info: VersionInfo {
+                version: 2,
+                orig_version: 2,
+                block_header_version: 1,
+            },
+            num_participants: slate.num_participants,
+            id: slate.id,
+            tx: TransactionV2 {
+                offset: slate.tx.offset.clone(),
+                body: TransactionBodyV2 {
+                    inputs: body
+                        .inputs
+                        .iter()
+                        .map(|input| InputV2 {
+                            features: input.features,
+                            commit: input.commit.clone(),
+                        })
+                        .collect(),
+                    outputs: body
+                        .outputs
+                        .iter()
+                        .map(|output| OutputV2 {
+                            features: output.features,
+                            commit: output.commit.clone(),
+                            proof: output.proof.clone(),
+                        })
+                        .collect(),
+                    kernels: body
+                        .kernels
+                        .iter()
+                        .map(|kernel| {
+                            Ok(TxKernelV2 {
+                                features: kernel.features,
+                                fee: kernel.fee,
+                                lock_height: kernel.lock_height,
+                                excess: kernel.excess.clone(),
+                                excess_sig: compact_signature(&kernel.excess_sig)?,
+                            })
+                        })
+                        .collect::<Result<_, Error>>()?,
+                },
+            },
+            amount: slate.amount,
+            fee: slate.fee,
+            height: slate.height,
+            lock_height: slate.lock_height,
+            ttl_cutoff_height: None,
+            participant_data: slate
+                .participant_data
+                .iter()
+                .map(|participant| {
+                    Ok(ParticipantDataV2 {
+                        id: participant.id,
+                        public_blind_excess: participant.public_blind_excess.clone(),
+                        public_nonce: participant.public_nonce.clone(),
+                        part_sig: opt_compact_signature(&participant.part_sig)?,
+                        message: participant.message.clone(),
+                        message_sig: opt_compact_signature(&participant.message_sig)?,
+                    })
+                })
+                .collect::<Result<_, Error>>()?,
+            payment_proof: None,
+        })
+    }
+}
+
+/// V4 slate, as grin wallets 5.0 and newer send it. It's compact: the
+/// sender's S1 leaves its inputs and change out and the receiver's S2
+/// only carries the receiver's signature data and outputs, each side
+/// keeps the rest. Participants are in the order of `sigs`, the sender
+/// first, and slates carry no messages nor the sender's height.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SlateV4 {
+    /// Version and block header version, as `4:3`
+    pub ver: String,
+    pub id: Uuid,
+    /// State of the slate, `S1` as sent by the sender
+    pub sta: String,
+    #[serde(default = "zero_offset", with = "ser::hex_bytes")]
+    pub off: Vec<u8>,
+    #[serde(default = "two_parts", skip_serializing_if = "is_two_parts")]
+    pub num_parts: u8,
+    #[serde(default, skip_serializing_if = "is_zero", with = "ser::string_or_u64")]
+    pub amt: u64,
+    /// Fee with the fee shift in the bits above `FEE_BITS`
+    #[serde(default, skip_serializing_if = "is_zero", with = "ser::string_or_u64")]
+    pub fee: u64,
+    /// Kernel features, as `KernelFeatures`
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub feat: u8,
+    #[serde(default, skip_serializing_if = "is_zero", with = "ser::string_or_u64")]
+    pub ttl: u64,
+    pub sigs: Vec<ParticipantDataV4>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coms: Option<Vec<CommitsV4>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proof: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub feat_args: Option<KernelFeaturesArgsV4>,
+}
+
+/// Bits of the fee in the fee of V4 slates
+pub const FEE_BITS: u64 = 40;
+
+fn zero_offset() -> Vec<u8> {
+    vec![0; OFFSET_SIZE]
+}
+
+fn two_parts() -> u8 {
+    2
+}
+
+fn is_two_parts(num_parts: &u8) -> bool {
+    *num_parts == 2
+}
+
+fn is_zero<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ParticipantDataV4 {
+    /// Public blind excess
+    #[serde(with = "ser::hex_bytes")]
+    pub xs: Vec<u8>,
+    #[serde(with = "ser::hex_bytes")]
+    pub nonce: Vec<u8>,
+    /// Partial signature
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "ser::opt_hex_bytes"
+    )]
+    pub part: Option<Vec<u8>>,
+}
+
+/// An input, or an output when it has a range proof
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommitsV4 {
+    /// Output features, as `OutputFeatures`
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub f: u8,
+    #[serde(with = "ser::hex_bytes")]
+    pub c: Vec<u8>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "ser::opt_hex_bytes"
+    )]
+    pub p: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KernelFeaturesArgsV4 {
+    #[serde(with = "ser::string_or_u64")]
+    pub lock_hgt: u64,
+}
+
+impl SlateV4 {
+    pub fn check_payment_proof(&self) -> Result<(), Error> {
+        if self.proof.as_ref().map_or(false, |p| !p.is_null()) {
+            return Err(no_payment_proofs());
+        }
+        Ok(())
+    }
+
+    /// The sender's S1 as V1 slate, which is what the wallet API takes.
+    /// The kernel is the empty one of a V1 slate for the plain features,
+    /// lock heights can't be checked without the sender's height and fee
+    /// shifts can't be signed by the v1 API.
+    pub fn to_v1(&self) -> Result<Slate, Error> {
+        let invalid = |reason: &str| Err(Error::InvalidSlate(reason.to_owned()));
+        if self.sta != "S1" {
+            return invalid("only the sender's S1 slate can be received");
+        }
+        if self.feat != KernelFeatures::Plain as u8 {
+            return invalid("only plain kernels are supported");
+        }
+        if self.fee >> FEE_BITS != 0 {
+            return invalid("fee shifts aren't supported");
+        }
+        let mut inputs = vec![];
+        let mut outputs = vec![];
+        for com in self.coms.iter().flatten() {
+            let features = match com.f {
+                f if f == OutputFeatures::Plain as u8 => OutputFeatures::Plain,
+                f if f == OutputFeatures::Coinbase as u8 => OutputFeatures::Coinbase,
+                _ => return invalid("unknown output features"),
+            };
+            match com.p {
+                Some(ref proof) => outputs.push(Output {
+                    features,
+                    commit: com.c.clone(),
+                    proof: proof.clone(),
+                }),
+                None => inputs.push(Input {
+                    features,
+                    commit: com.c.clone(),
+                }),
+            }
+        }
+        Ok(Slate {
+            num_participants: self.num_parts as usize,
+            id: self.id,
+            tx: Transaction {
+                offset: self.off.clone(),
+                body: TransactionBody {
+                    inputs,
+                    outputs,
+                    kernels: vec![TxKernel {
+                        features: KernelFeatures::Plain,
+                        fee: self.fee,
+                        lock_height: 0,
+                        excess: vec![0; secp::constants::PEDERSEN_COMMITMENT_SIZE],
+                        excess_sig: vec![0; secp::constants::COMPACT_SIGNATURE_SIZE],
+                    }],
+                },
+            },
+            amount: self.amt,
+            fee: self.fee,
+            height: 0,
+            lock_height: 0,
+            participant_data: self
+                .sigs
+                .iter()
+                .enumerate()
+                .map(|(id, sig)| {
+                    Ok(ParticipantData {
+                        id: id as u64,
+                        public_blind_excess: sig.xs.clone(),
+                        public_nonce: sig.nonce.clone(),
+                        part_sig: opt_raw_signature(&sig.part)?,
+                        message: None,
+                        message_sig: None,
+                    })
+                })
+                .collect::<Result<_, Error>>()?,
+            version: 1,
+        })
+    }
+
+    /// S2 of `slate`, the wallet's answer to this one, with the receiver's
+    /// signature data and outputs only, as grin wallets answer
+    pub fn answer(&self, slate: &Slate) -> Result<SlateV4, Error> {
+        let sent: Vec<&Vec<u8>> = self.coms.iter().flatten().map(|com| &com.c).collect();
+        let coms = slate
+            .tx
+            .body
+            .outputs
+            .iter()
+            .filter(|output| !sent.contains(&&output.commit))
+            .map(|output| CommitsV4 {
+                f: output.features as u8,
+                c: output.commit.clone(),
+                p: Some(output.proof.clone()),
+            })
+            .collect();
+        let sigs = slate
+            .participant_data
+            .iter()
+            .filter(|participant| participant.id != 0)
+            .map(|participant| {
+                Ok(ParticipantDataV4 {
+                    xs: participant.public_blind_excess.clone(),
+                    nonce: participant.public_nonce.clone(),
+                    part: opt_compact_signature(&participant.part_sig)?,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(SlateV4 {
+            ver: self.ver.clone(),
+            id: slate.id,
+            sta: s!("S2"),
+            off: slate.tx.offset.clone(),
+            num_parts: self.num_parts,
+            amt: 0,
+            fee: 0,
+            feat: self.feat,
+            ttl: self.ttl,
+            sigs,
+            coms: Some(coms),
+            proof: None,
+            feat_args: None,
+        })
+    }
+}
+
 /// A range proof. Typically much larger in memory that the above (~5k).
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RangeProof {
@@ -510,6 +1493,345 @@ struct SendTx {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_selection() {
+        assert!(OutputSelection::default().validate().is_ok());
+        let no_change = OutputSelection {
+            num_change_outputs: 0,
+            ..OutputSelection::default()
+        };
+        assert!(no_change.validate().is_err());
+        let no_inputs = OutputSelection {
+            max_outputs: 0,
+            ..OutputSelection::default()
+        };
+        assert!(no_inputs.validate().is_err());
+    }
+
+    #[test]
+    fn test_message_signer() {
+        let mut sender = ParticipantData {
+            id: 0,
+            public_blind_excess: vec![2; 33],
+            public_nonce: vec![2; 33],
+            part_sig: None,
+            message: Some(s!("order 42")),
+            message_sig: None,
+        };
+        assert_eq!(sender.message_signer().unwrap(), None);
+        sender.message_sig = Some(vec![1; 64]);
+        assert!(sender.message_signer().is_err());
+        sender.message_sig = Some(vec![1; 10]);
+        assert!(sender.message_signer().is_err());
+        sender.message = None;
+        assert!(sender.message_signer().is_err());
+    }
+
+    /// Signed the way grin wallets sign slate messages, see
+    /// `Slate::add_participant_info` in grin's libwallet, and serialized
+    /// like grin serializes signatures in slates
+    /// Signs `message` the way grin wallets sign slate messages, returns
+    /// the public key and the signature
+    fn grin_signature(message: &str) -> (Vec<u8>, Signature) {
+        let secp = Secp256k1::with_caps(ContextFlag::Full);
+        let sec_key = secp::key::SecretKey::from_slice(&secp, &[7; 32]).unwrap();
+        let pub_key = PublicKey::from_secret_key(&secp, &sec_key).unwrap();
+        let hash = blake2b(secp::constants::MESSAGE_SIZE, &[], message.as_bytes());
+        let msg = secp::Message::from_slice(hash.as_bytes()).unwrap();
+        let sig = aggsig::sign_single(
+            &secp,
+            &msg,
+            &sec_key,
+            None,
+            None,
+            None,
+            Some(&pub_key),
+            None,
+        )
+        .unwrap();
+        (pub_key.serialize_vec(&secp, true).to_vec(), sig)
+    }
+
+    #[test]
+    fn test_grin_signed_message() {
+        let message = s!("Order 42 at Knockturn");
+        let (public_blind_excess, sig) = grin_signature(&message);
+        let mut sender = ParticipantData {
+            id: 0,
+            public_blind_excess: public_blind_excess.clone(),
+            public_nonce: vec![2; 33],
+            part_sig: None,
+            message: Some(message),
+            message_sig: Some(sig.to_raw_data().to_vec()),
+        };
+        assert_eq!(
+            sender.message_signer().unwrap(),
+            Some(ser::to_hex(public_blind_excess))
+        );
+
+        // The signature covers the message
+        sender.message = Some(s!("Order 43 at Knockturn"));
+        assert!(sender.message_signer().is_err());
+    }
+
+    fn sender_slate() -> Slate {
+        Slate {
+            num_participants: 2,
+            id: Uuid::new_v4(),
+            tx: Transaction {
+                offset: vec![0; 32],
+                body: TransactionBody {
+                    inputs: vec![Input {
+                        features: OutputFeatures::Plain,
+                        commit: vec![8; 33],
+                    }],
+                    outputs: vec![Output {
+                        features: OutputFeatures::Plain,
+                        commit: vec![9; 33],
+                        proof: vec![1; 675],
+                    }],
+                    kernels: vec![TxKernel {
+                        features: KernelFeatures::Plain,
+                        fee: 7_000_000,
+                        lock_height: 0,
+                        excess: vec![0; 33],
+                        excess_sig: vec![0; 64],
+                    }],
+                },
+            },
+            amount: 1_000_000_000,
+            fee: 7_000_000,
+            height: 5,
+            lock_height: 0,
+            participant_data: vec![ParticipantData {
+                id: 0,
+                public_blind_excess: vec![2; 33],
+                public_nonce: vec![2; 33],
+                part_sig: None,
+                message: None,
+                message_sig: None,
+            }],
+            version: 1,
+        }
+    }
+
+    #[test]
+    fn test_validate_slate() {
+        assert!(sender_slate().validate().is_ok());
+
+        let invalid = |change: &dyn Fn(&mut Slate)| {
+            let mut slate = sender_slate();
+            change(&mut slate);
+            match slate.validate() {
+                Err(Error::InvalidSlate(_)) => {}
+                res => panic!("malformed slate was accepted: {:?}", res),
+            }
+        };
+        invalid(&|slate| slate.num_participants = 3);
+        invalid(&|slate| slate.participant_data.clear());
+        invalid(&|slate| {
+            let receiver = ParticipantData {
+                id: 1,
+                ..slate.participant_data[0].clone()
+            };
+            slate.participant_data.push(receiver);
+        });
+        invalid(&|slate| slate.participant_data[0].public_nonce = vec![2; 5]);
+        invalid(&|slate| slate.amount = 0);
+        invalid(&|slate| slate.fee = 2_000_000_000);
+        invalid(&|slate| slate.amount = u64::max_value());
+        invalid(&|slate| slate.lock_height = 6);
+        invalid(&|slate| slate.tx.body.kernels[0].fee = 1);
+        invalid(&|slate| slate.tx.body.kernels.clear());
+        invalid(&|slate| slate.tx.body.inputs.clear());
+        invalid(&|slate| slate.tx.body.outputs[0].features = OutputFeatures::Coinbase);
+        invalid(&|slate| slate.tx.body.outputs[0].proof = vec![1; 6000]);
+        invalid(&|slate| slate.tx.offset = vec![0; 2]);
+
+        let mut locked = sender_slate();
+        locked.lock_height = 5;
+        locked.tx.body.kernels[0].lock_height = 5;
+        locked.tx.body.kernels[0].features = KernelFeatures::HeightLocked;
+        assert!(locked.validate().is_ok());
+    }
+
+    #[test]
+    fn test_slate_versions() {
+        let secp = Secp256k1::with_caps(ContextFlag::None);
+        let (public_blind_excess, message_sig) = grin_signature("order 42");
+        let v3 = json!({
+            "version_info": {"version": 3, "orig_version": 3, "block_header_version": 2},
+            "num_participants": 2,
+            "id": "0436430c-2b02-624c-2032-570501212b00",
+            "tx": {
+                "offset": "0a0b",
+                "body": {
+                    "inputs": [{"features": "Plain", "commit": "08aa"}],
+                    "outputs": [{"features": "Plain", "commit": "09bb", "proof": "cc"}],
+                    "kernels": [{
+                        "features": "Plain",
+                        "fee": "7000000",
+                        "lock_height": "0",
+                        "excess": "00",
+                        "excess_sig": ser::to_hex(vec![0; 64])
+                    }]
+                }
+            },
+            "amount": "1000000000",
+            "fee": "7000000",
+            "height": "5",
+            "lock_height": "0",
+            "ttl_cutoff_height": "1440",
+            "participant_data": [{
+                "id": "0",
+                "public_blind_excess": ser::to_hex(public_blind_excess.clone()),
+                "public_nonce": "02bb",
+                "part_sig": null,
+                "message": "order 42",
+                "message_sig": ser::to_hex(message_sig.serialize_compact(&secp).to_vec())
+            }],
+            "payment_proof": null
+        });
+        let versioned = VersionedSlate::parse(v3).unwrap();
+        assert_eq!(versioned.version(), 3);
+        let slate = versioned.to_slate().unwrap();
+        assert_eq!(slate.version, 1);
+        assert_eq!(slate.amount, 1_000_000_000);
+        assert_eq!(slate.tx.output_commitments(), vec![vec![0x09, 0xbb]]);
+        // Hex slates carry compact signatures, V1 ones their raw data
+        let sender = &slate.participant_data[0];
+        assert_eq!(sender.message_sig, Some(message_sig.to_raw_data().to_vec()));
+        assert_eq!(
+            sender.message_signer().unwrap(),
+            Some(ser::to_hex(public_blind_excess))
+        );
+
+        let answer = serde_json::to_value(versioned.answer(slate.clone()).unwrap()).unwrap();
+        assert_eq!(answer["version_info"]["version"], 3);
+        assert_eq!(answer["ttl_cutoff_height"], "1440");
+        assert_eq!(answer["amount"], "1000000000");
+        assert_eq!(answer["tx"]["body"]["outputs"][0]["commit"], "09bb");
+        assert_eq!(
+            answer["participant_data"][0]["message_sig"],
+            ser::to_hex(message_sig.serialize_compact(&secp).to_vec())
+        );
+        assert!(answer.get("payment_proof").is_none());
+
+        let v1 = serde_json::to_value(&slate).unwrap();
+        let versioned = VersionedSlate::parse(v1).unwrap();
+        assert_eq!(versioned.version(), 1);
+        let mut v0 = serde_json::to_value(&slate).unwrap();
+        v0.as_object_mut().unwrap().remove("version");
+        assert_eq!(VersionedSlate::parse(v0).unwrap().version(), 0);
+
+        match VersionedSlate::parse(json!({"ver": "5:3", "sta": "S1"})) {
+            Err(Error::UnsupportedSlateVersion(version)) => assert_eq!(version, "V5"),
+            res => panic!("V5 slate parsed: {:?}", res),
+        }
+    }
+
+    fn v4_slate() -> serde_json::Value {
+        json!({
+            "ver": "4:3",
+            "id": "0436430c-2b02-624c-2032-570501212b00",
+            "sta": "S1",
+            "off": ser::to_hex(vec![3; 32]),
+            "amt": "1000000000",
+            "fee": "7000000",
+            "ttl": "1440",
+            "sigs": [{
+                "xs": ser::to_hex(vec![2; 33]),
+                "nonce": ser::to_hex(vec![3; 33])
+            }]
+        })
+    }
+
+    #[test]
+    fn test_v4_slate() {
+        let versioned = VersionedSlate::parse(v4_slate()).unwrap();
+        assert_eq!(versioned.version(), 4);
+        // The sender's inputs and change stay with the sender
+        assert!(versioned.to_slate().unwrap().validate().is_err());
+        let mut slate = versioned.to_valid_slate().unwrap();
+        assert_eq!(slate.amount, 1_000_000_000);
+        assert_eq!(slate.fee, 7_000_000);
+        assert_eq!(slate.tx.offset, vec![3; 32]);
+        assert_eq!(slate.tx.body.kernels[0].fee, 7_000_000);
+        assert_eq!(slate.participant_data[0].public_blind_excess, vec![2; 33]);
+
+        // The wallet adds its output and signature data
+        let (public_blind_excess, part_sig) = grin_signature("receiver");
+        slate.tx.body.outputs.push(Output {
+            features: OutputFeatures::Plain,
+            commit: vec![9; 33],
+            proof: vec![1; 675],
+        });
+        slate.participant_data.push(ParticipantData {
+            id: 1,
+            public_blind_excess: public_blind_excess.clone(),
+            public_nonce: vec![3; 33],
+            part_sig: Some(part_sig.to_raw_data().to_vec()),
+            message: None,
+            message_sig: None,
+        });
+        let answer = serde_json::to_value(versioned.answer(slate.clone()).unwrap()).unwrap();
+        let secp = Secp256k1::with_caps(ContextFlag::None);
+        assert_eq!(
+            answer,
+            json!({
+                "ver": "4:3",
+                "id": "0436430c-2b02-624c-2032-570501212b00",
+                "sta": "S2",
+                "off": ser::to_hex(vec![3; 32]),
+                "ttl": "1440",
+                "sigs": [{
+                    "xs": ser::to_hex(public_blind_excess),
+                    "nonce": ser::to_hex(vec![3; 33]),
+                    "part": ser::to_hex(part_sig.serialize_compact(&secp).to_vec())
+                }],
+                "coms": [{"c": ser::to_hex(vec![9; 33]), "p": ser::to_hex(vec![1; 675])}]
+            })
+        );
+
+        // A slatepack is answered with one
+        let text = Slatepack(serde_json::from_value(v4_slate()).unwrap())
+            .encode()
+            .unwrap();
+        let versioned = VersionedSlate::parse(json!(text)).unwrap();
+        assert_eq!(versioned.version(), 4);
+        assert_eq!(versioned.to_valid_slate().unwrap().amount, 1_000_000_000);
+        let answer = versioned.answer(slate).unwrap();
+        let text = serde_json::to_value(&answer).unwrap();
+        let answer = Slatepack::decode(text.as_str().unwrap()).unwrap().0;
+        assert_eq!(answer.sta, "S2");
+        assert_eq!(answer.sigs.len(), 1);
+        assert_eq!(
+            answer.sigs[0].part,
+            Some(part_sig.serialize_compact(&secp).to_vec())
+        );
+
+        let invalid = |change: &dyn Fn(&mut serde_json::Value)| {
+            let mut slate = v4_slate();
+            change(&mut slate);
+            match VersionedSlate::parse(slate).and_then(|slate| slate.to_valid_slate()) {
+                Err(Error::InvalidSlate(_)) => {}
+                res => panic!("unsupported V4 slate was accepted: {:?}", res),
+            }
+        };
+        invalid(&|slate| slate["sta"] = json!("I1"));
+        invalid(&|slate| {
+            slate["feat"] = json!(2);
+            slate["feat_args"] = json!({"lock_hgt": "5"});
+        });
+        invalid(&|slate| slate["fee"] = json!(((1u64 << FEE_BITS) + 7_000_000).to_string()));
+        invalid(&|slate| slate["sigs"][0]["part"] = json!(ser::to_hex(vec![1; 64])));
+
+        let mut proof = v4_slate();
+        proof["proof"] = json!({"saddr": ser::to_hex(vec![4; 32])});
+        assert!(VersionedSlate::parse(proof).is_err());
+    }
 
     #[test]
     fn wallet_get_tx_test() {
@@ -0,0 +1,167 @@
+//! Which wallet the gateway talks to.
+//!
+//! Every instance asks the wallet for its version on start and then every
+//! `VERSION_CHECK_SECONDS`, with `check_version` of the v2 foreign API.
+//! Wallets before 1.1 don't have it and are taken for v1 wallets. The
+//! answer picks the API the gateway calls the wallet with, a wallet which
+//! only speaks newer APIs or slate versions is logged as an error on every
+//! check and `wallet_compatible` is 0 until it's replaced.
+//!
+//! The v1 owner API is the only one implemented so far, wallets which
+//! dropped it can't be used.
+
+use crate::metrics;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+use strum_macros::Display;
+
+pub const VERSION_CHECK_SECONDS: u64 = 10 * 60;
+/// Newest foreign API whose wallets still serve the v1 owner API
+const MAX_FOREIGN_API_VERSION: u16 = 2;
+/// Slates `wallet::Slate` reads and writes
+pub const SUPPORTED_SLATE_VERSIONS: &[&str] = &["V0", "V1"];
+
+lazy_static::lazy_static! {
+    static ref CAPABILITIES: RwLock<Option<WalletCapabilities>> = RwLock::new(None);
+}
+
+/// Answer of the wallet's `check_version`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WalletVersion {
+    pub foreign_api_version: u16,
+    pub supported_slate_versions: Vec<String>,
+}
+
+impl WalletVersion {
+    /// What wallets without `check_version` speak
+    pub fn legacy() -> Self {
+        WalletVersion {
+            foreign_api_version: 1,
+            supported_slate_versions: vec![s!("V0"), s!("V1")],
+        }
+    }
+}
+
+/// API the gateway calls the wallet with
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Display)]
+pub enum WalletApi {
+    #[strum(serialize = "owner_v1")]
+    OwnerV1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Display)]
+pub enum Compatibility {
+    #[strum(serialize = "supported")]
+    Supported,
+    /// Works, but can't tell what it supports, it should be upgraded
+    #[strum(serialize = "too_old")]
+    TooOld,
+    #[strum(serialize = "too_new")]
+    TooNew,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WalletCapabilities {
+    /// `None` for wallets without `check_version`
+    pub version: Option<WalletVersion>,
+    pub compatibility: Compatibility,
+    /// `None` when the gateway can't talk to the wallet
+    pub api: Option<WalletApi>,
+}
+
+impl WalletCapabilities {
+    pub fn negotiate(version: Option<WalletVersion>) -> Self {
+        let (compatibility, api) = match version {
+            None => (Compatibility::TooOld, Some(WalletApi::OwnerV1)),
+            Some(ref version)
+                if version.foreign_api_version > MAX_FOREIGN_API_VERSION
+                    || !version
+                        .supported_slate_versions
+                        .iter()
+                        .any(|v| SUPPORTED_SLATE_VERSIONS.contains(&v.as_str())) =>
+            {
+                (Compatibility::TooNew, None)
+            }
+            Some(_) => (Compatibility::Supported, Some(WalletApi::OwnerV1)),
+        };
+        WalletCapabilities {
+            version,
+            compatibility,
+            api,
+        }
+    }
+
+    pub fn slate_versions_text(&self) -> String {
+        self.version
+            .as_ref()
+            .map(|version| version.supported_slate_versions.join(", "))
+            .unwrap_or_else(|| s!("unknown"))
+    }
+}
+
+/// Remembers the outcome of a check and complains about unusable wallets
+pub fn record(capabilities: WalletCapabilities) {
+    match capabilities.compatibility {
+        Compatibility::Supported => {}
+        Compatibility::TooOld => warn!(
+            "The wallet doesn't tell its version, it's older than 1.1 and should be upgraded"
+        ),
+        Compatibility::TooNew => error!(
+            "The wallet is too new for the gateway: {:?}, supported are foreign API up to {} and slates {}",
+            capabilities.version,
+            MAX_FOREIGN_API_VERSION,
+            SUPPORTED_SLATE_VERSIONS.join(", ")
+        ),
+    }
+    metrics::set(
+        "wallet_compatible",
+        &[],
+        (capabilities.compatibility != Compatibility::TooNew) as i64,
+    );
+    let mut current = CAPABILITIES.write().unwrap();
+    if current.as_ref() != Some(&capabilities) {
+        info!(
+            "Wallet is {}, calling it with {:?}",
+            capabilities.compatibility, capabilities.api
+        );
+    }
+    *current = Some(capabilities);
+}
+
+/// Outcome of the last check, `None` before the first one went through
+pub fn current() -> Option<WalletCapabilities> {
+    CAPABILITIES.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let legacy = WalletCapabilities::negotiate(None);
+        assert_eq!(legacy.compatibility, Compatibility::TooOld);
+        assert_eq!(legacy.api, Some(WalletApi::OwnerV1));
+
+        let mut version = WalletVersion {
+            foreign_api_version: 2,
+            supported_slate_versions: vec![s!("V2"), s!("V1"), s!("V0")],
+        };
+        let supported = WalletCapabilities::negotiate(Some(version.clone()));
+        assert_eq!(supported.compatibility, Compatibility::Supported);
+        assert_eq!(supported.slate_versions_text(), "V2, V1, V0");
+
+        version.supported_slate_versions = vec![s!("V3"), s!("V2")];
+        let too_new = WalletCapabilities::negotiate(Some(version.clone()));
+        assert_eq!(too_new.compatibility, Compatibility::TooNew);
+        assert_eq!(too_new.api, None);
+
+        version.supported_slate_versions = vec![s!("V1")];
+        version.foreign_api_version = 3;
+        assert_eq!(
+            WalletCapabilities::negotiate(Some(version)).compatibility,
+            Compatibility::TooNew
+        );
+    }
+}
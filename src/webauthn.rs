@@ -0,0 +1,279 @@
+//! Minimal WebAuthn relying party for security keys as a second factor.
+//!
+//! Only "none" attestation is supported: the browser hands us the credential
+//! public key (`AuthenticatorAttestationResponse.getPublicKey()`, SPKI DER) on
+//! registration, later assertions are checked against it. ES256 and RS256
+//! keys are accepted.
+
+use crate::errors::Error;
+use data_encoding::BASE64URL_NOPAD;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sha::sha256;
+use openssl::sign::Verifier;
+use rand::{thread_rng, Rng};
+use serde::Deserialize;
+
+/// Session key where the pending challenge is stored between the two
+/// registration or authentication requests
+pub const CHALLENGE_SESSION_KEY: &str = "webauthn_challenge";
+
+const CLIENT_DATA_CREATE: &str = "webauthn.create";
+const CLIENT_DATA_GET: &str = "webauthn.get";
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const AUTHENTICATOR_DATA_MIN_LEN: usize = 37; // rp id hash + flags + sign count
+
+pub fn new_challenge() -> String {
+    BASE64URL_NOPAD.encode(&thread_rng().gen::<[u8; 32]>())
+}
+
+pub fn decode(value: &str) -> Result<Vec<u8>, Error> {
+    BASE64URL_NOPAD
+        .decode(value.as_bytes())
+        .map_err(|e| Error::SecurityKey(format!("invalid base64url value: {}", e)))
+}
+
+#[derive(Debug, Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct RelyingParty {
+    /// Effective domain, e.g. `domain.com`
+    pub id: String,
+    /// Origin the browser reports, e.g. `https://domain.com:3000`
+    pub origin: String,
+}
+
+impl RelyingParty {
+    /// Builds relying party from the DOMAIN setting, e.g. `http://domain.com:3000/`
+    pub fn from_domain(domain: &str) -> Self {
+        let origin = domain.trim_end_matches('/').to_owned();
+        let host = origin.splitn(2, "://").last().unwrap_or("");
+        let id = host.split(|c| c == ':' || c == '/').next().unwrap_or("");
+        RelyingParty {
+            id: id.to_owned(),
+            origin,
+        }
+    }
+
+    /// Checks a response to `navigator.credentials.create`, returns
+    /// the authenticator's signature counter
+    pub fn verify_registration(
+        &self,
+        challenge: &str,
+        client_data_json: &[u8],
+        authenticator_data: &[u8],
+        public_key: &[u8],
+    ) -> Result<u32, Error> {
+        self.verify_client_data(CLIENT_DATA_CREATE, challenge, client_data_json)?;
+        PKey::public_key_from_der(public_key)
+            .map_err(|e| Error::SecurityKey(format!("unsupported public key: {}", e)))?;
+        self.verify_authenticator_data(authenticator_data)
+    }
+
+    /// Checks a response to `navigator.credentials.get` against the stored
+    /// public key, returns the new signature counter
+    pub fn verify_assertion(
+        &self,
+        challenge: &str,
+        client_data_json: &[u8],
+        authenticator_data: &[u8],
+        signature: &[u8],
+        public_key: &[u8],
+        stored_sign_count: i64,
+    ) -> Result<u32, Error> {
+        self.verify_client_data(CLIENT_DATA_GET, challenge, client_data_json)?;
+        let sign_count = self.verify_authenticator_data(authenticator_data)?;
+
+        let key = PKey::public_key_from_der(public_key).map_err(|e| Error::SecurityKey(s!(e)))?;
+        let mut verifier =
+            Verifier::new(MessageDigest::sha256(), &key).map_err(|e| Error::SecurityKey(s!(e)))?;
+        verifier
+            .update(authenticator_data)
+            .and_then(|_| verifier.update(&sha256(client_data_json)))
+            .map_err(|e| Error::SecurityKey(s!(e)))?;
+        if !verifier.verify(signature).unwrap_or(false) {
+            return Err(Error::SecurityKey(s!("wrong signature")));
+        }
+
+        // A counter which doesn't grow means the key could have been cloned,
+        // authenticators without a counter always report 0
+        if (sign_count != 0 || stored_sign_count != 0) && sign_count as i64 <= stored_sign_count {
+            return Err(Error::SecurityKey(s!("signature counter didn't increase")));
+        }
+        Ok(sign_count)
+    }
+
+    fn verify_client_data(
+        &self,
+        expected_type: &str,
+        challenge: &str,
+        client_data_json: &[u8],
+    ) -> Result<(), Error> {
+        let client_data: ClientData = serde_json::from_slice(client_data_json)?;
+        if client_data.kind != expected_type {
+            return Err(Error::SecurityKey(format!(
+                "unexpected client data type {}",
+                client_data.kind
+            )));
+        }
+        if client_data.challenge != challenge {
+            return Err(Error::SecurityKey(s!("challenge mismatch")));
+        }
+        if client_data.origin != self.origin {
+            return Err(Error::SecurityKey(format!(
+                "unexpected origin {}",
+                client_data.origin
+            )));
+        }
+        Ok(())
+    }
+
+    fn verify_authenticator_data(&self, authenticator_data: &[u8]) -> Result<u32, Error> {
+        if authenticator_data.len() < AUTHENTICATOR_DATA_MIN_LEN {
+            return Err(Error::SecurityKey(s!("authenticator data is too short")));
+        }
+        if authenticator_data[..32] != sha256(self.id.as_bytes()) {
+            return Err(Error::SecurityKey(s!("relying party id mismatch")));
+        }
+        if authenticator_data[32] & FLAG_USER_PRESENT == 0 {
+            return Err(Error::SecurityKey(s!("user is not present")));
+        }
+        let mut sign_count = [0u8; 4];
+        sign_count.copy_from_slice(&authenticator_data[33..37]);
+        Ok(u32::from_be_bytes(sign_count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::pkey::Private;
+    use openssl::sign::Signer;
+
+    fn rp() -> RelyingParty {
+        RelyingParty::from_domain("https://domain.com:3000/")
+    }
+
+    fn key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn authenticator_data(rp_id: &str, sign_count: u32) -> Vec<u8> {
+        let mut data = sha256(rp_id.as_bytes()).to_vec();
+        data.push(FLAG_USER_PRESENT);
+        data.extend_from_slice(&sign_count.to_be_bytes());
+        data
+    }
+
+    fn client_data(kind: &str, challenge: &str) -> Vec<u8> {
+        format!(
+            r#"{{"type":"{}","challenge":"{}","origin":"https://domain.com:3000"}}"#,
+            kind, challenge
+        )
+        .into_bytes()
+    }
+
+    fn sign(key: &PKey<Private>, auth_data: &[u8], client_data: &[u8]) -> Vec<u8> {
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+        signer.update(auth_data).unwrap();
+        signer.update(&sha256(client_data)).unwrap();
+        signer.sign_to_vec().unwrap()
+    }
+
+    #[test]
+    fn test_relying_party_from_domain() {
+        let rp = rp();
+        assert_eq!(rp.id, "domain.com");
+        assert_eq!(rp.origin, "https://domain.com:3000");
+    }
+
+    #[test]
+    fn test_verify_registration() {
+        let key = key();
+        let public_key = key.public_key_to_der().unwrap();
+        let challenge = new_challenge();
+        let client_data = client_data(CLIENT_DATA_CREATE, &challenge);
+        assert_eq!(
+            rp().verify_registration(
+                &challenge,
+                &client_data,
+                &authenticator_data("domain.com", 0),
+                &public_key
+            )
+            .unwrap(),
+            0
+        );
+        assert!(rp()
+            .verify_registration(
+                &new_challenge(),
+                &client_data,
+                &authenticator_data("domain.com", 0),
+                &public_key
+            )
+            .is_err());
+        assert!(rp()
+            .verify_registration(
+                &challenge,
+                &client_data,
+                &authenticator_data("evil.com", 0),
+                &public_key
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_verify_assertion() {
+        let key = key();
+        let public_key = key.public_key_to_der().unwrap();
+        let challenge = new_challenge();
+        let client_data = client_data(CLIENT_DATA_GET, &challenge);
+        let auth_data = authenticator_data("domain.com", 5);
+        let signature = sign(&key, &auth_data, &client_data);
+
+        assert_eq!(
+            rp().verify_assertion(
+                &challenge,
+                &client_data,
+                &auth_data,
+                &signature,
+                &public_key,
+                4
+            )
+            .unwrap(),
+            5
+        );
+        // replayed counter
+        assert!(rp()
+            .verify_assertion(
+                &challenge,
+                &client_data,
+                &auth_data,
+                &signature,
+                &public_key,
+                5
+            )
+            .is_err());
+        // signed by another key
+        let other_signature = sign(&self::key(), &auth_data, &client_data);
+        assert!(rp()
+            .verify_assertion(
+                &challenge,
+                &client_data,
+                &auth_data,
+                &other_signature,
+                &public_key,
+                4
+            )
+            .is_err());
+    }
+}